@@ -0,0 +1,110 @@
+#!/usr/bin/python3
+
+"""
+Decodes the NR operation trace of a kernel compiled with the `nrtrace` feature.
+
+Reads a serial log (file or stdin), extracts the `NRTRACE` lines printed by
+`nrtrace::dump()` and writes the records as CSV (one line per operation).
+
+Usage: python3 nrtrace.py serial.log > trace.csv
+"""
+
+import csv
+import os
+import re
+import struct
+import sys
+from collections import defaultdict
+
+RECORD_FORMAT = "<QQIBxH"
+RECORD_SIZE = struct.calcsize(RECORD_FORMAT)
+assert RECORD_SIZE == 24
+
+# The operations are recorded as the index of their variant, so we read the
+# names from the enums in the kernel source (declaration order).
+SRC = os.path.join(os.path.dirname(os.path.abspath(__file__)), "src")
+OP_ENUMS = {
+    0: ("nr-read", "nr.rs", "ReadOps"),
+    1: ("nr-write", "nr.rs", "Op"),
+    2: ("mlnr-read", "mlnr.rs", "Access"),
+    3: ("mlnr-write", "mlnr.rs", "Modify"),
+}
+
+
+def enum_variants(source, name):
+    """Returns the variant names of `pub enum name` in `source`."""
+    source = re.sub(r"//[^\n]*", "", source)
+    source = re.sub(r"#\[[^\]]*\]", "", source)
+    start = re.search(r"pub enum {}\s*{{".format(name), source)
+    assert start is not None, "enum {} not found".format(name)
+
+    variants = []
+    depth = 0
+    expect_name = True
+    for token in re.finditer(r"\w+|\S", source[start.end():]):
+        token = token.group(0)
+        if token in "({[<":
+            depth += 1
+        elif token in ")]>":
+            depth -= 1
+        elif token == "}":
+            if depth == 0:
+                return variants
+            depth -= 1
+        elif token == "," and depth == 0:
+            expect_name = True
+        elif depth == 0 and expect_name and re.match(r"\w+", token):
+            variants.append(token)
+            expect_name = False
+    raise AssertionError("enum {} isn't closed".format(name))
+
+
+def op_names(src=SRC):
+    names = {}
+    for cls, (cls_name, filename, enum) in OP_ENUMS.items():
+        with open(os.path.join(src, filename)) as f:
+            names[cls] = (cls_name, enum_variants(f.read(), enum))
+    return names
+
+
+OP_NAMES = op_names()
+
+
+def op_name(cls, op):
+    (cls_name, names) = OP_NAMES.get(cls, ("unknown", []))
+    name = names[op] if op < len(names) else "op{}".format(op)
+    return cls_name, name
+
+
+def records(lines):
+    for line in lines:
+        idx = line.find("NRTRACE ")
+        if idx == -1:
+            continue
+        parts = line[idx:].split()
+        if len(parts) != 3:
+            continue
+        data = bytes.fromhex(parts[2])
+        for off in range(0, len(data) - RECORD_SIZE + 1, RECORD_SIZE):
+            yield struct.unpack_from(RECORD_FORMAT, data, off)
+
+
+def main():
+    source = open(sys.argv[1]) if len(sys.argv) > 1 else sys.stdin
+    writer = csv.writer(sys.stdout)
+    writer.writerow(["start", "cycles", "core", "class", "op"])
+
+    totals = defaultdict(lambda: [0, 0])
+    for (start, cycles, core, cls, op) in records(source):
+        cls_name, name = op_name(cls, op)
+        writer.writerow([start, cycles, core, cls_name, name])
+        totals[(cls_name, name)][0] += 1
+        totals[(cls_name, name)][1] += cycles
+
+    for (cls_name, name), (count, cycles) in sorted(totals.items()):
+        sys.stderr.write("{:>10} {:<24} count={:<8} avg={} cycles\n".format(
+            cls_name, name, count, cycles // count))
+
+
+if __name__ == '__main__':
+    main()
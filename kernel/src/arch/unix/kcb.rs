@@ -4,6 +4,7 @@ use alloc::sync::Arc;
 use core::any::Any;
 use core::cell::{RefCell, RefMut};
 use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use cnr::Replica as MlnrReplica;
 use cnr::ReplicaToken as MlnrReplicaToken;
@@ -19,24 +20,29 @@ use super::process::{UnixProcess, UnixThread};
 use super::vspace::VSpace;
 use super::KernelArgs;
 
-static mut KCB: *mut Kcb<ArchKcb> = ptr::null_mut();
+/// The KCB for the unix platform.
+///
+/// The unit tests run in multiple threads, every one of them sets up its own
+/// KCB (see `start`): `get_kcb` hands out a `&mut` like on bare-metal, where
+/// each core has its own KCB too.
+#[cfg_attr(test, thread_local)]
+static KCB: AtomicPtr<Kcb<ArchKcb>> = AtomicPtr::new(ptr::null_mut());
 
 pub fn try_get_kcb<'a>() -> Option<&'a mut Kcb<ArchKcb>> {
-    unsafe {
-        if !KCB.is_null() {
-            Some(&mut *KCB as &mut Kcb<ArchKcb>)
-        } else {
-            None
-        }
+    let kcb = KCB.load(Ordering::Acquire);
+    if !kcb.is_null() {
+        unsafe { Some(&mut *kcb as &mut Kcb<ArchKcb>) }
+    } else {
+        None
     }
 }
 
 pub fn get_kcb<'a>() -> &'a mut Kcb<ArchKcb> {
-    unsafe { &mut *KCB as &mut Kcb<ArchKcb> }
+    try_get_kcb().expect("KCB not initialized")
 }
 
 unsafe fn set_kcb(kcb: ptr::NonNull<Kcb<ArchKcb>>) {
-    KCB = kcb.as_ptr();
+    KCB.store(kcb.as_ptr(), Ordering::Release);
 }
 
 /// Initialize the KCB in the system.
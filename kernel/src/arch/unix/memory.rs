@@ -1,7 +1,9 @@
 use core::mem::transmute;
 
 use crate::memory::Frame;
-pub use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, CACHE_LINE_SIZE, LARGE_PAGE_SIZE};
+pub use x86::bits64::paging::{
+    PAddr, VAddr, BASE_PAGE_SIZE, CACHE_LINE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE,
+};
 
 /// Maximum amount of addressable physical memory in kernel (32 TiB).
 pub const KERNEL_BASE: u64 = 0x0;
@@ -1,5 +1,6 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use arrayvec::ArrayVec;
 use node_replication::Log;
@@ -17,6 +18,7 @@ pub mod irq;
 pub mod kcb;
 pub mod memory;
 pub mod process;
+pub mod replay;
 pub mod timer;
 pub mod vspace;
 
@@ -26,7 +28,10 @@ pub use bootloader_shared::*;
 
 pub const MAX_NUMA_NODES: usize = 12;
 
-static mut initialized: bool = false;
+/// Set once the kernel is set up (for every thread of the unit tests, they
+/// each have their own KCB).
+#[cfg_attr(test, thread_local)]
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 pub fn halt() -> ! {
     unsafe { libc::exit(0) };
@@ -36,14 +41,17 @@ pub fn advance_mlnr_replica() {
     unreachable!("eager_advance_mlnr_replica not implemented for unix");
 }
 
+pub fn poll_async_ring() {}
+
+pub fn isolate_core() {}
+
 #[start]
 pub fn start(_argc: isize, _argv: *const *const u8) -> isize {
-    unsafe {
-        if initialized {
-            return 0;
-        } else {
-            initialized = true;
-        }
+    if INITIALIZED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return 0;
     }
 
     // Note anything lower than Info is currently broken
@@ -4,13 +4,14 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ops::{Deref, DerefMut};
 
-use kpi::process::FrameId;
+use kpi::process::{FrameId, SchedulingPolicy};
 
 use crate::arch::Module;
 use crate::error::KError;
 use crate::fs::Fd;
+use crate::memory::vspace::{AddressSpaceError, MapAction, MappingType, TlbFlushHandle};
 use crate::memory::{Frame, VAddr};
-use crate::process::{Eid, Executor, Pid, Process, ProcessError, ResumeHandle};
+use crate::process::{Credentials, Eid, Executor, Pid, Process, ProcessError, ResumeHandle};
 
 use super::debug;
 use super::vspace::VSpace;
@@ -53,7 +54,7 @@ impl<T> UserValue<T> {
     }
 
     pub fn as_mut_ptr(&mut self) -> *mut T {
-        unsafe { core::mem::transmute(&self.value) }
+        &mut self.value as *mut T
     }
 }
 
@@ -80,11 +81,15 @@ impl<'a> UserSlice<'a> {
         UserSlice { buffer }
     }
 
-    pub fn new(base: u64, len: usize) -> UserSlice<'a> {
+    /// Creates a slice for `[base, base+len)` in user-space.
+    ///
+    /// # Safety
+    /// `[base, base+len)` has to be valid memory for the lifetime of the
+    /// slice (see `from_slice` for a safe alternative).
+    pub unsafe fn new(base: u64, len: usize) -> UserSlice<'a> {
         let mut user_ptr = VAddr::from(base);
         let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
+        let user_slice: &mut [u8] = core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len);
         UserSlice { buffer: user_slice }
     }
 }
@@ -106,6 +111,7 @@ impl<'a> DerefMut for UserSlice<'a> {
 pub struct UnixProcess {
     vspace: VSpace,
     fd: Fd,
+    credentials: Credentials,
     pinfo: kpi::process::ProcessInfo,
 }
 
@@ -161,11 +167,16 @@ impl Process for UnixProcess {
         _module: &Module,
         _pid: Pid,
         _writable_sections: Vec<Frame>,
+        policy: SchedulingPolicy,
     ) -> Result<Self, ProcessError> {
         Ok(UnixProcess {
             vspace: VSpace::new(),
             fd: Default::default(),
-            pinfo: Default::default(),
+            credentials: Default::default(),
+            pinfo: kpi::process::ProcessInfo {
+                policy,
+                ..Default::default()
+            },
         })
     }
 
@@ -204,14 +215,32 @@ impl Process for UnixProcess {
         0
     }
 
-    fn get_fd(&self, _index: usize) -> &Fd {
-        &self.fd
+    fn get_fd(&self, _index: usize) -> Option<&Fd> {
+        Some(&self.fd)
     }
 
+    fn open_fds(&self) -> Vec<&Fd> {
+        Vec::new()
+    }
+
+    fn fds(&self) -> Vec<Option<Fd>> {
+        Vec::new()
+    }
+
+    fn set_fds(&mut self, _fds: Vec<Option<Fd>>) {}
+
     fn pinfo(&self) -> &kpi::process::ProcessInfo {
         &self.pinfo
     }
 
+    fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
     fn add_frame(&mut self, _frame: Frame) -> Result<FrameId, ProcessError> {
         Err(ProcessError::InvalidFrameId)
     }
@@ -219,8 +248,81 @@ impl Process for UnixProcess {
     fn get_frame(&mut self, _frame_id: FrameId) -> Result<Frame, ProcessError> {
         Err(ProcessError::InvalidFrameId)
     }
+
+    fn remove_frame(&mut self, _frame_id: FrameId) -> Result<Frame, ProcessError> {
+        Err(ProcessError::InvalidFrameId)
+    }
+
+    fn executor_frames(&self) -> Vec<Frame> {
+        Vec::new()
+    }
+
+    fn fork(
+        &mut self,
+        _pid: Pid,
+        _executor_frames: Vec<Frame>,
+        _eid: Eid,
+    ) -> Result<(Self, Box<Self::E>), KError> {
+        Err(KError::NotSupported)
+    }
+
+    fn cow_mapping(&self, _vaddr: VAddr) -> Option<(VAddr, Frame)> {
+        None
+    }
+
+    fn has_cow_mapping(&self, _base: VAddr, _len: usize) -> bool {
+        false
+    }
+
+    fn resolve_cow(
+        &mut self,
+        _base: VAddr,
+        _copy: Option<Frame>,
+    ) -> Result<TlbFlushHandle, AddressSpaceError> {
+        Err(AddressSpaceError::NotMapped)
+    }
+
+    fn protect(
+        &mut self,
+        _base: VAddr,
+        _len: usize,
+        _rights: MapAction,
+    ) -> Result<(), AddressSpaceError> {
+        Err(AddressSpaceError::NotMapped)
+    }
+
+    fn set_key(&mut self, _base: VAddr, _len: usize, _key: u16) -> Result<(), AddressSpaceError> {
+        Err(AddressSpaceError::NotMapped)
+    }
+
+    fn exec(&mut self, _module: &Module, _writeable_sections: Vec<Frame>) -> Result<Self, KError> {
+        Err(KError::NotSupported)
+    }
+
+    fn map_foreign(
+        &mut self,
+        _base: VAddr,
+        _frame: Frame,
+        _action: MapAction,
+        _typ: MappingType,
+    ) -> Result<(), AddressSpaceError> {
+        Err(AddressSpaceError::InvalidFrame)
+    }
+
+    fn owned_frames(&self) -> Vec<Frame> {
+        Vec::new()
+    }
+
+    fn replica_frames(&self) -> &[Frame] {
+        &[]
+    }
 }
 
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
+pub fn spawn(
+    binary: &'static str,
+    args: &str,
+    parent: Option<Pid>,
+    policy: SchedulingPolicy,
+) -> Result<Pid, KError> {
     Ok(0)
 }
@@ -0,0 +1,412 @@
+//! Records the kernel schedule on the unix platform and replays it.
+//!
+//! In record mode we log every operation that is dispatched on a replica
+//! (see `nrtrace::Span`) and every timer event, in the order they happen and
+//! together with the thread that caused them. The resulting schedule can be
+//! encoded as text and stored.
+//!
+//! In replay mode every thread waits at each event until the schedule says
+//! it's its turn, so the threads go through the events in the recorded
+//! order. We panic with a report at the first event that doesn't match the
+//! schedule. Threads are numbered in the order they show up first, so the
+//! threads of a run don't need to have the same ids as the recorded ones.
+//!
+//! Dispatches are only in the order of their threads if every thread
+//! dispatches its own operations: a thread that combines the operations of
+//! others (see `node_replication::Replica`) would make them wait for it. A
+//! thread that waits for too long gives up and reports where the schedule
+//! got stuck. There is a single schedule for the whole process, so tests
+//! that use it have to run on their own (e.g., with `--test-threads=1`).
+//!
+//! # Example
+//! ```ignore
+//! replay::record();
+//! // ... run the test ...
+//! let schedule = replay::encode(&replay::stop());
+//!
+//! replay::replay(replay::decode(&schedule).unwrap());
+//! // ... run the test again, in the recorded order ...
+//! replay::stop();
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use crate::nrtrace::OpClass;
+
+/// How often a thread gives up its time-slice while it waits for its turn,
+/// before we consider the replay stuck.
+const MAX_WAIT_YIELDS: usize = 1_000_000;
+
+/// An event that is part of the recorded schedule.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// An operation of `class` (variant index `op`) was dispatched.
+    Dispatch { class: OpClass, op: u16 },
+    /// A timer was armed with `deadline`.
+    Timer { deadline: u64 },
+}
+
+/// An event and the thread (numbered in the order threads show up) it
+/// happened on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub thread: usize,
+    pub event: Event,
+}
+
+/// Reasons why a schedule can't be decoded.
+#[derive(Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The line (0-based) has an invalid format.
+    MalformedLine(usize),
+}
+
+/// An event happened that doesn't match the recorded schedule.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Divergence {
+    /// Index of the event in the schedule.
+    pub index: usize,
+    /// What we expected (None if the schedule was exhausted).
+    pub expected: Option<Entry>,
+    /// What actually happened.
+    pub actual: Entry,
+}
+
+/// What a thread does with an event in replay mode.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Turn {
+    /// The event is next in the schedule (or we don't replay), go ahead.
+    Go,
+    /// Another thread is next, try again later.
+    Wait,
+}
+
+/// The state of the recorder.
+pub enum Schedule {
+    /// Don't record anything.
+    Off,
+    /// Append all events, `threads` has the ids of the threads we have seen
+    /// (an `Entry::thread` is an index into it).
+    Record {
+        entries: Vec<Entry>,
+        threads: Vec<u64>,
+    },
+    /// Let the threads go through the events of `entries` in order.
+    Replay {
+        entries: Vec<Entry>,
+        cursor: usize,
+        threads: Vec<u64>,
+    },
+}
+
+impl Schedule {
+    /// Registers `event` of the thread with id `tid` with the schedule.
+    pub fn event(&mut self, tid: u64, event: Event) -> Result<Turn, Divergence> {
+        match self {
+            Schedule::Off => Ok(Turn::Go),
+            Schedule::Record { entries, threads } => {
+                let thread = threads.iter().position(|t| *t == tid).unwrap_or_else(|| {
+                    threads.push(tid);
+                    threads.len() - 1
+                });
+                entries.push(Entry { thread, event });
+                Ok(Turn::Go)
+            }
+            Schedule::Replay {
+                entries,
+                cursor,
+                threads,
+            } => {
+                let expected = entries.get(*cursor).cloned();
+                let thread = match threads.iter().position(|t| *t == tid) {
+                    Some(thread) => thread,
+                    // A new thread gets the next number once a thread we
+                    // haven't seen yet is next
+                    None if expected.map(|e| e.thread) == Some(threads.len()) => {
+                        threads.push(tid);
+                        threads.len() - 1
+                    }
+                    None if expected.is_some() => return Ok(Turn::Wait),
+                    None => threads.len(),
+                };
+                let actual = Entry { thread, event };
+
+                match expected {
+                    Some(e) if e == actual => {
+                        *cursor += 1;
+                        Ok(Turn::Go)
+                    }
+                    Some(e) if e.thread != thread => Ok(Turn::Wait),
+                    _ => Err(Divergence {
+                        index: *cursor,
+                        expected,
+                        actual,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// The event the schedule waits for (None if it doesn't wait).
+    fn next(&self) -> Option<(usize, Entry)> {
+        match self {
+            Schedule::Replay {
+                entries, cursor, ..
+            } => entries.get(*cursor).map(|e| (*cursor, *e)),
+            _ => None,
+        }
+    }
+
+    /// Stops recording or replaying, returns the recorded (or replayed)
+    /// events.
+    pub fn stop(&mut self) -> Vec<Entry> {
+        match core::mem::replace(self, Schedule::Off) {
+            Schedule::Off => Vec::new(),
+            Schedule::Record { entries, .. } => entries,
+            Schedule::Replay { entries, .. } => entries,
+        }
+    }
+}
+
+static SCHEDULE: Mutex<Schedule> = Mutex::new(Schedule::Off);
+
+/// Start recording a new schedule.
+pub fn record() {
+    *SCHEDULE.lock() = Schedule::Record {
+        entries: Vec::with_capacity(1024),
+        threads: Vec::new(),
+    };
+}
+
+/// Start replaying `entries`.
+pub fn replay(entries: Vec<Entry>) {
+    *SCHEDULE.lock() = Schedule::Replay {
+        entries,
+        cursor: 0,
+        threads: Vec::new(),
+    };
+}
+
+/// Stop recording/replaying and return the schedule.
+pub fn stop() -> Vec<Entry> {
+    SCHEDULE.lock().stop()
+}
+
+/// Called for every event that should be part of the schedule, returns
+/// once it's the turn of `event`.
+pub fn event(event: Event) {
+    let tid = unsafe { libc::pthread_self() } as u64;
+    for _yields in 0..MAX_WAIT_YIELDS {
+        let r = SCHEDULE.lock().event(tid, event);
+        match r {
+            Ok(Turn::Go) => return,
+            Ok(Turn::Wait) => unsafe {
+                libc::sched_yield();
+            },
+            Err(d) => panic!(
+                "Schedule diverged at event {}: expected {:?}, got {:?}",
+                d.index, d.expected, d.actual
+            ),
+        }
+    }
+
+    let next = SCHEDULE.lock().next();
+    panic!(
+        "Replay is stuck: {:?} waits for its turn, the schedule waits for {:?}",
+        event, next
+    );
+}
+
+fn class_from_u8(class: u8) -> Option<OpClass> {
+    match class {
+        0 => Some(OpClass::NrRead),
+        1 => Some(OpClass::NrWrite),
+        2 => Some(OpClass::MlnrRead),
+        3 => Some(OpClass::MlnrWrite),
+        _ => None,
+    }
+}
+
+/// Encode a schedule in a simple line based text format.
+///
+/// `<thread> d <class> <op>` for a dispatched operation and
+/// `<thread> t <deadline>` for timers.
+pub fn encode(entries: &[Entry]) -> String {
+    let mut s = String::with_capacity(entries.len() * 10);
+    for e in entries {
+        let _r = match e.event {
+            Event::Dispatch { class, op } => writeln!(s, "{} d {} {}", e.thread, class as u8, op),
+            Event::Timer { deadline } => writeln!(s, "{} t {}", e.thread, deadline),
+        };
+    }
+    s
+}
+
+/// Decode a schedule that was encoded with `encode`.
+pub fn decode(s: &str) -> Result<Vec<Entry>, DecodeError> {
+    let mut entries = Vec::new();
+    for (idx, line) in s.lines().enumerate() {
+        let malformed = || DecodeError::MalformedLine(idx);
+        let mut parts = line.split_whitespace();
+        let thread = match parts.next() {
+            Some(thread) => thread.parse::<usize>().map_err(|_e| malformed())?,
+            None => continue,
+        };
+        let event = match parts.next() {
+            Some("d") => {
+                let class = parts
+                    .next()
+                    .and_then(|c| c.parse::<u8>().ok())
+                    .and_then(class_from_u8)
+                    .ok_or_else(malformed)?;
+                let op = parts
+                    .next()
+                    .and_then(|o| o.parse::<u16>().ok())
+                    .ok_or_else(malformed)?;
+                Event::Dispatch { class, op }
+            }
+            Some("t") => {
+                let deadline = parts
+                    .next()
+                    .and_then(|d| d.parse::<u64>().ok())
+                    .ok_or_else(malformed)?;
+                Event::Timer { deadline }
+            }
+            _ => return Err(malformed()),
+        };
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+        entries.push(Entry { thread, event });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let entries = vec![
+            Entry {
+                thread: 0,
+                event: Event::Dispatch {
+                    class: OpClass::NrWrite,
+                    op: 16,
+                },
+            },
+            Entry {
+                thread: 1,
+                event: Event::Timer { deadline: 2000 },
+            },
+            Entry {
+                thread: 0,
+                event: Event::Dispatch {
+                    class: OpClass::MlnrRead,
+                    op: 0,
+                },
+            },
+        ];
+        let encoded = encode(&entries);
+        assert_eq!(encoded, "0 d 1 16\n1 t 2000\n0 d 2 0\n");
+        assert_eq!(decode(&encoded), Ok(entries));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode("0 d 1\n"), Err(DecodeError::MalformedLine(0)));
+        assert_eq!(
+            decode("0 t 1\n0 d 9 1\n"),
+            Err(DecodeError::MalformedLine(1))
+        );
+        assert_eq!(decode("0 x 1\n"), Err(DecodeError::MalformedLine(0)));
+        assert_eq!(decode("0 t 1 2\n"), Err(DecodeError::MalformedLine(0)));
+        assert_eq!(decode("t 1\n"), Err(DecodeError::MalformedLine(0)));
+        assert_eq!(decode("0\n"), Err(DecodeError::MalformedLine(0)));
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let a = Event::Timer { deadline: 1 };
+        let b = Event::Dispatch {
+            class: OpClass::NrRead,
+            op: 5,
+        };
+
+        // Thread 10 shows up first, then thread 20
+        let mut s = Schedule::Record {
+            entries: Vec::new(),
+            threads: Vec::new(),
+        };
+        assert_eq!(s.event(10, a), Ok(Turn::Go));
+        assert_eq!(s.event(20, b), Ok(Turn::Go));
+        assert_eq!(s.event(10, b), Ok(Turn::Go));
+        let recorded = s.stop();
+        assert_eq!(
+            recorded[1],
+            Entry {
+                thread: 1,
+                event: b
+            }
+        );
+
+        // Other thread ids, and the second thread is early
+        let mut s = Schedule::Replay {
+            entries: recorded.clone(),
+            cursor: 0,
+            threads: Vec::new(),
+        };
+        assert_eq!(s.event(7, a), Ok(Turn::Go));
+        assert_eq!(s.event(7, b), Ok(Turn::Wait));
+        assert_eq!(s.event(8, b), Ok(Turn::Go));
+        assert_eq!(s.event(7, b), Ok(Turn::Go));
+        assert_eq!(
+            s.event(7, a),
+            Err(Divergence {
+                index: 3,
+                expected: None,
+                actual: Entry {
+                    thread: 0,
+                    event: a
+                },
+            })
+        );
+
+        // A thread we haven't seen waits until a new thread is next
+        let mut s = Schedule::Replay {
+            entries: recorded.clone(),
+            cursor: 0,
+            threads: Vec::new(),
+        };
+        assert_eq!(s.event(7, a), Ok(Turn::Go));
+        assert_eq!(s.event(9, b), Ok(Turn::Go));
+        assert_eq!(s.event(11, b), Ok(Turn::Wait));
+        assert_eq!(s.event(7, b), Ok(Turn::Go));
+
+        let mut s = Schedule::Replay {
+            entries: recorded,
+            cursor: 0,
+            threads: Vec::new(),
+        };
+        assert_eq!(
+            s.event(7, b),
+            Err(Divergence {
+                index: 0,
+                expected: Some(Entry {
+                    thread: 0,
+                    event: a
+                }),
+                actual: Entry {
+                    thread: 0,
+                    event: b
+                },
+            })
+        );
+    }
+}
@@ -4,4 +4,6 @@
 pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
 
 /// Register a periodic timer to advance replica.
-pub fn set(deadline: u64) {}
+pub fn set(deadline: u64) {
+    super::replay::event(super::replay::Event::Timer { deadline });
+}
@@ -1,5 +1,7 @@
 #![allow(bad_style, dead_code, unused_variables)]
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::ffi::VaList;
 use core::ptr;
@@ -14,11 +16,21 @@ use crate::memory::vspace::MapAction;
 
 use super::kcb::{try_get_kcb, Arch86Kcb};
 use super::memory::{paddr_to_kernel_vaddr, PAddr};
+use super::steering::PciIrqRoute;
 
 use x86::io;
 
 const ACPI_FULL_PATHNAME: u32 = 0;
 const ACPI_TYPE_INTEGER: u32 = 0x01;
+const ACPI_TYPE_DEVICE: u32 = 0x06;
+const ACPI_VALID_HID: u32 = 0x0004;
+const ACPI_RESOURCE_TYPE_IRQ: u32 = 0;
+const ACPI_RESOURCE_TYPE_END_TAG: u32 = 7;
+const ACPI_RESOURCE_TYPE_EXTENDED_IRQ: u32 = 15;
+/// Lets ACPICA allocate the memory of an `ACPI_BUFFER` (we free it).
+const ACPI_ALLOCATE_BUFFER: ACPI_SIZE = ACPI_SIZE::max_value();
+/// The root of the namespace (`\`).
+const ACPI_ROOT_OBJECT: ACPI_HANDLE = usize::max_value() as ACPI_HANDLE;
 
 #[no_mangle]
 #[linkage = "external"]
@@ -662,9 +674,294 @@ pub(crate) fn init() -> Result<(), ACPI_STATUS> {
         //trace!("AcpiInitializeObjects {:?}", ret);
     }
 
+    // Tell the firmware we use the IOAPIC, otherwise `_PRT` returns the
+    // routing for the legacy PIC
+    if let Err(status) = set_interrupt_model(1) {
+        debug!("Can't evaluate _PIC: {:?}", status);
+    }
+
     // Required for integration test, don't modify without adjusting
     // `acpi_topology` test
     info!("ACPI Initialized");
 
     Ok(())
 }
+
+/// Evaluates `\_PIC` with `model` (0 = PIC, 1 = IOAPIC).
+fn set_interrupt_model(model: u64) -> Result<(), ACPI_STATUS> {
+    unsafe {
+        let mut arg: ACPI_OBJECT = core::mem::zeroed();
+        arg.Integer.Type = ACPI_TYPE_INTEGER;
+        arg.Integer.Value = model;
+        let mut args = ACPI_OBJECT_LIST {
+            Count: 1,
+            Pointer: &mut arg,
+        };
+
+        let ret = AcpiEvaluateObject(
+            ptr::null_mut(),
+            b"\\_PIC\0".as_ptr() as *mut _,
+            &mut args,
+            ptr::null_mut(),
+        );
+        if ret == AE_OK {
+            Ok(())
+        } else {
+            Err(ret)
+        }
+    }
+}
+
+/// A device declared in the ACPI namespace (e.g., in the DSDT).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AcpiDevice {
+    /// Full path in the namespace (e.g., `\_SB_.PCI0`).
+    pub path: String,
+    /// Hardware ID (e.g., `PNP0A03`), if the device has one.
+    pub hid: Option<String>,
+}
+
+impl AcpiDevice {
+    pub fn is_pci_root_bridge(&self) -> bool {
+        matches!(self.hid.as_deref(), Some("PNP0A03") | Some("PNP0A08"))
+    }
+}
+
+/// All devices in the ACPI namespace.
+pub fn devices() -> Vec<AcpiDevice> {
+    namespace_devices()
+        .into_iter()
+        .map(|(_handle, device)| device)
+        .collect()
+}
+
+/// Finds the interrupt lines of PCI devices: the `_PRT` of every PCI root
+/// bridge, with link devices resolved to their current IRQ.
+pub fn pci_irq_routes() -> Vec<PciIrqRoute> {
+    let mut routes = Vec::new();
+    for (handle, device) in namespace_devices() {
+        if device.is_pci_root_bridge() {
+            let bus = unsafe { evaluate_integer(handle, b"_BBN\0") }.unwrap_or(0);
+            let found = unsafe { routing_table(handle, bus, &mut routes) };
+            debug!("{} routes {} PCI interrupts", device.path, found);
+        }
+    }
+    routes
+}
+
+fn namespace_devices() -> Vec<(ACPI_HANDLE, AcpiDevice)> {
+    unsafe extern "C" fn collect(
+        handle: ACPI_HANDLE,
+        _level: UINT32,
+        context: *mut c_void,
+        _ret: *mut *mut c_void,
+    ) -> ACPI_STATUS {
+        let devices = &mut *(context as *mut Vec<(ACPI_HANDLE, AcpiDevice)>);
+        devices.push((
+            handle,
+            AcpiDevice {
+                path: path(handle).unwrap_or_default(),
+                hid: hardware_id(handle),
+            },
+        ));
+        AE_OK
+    }
+
+    let mut devices: Vec<(ACPI_HANDLE, AcpiDevice)> = Vec::new();
+    let ret = unsafe {
+        AcpiWalkNamespace(
+            ACPI_TYPE_DEVICE,
+            ACPI_ROOT_OBJECT,
+            u32::max_value(),
+            Some(collect),
+            None,
+            &mut devices as *mut _ as *mut c_void,
+            ptr::null_mut(),
+        )
+    };
+    if ret != AE_OK {
+        error!("AcpiWalkNamespace failed {:?}", ret);
+    }
+    devices
+}
+
+unsafe fn path(handle: ACPI_HANDLE) -> Option<String> {
+    let mut buffer = ACPI_BUFFER {
+        Length: ACPI_ALLOCATE_BUFFER,
+        Pointer: ptr::null_mut(),
+    };
+    if AcpiGetName(handle, ACPI_FULL_PATHNAME, &mut buffer) != AE_OK {
+        return None;
+    }
+
+    let name = CStr::from_ptr(buffer.Pointer as *const _)
+        .to_str()
+        .ok()
+        .map(String::from);
+    AcpiOsFree(buffer.Pointer as *mut u8);
+    name
+}
+
+unsafe fn hardware_id(handle: ACPI_HANDLE) -> Option<String> {
+    let mut info: *mut ACPI_DEVICE_INFO = ptr::null_mut();
+    if AcpiGetObjectInfo(handle, &mut info) != AE_OK {
+        return None;
+    }
+
+    let hid = if u32::from((*info).Valid) & ACPI_VALID_HID != 0 {
+        CStr::from_ptr((*info).HardwareId.String as *const _)
+            .to_str()
+            .ok()
+            .map(String::from)
+    } else {
+        None
+    };
+    AcpiOsFree(info as *mut u8);
+    hid
+}
+
+/// Evaluates the integer object `name` (nul-terminated) of `handle`.
+unsafe fn evaluate_integer(handle: ACPI_HANDLE, name: &[u8]) -> Option<u64> {
+    let mut object: ACPI_OBJECT = core::mem::zeroed();
+    let mut buffer = ACPI_BUFFER {
+        Length: core::mem::size_of::<ACPI_OBJECT>() as ACPI_SIZE,
+        Pointer: &mut object as *mut _ as *mut c_void,
+    };
+    let ret = AcpiEvaluateObjectTyped(
+        handle,
+        name.as_ptr() as *mut _,
+        ptr::null_mut(),
+        &mut buffer,
+        ACPI_TYPE_INTEGER,
+    );
+    if ret == AE_OK {
+        Some(object.Integer.Value)
+    } else {
+        None
+    }
+}
+
+/// Adds the entries of the `_PRT` of the root bridge `bridge` (of `bus`)
+/// to `routes`, returns how many there were.
+unsafe fn routing_table(bridge: ACPI_HANDLE, bus: u64, routes: &mut Vec<PciIrqRoute>) -> usize {
+    let mut buffer = ACPI_BUFFER {
+        Length: ACPI_ALLOCATE_BUFFER,
+        Pointer: ptr::null_mut(),
+    };
+    if AcpiGetIrqRoutingTable(bridge, &mut buffer) != AE_OK {
+        return 0;
+    }
+
+    let mut found = 0;
+    let mut entry = buffer.Pointer as *const u8;
+    loop {
+        let prt = &*(entry as *const ACPI_PCI_ROUTING_TABLE);
+        if prt.Length == 0 {
+            break;
+        }
+
+        // Either hard-wired to a GSI or connected to a link device
+        let source = CStr::from_ptr(prt.Source.as_ptr() as *const _);
+        let gsi = if source.to_bytes().is_empty() {
+            Some(prt.SourceIndex as u64)
+        } else {
+            link_irq(bridge, source)
+        };
+        match gsi {
+            Some(gsi) => {
+                routes.push(PciIrqRoute {
+                    bus,
+                    device: prt.Address >> 16,
+                    pin: prt.Pin as u64,
+                    gsi,
+                });
+                found += 1;
+            }
+            None => warn!("Can't resolve PCI interrupt source {:?}", source),
+        }
+        entry = entry.add(prt.Length as usize);
+    }
+
+    AcpiOsFree(buffer.Pointer as *mut u8);
+    found
+}
+
+/// The IRQ a PCI interrupt link device (e.g., `LNKA`) is configured to.
+///
+/// We assume the IRQ isn't remapped by an interrupt source override (true
+/// for the IRQs link devices use).
+unsafe fn link_irq(scope: ACPI_HANDLE, source: &CStr) -> Option<u64> {
+    let mut link: ACPI_HANDLE = ptr::null_mut();
+    if AcpiGetHandle(scope, source.as_ptr() as *mut _, &mut link) != AE_OK {
+        return None;
+    }
+    let mut buffer = ACPI_BUFFER {
+        Length: ACPI_ALLOCATE_BUFFER,
+        Pointer: ptr::null_mut(),
+    };
+    if AcpiGetCurrentResources(link, &mut buffer) != AE_OK {
+        return None;
+    }
+
+    let mut irq = None;
+    let mut resource = buffer.Pointer as *const ACPI_RESOURCE;
+    while (*resource).Type != ACPI_RESOURCE_TYPE_END_TAG && (*resource).Length != 0 {
+        match (*resource).Type {
+            ACPI_RESOURCE_TYPE_IRQ if (*resource).Data.Irq.InterruptCount > 0 => {
+                irq = Some((*resource).Data.Irq.Interrupts[0] as u64);
+                break;
+            }
+            ACPI_RESOURCE_TYPE_EXTENDED_IRQ if (*resource).Data.ExtendedIrq.InterruptCount > 0 => {
+                irq = Some((*resource).Data.ExtendedIrq.Interrupts[0] as u64);
+                break;
+            }
+            _ => {}
+        }
+        resource = (resource as *const u8).add((*resource).Length as usize) as *const ACPI_RESOURCE;
+    }
+
+    AcpiOsFree(buffer.Pointer as *mut u8);
+    irq
+}
+
+/// Prepares the system to enter the sleep state `state` (e.g., 3 for S3).
+///
+/// `waking_vector` is the (real-mode) physical address the firmware jumps
+/// to when the system wakes up again.
+pub(crate) fn prepare_sleep(state: u8, waking_vector: u64) -> Result<(), ACPI_STATUS> {
+    unsafe {
+        let ret = AcpiSetFirmwareWakingVector(waking_vector, 0);
+        trace!("AcpiSetFirmwareWakingVector {:?}", ret);
+        if ret != AE_OK {
+            return Err(ret);
+        }
+
+        let ret = AcpiEnterSleepStatePrep(state);
+        trace!("AcpiEnterSleepStatePrep {:?}", ret);
+        if ret != AE_OK {
+            return Err(ret);
+        }
+    }
+
+    Ok(())
+}
+
+/// Puts the system to sleep, this only returns in case of an error.
+///
+/// # Safety
+/// Must be called with interrupts disabled after `prepare_sleep`, all
+/// other cores should be halted and caches flushed.
+pub(crate) unsafe fn enter_sleep(state: u8) -> ACPI_STATUS {
+    AcpiEnterSleepState(state)
+}
+
+/// Restores the ACPI state after we woke up from sleep state `state` (or
+/// failed to enter it).
+pub(crate) fn leave_sleep(state: u8) {
+    unsafe {
+        let ret = AcpiLeaveSleepStatePrep(state);
+        trace!("AcpiLeaveSleepStatePrep {:?}", ret);
+        let ret = AcpiLeaveSleepState(state);
+        trace!("AcpiLeaveSleepState {:?}", ret);
+    }
+}
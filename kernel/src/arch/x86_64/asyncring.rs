@@ -0,0 +1,205 @@
+//! Kernel side of the asynchronous system call rings (see `kpi::asyncio`).
+//!
+//! A process has at most one ring. We execute its submissions when the
+//! process is scheduled on a core (its address space is active then) and
+//! when it calls `AsyncOperation::Enter`. Submissions run through the
+//! regular system call handlers, in the order they were submitted.
+
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crossbeam_utils::CachePadded;
+use kpi::asyncio::{
+    completion_offset, ring_size, submission_offset, Completion, RingHeader, Submission,
+    MAX_RING_ENTRIES,
+};
+use kpi::SystemCallError;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::memory::vspace::Access;
+use crate::process::{Pid, MAX_ACCOUNTED_PROCESSES};
+
+use super::process::UserPtr;
+
+/// A registered ring.
+#[derive(Debug, Copy, Clone)]
+struct Ring {
+    base: u64,
+    entries: u64,
+}
+
+lazy_static! {
+    /// The ring of every process (indexed by pid).
+    static ref RINGS: Vec<CachePadded<Mutex<Option<Ring>>>> = {
+        let mut rings = Vec::with_capacity(MAX_ACCOUNTED_PROCESSES);
+        for _i in 0..MAX_ACCOUNTED_PROCESSES {
+            rings.push(Default::default());
+        }
+        rings
+    };
+}
+
+fn ring_slot(pid: Pid) -> Result<&'static Mutex<Option<Ring>>, KError> {
+    RINGS
+        .get(pid as usize)
+        .map(|slot| &**slot)
+        .ok_or(KError::NotSupported)
+}
+
+/// Registers the ring of process `pid` at `base` (removes it if `base` is 0).
+///
+/// Fails if the process already registered a ring.
+pub fn setup(pid: Pid, base: u64, entries: u64) -> Result<(u64, u64), KError> {
+    let slot = ring_slot(pid)?;
+    if base == 0 {
+        *slot.lock() = None;
+        return Ok((0, 0));
+    }
+
+    if entries == 0 || entries > MAX_RING_ENTRIES {
+        return Err(KError::NotSupported);
+    }
+    if base % core::mem::align_of::<RingHeader>() as u64 != 0 {
+        return Err(KError::BadAddress);
+    }
+    super::syscall::user_virt_addr_valid(pid, base, ring_size(entries) as u64, Access::Write)?;
+
+    let mut ring = slot.lock();
+    if ring.is_some() {
+        // Remove the old one first
+        return Err(KError::NotSupported);
+    }
+    *ring = Some(Ring { base, entries });
+    Ok((0, 0))
+}
+
+/// Forgets the ring of an exited process.
+pub fn unregister(pid: Pid) {
+    if let Ok(slot) = ring_slot(pid) {
+        *slot.lock() = None;
+    }
+}
+
+/// How many submissions we can execute, given the indices of a ring.
+///
+/// We stop when the completion queue is full, user-space has to make room
+/// first.
+fn runnable(
+    entries: u64,
+    sq_head: u64,
+    sq_tail: u64,
+    cq_head: u64,
+    cq_tail: u64,
+) -> Result<u64, KError> {
+    let submitted = sq_tail.wrapping_sub(sq_head);
+    let completed = cq_tail.wrapping_sub(cq_head);
+    if submitted > entries || completed > entries {
+        // User-space messed up the indices
+        return Err(KError::BadAddress);
+    }
+    Ok(core::cmp::min(submitted, entries - completed))
+}
+
+/// Executes the pending submissions in the ring of process `pid`, returns
+/// how many completed.
+///
+/// The address space of `pid` has to be active.
+pub fn poll(pid: Pid) -> Result<(u64, u64), KError> {
+    let slot = ring_slot(pid)?;
+    let mut ring = slot.lock();
+    let Ring { base, entries } = match *ring {
+        Some(r) => r,
+        None => return Ok((0, 0)),
+    };
+
+    // The process might have unmapped the ring in the meantime
+    if let Err(e) =
+        super::syscall::user_virt_addr_valid(pid, base, ring_size(entries) as u64, Access::Write)
+    {
+        *ring = None;
+        return Err(e);
+    }
+
+    // Every access to user memory goes through a `UserPtr` (which re-enables
+    // access): the system call handlers disable it again when they're done.
+    let header = UserPtr::new(base as *mut RingHeader);
+    let (sq_head, cq_tail) = (
+        header.sq_head.load(Ordering::Relaxed),
+        header.cq_tail.load(Ordering::Relaxed),
+    );
+    let n = runnable(
+        entries,
+        sq_head,
+        header.sq_tail.load(Ordering::Acquire),
+        header.cq_head.load(Ordering::Acquire),
+        cq_tail,
+    )?;
+
+    for i in 0..n {
+        let submission: Submission = *UserPtr::new(
+            (base + submission_offset(entries, sq_head.wrapping_add(i)) as u64) as *mut Submission,
+        );
+        header
+            .sq_head
+            .store(sq_head.wrapping_add(i + 1), Ordering::Release);
+
+        let completion = match super::syscall::handle_submission(&submission) {
+            Ok((a1, a2)) => Completion {
+                user_data: submission.user_data,
+                error: 0,
+                ret: [a1, a2],
+            },
+            Err(e) => {
+                let detail = e.detail();
+                let error: SystemCallError = e.into();
+                Completion {
+                    user_data: submission.user_data,
+                    error: error.with_detail(detail),
+                    ret: [0, 0],
+                }
+            }
+        };
+
+        let mut cqe = UserPtr::new(
+            (base + completion_offset(entries, cq_tail.wrapping_add(i)) as u64) as *mut Completion,
+        );
+        *cqe = completion;
+        header
+            .cq_tail
+            .store(cq_tail.wrapping_add(i + 1), Ordering::Release);
+    }
+
+    Ok((n, 0))
+}
+
+/// Executes the pending submissions of the process that runs on this core.
+pub fn poll_current() {
+    let kcb = super::kcb::get_kcb();
+    if let Ok(pid) = kcb.current_pid() {
+        if let Err(e) = poll(pid) {
+            warn!("Can't process the async ring of {}: {:?}", pid, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runnable_submissions() {
+        assert_eq!(runnable(4, 0, 0, 0, 0), Ok(0));
+        assert_eq!(runnable(4, 0, 3, 0, 0), Ok(3));
+        // Completion queue has room for one more
+        assert_eq!(runnable(4, 3, 7, 0, 3), Ok(1));
+        assert_eq!(runnable(4, 4, 8, 0, 4), Ok(0));
+        // Indices wrap around
+        assert_eq!(runnable(4, u64::MAX, 1, u64::MAX, u64::MAX), Ok(2));
+        // Bogus indices
+        assert_eq!(runnable(4, 0, 5, 0, 0), Err(KError::BadAddress));
+        assert_eq!(runnable(4, 1, 0, 0, 0), Err(KError::BadAddress));
+        assert_eq!(runnable(4, 0, 1, 0, 5), Err(KError::BadAddress));
+    }
+}
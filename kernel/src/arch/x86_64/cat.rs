@@ -0,0 +1,172 @@
+//! Cache allocation technology (Intel CAT): partitions the L3 cache by ways.
+//!
+//! Every class of service (CLOS) has a mask of the L3 ways it can allocate
+//! into. Processes (or, for processes that aren't in a class, the cores
+//! they run on) are put into classes with the `SystemOperation::CacheClass`
+//! and `SystemOperation::CoreCacheClass` system calls. A core applies the
+//! configuration when it dispatches an executor (see `dispatch`) and only
+//! touches the MSRs if something changed since the last time.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::process::Pid;
+
+use super::kcb::Arch86Kcb;
+
+/// Selects the class of service (bits 63:32) and the monitoring id (bits
+/// 9:0) of the core.
+pub(super) const IA32_PQR_ASSOC: u32 = 0xc8f;
+/// The monitoring id bits of `IA32_PQR_ASSOC` (see `mbm`).
+pub(super) const PQR_RMID_MASK: u64 = 0x3ff;
+/// Way mask of class of service 0 (the others follow).
+const IA32_L3_QOS_MASK_0: u32 = 0xc90;
+
+/// The configuration set up by user-space.
+struct Config {
+    /// Way masks of the classes of service that were set.
+    masks: Vec<Option<u64>>,
+    processes: HashMap<Pid, u8>,
+    cores: HashMap<topology::GlobalThreadId, u8>,
+}
+
+lazy_static! {
+    static ref CONFIG: Mutex<Config> = Mutex::new(Config {
+        masks: Vec::new(),
+        processes: HashMap::new(),
+        cores: HashMap::new(),
+    });
+}
+
+/// Incremented on every change of `CONFIG` (0 means CAT was never used).
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The number of classes of service and L3 ways (None if the machine doesn't
+/// have CAT).
+pub fn support() -> Option<(u64, u64)> {
+    let cpuid = CpuId::new();
+    let l3_cat = cpuid.get_rdt_allocation_info()?.l3_cat()?;
+    Some((
+        l3_cat.highest_cos() as u64 + 1,
+        l3_cat.capacity_mask_length() as u64,
+    ))
+}
+
+/// A mask needs at least one way, the ways have to be contiguous and exist.
+fn valid_mask(mask: u64, ways: u64) -> bool {
+    if mask == 0 {
+        return false;
+    }
+    let shifted = mask >> mask.trailing_zeros();
+    shifted & shifted.wrapping_add(1) == 0 && (ways >= 64 || mask >> ways == 0)
+}
+
+/// Sets the way `mask` of class of service `clos`.
+pub fn set_mask(clos: u64, mask: u64) -> Result<(), KError> {
+    let (classes, ways) = support().ok_or(KError::NotSupported)?;
+    if clos >= classes {
+        return Err(KError::InvalidSyscallArgument1 { a: clos });
+    }
+    if !valid_mask(mask, ways) {
+        return Err(KError::InvalidSyscallArgument1 { a: mask });
+    }
+
+    let mut config = CONFIG.lock();
+    if config.masks.len() < classes as usize {
+        config.masks.resize(classes as usize, None);
+    }
+    config.masks[clos as usize] = Some(mask);
+    GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
+/// Puts process `pid` into class of service `clos`.
+pub fn set_process_class(pid: Pid, clos: u64) -> Result<(), KError> {
+    let (classes, _ways) = support().ok_or(KError::NotSupported)?;
+    if clos >= classes {
+        return Err(KError::InvalidSyscallArgument1 { a: clos });
+    }
+
+    CONFIG.lock().processes.insert(pid, clos as u8);
+    GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
+/// Puts core `gtid` into class of service `clos`.
+pub fn set_core_class(gtid: topology::GlobalThreadId, clos: u64) -> Result<(), KError> {
+    let (classes, _ways) = support().ok_or(KError::NotSupported)?;
+    if clos >= classes {
+        return Err(KError::InvalidSyscallArgument1 { a: clos });
+    }
+    if gtid >= topology::MACHINE_TOPOLOGY.num_threads() {
+        return Err(KError::InvalidSyscallArgument1 { a: gtid as u64 });
+    }
+
+    CONFIG.lock().cores.insert(gtid, clos as u8);
+    GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
+/// Forgets the class of service of a process that exited.
+pub fn unregister(pid: Pid) {
+    if CONFIG.lock().processes.remove(&pid).is_some() {
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Programs the class of service of process `pid` (that we're about to run)
+/// on this core, in case the configuration or the process changed.
+pub fn dispatch(kcb: &mut Kcb<Arch86Kcb>, pid: Pid) {
+    let generation = GENERATION.load(Ordering::Acquire);
+    let (last_pid, last_generation) = kcb.arch.cache_class;
+    if generation == 0 || (last_pid, last_generation) == (pid, generation) {
+        return;
+    }
+    kcb.arch.cache_class = (pid, generation);
+
+    let config = CONFIG.lock();
+    unsafe {
+        // The masks are per package, all its cores write the same ones
+        if last_generation != generation {
+            for (clos, mask) in config.masks.iter().enumerate() {
+                if let Some(mask) = mask {
+                    wrmsr(IA32_L3_QOS_MASK_0 + clos as u32, *mask);
+                }
+            }
+        }
+
+        let clos = config
+            .processes
+            .get(&pid)
+            .or_else(|| config.cores.get(&kcb.arch.id()))
+            .copied()
+            .unwrap_or(0);
+        let rmid = rdmsr(IA32_PQR_ASSOC) & PQR_RMID_MASK;
+        wrmsr(IA32_PQR_ASSOC, (clos as u64) << 32 | rmid);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert!(valid_mask(0b1, 11));
+        assert!(valid_mask(0b111_1000, 11));
+        assert!(valid_mask(0x7ff, 11));
+        assert!(valid_mask(u64::max_value(), 64));
+
+        assert!(!valid_mask(0, 11));
+        assert!(!valid_mask(0b101, 11));
+        assert!(!valid_mask(0xfff, 11));
+    }
+}
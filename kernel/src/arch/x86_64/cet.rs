@@ -0,0 +1,219 @@
+//! Shadow stacks for user-space (Intel CET).
+//!
+//! An executor opts in with `ProcessOperation::EnableShadowStack`: we map a
+//! shadow stack for it into the process and turn shadow stacks on whenever
+//! the executor runs (see `dispatch`). The CPU then pushes return addresses
+//! on both stacks and raises a control protection exception (#CP) if a
+//! return doesn't match, which terminates the process (see
+//! `irq::cp_handler`).
+//!
+//! Only shadow stacks are supported (no indirect branch tracking) and only
+//! for user-space. The shadow stack pointer of user-space is in
+//! `IA32_PL3_SSP` while the kernel runs, so it's saved with the executor
+//! when it leaves the core (see `save`).
+//!
+//! Shadow stacks are enabled in the middle of a call chain, so the frames
+//! that were there before can't return anymore (their return addresses
+//! aren't on the shadow stack). Processes created with `fork` run without.
+//!
+//! There is one shadow stack per executor, so a runtime that switches
+//! between stacks in user-space has to switch shadow stacks too. None does
+//! yet: lineup (and so vibrio and everything that runs on top of it)
+//! switches threads without, and we don't hand out more than one shadow
+//! stack (or restore tokens to switch to it) per executor. Until then only
+//! code that never switches stacks can enable them.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::controlregs;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{Frame, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE};
+use crate::nr;
+use crate::process::{Eid, Pid};
+
+use super::kcb::Arch86Kcb;
+use super::process::Ring3Executor;
+
+/// User-mode CET configuration of the core.
+const IA32_U_CET: u32 = 0x6a0;
+/// Enables shadow stacks (in `IA32_U_CET`).
+const U_CET_SH_STK_EN: u64 = 0x1;
+/// Shadow stack pointer of user-space.
+const IA32_PL3_SSP: u32 = 0x6a7;
+/// Enables CET (CR4.CET).
+const CR4_CET: usize = 1 << 23;
+
+/// Where the shadow stacks of the executors are in a process.
+const SHADOW_STACK_OFFSET: u64 = 0x30_0000_0000;
+/// Size of a shadow stack (enough for 4096 nested calls).
+pub const SHADOW_STACK_SIZE: usize = 8 * BASE_PAGE_SIZE;
+/// Space of a shadow stack in the process, the page below the stack stays
+/// unmapped (so an overflow faults).
+const SHADOW_STACK_SPACE: usize = SHADOW_STACK_SIZE + BASE_PAGE_SIZE;
+
+/// Set once CET is enabled (on all cores).
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// The frames of the shadow stacks of every process (we give them back
+    /// once the process is gone).
+    static ref STACKS: Mutex<HashMap<Pid, Vec<Frame>>> = Mutex::new(HashMap::new());
+}
+
+/// Does the machine support shadow stacks?
+pub fn support() -> bool {
+    let features = x86::cpuid::cpuid!(0x7, 0x0);
+    features.ecx & (1 << 7) != 0
+}
+
+/// Enables CET on the core (if the machine has shadow stacks).
+///
+/// CET can only be enabled when the kernel respects read-only pages
+/// (CR0.WP), we leave it off otherwise.
+pub fn enable() {
+    if !support() {
+        return;
+    }
+    unsafe {
+        if !controlregs::cr0().contains(controlregs::Cr0::CR0_WRITE_PROTECT) {
+            return;
+        }
+        let cr4 = controlregs::cr4().bits() | CR4_CET;
+        controlregs::cr4_write(controlregs::Cr4::from_bits_unchecked(cr4));
+        wrmsr(IA32_U_CET, 0);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Are shadow stacks enabled?
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Base of the shadow stack of executor `eid`.
+fn shadow_stack_base(eid: Eid) -> VAddr {
+    VAddr::from(SHADOW_STACK_OFFSET + eid * SHADOW_STACK_SPACE as u64 + BASE_PAGE_SIZE as u64)
+}
+
+/// Maps a shadow stack for `executor` (of process `pid`) and turns it on,
+/// returns its base.
+///
+/// The shadow stack pointer starts at the top of it.
+pub fn allocate(pid: Pid, executor: &Arc<Ring3Executor>) -> Result<VAddr, KError> {
+    if !enabled() {
+        return Err(KError::NotSupported);
+    }
+    let base = shadow_stack_base(executor.eid);
+    if executor.ssp != 0 {
+        return Ok(base);
+    }
+
+    for offset in (0..SHADOW_STACK_SIZE).step_by(BASE_PAGE_SIZE) {
+        let frame = {
+            let kcb = super::kcb::get_kcb();
+            let mut frame = kcb.mem_manager().allocate_base_page()?;
+            unsafe { frame.zero() };
+            frame
+        };
+        STACKS
+            .lock()
+            .entry(pid)
+            .or_insert_with(Vec::new)
+            .push(frame);
+
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                replica
+                    .execute_mut(
+                        nr::Op::MemMapKernel(pid, base + offset, frame, MapAction::ShadowStackUser),
+                        *token,
+                    )
+                    .map(|_r| ())
+            })?;
+    }
+
+    // Safety: The executor is the one on this core (it made the system call)
+    unsafe { Ring3Executor::set_ssp(executor, (base + SHADOW_STACK_SIZE).as_u64()) };
+    dispatch(&mut super::kcb::get_kcb().arch, executor);
+    Ok(base)
+}
+
+/// Gives the shadow stacks of a process that exited back.
+pub fn unregister(pid: Pid) {
+    let frames = STACKS.lock().remove(&pid);
+    if let Some(frames) = frames {
+        let kcb = super::kcb::get_kcb();
+        let mut pmanager = kcb.mem_manager();
+        for frame in frames {
+            if let Err(e) = pmanager.release_base_page(frame) {
+                warn!("Can't release shadow stack frame {:?}: {:?}", frame, e);
+            }
+        }
+    }
+}
+
+/// The shadow stack pointer user-space had when it entered the kernel.
+pub fn user_ssp() -> u64 {
+    if enabled() {
+        unsafe { rdmsr(IA32_PL3_SSP) }
+    } else {
+        0
+    }
+}
+
+/// Remembers the shadow stack pointer of `executor` which leaves the core.
+pub fn save(arch: &mut Arch86Kcb, executor: &Arc<Ring3Executor>) {
+    if arch.ssp_owner == Some((executor.pid, executor.eid)) {
+        // Safety: The executor is the one on this core
+        unsafe { Ring3Executor::set_ssp(executor, user_ssp()) };
+        arch.ssp_owner = None;
+    }
+}
+
+/// Turns shadow stacks on (with the shadow stack of `executor`) or off (if
+/// the executor has none) for the executor that is dispatched on the core.
+///
+/// Nothing changes if the core still has the shadow stack pointer of the
+/// executor (it is newer than the one we saved).
+pub fn dispatch(arch: &mut Arch86Kcb, executor: &Ring3Executor) {
+    let owner = (executor.pid, executor.eid);
+    if !enabled() || arch.ssp_owner == Some(owner) {
+        return;
+    }
+    unsafe {
+        if executor.ssp != 0 {
+            wrmsr(IA32_PL3_SSP, executor.ssp);
+            wrmsr(IA32_U_CET, U_CET_SH_STK_EN);
+            arch.ssp_owner = Some(owner);
+        } else if rdmsr(IA32_U_CET) != 0 {
+            wrmsr(IA32_U_CET, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shadow_stacks_dont_overlap() {
+        let first = shadow_stack_base(0).as_u64();
+        let second = shadow_stack_base(1).as_u64();
+        assert_eq!(first, SHADOW_STACK_OFFSET + BASE_PAGE_SIZE as u64);
+        // A guard page between them
+        assert_eq!(
+            second - (first + SHADOW_STACK_SIZE as u64),
+            BASE_PAGE_SIZE as u64
+        );
+    }
+}
@@ -4,7 +4,7 @@
 //! make sure these two files are and stay in sync.
 
 use alloc::sync::Arc;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use apic::ApicDriver;
 use x86::apic::ApicId;
@@ -15,7 +15,7 @@ use crate::round_up;
 use crate::stack::Stack;
 
 use super::kcb;
-use super::memory::BASE_PAGE_SIZE;
+use super::memory::{paddr_to_kernel_vaddr, BASE_PAGE_SIZE};
 
 /// The 16-bit segement where our bootstrap code is.
 const X86_64_REAL_MODE_SEGMENT: u16 = 0x0600;
@@ -29,6 +29,102 @@ const REAL_MODE_LINEAR_OFFSET: u16 = X86_64_REAL_MODE_SEGMENT << 4;
 /// The corresponding 64-bit address (0 + offset in our case).
 const REAL_MODE_BASE: usize = REAL_MODE_LINEAR_OFFSET as usize;
 
+/// How far the app core that is currently booting got.
+///
+/// `start_ap.S` reports the stages up to `LongMode` (in
+/// `x86_64_init_ap_state`), the Rust entry path of the core the rest (see
+/// `set_boot_stage`). The BSP uses it to tell where a core got stuck.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u64)]
+pub enum BootStage {
+    /// The BSP sent the INIT and STARTUP IPIs.
+    Fired = 0,
+    /// The core executes the real-mode trampoline.
+    Trampoline = 1,
+    /// The core is in protected mode.
+    ProtectedMode = 2,
+    /// The core enabled paging and is in long mode.
+    LongMode = 3,
+    /// The core called the Rust entry function.
+    Entered = 4,
+    /// The core has a KCB and registered with its replica.
+    Registered = 5,
+}
+
+impl BootStage {
+    fn from_u64(stage: u64) -> Option<BootStage> {
+        match stage {
+            0 => Some(BootStage::Fired),
+            1 => Some(BootStage::Trampoline),
+            2 => Some(BootStage::ProtectedMode),
+            3 => Some(BootStage::LongMode),
+            4 => Some(BootStage::Entered),
+            5 => Some(BootStage::Registered),
+            _ => None,
+        }
+    }
+
+    /// What went wrong if a core doesn't get past this stage.
+    pub fn diagnosis(&self) -> &'static str {
+        match self {
+            BootStage::Fired => "never started to execute the trampoline (IPIs lost?)",
+            BootStage::Trampoline => "didn't make it to protected mode (bad GDT?)",
+            BootStage::ProtectedMode => "didn't make it to long mode (bad page-table?)",
+            BootStage::LongMode => "didn't call the entry function (bad stack or entry?)",
+            BootStage::Entered => "didn't finish initializing the kernel on the core",
+            BootStage::Registered => "never signaled that it's initialized",
+        }
+    }
+}
+
+/// Where the stage of the booting core is (a kernel address for the
+/// `x86_64_init_ap_state` word in the copy of the bootstrap code).
+fn boot_stage_pointer() -> *mut u64 {
+    extern "C" {
+        /// The boot stage of the core that is currently booting.
+        static x86_64_init_ap_state: *mut u64;
+    }
+
+    let (start_addr, _end_addr) = ap_code_address_range();
+    let offset = unsafe { &x86_64_init_ap_state as *const _ as u64 } - start_addr.as_u64();
+    let paddr = PAddr::from(REAL_MODE_BASE as u64 + offset);
+    paddr_to_kernel_vaddr(paddr).as_u64() as *mut u64
+}
+
+/// The stage the booting core reached (`None` if the stage is garbage).
+pub fn boot_stage() -> Option<BootStage> {
+    // Safe: the bootstrap region is reserved and always mapped
+    let stage = unsafe { core::ptr::read_volatile(boot_stage_pointer()) };
+    BootStage::from_u64(stage)
+}
+
+/// Records that the booting core reached `stage` (from the entry function).
+pub fn set_boot_stage(stage: BootStage) {
+    unsafe { core::ptr::write_volatile(boot_stage_pointer(), stage as u64) };
+}
+
+/// Waits until the core we booted signals it's `initialized` or `cycles` (of
+/// rdtsc) passed, returns the stage the core is stuck in then.
+pub fn wait_until_initialized(
+    initialized: &AtomicBool,
+    cycles: u64,
+) -> Result<(), Option<BootStage>> {
+    let timeout = unsafe { x86::time::rdtsc() } + cycles;
+    loop {
+        // Did the core signal us initialization completed?
+        if initialized.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Have we waited long enough?
+        if unsafe { x86::time::rdtsc() } > timeout {
+            return Err(boot_stage());
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
 /// Return the address range of `start_ap.S` as (start, end)
 ///
 /// # Note
@@ -64,14 +160,22 @@ fn get_boostrap_code_size() -> usize {
     boostrap_code_size.into()
 }
 
+/// The physical memory the bootstrap code is copied to (as base and size),
+/// it's kept away from the allocators (see `reserved`).
+pub fn bootstrap_region() -> (PAddr, usize) {
+    (
+        PAddr::from(REAL_MODE_BASE as u64),
+        round_up!(get_boostrap_code_size(), BASE_PAGE_SIZE),
+    )
+}
+
 /// Puts the bootstrap code at a well defined segement that an
 /// app core (booting in 16-bit mode can read from) (for us this is
 /// REAL_MODE_BASE).
 ///
 /// # Safety
-/// Let's hope noone else put something there (we should be ok
-/// by just excluding everything below 1 MiB from every being
-/// allocated).
+/// The region is reserved (see `bootstrap_region`) so the allocators never
+/// hand it out, but nothing stops firmware from having used it.
 unsafe fn copy_bootstrap_code() {
     let boot_code_size = get_boostrap_code_size();
 
@@ -172,6 +276,9 @@ unsafe fn setup_boostrap_code<A>(
     let ap_lock_pointer: *mut u64 = to_bootstrap_pointer(&x86_64_init_ap_lock as *const _ as u64);
     *ap_lock_pointer = &*initialized as *const _ as u64;
 
+    // The core reports how far it got here
+    set_boot_stage(BootStage::Fired);
+
     trace!(
         "x86_64_init_ap_absolute_entry is at {:p} and set to {:#x}",
         entry_pointer,
@@ -253,19 +360,21 @@ unsafe fn wakeup_core(core_id: ApicId) {
     kcb.arch.apic().ipi_startup(core_id, REAL_MODE_PAGE);
 }
 
-/// Starts up the core identified by `core_id`, after initialization it begins
-/// to executing in `init_function` and uses `stack` as a stack.
+/// Prepares the bootstrap code so a core that starts executing at the returned
+/// (real-mode) address begins to execute `init_function` using `stack`.
+///
+/// This is used to boot app cores (see `initialize`) but also as the ACPI
+/// firmware waking vector when we resume from suspend-to-RAM.
 ///
 /// # Safety
-/// You're waking up a core that goes off and does random things
-/// (if not being careful), so this can be pretty bad for memory safety.
-pub unsafe fn initialize<A>(
-    core_id: x86::apic::ApicId,
+/// Overwrites the bootstrap code region and its parameters, so no other
+/// core can be in the middle of booting.
+pub unsafe fn prepare<A>(
     init_function: fn(Arc<A>, &AtomicBool),
     args: Arc<A>,
     initialized: &AtomicBool,
     stack: &dyn Stack,
-) {
+) -> PAddr {
     // Make sure bootsrap code is at correct location in memory
     copy_bootstrap_code();
 
@@ -279,6 +388,39 @@ pub unsafe fn initialize<A>(
         stack.base() as u64,
     );
 
+    PAddr::from(REAL_MODE_BASE as u64)
+}
+
+/// Starts up the core identified by `core_id`, after initialization it begins
+/// to executing in `init_function` and uses `stack` as a stack.
+///
+/// # Safety
+/// You're waking up a core that goes off and does random things
+/// (if not being careful), so this can be pretty bad for memory safety.
+pub unsafe fn initialize<A>(
+    core_id: x86::apic::ApicId,
+    init_function: fn(Arc<A>, &AtomicBool),
+    args: Arc<A>,
+    initialized: &AtomicBool,
+    stack: &dyn Stack,
+) {
+    prepare(init_function, args, initialized, stack);
+
     // Send IPIs
     wakeup_core(core_id);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boot_stages() {
+        for raw in 0..=5 {
+            assert_eq!(BootStage::from_u64(raw).map(|s| s as u64), Some(raw));
+        }
+        assert_eq!(BootStage::from_u64(6), None);
+        assert_eq!(BootStage::from_u64(0xbeefbeefbeefbeef), None);
+        assert!(BootStage::Trampoline < BootStage::Registered);
+    }
+}
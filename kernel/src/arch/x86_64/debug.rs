@@ -39,6 +39,16 @@ pub unsafe fn getc() -> char {
     scancode as char
 }
 
+/// Read a byte from the input channel, returns None if nothing was received.
+pub unsafe fn try_getb() -> Option<u8> {
+    // Data ready bit in the line status register
+    if (io::inb(PORT1 + 5) & 0x1) != 0 {
+        Some(io::inb(PORT1))
+    } else {
+        None
+    }
+}
+
 /// Write a string to the output channel
 pub unsafe fn puts(s: &str) {
     for b in s.bytes() {
@@ -64,6 +74,10 @@ pub unsafe fn putb(b: u8) {
 /// Currently we only support the debug exit method from qemu, which conveniently
 /// allows us to supply an exit code for testing purposes.
 pub fn shutdown(val: ExitReason) -> ! {
+    // Don't lose what user-space printed last
+    super::printq::flush();
+    unsafe { super::printq::flush_unlocked() };
+
     unsafe {
         // For QEMU with debug-exit,iobase=0xf4,iosize=0x04
         // qemu will call: exit((val << 1) | 1);
@@ -0,0 +1,408 @@
+//! The devices of the machine and the kernel drivers bound to them.
+//!
+//! At boot, every device in the ACPI namespace and every function on the
+//! PCI bus is added to a registry ([`init`]). A driver built into the kernel
+//! (see `DRIVERS`) has a table of the ids it supports; for every device that
+//! matches an entry, the driver gets to probe it and if it wants the device
+//! it returns an instance that is attached right away.
+//!
+//! PCI devices that are bound to a kernel driver can't be claimed by
+//! user-space drivers (and devices a process claimed aren't bound).
+//!
+//! When a PCI function is removed from the bus (see `pci::check_removed`),
+//! its driver is told to stop using it ([`DeviceDriver::remove`]) and the
+//! device leaves the registry.
+//!
+//! Before the system suspends to RAM all attached drivers are suspended (in
+//! the reverse order they were added, so PCI functions go before the ACPI
+//! devices like the root bridges they are behind) and resumed again (in
+//! order) once it woke up.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::error::KError;
+
+use super::acpi::AcpiDevice;
+use super::pci::PciDevice;
+
+/// A device in the registry.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Device {
+    /// A function on the PCI bus.
+    Pci(PciDevice),
+    /// A device in the ACPI namespace.
+    Acpi(AcpiDevice),
+}
+
+impl Device {
+    /// Name of the device (for the PCI bus `bus:device.function`, for ACPI
+    /// the path in the namespace).
+    pub fn name(&self) -> String {
+        match self {
+            Device::Pci(d) => alloc::format!(
+                "{:02x}:{:02x}.{}",
+                d.address >> 8,
+                (d.address >> 3) & 0x1f,
+                d.address & 0x7
+            ),
+            Device::Acpi(d) => d.path.clone(),
+        }
+    }
+}
+
+/// An entry in the id table of a driver.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceId {
+    /// PCI functions with a vendor and device id.
+    Pci { vendor_id: u16, device_id: u16 },
+    /// PCI functions with a class code (class, subclass and programming
+    /// interface).
+    PciClass { class: u32 },
+    /// ACPI devices with a hardware id (e.g., `PNP0501`).
+    Acpi { hid: &'static str },
+}
+
+impl DeviceId {
+    /// Does `device` have this id?
+    pub fn matches(&self, device: &Device) -> bool {
+        match (self, device) {
+            (
+                DeviceId::Pci {
+                    vendor_id,
+                    device_id,
+                },
+                Device::Pci(d),
+            ) => d.vendor_id == *vendor_id && d.device_id == *device_id,
+            (DeviceId::PciClass { class }, Device::Pci(d)) => d.class == *class,
+            (DeviceId::Acpi { hid }, Device::Acpi(d)) => d.hid.as_deref() == Some(*hid),
+            _ => false,
+        }
+    }
+}
+
+/// A driver that is built into the kernel.
+pub trait Driver: Sync {
+    /// Name of the driver (for the logs).
+    fn name(&self) -> &'static str;
+
+    /// The devices the driver supports.
+    fn ids(&self) -> &'static [DeviceId];
+
+    /// Creates an instance of the driver for `device` (which matched one of
+    /// the `ids`), or `None` if the driver can't handle it after all.
+    fn probe(&self, device: &Device) -> Option<Box<dyn DeviceDriver>>;
+}
+
+/// An instance of a driver that drives a single device.
+pub trait DeviceDriver: Send {
+    /// Initializes the device, it is ours from now on.
+    fn attach(&mut self) -> Result<(), KError>;
+
+    /// Stops using the device.
+    fn detach(&mut self);
+
+    /// The device is gone (surprise removal), stops using it without
+    /// touching the hardware anymore.
+    fn remove(&mut self) {
+        self.detach();
+    }
+
+    /// Saves the state of the device before the system suspends to RAM.
+    fn suspend(&mut self) {}
+
+    /// Reinitializes the device after the system woke up again.
+    fn resume(&mut self) {}
+}
+
+/// The drivers built into the kernel (the order decides which driver gets a
+/// device if several match it).
+static DRIVERS: &[&dyn Driver] = &[];
+
+/// A driver bound to a device.
+struct Binding {
+    driver: &'static str,
+    instance: Box<dyn DeviceDriver>,
+}
+
+struct Entry {
+    device: Device,
+    binding: Option<Binding>,
+}
+
+/// All devices and their drivers.
+#[derive(Default)]
+struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    fn add(&mut self, device: Device) {
+        self.entries.push(Entry {
+            device,
+            binding: None,
+        });
+    }
+
+    /// Binds `drivers` to the devices that don't have a driver yet, returns
+    /// how many got one.
+    fn bind(&mut self, drivers: &[&'static dyn Driver]) -> usize {
+        let mut bound = 0;
+        for entry in self.entries.iter_mut().filter(|e| e.binding.is_none()) {
+            for driver in drivers {
+                if !driver.ids().iter().any(|id| id.matches(&entry.device)) {
+                    continue;
+                }
+                match attach(*driver, &entry.device) {
+                    Ok(Some(binding)) => {
+                        info!("Bound {} to {}", driver.name(), entry.device.name());
+                        entry.binding = Some(binding);
+                        bound += 1;
+                        break;
+                    }
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!(
+                            "{} can't attach to {}: {}",
+                            driver.name(),
+                            entry.device.name(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+        bound
+    }
+
+    /// Detaches the driver of the device called `name`.
+    fn unbind(&mut self, name: &str) -> Result<(), KError> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|e| e.device.name() == name)
+            .ok_or(KError::NotSupported)?;
+        if let Some(mut binding) = entry.binding.take() {
+            binding.instance.detach();
+            if let Device::Pci(d) = &entry.device {
+                super::pci::unreserve(d.address);
+            }
+            info!("Unbound {} from {}", binding.driver, name);
+        }
+        Ok(())
+    }
+
+    /// Drops the PCI function at `address` (after it was removed from the
+    /// bus).
+    fn remove_pci(&mut self, address: u64) {
+        let removed = self
+            .entries
+            .drain_filter(|e| matches!(&e.device, Device::Pci(d) if d.address == address));
+        for entry in removed {
+            if let Some(mut binding) = entry.binding {
+                binding.instance.remove();
+                info!("{} lost {}", binding.driver, entry.device.name());
+            }
+        }
+    }
+
+    fn suspend(&mut self) {
+        for binding in self
+            .entries
+            .iter_mut()
+            .rev()
+            .filter_map(|e| e.binding.as_mut())
+        {
+            binding.instance.suspend();
+        }
+    }
+
+    fn resume(&mut self) {
+        for binding in self.entries.iter_mut().filter_map(|e| e.binding.as_mut()) {
+            binding.instance.resume();
+        }
+    }
+}
+
+/// Lets `driver` probe `device` and attaches the instance it creates.
+fn attach(driver: &'static dyn Driver, device: &Device) -> Result<Option<Binding>, KError> {
+    let mut instance = match driver.probe(device) {
+        Some(instance) => instance,
+        None => return Ok(None),
+    };
+
+    // Reserve PCI functions before we touch them (fails if a process has
+    // claimed the function)
+    if let Device::Pci(d) = device {
+        super::pci::reserve(d.address)?;
+    }
+    if let Err(e) = instance.attach() {
+        if let Device::Pci(d) = device {
+            super::pci::unreserve(d.address);
+        }
+        return Err(e);
+    }
+
+    Ok(Some(Binding {
+        driver: driver.name(),
+        instance,
+    }))
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// Adds the devices of the ACPI namespace and the PCI bus to the registry
+/// and binds the kernel drivers (needs ACPI, alloc and `pci::enumerate`).
+pub fn init() {
+    let mut registry = REGISTRY.lock();
+    for device in super::acpi::devices() {
+        registry.add(Device::Acpi(device));
+    }
+    for device in super::pci::devices() {
+        registry.add(Device::Pci(device));
+    }
+
+    let bound = registry.bind(DRIVERS);
+    debug!(
+        "Found {} devices, {} have a kernel driver",
+        registry.entries.len(),
+        bound
+    );
+}
+
+/// The devices and the name of their driver (if they have one).
+pub fn devices() -> Vec<(String, Option<&'static str>)> {
+    REGISTRY
+        .lock()
+        .entries
+        .iter()
+        .map(|e| (e.device.name(), e.binding.as_ref().map(|b| b.driver)))
+        .collect()
+}
+
+/// Detaches the driver of the device called `name` (see [`Device::name`]).
+pub fn unbind(name: &str) -> Result<(), KError> {
+    REGISTRY.lock().unbind(name)
+}
+
+/// Forgets the PCI function at `address` that was removed from the bus.
+pub fn remove(address: u64) {
+    REGISTRY.lock().remove_pci(address);
+}
+
+/// Suspends all drivers (before the system suspends to RAM).
+pub fn suspend() {
+    REGISTRY.lock().suspend();
+}
+
+/// Resumes all drivers (after the system woke up).
+pub fn resume() {
+    REGISTRY.lock().resume();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static ATTACHED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Uart;
+
+    impl DeviceDriver for Uart {
+        fn attach(&mut self) -> Result<(), KError> {
+            ATTACHED.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn detach(&mut self) {
+            ATTACHED.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    struct UartDriver;
+
+    impl Driver for UartDriver {
+        fn name(&self) -> &'static str {
+            "uart"
+        }
+
+        fn ids(&self) -> &'static [DeviceId] {
+            &[DeviceId::Acpi { hid: "PNP0501" }]
+        }
+
+        fn probe(&self, _device: &Device) -> Option<Box<dyn DeviceDriver>> {
+            Some(Box::new(Uart))
+        }
+    }
+
+    fn acpi(path: &str, hid: Option<&str>) -> Device {
+        Device::Acpi(AcpiDevice {
+            path: path.to_string(),
+            hid: hid.map(|h| h.to_string()),
+        })
+    }
+
+    fn pci(address: u64) -> Device {
+        Device::Pci(PciDevice {
+            address,
+            vendor_id: 0x8086,
+            device_id: 0x100e,
+            class: 0x020000,
+            bars: [None; super::super::pci::MAX_BARS],
+            owner: None,
+            reserved: false,
+        })
+    }
+
+    #[test]
+    fn ids_match() {
+        let nic = pci(0x18);
+        let e1000 = DeviceId::Pci {
+            vendor_id: 0x8086,
+            device_id: 0x100e,
+        };
+        assert!(e1000.matches(&nic));
+        assert!(DeviceId::PciClass { class: 0x020000 }.matches(&nic));
+        assert!(!DeviceId::PciClass { class: 0x010601 }.matches(&nic));
+        assert!(!DeviceId::Acpi { hid: "PNP0501" }.matches(&nic));
+        assert!(DeviceId::Acpi { hid: "PNP0501" }.matches(&acpi("\\_SB_.COM1", Some("PNP0501"))));
+        assert!(!DeviceId::Acpi { hid: "PNP0501" }.matches(&acpi("\\_SB_.PCI0", None)));
+        assert_eq!(nic.name(), "00:03.0");
+    }
+
+    #[test]
+    fn bind_and_unbind() {
+        let mut registry = Registry::default();
+        registry.add(acpi("\\_SB_.PCI0", Some("PNP0A03")));
+        registry.add(acpi("\\_SB_.COM1", Some("PNP0501")));
+
+        assert_eq!(registry.bind(&[&UartDriver]), 1);
+        assert_eq!(ATTACHED.load(Ordering::Relaxed), 1);
+        // Devices are only bound once
+        assert_eq!(registry.bind(&[&UartDriver]), 0);
+
+        assert!(registry.unbind("\\_SB_.COM1").is_ok());
+        assert_eq!(ATTACHED.load(Ordering::Relaxed), 0);
+        assert!(registry.unbind("\\_SB_.COM2").is_err());
+    }
+
+    #[test]
+    fn remove_function() {
+        let mut registry = Registry::default();
+        registry.add(acpi("\\_SB_.PCI0", Some("PNP0A03")));
+        registry.add(pci(0x18));
+        registry.add(pci(0x20));
+
+        registry.remove_pci(0x18);
+        let names: Vec<String> = registry.entries.iter().map(|e| e.device.name()).collect();
+        assert_eq!(names, ["\\_SB_.PCI0", "00:04.0"]);
+    }
+}
@@ -0,0 +1,319 @@
+//! Kernel side of the event rings (see `kpi::eventring`).
+//!
+//! Events are produced in places where we can't allocate (e.g., the logger
+//! runs before we have a heap), so the rings are in a fixed-size table. We
+//! never allocate or log while we hold the table, anything that does (like
+//! mapping a ring) happens after we reserved a slot. The kernel writes a ring
+//! through the kernel alias of its frame, the process has it mapped
+//! read-only.
+
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kpi::eventring::{write_event, EventRingHeader, EventTopics};
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
+use crate::memory::vspace::MapAction;
+use crate::memory::{Frame, VAddr, LARGE_PAGE_SIZE};
+use crate::nr;
+use crate::process::{Eid, Pid};
+
+use super::kmsg::Truncate;
+
+/// How many processes can have an event ring at the same time.
+const MAX_EVENT_RINGS: usize = 4;
+
+/// The event ring of a process.
+#[derive(Copy, Clone)]
+struct Ring {
+    pid: Pid,
+    /// Where the process has the ring mapped.
+    base: VAddr,
+    /// Empty until the ring is mapped.
+    topics: EventTopics,
+    /// The process wants an upcall while there are events from this index
+    /// on (see `arm`).
+    doorbell: Option<u64>,
+}
+
+/// An entry in the ring table.
+#[derive(Copy, Clone)]
+struct Slot {
+    ring: Option<Ring>,
+    /// Memory of the ring. We keep it when the ring is removed (we can't tell
+    /// when the last core stopped using the old mapping) and use it for the
+    /// next ring in this slot.
+    frame: Option<Frame>,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        ring: None,
+        frame: None,
+    };
+
+    /// The ring if it is mapped, together with its memory.
+    fn mapped(&self) -> Option<(Ring, Frame)> {
+        match (self.ring, self.frame) {
+            (Some(ring), Some(frame)) if !ring.topics.is_empty() => Some((ring, frame)),
+            _ => None,
+        }
+    }
+}
+
+static RINGS: Mutex<[Slot; MAX_EVENT_RINGS]> = Mutex::new([Slot::EMPTY; MAX_EVENT_RINGS]);
+
+/// Topics of all rings, producers can return early if nobody wants theirs.
+static TOPICS: AtomicU64 = AtomicU64::new(0);
+
+fn update_topics(slots: &[Slot]) {
+    let topics = slots
+        .iter()
+        .filter_map(|slot| slot.ring)
+        .fold(EventTopics::empty(), |topics, ring| topics | ring.topics);
+    TOPICS.store(topics.bits(), Ordering::Relaxed);
+}
+
+/// Next event the kernel writes into the ring in `frame`.
+fn tail(frame: &Frame) -> u64 {
+    let header = unsafe { &*frame.kernel_vaddr().as_ptr::<EventRingHeader>() };
+    header.tail.load(Ordering::Acquire)
+}
+
+fn allocate_frame() -> Result<Frame, KError> {
+    let kcb = super::kcb::get_kcb();
+    let gmanager = kcb
+        .physical_memory
+        .gmanager
+        .ok_or(KError::GlobalMemoryNotSet)?;
+    let mut ncache = gmanager.node_caches[kcb.node].lock();
+    Ok(ncache.allocate_large_page()?)
+}
+
+/// Maps the (zeroed) ring memory into process `pid` at `base`, allocates the
+/// memory if `frame` is None.
+fn map_ring(pid: Pid, base: VAddr, frame: Option<Frame>) -> (Option<Frame>, Result<(), KError>) {
+    let frame = match frame.map_or_else(allocate_frame, Ok) {
+        Ok(frame) => frame,
+        Err(e) => return (None, Err(e)),
+    };
+    unsafe {
+        ptr::write_bytes(frame.kernel_vaddr().as_mut_ptr::<u8>(), 0, frame.size());
+    }
+
+    let kcb = super::kcb::get_kcb();
+    let r = kcb
+        .replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            replica
+                .execute_mut(
+                    nr::Op::MemMapKernel(pid, base, frame, MapAction::ReadUser),
+                    *token,
+                )
+                .map(|_r| ())
+        });
+    (Some(frame), r)
+}
+
+/// Maps an event ring for `topics` into process `pid` at `base` (removes it
+/// if `base` is 0).
+///
+/// Fails if the process already has a ring.
+pub fn open(pid: Pid, base: u64, topics: u64) -> Result<(u64, u64), KError> {
+    if base == 0 {
+        close(pid);
+        return Ok((0, 0));
+    }
+
+    let topics = EventTopics::from_bits(topics).ok_or(KError::NotSupported)?;
+    if topics.is_empty() {
+        return Err(KError::NotSupported);
+    }
+    if base % LARGE_PAGE_SIZE as u64 != 0 {
+        return Err(KError::BadAddress);
+    }
+    let base = VAddr::from(base);
+
+    // Reserve a slot (producers skip it until it has topics)
+    let (idx, frame) = {
+        let mut slots = RINGS.lock();
+        if slots
+            .iter()
+            .any(|slot| slot.ring.map_or(false, |r| r.pid == pid))
+        {
+            // Remove the old one first
+            return Err(KError::NotSupported);
+        }
+        let idx = slots
+            .iter()
+            .position(|slot| slot.ring.is_none())
+            .ok_or(KError::NotSupported)?;
+        slots[idx].ring = Some(Ring {
+            pid,
+            base,
+            topics: EventTopics::empty(),
+            doorbell: None,
+        });
+        (idx, slots[idx].frame.take())
+    };
+
+    let (frame, r) = map_ring(pid, base, frame);
+
+    let mut slots = RINGS.lock();
+    slots[idx].frame = frame;
+    match r {
+        Ok(()) => {
+            if let Some(ring) = slots[idx].ring.as_mut() {
+                ring.topics = topics;
+            }
+            update_topics(&*slots);
+            Ok((0, 0))
+        }
+        Err(e) => {
+            slots[idx].ring = None;
+            Err(e)
+        }
+    }
+}
+
+/// Removes the ring of process `pid` from the table, returns where it was
+/// mapped.
+fn remove(pid: Pid) -> Option<VAddr> {
+    let mut slots = RINGS.lock();
+    let slot = slots
+        .iter_mut()
+        .find(|slot| slot.ring.map_or(false, |r| r.pid == pid))?;
+    let ring = slot.ring.take();
+    update_topics(&*slots);
+    ring.map(|r| r.base)
+}
+
+/// Removes the ring of process `pid` and unmaps it.
+fn close(pid: Pid) {
+    if let Some(base) = remove(pid) {
+        let kcb = super::kcb::get_kcb();
+        let response = kcb
+            .replica
+            .as_ref()
+            .map(|(replica, token)| replica.execute_mut(nr::Op::MemUnmap(pid, base), *token));
+        // The process might have unmapped it already
+        if let Some(Ok(nr::NodeResult::Unmapped(handle))) = response {
+            super::tlb::shootdown(handle);
+        }
+    }
+}
+
+/// Forgets the ring of an exited process.
+pub fn unregister(pid: Pid) {
+    let _r = remove(pid);
+}
+
+/// Arms the doorbell of the ring of process `pid`: it gets upcalls while the
+/// ring has events from index `seen` on (`u64::MAX` disarms it).
+///
+/// Returns the tail of the ring.
+pub fn arm(pid: Pid, seen: u64) -> Result<(u64, u64), KError> {
+    let mut slots = RINGS.lock();
+    let slot = slots
+        .iter_mut()
+        .find(|slot| slot.mapped().map_or(false, |(r, _f)| r.pid == pid))
+        .ok_or(KError::NotSupported)?;
+
+    let tail = slot.frame.as_ref().map_or(0, tail);
+    if let Some(ring) = slot.ring.as_mut() {
+        ring.doorbell = if seen == u64::max_value() {
+            None
+        } else {
+            Some(seen)
+        };
+    }
+    Ok((tail, 0))
+}
+
+/// Sends an `upcall::EVENT_RING` upcall to every process that has events in
+/// its ring it asked for (see `arm`).
+///
+/// Called periodically (on timer interrupts of the first core).
+pub fn ring_doorbells() {
+    if TOPICS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let mut pending: [Option<(Pid, u64)>; MAX_EVENT_RINGS] = [None; MAX_EVENT_RINGS];
+    for (slot, pending) in RINGS.lock().iter().zip(pending.iter_mut()) {
+        if let Some((ring, frame)) = slot.mapped() {
+            let tail = tail(&frame);
+            if ring.doorbell.map_or(false, |seen| tail > seen) {
+                *pending = Some((ring.pid, tail));
+            }
+        }
+    }
+
+    let kcb = super::kcb::get_kcb();
+    for (pid, tail) in pending.iter().flatten() {
+        let response = kcb
+            .replica
+            .as_ref()
+            .map(|(replica, token)| replica.execute(nr::ReadOps::ProcessCores(*pid), *token));
+        if let Some(Ok(nr::NodeResult::Cores(_policy, cores))) = response {
+            if let Some(gtid) = cores.first() {
+                super::tlb::activate(*gtid, *pid, kpi::upcall::EVENT_RING, *tail);
+            }
+        }
+    }
+}
+
+/// Appends an event to every ring that wants `topic`.
+fn publish(topic: EventTopics, args: [u64; 2], data: fmt::Arguments) {
+    if TOPICS.load(Ordering::Relaxed) & topic.bits() == 0 {
+        return;
+    }
+
+    let timestamp = rawtime::duration_since_boot().as_nanos() as u64;
+    let core = super::kcb::try_get_kcb().map_or(0, |kcb| kcb.arch.hwthread_id());
+    for (ring, frame) in RINGS.lock().iter().filter_map(Slot::mapped) {
+        if !ring.topics.contains(topic) {
+            continue;
+        }
+        unsafe {
+            write_event(frame.kernel_vaddr().as_mut_ptr::<u8>(), |event| {
+                event.topic = topic.bits();
+                event.timestamp = timestamp;
+                event.core = core;
+                event.args = args;
+
+                let mut w = Truncate::new(&mut event.data);
+                let _r = w.write_fmt(data);
+                event.len = w.len as u64;
+            });
+        }
+    }
+}
+
+/// Publishes a kernel log record (`EventTopics::LOG`).
+pub fn log(level: log::Level, module: &str, message: &fmt::Arguments) {
+    publish(
+        EventTopics::LOG,
+        [level as u64, 0],
+        format_args!("{}: {}", module, message),
+    );
+}
+
+/// Publishes that executor `eid` of process `pid` got dispatched on this
+/// core (`EventTopics::SCHEDULER`).
+pub fn dispatched(pid: Pid, eid: Eid) {
+    publish(EventTopics::SCHEDULER, [pid, eid], format_args!(""));
+}
+
+/// Publishes an operation a replica dispatched (`EventTopics::TRACE`).
+pub fn traced(class: u8, op: u16, cycles: u64) {
+    publish(
+        EventTopics::TRACE,
+        [((class as u64) << 32) | op as u64, cycles],
+        format_args!(""),
+    );
+}
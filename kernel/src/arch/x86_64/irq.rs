@@ -33,6 +33,8 @@
 use core::fmt;
 
 use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 
 use x86::bits64::segmentation::Descriptor64;
 use x86::dtables;
@@ -43,20 +45,24 @@ use x86::segmentation::{
 use x86::Ring;
 
 use apic::ApicDriver;
+use kpi::process::SchedulingPolicy;
 use log::debug;
 
+use crate::error::KError;
+use crate::kcb::ArchSpecificKcb;
 use crate::memory::{vspace::MapAction, Frame};
 use crate::mlnr;
 use crate::nr;
 use crate::panic::{backtrace, backtrace_from};
-use crate::process::{Executor, ResumeHandle};
+use crate::process::{Executor, Pid, ResumeHandle};
+use crate::scheduler::fair::ExecutorKey;
 use crate::ExitReason;
 
 use super::debug;
 use super::gdt::GdtTable;
 use super::kcb::{get_kcb, Arch86Kcb};
 use super::memory::{PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE};
-use super::process::{Ring3Process, Ring3Resumer};
+use super::process::{Ring3Executor, Ring3Process, Ring3Resumer};
 use super::timer;
 
 /// A macro to initialize an entry in an IDT table.
@@ -105,6 +111,9 @@ pub const TLB_WORK_PENDING: u8 = 251;
 /// The IDT entry for handling GC in mlnr.
 pub const MLNR_GC_INIT: u8 = 250;
 
+/// Vectors we send as IPIs (and for which we measure the delivery latency).
+pub const IPI_VECTORS: [u8; 2] = [TLB_WORK_PENDING, MLNR_GC_INIT];
+
 /// The IDT table can hold a maximum of 256 entries.
 pub const IDT_SIZE: usize = 256;
 
@@ -151,6 +160,7 @@ impl Default for IdtTable {
         idt_set!(table.0, 18, isr_handler_early18, 1);
         idt_set!(table.0, 19, isr_handler19, 0);
         idt_set!(table.0, 20, isr_handler20, 0);
+        idt_set!(table.0, 21, isr_handler21, 0);
         idt_set!(table.0, 30, isr_handler30, 0);
 
         // PIC interrupts:
@@ -206,6 +216,7 @@ impl IdtTable {
         idt_set!(table.0, 18, isr_handler_early18, 0);
         idt_set!(table.0, 19, isr_handler_early19, 0);
         idt_set!(table.0, 20, isr_handler_early20, 0);
+        idt_set!(table.0, 21, isr_handler_early21, 0);
         idt_set!(table.0, 30, isr_handler_early30, 0);
 
         idt_set!(table.0, TLB_WORK_PENDING as usize, isr_handler_early251, 0);
@@ -296,7 +307,13 @@ unsafe fn unhandled_irq(a: &ExceptionArguments) {
     debug::shutdown(ExitReason::UnhandledInterrupt);
 }
 
-/// Handler for unexpected page-faults.
+/// Handler for page-faults.
+///
+/// Writes to copy-on-write pages (after a fork) and spurious faults of
+/// user-space are resolved (a process we can't copy a page for is
+/// terminated), other faults of user-space are forwarded to the
+/// process if it can handle them (see `forward_page_fault`), everything else
+/// is unexpected.
 ///
 /// TODO: Right now we terminate kernel.
 /// Should abort process and resume.
@@ -314,14 +331,42 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
             .current_pid()
             .expect("A pid must be set in this if branch (US bit set in page-fault error)");
 
-        match nr::KernelNode::<Ring3Process>::resolve(pid, faulting_address_va) {
-            Ok(_) => {
-                // Spurious page-fault, after resolve page-table is up to date
-                let r = kcb_iret_handle(kcb);
-                r.resume()
+        let mut cow_failed = false;
+        if err.contains(PageFaultError::P | PageFaultError::WR) {
+            match super::process::resolve_cow(pid, faulting_address_va) {
+                Ok(true) => {
+                    // The page is writable now, try again
+                    let r = kcb_iret_handle(kcb);
+                    r.resume()
+                }
+                Ok(false) => { /* Not copy-on-write, check below */ }
+                Err(e) => {
+                    // Resuming would only fault again, the process can't go on
+                    error!("Can't resolve copy-on-write fault of {}: {}", pid, e);
+                    let code = kpi::process::PAGE_FAULT_EXIT_CODE;
+                    if let Err(e) = super::syscall::process_exit(code) {
+                        error!("Can't terminate process {}: {}", pid, e);
+                    }
+                    // Abort below
+                    cow_failed = true;
+                }
             }
-            Err(_) => {
-                // unresolved page-fault, proceed with abort below
+        }
+
+        if !cow_failed {
+            match nr::KernelNode::<Ring3Process>::resolve(pid, faulting_address_va) {
+                Ok(_) => {
+                    // Spurious page-fault, after resolve page-table is up to date
+                    let r = kcb_iret_handle(kcb);
+                    r.resume()
+                }
+                Err(_) => {
+                    // Let the process handle it (e.g., a thread ran into the
+                    // guard page of its stack), or abort below
+                    if let Some(r) = forward_page_fault(kcb, a.rip, faulting_address) {
+                        r.resume()
+                    }
+                }
             }
         }
     }
@@ -384,6 +429,29 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
     debug::shutdown(ExitReason::PageFault);
 }
 
+/// Forwards a page-fault of user-space on `faulting_address` that we can't
+/// resolve to the process (`kpi::upcall::PAGE_FAULT`).
+///
+/// Returns `None` if the process has no upcall handler or faulted while it
+/// had upcalls disabled.
+unsafe fn forward_page_fault(
+    kcb: &crate::kcb::Kcb<Arch86Kcb>,
+    rip: u64,
+    faulting_address: u64,
+) -> Option<Ring3Resumer> {
+    let p = kcb.arch.current_process().ok()?;
+    let no_handler = p.vcpu().resume_with_upcall == VAddr::zero();
+    if no_handler || p.vcpu().upcalls_disabled(VAddr::from(rip)) {
+        return None;
+    }
+
+    p.vcpu().disable_upcalls();
+    kcb.arch.save_area.as_ref().map(|sa| {
+        p.vcpu().enabled_state = **sa;
+    });
+    Some(p.upcall(kpi::upcall::PAGE_FAULT, faulting_address))
+}
+
 /// Handler for a debug exception.
 ///
 /// The default behavior right now is just to print a warning and resume
@@ -398,10 +466,358 @@ unsafe fn dbg_handler(a: &ExceptionArguments) {
     r.resume()
 }
 
+/// Vector of the control protection exception (#CP).
+const CONTROL_PROTECTION_VECTOR: u64 = 21;
+
+/// What went wrong, according to the error code of a #CP.
+fn control_protection_reason(code: u64) -> &'static str {
+    match code & 0x7fff {
+        1 => "near return doesn't match the shadow stack",
+        2 => "far return or iret doesn't match the shadow stack",
+        3 => "missing endbranch",
+        4 => "invalid shadow stack restore token",
+        5 => "shadow stack token is busy",
+        _ => "unknown reason",
+    }
+}
+
+/// Handler for a control protection exception (see `cet`).
+///
+/// A process whose shadow stack didn't match a return gets terminated (with
+/// `kpi::process::CONTROL_PROTECTION_EXIT_CODE`), the kernel doesn't run
+/// with shadow stacks so anything else is unexpected.
+unsafe fn cp_handler(a: &ExceptionArguments) {
+    let kcb = get_kcb();
+    let from_user = a.cs & 0x3 == 0x3;
+    let executor = kcb.arch.current_process();
+
+    sprintln!(
+        "[IRQ] CONTROL PROTECTION FAULT on {}: {} (error {:#x})",
+        topology::MACHINE_TOPOLOGY.current_thread().id,
+        control_protection_reason(a.exception),
+        a.exception
+    );
+    sprintln!(
+        "Instruction Pointer: {:#x} Stack: {:#x} Shadow stack: {:#x}",
+        a.rip,
+        a.rsp,
+        super::cet::user_ssp()
+    );
+
+    match executor {
+        Ok(executor) if from_user && executor.pid != crate::process::INIT_PID => {
+            let pid = executor.pid;
+            sprintln!("Terminating process {} (executor {})", pid, executor.eid);
+            drop(executor);
+
+            // Its page-tables are about to be given back, get off them
+            let pml4 = kcb.arch.init_vspace().pml4_address();
+            x86::controlregs::cr3_write(pml4.into());
+            match nr::KernelNode::<Ring3Process>::exit(
+                pid,
+                kpi::process::CONTROL_PROTECTION_EXIT_CODE,
+            )
+            .and_then(|released| super::process::release_exited(&[pid], &released))
+            {
+                Ok(()) => leave_exited_executor(kcb),
+                Err(e) => error!("Can't terminate process {}: {}", pid, e),
+            }
+        }
+        _ => {}
+    }
+
+    sprintln!("{:?}", a);
+    debug::shutdown(ExitReason::UnhandledInterrupt);
+}
+
+/// Tells the process of `preempted` that we took this core away from it
+/// (scheduler activations).
+///
+/// Marks the vCPU of the executor as preempted and sends a
+/// `kpi::upcall::CORE_PREEMPTED` upcall to another core of the process, so
+/// it can take over the threads that were queued on this core. Gang-scheduled
+/// processes are not notified as they lose all their cores at once.
+unsafe fn notify_preemption(kcb: &crate::kcb::Kcb<Arch86Kcb>, preempted: &Ring3Executor) {
+    (*preempted.vcpu_kernel()).preempted = true;
+
+    let my_gtid = kcb.arch.hwthread_id();
+    let response = kcb
+        .replica
+        .as_ref()
+        .map(|(replica, token)| replica.execute(nr::ReadOps::ProcessCores(preempted.pid), *token));
+    if let Some(Ok(nr::NodeResult::Cores(SchedulingPolicy::Fair, cores))) = response {
+        if let Some(gtid) = cores.iter().find(|gtid| **gtid != my_gtid) {
+            super::tlb::activate(
+                *gtid,
+                preempted.pid,
+                kpi::upcall::CORE_PREEMPTED,
+                my_gtid as u64,
+            );
+        }
+    }
+}
+
+/// Sends an `event` upcall (see `kpi::upcall`) with `arg` to every process
+/// that subscribed to it (on one of its cores).
+///
+/// Best-effort, like all upcalls: processes that don't run right now or
+/// have upcalls disabled miss it.
+pub(super) fn notify_subscribers(event: u64, arg: u64) {
+    match nr::KernelNode::<Ring3Process>::subscribers(event) {
+        Ok(subscribers) => {
+            for (pid, gtid) in subscribers {
+                super::tlb::activate(gtid, pid, event, arg);
+            }
+        }
+        Err(e) => warn!("Can't notify subscribers of event {:#x}: {:?}", event, e),
+    }
+}
+
+/// Sends an `event` upcall (see `kpi::upcall`) with `arg` to process `pid`
+/// (if it subscribed to it).
+pub(super) fn notify_process(pid: Pid, event: u64, arg: u64) {
+    match nr::KernelNode::<Ring3Process>::subscribers(event) {
+        Ok(subscribers) => {
+            if let Some((pid, gtid)) = subscribers.into_iter().find(|(p, _gtid)| *p == pid) {
+                super::tlb::activate(gtid, pid, event, arg);
+            }
+        }
+        Err(e) => warn!(
+            "Can't notify process {} of event {:#x}: {:?}",
+            pid, event, e
+        ),
+    }
+}
+
+/// Delivers a scheduler activation (see `notify_preemption`) to the
+/// executor that runs on this core.
+///
+/// Activations for other processes are dropped (the core was time-sliced
+/// in the meantime). If the executor has upcalls disabled we only set
+/// `has_pending_upcall` in its vCPU area.
+unsafe fn deliver_activation(
+    kcb: &crate::kcb::Kcb<Arch86Kcb>,
+    pid: Pid,
+    cmd: u64,
+    arg: u64,
+) -> Ring3Resumer {
+    let p = kcb.arch.current_process().expect("Need a process");
+    if p.pid != pid {
+        return kcb_iret_handle(kcb);
+    }
+
+    let rip = kcb.arch.save_area.as_ref().map_or(0, |sa| sa.rip);
+    if p.vcpu().upcalls_disabled(VAddr::from(rip)) {
+        p.vcpu().has_pending_upcall = true;
+        kcb_iret_handle(kcb)
+    } else {
+        p.vcpu().disable_upcalls();
+        kcb.arch.save_area.as_ref().map(|sa| {
+            p.vcpu().enabled_state = **sa;
+        });
+        p.upcall(cmd, arg)
+    }
+}
+
+/// Picks the executor that should run next on this core.
+///
+/// If multiple executors are assigned to the core they are time-sliced
+/// fairly (see `scheduler::fair`). Saves the interrupted context of the
+/// current executor if we switch to a different one.
+///
+/// If we switch to a gang-scheduled process on our own accord, we ask the
+/// other cores of the process to switch to it too.
+unsafe fn time_slice(kcb: &mut crate::kcb::Kcb<Arch86Kcb>, now: u64) -> Ring3Resumer {
+    let executors = core_executors(kcb);
+
+    let current = kcb.arch.current_process().expect("Need a process");
+    if !executors.iter().any(|e| Arc::ptr_eq(e, &current)) {
+        // The process exited on another core
+        leave_exited_executor(kcb);
+    }
+    let is_gang_request = kcb.arch.fair.has_request();
+    sync_run_queue(kcb, &executors);
+    if kcb.arch.fair.current() != Some((current.pid, current.eid)) {
+        kcb.arch.fair.dispatch((current.pid, current.eid), now);
+    }
+    kcb.arch.fair.charge(now);
+
+    let next = kcb
+        .arch
+        .fair
+        .pick_next()
+        .and_then(|key| executors.iter().find(|e| (e.pid, e.eid) == key));
+    let resumer = match next {
+        Some(next) if kcb.arch.fair.expired(now) && Arc::ptr_eq(next, &current) => {
+            // Nobody else is more deserving, start a new time slice
+            kcb.arch.fair.dispatch((current.pid, current.eid), now);
+            kcb_iret_handle(kcb)
+        }
+        Some(next) if kcb.arch.fair.expired(now) => {
+            trace!(
+                "time_slice: switch from {:?} to {:?}",
+                current.eid,
+                next.eid
+            );
+            // The interrupted context lives in the core save-area, move it
+            // to the executor so we can continue with it later:
+            kcb.arch.save_area.as_ref().map(|sa| {
+                Ring3Executor::save_context(&current, sa);
+            });
+            kcb.arch.swap_current_process(next.clone());
+
+            notify_preemption(kcb, &current);
+            if !is_gang_request {
+                let response = kcb.replica.as_ref().map(|(replica, token)| {
+                    replica.execute(nr::ReadOps::ProcessCores(next.pid), *token)
+                });
+                if let Some(Ok(nr::NodeResult::Cores(SchedulingPolicy::Gang, cores))) = response {
+                    super::tlb::gang_schedule(next.pid, &cores);
+                }
+            }
+
+            dispatch(kcb, next, now)
+        }
+        _ => kcb_iret_handle(kcb),
+    };
+
+    update_quantum_end(kcb);
+    resumer
+}
+
+/// All executors that are assigned to this core.
+fn core_executors(kcb: &crate::kcb::Kcb<Arch86Kcb>) -> Vec<Arc<Ring3Executor>> {
+    kcb.replica.as_ref().map_or(Vec::new(), |(replica, token)| {
+        match replica.execute(nr::ReadOps::CoreExecutors(kcb.arch.hwthread_id()), *token) {
+            Ok(nr::NodeResult::Executors(executors)) => {
+                executors.iter().filter_map(Weak::upgrade).collect()
+            }
+            _ => Vec::new(),
+        }
+    })
+}
+
+/// Updates the run-queue of the core to `executors` (with the scheduling
+/// weights of their processes, see `ProcessOperation::SetWeight`).
+fn sync_run_queue(kcb: &mut crate::kcb::Kcb<Arch86Kcb>, executors: &[Arc<Ring3Executor>]) {
+    let keys: Vec<ExecutorKey> = executors.iter().map(|e| (e.pid, e.eid)).collect();
+    kcb.arch.fair.sync(&keys);
+
+    for (pid, _eid) in keys {
+        let response = kcb
+            .replica
+            .as_ref()
+            .map(|(replica, token)| replica.execute(nr::ReadOps::ProcessWeight(pid), *token));
+        if let Some(Ok(nr::NodeResult::Weight(weight))) = response {
+            kcb.arch.fair.set_weight(pid, weight);
+        }
+    }
+}
+
+/// Dispatches `next` (which is already the current process of the core):
+/// Resumes it where it was interrupted or starts it if it never ran here.
+unsafe fn dispatch(
+    kcb: &mut crate::kcb::Kcb<Arch86Kcb>,
+    next: &Arc<Ring3Executor>,
+    now: u64,
+) -> Ring3Resumer {
+    let key = (next.pid, next.eid);
+    if kcb.arch.fair.dispatch(key, now) {
+        kcb.arch.save_area.as_mut().map(|sa| {
+            **sa = next.save_area;
+        });
+        next.maybe_switch_vspace();
+        super::eventring::dispatched(next.pid, next.eid);
+        kcb_iret_handle(kcb)
+    } else {
+        next.start()
+    }
+}
+
+/// Raises the next timer interrupt at the end of the time slice (if the
+/// core is shared) or when the current executor gets its next timer upcall
+/// (see `kpi::upcall::TIMER`), whatever comes first.
+pub(super) fn arm_timer(kcb: &crate::kcb::Kcb<Arch86Kcb>, now: u64) {
+    let mut deadline = if kcb.arch.fair.len() > 1 {
+        kcb.arch.fair.slice_end().saturating_sub(now)
+    } else {
+        timer::DEFAULT_TIMER_DEADLINE
+    };
+    if let Ok(current) = kcb.arch.current_process() {
+        if current.timer_period > 0 {
+            deadline = core::cmp::min(deadline, current.timer_next.saturating_sub(now));
+        }
+    }
+    timer::set(deadline);
+}
+
+/// Tells the current executor when its time slice ends.
+unsafe fn update_quantum_end(kcb: &crate::kcb::Kcb<Arch86Kcb>) {
+    let current = kcb.arch.current_process().expect("Need a process");
+    let quantum_end = if kcb.arch.fair.len() > 1 {
+        kcb.arch.fair.slice_end()
+    } else {
+        u64::max_value()
+    };
+    (*current.vcpu_kernel()).quantum_end = quantum_end;
+}
+
+/// Gives up the core while the current executor (which is in a system call)
+/// isn't scheduled, e.g., until a child process exited (see
+/// `ProcessOperation::WaitPid`).
+///
+/// When the executor runs again, the system call returns `WouldBlock`.
+pub unsafe fn block_current_executor(kcb: &mut crate::kcb::Kcb<Arch86Kcb>) -> ! {
+    let current = kcb.arch.current_process().expect("Need a process");
+    kcb.arch.save_area.as_mut().map(|sa| {
+        sa.set_syscall_error_code(kpi::SystemCallError::WouldBlock);
+        Ring3Executor::save_context(&current, sa);
+        let executor = Arc::as_ptr(&current) as *mut Ring3Executor;
+        (*executor).syscall_return = true;
+    });
+    drop(current);
+
+    leave_exited_executor(kcb)
+}
+
+/// Continues with another executor after the process of the current
+/// executor exited, was terminated or replaced its image (see
+/// `ProcessOperation::Exit`, `ProcessOperation::TerminateGroup` and
+/// `ProcessOperation::Exec`), or the executor blocked (see
+/// `block_current_executor`).
+///
+/// Picks the next executor assigned to this core or goes back to the
+/// scheduler to wait for one if there is none left.
+pub unsafe fn leave_exited_executor(kcb: &mut crate::kcb::Kcb<Arch86Kcb>) -> ! {
+    let executors = core_executors(kcb);
+    let exited = kcb.arch.take_current_process();
+    drop(exited);
+
+    sync_run_queue(kcb, &executors);
+
+    let now = x86::time::rdtsc();
+    let next = kcb
+        .arch
+        .fair
+        .pick_next()
+        .and_then(|key| executors.iter().find(|e| (e.pid, e.eid) == key));
+    match next {
+        Some(next) => {
+            kcb.arch.swap_current_process(next.clone());
+            let resumer = dispatch(kcb, next, now);
+            update_quantum_end(kcb);
+            arm_timer(kcb, now);
+            resumer.resume()
+        }
+        None => crate::scheduler::schedule(),
+    }
+}
+
 /// Handler for the timer exception.
 ///
 /// We currently use it to periodically make sure that a replica
-/// makes forward progress to avoid liveness issues.
+/// makes forward progress to avoid liveness issues and to time-slice
+/// cores that are shared by multiple executors.
 unsafe fn timer_handler(a: &ExceptionArguments) {
     #[cfg(feature = "test-timer")]
     {
@@ -414,30 +830,42 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     // Periodically advance replica state, then resume immediately
     nr::KernelNode::<Ring3Process>::synchronize();
     let kcb = get_kcb();
+    if kcb.arch.id() == 0 {
+        super::steering::rebalance();
+        super::eventring::ring_doorbells();
+        super::pci::check_removed();
+        super::isolation::handle_failed();
+    }
     if kcb.arch.has_current_process() {
+        let now = x86::time::rdtsc();
+        let interrupted = kcb.arch.current_process().ok();
+        let mut resumer = time_slice(kcb, now);
+        // Make progress on asynchronous system calls even if the process
+        // never enters the kernel:
+        super::asyncring::poll_current();
+
+        // The executor keeps running, tell it if it asked for it
+        let current = kcb.arch.current_process().ok();
+        if let (Some(interrupted), Some(current)) = (interrupted, current) {
+            // Safety: `current` is the executor on this core
+            if Arc::ptr_eq(&interrupted, &current)
+                && unsafe { Ring3Executor::timer_due(&current, now) }
+            {
+                resumer = deliver_activation(kcb, current.pid, kpi::upcall::TIMER, now);
+            }
+        }
+
         // TODO(process-mgmt): Ensures that we still periodically
         // check and advance replicas even on cores that have a core.
         // Only a single idle core per replica should probably do that,
         // so if cores go properly back to idling when finished execution,
         // this is no longer necessary...
-        let is_replica_main_thread = {
-            let thread = topology::MACHINE_TOPOLOGY.current_thread();
-            thread.node().is_none()
-                || thread
-                    .node()
-                    .unwrap()
-                    .threads()
-                    .next()
-                    .map(|t| t.id == thread.id)
-                    .unwrap_or(false)
-        };
-        if is_replica_main_thread {
-            timer::set(timer::DEFAULT_TIMER_DEADLINE);
-        }
+        //
+        // We also have to check back periodically in case more executors
+        // get assigned to this core.
+        arm_timer(kcb, now);
 
-        // Return immediately
-        let r = kcb_iret_handle(kcb);
-        r.resume()
+        resumer.resume()
     } else {
         // Go to scheduler instead
         //warn!("got a timer on core {}", kcb.arch.id());
@@ -568,10 +996,18 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
         assert!(a.vector < 256);
         trace!("handle_generic_exception {:?}", a);
         acknowledge();
+        get_kcb().arch.account_user_time(start);
+        super::steering::record(a.vector);
 
         // If we have an active process we should do scheduler activations:
         // TODO(scheduling): do proper masking based on some VCPU mask
         // TODO(scheduling): Currently don't deliver interrupts to process not currently running
+        if super::steering::is_unrouted_msi(a.vector) {
+            // In flight from a device that was removed (or the process freed
+            // its vectors), nobody wants it anymore
+            kcb_iret_handle(get_kcb()).resume()
+        }
+
         if a.vector > 30 && a.vector < 250 || a.vector == 3 {
             trace!("handle_generic_exception {:?}", a);
 
@@ -588,8 +1024,13 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
                 };
 
                 if was_disabled {
-                    // Resume to the current save area...
-                    warn!("Upcalling while disabled");
+                    // Resume to the current save area, user-space finds the
+                    // interrupt in its vCPU area on the next upcall
+                    if a.vector >= super::steering::IOAPIC_VECTOR_BASE {
+                        p.vcpu().set_pending_irq(a.vector);
+                    } else {
+                        warn!("Upcalling while disabled");
+                    }
                     kcb_resume_handle(kcb)
                 } else {
                     // Copy CURRENT_SAVE_AREA to process enabled save area
@@ -615,17 +1056,40 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             pf_handler(&a);
         } else if a.vector == 0x3 {
             dbg_handler(&a);
+        } else if a.vector == CONTROL_PROTECTION_VECTOR {
+            cp_handler(&a);
         } else if a.vector == TLB_WORK_PENDING.into() {
             trace!(
                 "got an interrupt {:?}",
                 topology::MACHINE_TOPOLOGY.current_thread().apic_id()
             );
-            super::tlb::dequeue(topology::MACHINE_TOPOLOGY.current_thread().id);
+            let sent = super::tlb::dequeue(topology::MACHINE_TOPOLOGY.current_thread().id);
 
             let kcb = get_kcb();
+            if let Some(sent) = sent {
+                kcb.arch
+                    .record_ipi_latency(TLB_WORK_PENDING, start.saturating_sub(sent));
+            }
             if kcb.arch.has_current_process() {
                 // Return immediately
-                kcb.tlb_time += x86::time::rdtsc() - start;
+                let cycles = x86::time::rdtsc() - start;
+                kcb.tlb_time += cycles;
+                kcb.tlb_handler.record(cycles);
+                if kcb.arch.fair.has_request() {
+                    // A gang-scheduled process wants to run on this core
+                    time_slice(kcb, x86::time::rdtsc()).resume()
+                }
+                if let Some(pid) = kcb.arch.terminated.take() {
+                    if kcb.arch.current_process().map_or(false, |p| p.pid == pid) {
+                        // Its page-tables are gone
+                        let pml4 = kcb.arch.init_vspace().pml4_address();
+                        x86::controlregs::cr3_write(pml4.into());
+                        leave_exited_executor(kcb)
+                    }
+                }
+                if let Some((pid, cmd, arg)) = kcb.arch.activation.take() {
+                    deliver_activation(kcb, pid, cmd, arg).resume()
+                }
                 kcb_iret_handle(kcb).resume()
             } else {
                 // Go to scheduler instead
@@ -633,9 +1097,13 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             }
         } else if a.vector == MLNR_GC_INIT.into() {
             // nr::KernelNode::<Ring3Process>::synchronize(); /* TODO: Do we need this?
-            super::tlb::dequeue(topology::MACHINE_TOPOLOGY.current_thread().id);
+            let sent = super::tlb::dequeue(topology::MACHINE_TOPOLOGY.current_thread().id);
 
             let kcb = get_kcb();
+            if let Some(sent) = sent {
+                kcb.arch
+                    .record_ipi_latency(MLNR_GC_INIT, start.saturating_sub(sent));
+            }
             if kcb.arch.has_current_process() {
                 kcb_iret_handle(kcb).resume()
             } else {
@@ -704,37 +1172,44 @@ pub fn ioapic_initialize() {
 
 /// Establishes a route for a GSI on the IOAPIC.
 ///
-/// # TODO
-/// Currently this just enables everything and routes it to
-/// core 0. This is because, we should probably just support MSI(X)
-/// and don't invest a lot in legacy interrupts...
-pub fn ioapic_establish_route(_gsi: u64, _core: u64) {
-    use crate::memory::{paddr_to_kernel_vaddr, vspace::MapAction, PAddr};
-
+/// Enables the interrupt line `gsi` and delivers it to `core` (replacing
+/// any previous route). Use `steering::allocate` and `steering::steer`
+/// which keep track of who owns the route.
+///
+/// Fails for cores that don't exist or that the IOAPIC can't address (it
+/// only takes 8-bit APIC ids).
+pub fn ioapic_establish_route(gsi: u64, core: u64) -> Result<(), KError> {
+    use crate::memory::{paddr_to_kernel_vaddr, PAddr};
+
+    let apic_id: u32 = topology::MACHINE_TOPOLOGY
+        .threads
+        .get(core as usize)
+        .ok_or(KError::InvalidCore { core })?
+        .apic_id()
+        .into();
+    if apic_id > 0xff {
+        return Err(KError::InvalidCore { core });
+    }
     for io_apic in topology::MACHINE_TOPOLOGY.io_apics() {
         let addr = PAddr::from(io_apic.address as u64);
 
         let mut inst =
             unsafe { x86::apic::ioapic::IoApic::new(paddr_to_kernel_vaddr(addr).as_usize()) };
-        trace!(
-            "This IOAPIC supports {} Interrupts",
-            inst.supported_interrupts()
-        );
 
-        for i in 0..inst.supported_interrupts() {
-            let gsi = io_apic.global_irq_base + i as u32;
-            if gsi < 16 {
-                trace!(
-                    "Enable irq {} which maps to GSI#{}",
-                    i,
-                    io_apic.global_irq_base + i as u32
-                );
-                if i != 2 && i != 1 {
-                    inst.enable(i, 0);
-                }
-            }
+        let base = io_apic.global_irq_base as u64;
+        if gsi >= base && gsi < base + inst.supported_interrupts() as u64 {
+            let i = (gsi - base) as u8;
+            trace!(
+                "Route irq {} (GSI#{}) to core {} (APIC {})",
+                i,
+                gsi,
+                core,
+                apic_id
+            );
+            inst.enable(i, apic_id as u8);
         }
     }
+    Ok(())
 }
 
 fn acknowledge() {
@@ -0,0 +1,115 @@
+//! Surviving a kernel panic on an app core (with `panic=isolate`).
+//!
+//! Normally a panic on any core shuts the machine down. With `panic=isolate`
+//! on the command-line, an app core whose kernel panics marks itself as
+//! failed and halts (with interrupts disabled) instead, see [`isolate`]. The
+//! BSP notices it on its next timer interrupt ([`handle_failed`]): it takes
+//! the executors away from the core, so the scheduler never places anything
+//! there again, and sends `kpi::upcall::CORE_FAILED` to the processes that
+//! had one there.
+//!
+//! Other cores don't wait for a failed core: TLB shootdowns and replica
+//! updates skip it and it's left out when the system suspends.
+//!
+//! This is best-effort: if the core panicked while holding a lock (e.g., of
+//! a replica or a log) the other cores will get stuck on it eventually. A
+//! panic on the BSP always shuts the machine down.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::nr;
+
+use super::process::Ring3Process;
+
+/// How many cores we can keep track of.
+const MAX_CORES: usize = 256;
+
+/// Cores that failed (one bit per core).
+static FAILED: [AtomicU64; MAX_CORES / 64] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Failed cores the BSP didn't clean up after yet.
+static PENDING: [AtomicU64; MAX_CORES / 64] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Word and bit of `gtid` in `FAILED` and `PENDING`.
+fn bit(gtid: usize) -> (usize, u64) {
+    (gtid / 64, 1 << (gtid % 64))
+}
+
+/// Did core `gtid` fail?
+pub fn failed(gtid: topology::GlobalThreadId) -> bool {
+    let (word, mask) = bit(gtid as usize);
+    word < FAILED.len() && FAILED[word].load(Ordering::Acquire) & mask != 0
+}
+
+/// Takes the current core out of service after its kernel panicked (we
+/// printed the panic and a backtrace already), doesn't return if it did.
+///
+/// Returns if isolation is off, this is the BSP or we don't have a KCB;
+/// the machine shuts down then.
+pub fn isolate() {
+    let kcb = match super::kcb::try_get_kcb() {
+        Some(kcb) => kcb,
+        None => return,
+    };
+    let gtid = kcb.arch.id();
+    if !kcb.cmdline.isolate_panics || gtid == 0 || gtid >= MAX_CORES {
+        return;
+    }
+
+    let (word, mask) = bit(gtid);
+    FAILED[word].fetch_or(mask, Ordering::AcqRel);
+    PENDING[word].fetch_or(mask, Ordering::AcqRel);
+    sprintln!("Core #{} failed, taking it out of service", gtid);
+
+    unsafe { x86::irq::disable() };
+    loop {
+        unsafe { x86::halt() };
+    }
+}
+
+/// Cleans up after the cores that failed since the last call (on the BSP,
+/// from the timer interrupt).
+pub fn handle_failed() {
+    for (word, pending) in PENDING.iter().enumerate() {
+        let mut bits = pending.swap(0, Ordering::AcqRel);
+        while bits != 0 {
+            let gtid = (word * 64 + bits.trailing_zeros() as usize) as topology::GlobalThreadId;
+            bits &= bits - 1;
+
+            match nr::KernelNode::<Ring3Process>::core_failed(gtid) {
+                Ok(pids) => {
+                    error!("Core #{} failed, it ran {:?}", gtid, pids);
+                    for pid in pids {
+                        super::irq::notify_process(pid, kpi::upcall::CORE_FAILED, gtid as u64);
+                    }
+                }
+                Err(e) => error!("Can't clean up after failed core #{}: {:?}", gtid, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn core_bits() {
+        assert_eq!(bit(0), (0, 1));
+        assert_eq!(bit(63), (0, 1 << 63));
+        assert_eq!(bit(64), (1, 1));
+        assert_eq!(bit(MAX_CORES - 1).0, FAILED.len() - 1);
+        assert!(!failed(3));
+        assert!(!failed(MAX_CORES as topology::GlobalThreadId));
+    }
+}
@@ -18,11 +18,13 @@ use crate::error::KError;
 use crate::kcb::{ArchSpecificKcb, Kcb};
 use crate::mlnr::MlnrKernelNode;
 
-use crate::process::{Pid, ProcessError};
+use crate::process::{Eid, Pid, ProcessError};
+use crate::scheduler::fair::FairScheduler;
 use crate::stack::{OwnedStack, Stack};
+use crate::stats::Histogram;
 
 use super::gdt::GdtTable;
-use super::irq::IdtTable;
+use super::irq::{IdtTable, IPI_VECTORS};
 use super::process::{Ring3Executor, Ring3Process};
 use super::vspace::page_table::PageTable;
 use super::KernelArgs;
@@ -126,6 +128,48 @@ pub struct Arch86Kcb {
     /// Max number of hyperthreads on the current socket.
     max_threads: usize,
 
+    /// Cycles between sending an IPI and entering the handler on this core
+    /// (one histogram for every vector in `IPI_VECTORS`).
+    pub ipi_latency: [Histogram; IPI_VECTORS.len()],
+
+    /// Cycles spent in `syscall_handle` (one histogram for every `SystemCall`
+    /// class, i.e., `syscall_latency[SystemCall::FileIO as usize - 1]`).
+    pub syscall_latency: [Histogram; 6],
+
+    /// rdtsc at the time we last returned to user-space (0 if we're not
+    /// currently running a process).
+    dispatched_at: u64,
+
+    /// rdtsc when the next timer interrupt is due (see `timer::set`).
+    timer_deadline: u64,
+
+    /// Run-queue for the executors that time-share this core.
+    pub fair: FairScheduler,
+
+    /// A scheduler activation (pid, upcall cmd, upcall arg) that we
+    /// received from another core and still need to deliver.
+    pub activation: Option<(Pid, u64, u64)>,
+
+    /// A process that another core terminated (see
+    /// `ProcessOperation::TerminateGroup`), we stop running its executor.
+    pub terminated: Option<Pid>,
+
+    /// The process and `cat` configuration (generation) we last programmed
+    /// the class of service for (see `cat::dispatch`).
+    pub cache_class: (Pid, u64),
+
+    /// The process whose RMID the core tags its cache lines and memory
+    /// traffic with (see `mbm::dispatch`).
+    pub monitored_pid: Pid,
+
+    /// The executor whose protection key rights are in the PKRU register
+    /// (see `pkeys::dispatch`).
+    pub pkru_owner: Option<(Pid, Eid)>,
+
+    /// The executor whose shadow stack pointer is in `IA32_PL3_SSP` (see
+    /// `cet::dispatch`).
+    pub ssp_owner: Option<(Pid, Eid)>,
+
     /// The interrupt stack (that is used by the CPU on interrupts/traps/faults)
     ///
     /// The CPU switches to this stack automatically for normal interrupts
@@ -171,6 +215,17 @@ impl Arch86Kcb {
             mlnr_replica: None,
             id: 0,
             max_threads: 0,
+            ipi_latency: [Histogram::new(); IPI_VECTORS.len()],
+            syscall_latency: [Histogram::new(); 6],
+            dispatched_at: 0,
+            timer_deadline: u64::max_value(),
+            fair: FairScheduler::new(),
+            activation: None,
+            terminated: None,
+            cache_class: (0, 0),
+            monitored_pid: 0,
+            pkru_owner: None,
+            ssp_owner: None,
         }
     }
 
@@ -204,14 +259,67 @@ impl Arch86Kcb {
         self.max_threads
     }
 
+    /// Records the latency (in cycles) of an IPI received by this core.
+    pub fn record_ipi_latency(&mut self, vector: u8, cycles: u64) {
+        if let Some(idx) = IPI_VECTORS.iter().position(|&v| v == vector) {
+            self.ipi_latency[idx].record(cycles);
+        }
+    }
+
+    /// Remember when we (re-)dispatched the current process (see
+    /// `account_user_time`).
+    pub fn mark_dispatch(&mut self) {
+        self.dispatched_at = unsafe { x86::time::rdtsc() };
+    }
+
+    /// Charges the cycles since the last dispatch to the current process,
+    /// should be called when we enter the kernel from user-space at time `now`.
+    pub fn account_user_time(&mut self, now: u64) {
+        if self.dispatched_at != 0 {
+            if let Some(p) = self.current_process.as_ref() {
+                crate::process::account_cpu_time(p.pid, now.saturating_sub(self.dispatched_at));
+            }
+            self.dispatched_at = 0;
+        }
+    }
+
+    /// Remember when the timer interrupt (see `timer::set`) is due.
+    pub fn set_timer_deadline(&mut self, deadline: u64) {
+        self.timer_deadline = deadline;
+    }
+
+    /// Should a long-running system call stop and return to user-space?
+    ///
+    /// Interrupts are off in the kernel, so if the timer interrupt is due
+    /// or another core sent us work (e.g., it terminated the process, see
+    /// `tlb::terminate`) it's held back until we leave: time-slicing,
+    /// replica progress and cancellation are waiting on us.
+    pub fn should_yield(&self) -> bool {
+        unsafe { x86::time::rdtsc() >= self.timer_deadline }
+        || super::tlb::has_work(self.id() as topology::GlobalThreadId)
+    }
+
     /// Swaps out current process with a new process. Returns the old process.
     pub fn swap_current_process(
         &mut self,
         new_current_process: Arc<Ring3Executor>,
     ) -> Option<Arc<Ring3Executor>> {
+        if let Some(executor) = self.current_process.clone() {
+            super::pkeys::save(self, &executor);
+            super::cet::save(self, &executor);
+        }
         self.current_process.replace(new_current_process)
     }
 
+    /// Removes the current process from the core (e.g., because it exited).
+    pub fn take_current_process(&mut self) -> Option<Arc<Ring3Executor>> {
+        if let Some(executor) = self.current_process.clone() {
+            super::pkeys::save(self, &executor);
+            super::cet::save(self, &executor);
+        }
+        self.current_process.take()
+    }
+
     pub fn has_current_process(&self) -> bool {
         self.current_process.is_some()
     }
@@ -0,0 +1,787 @@
+//! Kexec-style soft reboot into a new kernel image.
+//!
+//! Instead of going through a reset and the firmware we load a new kernel
+//! ELF binary (a boot module or an image supplied by user-space), construct
+//! the same environment the UEFI bootloader would (page-tables, init stack,
+//! memory map and `KernelArgs`) and jump to its entry point.
+//!
+//! All memory the new kernel needs to boot is reserved in one physically
+//! contiguous region and shows up with the bootloader memory types in the
+//! new memory map, everything else the old kernel used is handed to the new
+//! kernel as conventional memory again. The old kernel ELF stays reserved
+//! because we are still executing in it while switching to the new
+//! address-space.
+//!
+//! # Notes
+//! Devices are not reset, so this only works well if drivers re-initialize
+//! the devices they use.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86::bits64::paging::{PAddr, VAddr, PML4};
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{
+    AllocationError, AllocatorStatistics, Frame, GrowBackend, PhysicalPageProvider,
+};
+use crate::round_up;
+
+use super::kcb::get_kcb;
+use super::memory::{paddr_to_kernel_vaddr, BASE_PAGE_SIZE, KERNEL_BASE, LARGE_PAGE_SIZE};
+use super::vspace::page_table::PageTable;
+use super::{KernelArgs, Module};
+
+/// Memory types the bootloader uses for its allocations
+/// (see `bootloader/src/kernel.rs`).
+const KERNEL_ELF: u32 = 0x80000001;
+const KERNEL_PT: u32 = 0x80000002;
+const KERNEL_STACK: u32 = 0x80000003;
+const UEFI_MEMORY_MAP: u32 = 0x80000004;
+const KERNEL_ARGS: u32 = 0x80000005;
+const MODULE: u32 = 0x80000006;
+
+/// Size of the init stack (same as what the bootloader uses).
+const STACK_PAGES: usize = 768;
+
+/// Space for `KernelArgs`, the memory map and the command line.
+const ARGS_SIZE: usize = 16 * BASE_PAGE_SIZE;
+
+/// Space for the page-tables of the new kernel.
+const PT_ARENA_SIZE: usize = LARGE_PAGE_SIZE;
+
+/// The bootloader doesn't map the region that contains the local APIC.
+const APIC_BASE: u64 = 0xfee00000;
+
+/// Where the pieces the new kernel needs are in the reserved region
+/// (physical `[start, end)` ranges).
+#[derive(Debug, Clone, Copy)]
+struct Reservation {
+    frame: Frame,
+    elf: (u64, u64),
+    stack: (u64, u64),
+    image: (u64, u64),
+    args: (u64, u64),
+    pts: (u64, u64),
+}
+
+impl Reservation {
+    /// Memory types of the ranges returned by `ranges`.
+    const TYPES: [u32; 5] = [KERNEL_ELF, KERNEL_STACK, MODULE, KERNEL_ARGS, KERNEL_PT];
+
+    /// How much memory we need for an ELF of `elf_size` and (in case we have
+    /// to copy the binary) `image_size` bytes.
+    fn size(elf_size: usize, image_size: usize) -> usize {
+        elf_size
+            + STACK_PAGES * BASE_PAGE_SIZE
+            + round_up!(image_size, BASE_PAGE_SIZE)
+            + ARGS_SIZE
+            + PT_ARENA_SIZE
+    }
+
+    fn new(frame: Frame, elf_size: usize, image_size: usize) -> Reservation {
+        let base = frame.base.as_u64();
+        let elf = (base, base + elf_size as u64);
+        let stack = (elf.1, elf.1 + (STACK_PAGES * BASE_PAGE_SIZE) as u64);
+        let image = (
+            stack.1,
+            stack.1 + round_up!(image_size, BASE_PAGE_SIZE) as u64,
+        );
+        let args = (image.1, image.1 + ARGS_SIZE as u64);
+        let pts = (args.1, args.1 + PT_ARENA_SIZE as u64);
+        assert!(pts.1 <= frame.end().as_u64());
+
+        Reservation {
+            frame,
+            elf,
+            stack,
+            image,
+            args,
+            pts,
+        }
+    }
+
+    fn ranges(&self) -> [(u64, u64); 5] {
+        [self.elf, self.stack, self.image, self.args, self.pts]
+    }
+}
+
+/// Splits the physical range `[start, end)` along the (non-overlapping)
+/// `reserved` ranges.
+///
+/// Returns the pieces in ascending order along with the index of the
+/// reserved range that covers them (or None if none does).
+pub fn split(start: u64, end: u64, reserved: &[(u64, u64)]) -> Vec<(u64, u64, Option<usize>)> {
+    let mut overlapping: Vec<(usize, (u64, u64))> = reserved
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_idx, (rstart, rend))| rstart < rend && *rstart < end && *rend > start)
+        .collect();
+    overlapping.sort_unstable_by_key(|(_idx, (rstart, _rend))| *rstart);
+
+    let mut pieces = Vec::with_capacity(2 * overlapping.len() + 1);
+    let mut cur = start;
+    for (idx, (rstart, rend)) in overlapping {
+        let rstart = core::cmp::max(rstart, start);
+        let rend = core::cmp::min(rend, end);
+        if cur < rstart {
+            pieces.push((cur, rstart, None));
+        }
+        pieces.push((rstart, rend, Some(idx)));
+        cur = rend;
+    }
+    if cur < end {
+        pieces.push((cur, end, None));
+    }
+
+    pieces
+}
+
+/// Builds the memory map for the new kernel: every conventional region that
+/// overlaps with `reservation` is split and the reserved parts get the memory
+/// type the bootloader would use for them.
+fn memory_map(old: &[MemoryDescriptor], reservation: &Reservation) -> Vec<MemoryDescriptor> {
+    let reserved = reservation.ranges();
+    let mut descriptors = Vec::with_capacity(old.len() + 2 * reserved.len());
+
+    for desc in old {
+        if desc.ty != MemoryType::CONVENTIONAL {
+            descriptors.push(*desc);
+            continue;
+        }
+
+        let end = desc.phys_start + desc.page_count * BASE_PAGE_SIZE as u64;
+        for (start, end, idx) in split(desc.phys_start, end, &reserved) {
+            let mut piece = *desc;
+            piece.phys_start = start;
+            piece.virt_start = desc.virt_start + (start - desc.phys_start);
+            piece.page_count = (end - start) / BASE_PAGE_SIZE as u64;
+            piece.ty = idx.map_or(MemoryType::CONVENTIONAL, |idx| {
+                MemoryType(Reservation::TYPES[idx])
+            });
+            descriptors.push(piece);
+        }
+    }
+
+    descriptors
+}
+
+/// How the bootloader maps a memory region of type `ty`.
+///
+/// Returns the rights and whether the region is also mapped in kernel space
+/// (at `KERNEL_BASE`) in addition to the identity mapping.
+fn memory_rights(ty: MemoryType) -> (MapAction, bool) {
+    match ty {
+        MemoryType::LOADER_CODE => (MapAction::ReadExecuteKernel, false),
+        MemoryType::LOADER_DATA => (MapAction::ReadWriteKernel, true),
+        MemoryType::BOOT_SERVICES_CODE => (MapAction::ReadExecuteKernel, false),
+        MemoryType::BOOT_SERVICES_DATA => (MapAction::ReadWriteKernel, true),
+        MemoryType::RUNTIME_SERVICES_CODE => (MapAction::ReadExecuteKernel, false),
+        MemoryType::RUNTIME_SERVICES_DATA => (MapAction::ReadWriteKernel, false),
+        MemoryType::CONVENTIONAL => (MapAction::ReadWriteExecuteKernel, true),
+        MemoryType::ACPI_RECLAIM => (MapAction::ReadWriteKernel, false),
+        MemoryType::ACPI_NON_VOLATILE => (MapAction::ReadWriteKernel, false),
+        MemoryType::MMIO => (MapAction::ReadWriteKernel, false),
+        MemoryType::MMIO_PORT_SPACE => (MapAction::ReadWriteKernel, false),
+        MemoryType::PAL_CODE => (MapAction::ReadExecuteKernel, false),
+        MemoryType::PERSISTENT_MEMORY => (MapAction::ReadWriteKernel, false),
+        MemoryType(KERNEL_ELF) => (MapAction::ReadKernel, false),
+        MemoryType(KERNEL_PT) => (MapAction::ReadWriteKernel, true),
+        MemoryType(KERNEL_STACK) => (MapAction::ReadWriteKernel, false),
+        MemoryType(UEFI_MEMORY_MAP) => (MapAction::ReadWriteKernel, false),
+        MemoryType(KERNEL_ARGS) => (MapAction::ReadKernel, true),
+        MemoryType(MODULE) => (MapAction::ReadKernel, true),
+        _ => (MapAction::None, false),
+    }
+}
+
+/// Hands out the base-pages of a frame for the page-tables of the new kernel.
+///
+/// The pages are never given back (they have to stay around until the new
+/// kernel switched to its own page-tables).
+struct ArenaPager {
+    frame: Frame,
+    used: usize,
+}
+
+impl ArenaPager {
+    fn new(frame: Frame) -> ArenaPager {
+        ArenaPager { frame, used: 0 }
+    }
+}
+
+impl crate::kcb::MemManager for ArenaPager {}
+
+impl PhysicalPageProvider for ArenaPager {
+    fn allocate_base_page(&mut self) -> Result<Frame, AllocationError> {
+        if self.used + BASE_PAGE_SIZE > self.frame.size() {
+            return Err(AllocationError::CacheExhausted);
+        }
+        let frame = Frame::new(self.frame.base + self.used, BASE_PAGE_SIZE, 0);
+        self.used += BASE_PAGE_SIZE;
+        Ok(frame)
+    }
+
+    fn release_base_page(&mut self, _frame: Frame) -> Result<(), AllocationError> {
+        Ok(())
+    }
+
+    fn allocate_large_page(&mut self) -> Result<Frame, AllocationError> {
+        Err(AllocationError::CacheExhausted)
+    }
+
+    fn release_large_page(&mut self, _frame: Frame) -> Result<(), AllocationError> {
+        Ok(())
+    }
+}
+
+impl AllocatorStatistics for ArenaPager {
+    fn allocated(&self) -> usize {
+        self.used
+    }
+
+    fn size(&self) -> usize {
+        self.frame.size()
+    }
+
+    fn capacity(&self) -> usize {
+        self.frame.size()
+    }
+
+    fn internal_fragmentation(&self) -> usize {
+        0
+    }
+
+    fn free_base_pages(&self) -> usize {
+        (self.frame.size() - self.used) / BASE_PAGE_SIZE
+    }
+}
+
+impl GrowBackend for ArenaPager {
+    fn base_page_capcacity(&self) -> usize {
+        0
+    }
+
+    fn grow_base_pages(&mut self, _free_list: &[Frame]) -> Result<(), AllocationError> {
+        Err(AllocationError::CantGrowFurther { count: 0 })
+    }
+
+    fn large_page_capcacity(&self) -> usize {
+        0
+    }
+
+    fn grow_large_pages(&mut self, _free_list: &[Frame]) -> Result<(), AllocationError> {
+        Err(AllocationError::CantGrowFurther { count: 0 })
+    }
+}
+
+/// Allocates `size` bytes of physically contiguous memory (in large-pages).
+fn reserve(size: usize) -> Result<Frame, KError> {
+    let kcb = get_kcb();
+    let gmanager = kcb
+        .physical_memory
+        .gmanager
+        .ok_or(KError::GlobalMemoryNotSet)?;
+    let mut ncache = gmanager.node_caches[0].lock();
+
+    let pages = round_up!(size, LARGE_PAGE_SIZE) / LARGE_PAGE_SIZE;
+    let mut run: Vec<Frame> = Vec::with_capacity(pages);
+    let mut skipped: Vec<Frame> = Vec::new();
+    let (mut low, mut high) = (PAddr::zero(), PAddr::zero());
+
+    // The cache hands out pages in (mostly) sequential order, so we just keep
+    // allocating until we have a long enough run of adjacent pages:
+    let result = loop {
+        if run.len() == pages {
+            break Ok(Frame::new(low, pages * LARGE_PAGE_SIZE, 0));
+        }
+
+        let frame = match ncache.allocate_large_page() {
+            Ok(frame) => frame,
+            Err(e) => break Err(KError::PhysicalMemory { source: e }),
+        };
+        if run.is_empty() {
+            low = frame.base;
+            high = frame.end();
+        } else if frame.end() == low {
+            low = frame.base;
+        } else if frame.base == high {
+            high = frame.end();
+        } else {
+            skipped.append(&mut run);
+            low = frame.base;
+            high = frame.end();
+        }
+        run.push(frame);
+    };
+
+    if result.is_err() {
+        skipped.append(&mut run);
+    }
+    for frame in skipped {
+        ncache
+            .release_large_page(frame)
+            .expect("Can't give back a page we just allocated");
+    }
+
+    result
+}
+
+/// Gives the memory we allocated with `reserve` back.
+fn release(frame: Frame) {
+    let kcb = get_kcb();
+    if let Some(gmanager) = kcb.physical_memory.gmanager {
+        let mut ncache = gmanager.node_caches[0].lock();
+        for i in 0..frame.size() / LARGE_PAGE_SIZE {
+            let page = Frame::new(frame.base + i * LARGE_PAGE_SIZE, LARGE_PAGE_SIZE, 0);
+            let _r = ncache.release_large_page(page);
+        }
+    }
+}
+
+/// Loads and relocates the new kernel ELF into the reserved memory.
+///
+/// Like the bootloader, we put the ELF at `KERNEL_BASE` + its physical
+/// address so the new kernel ends up with a simple 1:1 mapping of physical
+/// memory.
+struct KexecLoader {
+    /// How many bytes we have to set aside for a copy of the binary.
+    image_size: usize,
+    /// The memory we reserved (once `allocate` was called).
+    reservation: Option<Reservation>,
+    /// Segments of the ELF (virtual base, size) and their rights.
+    mapping: Vec<(u64, usize, MapAction)>,
+}
+
+impl KexecLoader {
+    fn offset(&self) -> u64 {
+        self.reservation.map_or(0, |r| KERNEL_BASE + r.elf.0)
+    }
+
+    /// Returns a pointer to the ELF address `vaddr` in the current kernel
+    /// address-space (if it's part of the loaded ELF).
+    fn resolve(&self, vaddr: u64, len: usize) -> Option<*mut u8> {
+        let reservation = self.reservation.as_ref()?;
+        let paddr = reservation.elf.0.checked_add(vaddr)?;
+        if paddr.checked_add(len as u64)? > reservation.elf.1 {
+            return None;
+        }
+        Some(paddr_to_kernel_vaddr(PAddr::from(paddr)).as_mut_ptr::<u8>())
+    }
+}
+
+impl elfloader::ElfLoader for KexecLoader {
+    fn allocate(&mut self, load_headers: elfloader::LoadableHeaders) -> Result<(), &'static str> {
+        let mut max_end: u64 = 0;
+        for header in load_headers.into_iter() {
+            let base = header.virtual_addr();
+            let size = header.mem_size() as usize;
+            let flags = header.flags();
+
+            if header.align() > LARGE_PAGE_SIZE as u64 {
+                return Err("Unsupported alignment of a loadable segment.");
+            }
+
+            let page_base = base & !(BASE_PAGE_SIZE as u64 - 1);
+            let size_page = round_up!(size + (base - page_base) as usize, BASE_PAGE_SIZE);
+            max_end = core::cmp::max(max_end, page_base + size_page as u64);
+
+            let map_action = match (flags.is_execute(), flags.is_write(), flags.is_read()) {
+                (false, false, true) => MapAction::ReadKernel,
+                (true, false, true) => MapAction::ReadExecuteKernel,
+                (false, true, true) => MapAction::ReadWriteKernel,
+                (true, true, true) => MapAction::ReadWriteExecuteKernel,
+                _ => MapAction::None,
+            };
+            self.mapping.push((page_base, size_page, map_action));
+        }
+
+        let elf_size = max_end as usize;
+        let frame = reserve(Reservation::size(elf_size, self.image_size))
+            .map_err(|_e| "Not enough contiguous memory for the kernel image.")?;
+        let reservation = Reservation::new(frame, elf_size, self.image_size);
+        info!(
+            "New kernel will be loaded at {:#x} -- {:#x}",
+            reservation.elf.0, reservation.elf.1
+        );
+
+        // Zero the ELF memory, this takes care of .bss:
+        unsafe {
+            ptr::write_bytes(
+                paddr_to_kernel_vaddr(PAddr::from(reservation.elf.0)).as_mut_ptr::<u8>(),
+                0,
+                elf_size,
+            );
+        }
+        self.reservation = Some(reservation);
+
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        _flags: elfloader::Flags,
+        destination: u64,
+        region: &[u8],
+    ) -> Result<(), &'static str> {
+        let ptr = self
+            .resolve(destination, region.len())
+            .ok_or("Segment is outside of the allocated ELF memory.")?;
+        unsafe { ptr::copy_nonoverlapping(region.as_ptr(), ptr, region.len()) };
+        Ok(())
+    }
+
+    fn relocate(&mut self, entry: &elfloader::Rela<elfloader::P64>) -> Result<(), &'static str> {
+        let ptr = self
+            .resolve(entry.get_offset(), mem::size_of::<u64>())
+            .ok_or("Relocation is outside of the allocated ELF memory.")?;
+
+        use elfloader::TypeRela64;
+        if let TypeRela64::R_RELATIVE = TypeRela64::from(entry.get_type()) {
+            unsafe { *(ptr as *mut u64) = self.offset() + entry.get_addend() };
+            Ok(())
+        } else {
+            Err("Can only handle R_RELATIVE for relocation")
+        }
+    }
+}
+
+/// Constructs the address-space of the new kernel in `reservation`.
+///
+/// Returns the physical address of the PML4.
+fn build_page_table(
+    reservation: &Reservation,
+    descriptors: &[MemoryDescriptor],
+    mapping: &[(u64, usize, MapAction)],
+) -> Result<PAddr, KError> {
+    let mut pager = ArenaPager::new(Frame::new(PAddr::from(reservation.pts.0), PT_ARENA_SIZE, 0));
+    let mut pml4_frame = pager.allocate_base_page()?;
+    unsafe { pml4_frame.zero() };
+    let pml4_ptr = paddr_to_kernel_vaddr(pml4_frame.base).as_mut_ptr::<PML4>();
+    let mut page_table = PageTable {
+        pml4: unsafe { Box::into_pin(Box::from_raw(pml4_ptr)) },
+    };
+
+    // Replicate what the bootloader maps:
+    for desc in descriptors {
+        let start = desc.phys_start;
+        let size = desc.page_count as usize * BASE_PAGE_SIZE;
+        if start == 0x0 || size == 0 {
+            continue;
+        }
+        if start <= APIC_BASE && start + size as u64 >= APIC_BASE {
+            continue;
+        }
+
+        let (rights, in_kernel_space) = memory_rights(desc.ty);
+        if rights == MapAction::None {
+            continue;
+        }
+        let pregion = (PAddr::from(start), size);
+        page_table.map_generic(VAddr::from(start), pregion, rights, true, &mut pager)?;
+        if in_kernel_space {
+            let vbase = VAddr::from(KERNEL_BASE + start);
+            page_table.map_generic(vbase, pregion, rights, true, &mut pager)?;
+        }
+
+        // Kernel images other than the new one: we're currently executing
+        // in the old kernel, so it must be mapped when we switch to the new
+        // address-space.
+        if desc.ty == MemoryType(KERNEL_ELF) && start != reservation.elf.0 {
+            let vbase = VAddr::from(KERNEL_BASE + start);
+            page_table.map_generic(
+                vbase,
+                pregion,
+                MapAction::ReadExecuteKernel,
+                true,
+                &mut pager,
+            )?;
+        }
+    }
+
+    // The ELF segments of the new kernel:
+    for (base, size, rights) in mapping {
+        if *rights == MapAction::None {
+            continue;
+        }
+        let vbase = VAddr::from(KERNEL_BASE + reservation.elf.0 + base);
+        let pregion = (PAddr::from(reservation.elf.0 + base), *size);
+        page_table.map_generic(vbase, pregion, *rights, true, &mut pager)?;
+    }
+
+    // The init stack (the lowest page stays unmapped as a guard):
+    let stack_base = reservation.stack.0 + BASE_PAGE_SIZE as u64;
+    page_table.map_generic(
+        VAddr::from(KERNEL_BASE + stack_base),
+        (
+            PAddr::from(stack_base),
+            (reservation.stack.1 - stack_base) as usize,
+        ),
+        MapAction::ReadWriteKernel,
+        true,
+        &mut pager,
+    )?;
+
+    let pml4 = page_table.pml4_address();
+    // The table lives in `reservation` and not on the heap:
+    mem::forget(page_table);
+    Ok(pml4)
+}
+
+/// Writes the `KernelArgs` for the new kernel.
+///
+/// Everything is passed on from the current arguments, except for the
+/// memory related fields and the kernel binary (`modules[0]`).
+///
+/// Returns the address of the arguments in kernel space.
+fn build_kernel_args(
+    reservation: &Reservation,
+    descriptors: &[MemoryDescriptor],
+    pml4: PAddr,
+    kernel: Module,
+) -> Result<VAddr, KError> {
+    let old = get_kcb().arch.kernel_args();
+
+    let desc_offset = round_up!(mem::size_of::<KernelArgs>(), 64);
+    let desc_bytes = descriptors.len() * mem::size_of::<MemoryDescriptor>();
+    let cmdline_offset = desc_offset + desc_bytes;
+    if cmdline_offset + old.command_line.len() > ARGS_SIZE {
+        return Err(KError::InvalidKernelImage {
+            reason: "Memory map or command line too big.",
+        });
+    }
+
+    let args_vaddr = paddr_to_kernel_vaddr(PAddr::from(reservation.args.0));
+    let mut modules = arrayvec::ArrayVec::new();
+    modules.push(kernel);
+    modules.extend(old.modules.iter().skip(1).cloned());
+
+    unsafe {
+        let desc_ptr = (args_vaddr + desc_offset).as_mut_ptr::<MemoryDescriptor>();
+        ptr::copy_nonoverlapping(descriptors.as_ptr(), desc_ptr, descriptors.len());
+
+        let cmdline_ptr = (args_vaddr + cmdline_offset).as_mut_ptr::<u8>();
+        let cmdline_len = old.command_line.len();
+        ptr::copy_nonoverlapping(old.command_line.as_ptr(), cmdline_ptr, cmdline_len);
+
+        let stack_base = reservation.stack.0 + BASE_PAGE_SIZE as u64;
+        let args = KernelArgs {
+            mm: (
+                PAddr::from(reservation.args.0 + desc_offset as u64),
+                desc_bytes,
+            ),
+            mm_iter: Vec::from_raw_parts(desc_ptr, descriptors.len(), descriptors.len()),
+            command_line: core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+                cmdline_ptr,
+                cmdline_len,
+            )),
+            frame_buffer: old
+                .frame_buffer
+                .as_ref()
+                .map(|fb| core::slice::from_raw_parts_mut(fb.as_ptr() as *mut u8, fb.len())),
+            mode_info: old.mode_info,
+            pml4,
+            stack: (
+                PAddr::from(KERNEL_BASE + stack_base),
+                (reservation.stack.1 - stack_base) as usize,
+            ),
+            kernel_elf_offset: VAddr::from(KERNEL_BASE + reservation.elf.0),
+            acpi1_rsdp: old.acpi1_rsdp,
+            acpi2_rsdp: old.acpi2_rsdp,
+            modules,
+        };
+        ptr::write(args_vaddr.as_mut_ptr::<KernelArgs>(), args);
+    }
+
+    Ok(args_vaddr)
+}
+
+/// Switches to the new address-space and stack and jumps to the new kernel
+/// (this does the same as `jump_to_kernel` in the bootloader).
+///
+/// # Safety
+/// The code of this function has to be mapped in the new address-space.
+unsafe fn switch(pml4: PAddr, stack_top: u64, entry: u64, args: VAddr) -> ! {
+    llvm_asm!("
+        mov $0, %cr3
+        mov $1, %rsp
+        mov $1, %rbp
+        pushq $$0
+        jmp *$2"
+        :: "r" (pml4.as_u64()), "r" (stack_top), "r" (entry), "{rdi}" (args.as_u64())
+        : "memory"
+        : "volatile");
+
+    unreachable!("We're not supposed to return here from the new kernel.")
+}
+
+/// Soft-reboots into the kernel `binary`.
+///
+/// `module` is the boot module that contains the binary, if it's None we
+/// copy the binary into reserved memory for the new kernel. Only returns in
+/// case of an error.
+fn boot(binary: &[u8], module: Option<Module>) -> Result<(), KError> {
+    let kcb = get_kcb();
+    if kcb.arch.id() != 0 {
+        // The new kernel expects to start on the BSP
+        return Err(KError::NotSupported);
+    }
+
+    let elf = elfloader::ElfBinary::new("kernel", binary)
+        .map_err(|reason| KError::InvalidKernelImage { reason })?;
+    if !elf.is_pie() {
+        return Err(KError::InvalidKernelImage {
+            reason: "Kernel needs to be a position independent executable.",
+        });
+    }
+
+    let mut loader = KexecLoader {
+        image_size: if module.is_none() { binary.len() } else { 0 },
+        reservation: None,
+        mapping: Vec::with_capacity(8),
+    };
+    let loaded = elf.load(&mut loader);
+    let reservation = match (loaded, loader.reservation) {
+        (Ok(()), Some(reservation)) => reservation,
+        (Err(reason), r) => {
+            if let Some(r) = r {
+                release(r.frame);
+            }
+            return Err(KError::InvalidKernelImage { reason });
+        }
+        (Ok(()), None) => {
+            return Err(KError::InvalidKernelImage {
+                reason: "No loadable segments.",
+            })
+        }
+    };
+
+    let kernel = module.unwrap_or_else(|| {
+        let vaddr = paddr_to_kernel_vaddr(PAddr::from(reservation.image.0));
+        unsafe { ptr::copy_nonoverlapping(binary.as_ptr(), vaddr.as_mut_ptr(), binary.len()) };
+        Module::new(
+            "kernel",
+            vaddr,
+            PAddr::from(reservation.image.0),
+            binary.len(),
+        )
+    });
+
+    let descriptors = memory_map(&kcb.arch.kernel_args().mm_iter, &reservation);
+    let prepared = build_page_table(&reservation, &descriptors, &loader.mapping).and_then(|pml4| {
+        build_kernel_args(&reservation, &descriptors, pml4, kernel).map(|a| (pml4, a))
+    });
+    let (pml4, args) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            release(reservation.frame);
+            return Err(e);
+        }
+    };
+
+    // From here on the app cores are gone:
+    if let Err(e) = super::power::park_app_cores() {
+        release(reservation.frame);
+        return Err(e);
+    }
+
+    let entry = loader.offset() + elf.entry_point();
+    let stack_top = KERNEL_BASE + reservation.stack.1;
+    info!("Jumping into new kernel at {:#x}...", entry);
+    super::irq::disable();
+    unsafe { switch(pml4, stack_top, entry, args) }
+}
+
+/// Soft-reboots into the kernel binary of the boot module `name`.
+///
+/// Only returns in case of an error.
+pub fn boot_module(name: &str) -> Result<(), KError> {
+    let module = get_kcb()
+        .arch
+        .kernel_args()
+        .modules
+        .iter()
+        .find(|module| module.name() == name)
+        .cloned()
+        .ok_or(KError::InvalidKernelImage {
+            reason: "No boot module with this name.",
+        })?;
+
+    // Safe: Modules are mapped in kernel space and never removed
+    let binary = unsafe { module.as_slice() };
+    boot(binary, Some(module))
+}
+
+/// Soft-reboots into the kernel binary `image`.
+///
+/// Only returns in case of an error.
+pub fn boot_image(image: &[u8]) -> Result<(), KError> {
+    boot(image, None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn split_without_overlap() {
+        assert_eq!(split(0x1000, 0x5000, &[]), vec![(0x1000, 0x5000, None)]);
+        assert_eq!(
+            split(0x1000, 0x5000, &[(0x5000, 0x6000), (0x0, 0x1000)]),
+            vec![(0x1000, 0x5000, None)]
+        );
+    }
+
+    #[test]
+    fn split_with_overlap() {
+        // In the middle:
+        assert_eq!(
+            split(0x1000, 0x5000, &[(0x2000, 0x3000)]),
+            vec![
+                (0x1000, 0x2000, None),
+                (0x2000, 0x3000, Some(0)),
+                (0x3000, 0x5000, None)
+            ]
+        );
+
+        // Covers the beginning and the whole range:
+        assert_eq!(
+            split(0x1000, 0x5000, &[(0x0, 0x2000)]),
+            vec![(0x1000, 0x2000, Some(0)), (0x2000, 0x5000, None)]
+        );
+        assert_eq!(
+            split(0x1000, 0x5000, &[(0x0, 0x8000)]),
+            vec![(0x1000, 0x5000, Some(0))]
+        );
+    }
+
+    #[test]
+    fn split_multiple_ranges() {
+        // Ranges don't need to be sorted and empty ones are ignored:
+        assert_eq!(
+            split(
+                0x0,
+                0x8000,
+                &[
+                    (0x6000, 0x9000),
+                    (0x3000, 0x3000),
+                    (0x1000, 0x2000),
+                    (0x2000, 0x4000)
+                ]
+            ),
+            vec![
+                (0x0, 0x1000, None),
+                (0x1000, 0x2000, Some(2)),
+                (0x2000, 0x4000, Some(3)),
+                (0x4000, 0x6000, None),
+                (0x6000, 0x8000, Some(0))
+            ]
+        );
+    }
+}
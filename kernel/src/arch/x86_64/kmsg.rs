@@ -0,0 +1,328 @@
+//! The kernel logger.
+//!
+//! Besides writing log records to the serial line, we keep the most recent
+//! records in a ring buffer so user-space can read them later
+//! (`SystemOperation::ReadKernelLog`, e.g., for a `dmesg` tool).
+//!
+//! Logging starts before we have a heap, so the ring buffer has a fixed size
+//! and long module names or messages are truncated.
+//!
+//! A record can be logged while another one is (e.g., by an interrupt
+//! handler), so the logger never waits for its locks indefinitely: if it
+//! can't get the ring buffer the record is only printed, if it can't get the
+//! serial line it prints without it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::{Mutex, MutexGuard, Once};
+
+/// How many records we keep.
+const RECORDS: usize = 256;
+
+/// How many bytes of the module name we keep.
+const MODULE_LEN: usize = 48;
+
+/// How many bytes of the message we keep.
+const MESSAGE_LEN: usize = 160;
+
+/// How often we try to get a lock before we log without it (the one who
+/// holds it might be the code we interrupted).
+const LOCK_RETRIES: usize = 64;
+
+/// A log record in the ring buffer.
+#[derive(Copy, Clone)]
+struct Entry {
+    level: Level,
+    /// Nanoseconds since boot.
+    timestamp: u64,
+    module: [u8; MODULE_LEN],
+    module_len: usize,
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        level: Level::Error,
+        timestamp: 0,
+        module: [0; MODULE_LEN],
+        module_len: 0,
+        message: [0; MESSAGE_LEN],
+        message_len: 0,
+    };
+}
+
+/// Writes into a fixed buffer, drops whatever doesn't fit.
+pub(super) struct Truncate<'a> {
+    buf: &'a mut [u8],
+    pub(super) len: usize,
+}
+
+impl<'a> Truncate<'a> {
+    pub(super) fn new(buf: &'a mut [u8]) -> Truncate<'a> {
+        Truncate { buf, len: 0 }
+    }
+}
+
+impl<'a> Write for Truncate<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = core::cmp::min(s.len(), self.buf.len() - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Keeps the last `RECORDS` log records.
+struct Ring {
+    entries: [Entry; RECORDS],
+    /// Sequence number of the next record.
+    next: u64,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            entries: [Entry::EMPTY; RECORDS],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, level: Level, timestamp: u64, module: &str, args: &fmt::Arguments) {
+        let entry = &mut self.entries[self.next as usize % RECORDS];
+        entry.level = level;
+        entry.timestamp = timestamp;
+
+        let mut w = Truncate {
+            buf: &mut entry.module,
+            len: 0,
+        };
+        let _r = w.write_str(module);
+        entry.module_len = w.len;
+
+        let mut w = Truncate {
+            buf: &mut entry.message,
+            len: 0,
+        };
+        let _r = w.write_fmt(*args);
+        entry.message_len = w.len;
+
+        self.next += 1;
+    }
+
+    /// Copies the records we still have (oldest first) into `entries`, returns
+    /// the sequence number of the first one.
+    ///
+    /// Doesn't allocate if `entries` has space for `RECORDS` entries (the
+    /// allocator might log something while we hold the lock).
+    fn copy_into(&self, entries: &mut Vec<Entry>) -> u64 {
+        let first = self.next.saturating_sub(RECORDS as u64);
+        entries.extend((first..self.next).map(|seq| self.entries[seq as usize % RECORDS]));
+        first
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Tries to lock the ring buffer `LOCK_RETRIES` times.
+fn try_lock_ring() -> Option<MutexGuard<'static, Ring>> {
+    for _i in 0..LOCK_RETRIES {
+        match RING.try_lock() {
+            Some(ring) => return Some(ring),
+            None => core::hint::spin_loop(),
+        }
+    }
+    None
+}
+
+/// Log filter from the command-line, e.g., `info` or
+/// `bespin::memory=debug,topology::acpi=debug`.
+///
+/// A directive without module sets the level for all modules, otherwise the
+/// directive with the longest matching module wins.
+struct Filter(&'static str);
+
+impl Filter {
+    fn directives(&self) -> impl Iterator<Item = (Option<&'static str>, LevelFilter)> {
+        self.0.split(',').filter(|d| !d.is_empty()).filter_map(|d| {
+            let mut parts = d.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(level), None) => match level.parse() {
+                    Ok(level) => Some((None, level)),
+                    // Just a module name: log everything it does
+                    Err(_) => Some((Some(level), LevelFilter::Trace)),
+                },
+                (Some(module), Some(level)) => {
+                    level.parse().ok().map(|level| (Some(module), level))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// The level we log at in module `target`.
+    fn level(&self, target: &str) -> LevelFilter {
+        let mut best: Option<(usize, LevelFilter)> = None;
+        for (module, level) in self.directives() {
+            let len = match module {
+                None => 0,
+                Some(module) if target.starts_with(module) => module.len(),
+                Some(_) => continue,
+            };
+            if best.map_or(true, |(best_len, _)| len >= best_len) {
+                best = Some((len, level));
+            }
+        }
+        best.map_or(LevelFilter::Off, |(_, level)| level)
+    }
+
+    /// The most verbose level of all directives.
+    fn max_level(&self) -> LevelFilter {
+        self.directives()
+            .map(|(_, level)| level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+static FILTER: Once<Filter> = Once::new();
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.r#try().map_or(false, |filter| {
+            metadata.level() <= filter.level(metadata.target())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = rawtime::duration_since_boot().as_nanos() as u64;
+        if let Some(mut ring) = try_lock_ring() {
+            ring.push(record.level(), timestamp, record.target(), record.args());
+        }
+        super::eventring::log(record.level(), record.target(), record.args());
+
+        // Same as for the ring buffer: better to interleave with other
+        // output than to deadlock
+        let mut _serial = None;
+        for _i in 0..LOCK_RETRIES {
+            _serial = klogger::SERIAL_LINE_MUTEX.try_lock();
+            if _serial.is_some() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        sprintln!(
+            "[{:>5}] - {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel logger, `filter` is the log filter from the
+/// command-line.
+pub fn init(filter: &'static str) -> Result<(), log::SetLoggerError> {
+    let filter = FILTER.call_once(|| Filter(filter));
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(filter.max_level());
+    Ok(())
+}
+
+/// Returns the log records that are still in the ring buffer (oldest first).
+pub fn records() -> Vec<kpi::system::KernelLogRecord> {
+    let mut entries = Vec::with_capacity(RECORDS);
+    let first = RING.lock().copy_into(&mut entries);
+    to_records(first, &entries)
+}
+
+fn to_records(first: u64, entries: &[Entry]) -> Vec<kpi::system::KernelLogRecord> {
+    entries
+        .iter()
+        .zip(first..)
+        .map(|(entry, seq)| kpi::system::KernelLogRecord {
+            seq,
+            level: entry.level as u64,
+            timestamp: entry.timestamp,
+            module: String::from_utf8_lossy(&entry.module[..entry.module_len]).into(),
+            message: String::from_utf8_lossy(&entry.message[..entry.message_len]).into(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn records(ring: &Ring) -> Vec<kpi::system::KernelLogRecord> {
+        let mut entries = Vec::new();
+        let first = ring.copy_into(&mut entries);
+        to_records(first, &entries)
+    }
+
+    #[test]
+    fn filter() {
+        let f = Filter("info");
+        assert_eq!(f.level("bespin::memory"), LevelFilter::Info);
+        assert_eq!(f.max_level(), LevelFilter::Info);
+
+        let f = Filter("bespin::memory=debug,topology::acpi=trace");
+        assert_eq!(f.level("bespin::memory::tcache"), LevelFilter::Debug);
+        assert_eq!(f.level("topology::acpi"), LevelFilter::Trace);
+        assert_eq!(f.level("bespin::nr"), LevelFilter::Off);
+        assert_eq!(f.max_level(), LevelFilter::Trace);
+
+        let f = Filter("warn,bespin::memory=debug");
+        assert_eq!(f.level("bespin::memory"), LevelFilter::Debug);
+        assert_eq!(f.level("bespin::nr"), LevelFilter::Warn);
+
+        let f = Filter("bespin::memory");
+        assert_eq!(f.level("bespin::memory"), LevelFilter::Trace);
+        assert_eq!(f.level("bespin"), LevelFilter::Off);
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut ring = Ring::new();
+        assert!(records(&ring).is_empty());
+
+        ring.push(Level::Info, 1, "bespin", &format_args!("hello {}", 1));
+        let records = records(&ring);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq, 0);
+        assert_eq!(records[0].level, 3);
+        assert_eq!(records[0].timestamp, 1);
+        assert_eq!(records[0].module, "bespin");
+        assert_eq!(records[0].message, "hello 1");
+
+        for i in 0..RECORDS + 10 {
+            ring.push(Level::Debug, i as u64, "bespin", &format_args!("{}", i));
+        }
+        let records = records(&ring);
+        assert_eq!(records.len(), RECORDS);
+        assert_eq!(records[0].seq, 11);
+        assert_eq!(records[0].message, "10");
+        assert_eq!(records[RECORDS - 1].seq, RECORDS as u64 + 10);
+
+        // Long messages are truncated
+        let long = "x".repeat(2 * MESSAGE_LEN);
+        ring.push(Level::Warn, 0, "bespin", &format_args!("{}", long));
+        assert_eq!(records(&ring)[RECORDS - 1].message.len(), MESSAGE_LEN);
+    }
+}
@@ -0,0 +1,207 @@
+//! Cache and memory bandwidth monitoring (Intel CMT/MBM) of processes.
+//!
+//! A process gets a resource monitoring id (RMID) the first time one of its
+//! executors is dispatched, and from then on the core tags the L3 lines and
+//! memory traffic of the process with it (see `dispatch`). The occupancy and
+//! traffic are reported by `ProcessOperation::GetUsage` (`UsageKind::Cache`).
+//!
+//! The hardware counts per package and a core can only read the counters of
+//! its own package, so the readings are the ones of the package of the core
+//! that asks. The traffic counters are narrow and wrap, we accumulate them
+//! into 64-bit totals whenever they are read. Traffic is accounted from the
+//! first time it was read on a package (sample it to get the bandwidth).
+
+use alloc::collections::VecDeque;
+
+use hashbrown::HashMap;
+use kpi::process::CacheUsage;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::process::Pid;
+
+use super::cat::{IA32_PQR_ASSOC, PQR_RMID_MASK};
+use super::kcb::Arch86Kcb;
+
+/// Selects the event and RMID that `IA32_QM_CTR` reports.
+const IA32_QM_EVTSEL: u32 = 0xc8d;
+/// Monitoring counter of the selected event and RMID.
+const IA32_QM_CTR: u32 = 0xc8e;
+
+/// Event id of the L3 occupancy.
+const EVENT_L3_OCCUPANCY: u64 = 0x1;
+/// Event id of the total (local and remote) memory traffic.
+const EVENT_TOTAL_TRAFFIC: u64 = 0x2;
+
+/// The counter couldn't be read (e.g., unsupported event or RMID).
+const QM_CTR_ERROR: u64 = 1 << 63;
+/// There is no data for the RMID (yet).
+const QM_CTR_UNAVAILABLE: u64 = 1 << 62;
+
+/// All CPUs with MBM have at least 24-bit traffic counters, we only rely on
+/// those bits.
+const TRAFFIC_COUNTER_MASK: u64 = (1 << 24) - 1;
+
+/// Traffic counter of an RMID on a package.
+#[derive(Debug, Default, Copy, Clone)]
+struct Traffic {
+    /// Last value we read from the hardware.
+    last: u64,
+    /// Bytes accumulated so far.
+    total: u64,
+}
+
+/// RMIDs handed out to processes (RMID 0 is the one of the kernel and of
+/// processes we ran out of RMIDs for).
+struct Rmids {
+    processes: HashMap<Pid, u16>,
+    /// RMIDs of exited processes, reused oldest first to give the lines
+    /// they still tag time to get evicted.
+    free: VecDeque<u16>,
+    next: u16,
+}
+
+lazy_static! {
+    /// Highest RMID and the bytes per counter unit (None if we can't monitor).
+    static ref SUPPORT: Option<(u16, u64)> = support();
+
+    static ref RMIDS: Mutex<Rmids> = Mutex::new(Rmids {
+        processes: HashMap::new(),
+        free: VecDeque::new(),
+        next: 1,
+    });
+
+    /// Indexed by package and RMID.
+    static ref TRAFFIC: Mutex<HashMap<(u64, u16), Traffic>> = Mutex::new(HashMap::new());
+}
+
+fn support() -> Option<(u16, u64)> {
+    let l3 = CpuId::new().get_rdt_monitoring_info()?.l3_monitoring()?;
+    if !l3.has_occupancy_monitoring() || !l3.has_total_bandwidth_monitoring() {
+        return None;
+    }
+    let max_rmid = core::cmp::min(l3.maximum_rmid_range() as u64, PQR_RMID_MASK);
+    Some((max_rmid as u16, l3.conversion_factor() as u64))
+}
+
+/// The RMID of process `pid` (assigns one if it doesn't have one yet).
+fn rmid(pid: Pid, max_rmid: u16) -> u16 {
+    let mut rmids = RMIDS.lock();
+    if let Some(rmid) = rmids.processes.get(&pid) {
+        return *rmid;
+    }
+
+    let rmid = if rmids.next <= max_rmid {
+        rmids.next += 1;
+        rmids.next - 1
+    } else {
+        rmids.free.pop_front().unwrap_or(0)
+    };
+    if rmid != 0 {
+        rmids.processes.insert(pid, rmid);
+    }
+    rmid
+}
+
+/// Releases the RMID of a process that exited.
+pub fn unregister(pid: Pid) {
+    let mut rmids = RMIDS.lock();
+    if let Some(rmid) = rmids.processes.remove(&pid) {
+        TRAFFIC.lock().retain(|(_package, r), _t| *r != rmid);
+        rmids.free.push_back(rmid);
+    }
+}
+
+/// Tags everything this core does with the RMID of process `pid` (that
+/// we're about to run).
+pub fn dispatch(kcb: &mut Kcb<Arch86Kcb>, pid: Pid) {
+    if kcb.arch.monitored_pid == pid {
+        return;
+    }
+    let (max_rmid, _factor) = match *SUPPORT {
+        Some(support) => support,
+        None => return,
+    };
+    kcb.arch.monitored_pid = pid;
+
+    let rmid = rmid(pid, max_rmid);
+    unsafe {
+        let assoc = rdmsr(IA32_PQR_ASSOC);
+        wrmsr(IA32_PQR_ASSOC, assoc & !PQR_RMID_MASK | rmid as u64);
+    }
+}
+
+/// The value of a monitoring counter (None if there is no data).
+fn counter_value(ctr: u64) -> Option<u64> {
+    if ctr & (QM_CTR_ERROR | QM_CTR_UNAVAILABLE) != 0 {
+        None
+    } else {
+        Some(ctr)
+    }
+}
+
+/// Counter units between two reads of a traffic counter.
+fn traffic_delta(last: u64, now: u64) -> u64 {
+    now.wrapping_sub(last) & TRAFFIC_COUNTER_MASK
+}
+
+fn read(rmid: u16, event: u64) -> Option<u64> {
+    unsafe {
+        wrmsr(IA32_QM_EVTSEL, (rmid as u64) << 32 | event);
+        counter_value(rdmsr(IA32_QM_CTR))
+    }
+}
+
+/// LLC occupancy and memory traffic of process `pid` in the package of the
+/// core we run on.
+pub fn usage(pid: Pid) -> Result<CacheUsage, KError> {
+    let (_max_rmid, factor) = SUPPORT.ok_or(KError::NotSupported)?;
+    let rmid = match RMIDS.lock().processes.get(&pid) {
+        Some(rmid) => *rmid,
+        // Never ran (or we ran out of RMIDs)
+        None => return Ok(Default::default()),
+    };
+
+    let package = topology::MACHINE_TOPOLOGY.current_thread().package_id as u64;
+    let llc_occupancy = read(rmid, EVENT_L3_OCCUPANCY).unwrap_or(0) * factor;
+    let mut traffic = TRAFFIC.lock();
+    let memory_traffic = match read(rmid, EVENT_TOTAL_TRAFFIC) {
+        Some(now) => {
+            let counter = traffic.entry((package, rmid)).or_insert(Traffic {
+                last: now,
+                total: 0,
+            });
+            counter.total += traffic_delta(counter.last, now) * factor;
+            counter.last = now;
+            counter.total
+        }
+        None => traffic.get(&(package, rmid)).map_or(0, |t| t.total),
+    };
+
+    Ok(CacheUsage {
+        llc_occupancy,
+        memory_traffic,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        assert_eq!(counter_value(0x1234), Some(0x1234));
+        assert_eq!(counter_value(QM_CTR_ERROR | 0x1234), None);
+        assert_eq!(counter_value(QM_CTR_UNAVAILABLE), None);
+
+        assert_eq!(traffic_delta(10, 25), 15);
+        // The counter wrapped
+        assert_eq!(traffic_delta(TRAFFIC_COUNTER_MASK - 4, 5), 10);
+        // Bits above the ones we rely on don't matter
+        assert_eq!(traffic_delta(TRAFFIC_COUNTER_MASK, 1 << 24 | 3), 4);
+    }
+}
@@ -36,16 +36,37 @@ use node_replication::Replica;
 
 use apic::x2apic;
 
+pub mod asyncring;
+pub mod cat;
+pub mod cet;
 pub mod coreboot;
 pub mod debug;
+pub mod devices;
+pub mod eventring;
 pub mod gdt;
 pub mod irq;
+pub mod isolation;
 pub mod kcb;
+pub mod kexec;
+pub mod kmsg;
+pub mod mbm;
 pub mod memory;
+pub mod pci;
+pub mod pkeys;
+pub mod power;
+pub mod printlimit;
+pub mod printq;
 pub mod process;
+pub mod reserved;
+pub mod rng;
+#[cfg(feature = "selftest")]
+pub mod selftest;
+pub mod steering;
 pub mod syscall;
+pub mod telemetry;
 pub mod timer;
 pub mod tlb;
+pub mod tscsync;
 pub mod vspace;
 
 use uefi::table::boot::MemoryType;
@@ -54,9 +75,8 @@ pub mod acpi;
 mod isr;
 
 pub use bootloader_shared::*;
-use klogger;
 
-use crate::kcb::{BootloaderArguments, Kcb};
+use crate::kcb::{BootloaderArguments, Kcb, MemoryPhase};
 use crate::memory::{
     tcache, tcache_sp, Frame, GlobalMemory, PhysicalPageProvider, BASE_PAGE_SIZE, LARGE_PAGE_SIZE,
 };
@@ -210,10 +230,14 @@ struct AppCoreArgs {
 /// This is almost identical to `_start` which is initializing the BSP core
 /// (and called from UEFI instead).
 fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
+    coreboot::set_boot_stage(coreboot::BootStage::Entered);
     enable_sse();
     enable_fsgsbase();
+    pkeys::enable();
+    cet::enable();
     assert_required_cpu_features();
     syscall::enable_fast_syscalls();
+    rng::init();
     irq::disable();
 
     unsafe {
@@ -258,6 +282,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
 
         let mlnr_replica = args.mlnr_replica.register().unwrap();
         kcb.arch.setup_mlnr(args.mlnr_replica.clone(), mlnr_replica);
+        coreboot::set_boot_stage(coreboot::BootStage::Registered);
 
         // Don't modify this line without adjusting `coreboot` integration test:
         info!(
@@ -270,6 +295,8 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
 
     // Signals to BSP core that we're done initializing.
     initialized.store(true, Ordering::SeqCst);
+    // The BSP measures the offset of our TSC next
+    tscsync::respond();
 
     crate::scheduler::schedule()
 }
@@ -299,6 +326,8 @@ fn boot_app_cores(
 ) {
     let bsp_thread = topology::MACHINE_TOPOLOGY.current_thread();
     let kcb = kcb::get_kcb();
+    // The app cores get their memory from GlobalMemory
+    kcb.require_memory_phase(MemoryPhase::Full);
 
     // Let's go with one replica per NUMA node for now:
     let numa_nodes = topology::MACHINE_TOPOLOGY.num_nodes();
@@ -372,25 +401,26 @@ fn boot_app_cores(
             );
 
             // Wait until core is up or we time out
-            let timeout = x86::time::rdtsc() + 1_000_000_000;
-            loop {
-                // Did the core signal us initialization completed?
-                if initialized.load(Ordering::SeqCst) {
-                    break;
+            if let Err(stage) = coreboot::wait_until_initialized(&initialized, 1_000_000_000) {
+                match stage {
+                    Some(stage) => panic!(
+                        "Core {:?} didn't boot properly, stuck at {:?}: {}",
+                        thread.apic_id(),
+                        stage,
+                        stage.diagnosis()
+                    ),
+                    None => panic!(
+                        "Core {:?} didn't boot properly, its boot stage is garbage",
+                        thread.apic_id()
+                    ),
                 }
-
-                // Have we waited long enough?
-                if x86::time::rdtsc() > timeout {
-                    panic!("Core {:?} didn't boot properly...", thread.apic_id());
-                }
-
-                core::hint::spin_loop();
             }
         }
         core::mem::forget(coreboot_stack);
 
         assert!(initialized.load(Ordering::SeqCst));
         debug!("Core {:?} has started", thread.apic_id());
+        tscsync::measure(thread.id);
         kcb.set_allocation_affinity(0).expect("Can't set affinity");
     }
     core::mem::forget(replicas);
@@ -464,6 +494,8 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     sprint!("\r\n");
     enable_sse();
     enable_fsgsbase();
+    pkeys::enable();
+    cet::enable();
     unsafe {
         gdt::setup_early_gdt();
         irq::setup_early_idt();
@@ -480,7 +512,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
 
     // Parse the command line arguments
     let cmdline = BootloaderArguments::from_str(kernel_args.command_line);
-    klogger::init(cmdline.log_filter).expect("Can't set-up logging");
+    kmsg::init(cmdline.log_filter).expect("Can't set-up logging");
 
     info!(
         "Started at {} with {:?} since CPU startup",
@@ -522,6 +554,10 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Ideally, if this works, we should end up with an early TCache
     // that has a small amount of space we can allocate from, and a list of (yet) unmaintained
     // regions of memory.
+    //
+    // Memory that is already in use (bootstrap code, ACPI tables, modules,
+    // frame-buffer) is reserved first and cut out of the regions.
+    reserved::init(kernel_args);
     let mut emanager: Option<tcache_sp::TCacheSp> = None;
     let mut memory_regions = ArrayVec::<[Frame; 64]>::new();
     for region in &mut kernel_args.mm_iter {
@@ -530,37 +566,41 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
 
             let base: PAddr = PAddr::from(region.phys_start);
             let size: usize = region.page_count as usize * BASE_PAGE_SIZE;
-            let f = Frame::new(base, size, 0);
-
-            const ONE_MIB: usize = 1 * 1024 * 1024;
-            const EARLY_MEMORY_CAPACITY: usize = 32 * 1024 * 1024;
-            if base.as_usize() >= ONE_MIB {
-                if size > EARLY_MEMORY_CAPACITY && emanager.is_none() {
-                    // This seems like a good frame for the early allocator on the BSP core.
-                    // We don't have NUMA information yet so we'd hope that on
-                    // a NUMA machine this memory will be on node 0.
-                    // Ideally `mem_iter` is ordered by physical address which would increase
-                    // our chances, but the UEFI spec doesn't guarantee anything :S
-                    let (early_frame, high) = f.split_at(EARLY_MEMORY_CAPACITY);
-                    emanager = Some(tcache_sp::TCacheSp::new_with_frame(0, 0, early_frame));
-
-                    if high != Frame::empty() {
+
+            for f in reserved::exclude(Frame::new(base, size, 0)) {
+                let (base, size) = (f.base, f.size());
+                const ONE_MIB: usize = 1 * 1024 * 1024;
+                const EARLY_MEMORY_CAPACITY: usize = 32 * 1024 * 1024;
+                if base.as_usize() >= ONE_MIB {
+                    if size > EARLY_MEMORY_CAPACITY && emanager.is_none() {
+                        // This seems like a good frame for the early allocator on the BSP core.
+                        // We don't have NUMA information yet so we'd hope that on
+                        // a NUMA machine this memory will be on node 0.
+                        // Ideally `mem_iter` is ordered by physical address which would increase
+                        // our chances, but the UEFI spec doesn't guarantee anything :S
+                        let (early_frame, high) = f.split_at(EARLY_MEMORY_CAPACITY);
+                        assert!(!reserved::overlaps(&early_frame));
+                        emanager = Some(tcache_sp::TCacheSp::new_with_frame(0, 0, early_frame));
+
+                        if high != Frame::empty() {
+                            assert!(!memory_regions.is_full());
+                            memory_regions.push(high);
+                        }
+                    } else {
                         assert!(!memory_regions.is_full());
-                        memory_regions.push(high);
+                        memory_regions.push(f);
                     }
                 } else {
-                    assert!(!memory_regions.is_full());
-                    memory_regions.push(f);
+                    // Ignore all physical memory below 1 MiB
+                    // because it's not worth the hassle of dealing with it
+                    // (the bootstrap code for the app cores lives there
+                    // but it's reserved anyways, see `reserved`).
                 }
-            } else {
-                // Ignore all physical memory below 1 MiB
-                // because it's not worth the hassle of dealing with it
-                // Some of the memory here will be used by coreboot, there we just assume
-                // the memory is free for us to use -- so in case someone
-                // wants to change it have a look there first!
             }
         }
     }
+    // The allocators must never hand out reserved memory
+    assert!(memory_regions.iter().all(|f| !reserved::overlaps(f)));
     let emanager = emanager
         .expect("Couldn't build an early physical memory manager, increase system main memory?");
 
@@ -613,7 +653,23 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         trace!("{:#?}", *topology::MACHINE_TOPOLOGY);
     }
 
-    // Identify NUMA region for physical memory (needs topology)
+    // Find the interrupt lines of PCI devices in the ACPI namespace (needs
+    // ACPI and alloc)
+    {
+        let routes = acpi::pci_irq_routes();
+        debug!("Found {} PCI interrupt routes", routes.len());
+        steering::set_pci_routes(routes);
+    }
+
+    // Find the devices on the PCI bus (needs alloc)
+    pci::enumerate();
+
+    // Bind the kernel drivers to the devices we found (needs ACPI and PCI)
+    devices::init();
+
+    // Identify NUMA region for physical memory (needs topology), everything
+    // up to here only had the early allocator
+    assert_eq!(kcb::get_kcb().memory_phase(), MemoryPhase::Early);
     let mut annotated_regions = ArrayVec::<[Frame; 64]>::new();
     identify_numa_affinity(&memory_regions, &mut annotated_regions);
     // Make sure we don't accidentially use the memory_regions but rather,
@@ -633,7 +689,9 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     let global_memory_static =
         unsafe { core::mem::transmute::<&GlobalMemory, &'static GlobalMemory>(&global_memory) };
 
-    // Make sure our BSP core has a reference to GlobalMemory
+    // Make sure our BSP core has a reference to GlobalMemory, this moves
+    // memory management from the early allocator (`MemoryPhase::Early`) over
+    // `MemoryPhase::NumaReady` to the TCache of the core (`MemoryPhase::Full`)
     {
         let kcb = kcb::get_kcb();
         kcb.set_global_memory(&global_memory_static);
@@ -709,6 +767,9 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         mlnr_replica,
     );
 
+    #[cfg(feature = "selftest")]
+    selftest::run();
+
     // Done with initialization, now we go in
     // the arch-independent part:
     xmain();
@@ -721,3 +782,14 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
 pub fn advance_mlnr_replica() {
     tlb::eager_advance_mlnr_replica();
 }
+
+/// Executes the asynchronous system calls the process on this core submitted.
+pub fn poll_async_ring() {
+    asyncring::poll_current();
+}
+
+/// Takes the current core out of service after its kernel panicked (see
+/// `isolation`), returns if that's not possible.
+pub fn isolate_core() {
+    isolation::isolate();
+}
@@ -0,0 +1,730 @@
+//! PCI bus enumeration and device handles for user-space drivers.
+//!
+//! We scan the configuration space (through the legacy `0xcf8`/`0xcfc`
+//! ports) once at boot ([`enumerate`]) and remember every function we find
+//! along with its base address registers (BARs).
+//!
+//! Processes claim a device by vendor and device id (`DeviceOperation::Claim`)
+//! instead of poking at a hardcoded bus/device/function. A claimed device
+//! belongs to that process until it gives it back or exits, only the owner
+//! can map its BARs and access its configuration space.
+//!
+//! The initial process can also assign a device to another process
+//! ([`assign`]): the kernel claims it for the process, maps its BARs and
+//! routes its MSI-X vectors to the cores of the process. We don't have an
+//! IOMMU driver, so an assigned device can still DMA to all of memory.
+//!
+//! Devices can disappear from the bus at any time (surprise removal). We
+//! periodically check that the devices in use are still there
+//! ([`check_removed`]) and clean up after the ones that are gone: the kernel
+//! driver detaches, the owner loses the mappings of the BARs and its MSI
+//! vectors and gets a `kpi::upcall::DEVICE_REMOVED` upcall.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::io;
+
+use kpi::io::PciBar;
+
+use crate::error::KError;
+use crate::memory::vspace::{AddressSpaceError, MapAction};
+use crate::memory::{paddr_to_kernel_vaddr, Frame, PAddr, VAddr, BASE_PAGE_SIZE};
+use crate::nr;
+use crate::process::Pid;
+
+use super::memory::KERNEL_BASE;
+use super::process::Ring3Process;
+
+/// Selects the register in the configuration space.
+const PCI_CONF_ADDR: u16 = 0xcf8;
+/// Data of the register selected with `PCI_CONF_ADDR`.
+const PCI_CONF_DATA: u16 = 0xcfc;
+
+/// Size of the (legacy) configuration space of a function.
+pub const CONFIG_SPACE_SIZE: u64 = 256;
+
+/// Offset of the vendor id (low 16 bits) and device id (high 16 bits).
+const REG_ID: u64 = 0x00;
+/// Offset of the command (low 16 bits) and status (high 16 bits) registers.
+const REG_COMMAND: u64 = 0x04;
+/// Offset of the class code (high 24 bits) and revision id (low 8 bits).
+const REG_CLASS: u64 = 0x08;
+/// Offset of the register that contains the header type (bits 16..24).
+const REG_HEADER: u64 = 0x0c;
+/// Offset of the first BAR.
+const REG_BAR0: u64 = 0x10;
+/// Offset of the pointer to the first capability.
+const REG_CAPABILITIES: u64 = 0x34;
+
+/// Decoding of the I/O and memory space in the command register.
+const COMMAND_DECODE: u32 = 0x3;
+/// Set in the header type of multi-function devices.
+const HEADER_MULTI_FUNCTION: u32 = 0x80;
+/// Bridges (header type 1) only have two BARs.
+const HEADER_BRIDGE: u32 = 0x01;
+/// Set in the status register of functions with a capability list.
+const STATUS_CAPABILITIES: u32 = 1 << (16 + 4);
+/// A function has at most 48 capabilities (in the legacy configuration
+/// space), we stop there in case the list has a loop.
+const MAX_CAPABILITIES: usize = 48;
+
+/// Capability id of MSI-X.
+const CAP_MSIX: u32 = 0x11;
+/// Enables MSI-X (in the message control, the high half of the first dword
+/// of the capability).
+const MSIX_ENABLE: u32 = 1 << 31;
+/// Masks all MSI-X vectors of the function.
+const MSIX_FUNCTION_MASK: u32 = 1 << 30;
+/// Size of an entry in the MSI-X table.
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// Functions have at most 6 BARs.
+pub const MAX_BARS: usize = 6;
+
+/// A function on the PCI bus.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciDevice {
+    /// `bus << 8 | device << 3 | function` (also used as handle).
+    pub address: u64,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class code, subclass and programming interface.
+    pub class: u32,
+    /// BARs of the function, the upper half of 64-bit BARs is `None`.
+    pub bars: [Option<PciBar>; MAX_BARS],
+    /// Process that claimed the device.
+    pub owner: Option<Pid>,
+    /// Driven by a kernel driver (see `devices`), can't be claimed.
+    pub reserved: bool,
+}
+
+lazy_static! {
+    /// All functions we found on the bus (see `enumerate`).
+    static ref DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+}
+
+/// The value for `PCI_CONF_ADDR` to access register `offset` of the
+/// function at `address`.
+fn config_address(address: u64, offset: u64) -> u32 {
+    debug_assert!(offset < CONFIG_SPACE_SIZE);
+    (1 << 31) | (address as u32) << 8 | (offset as u32 & 0xfc)
+}
+
+fn config_read(address: u64, offset: u64) -> u32 {
+    // Safe: we are the only ones accessing the configuration ports (user-space
+    // goes through `config_read`/`config_write` on a claimed device)
+    unsafe {
+        io::outl(PCI_CONF_ADDR, config_address(address, offset));
+        io::inl(PCI_CONF_DATA)
+    }
+}
+
+fn config_write(address: u64, offset: u64, value: u32) {
+    unsafe {
+        io::outl(PCI_CONF_ADDR, config_address(address, offset));
+        io::outl(PCI_CONF_DATA, value);
+    }
+}
+
+/// The size of a BAR from what it reads back after all ones were written to
+/// it (`mask`, the bits of the address that aren't hardwired to zero).
+fn bar_size(mask: u64, io: bool) -> u64 {
+    let mask = if io { mask & !0x3 } else { mask & !0xf };
+    if mask == 0 {
+        0
+    } else {
+        1 << mask.trailing_zeros()
+    }
+}
+
+/// Reads BAR `index` and its size (the decoding of the function has to be
+/// disabled while we do this).
+///
+/// Returns the BAR and whether it is a 64-bit BAR (which occupies the next
+/// register too).
+fn probe_bar(address: u64, index: usize) -> (Option<PciBar>, bool) {
+    let offset = REG_BAR0 + 4 * index as u64;
+    let low = config_read(address, offset);
+    config_write(address, offset, 0xffff_ffff);
+    let low_mask = config_read(address, offset);
+    config_write(address, offset, low);
+
+    let io = low & 0x1 != 0;
+    let wide = !io && (low >> 1) & 0x3 == 0x2 && index + 1 < MAX_BARS;
+    let (encoded, mask) = if wide {
+        let high = config_read(address, offset + 4);
+        config_write(address, offset + 4, 0xffff_ffff);
+        let high_mask = config_read(address, offset + 4);
+        config_write(address, offset + 4, high);
+        (
+            (high as u64) << 32 | low as u64,
+            (high_mask as u64) << 32 | low_mask as u64,
+        )
+    } else {
+        let mask = if io {
+            // The upper 16 bits of I/O BARs can be hardwired to zero
+            low_mask as u64 | 0xffff_0000
+        } else {
+            low_mask as u64 | 0xffff_ffff_0000_0000
+        };
+        (low as u64, mask)
+    };
+
+    let size = bar_size(mask, io);
+    if size == 0 {
+        (None, wide)
+    } else {
+        (Some(PciBar::from_encoded(encoded, size)), wide)
+    }
+}
+
+/// Reads of the configuration space of a function that isn't there return
+/// all ones.
+fn absent(id: u32) -> bool {
+    id & 0xffff == 0xffff
+}
+
+/// Finds the capability with `id` in a configuration space that we access
+/// with `read`, returns its offset.
+fn capability<F: Fn(u64) -> u32>(read: F, id: u32) -> Option<u64> {
+    if read(REG_COMMAND) & STATUS_CAPABILITIES == 0 {
+        return None;
+    }
+    let mut offset = (read(REG_CAPABILITIES) & 0xfc) as u64;
+    for _i in 0..MAX_CAPABILITIES {
+        if offset == 0 {
+            return None;
+        }
+        let header = read(offset);
+        if header & 0xff == id {
+            return Some(offset);
+        }
+        offset = ((header >> 8) & 0xfc) as u64;
+    }
+    None
+}
+
+/// Where the MSI-X table of a function is.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct MsixTable {
+    /// Offset of the capability in the configuration space.
+    capability: u64,
+    /// The BAR that contains the table.
+    bar: usize,
+    /// Offset of the table in the BAR.
+    offset: u64,
+    entries: u64,
+}
+
+/// Reads the MSI-X capability of the function at `address`.
+fn msix_table(address: u64) -> Option<MsixTable> {
+    let capability = capability(|offset| config_read(address, offset), CAP_MSIX)?;
+    let control = config_read(address, capability) >> 16;
+    let table = config_read(address, capability + 4);
+    Some(MsixTable {
+        capability,
+        bar: (table & 0x7) as usize,
+        offset: (table & !0x7) as u64,
+        entries: (control & 0x7ff) as u64 + 1,
+    })
+}
+
+/// Reads the id, class and BARs of the function at `address`.
+fn probe(address: u64) -> Option<PciDevice> {
+    let id = config_read(address, REG_ID);
+    if absent(id) {
+        return None;
+    }
+
+    let mut device = PciDevice {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: config_read(address, REG_CLASS) >> 8,
+        bars: [None; MAX_BARS],
+        owner: None,
+        reserved: false,
+    };
+
+    let header = (config_read(address, REG_HEADER) >> 16) & 0x7f;
+    let bars = match header {
+        0 => MAX_BARS,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+
+    let command = config_read(address, REG_COMMAND);
+    config_write(address, REG_COMMAND, command & !COMMAND_DECODE);
+    let mut index = 0;
+    while index < bars {
+        let (bar, wide) = probe_bar(address, index);
+        device.bars[index] = bar;
+        index += if wide { 2 } else { 1 };
+    }
+    config_write(address, REG_COMMAND, command);
+
+    Some(device)
+}
+
+/// Scans all buses for functions (needs alloc).
+pub fn enumerate() {
+    let mut devices = Vec::new();
+    for bus in 0..256 {
+        for slot in 0..32 {
+            let address = bus << 8 | slot << 3;
+            let first = match probe(address) {
+                Some(device) => device,
+                None => continue,
+            };
+            devices.push(first);
+
+            let header = config_read(address, REG_HEADER) >> 16;
+            if header & HEADER_MULTI_FUNCTION != 0 {
+                devices.extend((1..8).filter_map(|function| probe(address | function)));
+            }
+        }
+    }
+
+    for device in devices.iter() {
+        debug!(
+            "PCI {:02x}:{:02x}.{} {:04x}:{:04x} class {:06x}",
+            device.address >> 8,
+            (device.address >> 3) & 0x1f,
+            device.address & 0x7,
+            device.vendor_id,
+            device.device_id,
+            device.class
+        );
+    }
+    *DEVICES.lock() = devices;
+}
+
+/// All functions we found on the bus.
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+/// Finds the `index`-th function with `vendor_id` and `device_id`.
+fn find(devices: &[PciDevice], vendor_id: u16, device_id: u16, index: u64) -> Option<usize> {
+    devices
+        .iter()
+        .enumerate()
+        .filter(|(_idx, d)| d.vendor_id == vendor_id && d.device_id == device_id)
+        .nth(index as usize)
+        .map(|(idx, _d)| idx)
+}
+
+/// The device behind `handle` if it was claimed by `pid`.
+fn owned(devices: &mut [PciDevice], pid: Pid, handle: u64) -> Result<&mut PciDevice, KError> {
+    devices
+        .iter_mut()
+        .find(|d| d.address == handle && d.owner == Some(pid))
+        .ok_or(KError::InvalidDeviceHandle { handle })
+}
+
+/// Checks that the device behind `handle` was claimed by `pid`.
+pub fn check_owner(pid: Pid, handle: u64) -> Result<(), KError> {
+    let mut devices = DEVICES.lock();
+    owned(&mut devices, pid, handle).map(|_device| ())
+}
+
+/// Claims the `index`-th function with `vendor_id` and `device_id` for
+/// process `pid`, returns the handle of the device.
+pub fn claim(pid: Pid, vendor_id: u16, device_id: u16, index: u64) -> Result<u64, KError> {
+    let mut devices = DEVICES.lock();
+    let id = (vendor_id as u64) << 16 | device_id as u64;
+    let idx = find(&devices, vendor_id, device_id, index).ok_or(KError::NoPciDevice { id })?;
+
+    let device = &mut devices[idx];
+    match device.owner {
+        _ if device.reserved => Err(KError::DeviceClaimed { id }),
+        Some(owner) if owner != pid => Err(KError::DeviceClaimed { id }),
+        _ => {
+            device.owner = Some(pid);
+            Ok(device.address)
+        }
+    }
+}
+
+/// Reserves the function at `address` for a kernel driver.
+pub fn reserve(address: u64) -> Result<(), KError> {
+    let mut devices = DEVICES.lock();
+    let device = devices
+        .iter_mut()
+        .find(|d| d.address == address)
+        .ok_or(KError::InvalidDeviceHandle { handle: address })?;
+    let id = (device.vendor_id as u64) << 16 | device.device_id as u64;
+    if device.owner.is_some() || device.reserved {
+        return Err(KError::DeviceClaimed { id });
+    }
+    device.reserved = true;
+    Ok(())
+}
+
+/// Gives the function at `address` back (the kernel driver detached).
+pub fn unreserve(address: u64) {
+    if let Some(device) = DEVICES.lock().iter_mut().find(|d| d.address == address) {
+        device.reserved = false;
+    }
+}
+
+/// Assigns the `index`-th function with `vendor_id` and `device_id` to
+/// process `pid` (on behalf of the initial process).
+///
+/// Claims the device for `pid`, routes one MSI-X vector to every core the
+/// process runs on and maps the memory BARs into the process (at their
+/// physical address). Returns the handle and the first MSI-X vector and the
+/// number of vectors (`count << 32 | first`).
+pub fn assign(pid: Pid, vendor_id: u16, device_id: u16, index: u64) -> Result<(u64, u64), KError> {
+    let device = {
+        let mut devices = DEVICES.lock();
+        let id = (vendor_id as u64) << 16 | device_id as u64;
+        let idx = find(&devices, vendor_id, device_id, index).ok_or(KError::NoPciDevice { id })?;
+        let device = &mut devices[idx];
+        if device.reserved || device.owner.map_or(false, |owner| owner != pid) {
+            return Err(KError::DeviceClaimed { id });
+        }
+        device.owner = Some(pid);
+        *device
+    };
+
+    let vectors = match route_msix(pid, &device) {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            let _r = release(pid, device.address);
+            return Err(e);
+        }
+    };
+
+    // BARs can share a page, we map every page once
+    let mut mapped: Vec<(u64, u64)> = Vec::with_capacity(MAX_BARS);
+    let node = super::kcb::get_kcb().node;
+    for bar in device.bars.iter().flatten().filter(|bar| !bar.io) {
+        let (start, end) = bar_pages(bar);
+        if mapped.iter().any(|(s, e)| start < *e && *s < end) {
+            continue;
+        }
+        let frame = Frame::new(PAddr::from(start), (end - start) as usize, node);
+        if let Err(e) =
+            nr::KernelNode::<Ring3Process>::map_device_frame(pid, frame, MapAction::ReadWriteUser)
+        {
+            // Undo what we did so far, like a failed `route_msix`
+            for (start, _end) in mapped {
+                if let Ok(handle) = nr::KernelNode::<Ring3Process>::unmap(pid, VAddr::from(start)) {
+                    super::tlb::shootdown(handle);
+                }
+            }
+            super::steering::release_function_msi(device.address);
+            let _r = release(pid, device.address);
+            return Err(e);
+        }
+        mapped.push((start, end));
+    }
+
+    Ok((device.address, vectors))
+}
+
+/// Routes the MSI-X vectors of `device` to the cores of process `pid`,
+/// returns `count << 32 | first` (0 if the device doesn't have MSI-X).
+fn route_msix(pid: Pid, device: &PciDevice) -> Result<u64, KError> {
+    let table = match msix_table(device.address) {
+        Some(table) => table,
+        None => return Ok(0),
+    };
+    let bar = device
+        .bars
+        .get(table.bar)
+        .copied()
+        .flatten()
+        .filter(|bar| !bar.io)
+        .ok_or(KError::InvalidBar {
+            index: table.bar as u64,
+        })?;
+
+    let mut cores = match nr::KernelNode::<Ring3Process>::process_cores(pid)? {
+        cores if cores.is_empty() => return Err(KError::NoExecutorForCore),
+        cores => cores,
+    };
+    cores.sort_unstable();
+    cores.truncate(table.entries as usize);
+    let vectors = super::steering::allocate_msix(pid, device.address, &cores)?;
+
+    // The kernel writes the table through its alias of the BAR
+    let start = bar.base + table.offset;
+    let (pstart, pend) = bar_pages(&PciBar {
+        base: start,
+        size: table.entries * MSIX_ENTRY_SIZE,
+        ..bar
+    });
+    let mapped = crate::memory::KernelAllocator::try_refill_tcache(4, 0)
+        .map_err(KError::from)
+        .and_then(|_r| {
+            let kcb = super::kcb::get_kcb();
+            let mut vspace = kcb.arch.init_vspace();
+            match vspace.map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                PAddr::from(pstart),
+                (pend - pstart) as usize,
+                MapAction::ReadWriteKernel,
+            ) {
+                Ok(()) | Err(AddressSpaceError::AlreadyMapped { .. }) => Ok(()),
+                Err(e) => Err(KError::from(e)),
+            }
+        });
+    if let Err(e) = mapped {
+        super::steering::release_function_msi(device.address);
+        return Err(e);
+    }
+
+    let entries = paddr_to_kernel_vaddr(PAddr::from(start)).as_mut_ptr::<u32>();
+    for (idx, (vector, address)) in vectors.iter().enumerate() {
+        // Address (low and high), data and vector control (unmasked)
+        unsafe {
+            let entry = entries.add(idx * MSIX_ENTRY_SIZE as usize / 4);
+            ptr::write_volatile(entry, *address as u32);
+            ptr::write_volatile(entry.add(1), (*address >> 32) as u32);
+            ptr::write_volatile(entry.add(2), *vector as u32);
+            ptr::write_volatile(entry.add(3), 0);
+        }
+    }
+    let control = config_read(device.address, table.capability);
+    config_write(
+        device.address,
+        table.capability,
+        (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+    );
+
+    let first = vectors.first().map_or(0, |(vector, _address)| *vector);
+    Ok((vectors.len() as u64) << 32 | first)
+}
+
+/// Gives the device behind `handle` back.
+pub fn release(pid: Pid, handle: u64) -> Result<(), KError> {
+    let mut devices = DEVICES.lock();
+    owned(&mut devices, pid, handle)?.owner = None;
+    Ok(())
+}
+
+/// Gives the devices of a process that exited back.
+pub fn release_all(pid: Pid) {
+    for device in DEVICES.lock().iter_mut() {
+        if device.owner == Some(pid) {
+            device.owner = None;
+        }
+    }
+}
+
+/// BAR `index` of the device behind `handle`.
+pub fn bar(pid: Pid, handle: u64, index: u64) -> Result<PciBar, KError> {
+    let mut devices = DEVICES.lock();
+    let device = owned(&mut devices, pid, handle)?;
+    device
+        .bars
+        .get(index as usize)
+        .copied()
+        .flatten()
+        .ok_or(KError::InvalidBar { index })
+}
+
+/// The pages that hold memory BAR `bar`, `[start, end)` (small BARs still
+/// get a whole page).
+pub fn bar_pages(bar: &PciBar) -> (u64, u64) {
+    let page = BASE_PAGE_SIZE as u64;
+    let start = bar.base & !(page - 1);
+    let end = (bar.base + bar.size + page - 1) & !(page - 1);
+    (start, end)
+}
+
+/// Looks for claimed (or reserved) functions that disappeared from the bus
+/// and cleans up after them (called periodically on the BSP).
+pub fn check_removed() {
+    let removed: Vec<PciDevice> = DEVICES
+        .lock()
+        .drain_filter(|d| {
+            (d.owner.is_some() || d.reserved) && absent(config_read(d.address, REG_ID))
+        })
+        .collect();
+
+    for device in removed {
+        unplug(device);
+    }
+}
+
+/// Cleans up after `device` was removed.
+fn unplug(device: PciDevice) {
+    warn!(
+        "PCI device {:04x}:{:04x} at {:#x} was removed",
+        device.vendor_id, device.device_id, device.address
+    );
+    if device.reserved {
+        super::devices::remove(device.address);
+    }
+
+    if let Some(pid) = device.owner {
+        // The owner maps BARs at their physical address (see
+        // `DeviceOperation::MapBar`), unmap the ones it mapped
+        for bar in device.bars.iter().flatten().filter(|bar| !bar.io) {
+            let (start, _end) = bar_pages(bar);
+            if let Ok(handle) = nr::KernelNode::<Ring3Process>::unmap(pid, VAddr::from(start)) {
+                super::tlb::shootdown(handle);
+            }
+        }
+        super::steering::release_function_msi(device.address);
+        super::irq::notify_process(pid, kpi::upcall::DEVICE_REMOVED, device.address);
+    }
+}
+
+/// Is `offset` a register of the configuration space user-space may access?
+///
+/// Accesses have to be aligned dwords, and the BARs can't be changed (we
+/// only map the regions we found at boot).
+fn config_accessible(offset: u64, write: bool) -> bool {
+    let bars = REG_BAR0..REG_BAR0 + 4 * MAX_BARS as u64;
+    offset < CONFIG_SPACE_SIZE && offset % 4 == 0 && !(write && bars.contains(&offset))
+}
+
+/// Reads the register at `offset` in the configuration space of the device
+/// behind `handle`.
+pub fn read_config(pid: Pid, handle: u64, offset: u64) -> Result<u32, KError> {
+    if !config_accessible(offset, false) {
+        return Err(KError::InvalidSyscallArgument1 { a: offset });
+    }
+    let mut devices = DEVICES.lock();
+    let device = owned(&mut devices, pid, handle)?;
+    Ok(config_read(device.address, offset))
+}
+
+/// Writes the register at `offset` in the configuration space of the device
+/// behind `handle`.
+pub fn write_config(pid: Pid, handle: u64, offset: u64, value: u32) -> Result<(), KError> {
+    if !config_accessible(offset, true) {
+        return Err(KError::InvalidSyscallArgument1 { a: offset });
+    }
+    let mut devices = DEVICES.lock();
+    let device = owned(&mut devices, pid, handle)?;
+    config_write(device.address, offset, value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn device(address: u64, vendor_id: u16, device_id: u16) -> PciDevice {
+        PciDevice {
+            address,
+            vendor_id,
+            device_id,
+            class: 0x020000,
+            bars: [None; MAX_BARS],
+            owner: None,
+            reserved: false,
+        }
+    }
+
+    #[test]
+    fn bar_sizes() {
+        // 4 KiB memory BAR
+        assert_eq!(bar_size(0xffff_ffff_ffff_f000, false), 0x1000);
+        // The flags in the low bits don't count
+        assert_eq!(bar_size(0xffff_ffff_fff0_0008, false), 0x10_0000);
+        // 32 ports
+        assert_eq!(bar_size(0xffff_ffe1, true), 0x20);
+        // Unimplemented BAR
+        assert_eq!(bar_size(0x0, false), 0);
+        assert_eq!(bar_size(0x1, true), 0);
+    }
+
+    #[test]
+    fn bar_page_ranges() {
+        let bar = |base, size| PciBar {
+            base,
+            size,
+            io: false,
+            prefetchable: false,
+        };
+        assert_eq!(
+            bar_pages(&bar(0xfebf_0000, 0x1000)),
+            (0xfebf_0000, 0xfebf_1000)
+        );
+        assert_eq!(
+            bar_pages(&bar(0xfebf_1010, 0x10)),
+            (0xfebf_1000, 0xfebf_2000)
+        );
+        assert_eq!(
+            bar_pages(&bar(0xfebf_0800, 0x1000)),
+            (0xfebf_0000, 0xfebf_2000)
+        );
+        assert!(absent(0xffff_ffff));
+        assert!(!absent(0x100e_8086));
+    }
+
+    #[test]
+    fn capability_list() {
+        // Capabilities at 0x40 (power management) -> 0x50 (MSI) -> 0x70 (MSI-X)
+        let read = |offset: u64| match offset {
+            REG_COMMAND => STATUS_CAPABILITIES,
+            REG_CAPABILITIES => 0x40,
+            0x40 => 0x5001,
+            0x50 => 0x7005,
+            0x70 => 0x0011,
+            _ => 0,
+        };
+        assert_eq!(capability(read, CAP_MSIX), Some(0x70));
+        assert_eq!(capability(read, 0x05), Some(0x50));
+        assert_eq!(capability(read, 0x10), None);
+
+        // Without a list, and with a list that loops
+        assert_eq!(capability(|_offset| 0, CAP_MSIX), None);
+        let looping = |offset: u64| match offset {
+            REG_COMMAND => STATUS_CAPABILITIES,
+            REG_CAPABILITIES => 0x40,
+            _ => 0x4001,
+        };
+        assert_eq!(capability(looping, CAP_MSIX), None);
+    }
+
+    #[test]
+    fn config_addresses() {
+        assert_eq!(config_address(0, 0), 0x8000_0000);
+        assert_eq!(config_address(0x1_18, 0x10), 0x8001_1810);
+        assert_eq!(config_address(0xff_ff, 0xfe), 0x80ff_fffc);
+    }
+
+    #[test]
+    fn find_nth() {
+        let devices = vec![
+            device(0x08, 0x8086, 0x100e),
+            device(0x10, 0x15ad, 0x07b0),
+            device(0x18, 0x15ad, 0x07b0),
+        ];
+        assert_eq!(find(&devices, 0x15ad, 0x07b0, 0), Some(1));
+        assert_eq!(find(&devices, 0x15ad, 0x07b0, 1), Some(2));
+        assert_eq!(find(&devices, 0x15ad, 0x07b0, 2), None);
+        assert_eq!(find(&devices, 0x8086, 0x10d3, 0), None);
+    }
+
+    #[test]
+    fn only_owner_has_access() {
+        let mut devices = vec![device(0x08, 0x8086, 0x100e)];
+        assert!(owned(&mut devices, 1, 0x08).is_err());
+        devices[0].owner = Some(1);
+        assert!(owned(&mut devices, 1, 0x08).is_ok());
+        assert!(owned(&mut devices, 2, 0x08).is_err());
+        assert!(owned(&mut devices, 1, 0x10).is_err());
+    }
+
+    #[test]
+    fn config_access() {
+        assert!(config_accessible(REG_COMMAND, true));
+        assert!(config_accessible(REG_BAR0, false));
+        assert!(!config_accessible(REG_BAR0 + 4, true));
+        assert!(config_accessible(REG_BAR0 + 24, true));
+        assert!(!config_accessible(0x41, false));
+        assert!(!config_accessible(CONFIG_SPACE_SIZE, false));
+    }
+}
@@ -0,0 +1,150 @@
+//! Memory protection keys (Intel PKU): lets a process split its address
+//! space into protection domains that it can switch between without a
+//! system call.
+//!
+//! A process allocates keys (`ProcessOperation::AllocateKey`) and tags
+//! mappings with them (`VSpaceOperation::ProtectKey`). Which keys an executor
+//! can read or write is decided by the PKRU register that user-space changes
+//! with `wrpkru`. The PKRU belongs to the executor: we remember it when an
+//! executor leaves the core (see `save`) and load it again when it is
+//! dispatched (see `dispatch`).
+//!
+//! Processes created with `fork` don't inherit the keys, the mappings of the
+//! child all have key 0.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::controlregs;
+use x86::cpuid::CpuId;
+
+use kpi::x86_64::{read_pkru, write_pkru, PROTECTION_KEYS};
+
+use crate::error::KError;
+use crate::process::Pid;
+
+use super::kcb::Arch86Kcb;
+use super::process::Ring3Executor;
+
+/// Set once protection keys are enabled (on all cores).
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// The allocated keys of every process (one bit per key).
+    static ref KEYS: Mutex<HashMap<Pid, u16>> = Mutex::new(HashMap::new());
+}
+
+/// Does the machine have protection keys?
+pub fn support() -> bool {
+    CpuId::new()
+        .get_extended_feature_info()
+        .map_or(false, |f| f.has_pku())
+}
+
+/// Enables protection keys on the core (if the machine has them).
+pub fn enable() {
+    if !support() {
+        return;
+    }
+    unsafe {
+        let cr4 = controlregs::cr4() | controlregs::Cr4::CR4_ENABLE_PROTECTION_KEY;
+        controlregs::cr4_write(cr4);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Are protection keys enabled?
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The lowest key that isn't set in `allocated` (key 0 is always taken).
+fn free_key(allocated: u16) -> Option<u16> {
+    let allocated = allocated | 0x1;
+    if allocated == u16::MAX {
+        None
+    } else {
+        Some(allocated.trailing_ones() as u16)
+    }
+}
+
+/// Allocates a key for process `pid`.
+pub fn allocate(pid: Pid) -> Result<u16, KError> {
+    if !enabled() {
+        return Err(KError::NotSupported);
+    }
+
+    let mut keys = KEYS.lock();
+    let allocated = keys.entry(pid).or_insert(0);
+    let key = free_key(*allocated).ok_or(KError::NoProtectionKeys)?;
+    *allocated |= 1 << key;
+    Ok(key)
+}
+
+/// Gives `key` of process `pid` back.
+pub fn free(pid: Pid, key: u64) -> Result<(), KError> {
+    if !is_allocated(pid, key) || key == 0 {
+        return Err(KError::InvalidProtectionKey { key });
+    }
+    if let Some(allocated) = KEYS.lock().get_mut(&pid) {
+        *allocated &= !(1 << key);
+    }
+    Ok(())
+}
+
+/// Can process `pid` tag mappings with `key`?
+pub fn is_allocated(pid: Pid, key: u64) -> bool {
+    if !enabled() || key >= PROTECTION_KEYS as u64 {
+        return false;
+    }
+    key == 0 || KEYS.lock().get(&pid).map_or(false, |k| k & (1 << key) != 0)
+}
+
+/// Forgets the keys of a process that exited (or replaced its image).
+pub fn unregister(pid: Pid) {
+    KEYS.lock().remove(&pid);
+}
+
+/// Remembers the PKRU of `executor` which leaves the core.
+pub fn save(arch: &mut Arch86Kcb, executor: &Arc<Ring3Executor>) {
+    if arch.pkru_owner == Some((executor.pid, executor.eid)) {
+        // Safety: The executor is the one on this core
+        unsafe { Ring3Executor::set_pkru(executor, read_pkru()) };
+        arch.pkru_owner = None;
+    }
+}
+
+/// Loads the PKRU of `executor` which is dispatched on the core.
+///
+/// Nothing changes if the register still has the rights of the executor
+/// (they might be newer than the ones we saved).
+pub fn dispatch(arch: &mut Arch86Kcb, executor: &Ring3Executor) {
+    let owner = (executor.pid, executor.eid);
+    if !enabled() || arch.pkru_owner == Some(owner) {
+        return;
+    }
+    unsafe {
+        if read_pkru() != executor.pkru {
+            write_pkru(executor.pkru);
+        }
+    }
+    arch.pkru_owner = Some(owner);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn free_keys() {
+        assert_eq!(free_key(0), Some(1));
+        assert_eq!(free_key(0x1), Some(1));
+        assert_eq!(free_key(0x7), Some(3));
+        assert_eq!(free_key(0x7ffe), Some(15));
+        assert_eq!(free_key(0xfffe), None);
+        assert_eq!(free_key(u16::MAX), None);
+    }
+}
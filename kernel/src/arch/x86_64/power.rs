@@ -0,0 +1,250 @@
+//! Suspend-to-RAM (ACPI S3) support.
+//!
+//! To suspend, the BSP asks all other cores to park themselves (see `park`),
+//! flushes its caches and enters S3 through ACPI. Memory content survives S3
+//! but all CPU and most device state is lost. On wake-up, the firmware
+//! starts the BSP in real-mode at the waking vector. We point the vector to
+//! the same bootstrap code (`start_ap.S`) that we use to boot app cores,
+//! which brings us to `resume_bsp`. From there we reinitialize the BSP,
+//! the IOAPIC and finally re-boot the app cores (into `resume_app_core`)
+//! which pick up their existing KCB and resume where they were interrupted.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use apic::ApicDriver;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::kcb::Kcb;
+use crate::stack::{OwnedStack, Stack};
+
+use super::kcb::{get_kcb, Arch86Kcb};
+use super::memory::BASE_PAGE_SIZE;
+use super::process::Ring3Resumer;
+use super::timer;
+
+/// The ACPI sleep state for suspend-to-RAM.
+const ACPI_STATE_S3: u8 = 3;
+
+/// How long we wait (in rdtsc cycles) for cores to park or resume.
+const CORE_TIMEOUT: u64 = 1_000_000_000;
+
+/// Argument passed to the entry functions after we wake up.
+struct ResumeArgs {
+    kcb: *mut Kcb<Arch86Kcb>,
+}
+
+lazy_static! {
+    /// Cores that are parked (along with a pointer to their KCB).
+    static ref PARKED: Mutex<Vec<(topology::GlobalThreadId, usize)>> = Mutex::new(Vec::new());
+
+    /// The stacks we use to resume cores (allocated the first time we
+    /// suspend and reused afterwards).
+    static ref RESUME_STACKS: Mutex<Vec<OwnedStack>> = Mutex::new(Vec::new());
+}
+
+/// Flushes all caches, we have to do this before we power down a core.
+fn wbinvd() {
+    unsafe { llvm_asm!("wbinvd" :::: "volatile") };
+}
+
+/// Halts the current core until the system goes to sleep.
+///
+/// This is called (with interrupts disabled) on every core except the BSP
+/// when it receives a `WorkItem::Park` IPI. The state of the interrupted
+/// context remains in the save area of the KCB.
+pub fn park() -> ! {
+    let kcb = get_kcb();
+    let gtid = kcb.arch.id() as topology::GlobalThreadId;
+    PARKED.lock().push((gtid, kcb as *mut _ as usize));
+    trace!("Core #{} parked", gtid);
+
+    wbinvd();
+    loop {
+        unsafe { x86::halt() };
+    }
+}
+
+/// Asks all cores except the current one to park themselves (see `park`)
+/// and waits until they did.
+///
+/// In case not all cores parked in time, we resume the ones that did and
+/// return an error.
+pub fn park_app_cores() -> Result<(), KError> {
+    let kcb = get_kcb();
+    let num_threads = topology::MACHINE_TOPOLOGY.num_threads();
+    {
+        let mut stacks = RESUME_STACKS.lock();
+        while stacks.len() < num_threads {
+            stacks.push(OwnedStack::new(128 * BASE_PAGE_SIZE));
+        }
+    }
+
+    // Failed cores are halted already (see `isolation`)
+    let mut expected = 0;
+    for thread in topology::MACHINE_TOPOLOGY.threads() {
+        if thread.id as usize != kcb.arch.id() && !super::isolation::failed(thread.id) {
+            super::tlb::park(thread.id);
+            expected += 1;
+        }
+    }
+    let timeout = unsafe { x86::time::rdtsc() } + CORE_TIMEOUT;
+    while PARKED.lock().len() < expected {
+        if unsafe { x86::time::rdtsc() } > timeout {
+            error!("Not all cores parked, abort.");
+            resume_app_cores();
+            return Err(KError::NotSupported);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Suspends the system to RAM.
+///
+/// This returns (with a successful system call result) in `resume_bsp` after
+/// the system woke up again, or with an error in case we couldn't suspend.
+pub fn suspend() -> Result<(), KError> {
+    let kcb = get_kcb();
+    if kcb.arch.id() != 0 {
+        // Only the BSP gets started by the firmware on wake-up
+        return Err(KError::NotSupported);
+    }
+
+    // Quiesce all the app cores and the devices with a kernel driver
+    park_app_cores()?;
+    super::devices::suspend();
+
+    // Prepare the firmware waking vector to end up in `resume_bsp`
+    let initialized = AtomicBool::new(false);
+    let args = Arc::new(ResumeArgs { kcb: kcb as *mut _ });
+    let waking_vector = unsafe {
+        let stacks = RESUME_STACKS.lock();
+        super::coreboot::prepare(resume_bsp, args, &initialized, &stacks[0])
+    };
+    if let Err(status) = super::acpi::prepare_sleep(ACPI_STATE_S3, waking_vector.as_u64()) {
+        error!("Can't prepare for S3: {:?}", status);
+        super::acpi::leave_sleep(ACPI_STATE_S3);
+        super::devices::resume();
+        resume_app_cores();
+        return Err(KError::NotSupported);
+    }
+
+    info!("Entering S3...");
+    super::irq::disable();
+    wbinvd();
+    let status = unsafe { super::acpi::enter_sleep(ACPI_STATE_S3) };
+
+    // We only get here if entering S3 failed:
+    error!("Can't enter S3: {:?}", status);
+    super::acpi::leave_sleep(ACPI_STATE_S3);
+    super::devices::resume();
+    resume_app_cores();
+    Err(KError::NotSupported)
+}
+
+/// Common reinitialization after waking up, for every core.
+///
+/// Restores CPU state we configure during boot, installs the KCB again and
+/// reinitializes the local APIC.
+fn reinitialize_core(args: &ResumeArgs) -> &'static mut Kcb<Arch86Kcb> {
+    super::enable_sse();
+    super::enable_fsgsbase();
+    super::pkeys::enable();
+    super::cet::enable();
+    super::syscall::enable_fast_syscalls();
+    super::irq::disable();
+    unsafe {
+        super::gdt::setup_early_gdt();
+        super::irq::setup_early_idt();
+    };
+
+    // Safe: KCBs are never deallocated, and the memory survived S3
+    unsafe { (&mut *args.kcb).install() };
+    let kcb = get_kcb();
+    kcb.arch.apic().attach();
+
+    // The timestamp counter starts from zero again:
+    let now = unsafe { x86::time::rdtsc() };
+    kcb.arch.fair.reset_clock(now);
+
+    kcb
+}
+
+/// Entry point of the BSP after wake-up (called from `start_ap.S`).
+fn resume_bsp(args: Arc<ResumeArgs>, _initialized: &AtomicBool) {
+    let kcb = reinitialize_core(&args);
+    super::acpi::leave_sleep(ACPI_STATE_S3);
+    super::steering::restore();
+    super::devices::resume();
+    info!("Resumed from S3");
+
+    for gtid in resume_app_cores() {
+        super::irq::notify_subscribers(kpi::upcall::CORE_ONLINE, gtid as u64);
+    }
+
+    // Return from the `Suspend` system call:
+    kcb.arch.save_area.as_mut().map(|sa| {
+        sa.set_syscall_ret1(0);
+        sa.set_syscall_ret2(0);
+        sa.set_syscall_error_code(kpi::SystemCallError::Ok);
+    });
+    timer::set(timer::DEFAULT_TIMER_DEADLINE);
+    unsafe { Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr()).resume() }
+}
+
+/// Entry point of the app cores after wake-up (called from `start_ap.S`).
+fn resume_app_core(args: Arc<ResumeArgs>, initialized: &AtomicBool) {
+    let kcb = reinitialize_core(&args);
+    initialized.store(true, Ordering::SeqCst);
+
+    if kcb.arch.has_current_process() {
+        // Continue where we got interrupted by the park IPI
+        timer::set(timer::DEFAULT_TIMER_DEADLINE);
+        unsafe { Ring3Resumer::new_iret(kcb.arch.get_save_area_ptr()).resume() }
+    } else {
+        crate::scheduler::schedule()
+    }
+}
+
+/// Boots all parked app cores (one after the other) into `resume_app_core`,
+/// returns the cores that are back.
+fn resume_app_cores() -> Vec<topology::GlobalThreadId> {
+    let parked: Vec<(topology::GlobalThreadId, usize)> = PARKED.lock().drain(..).collect();
+    let stacks = RESUME_STACKS.lock();
+    let mut resumed = Vec::with_capacity(parked.len());
+
+    for (gtid, kcb) in parked {
+        let initialized = AtomicBool::new(false);
+        let args = Arc::new(ResumeArgs {
+            kcb: kcb as *mut Kcb<Arch86Kcb>,
+        });
+        let stack: &dyn Stack = &stacks[gtid as usize];
+
+        unsafe {
+            super::coreboot::initialize(
+                topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id(),
+                resume_app_core,
+                args,
+                &initialized,
+                stack,
+            );
+        }
+
+        let timeout = unsafe { x86::time::rdtsc() } + CORE_TIMEOUT;
+        while !initialized.load(Ordering::SeqCst) {
+            if unsafe { x86::time::rdtsc() } > timeout {
+                panic!("Core {} didn't resume properly...", gtid);
+            }
+            core::hint::spin_loop();
+        }
+        debug!("Core {} has resumed", gtid);
+        resumed.push(gtid);
+    }
+
+    resumed
+}
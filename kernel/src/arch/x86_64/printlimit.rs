@@ -0,0 +1,245 @@
+//! Rate limiting for the output of user-space processes.
+//!
+//! A process that prints a lot can keep the serial line busy for everyone
+//! else (and distort benchmarks that report over it). With a limit set, every
+//! process gets a budget of `rate` bytes per second (it can save up at most
+//! one second worth of budget). What happens to lines over budget depends on
+//! the policy:
+//!
+//! - `drop`: the line isn't printed.
+//! - `truncate`: only the part of the line that is in budget is printed.
+//! - `delay`: the line is printed, but the process waits until it paid for it.
+//!
+//! The limit is set on the kernel command-line with
+//! `printlimit=<policy>:<bytes per second>` (e.g., `printlimit=drop:4096`),
+//! without it there is no limit.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+use lazy_static::lazy_static;
+use log::warn;
+use spin::{Mutex, Once};
+
+use crate::process::{Pid, MAX_ACCOUNTED_PROCESSES};
+
+/// What to do with output that is over budget.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Policy {
+    Drop,
+    Truncate,
+    Delay,
+}
+
+/// Output limit for every process.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Limit {
+    pub policy: Policy,
+    /// Bytes per second.
+    pub rate: u64,
+}
+
+impl Limit {
+    /// Parses a limit from the command-line (e.g., "truncate:4096").
+    pub fn parse(conf: &str) -> Option<Limit> {
+        let mut parts = conf.splitn(2, ':');
+        let policy = match parts.next()? {
+            "drop" => Policy::Drop,
+            "truncate" => Policy::Truncate,
+            "delay" => Policy::Delay,
+            _ => return None,
+        };
+
+        match parts.next()?.parse::<u64>() {
+            Ok(rate) if rate > 0 => Some(Limit { policy, rate }),
+            _ => None,
+        }
+    }
+}
+
+/// Budget of a single process.
+#[derive(Default)]
+struct Bucket {
+    /// Bytes the process can print right now (negative if it's in debt
+    /// with the delay policy).
+    tokens: i64,
+    /// When we last added tokens (None if the process didn't print yet).
+    last_refill: Option<rawtime::Instant>,
+    /// If we already complained about the process.
+    warned: bool,
+}
+
+/// Output statistics of a process.
+#[derive(Default)]
+struct Counters {
+    printed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+lazy_static! {
+    /// Budget for every process (indexed by pid).
+    static ref BUCKETS: Vec<CachePadded<Mutex<Bucket>>> = {
+        let mut buckets = Vec::with_capacity(MAX_ACCOUNTED_PROCESSES);
+        for _i in 0..MAX_ACCOUNTED_PROCESSES {
+            buckets.push(Default::default());
+        }
+        buckets
+    };
+
+    /// Output statistics for every process (indexed by pid).
+    static ref COUNTERS: Vec<CachePadded<Counters>> = {
+        let mut counters = Vec::with_capacity(MAX_ACCOUNTED_PROCESSES);
+        for _i in 0..MAX_ACCOUNTED_PROCESSES {
+            counters.push(Default::default());
+        }
+        counters
+    };
+}
+
+static LIMIT: Once<Option<Limit>> = Once::new();
+
+fn limit() -> Option<Limit> {
+    *LIMIT.call_once(|| {
+        let conf = super::kcb::get_kcb().cmdline.print_limit;
+        let limit = Limit::parse(conf);
+        if limit.is_none() && !conf.is_empty() {
+            warn!("Ignoring invalid printlimit={}", conf);
+        }
+        limit
+    })
+}
+
+/// Decides what we print of `line` (printed by process `pid`), returns
+/// None if the line should be dropped.
+///
+/// With the delay policy this spins until the process has budget again.
+pub fn limit_line(pid: Pid, mut line: String) -> Option<String> {
+    let (limit, bucket, counters) = match (
+        limit(),
+        BUCKETS.get(pid as usize),
+        COUNTERS.get(pid as usize),
+    ) {
+        (Some(limit), Some(bucket), Some(counters)) => (limit, bucket, counters),
+        (None, _, Some(counters)) => {
+            counters
+                .printed
+                .fetch_add(line.len() as u64, Ordering::Relaxed);
+            return Some(line);
+        }
+        _ => return Some(line),
+    };
+    let rate = limit.rate as i64;
+    let len = line.len() as i64;
+
+    let mut bucket = bucket.lock();
+    let refill = match bucket.last_refill {
+        Some(last_refill) => {
+            (last_refill.elapsed().as_micros() as i64).saturating_mul(rate) / 1_000_000
+        }
+        None => rate,
+    };
+    if refill > 0 || bucket.last_refill.is_none() {
+        bucket.tokens = core::cmp::min(bucket.tokens.saturating_add(refill), rate);
+        bucket.last_refill = Some(rawtime::Instant::now());
+    }
+
+    let allowed = match limit.policy {
+        Policy::Delay => len,
+        _ => core::cmp::max(0, core::cmp::min(bucket.tokens, len)),
+    };
+    let allowed = match limit.policy {
+        Policy::Drop if allowed < len => 0,
+        _ => allowed,
+    };
+    bucket.tokens -= allowed;
+
+    if allowed < len && !bucket.warned {
+        bucket.warned = true;
+        warn!(
+            "Process {} prints more than {} bytes/s, limiting its output ({:?})",
+            pid, limit.rate, limit.policy
+        );
+    }
+    let debt = -core::cmp::min(bucket.tokens, 0);
+    drop(bucket);
+
+    counters
+        .printed
+        .fetch_add(allowed as u64, Ordering::Relaxed);
+    counters
+        .dropped
+        .fetch_add((len - allowed) as u64, Ordering::Relaxed);
+
+    if debt > 0 {
+        // Delay policy: wait until the process would have had the budget.
+        let wait_us = (debt as u64).saturating_mul(1_000_000) / limit.rate;
+        let start = rawtime::Instant::now();
+        while (start.elapsed().as_micros() as u64) < wait_us {
+            core::hint::spin_loop();
+        }
+    }
+
+    match allowed {
+        0 => None,
+        allowed if allowed == len => Some(line),
+        allowed => {
+            let mut end = allowed as usize;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            let newline = line.ends_with('\n');
+            line.truncate(end);
+            if newline {
+                line.push('\n');
+            }
+            Some(line)
+        }
+    }
+}
+
+/// Bytes of output process `pid` had to drop so far.
+pub fn dropped_bytes(pid: Pid) -> u64 {
+    COUNTERS
+        .get(pid as usize)
+        .map_or(0, |counters| counters.dropped.load(Ordering::Relaxed))
+}
+
+/// Bytes of output process `pid` printed so far.
+pub fn printed_bytes(pid: Pid) -> u64 {
+    COUNTERS
+        .get(pid as usize)
+        .map_or(0, |counters| counters.printed.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_limit() {
+        assert_eq!(
+            Limit::parse("drop:4096"),
+            Some(Limit {
+                policy: Policy::Drop,
+                rate: 4096
+            })
+        );
+        assert_eq!(
+            Limit::parse("truncate:1"),
+            Some(Limit {
+                policy: Policy::Truncate,
+                rate: 1
+            })
+        );
+        assert_eq!(
+            Limit::parse("delay:100").map(|l| l.policy),
+            Some(Policy::Delay)
+        );
+        assert_eq!(Limit::parse(""), None);
+        assert_eq!(Limit::parse("drop"), None);
+        assert_eq!(Limit::parse("drop:0"), None);
+        assert_eq!(Limit::parse("stop:10"), None);
+    }
+}
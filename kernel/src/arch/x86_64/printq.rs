@@ -0,0 +1,124 @@
+//! Ordered, non-blocking output for lines printed by user-space.
+//!
+//! Every core puts completed lines (tagged with a global sequence number) in
+//! its own queue. Whoever gets the serial line (`klogger::SERIAL_LINE_MUTEX`)
+//! writes out the pending lines of all cores, ordered by sequence number.
+//! A core never waits for the serial line: if someone else has it, that core
+//! prints our line too. Only if our queue is full (e.g., because the core
+//! that holds the lock panicked) we write to the serial line directly.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_queue::{ArrayQueue, PushError};
+use spin::Once;
+
+/// How many lines a core can have pending.
+const LINES_PER_CORE: usize = 128;
+
+/// How often we try to get the serial line before we leave our line in the
+/// queue (for the next core that prints something).
+const FLUSH_RETRIES: usize = 64;
+
+/// A line that waits to be written to the serial line.
+struct Line {
+    seq: u64,
+    text: String,
+}
+
+/// Sequence number for the next line.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Pending lines for every core (initialized on the first print).
+static LINE_QUEUES: Once<Vec<ArrayQueue<Line>>> = Once::new();
+
+fn line_queues() -> &'static Vec<ArrayQueue<Line>> {
+    LINE_QUEUES.call_once(|| {
+        let cores = topology::MACHINE_TOPOLOGY.num_threads();
+        let mut queues = Vec::with_capacity(cores);
+        for _i in 0..cores {
+            queues.push(ArrayQueue::new(LINES_PER_CORE));
+        }
+
+        queues
+    })
+}
+
+/// Queues `text` for printing and tries to write out all pending lines.
+pub fn print(text: String) {
+    let seq = SEQUENCE.fetch_add(1, Ordering::SeqCst);
+    let gtid = super::kcb::get_kcb().arch.id();
+    let queue = &line_queues()[gtid];
+
+    if let Err(PushError(line)) = queue.push(Line { seq, text }) {
+        flush();
+        if let Err(PushError(line)) = queue.push(line) {
+            // Nobody makes progress with the queues, better to print it
+            // out of order than to block:
+            sprint!("{}", line.text);
+        }
+    }
+
+    flush();
+}
+
+/// Writes out the pending lines of all cores, unless someone else is
+/// already doing it.
+pub fn flush() {
+    let queues = match LINE_QUEUES.r#try() {
+        Some(queues) => queues,
+        None => return,
+    };
+
+    for _i in 0..FLUSH_RETRIES {
+        match klogger::SERIAL_LINE_MUTEX.try_lock() {
+            Some(_guard) => drain(queues, true),
+            None => {
+                core::hint::spin_loop();
+                continue;
+            }
+        }
+
+        // Lines may have been added while we held the lock (by cores that
+        // didn't get it), so check again after we released it:
+        if queues.iter().all(|queue| queue.is_empty()) {
+            return;
+        }
+    }
+}
+
+/// Writes out all pending lines without taking the serial line.
+///
+/// # Safety
+/// Only for when the system is about to go down (i.e., panics or
+/// shutdown): the output may interleave with other cores.
+pub unsafe fn flush_unlocked() {
+    if let Some(queues) = LINE_QUEUES.r#try() {
+        drain(queues, false);
+    }
+}
+
+/// Writes out the pending lines of all cores, in order if `sorted` is set.
+fn drain(queues: &[ArrayQueue<Line>], sorted: bool) {
+    if !sorted {
+        for queue in queues.iter() {
+            while let Some(line) = queue.pop() {
+                sprint!("{}", line.text);
+            }
+        }
+        return;
+    }
+
+    let mut lines = Vec::with_capacity(LINES_PER_CORE);
+    for queue in queues.iter() {
+        while let Some(line) = queue.pop() {
+            lines.push(line);
+        }
+    }
+    lines.sort_unstable_by_key(|line| line.seq);
+
+    for line in lines {
+        sprint!("{}", line.text);
+    }
+}
@@ -1,14 +1,14 @@
 #![allow(warnings)]
 
 use alloc::boxed::Box;
-use alloc::collections::TryReserveError;
+use alloc::collections::{BTreeMap, TryReserveError};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 use core::ptr;
 
-use kpi::process::FrameId;
+use kpi::process::{FrameId, SchedulingPolicy};
 use x86::bits64::paging::*;
 use x86::bits64::rflags;
 use x86::controlregs;
@@ -16,13 +16,17 @@ use x86::controlregs;
 use crate::error::KError;
 use crate::fs::{Fd, FileDescriptor, MAX_FILES_PER_PROCESS};
 use crate::kcb::{self, Kcb};
-use crate::memory::vspace::{AddressSpace, MapAction};
+use crate::memory::vspace::{
+    AddressSpace, AddressSpaceError, MapAction, MappingType, TlbFlushHandle,
+};
 use crate::memory::{
     paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, PhysicalPageProvider, VAddr,
 };
 use crate::nr;
+use crate::prelude::overlaps;
 use crate::process::{
-    allocate_dispatchers, make_process, Eid, Executor, Pid, Process, ProcessError, ResumeHandle,
+    allocate_dispatchers, load_binary, make_process, Credentials, Eid, Executor, Pid, Process,
+    ProcessError, ResumeHandle,
 };
 use crate::round_up;
 
@@ -32,6 +36,9 @@ use super::Module;
 
 const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
 
+/// Where the memory for the executors of a process starts in user-space.
+const EXECUTOR_OFFSET: VAddr = VAddr(0x21_0000_0000);
+
 pub struct UserPtr<T> {
     value: *mut T,
 }
@@ -115,11 +122,18 @@ pub struct UserSlice<'a> {
 }
 
 impl<'a> UserSlice<'a> {
-    pub fn new(base: u64, len: usize) -> UserSlice<'a> {
+    /// Creates a slice for `[base, base+len)` in user-space.
+    ///
+    /// System call handlers should use `syscall::user_slice` which validates
+    /// the range first.
+    ///
+    /// # Safety
+    /// `[base, base+len)` has to be mapped in the current address space
+    /// (see `syscall::user_virt_addr_valid`).
+    pub(crate) unsafe fn new(base: u64, len: usize) -> UserSlice<'a> {
         let mut user_ptr = VAddr::from(base);
         let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
+        let user_slice: &mut [u8] = core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len);
         UserSlice { buffer: user_slice }
     }
 }
@@ -170,6 +184,7 @@ pub struct Ring3Resumer {
 
 impl ResumeHandle for Ring3Resumer {
     unsafe fn resume(self) -> ! {
+        super::kcb::get_kcb().arch.mark_dispatch();
         match self.typ {
             ResumeStrategy::Start => self.start(),
             ResumeStrategy::Upcall => self.upcall(),
@@ -503,6 +518,26 @@ pub struct Ring3Executor {
 
     /// A handle to the vspace PML4 entry point.
     pub pml4: PAddr,
+
+    /// The executor starts by returning from a system call (with
+    /// `save_area`): it is the copy of an executor that did `fork` or it
+    /// gave up its core in a system call (see `irq::block_current_executor`).
+    pub syscall_return: bool,
+
+    /// Period (in rdtsc cycles) of the `kpi::upcall::TIMER` upcalls the
+    /// executor subscribed to (0 if it didn't).
+    pub timer_period: u64,
+
+    /// rdtsc value at which the executor gets its next timer upcall.
+    pub timer_next: u64,
+
+    /// Protection key rights of the executor (its PKRU register, see
+    /// `pkeys`).
+    pub pkru: u32,
+
+    /// Shadow stack pointer of the executor (0 if it runs without a shadow
+    /// stack, see `cet`).
+    pub ssp: u64,
 }
 
 impl Ring3Executor {
@@ -549,9 +584,71 @@ impl Ring3Executor {
             save_area: Default::default(),
             entry_point: process.offset + process.entry_point,
             pml4: process.vspace.pml4_address(),
+            syscall_return: false,
+            timer_period: 0,
+            timer_next: 0,
+            pkru: kpi::x86_64::PKRU_DEFAULT,
+            ssp: 0,
         }
     }
 
+    /// Sends the executor a `kpi::upcall::TIMER` upcall every `period`
+    /// rdtsc cycles from `now` on (stops them if `period` is 0).
+    ///
+    /// # Safety
+    /// Only the core the executor is assigned to can call this (see
+    /// `save_context`).
+    pub unsafe fn subscribe_timer(executor: &Arc<Ring3Executor>, period: u64, now: u64) {
+        let executor = Arc::as_ptr(executor) as *mut Ring3Executor;
+        (*executor).timer_period = period;
+        (*executor).timer_next = now.saturating_add(period);
+    }
+
+    /// Stores `save_area` as the context the executor continues with when
+    /// it is dispatched next (see `resume`).
+    ///
+    /// # Safety
+    /// Only the core the executor is assigned to can call this, and only
+    /// while the executor isn't running on it.
+    pub unsafe fn save_context(executor: &Arc<Ring3Executor>, save_area: &kpi::arch::SaveArea) {
+        // The pointer comes from the `Arc` (not from a `&Ring3Executor`), so
+        // we're allowed to write through it
+        let executor = Arc::as_ptr(executor) as *mut Ring3Executor;
+        (*executor).save_area = *save_area;
+    }
+
+    /// Updates the protection key rights the executor runs with.
+    ///
+    /// # Safety
+    /// Only the core the executor is assigned to can call this.
+    pub unsafe fn set_pkru(executor: &Arc<Ring3Executor>, pkru: u32) {
+        let executor = Arc::as_ptr(executor) as *mut Ring3Executor;
+        (*executor).pkru = pkru;
+    }
+
+    /// Updates the shadow stack pointer the executor runs with.
+    ///
+    /// # Safety
+    /// Only the core the executor is assigned to can call this.
+    pub unsafe fn set_ssp(executor: &Arc<Ring3Executor>, ssp: u64) {
+        let executor = Arc::as_ptr(executor) as *mut Ring3Executor;
+        (*executor).ssp = ssp;
+    }
+
+    /// Is a timer upcall due at `now`? Schedules the next one if it is.
+    ///
+    /// # Safety
+    /// Only the core the executor is assigned to can call this.
+    pub unsafe fn timer_due(executor: &Arc<Ring3Executor>, now: u64) -> bool {
+        if executor.timer_period == 0 || now < executor.timer_next {
+            return false;
+        }
+
+        // We don't catch up on upcalls we missed
+        Ring3Executor::subscribe_timer(executor, executor.timer_period, now);
+        true
+    }
+
     pub fn vcpu(&self) -> UserPtr<kpi::arch::VirtualCpu> {
         UserPtr::new(self.vcpu_ctl.as_mut_ptr())
     }
@@ -595,6 +692,15 @@ impl Executor for Ring3Executor {
     /// Start the process (run it for the first time).
     fn start(&self) -> Self::Resumer {
         self.maybe_switch_vspace();
+        super::cat::dispatch(super::kcb::get_kcb(), self.pid);
+        super::mbm::dispatch(super::kcb::get_kcb(), self.pid);
+        super::pkeys::dispatch(&mut super::kcb::get_kcb().arch, self);
+        super::cet::dispatch(&mut super::kcb::get_kcb().arch, self);
+        super::eventring::dispatched(self.pid, self.eid);
+        if self.syscall_return {
+            return Ring3Resumer::new_restore(&self.save_area as *const kpi::arch::SaveArea);
+        }
+
         let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
 
         if entry_point == INVALID_EXECUTOR_START {
@@ -620,11 +726,19 @@ impl Executor for Ring3Executor {
 
     fn resume(&self) -> Self::Resumer {
         self.maybe_switch_vspace();
+        super::cat::dispatch(super::kcb::get_kcb(), self.pid);
+        super::mbm::dispatch(super::kcb::get_kcb(), self.pid);
+        super::pkeys::dispatch(&mut super::kcb::get_kcb().arch, self);
+        super::cet::dispatch(&mut super::kcb::get_kcb().arch, self);
         Ring3Resumer::new_restore(&self.save_area as *const kpi::arch::SaveArea)
     }
 
     fn upcall(&self, vector: u64, exception: u64) -> Self::Resumer {
         self.maybe_switch_vspace();
+        super::cat::dispatch(super::kcb::get_kcb(), self.pid);
+        super::mbm::dispatch(super::kcb::get_kcb(), self.pid);
+        super::pkeys::dispatch(&mut super::kcb::get_kcb().arch, self);
+        super::cet::dispatch(&mut super::kcb::get_kcb().arch, self);
         let entry_point = self.vcpu().resume_with_upcall;
         let cpu_ctl = self.vcpu().vaddr().as_u64();
 
@@ -669,13 +783,25 @@ pub struct Ring3Process {
     pub executor_offset: VAddr,
     /// File descriptors for the opened file.
     pub fds: arrayvec::ArrayVec<[Option<Fd>; MAX_FILES_PER_PROCESS]>,
-    /// Physical frame objects registered to the process.
-    pub frames: Vec<Frame>,
+    /// User and group the process runs as.
+    pub credentials: Credentials,
+    /// Physical frame objects registered to the process (indexed by
+    /// FrameId, released frames leave an empty slot behind).
+    pub frames: Vec<Option<Frame>>,
     /// Frames of the writeable ELF data section (shared across all replicated Process structs)
     pub writeable_sections: Vec<Frame>,
+    /// Frames of the read-only ELF sections (allocated by every replica for
+    /// its own copy of the process).
+    pub read_only_sections: Vec<Frame>,
     /// Section in ELF where last read-only header is (TODO: assumes that all read-only segments
     /// are before write).
     pub read_only_offset: VAddr,
+    /// Mappings that are shared copy-on-write with another process (after a
+    /// fork), with the rights they get back once they're written to.
+    ///
+    /// An entry is only valid as long as the same frame is still mapped at
+    /// its base.
+    pub cow: BTreeMap<VAddr, (Frame, MapAction)>,
 }
 
 impl Ring3Process {
@@ -698,14 +824,55 @@ impl Ring3Process {
             vspace: VSpace::new(),
             entry_point: VAddr::from(0usize),
             executor_cache,
-            executor_offset: VAddr::from(0x21_0000_0000usize),
+            executor_offset: EXECUTOR_OFFSET,
             fds,
+            credentials: Default::default(),
             pinfo: Default::default(),
             frames: Vec::with_capacity(12),
             writeable_sections,
+            read_only_sections: Vec::new(),
             read_only_offset: VAddr::zero(),
+            cow: BTreeMap::new(),
         }
     }
+
+    /// The mappings that cover `[base, base+len)`, the range has to start and
+    /// end at the boundaries of mappings (that belong to the process).
+    ///
+    /// Lets us check everything before we change anything (see `protect`).
+    fn covered_mappings(
+        &self,
+        base: VAddr,
+        len: usize,
+    ) -> Result<Vec<(VAddr, Frame)>, AddressSpaceError> {
+        let end = base
+            .as_usize()
+            .checked_add(len)
+            .ok_or(AddressSpaceError::InvalidLength)?;
+        if !self.vspace.mappings.contains_key(&base) {
+            return Err(AddressSpaceError::InvalidBase);
+        }
+
+        let mut mappings: Vec<(VAddr, Frame)> = Vec::new();
+        let mut next = base.as_usize();
+        for (mapping_base, mapping) in self.vspace.mappings.range(base..VAddr::from(end)) {
+            if mapping_base.as_usize() != next {
+                return Err(AddressSpaceError::NotMapped);
+            }
+            if mapping.typ == MappingType::Executor
+                || mapping.typ == MappingType::Kernel
+                || mapping.typ == MappingType::File
+            {
+                return Err(AddressSpaceError::InvalidBase);
+            }
+            mappings.push((*mapping_base, mapping.frame));
+            next += mapping.frame.size();
+        }
+        if next != end {
+            return Err(AddressSpaceError::InvalidLength);
+        }
+        Ok(mappings)
+    }
 }
 
 impl fmt::Debug for Ring3Process {
@@ -796,9 +963,11 @@ impl elfloader::ElfLoader for Ring3Process {
                             || map_action == MapAction::ReadExecuteUser
                     );
                     let mut pmanager = kcb.mem_manager();
-                    pmanager
+                    let frame = pmanager
                         .allocate_large_page()
-                        .expect("We refilled so allocation should work.")
+                        .expect("We refilled so allocation should work.");
+                    self.read_only_sections.push(frame);
+                    frame
                 };
 
                 trace!(
@@ -963,8 +1132,10 @@ impl Process for Ring3Process {
         module: &Module,
         pid: Pid,
         writeable_sections: Vec<Frame>,
+        policy: SchedulingPolicy,
     ) -> Result<Ring3Process, ProcessError> {
         let mut p = Ring3Process::create(pid, writeable_sections);
+        p.pinfo.policy = policy;
 
         // Load the Module into the process address-space
         // This needs mostly sanitation work on elfloader and
@@ -1135,17 +1306,39 @@ impl Process for Ring3Process {
         MAX_FILES_PER_PROCESS + 1
     }
 
-    fn get_fd(&self, index: usize) -> &Fd {
-        self.fds[index].as_ref().unwrap()
+    fn get_fd(&self, index: usize) -> Option<&Fd> {
+        self.fds.get(index).and_then(|fd| fd.as_ref())
+    }
+
+    fn open_fds(&self) -> Vec<&Fd> {
+        self.fds.iter().flatten().collect()
+    }
+
+    fn fds(&self) -> Vec<Option<Fd>> {
+        self.fds.iter().cloned().collect()
+    }
+
+    fn set_fds(&mut self, fds: Vec<Option<Fd>>) {
+        for (slot, fd) in self.fds.iter_mut().zip(fds.into_iter()) {
+            *slot = fd;
+        }
     }
 
     fn pinfo(&self) -> &kpi::process::ProcessInfo {
         &self.pinfo
     }
 
+    fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, ProcessError> {
         self.frames.try_reserve(1)?;
-        self.frames.push(frame);
+        self.frames.push(Some(frame));
         Ok(self.frames.len() - 1)
     }
 
@@ -1153,8 +1346,253 @@ impl Process for Ring3Process {
         self.frames
             .get(frame_id)
             .cloned()
+            .flatten()
             .ok_or(ProcessError::InvalidFrameId)
     }
+
+    fn remove_frame(&mut self, frame_id: FrameId) -> Result<Frame, ProcessError> {
+        let frame = self.get_frame(frame_id)?;
+        let frame_range = frame.base.as_u64()..frame.base.as_u64() + frame.size as u64;
+        let is_mapped = self.vspace.mappings.values().any(|mapping| {
+            let mapped_range = mapping.frame.base.as_u64()
+                ..mapping.frame.base.as_u64() + mapping.frame.size as u64;
+            overlaps(&frame_range, &mapped_range)
+        });
+        if is_mapped {
+            return Err(ProcessError::FrameStillMapped);
+        }
+
+        self.frames[frame_id] = None;
+        Ok(frame)
+    }
+
+    fn executor_frames(&self) -> Vec<Frame> {
+        self.vspace
+            .mappings
+            .range(EXECUTOR_OFFSET..self.executor_offset)
+            .map(|(_base, mapping)| mapping.frame)
+            .collect()
+    }
+
+    fn fork(
+        &mut self,
+        pid: Pid,
+        executor_frames: Vec<Frame>,
+        eid: Eid,
+    ) -> Result<(Ring3Process, Box<Ring3Executor>), KError> {
+        let mut child = Ring3Process::create(pid, self.writeable_sections.clone());
+        child.offset = self.offset;
+        child.entry_point = self.entry_point;
+        child.pinfo = self.pinfo;
+        // Unlike POSIX, the child gets its own copy of the file offsets
+        child.fds = self.fds.clone();
+        child.credentials = self.credentials;
+        child
+            .frames
+            .try_reserve(self.frames.len())
+            .map_err(ProcessError::from)?;
+        child.frames.extend_from_slice(&self.frames);
+        child.read_only_sections = self.read_only_sections.clone();
+        child.read_only_offset = self.read_only_offset;
+
+        // The executors get their own memory (a copy, so the stack of the
+        // forking executor is the same), everything else but the shadow
+        // stacks is shared:
+        let mappings: Vec<(VAddr, Frame, MapAction, MappingType)> = self
+            .vspace
+            .mappings
+            .iter()
+            .filter(|(base, _mapping)| **base < EXECUTOR_OFFSET || **base >= self.executor_offset)
+            .filter(|(_base, mapping)| mapping.rights != MapAction::ShadowStackUser)
+            .map(|(base, mapping)| (*base, mapping.frame, mapping.rights, mapping.typ))
+            .collect();
+        // We can't copy larger pages on write (yet), rather fail than have
+        // parent and child write to the same memory
+        let unshareable = mappings.iter().find(|(base, frame, rights, _typ)| {
+            let cow = self
+                .cow
+                .get(base)
+                .map_or(false, |(cow_frame, _rights)| cow_frame == frame);
+            let small = frame.size() == BASE_PAGE_SIZE || frame.size() == LARGE_PAGE_SIZE;
+            !cow && !small && rights.copy_on_write().is_some()
+        });
+        if let Some((base, _frame, _rights, _typ)) = unshareable {
+            return Err(KError::CantShareMapping {
+                base: base.as_u64(),
+            });
+        }
+
+        for (base, frame, rights, typ) in mappings {
+            KernelAllocator::try_refill_tcache(20, 0)?;
+
+            let writable_rights = match self.cow.get(&base) {
+                Some((cow_frame, writable_rights)) if *cow_frame == frame => Some(*writable_rights),
+                _ if frame.size() == BASE_PAGE_SIZE || frame.size() == LARGE_PAGE_SIZE => {
+                    rights.copy_on_write().map(|_read_only_rights| rights)
+                }
+                _ => None,
+            };
+
+            match writable_rights {
+                Some(writable_rights) => {
+                    // It might be protected to be read-only (see `protect`)
+                    let read_only_rights =
+                        writable_rights.copy_on_write().unwrap_or(writable_rights);
+                    if rights != read_only_rights {
+                        self.vspace.adjust(base, read_only_rights)?;
+                        self.cow.insert(base, (frame, writable_rights));
+                    }
+                    child.vspace.map_frame(base, frame, read_only_rights)?;
+                    child.cow.insert(base, (frame, writable_rights));
+                }
+                None => {
+                    // Read-only (see above) or meant to be shared (e.g., device memory)
+                    child.vspace.map_frame(base, frame, rights)?;
+                }
+            }
+            if let Some(mapping) = child.vspace.mappings.get_mut(&base) {
+                mapping.typ = typ;
+            }
+        }
+
+        // Same order as in the parent, so every executor ends up with the
+        // same id at the same address:
+        for frame in executor_frames {
+            child.allocate_executors(frame)?;
+        }
+        let mut executor = child
+            .executor_cache
+            .iter_mut()
+            .flatten()
+            .find_map(|executors| {
+                executors
+                    .iter()
+                    .position(|e| e.eid == eid)
+                    .map(|idx| executors.remove(idx))
+            })
+            .ok_or(ProcessError::NoExecutorAllocated)?;
+        executor.syscall_return = true;
+
+        for i in 128..=135 {
+            child.vspace.page_table.pml4[i] = self.vspace.page_table.pml4[i];
+        }
+
+        Ok((child, executor))
+    }
+
+    fn cow_mapping(&self, vaddr: VAddr) -> Option<(VAddr, Frame)> {
+        let (base, (frame, rights)) = self.cow.range(..=vaddr).next_back()?;
+        let mapping = self.vspace.mappings.get(base)?;
+        // It might have been protected to be read-only (see `protect`)
+        let writable = rights.copy_on_write().is_some();
+        if mapping.frame == *frame && vaddr < *base + frame.size() && writable {
+            Some((*base, *frame))
+        } else {
+            None
+        }
+    }
+
+    fn has_cow_mapping(&self, base: VAddr, len: usize) -> bool {
+        // Entries of mappings that are gone already might still be there
+        self.cow_mapping(base).is_some() || self.cow.range(base..base + len).next().is_some()
+    }
+
+    fn resolve_cow(
+        &mut self,
+        base: VAddr,
+        copy: Option<Frame>,
+    ) -> Result<TlbFlushHandle, AddressSpaceError> {
+        let (frame, rights) = self.cow.remove(&base).ok_or(AddressSpaceError::NotMapped)?;
+        match copy {
+            Some(copy) => {
+                let handle = self.vspace.unmap(base)?;
+                self.vspace.map_frame(base, copy, rights)?;
+                Ok(handle)
+            }
+            None => {
+                self.vspace.adjust(base, rights)?;
+                Ok(TlbFlushHandle::new(base, frame))
+            }
+        }
+    }
+
+    fn protect(
+        &mut self,
+        base: VAddr,
+        len: usize,
+        rights: MapAction,
+    ) -> Result<(), AddressSpaceError> {
+        let mappings = self.covered_mappings(base, len)?;
+        for (mapping_base, frame) in mappings {
+            let shared = match self.cow.get_mut(&mapping_base) {
+                Some((cow_frame, cow_rights)) if *cow_frame == frame => {
+                    *cow_rights = rights;
+                    true
+                }
+                _ => false,
+            };
+            if shared {
+                // Writes still have to go through `resolve_cow`
+                let read_only_rights = rights.copy_on_write().unwrap_or(rights);
+                self.vspace.adjust(mapping_base, read_only_rights)?;
+            } else {
+                self.vspace.adjust(mapping_base, rights)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_key(&mut self, base: VAddr, len: usize, key: u16) -> Result<(), AddressSpaceError> {
+        for (mapping_base, _frame) in self.covered_mappings(base, len)? {
+            self.vspace.set_key(mapping_base, key)?;
+        }
+        Ok(())
+    }
+
+    fn exec(&mut self, module: &Module, writeable_sections: Vec<Frame>) -> Result<Self, KError> {
+        let mut image = Ring3Process::new(module, self.pid, writeable_sections, self.pinfo.policy)?;
+        core::mem::swap(&mut image.fds, &mut self.fds);
+        image.credentials = self.credentials;
+        // The scheduler might still know the old executors, keep the ids
+        // unique:
+        image.current_eid = self.current_eid;
+
+        Ok(core::mem::replace(self, image))
+    }
+
+    fn map_foreign(
+        &mut self,
+        base: VAddr,
+        frame: Frame,
+        action: MapAction,
+        typ: MappingType,
+    ) -> Result<(), AddressSpaceError> {
+        self.vspace.map_frame(base, frame, action)?;
+        if let Some(mapping) = self.vspace.mappings.get_mut(&base) {
+            mapping.typ = typ;
+        }
+        Ok(())
+    }
+
+    fn owned_frames(&self) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = self
+            .vspace
+            .mappings
+            .values()
+            .filter(|mapping| mapping.typ.is_owned())
+            .map(|mapping| mapping.frame)
+            .chain(self.frames.iter().flatten().cloned())
+            .collect();
+        // A registered frame can be mapped too (or several times)
+        frames.sort_unstable_by_key(|frame| frame.base);
+        frames.dedup_by_key(|frame| frame.base);
+        frames
+    }
+
+    fn replica_frames(&self) -> &[Frame] {
+        &self.read_only_sections
+    }
 }
 
 /// Spawns a new process
@@ -1168,10 +1606,20 @@ impl Process for Ring3Process {
 /// - Then we allocate a bunch of memory on all NUMA nodes to create enough dispatchers
 ///   so we can run on all cores
 /// - Finally we allocate a dispatcher to the current core (0) and start running the process
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
+///
+/// `args` replace the application arguments of the kernel command-line for
+/// this process (if not empty), `parent` can wait for the process to exit.
+/// `policy` determines how the executors of the process are scheduled once
+/// it allocates more cores (see `scheduler::fair` and `tlb::gang_schedule`).
+pub fn spawn(
+    binary: &'static str,
+    args: &str,
+    parent: Option<Pid>,
+    policy: SchedulingPolicy,
+) -> Result<Pid, KError> {
     let kcb = kcb::get_kcb();
 
-    let pid = make_process(binary)?;
+    let pid = make_process(binary, args, parent, policy)?;
     allocate_dispatchers(pid)?;
 
     // Set current thread to run executor from our process (on the current core)
@@ -1185,3 +1633,289 @@ pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
 
     Ok(pid)
 }
+
+/// Restarts the supervised process `child` of `pid` once it exited (see
+/// `nr::KernelNode::supervise`): in one go, the new process gets the file
+/// descriptors (and pipe ends) of `child` and starts on the core `child` ran
+/// on.
+///
+/// Returns the new process and the exit code of `child`, or None if `child`
+/// is still running. In that case the executor `eid` isn't scheduled
+/// anymore until `child` exited.
+pub fn restart(pid: Pid, eid: Eid, child: Pid) -> Result<Option<(Pid, u64)>, KError> {
+    let binary = match nr::KernelNode::<Ring3Process>::restartable(pid, child)? {
+        Some(binary) => binary,
+        None => {
+            // It might have exited in the meantime
+            if nr::KernelNode::<Ring3Process>::wait_child(pid, eid, child)?.is_none() {
+                return Ok(None);
+            }
+            nr::KernelNode::<Ring3Process>::restartable(pid, child)?
+                .ok_or(ProcessError::NotAChild)?
+        }
+    };
+
+    let (module, writeable_sections) = load_binary(binary)?;
+    let (new_pid, code, gtid) =
+        nr::KernelNode::<Ring3Process>::restart(pid, child, module, writeable_sections)?;
+    crate::fs::pipe::transfer(child, new_pid);
+    allocate_dispatchers(new_pid)?;
+
+    // Back on the core it ran on (if we can)
+    let thread = gtid
+        .and_then(|gtid| {
+            topology::MACHINE_TOPOLOGY
+                .threads()
+                .find(|thread| thread.id == gtid)
+        })
+        .unwrap_or_else(|| topology::MACHINE_TOPOLOGY.current_thread());
+    let (_gtid, _eid) = nr::KernelNode::<Ring3Process>::allocate_core_to_process(
+        new_pid,
+        INVALID_EXECUTOR_START, // This VAddr is irrelevant as it is overriden later
+        thread.node_id.or(Some(0)),
+        Some(thread.id),
+    )?;
+
+    Ok(Some((new_pid, code)))
+}
+
+/// Replaces the image of process `pid` (which has to run on the current
+/// core only) with the boot module `binary`.
+///
+/// The old address space is gone afterwards: the caller has to continue
+/// with the executor we allocated for the new image on this core (see
+/// `irq::leave_exited_executor`).
+pub fn exec(pid: Pid, binary: &'static str) -> Result<(), KError> {
+    let (module, writeable_sections) = load_binary(binary)?;
+    let thread = topology::MACHINE_TOPOLOGY.current_thread();
+
+    // The page-tables of the old image are given back, get off them (until
+    // we know the exec worked)
+    let kcb = kcb::get_kcb();
+    let old_pml4 = unsafe { controlregs::cr3() };
+    unsafe { controlregs::cr3_write(kcb.arch.init_vspace().pml4_address().into()) };
+    if let Err(e) = nr::KernelNode::<Ring3Process>::exec(pid, module, writeable_sections, thread.id)
+    {
+        unsafe { controlregs::cr3_write(old_pml4) };
+        return Err(e);
+    }
+
+    allocate_dispatchers(pid)?;
+    let (_gtid, _eid) = nr::KernelNode::<Ring3Process>::allocate_core_to_process(
+        pid,
+        INVALID_EXECUTOR_START, // This VAddr is irrelevant as it is overriden later
+        thread.node_id.or(Some(0)),
+        Some(thread.id),
+    )?;
+
+    Ok(())
+}
+
+/// Terminates all processes of process `group` on behalf of process `pid`
+/// (see `nr::KernelNode::terminate_group`), returns the processes that were
+/// terminated.
+///
+/// If `pid` is one of them the caller has to continue with another executor
+/// (see `irq::leave_exited_executor`).
+pub fn terminate_group(pid: Pid, group: Pid) -> Result<Vec<Pid>, KError> {
+    let members: Vec<kpi::process::ProcessEntry> = nr::KernelNode::<Ring3Process>::processes()?
+        .into_iter()
+        .filter(|p| p.group == group)
+        .collect();
+    let in_group = members.iter().any(|p| p.pid == pid);
+
+    // Our page-tables might be given back, get off them (until we know the
+    // termination worked)
+    let kcb = kcb::get_kcb();
+    let old_pml4 = unsafe { controlregs::cr3() };
+    if in_group {
+        unsafe { controlregs::cr3_write(kcb.arch.init_vspace().pml4_address().into()) };
+    }
+    let terminated = match nr::KernelNode::<Ring3Process>::terminate_group(
+        pid,
+        group,
+        kpi::process::TERMINATED_EXIT_CODE,
+    ) {
+        Ok((terminated, released)) => {
+            release_exited(&terminated, &released)?;
+            terminated
+        }
+        Err(e) => {
+            unsafe { controlregs::cr3_write(old_pml4) };
+            return Err(e);
+        }
+    };
+
+    let my_gtid = kcb.arch.id();
+    for member in terminated.iter() {
+        // Members that joined in the meantime stop on the next timer
+        // interrupt of their cores
+        for entry in members.iter().filter(|p| p.pid == *member) {
+            for gtid in entry.cores.iter().filter(|gtid| **gtid != my_gtid) {
+                super::tlb::terminate(*gtid as topology::GlobalThreadId, *member);
+            }
+        }
+    }
+
+    Ok(terminated)
+}
+
+/// Drops what the kernel keeps outside of the NR replica for the processes
+/// that `exited` (or were terminated).
+///
+/// The file descriptors (pipes) only go away for the `released` processes
+/// (see `nr::KernelNode::exit`), a supervisor might still hand them to the
+/// restarted process.
+pub fn release_exited(exited: &[Pid], released: &[Pid]) -> Result<(), KError> {
+    for pid in exited {
+        super::asyncring::unregister(*pid);
+        super::eventring::unregister(*pid);
+        super::cat::unregister(*pid);
+        super::mbm::unregister(*pid);
+        super::steering::release_msi(*pid);
+        super::pci::release_all(*pid);
+        super::pkeys::unregister(*pid);
+        super::cet::unregister(*pid);
+        crate::process::forget_cpu_usage(*pid);
+    }
+    for pid in released {
+        if cfg!(feature = "mlnrfs") {
+            crate::mlnr::MlnrKernelNode::remove_process(*pid)?;
+        }
+        crate::fs::pipe::close_all(*pid);
+    }
+    Ok(())
+}
+
+/// Allocates a frame of the same size (and on the same node) as `frame` and
+/// copies the content of `frame` into it.
+fn copy_frame(frame: Frame) -> Result<Frame, KError> {
+    let copy = match frame.size() {
+        BASE_PAGE_SIZE => {
+            KernelAllocator::try_refill_tcache(1, 0)?;
+            let kcb = kcb::get_kcb();
+            let mut pmanager = kcb.mem_manager();
+            pmanager.allocate_base_page()?
+        }
+        LARGE_PAGE_SIZE => {
+            let kcb = kcb::get_kcb();
+            let gmanager = kcb
+                .physical_memory
+                .gmanager
+                .ok_or(KError::GlobalMemoryNotSet)?;
+            let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+            ncache.allocate_large_page()?
+        }
+        _ => return Err(KError::NotSupported),
+    };
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            frame.kernel_vaddr().as_ptr::<u8>(),
+            copy.kernel_vaddr().as_mut_ptr::<u8>(),
+            frame.size(),
+        );
+    }
+    Ok(copy)
+}
+
+/// Gives a frame allocated with `copy_frame` back.
+fn release_frame(frame: Frame) -> Result<(), KError> {
+    KernelAllocator::release_frame(frame)?;
+    Ok(())
+}
+
+/// Makes the page of `pid` at `vaddr` writable if it's shared copy-on-write,
+/// the process gets its own copy of the page unless it's the last one that
+/// uses it.
+///
+/// Returns false if `vaddr` isn't in a copy-on-write mapping.
+pub fn resolve_cow(pid: Pid, vaddr: VAddr) -> Result<bool, KError> {
+    let (base, frame, shared) = match nr::KernelNode::<Ring3Process>::cow_mapping(pid, vaddr)? {
+        Some(mapping) => mapping,
+        None => return Ok(false),
+    };
+
+    // Copy outside of NR, the replicas all have to map the same frame:
+    let copy = if shared {
+        Some(copy_frame(frame)?)
+    } else {
+        None
+    };
+    match nr::KernelNode::<Ring3Process>::resolve_cow(pid, base, copy)? {
+        Some(handle) => super::tlb::shootdown(handle),
+        None => {
+            // Someone else got there first, the caller tries again (and
+            // finds out what changed)
+            if let Some(copy) = copy {
+                release_frame(copy)?;
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Duplicates the process `pid` (see `ProcessOperation::Fork`).
+///
+/// The memory of the process is shared copy-on-write with the child, except
+/// for the executors: the child gets a copy of them and the copy of the
+/// executor that runs on this core continues on this core (it returns 0 from
+/// the system call).
+pub fn fork(pid: Pid) -> Result<Pid, KError> {
+    let kcb = kcb::get_kcb();
+    let eid = kcb.arch.current_process()?.eid;
+    let gtid = kcb.arch.hwthread_id();
+
+    let executor_frames = nr::KernelNode::<Ring3Process>::executor_frames(pid)?;
+    let mut copies = Vec::with_capacity(executor_frames.len());
+    for frame in executor_frames {
+        copies.push(copy_frame(frame)?);
+    }
+
+    let (child, executor, handle) =
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(nr::Op::ProcFork(pid, copies, eid, gtid), *token);
+                match response {
+                    Ok(nr::NodeResult::ProcForked(child, executor, handle)) => {
+                        Ok((child, executor, handle))
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })?;
+    // Make sure the parent doesn't write to the shared memory anymore.
+    //
+    // TODO(correctness): Cores of the parent that use a different replica
+    // see the read-only mappings only once their replica caught up.
+    super::tlb::shootdown(handle);
+
+    if cfg!(feature = "mlnrfs") {
+        crate::mlnr::MlnrKernelNode::fork_process(pid, child)?;
+    }
+    crate::fs::pipe::fork(pid, child);
+
+    // The child continues where the parent entered the system call, but
+    // `fork` returns 0 for it:
+    let mut save_area = kcb
+        .arch
+        .save_area
+        .as_ref()
+        .map(|sa| **sa)
+        .ok_or(ProcessError::ProcessNotSet)?;
+    save_area.set_syscall_ret1(0);
+    save_area.set_syscall_ret2(0);
+    save_area.set_syscall_error_code(kpi::SystemCallError::Ok);
+
+    // Nobody else dispatches executors of this core, so it's fine to
+    // write it before it runs (like `irq::time_slice` does):
+    let executor = executor
+        .upgrade()
+        .ok_or(ProcessError::ExecutorNoLongerValid)?;
+    unsafe { Ring3Executor::save_context(&executor, &save_area) };
+
+    Ok(child)
+}
@@ -0,0 +1,219 @@
+//! Physical memory that is in use before the allocators exist.
+//!
+//! Some memory must never be handed out by the physical memory allocators:
+//! the bootstrap code for app cores (see `coreboot`), the ACPI tables, the
+//! modules the bootloader loaded and the frame-buffer. We record these
+//! regions at boot ([`init`]), check them against the UEFI memory map and cut
+//! them out of the conventional memory before it goes to the allocators
+//! ([`exclude`]), instead of relying on where the bootloader and firmware
+//! happen to put things.
+
+use arrayvec::ArrayVec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
+
+use super::memory::kernel_vaddr_to_paddr;
+use super::KernelArgs;
+
+/// How many regions we can reserve.
+const MAX_REGIONS: usize = 64;
+
+/// In how many pieces the reserved regions can cut a frame.
+pub const MAX_PIECES: usize = 16;
+
+/// What a reserved region is used for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Purpose {
+    /// Real-mode code that boots (and wakes up) the app cores.
+    Trampoline,
+    /// ACPI tables (and the RSDP that points to them).
+    AcpiTables,
+    /// A module (binary) the bootloader loaded.
+    Module,
+    /// The frame-buffer of the graphics card.
+    FrameBuffer,
+}
+
+/// A reserved region of physical memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub purpose: Purpose,
+    pub base: PAddr,
+    pub size: usize,
+}
+
+impl Region {
+    /// The pages of the region, `[start, end)`.
+    fn pages(&self) -> (u64, u64) {
+        let page = BASE_PAGE_SIZE as u64;
+        let start = self.base.as_u64() & !(page - 1);
+        let end = (self.base.as_u64() + self.size as u64 + page - 1) & !(page - 1);
+        (start, end)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        let (rstart, rend) = self.pages();
+        rstart < end && start < rend
+    }
+}
+
+lazy_static! {
+    static ref REGIONS: Mutex<ArrayVec<[Region; MAX_REGIONS]>> = Mutex::new(ArrayVec::new());
+}
+
+/// Reserves `[base, base+size)` for `purpose`.
+pub fn reserve(purpose: Purpose, base: PAddr, size: usize) {
+    if size == 0 {
+        return;
+    }
+    let region = Region {
+        purpose,
+        base,
+        size,
+    };
+    debug!("Reserve {:?}", region);
+    REGIONS
+        .lock()
+        .try_push(region)
+        .expect("Too many reserved memory regions");
+}
+
+/// Reserves the regions we know about at boot and checks them against the
+/// UEFI memory map (needs to happen before we build any allocator).
+pub fn init(args: &KernelArgs) {
+    let (base, size) = super::coreboot::bootstrap_region();
+    reserve(Purpose::Trampoline, base, size);
+
+    for desc in args.mm_iter.iter() {
+        if desc.ty == MemoryType::ACPI_RECLAIM || desc.ty == MemoryType::ACPI_NON_VOLATILE {
+            let size = desc.page_count as usize * BASE_PAGE_SIZE;
+            reserve(Purpose::AcpiTables, PAddr::from(desc.phys_start), size);
+        }
+    }
+    for rsdp in [args.acpi1_rsdp, args.acpi2_rsdp].iter() {
+        if *rsdp != PAddr::zero() {
+            reserve(Purpose::AcpiTables, *rsdp, BASE_PAGE_SIZE);
+        }
+    }
+
+    for module in args.modules.iter() {
+        reserve(Purpose::Module, module.binary_paddr, module.size());
+    }
+
+    if let Some(frame_buffer) = args.frame_buffer.as_ref() {
+        let vaddr = VAddr::from(frame_buffer.as_ptr() as u64);
+        reserve(
+            Purpose::FrameBuffer,
+            kernel_vaddr_to_paddr(vaddr),
+            frame_buffer.len(),
+        );
+    }
+
+    audit(&args.mm_iter);
+}
+
+/// Reports reserved regions that overlap conventional memory in the UEFI
+/// memory map (they're excluded from the allocators, but the firmware or
+/// bootloader didn't tell us they're in use).
+fn audit(map: &[MemoryDescriptor]) {
+    for region in REGIONS.lock().iter() {
+        let conventional = map.iter().any(|desc| {
+            let start = desc.phys_start;
+            let end = start + desc.page_count * BASE_PAGE_SIZE as u64;
+            desc.ty == MemoryType::CONVENTIONAL && region.overlaps(start, end)
+        });
+        if conventional && region.purpose != Purpose::Trampoline {
+            warn!("Reserved {:?} is in conventional memory", region);
+        }
+    }
+}
+
+/// The parts of `frame` that don't overlap any of `regions`.
+fn exclude_from(frame: Frame, regions: &[Region]) -> ArrayVec<[Frame; MAX_PIECES]> {
+    let mut pieces: ArrayVec<[Frame; MAX_PIECES]> = ArrayVec::new();
+    pieces.push(frame);
+
+    for region in regions {
+        let mut remaining: ArrayVec<[Frame; MAX_PIECES]> = ArrayVec::new();
+        for piece in pieces.drain(..) {
+            let (start, end) = (piece.base.as_u64(), piece.end().as_u64());
+            if !region.overlaps(start, end) {
+                remaining.push(piece);
+                continue;
+            }
+
+            let (rstart, rend) = region.pages();
+            if rstart > start {
+                let (low, _high) = piece.split_at((rstart - start) as usize);
+                remaining
+                    .try_push(low)
+                    .expect("Frame is cut in too many pieces");
+            }
+            if rend < end {
+                let (_low, high) = piece.split_at((rend - start) as usize);
+                remaining
+                    .try_push(high)
+                    .expect("Frame is cut in too many pieces");
+            }
+        }
+        pieces = remaining;
+    }
+    pieces
+}
+
+/// The parts of `frame` that are not reserved.
+pub fn exclude(frame: Frame) -> ArrayVec<[Frame; MAX_PIECES]> {
+    exclude_from(frame, &REGIONS.lock())
+}
+
+/// Does `frame` contain reserved memory?
+pub fn overlaps(frame: &Frame) -> bool {
+    let (start, end) = (frame.base.as_u64(), frame.end().as_u64());
+    REGIONS.lock().iter().any(|r| r.overlaps(start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(base: u64, size: usize) -> Region {
+        Region {
+            purpose: Purpose::Module,
+            base: PAddr::from(base),
+            size,
+        }
+    }
+
+    fn ranges(pieces: &[Frame]) -> ArrayVec<[(u64, u64); MAX_PIECES]> {
+        pieces
+            .iter()
+            .map(|f| (f.base.as_u64(), f.end().as_u64()))
+            .collect()
+    }
+
+    #[test]
+    fn cut_out_regions() {
+        let frame = Frame::new(PAddr::from(0x10_0000), 0x10_0000, 0);
+
+        // Nothing reserved
+        assert_eq!(ranges(&exclude_from(frame, &[])), [(0x10_0000, 0x20_0000)]);
+
+        // A hole in the middle (rounded to pages), and one at the end
+        let regions = [region(0x18_0010, 0x100), region(0x1f_f000, 0x2000)];
+        assert_eq!(
+            ranges(&exclude_from(frame, &regions)),
+            [(0x10_0000, 0x18_0000), (0x18_1000, 0x1f_f000)]
+        );
+
+        // Everything reserved
+        let regions = [region(0x0, 0x100_0000)];
+        assert!(exclude_from(frame, &regions).is_empty());
+
+        // Somewhere else
+        let regions = [region(0x30_0000, 0x1000)];
+        assert_eq!(ranges(&exclude_from(frame, &regions)).len(), 1);
+    }
+}
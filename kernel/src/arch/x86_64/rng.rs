@@ -0,0 +1,222 @@
+//! Random numbers for user-space (`SystemOperation::GetRandom`).
+//!
+//! We use RDSEED and RDRAND if the CPU has them. They can fail temporarily
+//! (if the hardware can't keep up) and some machines (or hypervisors) don't
+//! have them at all, in that case we fall back to a ChaCha20 based DRBG.
+//!
+//! The DRBG is seeded at boot with RDRAND output (if there is any) and the
+//! jitter of timing measurements with the TSC. It replaces its key after
+//! every request so earlier output can't be reconstructed from its state.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+
+use spin::{Mutex, Once};
+
+/// How many bytes we hand out per system call at most.
+pub const MAX_REQUEST: usize = 64 * 1024;
+
+/// How often we retry RDSEED/RDRAND before we give up on them.
+const RETRIES: usize = 10;
+
+/// How many timing measurements we mix into the seed.
+const JITTER_ROUNDS: usize = 1024;
+
+/// Which random number instructions the CPU has.
+#[derive(Debug, Copy, Clone)]
+struct Features {
+    rdrand: bool,
+    rdseed: bool,
+}
+
+impl Features {
+    fn detect() -> Features {
+        let (leaf1, leaf7) = unsafe { (__cpuid(1), __cpuid_count(7, 0)) };
+        Features {
+            rdrand: leaf1.ecx & (1 << 30) != 0,
+            rdseed: leaf7.ebx & (1 << 18) != 0,
+        }
+    }
+}
+
+/// The ChaCha20 block function (RFC 7539).
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _i in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (s, i) in state.iter_mut().zip(input.iter()) {
+        *s = s.wrapping_add(*i);
+    }
+    state
+}
+
+/// Deterministic random bit generator (ChaCha20 with fast key erasure).
+struct Drbg {
+    key: [u32; 8],
+}
+
+impl Drbg {
+    /// Creates a DRBG, `seed` doesn't have to be uniformly distributed (it's
+    /// hashed with ChaCha20 first).
+    fn new(seed: &[u32; 8]) -> Drbg {
+        let block = chacha20_block(seed, 0, &[0; 3]);
+        let mut key = [0; 8];
+        key.copy_from_slice(&block[..8]);
+        Drbg { key }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        // The first block becomes the next key, the rest is output
+        let block = chacha20_block(&self.key, 0, &[0; 3]);
+        let mut next_key = [0; 8];
+        next_key.copy_from_slice(&block[..8]);
+
+        for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+            let block = chacha20_block(&self.key, counter as u32 + 1, &[0; 3]);
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> (8 * (i % 4))) as u8;
+            }
+        }
+
+        self.key = next_key;
+    }
+}
+
+/// Collects seed material: RDRAND output and TSC jitter.
+fn gather_seed(features: Features) -> [u32; 8] {
+    let mut pool = [0u32; 8];
+
+    for round in 0..JITTER_ROUNDS {
+        let start = unsafe { x86::time::rdtsc() };
+        for _i in 0..(round % 7) + 1 {
+            core::hint::spin_loop();
+        }
+        let delta = unsafe { x86::time::rdtsc() }.wrapping_sub(start);
+
+        let idx = round % pool.len();
+        pool[idx] = pool[idx].rotate_left(7) ^ delta as u32 ^ (start >> 32) as u32;
+    }
+
+    if features.rdrand {
+        for word in pool.iter_mut() {
+            let mut rand = 0;
+            if unsafe { _rdrand64_step(&mut rand) } == 1 {
+                *word ^= rand as u32 ^ (rand >> 32) as u32;
+            }
+        }
+    }
+
+    pool
+}
+
+static FEATURES: Once<Features> = Once::new();
+
+static DRBG: Once<Mutex<Drbg>> = Once::new();
+
+/// Detects the random number instructions and seeds the DRBG.
+pub fn init() {
+    let features = *FEATURES.call_once(Features::detect);
+    DRBG.call_once(|| Mutex::new(Drbg::new(&gather_seed(features))));
+    debug!("Random number generation: {:?}", features);
+}
+
+/// Reads a random word from RDSEED or RDRAND (None if both failed).
+fn hardware_random(features: Features) -> Option<u64> {
+    let mut rand = 0;
+    for _i in 0..RETRIES {
+        if features.rdseed && unsafe { _rdseed64_step(&mut rand) } == 1 {
+            return Some(rand);
+        }
+        if features.rdrand && unsafe { _rdrand64_step(&mut rand) } == 1 {
+            return Some(rand);
+        }
+    }
+    None
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let features = *FEATURES.call_once(Features::detect);
+
+    let mut filled = 0;
+    if features.rdseed || features.rdrand {
+        for chunk in buf.chunks_mut(8) {
+            match hardware_random(features) {
+                Some(rand) => {
+                    chunk.copy_from_slice(&rand.to_le_bytes()[..chunk.len()]);
+                    filled += chunk.len();
+                }
+                None => break,
+            }
+        }
+    }
+
+    if filled < buf.len() {
+        let drbg = DRBG.call_once(|| Mutex::new(Drbg::new(&gather_seed(features))));
+        drbg.lock().fill(&mut buf[filled..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chacha20_test_vector() {
+        // RFC 7539, section 2.3.2
+        let key = [
+            0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c, 0x13121110, 0x17161514, 0x1b1a1918,
+            0x1f1e1d1c,
+        ];
+        let nonce = [0x09000000, 0x4a000000, 0x00000000];
+        let block = chacha20_block(&key, 1, &nonce);
+        assert_eq!(
+            block,
+            [
+                0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3, 0xc7f4d1c7, 0x0368c033, 0x9aaa2204,
+                0x4e6cd4c3, 0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9, 0xd19c12b5, 0xb94e16de,
+                0xe883d0cb, 0x4e3c50a2,
+            ]
+        );
+    }
+
+    #[test]
+    fn drbg_rekeys() {
+        let mut drbg = Drbg::new(&[1; 8]);
+        let mut a = [0u8; 100];
+        let mut b = [0u8; 100];
+        drbg.fill(&mut a);
+        drbg.fill(&mut b);
+        assert_ne!(a, b);
+        assert_ne!(a[..32], a[64..96]);
+
+        // Same seed, same output
+        let mut c = [0u8; 100];
+        Drbg::new(&[1; 8]).fill(&mut c);
+        assert_eq!(a, c);
+    }
+}
@@ -0,0 +1,148 @@
+//! Quick self-tests that run on the BSP before we spawn init.
+//!
+//! Enabled with the `selftest` feature. The goal is to catch a broken
+//! machine (or VM) configuration early, so every test only takes a few
+//! milliseconds at most.
+//!
+//! Results are printed in a machine-readable format, one line per test
+//! followed by a summary:
+//!
+//! ```text
+//! SELFTEST <name> PASS
+//! SELFTEST <name> FAIL <reason>
+//! SELFTEST SUMMARY pass=<n> fail=<m>
+//! ```
+
+use apic::ApicDriver;
+use x86::apic::ApicId;
+
+use crate::memory::{AllocatorStatistics, KernelAllocator, PhysicalPageProvider, BASE_PAGE_SIZE};
+use crate::mlnr::MlnrKernelNode;
+use crate::nr::KernelNode;
+
+use super::kcb::get_kcb;
+use super::process::Ring3Process;
+
+type TestResult = Result<(), &'static str>;
+
+/// Our core-local physical memory allocator keeps its accounting straight.
+fn allocator_invariants() -> TestResult {
+    KernelAllocator::try_refill_tcache(1, 0).map_err(|_e| "can't refill tcache")?;
+
+    let kcb = get_kcb();
+    let mut pmanager = kcb.mem_manager();
+    if pmanager.capacity() < pmanager.free() {
+        return Err("capacity < free");
+    }
+
+    let free_before = pmanager.free();
+    let frame = pmanager
+        .allocate_base_page()
+        .map_err(|_e| "can't allocate a base page")?;
+    if frame.size() != BASE_PAGE_SIZE || frame.base.as_u64() % BASE_PAGE_SIZE as u64 != 0 {
+        return Err("allocated base page is malformed");
+    }
+    if pmanager.free() + BASE_PAGE_SIZE != free_before {
+        return Err("allocation not accounted for");
+    }
+    pmanager
+        .release_base_page(frame)
+        .map_err(|_e| "can't release base page")?;
+    if pmanager.free() != free_before {
+        return Err("release not accounted for");
+    }
+
+    Ok(())
+}
+
+/// The TSC (which drives our APIC deadline timer) is usable and
+/// agrees with our notion of wall-clock time.
+fn apic_timer_sanity() -> TestResult {
+    let has_deadline = x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |fi| fi.has_tsc_deadline());
+    if !has_deadline {
+        return Err("no TSC deadline mode");
+    }
+
+    let start = rawtime::Instant::now();
+    let tsc_start = unsafe { x86::time::rdtsc() };
+    while start.elapsed().as_micros() < 1000 {
+        core::hint::spin_loop();
+    }
+    let tsc_elapsed = unsafe { x86::time::rdtsc() } - tsc_start;
+
+    // 1 ms should be somewhere between 100 MHz and 10 GHz worth of cycles
+    if tsc_elapsed < 100_000 || tsc_elapsed > 10_000_000 {
+        return Err("TSC frequency is implausible");
+    }
+
+    Ok(())
+}
+
+/// The parsed topology is consistent with the hardware we run on.
+fn topology_consistency() -> TestResult {
+    let topology = &*topology::MACHINE_TOPOLOGY;
+    if topology.num_threads() == 0 {
+        return Err("no hardware threads");
+    }
+
+    for (idx, thread) in topology.threads().enumerate() {
+        if thread.id as usize != idx {
+            return Err("thread ids are not dense");
+        }
+        if topology.num_nodes() > 0
+            && thread.node_id.map_or(true, |n| n as usize >= topology.num_nodes())
+        {
+            return Err("thread with invalid NUMA node");
+        }
+    }
+
+    let kcb = get_kcb();
+    let apic_id = match topology.current_thread().apic_id() {
+        ApicId::X2Apic(id) => id,
+        ApicId::XApic(id) => id as u32,
+    };
+    if apic_id != kcb.arch.apic().id() {
+        return Err("APIC id doesn't match current thread");
+    }
+
+    Ok(())
+}
+
+/// Our replicas are set-up and can execute operations.
+fn nr_smoke() -> TestResult {
+    KernelNode::<Ring3Process>::synchronize().map_err(|_e| "can't synchronize nr replica")?;
+    MlnrKernelNode::synchronize_log(1).map_err(|_e| "can't synchronize mlnr replica")?;
+    Ok(())
+}
+
+/// Runs all self-tests and prints the results.
+///
+/// Returns true if all tests passed.
+pub fn run() -> bool {
+    let tests: [(&str, fn() -> TestResult); 4] = [
+        ("allocator_invariants", allocator_invariants),
+        ("apic_timer_sanity", apic_timer_sanity),
+        ("topology_consistency", topology_consistency),
+        ("nr_smoke", nr_smoke),
+    ];
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for (name, test) in tests.iter() {
+        match test() {
+            Ok(()) => {
+                passed += 1;
+                sprintln!("SELFTEST {} PASS", name);
+            }
+            Err(reason) => {
+                failed += 1;
+                sprintln!("SELFTEST {} FAIL {}", name, reason);
+            }
+        }
+    }
+
+    sprintln!("SELFTEST SUMMARY pass={} fail={}", passed, failed);
+    failed == 0
+}
@@ -0,0 +1,499 @@
+//! IRQ steering: routes device interrupts (IOAPIC GSIs) to cores at runtime.
+//!
+//! A route is established when a process allocates an interrupt line
+//! (`ProcessOperation::AllocateVector`). After that it can be changed
+//! explicitly (`ProcessOperation::SteerVector`) or by the balancing policy
+//! ([`rebalance`]) which periodically moves interrupts from the busiest to
+//! the least busy core of the process that owns them.
+//!
+//! The interrupt lines of PCI devices are found in the ACPI namespace (the
+//! `_PRT` of the PCI root bridges, see [`pci_gsi`]).
+//!
+//! PCI devices that signal with MSI or MSI-X get vectors from a separate
+//! pool ([`allocate_msi`]). We don't have interrupt remapping, so the
+//! driver writes the message (address and data) we hand out to the MSI
+//! capability or MSI-X table of its device itself.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::error::KError;
+use crate::process::Pid;
+
+/// IOAPIC interrupt lines are delivered with vector `gsi + IOAPIC_VECTOR_BASE`.
+pub const IOAPIC_VECTOR_BASE: u64 = kpi::io::IRQ_VECTOR_BASE;
+
+/// How many interrupt lines we can steer.
+pub const MAX_GSIS: usize = 64;
+
+/// MSI vectors follow the ones of the IOAPIC interrupt lines.
+pub const MSI_VECTOR_BASE: u64 = IOAPIC_VECTOR_BASE + MAX_GSIS as u64;
+
+/// How many MSI vectors we have (they have to stay below the upcall
+/// commands in `kpi::upcall`, which share the vector space in user-space).
+pub const MAX_MSI_VECTORS: usize = 48;
+
+/// How many MSI vectors a process can allocate (the most a device can ask
+/// for with MSI), so one driver can't use up all of them.
+pub const MAX_MSI_VECTORS_PER_PROCESS: usize = 32;
+
+/// Address of an MSI message that goes to the local APIC with id 0.
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Only move an interrupt if the busiest core handled at least this many
+/// more interrupts than the least busy one (since the last rebalancing).
+pub const REBALANCE_THRESHOLD: u64 = 1000;
+
+/// Where an interrupt line is routed to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    /// Process that allocated the interrupt line.
+    pub pid: Pid,
+    /// Core that receives the interrupts.
+    pub core: topology::GlobalThreadId,
+}
+
+/// Interrupts of a line during the last balancing period.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IrqLoad {
+    pub gsi: u64,
+    pub core: topology::GlobalThreadId,
+    pub count: u64,
+}
+
+/// The interrupt line of an interrupt pin of a PCI device (from the `_PRT`
+/// of a PCI root bridge, see `acpi::pci_irq_routes`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciIrqRoute {
+    pub bus: u64,
+    pub device: u64,
+    /// 0 for INTA# to 3 for INTD#.
+    pub pin: u64,
+    pub gsi: u64,
+}
+
+/// Owner of an MSI vector.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MsiRoute {
+    pub pid: Pid,
+    /// PCI function that signals the vector (`bus << 8 | device << 3 | function`).
+    pub function: u64,
+    /// Core that receives the interrupts.
+    pub core: topology::GlobalThreadId,
+}
+
+lazy_static! {
+    /// Interrupt lines of PCI devices.
+    static ref PCI_ROUTES: Mutex<Vec<PciIrqRoute>> = Mutex::new(Vec::new());
+
+    /// The routing table, indexed by GSI.
+    static ref ROUTES: Mutex<[Option<Route>; MAX_GSIS]> = Mutex::new([None; MAX_GSIS]);
+
+    /// The MSI vectors, indexed by `vector - MSI_VECTOR_BASE`.
+    static ref MSI_ROUTES: Mutex<[Option<MsiRoute>; MAX_MSI_VECTORS]> =
+        Mutex::new([None; MAX_MSI_VECTORS]);
+
+    /// Interrupts received per GSI (reset whenever we rebalance).
+    static ref COUNTERS: Vec<AtomicU64> = {
+        let mut counters = Vec::with_capacity(MAX_GSIS);
+        for _i in 0..MAX_GSIS {
+            counters.push(AtomicU64::new(0));
+        }
+        counters
+    };
+}
+
+/// Counts an interrupt with `vector` (ignores vectors that are not
+/// IOAPIC interrupt lines).
+pub fn record(vector: u64) {
+    if let Some(gsi) = vector.checked_sub(IOAPIC_VECTOR_BASE) {
+        if (gsi as usize) < MAX_GSIS {
+            COUNTERS[gsi as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sets the interrupt lines of PCI devices (see `acpi::pci_irq_routes`).
+pub fn set_pci_routes(routes: Vec<PciIrqRoute>) {
+    *PCI_ROUTES.lock() = routes;
+}
+
+/// All known interrupt lines of PCI devices.
+pub fn pci_routes() -> Vec<PciIrqRoute> {
+    PCI_ROUTES.lock().clone()
+}
+
+/// The interrupt line (GSI) `pin` of PCI `device` on `bus` is connected to.
+pub fn pci_gsi(bus: u64, device: u64, pin: u64) -> Option<u64> {
+    lookup(&PCI_ROUTES.lock(), bus, device, pin)
+}
+
+/// Finds `count` free consecutive MSI vectors, aligned to the next power
+/// of two (MSI with multiple messages needs that, MSI-X doesn't care).
+///
+/// Returns the index of the first one.
+fn free_msi_range(routes: &[Option<MsiRoute>], count: usize) -> Option<usize> {
+    if count == 0 || count > routes.len() {
+        return None;
+    }
+    let align = count.next_power_of_two();
+    (0..routes.len())
+        .step_by(align)
+        .take_while(|start| start + count <= routes.len())
+        .find(|start| routes[*start..*start + count].iter().all(|r| r.is_none()))
+}
+
+/// How many MSI vectors process `pid` has.
+fn msi_vectors_of(routes: &[Option<MsiRoute>], pid: Pid) -> usize {
+    routes
+        .iter()
+        .filter(|r| r.map_or(false, |r| r.pid == pid))
+        .count()
+}
+
+/// Allocates `count` consecutive MSI vectors for PCI `function` of process
+/// `pid` that are delivered to `core`.
+///
+/// The caller has to make sure `pid` owns `function`. A process can't have
+/// more than `MAX_MSI_VECTORS_PER_PROCESS` vectors.
+///
+/// Returns the first vector and the message address. The message data of
+/// the n-th vector is the vector itself (fixed delivery, edge triggered).
+pub fn allocate_msi(
+    pid: Pid,
+    function: u64,
+    core: topology::GlobalThreadId,
+    count: u64,
+) -> Result<(u64, u64), KError> {
+    let address = msi_address(core)?;
+
+    let mut routes = MSI_ROUTES.lock();
+    if msi_vectors_of(&routes[..], pid) + count as usize > MAX_MSI_VECTORS_PER_PROCESS {
+        return Err(KError::NoMsiVectors { count });
+    }
+    let start =
+        free_msi_range(&routes[..], count as usize).ok_or(KError::NoMsiVectors { count })?;
+    for route in routes[start..start + count as usize].iter_mut() {
+        *route = Some(MsiRoute {
+            pid,
+            function,
+            core,
+        });
+    }
+
+    Ok((MSI_VECTOR_BASE + start as u64, address))
+}
+
+/// Allocates consecutive MSI vectors for PCI `function` of process `pid`,
+/// one for each of `cores`.
+///
+/// Returns the vectors and their message address (MSI-X messages have an
+/// address each).
+pub fn allocate_msix(
+    pid: Pid,
+    function: u64,
+    cores: &[topology::GlobalThreadId],
+) -> Result<Vec<(u64, u64)>, KError> {
+    let count = cores.len() as u64;
+    let addresses = cores
+        .iter()
+        .map(|core| msi_address(*core))
+        .collect::<Result<Vec<u64>, KError>>()?;
+
+    let mut routes = MSI_ROUTES.lock();
+    let start =
+        free_msi_range(&routes[..], count as usize).ok_or(KError::NoMsiVectors { count })?;
+    for (route, core) in routes[start..start + count as usize].iter_mut().zip(cores) {
+        *route = Some(MsiRoute {
+            pid,
+            function,
+            core: *core,
+        });
+    }
+
+    Ok(addresses
+        .into_iter()
+        .enumerate()
+        .map(|(idx, address)| (MSI_VECTOR_BASE + (start + idx) as u64, address))
+        .collect())
+}
+
+/// The address of MSI messages to `core`.
+fn msi_address(core: topology::GlobalThreadId) -> Result<u64, KError> {
+    if core as usize >= topology::MACHINE_TOPOLOGY.num_threads() {
+        return Err(KError::InvalidCore { core: core as u64 });
+    }
+    // Without interrupt remapping a message can only address xAPIC ids
+    let apic_id: u32 = topology::MACHINE_TOPOLOGY.threads[core as usize]
+        .apic_id()
+        .into();
+    if apic_id > 0xff {
+        return Err(KError::InvalidCore { core: core as u64 });
+    }
+    Ok(MSI_ADDRESS_BASE | (apic_id as u64) << 12)
+}
+
+/// Frees the MSI vectors of a process that exited.
+pub fn release_msi(pid: Pid) {
+    for route in MSI_ROUTES.lock().iter_mut() {
+        if route.map_or(false, |r| r.pid == pid) {
+            *route = None;
+        }
+    }
+}
+
+/// Frees the MSI vectors of PCI `function` (e.g., after it was removed).
+pub fn release_function_msi(function: u64) {
+    for route in MSI_ROUTES.lock().iter_mut() {
+        if route.map_or(false, |r| r.function == function) {
+            *route = None;
+        }
+    }
+}
+
+/// Is `vector` an MSI vector nobody allocated (anymore)?
+pub fn is_unrouted_msi(vector: u64) -> bool {
+    match vector.checked_sub(MSI_VECTOR_BASE) {
+        Some(idx) if (idx as usize) < MAX_MSI_VECTORS => MSI_ROUTES.lock()[idx as usize].is_none(),
+        _ => false,
+    }
+}
+
+fn lookup(routes: &[PciIrqRoute], bus: u64, device: u64, pin: u64) -> Option<u64> {
+    routes
+        .iter()
+        .find(|r| r.bus == bus && r.device == device && r.pin == pin)
+        .map(|r| r.gsi)
+}
+
+fn program(gsi: u64, core: topology::GlobalThreadId) -> Result<(), KError> {
+    super::irq::ioapic_establish_route(gsi, core as u64)
+}
+
+/// Routes the interrupt line `gsi` to `core` on behalf of process `pid`.
+///
+/// A line can only be allocated by a single process.
+pub fn allocate(pid: Pid, gsi: u64, core: topology::GlobalThreadId) -> Result<(), KError> {
+    if gsi as usize >= MAX_GSIS {
+        return Err(KError::InvalidIrq { gsi });
+    }
+
+    let mut routes = ROUTES.lock();
+    match routes[gsi as usize] {
+        Some(route) if route.pid != pid => Err(KError::InvalidIrq { gsi }),
+        _ => {
+            program(gsi, core)?;
+            routes[gsi as usize] = Some(Route { pid, core });
+            Ok(())
+        }
+    }
+}
+
+/// Moves an interrupt line that `pid` allocated to a different core.
+pub fn steer(pid: Pid, gsi: u64, core: topology::GlobalThreadId) -> Result<(), KError> {
+    if gsi as usize >= MAX_GSIS {
+        return Err(KError::InvalidIrq { gsi });
+    }
+
+    let mut routes = ROUTES.lock();
+    match routes[gsi as usize].as_mut() {
+        Some(route) if route.pid == pid => {
+            if route.core != core {
+                trace!("Steer GSI#{} from core {} to {}", gsi, route.core, core);
+                program(gsi, core)?;
+                route.core = core;
+            }
+            Ok(())
+        }
+        _ => Err(KError::InvalidIrq { gsi }),
+    }
+}
+
+/// Reprograms the IOAPIC with all established routes (e.g., after the
+/// IOAPIC lost its state during suspend-to-RAM).
+pub fn restore() {
+    let routes = ROUTES.lock();
+    for (gsi, route) in routes.iter().enumerate() {
+        if let Some(route) = route {
+            if let Err(e) = super::irq::ioapic_establish_route(gsi as u64, route.core as u64) {
+                error!("Can't restore the route of GSI#{}: {}", gsi, e);
+            }
+        }
+    }
+}
+
+/// Decides which interrupt line to move to balance the interrupt load
+/// between `cores`.
+///
+/// Returns the GSI and its new core. We pick the busiest interrupt of the
+/// busiest core that still makes the load more even when moved to the
+/// least busy core.
+pub fn plan(
+    loads: &[IrqLoad],
+    cores: &[topology::GlobalThreadId],
+) -> Option<(u64, topology::GlobalThreadId)> {
+    let core_load = |core: topology::GlobalThreadId| -> u64 {
+        loads
+            .iter()
+            .filter(|l| l.core == core)
+            .map(|l| l.count)
+            .sum()
+    };
+
+    let busiest = *cores.iter().max_by_key(|c| core_load(**c))?;
+    let idlest = *cores.iter().min_by_key(|c| core_load(**c))?;
+    let imbalance = core_load(busiest) - core_load(idlest);
+    if imbalance < REBALANCE_THRESHOLD {
+        return None;
+    }
+
+    loads
+        .iter()
+        .filter(|l| l.core == busiest && l.count > 0 && l.count < imbalance)
+        .max_by_key(|l| l.count)
+        .map(|l| (l.gsi, idlest))
+}
+
+/// Runs the balancing policy for all allocated interrupt lines.
+///
+/// Interrupts are only balanced between the cores that run executors of
+/// the process that allocated them (we currently deliver interrupts only
+/// to the process that runs on the core). At most one line per process
+/// is moved every time this is called.
+pub fn rebalance() {
+    let routes: Vec<(u64, Route)> = ROUTES
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(gsi, route)| route.map(|r| (gsi as u64, r)))
+        .collect();
+    let loads: Vec<(Pid, IrqLoad)> = routes
+        .iter()
+        .map(|(gsi, route)| {
+            let count = COUNTERS[*gsi as usize].swap(0, Ordering::Relaxed);
+            (
+                route.pid,
+                IrqLoad {
+                    gsi: *gsi,
+                    core: route.core,
+                    count,
+                },
+            )
+        })
+        .collect();
+
+    let mut pids: Vec<Pid> = loads.iter().map(|(pid, _l)| *pid).collect();
+    pids.sort_unstable();
+    pids.dedup();
+
+    let kcb = super::kcb::get_kcb();
+    let (replica, token) = match kcb.replica.as_ref() {
+        Some((replica, token)) => (replica, *token),
+        None => return,
+    };
+    for pid in pids {
+        let cores = match replica.execute(crate::nr::ReadOps::ProcessCores(pid), token) {
+            Ok(crate::nr::NodeResult::Cores(_policy, cores)) => cores,
+            _ => continue,
+        };
+
+        let process_loads: Vec<IrqLoad> = loads
+            .iter()
+            .filter(|(lpid, _l)| *lpid == pid)
+            .map(|(_pid, l)| *l)
+            .collect();
+        if let Some((gsi, core)) = plan(&process_loads, &cores) {
+            let _r = steer(pid, gsi, core);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn load(gsi: u64, core: topology::GlobalThreadId, count: u64) -> IrqLoad {
+        IrqLoad { gsi, core, count }
+    }
+
+    #[test]
+    fn plan_moves_from_busiest_to_idlest() {
+        let loads = vec![
+            load(4, 0, 3000),
+            load(10, 0, 2000),
+            load(11, 1, 500),
+            load(12, 2, 1000),
+        ];
+        // core 0: 5000, core 1: 500, core 2: 1000
+        assert_eq!(plan(&loads, &[0, 1, 2]), Some((4, 1)));
+
+        // Moving the 3000 line would just shift the imbalance:
+        let loads = vec![load(4, 0, 3000), load(11, 1, 0)];
+        assert_eq!(plan(&loads, &[0, 1]), None);
+    }
+
+    #[test]
+    fn plan_respects_threshold() {
+        let loads = vec![load(4, 0, 600), load(5, 0, 300), load(6, 1, 100)];
+        assert_eq!(plan(&loads, &[0, 1]), None);
+        assert_eq!(plan(&loads, &[]), None);
+
+        // A core without interrupts is the least busy one:
+        let loads = vec![load(4, 0, 1500), load(5, 0, 800)];
+        assert_eq!(plan(&loads, &[0, 3]), Some((4, 3)));
+    }
+
+    #[test]
+    fn pci_lookup() {
+        let route = |bus, device, pin, gsi| PciIrqRoute {
+            bus,
+            device,
+            pin,
+            gsi,
+        };
+        let routes = vec![route(0, 1, 0, 16), route(0, 1, 1, 17), route(1, 1, 0, 20)];
+        assert_eq!(lookup(&routes, 0, 1, 1), Some(17));
+        assert_eq!(lookup(&routes, 1, 1, 0), Some(20));
+        assert_eq!(lookup(&routes, 0, 2, 0), None);
+        assert_eq!(lookup(&[], 0, 1, 0), None);
+    }
+
+    #[test]
+    fn msi_ranges() {
+        let mut routes = [None; MAX_MSI_VECTORS];
+        assert_eq!(free_msi_range(&routes, 0), None);
+        assert_eq!(free_msi_range(&routes, 1), Some(0));
+        assert_eq!(free_msi_range(&routes, MAX_MSI_VECTORS), Some(0));
+        assert_eq!(free_msi_range(&routes, MAX_MSI_VECTORS + 1), None);
+
+        let taken = Some(MsiRoute {
+            pid: 1,
+            function: 0x18,
+            core: 0,
+        });
+        routes[0] = taken;
+        assert_eq!(free_msi_range(&routes, 1), Some(1));
+        // Multiple messages are aligned to their (rounded up) count
+        assert_eq!(free_msi_range(&routes, 3), Some(4));
+        assert_eq!(free_msi_range(&routes, 8), Some(8));
+        routes[33] = taken;
+        assert_eq!(free_msi_range(&routes, 32), None);
+        assert_eq!(free_msi_range(&routes, 16), Some(16));
+
+        assert_eq!(msi_vectors_of(&routes, 1), 2);
+        assert_eq!(msi_vectors_of(&routes, 2), 0);
+    }
+
+    #[test]
+    fn record_ignores_other_vectors() {
+        let before = COUNTERS[1].load(Ordering::Relaxed);
+        record(IOAPIC_VECTOR_BASE + 1);
+        record(3);
+        record(IOAPIC_VECTOR_BASE + MAX_GSIS as u64);
+        assert_eq!(COUNTERS[1].load(Ordering::Relaxed), before + 1);
+    }
+}
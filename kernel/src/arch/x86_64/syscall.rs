@@ -1,44 +1,56 @@
 #![allow(warnings)]
 
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::bits64::rflags;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 //use x86::tlb;
 
-use kpi::process::FrameId;
+use kpi::io::{FileInfo, SeekWhence};
+use kpi::process::{FrameId, MemoryRights, SchedulingPolicy, SPAWN_POLICY_SHIFT};
 use kpi::{
-    FileOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation, VSpaceOperation,
+    AsyncOperation, AsyncRequest, DeviceOperation, DeviceRequest, FileOperation, FileRequest,
+    ProcessOperation, ProcessRequest, SystemCall, SystemCallError, SystemOperation, SystemRequest,
+    UsageKind, VSpaceOperation, VSpaceRequest,
 };
 
 use crate::error::KError;
-use crate::fs::FileSystem;
-use crate::memory::vspace::MapAction;
-use crate::memory::{Frame, PhysicalPageProvider, KERNEL_BASE};
+use crate::fs::{pipe, FileSystem, FileSystemError};
+use crate::memory::vspace::{Access, AddressSpaceError, MapAction};
+use crate::memory::{AllocatorStatistics, Frame, PhysicalPageProvider};
 use crate::mlnr;
 use crate::nr;
-use crate::process::{Pid, ProcessError, ResumeHandle};
+use crate::process::{Credentials, Pid, ProcessError, ResumeHandle, INIT_PID};
 
 use super::gdt::GdtTable;
-use super::process::{Ring3Process, UserValue};
+use super::memory::KERNEL_BASE;
+use super::process::{Ring3Executor, Ring3Process, UserPtr, UserSlice, UserValue};
 
 extern "C" {
     #[no_mangle]
     fn syscall_enter();
 }
 
-fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
-    let op = SystemOperation::from(arg1);
-
-    match op {
-        SystemOperation::GetHardwareThreads => {
-            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
-            let vaddr_buf_len = arg3; // buf.len() as u64
+/// Fails with `NotPermitted` unless `pid` is the initial process.
+fn require_init_process(pid: Pid) -> Result<(), KError> {
+    if pid != INIT_PID {
+        return Err(KError::NotPermitted);
+    }
+    Ok(())
+}
 
+fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+    match SystemRequest::decode(arg1, [arg2, arg3, 0, 0]) {
+        SystemRequest::GetHardwareThreads {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
             let hwthreads = topology::MACHINE_TOPOLOGY.threads();
             let mut return_threads = Vec::with_capacity(topology::MACHINE_TOPOLOGY.num_threads());
             for hwthread in hwthreads {
@@ -53,28 +65,219 @@ fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             let serialized = serde_cbor::to_vec(&return_threads).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
+                let pid = super::kcb::get_kcb().current_pid()?;
+                let mut user_slice = user_slice(pid, vaddr_buf, serialized.len(), Access::Write)?;
                 user_slice.copy_from_slice(serialized.as_slice());
             }
 
             Ok((serialized.len() as u64, 0))
         }
-        SystemOperation::Stats => {
+        SystemRequest::Stats {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
+            // `vaddr_buf` is 0 if the caller only wants the stats printed
             let kcb = super::kcb::get_kcb();
             info!("IRQ handler time: {} cycles", kcb.tlb_time);
-            Ok((0, 0))
+            crate::nrtrace::dump();
+            if vaddr_buf == 0 {
+                return Ok((0, 0));
+            }
+
+            let irq_latency = super::irq::IPI_VECTORS
+                .iter()
+                .zip(kcb.arch.ipi_latency.iter())
+                .map(|(vector, histogram)| kpi::system::IrqLatency {
+                    vector: *vector as u64,
+                    cycles: histogram.into(),
+                })
+                .collect();
+            let syscall_latency = kcb
+                .arch
+                .syscall_latency
+                .iter()
+                .enumerate()
+                .map(|(idx, histogram)| kpi::system::SyscallLatency {
+                    syscall: idx as u64 + 1,
+                    cycles: histogram.into(),
+                })
+                .collect();
+            let stats = kpi::system::CoreStats {
+                id: kcb.arch.id(),
+                tlb_time: kcb.tlb_time,
+                tlb_handler: (&kcb.tlb_handler).into(),
+                irq_latency,
+                syscall_latency,
+                power: super::telemetry::sample(),
+            };
+
+            let serialized = serde_cbor::to_vec(&stats).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = user_slice(
+                    kcb.current_pid()?,
+                    vaddr_buf,
+                    serialized.len(),
+                    Access::Write,
+                )?;
+                user_slice.copy_from_slice(serialized.as_slice());
+            }
+
+            Ok((serialized.len() as u64, 0))
         }
-        SystemOperation::GetCoreID => {
+        SystemRequest::GetCoreID {} => {
             let kcb = super::kcb::get_kcb();
             Ok((kcb.arch.id() as u64, 0))
         }
-        SystemOperation::Unknown => Err(KError::InvalidSystemOperation { a: arg1 }),
+        SystemRequest::Suspend {} => {
+            // Only the initial process is allowed to put the machine to sleep
+            require_init_process(super::kcb::get_kcb().current_pid()?)?;
+            super::power::suspend()?;
+            Ok((0, 0))
+        }
+        SystemRequest::KexecModule { name, len } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            require_init_process(pid)?;
+            let name = boot_module(pid, name, len)?;
+
+            super::kexec::boot_module(name)?;
+            Ok((0, 0))
+        }
+        SystemRequest::KexecImage { image, len } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            require_init_process(pid)?;
+            let buffer = user_slice(pid, image, len as usize, Access::Read)?;
+
+            super::kexec::boot_image(buffer.buffer)?;
+            Ok((0, 0))
+        }
+        SystemRequest::MemoryStats {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
+            let kcb = super::kcb::get_kcb();
+
+            let gmanager = kcb
+                .physical_memory
+                .gmanager
+                .ok_or(KError::GlobalMemoryNotSet)?;
+            // Every replica drops the same pages (of its copy of the files)
+            let evicted_pages = nr::KernelNode::<Ring3Process>::evicted_pages()?;
+            let stats: Vec<kpi::system::NodeMemoryStats> = gmanager
+                .node_caches
+                .iter()
+                .enumerate()
+                .map(|(node, ncache)| {
+                    let ncache = ncache.lock();
+                    kpi::system::NodeMemoryStats {
+                        node,
+                        free: ncache.free() as u64,
+                        free_base_pages: ncache.free_base_pages() as u64,
+                        free_large_pages: ncache.free_large_pages() as u64,
+                        external_fragmentation: ncache.external_fragmentation() as u64,
+                        evicted_pages: evicted_pages as u64,
+                    }
+                })
+                .collect();
+
+            let serialized = serde_cbor::to_vec(&stats).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = user_slice(
+                    kcb.current_pid()?,
+                    vaddr_buf,
+                    serialized.len(),
+                    Access::Write,
+                )?;
+                user_slice.copy_from_slice(serialized.as_slice());
+            }
+
+            Ok((serialized.len() as u64, 0))
+        }
+        SystemRequest::ReadKernelLog {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            require_init_process(pid)?;
+
+            let serialized = serde_cbor::to_vec(&super::kmsg::records()).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = user_slice(pid, vaddr_buf, serialized.len(), Access::Write)?;
+                user_slice.copy_from_slice(serialized.as_slice());
+            }
+
+            Ok((serialized.len() as u64, 0))
+        }
+        SystemRequest::GetRandom {
+            buf: vaddr_buf,
+            len,
+        } => {
+            let len = core::cmp::min(len as usize, super::rng::MAX_REQUEST);
+            let pid = super::kcb::get_kcb().current_pid()?;
+
+            let mut user_slice = user_slice(pid, vaddr_buf, len, Access::Write)?;
+            super::rng::fill(&mut user_slice[..]);
+            Ok((len as u64, 0))
+        }
+        SystemRequest::OpenEventRing { base, topics } => {
+            // Like the kernel log, the events are only for the initial process
+            let pid = super::kcb::get_kcb().current_pid()?;
+            require_init_process(pid)?;
+            super::eventring::open(pid, base, topics)
+        }
+        SystemRequest::ArmEventRing { seen } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            super::eventring::arm(pid, seen)
+        }
+        // Usually handled by `fast_path`, but these can also be submitted
+        // asynchronously:
+        SystemRequest::Null {} => Ok((0, 0)),
+        SystemRequest::GetThreadId {} => thread_id(),
+        SystemRequest::PciInterruptLine { location, pin } => {
+            let (bus, device) = (location >> 8, location & 0xff);
+            let gsi = super::steering::pci_gsi(bus, device, pin)
+                .ok_or(KError::NoPciRoute { device: location })?;
+            Ok((gsi, 0))
+        }
+        SystemRequest::CacheAllocation { clos, mask } => {
+            require_init_process(super::kcb::get_kcb().current_pid()?)?;
+            let (classes, ways) = super::cat::support().ok_or(KError::NotSupported)?;
+            if mask != 0 {
+                super::cat::set_mask(clos, mask)?;
+            }
+            Ok((classes, ways))
+        }
+        SystemRequest::CacheClass { pid, clos } => {
+            require_init_process(super::kcb::get_kcb().current_pid()?)?;
+            // Make sure the process exists
+            nr::KernelNode::<Ring3Process>::pinfo(pid)?;
+            super::cat::set_process_class(pid, clos)?;
+            Ok((0, 0))
+        }
+        SystemRequest::CoreCacheClass { gtid, clos } => {
+            require_init_process(super::kcb::get_kcb().current_pid()?)?;
+            super::cat::set_core_class(gtid as topology::GlobalThreadId, clos)?;
+            Ok((0, 0))
+        }
+        SystemRequest::Unknown(op) => Err(KError::InvalidSystemOperation { a: op }),
+    }
+}
+
+/// Prints a line for process `pid` (if it's within its output limit).
+fn print_line(pid: Option<Pid>, line: String) {
+    let line = match pid {
+        Some(pid) => super::printlimit::limit_line(pid, line),
+        None => Some(line),
+    };
+
+    if let Some(line) = line {
+        super::printq::print(line);
     }
 }
 
 /// System call handler for printing
 fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
     let mut kcb = super::kcb::get_kcb();
+    let pid = kcb.current_pid().ok();
     let buffer: &str = *buf;
 
     // A poor mans line buffer scheme:
@@ -82,11 +285,10 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
         Some(kbuf) => match buffer.find("\n") {
             Some(idx) => {
                 let (low, high) = buffer.split_at(idx + 1);
-                kbuf.push_str(low);
-                {
-                    let r = klogger::SERIAL_LINE_MUTEX.lock();
-                    sprint!("{}", kbuf);
-                }
+                let mut line = String::with_capacity(kbuf.len() + low.len());
+                line.push_str(kbuf);
+                line.push_str(low);
+                print_line(pid, line);
                 kbuf.clear();
                 kbuf.push_str(high);
             }
@@ -94,25 +296,97 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
                 kbuf.push_str(buffer);
                 if kbuf.len() > 2048 {
                     // Don't let the buffer grow arbitrarily:
-                    {
-                        let r = klogger::SERIAL_LINE_MUTEX.lock();
-                        sprint!("{}", kbuf);
-                    }
+                    print_line(pid, kbuf.clone());
                     kbuf.clear();
                 }
             }
         },
-        None => {
-            let r = klogger::SERIAL_LINE_MUTEX.lock();
-            sprint!("{}", buffer);
-        }
+        None => print_line(pid, String::from(buffer)),
     }
 
     Ok((0, 0))
 }
 
-/// System call handler for process exit
-fn process_exit(code: u64) -> Result<(u64, u64), KError> {
+/// Returns the name of the boot module the process `pid` refers to with the
+/// string at `base` (the name of a module lives forever).
+fn boot_module(pid: Pid, base: u64, len: u64) -> Result<&'static str, KError> {
+    let buffer = user_slice(pid, base, len as usize, Access::Read)?;
+    let name = core::str::from_utf8(buffer.buffer).map_err(|_e| KError::NotSupported)?;
+
+    let kcb = super::kcb::get_kcb();
+    let binary = kcb
+        .arch
+        .kernel_args()
+        .modules
+        .iter()
+        .map(|module| module.name())
+        .find(|module| *module == name)
+        .ok_or_else(|| ProcessError::BinaryNotFound {
+            binary: name.to_string(),
+        })?;
+    Ok(binary)
+}
+
+/// Copies the arguments for a process started with `ProcessOperation::Spawn`
+/// from user-space. The process finds them in `ProcessInfo::app_cmdline`, in
+/// single quotes like `appcmd=` on the kernel command-line.
+fn spawn_args(pid: Pid, base: u64, len: u64) -> Result<String, KError> {
+    if len == 0 {
+        return Ok(String::new());
+    }
+    let buffer = user_slice(pid, base, len as usize, Access::Read)?;
+    let args = core::str::from_utf8(buffer.buffer).map_err(|_e| KError::NotSupported)?;
+    // The quotes delimit the arguments
+    if args.contains('\'') {
+        return Err(KError::NotSupported);
+    }
+
+    Ok(alloc::format!("'{}'", args))
+}
+
+/// Returns the id of the current executor.
+fn thread_id() -> Result<(u64, u64), KError> {
+    let p = super::kcb::get_kcb().arch.current_process()?;
+    Ok((p.eid, 0))
+}
+
+/// Handles the system calls that exist to measure the system call
+/// entry/exit cost (`SystemOperation::Null` and `GetThreadId`).
+///
+/// Returns None for all other system calls.
+#[inline(always)]
+fn fast_path(function: u64, arg1: u64) -> Option<(u64, u64)> {
+    if function != SystemCall::System as u64 {
+        return None;
+    }
+    match SystemOperation::from(arg1) {
+        SystemOperation::Null => Some((0, 0)),
+        SystemOperation::GetThreadId => thread_id().ok(),
+        _ => None,
+    }
+}
+
+/// System call handler for process exit (also terminates processes that
+/// can't go on after a fault, see `irq::pf_handler`).
+///
+/// Only returns if it fails.
+pub(super) fn process_exit(code: u64) -> Result<(u64, u64), KError> {
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.current_pid()?;
+    if pid != INIT_PID {
+        // Processes started with `ProcessOperation::Spawn` just go away,
+        // their parent can pick up the exit code:
+        debug!("Process {} exited with {}", pid, code);
+        // Its page-tables are about to be given back, get off them
+        unsafe {
+            let pml4 = kcb.arch.init_vspace().pml4_address();
+            x86::controlregs::cr3_write(pml4.into());
+        }
+        let released = nr::KernelNode::<Ring3Process>::exit(pid, code)?;
+        super::process::release_exited(&[pid], &released)?;
+        unsafe { super::irq::leave_exited_executor(kcb) }
+    }
+
     debug!("Process got exit, we are done for now...");
     // TODO: For now just a dummy version that exits Qemu
     if code != 0 {
@@ -124,60 +398,77 @@ fn process_exit(code: u64) -> Result<(u64, u64), KError> {
     }
 }
 
-fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
-    let op = ProcessOperation::from(arg1);
-
-    match op {
-        ProcessOperation::Log => {
-            let buffer: *const u8 = arg2 as *const u8;
-            let len: usize = arg3 as usize;
+fn handle_process(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
+    match ProcessRequest::decode(arg1, [arg2, arg3, arg4, arg5]) {
+        ProcessRequest::Log { buf, len } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let buffer = user_slice(pid, buf, len as usize, Access::Read)?;
 
-            let user_str = unsafe {
-                let slice = core::slice::from_raw_parts(buffer, len);
-                core::str::from_utf8_unchecked(slice)
-            };
+            let user_str = unsafe { core::str::from_utf8_unchecked(buffer.buffer) };
 
             process_print(UserValue::new(user_str))
         }
-        ProcessOperation::GetVCpuArea => unsafe {
+        ProcessRequest::GetVCpuArea {} => unsafe {
             let kcb = super::kcb::get_kcb();
 
             let vcpu_vaddr = kcb.arch.current_process()?.vcpu_addr().as_u64();
 
             Ok((vcpu_vaddr, 0))
         },
-        ProcessOperation::AllocateVector => {
-            // TODO: missing proper IRQ resource allocation...
-            let vector = arg2;
-            let core = arg3;
-            super::irq::ioapic_establish_route(vector, core);
+        ProcessRequest::AllocateVector { vector, core } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            super::steering::allocate(pid, vector, core as topology::GlobalThreadId)?;
+            Ok((vector, core))
+        }
+        ProcessRequest::SteerVector { vector, core } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            super::steering::steer(pid, vector, core as topology::GlobalThreadId)?;
             Ok((vector, core))
         }
-        ProcessOperation::Exit => {
-            let exit_code = arg2;
-            process_exit(exit_code)
+        ProcessRequest::AllocateMsiVectors {
+            function,
+            core,
+            count,
+        } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            // The function is also the handle of the device
+            super::pci::check_owner(pid, function)?;
+            super::steering::allocate_msi(pid, function, core as topology::GlobalThreadId, count)
         }
-        ProcessOperation::GetProcessInfo => {
-            let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
-            let vaddr_buf_len = arg3; // buf.len() as u64
+        ProcessRequest::Exit { code } => process_exit(code),
+        ProcessRequest::GetProcessInfo {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
             let kcb = super::kcb::get_kcb();
 
             let pid = kcb.current_pid()?;
             let mut pinfo = nr::KernelNode::<Ring3Process>::pinfo(pid)?;
             pinfo.cmdline = kcb.cmdline.test_cmdline;
-            pinfo.app_cmdline = kcb.cmdline.app_cmdline;
+            // Spawned processes can have their own arguments
+            let args = nr::KernelNode::<Ring3Process>::args(pid)?;
+            pinfo.app_cmdline = match args.as_ref() {
+                // Safety: `pinfo` is only serialized, it doesn't outlive `args`
+                Some(args) => unsafe { &*(args.as_ref() as *const str) },
+                None => kcb.cmdline.app_cmdline,
+            };
+            pinfo.malloc_conf = kcb.cmdline.malloc_conf;
 
             let serialized = serde_cbor::to_vec(&pinfo).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
+                let mut user_slice = user_slice(pid, vaddr_buf, serialized.len(), Access::Write)?;
                 user_slice.copy_from_slice(serialized.as_slice());
             }
 
             Ok((serialized.len() as u64, 0))
         }
-        ProcessOperation::RequestCore => {
-            let gtid = arg2;
-            let entry_point = arg3;
+        ProcessRequest::RequestCore { gtid, entry_point } => {
             let kcb = super::kcb::get_kcb();
 
             let mut affinity = None;
@@ -197,28 +488,33 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             Ok((gtid, eid))
         }
-        ProcessOperation::AllocatePhysical => {
-            let page_size: usize = arg2.try_into().unwrap_or(0);
-            //let affinity: usize = arg3.try_into().unwrap_or(0);
+        ProcessRequest::AllocatePhysical { page_size: size } => {
+            let page_size: usize = size.try_into().unwrap_or(0);
 
             // Validate input
-            if page_size != BASE_PAGE_SIZE && page_size != LARGE_PAGE_SIZE {
-                return Err(KError::InvalidSyscallArgument1 { a: arg2 });
+            if page_size != BASE_PAGE_SIZE
+                && page_size != LARGE_PAGE_SIZE
+                && page_size != HUGE_PAGE_SIZE
+            {
+                return Err(KError::InvalidSyscallArgument1 { a: size });
             }
 
             let kcb = super::kcb::get_kcb();
 
-            // Figure out what memory to allocate
-            let (bp, lp) = if page_size == BASE_PAGE_SIZE {
-                (1, 0)
+            let frame = if page_size == HUGE_PAGE_SIZE {
+                // Huge-pages don't go through the TCache
+                crate::memory::KernelAllocator::allocate_huge_page()?
             } else {
-                (0, 1)
-            };
-            crate::memory::KernelAllocator::try_refill_tcache(bp, lp)?;
+                // Figure out what memory to allocate
+                let (bp, lp) = if page_size == BASE_PAGE_SIZE {
+                    (1, 0)
+                } else {
+                    (0, 1)
+                };
+                crate::memory::KernelAllocator::try_refill_tcache(bp, lp)?;
 
-            // Allocate the page (need to make sure we drop pamanager again
-            // before we go to NR):
-            let frame = {
+                // Allocate the page (need to make sure we drop pamanager again
+                // before we go to NR):
                 let mut pmanager = kcb.mem_manager();
                 if page_size == BASE_PAGE_SIZE {
                     pmanager.allocate_base_page()?
@@ -233,76 +529,399 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             Ok((fid as u64, frame.base.as_u64()))
         }
-        ProcessOperation::SubscribeEvent => Err(KError::InvalidProcessOperation { a: arg1 }),
-        ProcessOperation::Unknown => Err(KError::InvalidProcessOperation { a: arg1 }),
+        ProcessRequest::AllocateDmaRegion { size, alignment } => {
+            let page_size = dma_page_size(size, alignment)?;
+
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+            let mut frame = if page_size == HUGE_PAGE_SIZE {
+                crate::memory::KernelAllocator::allocate_huge_page()?
+            } else {
+                let (bp, lp) = if page_size == BASE_PAGE_SIZE {
+                    (1, 0)
+                } else {
+                    (0, 1)
+                };
+                crate::memory::KernelAllocator::try_refill_tcache(bp, lp)?;
+
+                let mut pmanager = kcb.mem_manager();
+                if page_size == BASE_PAGE_SIZE {
+                    pmanager.allocate_base_page()?
+                } else {
+                    pmanager.allocate_large_page()?
+                }
+            };
+            unsafe { frame.zero() };
+
+            // The frame belongs to the process (it's given back when the
+            // process exits), we map it at a fixed offset from its physical
+            // address so regions never overlap
+            let fid = match nr::KernelNode::<Ring3Process>::allocate_frame_to_process(pid, frame) {
+                Ok(fid) => fid,
+                Err(e) => {
+                    let _r = crate::memory::KernelAllocator::release_frame(frame);
+                    return Err(e);
+                }
+            };
+            let base = VAddr::from(DMA_REGION_OFFSET + frame.base.as_u64());
+            match nr::KernelNode::<Ring3Process>::map_frame_id(
+                pid,
+                fid,
+                base,
+                MapAction::ReadWriteUser,
+            ) {
+                Ok((paddr, _size)) => Ok((base.as_u64(), paddr.as_u64())),
+                Err(e) => {
+                    // Don't charge the process for a region it can't use
+                    let _r = nr::KernelNode::<Ring3Process>::release_frame_from_process(
+                        pid, fid, frame.size,
+                    );
+                    Err(e)
+                }
+            }
+        }
+        ProcessRequest::ReleasePhysical {
+            frame_id: id,
+            page_size: size,
+        } => {
+            let frame_id: FrameId = id
+                .try_into()
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: id })?;
+            let page_size: usize = size.try_into().unwrap_or(0);
+            if page_size != BASE_PAGE_SIZE
+                && page_size != LARGE_PAGE_SIZE
+                && page_size != HUGE_PAGE_SIZE
+            {
+                return Err(KError::InvalidSyscallArgument1 { a: size });
+            }
+
+            let pid = super::kcb::get_kcb().current_pid()?;
+            nr::KernelNode::<Ring3Process>::release_frame_from_process(pid, frame_id, page_size)?;
+
+            Ok((0, 0))
+        }
+        ProcessRequest::GetUsage { kind } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            match UsageKind::from(kind) {
+                UsageKind::Cpu => {
+                    let usage = crate::process::cpu_usage(pid);
+                    Ok((usage.cpu_cycles, usage.dispatches))
+                }
+                UsageKind::FileSystem => {
+                    let (bytes, quota) = if cfg!(feature = "mlnrfs") {
+                        mlnr::MlnrKernelNode::fs_usage(pid)?
+                    } else {
+                        nr::KernelNode::<Ring3Process>::fs_usage(pid)?
+                    };
+                    // No quota is reported as u64::max_value()
+                    Ok((
+                        bytes as u64,
+                        quota.map_or(u64::max_value(), |quota| quota as u64),
+                    ))
+                }
+                UsageKind::Cache => {
+                    let usage = super::mbm::usage(pid)?;
+                    Ok((usage.llc_occupancy, usage.memory_traffic))
+                }
+                UsageKind::Unknown => Err(KError::InvalidSyscallArgument1 { a: kind }),
+            }
+        }
+        ProcessRequest::ReadConsole { buf, len } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let len = len as usize;
+
+            let mut input: Vec<u8> = Vec::with_capacity(len);
+            while input.len() < len {
+                match unsafe { super::debug::try_getb() } {
+                    Some(b) => input.push(b),
+                    None => break,
+                }
+            }
+
+            if !input.is_empty() {
+                let mut user_slice = user_slice(pid, buf, input.len(), Access::Write)?;
+                user_slice.copy_from_slice(input.as_slice());
+            }
+            Ok((input.len() as u64, 0))
+        }
+        ProcessRequest::Spawn {
+            binary,
+            binary_len,
+            args,
+            args_len,
+        } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let binary = boot_module(pid, binary, binary_len)?;
+            // The policy is in the upper bits of the length of the arguments
+            let policy = SchedulingPolicy::try_from(args_len >> SPAWN_POLICY_SHIFT)
+                .map_err(|_e| KError::InvalidSyscallArgument1 { a: args_len })?;
+            let args_len = args_len & ((1 << SPAWN_POLICY_SHIFT) - 1);
+            let args = spawn_args(pid, args, args_len)?;
+            let new_pid = super::process::spawn(binary, &args, Some(pid), policy)?;
+            info!("Process {} spawned {} (pid {})", pid, binary, new_pid);
+            Ok((new_pid, 0))
+        }
+        ProcessRequest::Wait { pid: child } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            match nr::KernelNode::<Ring3Process>::exit_status(pid, child)? {
+                Some(code) => Ok((1, code)),
+                None => Ok((0, 0)),
+            }
+        }
+        ProcessRequest::WaitPid { pid: child } => {
+            let kcb = super::kcb::get_kcb();
+            let (pid, eid) = kcb.arch.current_process().map(|p| (p.pid, p.eid))?;
+            match nr::KernelNode::<Ring3Process>::wait_child(pid, eid, child)? {
+                Some(code) => Ok((code, 0)),
+                None => unsafe { super::irq::block_current_executor(kcb) },
+            }
+        }
+        ProcessRequest::FutexWait { addr, expected } => {
+            let kcb = super::kcb::get_kcb();
+            let (pid, eid) = kcb.arch.current_process().map(|p| (p.pid, p.eid))?;
+            // Read the sequence number first: if someone changes the word
+            // and wakes us up after we looked at it, we don't wait
+            let seq = nr::KernelNode::<Ring3Process>::futex_sequence(pid)?;
+            if futex_word(pid, addr)? != expected {
+                return Ok((0, 0));
+            }
+            if nr::KernelNode::<Ring3Process>::futex_wait(pid, eid, addr, seq)? {
+                unsafe { super::irq::block_current_executor(kcb) }
+            }
+            Ok((0, 0))
+        }
+        ProcessRequest::FutexWake { addr, count } => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+            let mut cores = nr::KernelNode::<Ring3Process>::futex_wake(pid, addr, count as usize)?;
+            let woken = cores.len();
+
+            // The cores might be idle, let them know they have work again
+            cores.sort_unstable();
+            cores.dedup();
+            let my_gtid = kcb.arch.id() as topology::GlobalThreadId;
+            for gtid in cores.iter().filter(|gtid| **gtid != my_gtid) {
+                super::tlb::wakeup(*gtid);
+            }
+            Ok((woken as u64, 0))
+        }
+        ProcessRequest::Supervise {
+            pid: child,
+            cmdline: base,
+            cmdline_len: len,
+        } => {
+            if cfg!(feature = "mlnrfs") {
+                // The files of a process don't outlive it there
+                return Err(KError::NotSupported);
+            }
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let cmdline = user_slice(pid, base, len as usize, Access::Read)?;
+            // The boot module, then its arguments
+            let binary_len = cmdline
+                .buffer
+                .iter()
+                .position(|c| *c == b' ')
+                .unwrap_or(len as usize) as u64;
+            let binary = boot_module(pid, base, binary_len)?;
+            let args = spawn_args(
+                pid,
+                base + binary_len + 1,
+                len.saturating_sub(binary_len + 1),
+            )?;
+            nr::KernelNode::<Ring3Process>::supervise(pid, child, binary, &args)?;
+            Ok((0, 0))
+        }
+        ProcessRequest::Restart { pid: child } => {
+            let kcb = super::kcb::get_kcb();
+            let (pid, eid) = kcb.arch.current_process().map(|p| (p.pid, p.eid))?;
+            match super::process::restart(pid, eid, child)? {
+                Some((new_pid, code)) => {
+                    info!("Process {} restarted {}: {}", pid, child, new_pid);
+                    Ok((new_pid, code))
+                }
+                None => unsafe { super::irq::block_current_executor(kcb) },
+            }
+        }
+        ProcessRequest::SetGroup { pid: target, group } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let target = if target == 0 { pid } else { target };
+            let group = nr::KernelNode::<Ring3Process>::set_group(pid, target, group)?;
+            Ok((group, 0))
+        }
+        ProcessRequest::SetWeight { weight } => {
+            if weight == 0 || weight > crate::scheduler::fair::MAX_WEIGHT {
+                return Err(KError::InvalidSyscallArgument1 { a: weight });
+            }
+            let pid = super::kcb::get_kcb().current_pid()?;
+            nr::KernelNode::<Ring3Process>::set_weight(pid, weight)?;
+            Ok((0, 0))
+        }
+        ProcessRequest::TerminateGroup { group } => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+            let terminated = super::process::terminate_group(pid, group)?;
+            info!("Process {} terminated {:?}", pid, terminated);
+            if terminated.contains(&pid) {
+                unsafe { super::irq::leave_exited_executor(kcb) }
+            }
+            Ok((terminated.len() as u64, 0))
+        }
+        ProcessRequest::ListProcesses {
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+
+            let mut processes = nr::KernelNode::<Ring3Process>::processes()?;
+            for entry in processes.iter_mut() {
+                entry.printed_bytes = super::printlimit::printed_bytes(entry.pid);
+                entry.dropped_bytes = super::printlimit::dropped_bytes(entry.pid);
+            }
+            let serialized = serde_cbor::to_vec(&processes).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = user_slice(pid, vaddr_buf, serialized.len(), Access::Write)?;
+                user_slice.copy_from_slice(serialized.as_slice());
+            }
+
+            Ok((serialized.len() as u64, 0))
+        }
+        ProcessRequest::SetCredentials { uid, gid } => {
+            let uid =
+                u32::try_from(uid).map_err(|_e| KError::InvalidSyscallArgument1 { a: uid })?;
+            let gid =
+                u32::try_from(gid).map_err(|_e| KError::InvalidSyscallArgument1 { a: gid })?;
+            let credentials = Credentials { uid, gid };
+            let pid = super::kcb::get_kcb().current_pid()?;
+
+            // The file-system checks the credentials kept next to the file
+            // descriptors, so both replicas need to know about them.
+            nr::KernelNode::<Ring3Process>::set_credentials(pid, credentials)?;
+            if cfg!(feature = "mlnrfs") {
+                mlnr::MlnrKernelNode::set_credentials(pid, credentials)?;
+            }
+            Ok((0, 0))
+        }
+        ProcessRequest::Fork {} => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            let child = super::process::fork(pid)?;
+            info!("Process {} forked (pid {})", pid, child);
+            Ok((child, 0))
+        }
+        ProcessRequest::Exec { binary, binary_len } => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+            let binary = boot_module(pid, binary, binary_len)?;
+            super::process::exec(pid, binary)?;
+            info!("Process {} is now {}", pid, binary);
+
+            // The rings were registered in the old address space
+            super::asyncring::unregister(pid);
+            super::eventring::unregister(pid);
+            super::pkeys::unregister(pid);
+            unsafe { super::irq::leave_exited_executor(kcb) }
+        }
+        ProcessRequest::SubscribeEvent { event, value } => match event {
+            // Timer upcalls (e.g., to preempt user-level threads) are for the
+            // executor on this core only
+            kpi::upcall::TIMER => {
+                let period = value;
+                if period != 0 && period < super::timer::MIN_UPCALL_PERIOD {
+                    return Err(KError::InvalidSyscallArgument1 { a: period });
+                }
+
+                let kcb = super::kcb::get_kcb();
+                let now = x86::time::rdtsc();
+                let executor = kcb.arch.current_process()?;
+                // Safety: The executor is the one on this core
+                unsafe { Ring3Executor::subscribe_timer(&executor, period, now) };
+                super::irq::arm_timer(kcb, now);
+                Ok((0, 0))
+            }
+            // Events of the machine go to the whole process (see
+            // `irq::notify_subscribers`)
+            kpi::upcall::CORE_ONLINE
+            | kpi::upcall::LOW_MEMORY
+            | kpi::upcall::DEVICE_REMOVED
+            | kpi::upcall::CORE_FAILED => {
+                let pid = super::kcb::get_kcb().current_pid()?;
+                nr::KernelNode::<Ring3Process>::subscribe(pid, event, value != 0)?;
+                Ok((0, 0))
+            }
+            _ => Err(KError::InvalidSyscallArgument1 { a: event }),
+        },
+        ProcessRequest::AllocateKey { rights: bits } => {
+            let rights = MemoryRights::from_bits(bits)
+                .ok_or(AddressSpaceError::InvalidRights { rights: bits })?;
+            let kcb = super::kcb::get_kcb();
+            let executor = kcb.arch.current_process()?;
+            let key = super::pkeys::allocate(executor.pid)?;
+
+            // The caller gets `rights` to the new key right away
+            let pkru = unsafe { kpi::x86_64::read_pkru() };
+            let pkru = kpi::x86_64::pkru_with_rights(pkru, key, rights);
+            unsafe { kpi::x86_64::write_pkru(pkru) };
+            // Safety: The executor is the one on this core
+            unsafe { Ring3Executor::set_pkru(&executor, pkru) };
+
+            Ok((key as u64, 0))
+        }
+        ProcessRequest::FreeKey { key } => {
+            let pid = super::kcb::get_kcb().current_pid()?;
+            super::pkeys::free(pid, key)?;
+            Ok((0, 0))
+        }
+        ProcessRequest::EnableShadowStack {} => {
+            let executor = super::kcb::get_kcb().arch.current_process()?;
+            let base = super::cet::allocate(executor.pid, &executor)?;
+            Ok((base.as_u64(), super::cet::SHADOW_STACK_SIZE as u64))
+        }
+        ProcessRequest::Unknown(op) => Err(KError::InvalidProcessOperation { a: op }),
     }
 }
 
 /// System call handler for vspace operations
-fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
-    let op = VSpaceOperation::from(arg1);
-    let base = VAddr::from(arg2);
-    let region_size = arg3;
-    trace!("handle_vspace {:?} {:#x} {:#x}", op, base, region_size);
+fn handle_vspace(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    let request = VSpaceRequest::decode(arg1, [arg2, arg3, arg4, 0]);
+    trace!("handle_vspace {:?}", request);
 
     let kcb = super::kcb::get_kcb();
     let mut plock = kcb.arch.current_process();
 
-    match op {
-        VSpaceOperation::Map => unsafe {
-            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                let (bp, lp) = crate::memory::size_to_pages(region_size as usize);
-                let mut frames = Vec::with_capacity(bp + lp);
-                crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp)?;
-
-                // TODO(apihell): This `paddr` is bogus, it will return the PAddr of the
-                // first frame mapped but if you map multiple Frames, no chance getting that
-                // Better would be a function to request physically consecutive DMA memory
-                // or use IO-MMU translation (see also rumpuser_pci_dmalloc)
-                // also better to just return what NR replies with...
-                let mut paddr = None;
-                let mut total_len = 0;
-                {
-                    let mut pmanager = kcb.mem_manager();
-
-                    for _i in 0..lp {
-                        let mut frame = pmanager
-                            .allocate_large_page()
-                            .expect("We refilled so allocation should work.");
-                        total_len += frame.size;
-                        unsafe { frame.zero() };
-                        frames.push(frame);
-                        if paddr.is_none() {
-                            paddr = Some(frame.base);
-                        }
+    match request {
+        VSpaceRequest::Map {
+            base,
+            size: region_size,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            let base = VAddr::from(base);
+            // Mapping (and zeroing) a large region takes a while, we give the
+            // core back in between (user-space maps the rest with another
+            // system call)
+            let mut paddr = None;
+            let mut mapped = 0;
+            while mapped < region_size {
+                let left = region_size - mapped;
+                let chunk = core::cmp::min(left, MAP_CHUNK_SIZE);
+                match with_eviction(chunk, || map_chunk(p.pid, base + mapped, left)) {
+                    Ok((chunk_paddr, len)) => {
+                        paddr.get_or_insert(chunk_paddr);
+                        mapped += len;
                     }
-                    for _i in 0..bp {
-                        let mut frame = pmanager
-                            .allocate_base_page()
-                            .expect("We refilled so allocation should work.");
-                        total_len += frame.size;
-                        unsafe { frame.zero() };
-                        frames.push(frame);
-                        if paddr.is_none() {
-                            paddr = Some(frame.base);
-                        }
+                    Err(e) => {
+                        // Don't leave half of the region behind
+                        unmap_chunks(p.pid, base, mapped);
+                        return Err(e);
                     }
                 }
-
-                nr::KernelNode::<Ring3Process>::map_frames(
-                    p.pid,
-                    base,
-                    frames,
-                    MapAction::ReadWriteUser,
-                )
-                .expect("Can't map memory");
-                Ok((paddr.unwrap().as_u64(), total_len as u64))
-            })
-        },
-        VSpaceOperation::MapDevice => unsafe {
+                if kcb.arch.should_yield() {
+                    break;
+                }
+            }
+            Ok((paddr.unwrap_or(0), mapped))
+        }),
+        VSpaceRequest::MapDevice { base, size } => unsafe {
             plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                let paddr = PAddr::from(base.as_u64());
-                let size = region_size as usize;
+                let paddr = PAddr::from(base);
+                let size = size as usize;
 
                 let frame = Frame::new(paddr, size, kcb.node);
 
@@ -315,11 +934,12 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 })
             })
         },
-        VSpaceOperation::MapFrame => unsafe {
+        VSpaceRequest::MapFrame { base, frame_id } => unsafe {
             plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                let base = VAddr::from(arg2);
-                let frame_id: FrameId =
-                    arg3.try_into().map_err(|_e| ProcessError::InvalidFrameId)?;
+                let base = VAddr::from(base);
+                let frame_id: FrameId = frame_id
+                    .try_into()
+                    .map_err(|_e| ProcessError::InvalidFrameId)?;
 
                 let (paddr, size) = nr::KernelNode::<Ring3Process>::map_frame_id(
                     p.pid,
@@ -330,23 +950,197 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 Ok((paddr.as_u64(), size as u64))
             })
         },
-        VSpaceOperation::Unmap => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let handle = nr::KernelNode::<Ring3Process>::unmap(p.pid, base)?;
-            let va: u64 = handle.vaddr.as_u64();
-            let sz: u64 = handle.frame.size as u64;
-            super::tlb::shootdown(handle);
+        VSpaceRequest::Unmap { base, .. } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                let handle = nr::KernelNode::<Ring3Process>::unmap(p.pid, VAddr::from(base))?;
+                let va: u64 = handle.vaddr.as_u64();
+                let sz: u64 = handle.frame.size as u64;
+                super::tlb::shootdown(handle);
 
-            Ok((va, sz))
-        }),
-        VSpaceOperation::Identify => unsafe {
+                Ok((va, sz))
+            })
+        }
+        VSpaceRequest::Protect { base, size, rights } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                let rights = MemoryRights::from_bits(rights)
+                    .ok_or(AddressSpaceError::InvalidRights { rights })?;
+                let handle = nr::KernelNode::<Ring3Process>::protect(
+                    p.pid,
+                    VAddr::from(base),
+                    size as usize,
+                    rights.into(),
+                )?;
+                super::tlb::shootdown(handle);
+
+                Ok((base, size))
+            })
+        }
+        VSpaceRequest::ProtectKey { base, size, key } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                if !super::pkeys::is_allocated(p.pid, key) {
+                    return Err(KError::InvalidProtectionKey { key });
+                }
+                let handle = nr::KernelNode::<Ring3Process>::set_key(
+                    p.pid,
+                    VAddr::from(base),
+                    size as usize,
+                    key as u16,
+                )?;
+                super::tlb::shootdown(handle);
+
+                Ok((base, size))
+            })
+        }
+        VSpaceRequest::Identify { base } => unsafe {
             trace!("Identify base {:#x}.", base);
             plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                nr::KernelNode::<Ring3Process>::resolve(p.pid, base)
+                nr::KernelNode::<Ring3Process>::resolve(p.pid, VAddr::from(base))
             })
         },
-        VSpaceOperation::Unknown => {
+        VSpaceRequest::Unknown(op) => {
             error!("Got an invalid VSpaceOperation code.");
-            Err(KError::InvalidVSpaceOperation { a: arg1 })
+            Err(KError::InvalidVSpaceOperation { a: op })
+        }
+    }
+}
+
+/// Where `ProcessOperation::AllocateDmaRegion` maps regions (at this offset
+/// from their physical address).
+const DMA_REGION_OFFSET: u64 = 0x1000_0000_0000;
+
+/// The page size of a physically contiguous region of `size` bytes aligned
+/// to `alignment` (a 1 GiB page is the biggest we can do).
+fn dma_page_size(size: u64, alignment: u64) -> Result<usize, KError> {
+    if !alignment.is_power_of_two() || alignment > HUGE_PAGE_SIZE as u64 {
+        return Err(KError::InvalidSyscallArgument1 { a: alignment });
+    }
+    let required = core::cmp::max(size, alignment) as usize;
+    match required {
+        0 => Err(KError::InvalidSyscallArgument1 { a: size }),
+        1..=BASE_PAGE_SIZE => Ok(BASE_PAGE_SIZE),
+        _ if required <= LARGE_PAGE_SIZE => Ok(LARGE_PAGE_SIZE),
+        _ if required <= HUGE_PAGE_SIZE => Ok(HUGE_PAGE_SIZE),
+        _ => Err(KError::InvalidSyscallArgument1 { a: size }),
+    }
+}
+
+/// How much memory the Map system call maps (with large and base-pages) at
+/// most before it checks if it should give the core back.
+const MAP_CHUNK_SIZE: u64 = 16 * LARGE_PAGE_SIZE as u64;
+
+/// Allocates (zeroed) memory for the start of the `len` bytes at `base` and
+/// maps it into process `pid`: a huge-page if `base` is aligned for one and
+/// we get one, otherwise (at most `MAP_CHUNK_SIZE` of) large and base-pages.
+///
+/// Returns the address of the first frame and how much was mapped, nothing
+/// is left behind if it fails.
+fn map_chunk(pid: Pid, base: VAddr, len: u64) -> Result<(u64, u64), KError> {
+    let mut frames = Vec::new();
+    let huge_page = if base % HUGE_PAGE_SIZE == 0 && len >= HUGE_PAGE_SIZE as u64 {
+        crate::memory::KernelAllocator::allocate_huge_page().ok()
+    } else {
+        None
+    };
+    match huge_page {
+        Some(mut frame) => {
+            unsafe { frame.zero() };
+            frames.push(frame);
+        }
+        None => {
+            let (bp, lp) =
+                crate::memory::size_to_pages(core::cmp::min(len, MAP_CHUNK_SIZE) as usize);
+            frames
+                .try_reserve_exact(bp + lp)
+                .map_err(ProcessError::from)?;
+            let allocated = allocate_pages(lp, true, &mut frames)
+                .and_then(|()| allocate_pages(bp, false, &mut frames));
+            if let Err(e) = allocated {
+                release_frames(frames);
+                return Err(e);
+            }
+        }
+    }
+
+    // This `paddr` is only the PAddr of the first frame, memory that has to
+    // be physically consecutive comes from `ProcessOperation::AllocateDmaRegion`
+    let paddr = frames.first().map_or(0, |frame| frame.base.as_u64());
+    let mut offset = 0;
+    for frame in frames.iter() {
+        if let Err(e) = nr::KernelNode::<Ring3Process>::map_frame(
+            pid,
+            base + offset,
+            *frame,
+            MapAction::ReadWriteUser,
+        ) {
+            unmap_chunks(pid, base, offset as u64);
+            release_frames(frames);
+            return Err(e);
+        }
+        offset += frame.size();
+    }
+    Ok((paddr, offset as u64))
+}
+
+/// Allocates `count` zeroed large (or base) pages and appends them to
+/// `frames`.
+///
+/// The TCache only holds so many pages, it gets refilled (with what's still
+/// missing) whenever it runs dry.
+fn allocate_pages(count: usize, large: bool, frames: &mut Vec<Frame>) -> Result<(), KError> {
+    let kcb = super::kcb::get_kcb();
+    let allocate = || {
+        let mut pmanager = kcb.mem_manager();
+        if large {
+            pmanager.allocate_large_page()
+        } else {
+            pmanager.allocate_base_page()
+        }
+    };
+
+    for i in 0..count {
+        let mut frame = match allocate() {
+            Ok(frame) => frame,
+            Err(_e) => {
+                let missing = count - i;
+                if large {
+                    crate::memory::KernelAllocator::try_refill_tcache(0, missing)?;
+                } else {
+                    crate::memory::KernelAllocator::try_refill_tcache(missing, 0)?;
+                }
+                allocate()?
+            }
+        };
+        unsafe { frame.zero() };
+        frames.push(frame);
+    }
+    Ok(())
+}
+
+/// Gives frames of a region the Map system call didn't map back.
+fn release_frames(frames: Vec<Frame>) {
+    for frame in frames {
+        if let Err(e) = crate::memory::KernelAllocator::release_frame(frame) {
+            warn!("Unable to release {:?}: {:?}", frame, e);
+        }
+    }
+}
+
+/// Unmaps the first `len` bytes of a region at `base` the Map system call
+/// mapped and gives the frames back.
+fn unmap_chunks(pid: Pid, base: VAddr, len: u64) {
+    let mut offset = 0;
+    while offset < len {
+        match nr::KernelNode::<Ring3Process>::unmap(pid, base + offset) {
+            Ok(handle) => {
+                let frame = handle.frame;
+                super::tlb::shootdown(handle);
+                release_frames(alloc::vec![frame]);
+                offset += frame.size() as u64;
+            }
+            Err(e) => {
+                warn!("Can't unmap {:#x}: {:?}", base + offset, e);
+                break;
+            }
         }
     }
 }
@@ -359,20 +1153,21 @@ fn handle_fileio(
     arg4: u64,
     arg5: u64,
 ) -> Result<(u64, u64), KError> {
-    let op = FileOperation::from(arg1);
+    let request = FileRequest::decode(arg1, [arg2, arg3, arg4, arg5]);
+    let op = request.operation();
 
     let kcb = super::kcb::get_kcb();
     let mut plock = kcb.arch.current_process();
 
-    match op {
-        FileOperation::Create => {
-            unreachable!("Create is changed to Open with O_CREAT flag in vibrio")
-        }
-        FileOperation::Open => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let pathname = arg2;
-            let flags = arg3;
-            let modes = arg4;
-            match user_virt_addr_valid(p.pid, pathname, 0) {
+    match request {
+        // Create is changed to Open with O_CREAT flag in vibrio
+        FileRequest::Create { .. } => Err(KError::NotSupported),
+        FileRequest::Open {
+            pathname,
+            flags,
+            modes,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            match user_virt_addr_valid(p.pid, pathname, 0, Access::Read) {
                 Ok(_) => {
                     if cfg!(feature = "mlnrfs") {
                         mlnr::MlnrKernelNode::map_fd(p.pid, pathname, flags, modes)
@@ -383,58 +1178,68 @@ fn handle_fileio(
                 Err(e) => Err(e),
             }
         }),
-        FileOperation::Read | FileOperation::Write => {
+        FileRequest::Read { fd, buffer, len } | FileRequest::Write { fd, buffer, len } => {
             plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                let fd = arg2;
-                let buffer = arg3;
-                let len = arg4;
-
-                match user_virt_addr_valid(p.pid, buffer, len) {
+                match user_virt_addr_valid(p.pid, buffer, len, buffer_access(op)) {
                     Ok(_) => {
-                        if cfg!(feature = "mlnrfs") {
-                            mlnr::MlnrKernelNode::file_io(op, p.pid, fd, buffer, len, -1)
+                        if pipe::is_pipe(p.pid, fd) {
+                            pipe_io(op, p.pid, fd, buffer, len)
                         } else {
-                            nr::KernelNode::<Ring3Process>::file_io(op, p.pid, fd, buffer, len, -1)
+                            chunked_file_io(op, p.pid, fd, buffer, len, -1)
                         }
                     }
                     Err(e) => Err(e),
                 }
             })
         }
-        FileOperation::ReadAt | FileOperation::WriteAt => {
+        FileRequest::ReadAt {
+            fd,
+            buffer,
+            len,
+            offset,
+        }
+        | FileRequest::WriteAt {
+            fd,
+            buffer,
+            len,
+            offset,
+        } => {
             plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-                let fd = arg2;
-                let buffer = arg3;
-                let len = arg4;
-                let offset = arg5 as i64;
+                let offset = offset as i64;
+                if pipe::is_pipe(p.pid, fd) {
+                    // Pipes have no offset
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::InvalidOffset,
+                    });
+                }
 
-                match user_virt_addr_valid(p.pid, buffer, len) {
-                    Ok(_) => {
-                        if cfg!(feature = "mlnrfs") {
-                            mlnr::MlnrKernelNode::file_io(op, p.pid, fd, buffer, len, offset)
-                        } else {
-                            nr::KernelNode::<Ring3Process>::file_io(
-                                op, p.pid, fd, buffer, len, offset,
-                            )
-                        }
-                    }
+                match user_virt_addr_valid(p.pid, buffer, len, buffer_access(op)) {
+                    Ok(_) => chunked_file_io(op, p.pid, fd, buffer, len, offset),
                     Err(e) => Err(e),
                 }
             })
         }
-        FileOperation::Close => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let fd = arg2;
-            if cfg!(feature = "mlnrfs") {
-                mlnr::MlnrKernelNode::unmap_fd(p.pid, fd)
+        FileRequest::Close { fd } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            let r = if cfg!(feature = "mlnrfs") {
+                mlnr::MlnrKernelNode::unmap_fd(p.pid, fd)?
             } else {
-                nr::KernelNode::<Ring3Process>::unmap_fd(p.pid, fd)
-            }
+                nr::KernelNode::<Ring3Process>::unmap_fd(p.pid, fd)?
+            };
+            pipe::close(p.pid, fd);
+            Ok(r)
         }),
-        FileOperation::GetInfo => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let name = arg2;
-            let info_ptr = arg3;
+        FileRequest::GetInfo {
+            name,
+            info: info_ptr,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            user_virt_addr_valid(
+                p.pid,
+                info_ptr,
+                core::mem::size_of::<FileInfo>() as u64,
+                Access::Write,
+            )?;
 
-            match user_virt_addr_valid(p.pid, name, 0) {
+            match user_virt_addr_valid(p.pid, name, 0, Access::Read) {
                 Ok(_) => {
                     if cfg!(feature = "mlnrfs") {
                         mlnr::MlnrKernelNode::file_info(p.pid, name, info_ptr)
@@ -445,10 +1250,8 @@ fn handle_fileio(
                 Err(e) => Err(e),
             }
         }),
-        FileOperation::Delete => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let name = arg2;
-
-            match user_virt_addr_valid(p.pid, name, 0) {
+        FileRequest::Delete { name } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            match user_virt_addr_valid(p.pid, name, 0, Access::Read) {
                 Ok(_) => {
                     if cfg!(feature = "mlnrfs") {
                         mlnr::MlnrKernelNode::file_delete(p.pid, name)
@@ -459,27 +1262,32 @@ fn handle_fileio(
                 Err(e) => Err(e),
             }
         }),
-        FileOperation::WriteDirect => {
+        FileRequest::WriteDirect {
+            buffer: base,
+            len,
+            offset,
+            has_offset,
+        } => {
             let kcb = super::kcb::get_kcb();
-            let len = arg3;
-            let mut offset = arg4 as usize;
-            if arg5 == 0 {
-                offset = 0;
-            }
+            let offset = if has_offset == 0 { 0 } else { offset as usize };
 
-            let mut kernslice = crate::process::KernSlice::new(arg2, len as usize);
+            let mut kernslice = crate::process::KernSlice::new(base, len as usize);
             let mut buffer = unsafe { Arc::get_mut_unchecked(&mut kernslice.buffer) };
-            match kcb.memfs.as_mut().unwrap().write(2, &mut buffer, offset) {
+            // The dummy file-system is only used for measurements, everything
+            // in it belongs to root.
+            let memfs = kcb.memfs.as_mut().unwrap();
+            match memfs.write(Credentials::ROOT, 2, &mut buffer, offset) {
                 Ok(len) => Ok((len as u64, 0)),
-                Err(e) => Err(KError::FileSystem { source: e }),
+                Err(e) => Err(e.into()),
             }
         }
-        FileOperation::FileRename => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let oldname = arg2;
-            let newname = arg3;
+        FileRequest::FileRename {
+            old_name: oldname,
+            new_name: newname,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
             match (
-                user_virt_addr_valid(p.pid, oldname, 0),
-                user_virt_addr_valid(p.pid, newname, 0),
+                user_virt_addr_valid(p.pid, oldname, 0, Access::Read),
+                user_virt_addr_valid(p.pid, newname, 0, Access::Read),
             ) {
                 (Ok(_), Ok(_)) => {
                     if cfg!(feature = "mlnrfs") {
@@ -491,106 +1299,487 @@ fn handle_fileio(
                 (Err(e), _) | (_, Err(e)) => Err(e.clone()),
             }
         }),
-        FileOperation::MkDir => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
-            let pathname = arg2;
-            let modes = arg3;
-            match user_virt_addr_valid(p.pid, pathname, 0) {
-                Ok(_) => {
-                    if cfg!(feature = "mlnrfs") {
-                        mlnr::MlnrKernelNode::mkdir(p.pid, pathname, modes)
-                    } else {
-                        nr::KernelNode::<Ring3Process>::mkdir(p.pid, pathname, modes)
+        FileRequest::MkDir { pathname, modes } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                match user_virt_addr_valid(p.pid, pathname, 0, Access::Read) {
+                    Ok(_) => {
+                        if cfg!(feature = "mlnrfs") {
+                            mlnr::MlnrKernelNode::mkdir(p.pid, pathname, modes)
+                        } else {
+                            nr::KernelNode::<Ring3Process>::mkdir(p.pid, pathname, modes)
+                        }
                     }
+                    Err(e) => Err(e),
                 }
-                Err(e) => Err(e),
+            })
+        }
+        FileRequest::Mmap {
+            fd,
+            base,
+            len,
+            offset,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            file_mmap(p.pid, fd, base, len, offset as i64)
+        }),
+        FileRequest::Munmap { base, len } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                file_munmap(p.pid, base, len)
+            })
+        }
+        FileRequest::ReadDir {
+            pathname,
+            buf: vaddr_buf,
+            len: vaddr_buf_len,
+        } => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            user_virt_addr_valid(p.pid, pathname, 0, Access::Read)?;
+
+            let entries = if cfg!(feature = "mlnrfs") {
+                mlnr::MlnrKernelNode::readdir(p.pid, pathname)?
+            } else {
+                nr::KernelNode::<Ring3Process>::readdir(p.pid, pathname)?
+            };
+            let serialized = serde_cbor::to_vec(&entries).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = user_slice(p.pid, vaddr_buf, serialized.len(), Access::Write)?;
+                user_slice.copy_from_slice(serialized.as_slice());
             }
+
+            Ok((serialized.len() as u64, 0))
         }),
-        FileOperation::Unknown => {
-            unreachable!("FileOperation not allowed");
-            Err(KError::NotSupported)
+        FileRequest::Seek { fd, offset, whence } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                let offset = offset as i64;
+                let whence = SeekWhence::try_from(whence).map_err(|_e| KError::FileSystem {
+                    source: FileSystemError::InvalidFlags,
+                })?;
+                if pipe::is_pipe(p.pid, fd) {
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::InvalidOffset,
+                    });
+                }
+
+                if cfg!(feature = "mlnrfs") {
+                    mlnr::MlnrKernelNode::file_seek(p.pid, fd, offset, whence)
+                } else {
+                    nr::KernelNode::<Ring3Process>::file_seek(p.pid, fd, offset, whence)
+                }
+            })
+        }
+        FileRequest::Allocate { fd, offset, len } => {
+            plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+                if pipe::is_pipe(p.pid, fd) {
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::InvalidOffset,
+                    });
+                }
+
+                if cfg!(feature = "mlnrfs") {
+                    mlnr::MlnrKernelNode::file_allocate(p.pid, fd, offset, len)
+                } else {
+                    with_eviction(len, || {
+                        nr::KernelNode::<Ring3Process>::file_allocate(p.pid, fd, offset, len)
+                    })
+                }
+            })
         }
+        FileRequest::Pipe {} => plock.as_ref().map_or(Err(KError::ProcessNotSet), |p| {
+            let (read_fd, write_fd) = if cfg!(feature = "mlnrfs") {
+                mlnr::MlnrKernelNode::pipe(p.pid)?
+            } else {
+                nr::KernelNode::<Ring3Process>::pipe(p.pid)?
+            };
+            pipe::create(p.pid, read_fd, write_fd);
+            Ok((read_fd, write_fd))
+        }),
+        FileRequest::Unknown(_) => Err(KError::NotSupported),
     }
 }
 
-/// TODO: This method makes file-operations slow, improve it to use large page sizes. Or maintain a list of
-/// (low, high) memory limits per process and check if (base, size) are within the process memory limits.
-fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KError> {
-    let mut base = base;
-    let upper_addr = base + size;
-
-    if upper_addr < KERNEL_BASE {
-        while base <= upper_addr {
-            // Validate addresses for the buffer end.
-            if upper_addr - base <= BASE_PAGE_SIZE as u64 {
-                match nr::KernelNode::<Ring3Process>::resolve(pid, VAddr::from(base)) {
-                    Ok(_) => {
-                        return nr::KernelNode::<Ring3Process>::resolve(
-                            pid,
-                            VAddr::from(upper_addr - 1),
-                        )
-                    }
-                    Err(e) => return Err(e.clone()),
+/// Runs `op` and, if it ran out of memory, drops clean pages of evictable
+/// files (see `FileFlags::O_EVICTABLE`) to free `bytes` and runs it once more.
+///
+/// Only for operations that don't change anything when they fail.
+fn with_eviction<F>(bytes: u64, mut op: F) -> Result<(u64, u64), KError>
+where
+    F: FnMut() -> Result<(u64, u64), KError>,
+{
+    match op() {
+        Err(e) if e.is_out_of_memory() => {
+            // The retry might need a few page-tables (or buffers) more
+            let bytes = core::cmp::max(bytes as usize, LARGE_PAGE_SIZE);
+            match nr::KernelNode::<Ring3Process>::evict(bytes) {
+                Ok(pages) if pages > 0 => {
+                    debug!("Evicted {} pages of files to free {} bytes", pages, bytes);
+                    op()
                 }
+                _ => Err(e),
             }
+        }
+        r => r,
+    }
+}
 
-            match nr::KernelNode::<Ring3Process>::resolve(pid, VAddr::from(base)) {
-                Ok(_) => {
-                    base += BASE_PAGE_SIZE as u64;
-                    continue;
+/// System call handler for the asynchronous system call ring.
+fn handle_async(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+    let pid = super::kcb::get_kcb().current_pid()?;
+
+    match AsyncRequest::decode(arg1, [arg2, arg3, 0, 0]) {
+        AsyncRequest::Setup { base, entries } => super::asyncring::setup(pid, base, entries),
+        AsyncRequest::Enter {} => super::asyncring::poll(pid),
+        AsyncRequest::Unknown(op) => Err(KError::InvalidAsyncOperation { a: op }),
+    }
+}
+
+/// System call handler for PCI devices.
+fn handle_device(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    let pid = super::kcb::get_kcb().current_pid()?;
+
+    match DeviceRequest::decode(arg1, [arg2, arg3, arg4, 0]) {
+        DeviceRequest::Claim { ids, index } => {
+            let (vendor_id, device_id) = ((ids >> 16) as u16, ids as u16);
+            let handle = super::pci::claim(pid, vendor_id, device_id, index)?;
+            Ok((handle, 0))
+        }
+        DeviceRequest::Release { handle } => {
+            super::pci::release(pid, handle)?;
+            Ok((0, 0))
+        }
+        DeviceRequest::Bar { handle, index } => {
+            let bar = super::pci::bar(pid, handle, index)?;
+            Ok((bar.encoded_base(), bar.size))
+        }
+        DeviceRequest::MapBar { handle, index } => {
+            let bar = super::pci::bar(pid, handle, index)?;
+            if bar.io {
+                return Err(KError::InvalidBar { index });
+            }
+
+            // Small BARs still get a whole page (like `MapDevice`, the
+            // region is mapped at its physical address)
+            let (base, end) = super::pci::bar_pages(&bar);
+            let frame = Frame::new(
+                PAddr::from(base),
+                (end - base) as usize,
+                super::kcb::get_kcb().node,
+            );
+            nr::KernelNode::<Ring3Process>::map_device_frame(pid, frame, MapAction::ReadWriteUser)
+        }
+        DeviceRequest::ConfigRead { handle, offset } => {
+            let value = super::pci::read_config(pid, handle, offset)?;
+            Ok((value as u64, 0))
+        }
+        DeviceRequest::ConfigWrite {
+            handle,
+            offset,
+            value,
+        } => {
+            let value =
+                u32::try_from(value).map_err(|_e| KError::InvalidSyscallArgument1 { a: value })?;
+            super::pci::write_config(pid, handle, offset, value)?;
+            Ok((0, 0))
+        }
+        DeviceRequest::Assign {
+            ids,
+            index,
+            pid: target,
+        } => {
+            require_init_process(pid)?;
+            let (vendor_id, device_id) = ((ids >> 16) as u16, ids as u16);
+            // Make sure the process exists
+            nr::KernelNode::<Ring3Process>::pinfo(target)?;
+            super::pci::assign(target, vendor_id, device_id, index)
+        }
+        DeviceRequest::Unknown(op) => Err(KError::InvalidDeviceOperation { a: op }),
+    }
+}
+
+/// Executes a system call that was submitted through an asynchronous ring.
+///
+/// Only file and vspace operations can be submitted, they return just like
+/// the regular system call (other classes might not return at all, e.g.,
+/// `ProcessOperation::Exit`).
+pub(super) fn handle_submission(
+    submission: &kpi::asyncio::Submission,
+) -> Result<(u64, u64), KError> {
+    let [arg1, arg2, arg3, arg4, arg5] = submission.args;
+    match SystemCall::new(submission.syscall) {
+        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3, arg4),
+        SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
+        // `asyncring::poll` holds the ring lock while it executes us, a
+        // submission that polls or sets up rings would deadlock the core
+        SystemCall::Async => Err(KError::InvalidSyscallArgument1 {
+            a: submission.syscall,
+        }),
+        _ => Err(KError::InvalidSyscallArgument1 {
+            a: submission.syscall,
+        }),
+    }
+}
+
+/// How the kernel accesses the buffer of a read or write: it writes what it
+/// reads into it and reads what it writes out of it.
+fn buffer_access(op: FileOperation) -> Access {
+    match op {
+        FileOperation::Read | FileOperation::ReadAt => Access::Write,
+        _ => Access::Read,
+    }
+}
+
+/// Reads or writes `[buffer, buffer+len)` from/to the pipe `fd`.
+///
+/// This doesn't go through NR, see `fs::pipe`.
+fn pipe_io(
+    op: FileOperation,
+    pid: Pid,
+    fd: u64,
+    buffer: u64,
+    len: u64,
+) -> Result<(u64, u64), KError> {
+    let mut user_slice = user_slice(pid, buffer, len as usize, buffer_access(op))?;
+    let r = match op {
+        FileOperation::Read => pipe::read(pid, fd, &mut user_slice[..]),
+        FileOperation::Write => pipe::write(pid, fd, &user_slice[..]),
+        _ => unreachable!("pipe_io received non read/write op"),
+    };
+
+    match r {
+        Ok(len) => Ok((len as u64, 0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How much the Write and WriteAt system calls write to a file at most
+/// before they check if they should give the core back.
+const WRITE_CHUNK_SIZE: u64 = LARGE_PAGE_SIZE as u64;
+
+/// Reads or writes `[buffer, buffer+len)` from/to file `fd` at `offset` (the
+/// offset of `fd` if it's negative).
+///
+/// Large writes go to the log (of NR or MLNR) in chunks: once we should give
+/// the core back (see `Arch86Kcb::should_yield`), e.g., because another core
+/// terminated the process, we return how much was written so far and
+/// user-space writes the rest with another system call.
+fn chunked_file_io(
+    op: FileOperation,
+    pid: Pid,
+    fd: u64,
+    buffer: u64,
+    len: u64,
+    offset: i64,
+) -> Result<(u64, u64), KError> {
+    if op == FileOperation::Read || op == FileOperation::ReadAt {
+        return if cfg!(feature = "mlnrfs") {
+            mlnr::MlnrKernelNode::file_io(op, pid, fd, buffer, len, offset)
+        } else {
+            with_eviction(len, || {
+                nr::KernelNode::<Ring3Process>::file_io(op, pid, fd, buffer, len, offset)
+            })
+        };
+    }
+
+    let kcb = super::kcb::get_kcb();
+    let mut written = 0;
+    loop {
+        let chunk = core::cmp::min(len - written, WRITE_CHUNK_SIZE);
+        let chunk_offset = if offset < 0 {
+            offset
+        } else {
+            offset + written as i64
+        };
+        let r = if cfg!(feature = "mlnrfs") {
+            mlnr::MlnrKernelNode::file_io(op, pid, fd, buffer + written, chunk, chunk_offset)
+        } else {
+            with_eviction(chunk, || {
+                nr::KernelNode::<Ring3Process>::file_io(
+                    op,
+                    pid,
+                    fd,
+                    buffer + written,
+                    chunk,
+                    chunk_offset,
+                )
+            })
+        };
+        match r {
+            Ok((chunk_written, _)) => {
+                written += chunk_written;
+                if chunk_written < chunk {
+                    break;
                 }
-                Err(e) => return Err(e.clone()),
             }
+            // What we wrote so far stays written
+            Err(_e) if written > 0 => break,
+            Err(e) => return Err(e),
+        }
+        if written >= len || kcb.arch.should_yield() {
+            break;
         }
-        return Ok((base, size));
     }
-    Err(KError::BadAddress)
+    Ok((written, 0))
+}
+
+/// Maps `len` bytes of file `fd` (starting at `offset`) read-only at `base`.
+///
+/// The pages of the file itself get mapped (every replica maps its own copy),
+/// so the mapping sees every write to the file. Larger mappings are done in
+/// chunks of `nr::MMAP_MAX_PAGES`.
+fn file_mmap(pid: Pid, fd: u64, base: u64, len: u64, offset: i64) -> Result<(u64, u64), KError> {
+    if cfg!(feature = "mlnrfs") {
+        // The file system doesn't live with the page-tables
+        return Err(KError::NotSupported);
+    }
+    if base % BASE_PAGE_SIZE as u64 != 0
+        || offset < 0
+        || offset as u64 % BASE_PAGE_SIZE as u64 != 0
+        || len == 0
+    {
+        return Err(KError::BadAddress);
+    }
+    let upper_addr = base.checked_add(len).ok_or(KError::BadAddress)?;
+    if upper_addr >= KERNEL_BASE {
+        return Err(KError::BadAddress);
+    }
+
+    let chunk = (nr::MMAP_MAX_PAGES * BASE_PAGE_SIZE) as u64;
+    let mut mapped = 0;
+    while mapped < len {
+        let chunk_len = core::cmp::min(chunk, len - mapped);
+        if let Err(e) = nr::KernelNode::<Ring3Process>::file_mmap(
+            pid,
+            fd,
+            VAddr::from(base + mapped),
+            chunk_len as usize,
+            offset as usize + mapped as usize,
+        ) {
+            if mapped > 0 {
+                let _ = file_munmap(pid, base, mapped);
+            }
+            return Err(e);
+        }
+        mapped += chunk_len;
+    }
+    Ok((len, 0))
+}
+
+/// Removes the mappings `file_mmap` established in `[base, base+len)`.
+fn file_munmap(pid: Pid, base: u64, len: u64) -> Result<(u64, u64), KError> {
+    let len = (len as usize + BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE * BASE_PAGE_SIZE;
+    let handle = nr::KernelNode::<Ring3Process>::file_munmap(pid, VAddr::from(base), len)?;
+    super::tlb::shootdown(handle);
+    Ok((0, 0))
+}
+
+/// Reads the (8-byte aligned) futex word at `addr` in the address space of
+/// `pid` (which is the current one).
+fn futex_word(pid: Pid, addr: u64) -> Result<u64, KError> {
+    if addr % 8 != 0 {
+        return Err(KError::BadAddress);
+    }
+    user_virt_addr_valid(pid, addr, 8, Access::Read)?;
+    // The process might change it at the same time
+    let word = UserPtr::new(addr as *mut AtomicU64);
+    Ok(word.load(Ordering::SeqCst))
+}
+
+/// Returns `[base, base+len)` as a `UserSlice` after checking that it is
+/// mapped in the address space of `pid` with rights that allow the `access`.
+///
+/// System call handlers should use this instead of `UserSlice::new`.
+fn user_slice<'a>(
+    pid: Pid,
+    base: u64,
+    len: usize,
+    access: Access,
+) -> Result<UserSlice<'a>, KError> {
+    user_virt_addr_valid(pid, base, len as u64, access)?;
+    // Safety: We just checked that the range is mapped in `pid`
+    Ok(unsafe { UserSlice::new(base, len) })
+}
+
+/// Checks that `[base, base+size)` is below the kernel and mapped in the
+/// address space of `pid` (a `size` of 0 checks only `base`) with rights
+/// that allow the kernel to do `access` on behalf of the process.
+///
+/// This is a lookup in the regions of the process (see `VRegions`).
+/// Read-only, guard and shadow-stack pages are rejected for a write, guard
+/// and shadow-stack pages for a read.
+///
+/// For a write, copy-on-write pages in the range get their own copy: the
+/// kernel accesses user memory directly so writing to it doesn't go through
+/// the page-fault handler.
+pub(super) fn user_virt_addr_valid(
+    pid: Pid,
+    base: u64,
+    size: u64,
+    access: Access,
+) -> Result<(u64, u64), KError> {
+    let cow = user_range_mapped(base, size, |base, len| {
+        nr::KernelNode::<Ring3Process>::validate_range(pid, base, len, access)
+    })?;
+    if cow && access == Access::Write {
+        // Only after a fork, resolve the pages one by one
+        validate_user_range(base, size, |va| {
+            super::process::resolve_cow(pid, va)?;
+            Ok((va.as_u64(), 0))
+        })?;
+    }
+    Ok((base, size))
+}
+
+/// Checks that `[base, base+size)` is below the kernel before `validate`
+/// looks it up in the address space of the process.
+fn user_range_mapped<T, F>(base: u64, size: u64, validate: F) -> Result<T, KError>
+where
+    F: FnOnce(VAddr, usize) -> Result<T, KError>,
+{
+    let upper_addr = base.checked_add(size).ok_or(KError::BadAddress)?;
+    if upper_addr >= KERNEL_BASE {
+        return Err(KError::BadAddress);
+    }
+    validate(VAddr::from(base), size as usize)
+}
+
+/// Checks that every page in `[base, base+size)` is below the kernel and
+/// that `resolve` can translate it.
+///
+/// A `size` of 0 checks only the page `base` is in.
+fn validate_user_range<F>(base: u64, size: u64, resolve: F) -> Result<(u64, u64), KError>
+where
+    F: Fn(VAddr) -> Result<(u64, u64), KError>,
+{
+    let upper_addr = base.checked_add(size).ok_or(KError::BadAddress)?;
+    if upper_addr >= KERNEL_BASE {
+        return Err(KError::BadAddress);
+    }
+    // The last byte that belongs to the buffer:
+    let last = if size == 0 { base } else { upper_addr - 1 };
+
+    let mut addr = base;
+    while addr <= last {
+        resolve(VAddr::from(addr))?;
+        addr += BASE_PAGE_SIZE as u64;
+    }
+    // Make sure the end of the buffer is validated too if it wasn't
+    // covered by stepping through the pages:
+    if (last - base) % BASE_PAGE_SIZE as u64 != 0 {
+        resolve(VAddr::from(last))?;
+    }
+
+    Ok((base, size))
 }
 
 #[allow(unused)]
 fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
     sprint!("syscall: {:?}", SystemCall::new(function));
 
+    let args = [arg2, arg3, arg4, arg5];
     match SystemCall::new(function) {
-        SystemCall::System => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                SystemOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
-        }
-        SystemCall::Process => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                ProcessOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
-        }
-        SystemCall::VSpace => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                VSpaceOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
-        }
-        SystemCall::FileIO => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                FileOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
-        }
+        SystemCall::System => sprintln!(" {:?}", SystemRequest::decode(arg1, args)),
+        SystemCall::Process => sprintln!(" {:?}", ProcessRequest::decode(arg1, args)),
+        SystemCall::VSpace => sprintln!(" {:?}", VSpaceRequest::decode(arg1, args)),
+        SystemCall::FileIO => sprintln!(" {:?}", FileRequest::decode(arg1, args)),
+        SystemCall::Async => sprintln!(" {:?}", AsyncRequest::decode(arg1, args)),
+        SystemCall::Device => sprintln!(" {:?}", DeviceRequest::decode(arg1, args)),
         SystemCall::Unknown => unreachable!(),
     }
 }
@@ -605,11 +1794,29 @@ pub extern "C" fn syscall_handle(
     arg4: u64,
     arg5: u64,
 ) -> ! {
+    let start = unsafe { x86::time::rdtsc() };
+    super::kcb::get_kcb().arch.account_user_time(start);
+
+    // Return right away without dispatching, logging or recording the
+    // latency: the entry/exit cost is all we want to measure here.
+    if let Some((a1, a2)) = fast_path(function, arg1) {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch.save_area.as_mut().map(|sa| {
+            sa.set_syscall_ret1(a1);
+            sa.set_syscall_ret2(a2);
+            sa.set_syscall_error_code(SystemCallError::Ok);
+        });
+        let r = super::process::Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr());
+        unsafe { r.resume() }
+    }
+
     let status: Result<(u64, u64), KError> = match SystemCall::new(function) {
         SystemCall::System => handle_system(arg1, arg2, arg3),
-        SystemCall::Process => handle_process(arg1, arg2, arg3),
-        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3),
+        SystemCall::Process => handle_process(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3, arg4),
         SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::Async => handle_async(arg1, arg2, arg3),
+        SystemCall::Device => handle_device(arg1, arg2, arg3, arg4),
         _ => Err(KError::InvalidSyscallArgument1 { a: function }),
     };
 
@@ -625,13 +1832,31 @@ pub extern "C" fn syscall_handle(
                 });
             }
             Err(status) => {
-                error!("System call returned with error: {:?}", status);
+                match status {
+                    // Happens all the time when someone polls a pipe
+                    KError::FileSystem {
+                        source: FileSystemError::WouldBlock,
+                    } => {}
+                    _ => error!("System call returned with error: {:?}", status),
+                }
+                if status.is_out_of_memory() {
+                    // Let processes that care give memory back
+                    if let Ok(pid) = kcb.current_pid() {
+                        super::irq::notify_subscribers(kpi::upcall::LOW_MEMORY, pid);
+                    }
+                }
+                let detail = status.detail();
                 kcb.arch.save_area.as_mut().map(|sa| {
-                    sa.set_syscall_error_code(status.into());
+                    sa.set_syscall_error_detail(status.into(), detail);
                 });
             }
         };
 
+        if function >= 1 && function as usize <= kcb.arch.syscall_latency.len() {
+            let cycles = unsafe { x86::time::rdtsc() } - start;
+            kcb.arch.syscall_latency[function as usize - 1].record(cycles);
+        }
+
         super::process::Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr())
     };
 
@@ -664,3 +1889,163 @@ pub fn enable_fast_syscalls() {
         wrmsr(IA32_EFER, efer);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    use crate::memory::vspace::AddressSpace;
+    use crate::memory::KernelAllocator;
+
+    use super::super::vspace::VSpace;
+
+    const PAGE: u64 = BASE_PAGE_SIZE as u64;
+
+    /// A mocked vspace where only pages in `[start, end)` are mapped.
+    fn mapped(start: u64, end: u64) -> impl Fn(VAddr) -> Result<(u64, u64), KError> {
+        move |va: VAddr| {
+            if va.as_u64() >= start && va.as_u64() < end {
+                Ok((va.as_u64(), 0))
+            } else {
+                Err(KError::BadAddress)
+            }
+        }
+    }
+
+    #[test]
+    fn user_range_edge_cases() {
+        let resolve = mapped(PAGE, 3 * PAGE);
+        assert!(validate_user_range(PAGE, 0, &resolve).is_ok());
+        assert!(validate_user_range(PAGE, 2 * PAGE, &resolve).is_ok());
+        assert!(validate_user_range(PAGE + 1, 2 * PAGE - 1, &resolve).is_ok());
+        assert!(validate_user_range(PAGE + 1, 2 * PAGE, &resolve).is_err());
+        assert!(validate_user_range(0, 2 * PAGE, &resolve).is_err());
+        assert_eq!(
+            validate_user_range(u64::MAX, 2, &resolve),
+            Err(KError::BadAddress)
+        );
+        assert_eq!(
+            validate_user_range(KERNEL_BASE, 0, |_va| Ok((0, 0))),
+            Err(KError::BadAddress)
+        );
+    }
+
+    #[test]
+    fn dma_page_sizes() {
+        let (large, huge) = (LARGE_PAGE_SIZE as u64, HUGE_PAGE_SIZE as u64);
+        assert_eq!(dma_page_size(64, 64), Ok(BASE_PAGE_SIZE));
+        assert_eq!(dma_page_size(PAGE, 1), Ok(BASE_PAGE_SIZE));
+        assert_eq!(dma_page_size(PAGE + 1, PAGE), Ok(LARGE_PAGE_SIZE));
+        assert_eq!(dma_page_size(PAGE, large), Ok(LARGE_PAGE_SIZE));
+        assert_eq!(dma_page_size(large + 1, PAGE), Ok(HUGE_PAGE_SIZE));
+        assert!(dma_page_size(0, PAGE).is_err());
+        assert!(dma_page_size(huge + 1, PAGE).is_err());
+        assert!(dma_page_size(PAGE, 3).is_err());
+        assert!(dma_page_size(PAGE, 2 * huge).is_err());
+    }
+
+    #[test]
+    fn init_process_only() {
+        assert_eq!(require_init_process(INIT_PID), Ok(()));
+        assert_eq!(require_init_process(0), Err(KError::NotPermitted));
+        assert_eq!(
+            require_init_process(INIT_PID + 1),
+            Err(KError::NotPermitted)
+        );
+    }
+
+    #[test]
+    fn no_async_submissions() {
+        let submission = AsyncRequest::Enter {}.submission(0);
+        assert_eq!(
+            handle_submission(&submission),
+            Err(KError::InvalidSyscallArgument1 {
+                a: SystemCall::Async as u64
+            })
+        );
+    }
+
+    proptest! {
+        // Random (base, size) tuples from user-space never panic and are only
+        // accepted if every byte of the buffer is mapped with the rights
+        // for the access (checked like `ReadOps::MemValidRange` does, with
+        // the regions of a `VSpace`).
+        #[test]
+        fn user_range_validation(
+            base in prop_oneof![0..16 * PAGE, any::<u64>()],
+            size in prop_oneof![0..8 * PAGE, any::<u64>()],
+            map_start in 0..16u64,
+            map_len in 0..8u64,
+            writeable in any::<bool>(),
+            write in any::<bool>(),
+        ) {
+            crate::arch::start(0, core::ptr::null_mut());
+            let rights = if writeable { MapAction::ReadWriteUser } else { MapAction::ReadUser };
+            let access = if write { Access::Write } else { Access::Read };
+
+            let mut vspace = VSpace::new();
+            for page in map_start..map_start + map_len {
+                KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+                let frame = Frame::new(PAddr::from(page * PAGE), BASE_PAGE_SIZE, 0);
+                vspace
+                    .map_frame(VAddr::from(page * PAGE), frame, rights)
+                    .expect("Can't map frame");
+            }
+            let r = user_range_mapped(base, size, |base, len| {
+                if vspace.is_mapped(base, len, access) {
+                    Ok(())
+                } else {
+                    Err(KError::BadAddress)
+                }
+            });
+
+            let (start, end) = (map_start * PAGE, (map_start + map_len) * PAGE);
+            let last = base
+                .checked_add(size)
+                .map(|upper| if size == 0 { base } else { upper - 1 });
+            let expected = match last {
+                Some(last) => {
+                    last < KERNEL_BASE
+                        && base >= start
+                        && last < end
+                        && (writeable || access == Access::Read)
+                }
+                None => false,
+            };
+            prop_assert_eq!(r.is_ok(), expected);
+            if let Err(e) = r {
+                prop_assert_eq!(e, KError::BadAddress);
+            }
+        }
+
+        // Decoding random system calls (like the handlers do) never panics,
+        // only known operations get decoded and a request encodes back to
+        // the same request.
+        #[test]
+        fn syscall_decoding(
+            function in any::<u64>(),
+            arg1 in any::<u64>(),
+            args in any::<[u64; 4]>(),
+        ) {
+            macro_rules! check_decode {
+                ($request:ident, $operation:ident) => {{
+                    let request = $request::decode(arg1, args);
+                    prop_assert_eq!(request.operation(), $operation::from(arg1));
+                    let (op, args) = request.encode();
+                    prop_assert_eq!($request::decode(op, args), request);
+                }};
+            }
+
+            match SystemCall::new(function) {
+                SystemCall::System => check_decode!(SystemRequest, SystemOperation),
+                SystemCall::Process => check_decode!(ProcessRequest, ProcessOperation),
+                SystemCall::VSpace => check_decode!(VSpaceRequest, VSpaceOperation),
+                SystemCall::FileIO => check_decode!(FileRequest, FileOperation),
+                SystemCall::Async => check_decode!(AsyncRequest, AsyncOperation),
+                SystemCall::Device => check_decode!(DeviceRequest, DeviceOperation),
+                SystemCall::Unknown => prop_assert!(function == 0 || function > 6),
+            }
+        }
+    }
+}
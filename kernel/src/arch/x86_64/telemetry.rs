@@ -0,0 +1,134 @@
+//! Energy (RAPL) and thermal telemetry of the core we run on.
+//!
+//! The readings end up in the core statistics (`SystemOperation::Stats`),
+//! so benchmarks can report energy next to their throughput. Neither the
+//! RAPL nor the thermal MSRs are enumerated by CPUID and reading a missing
+//! one raises a #GP, so we only read them on (bare-metal) Intel machines
+//! that are known to have them. Hypervisors like QEMU/KVM don't emulate
+//! them.
+
+use kpi::system::PowerStats;
+use x86::cpuid::CpuId;
+use x86::msr::rdmsr;
+
+/// Units of the RAPL power, energy and time readings.
+const MSR_RAPL_POWER_UNIT: u32 = 0x606;
+/// Energy counter of the package.
+const MSR_PKG_ENERGY_STATUS: u32 = 0x611;
+/// Energy counter of all cores of the package (power plane 0).
+const MSR_PP0_ENERGY_STATUS: u32 = 0x639;
+/// Thermal status of the core.
+const IA32_THERM_STATUS: u32 = 0x19c;
+/// Thermal status of the package.
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1b1;
+/// Has the temperature at which the core starts throttling (TjMax).
+const MSR_TEMPERATURE_TARGET: u32 = 0x1a2;
+
+/// Intel family 6 models before Sandy Bridge don't have RAPL.
+const FIRST_RAPL_MODEL: u8 = 0x2a;
+
+/// Intel server models (Xeon) that don't have the power plane 0 counter.
+const SERVER_MODELS: [u8; 9] = [0x2d, 0x3e, 0x3f, 0x4f, 0x55, 0x56, 0x6a, 0x6c, 0x8f];
+
+/// TjMax of cores that don't tell us.
+const DEFAULT_TJ_MAX: u8 = 100;
+
+/// What telemetry the machine has.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct Support {
+    package_energy: bool,
+    cores_energy: bool,
+    core_temperature: bool,
+    package_temperature: bool,
+}
+
+fn support() -> Support {
+    let cpuid = CpuId::new();
+    let is_intel = cpuid
+        .get_vendor_info()
+        .map_or(false, |vi| vi.as_string() == "GenuineIntel");
+    let (family, model, in_vm) = cpuid.get_feature_info().map_or((0, 0, true), |fi| {
+        let model = fi.extended_model_id() << 4 | fi.model_id();
+        (fi.family_id(), model, fi.has_hypervisor())
+    });
+    if !is_intel || family != 6 || in_vm {
+        return Support::default();
+    }
+
+    let has_rapl = model >= FIRST_RAPL_MODEL;
+    let (dts, ptm) = cpuid
+        .get_thermal_power_info()
+        .map_or((false, false), |tpi| (tpi.has_dts(), tpi.has_ptm()));
+    Support {
+        package_energy: has_rapl,
+        cores_energy: has_rapl && !SERVER_MODELS.contains(&model),
+        core_temperature: dts,
+        package_temperature: ptm,
+    }
+}
+
+/// The energy counters count in units of `1 / 2^shift` joules.
+fn energy_unit_shift(power_unit: u64) -> u8 {
+    ((power_unit >> 8) & 0x1f) as u8
+}
+
+/// Temperature (in degrees Celsius) in a thermal status MSR (None if the
+/// reading isn't valid).
+fn temperature(tj_max: u8, therm_status: u64) -> Option<u8> {
+    let valid = therm_status & (1 << 31) != 0;
+    let below_tj_max = ((therm_status >> 16) & 0x7f) as u8;
+    if valid {
+        Some(tj_max.saturating_sub(below_tj_max))
+    } else {
+        None
+    }
+}
+
+/// Takes the readings of the core we run on (and its package).
+pub fn sample() -> PowerStats {
+    let support = support();
+    let mut stats = PowerStats::default();
+
+    unsafe {
+        if support.package_energy {
+            stats.energy_unit_shift = energy_unit_shift(rdmsr(MSR_RAPL_POWER_UNIT));
+            stats.package_energy = Some(rdmsr(MSR_PKG_ENERGY_STATUS) as u32);
+        }
+        if support.cores_energy {
+            stats.cores_energy = Some(rdmsr(MSR_PP0_ENERGY_STATUS) as u32);
+        }
+
+        if support.core_temperature || support.package_temperature {
+            let tj_max = match ((rdmsr(MSR_TEMPERATURE_TARGET) >> 16) & 0xff) as u8 {
+                0 => DEFAULT_TJ_MAX,
+                tj_max => tj_max,
+            };
+            if support.core_temperature {
+                let status = rdmsr(IA32_THERM_STATUS);
+                stats.core_temperature = temperature(tj_max, status);
+                stats.thermal_throttling = status & 0x1 != 0;
+            }
+            if support.package_temperature {
+                stats.package_temperature = temperature(tj_max, rdmsr(IA32_PACKAGE_THERM_STATUS));
+            }
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn readings() {
+        // Energy status units of 14 (61 µJ), as on most Intel machines
+        assert_eq!(energy_unit_shift(0x000a_0e03), 14);
+
+        // Valid reading, 35 degrees below TjMax
+        assert_eq!(temperature(100, 1 << 31 | 35 << 16), Some(65));
+        assert_eq!(temperature(100, 35 << 16), None);
+        assert_eq!(temperature(90, 1 << 31 | 0x7f << 16), Some(0));
+    }
+}
@@ -6,6 +6,10 @@ use apic::ApicDriver;
 /// Default when to raise the next timer irq (in rdtsc ticks)
 pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
 
+/// Shortest period (in rdtsc ticks) of the timer upcalls a process can
+/// subscribe to (see `kpi::upcall::TIMER`).
+pub const MIN_UPCALL_PERIOD: u64 = 100_000;
+
 /// Register a periodic timer to advance replica
 ///
 /// TODO(api): Ideally this should come from Instant::now() +
@@ -13,7 +17,11 @@ pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
 /// convert between TSC and Instant
 pub fn set(deadline: u64) {
     let kcb = get_kcb();
-    let mut apic = kcb.arch.apic();
-    apic.tsc_enable();
-    unsafe { apic.tsc_set(x86::time::rdtsc() + deadline) };
+    let deadline = unsafe { x86::time::rdtsc() } + deadline;
+    {
+        let mut apic = kcb.arch.apic();
+        apic.tsc_enable();
+        unsafe { apic.tsc_set(deadline) };
+    }
+    kcb.arch.set_timer_deadline(deadline);
 }
@@ -17,6 +17,7 @@ use super::memory::BASE_PAGE_SIZE;
 use super::process::Ring3Process;
 use crate::is_page_aligned;
 use crate::memory::vspace::TlbFlushHandle;
+use crate::process::Pid;
 use crate::{mlnr, nr};
 
 // In the xAPIC mode, the Destination Format Register (DFR) through the MMIO interface determines the choice of a
@@ -32,7 +33,9 @@ use crate::{mlnr, nr};
 // Logical x2APIC ID = [(x2APIC ID[19:4] « 16) | (1 « x2APIC ID[3:0])]
 
 lazy_static! {
-    static ref IPI_WORKQUEUE: Vec<ArrayQueue<WorkItem>> = {
+    /// Work items for every core, along with the rdtsc value at the time
+    /// they were enqueued (to measure IPI latency).
+    static ref IPI_WORKQUEUE: Vec<ArrayQueue<(WorkItem, u64)>> = {
         let cores = topology::MACHINE_TOPOLOGY.num_threads();
         let mut channels = Vec::with_capacity(cores);
         for _i in 0..cores {
@@ -47,6 +50,18 @@ lazy_static! {
 pub enum WorkItem {
     Shootdown(Arc<Shootdown>),
     AdvanceReplica(usize),
+    /// Dispatch an executor of a gang-scheduled process.
+    GangSchedule(Pid),
+    /// Upcall (cmd, arg) into the executor of a process (if it's running).
+    Activation(Pid, u64, u64),
+    /// The process was terminated by another core, stop running its
+    /// executor.
+    Terminate(Pid),
+    /// Halt the core because we're about to suspend the system.
+    Park,
+    /// An executor of the core became runnable again (e.g., it got woken up
+    /// from a futex), look for it if the core is idle.
+    Wakeup,
 }
 
 #[derive(Debug)]
@@ -82,8 +97,10 @@ impl Shootdown {
         // before this function completes:
         self.acknowledge();
 
-        let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-        if it.count() > 20 {
+        // Don't count the pages by iterating, the region can be all of
+        // user-space (see `Op::ProcFork`):
+        let pages = (self.vregion.end - self.vregion.start) / BASE_PAGE_SIZE as u64;
+        if pages > 20 {
             trace!("flush the entire TLB");
             unsafe { x86::tlb::flush_all() };
         } else {
@@ -98,19 +115,44 @@ impl Shootdown {
 
 pub fn enqueue(gtid: topology::GlobalThreadId, s: WorkItem) {
     trace!("TLB enqueue shootdown msg {:?}", s);
-    assert!(IPI_WORKQUEUE[gtid as usize].push(s).is_ok());
+    let sent = unsafe { x86::time::rdtsc() };
+    assert!(IPI_WORKQUEUE[gtid as usize].push((s, sent)).is_ok());
 }
 
-pub fn dequeue(gtid: topology::GlobalThreadId) {
+/// Is there work for `gtid` that it hasn't processed yet?
+pub fn has_work(gtid: topology::GlobalThreadId) -> bool {
+    !IPI_WORKQUEUE[gtid as usize].is_empty()
+}
+
+/// Processes the next work item for `gtid`.
+///
+/// Returns the rdtsc value of when the item was enqueued (or None
+/// in case there was no work in the queue).
+pub fn dequeue(gtid: topology::GlobalThreadId) -> Option<u64> {
     match IPI_WORKQUEUE[gtid as usize].pop() {
-        Ok(msg) => match msg {
-            WorkItem::Shootdown(s) => {
-                trace!("TLB channel got msg {:?}", s);
-                s.process();
-            }
-            WorkItem::AdvanceReplica(log_id) => advance_log(log_id),
-        },
-        Err(_) => { /*IPI request was handled by eager_advance_mlnr_replica()*/ }
+        Ok((msg, sent)) => {
+            match msg {
+                WorkItem::Shootdown(s) => {
+                    trace!("TLB channel got msg {:?}", s);
+                    s.process();
+                }
+                WorkItem::AdvanceReplica(log_id) => advance_log(log_id),
+                WorkItem::GangSchedule(pid) => super::kcb::get_kcb().arch.fair.request(pid),
+                WorkItem::Activation(pid, cmd, arg) => {
+                    super::kcb::get_kcb().arch.activation = Some((pid, cmd, arg))
+                }
+                WorkItem::Terminate(pid) => super::kcb::get_kcb().arch.terminated = Some(pid),
+                WorkItem::Park => super::power::park(),
+                // Getting the interrupt is enough: an idle core goes back to
+                // the scheduler after it (see `irq::handle_generic_exception`)
+                WorkItem::Wakeup => {}
+            };
+            Some(sent)
+        }
+        Err(_) => {
+            /*IPI request was handled by eager_advance_mlnr_replica()*/
+            None
+        }
     }
 }
 
@@ -132,11 +174,17 @@ fn advance_log(log_id: usize) {
 pub fn eager_advance_mlnr_replica() {
     let core_id = topology::MACHINE_TOPOLOGY.current_thread().id;
     match IPI_WORKQUEUE[core_id as usize].pop() {
-        Ok(msg) => {
+        Ok((msg, sent)) => {
             match &msg {
-                WorkItem::Shootdown(_s) => {
-                    // If its for TLB shootdown, insert it back into the queue.
-                    enqueue(core_id, msg)
+                WorkItem::Shootdown(_)
+                | WorkItem::GangSchedule(_)
+                | WorkItem::Activation(..)
+                | WorkItem::Terminate(_)
+                | WorkItem::Park
+                | WorkItem::Wakeup => {
+                    // If its for TLB shootdown or scheduling, insert it back
+                    // into the queue (and keep the original timestamp).
+                    assert!(IPI_WORKQUEUE[core_id as usize].push((msg, sent)).is_ok());
                 }
                 WorkItem::AdvanceReplica(log_id) => advance_log(*log_id),
             }
@@ -175,6 +223,102 @@ pub fn send_ipi_to_apic(apic_id: ApicId) {
     unsafe { apic.send_ipi(icr) }
 }
 
+fn send_work_pending(apic_id: ApicId) {
+    let kcb = super::kcb::get_kcb();
+    let mut apic = kcb.arch.apic();
+
+    let icr = Icr::for_x2apic(
+        super::irq::TLB_WORK_PENDING,
+        apic_id,
+        DestinationShorthand::NoShorthand,
+        DeliveryMode::Fixed,
+        DestinationMode::Physical,
+        DeliveryStatus::Idle,
+        Level::Assert,
+        TriggerMode::Edge,
+    );
+
+    unsafe { apic.send_ipi(icr) }
+}
+
+/// Asks `cores` to dispatch an executor of the gang-scheduled process `pid`.
+///
+/// Unlike a shootdown this is best-effort: we don't wait for the other
+/// cores and skip cores that have too much pending work already.
+pub fn gang_schedule(pid: Pid, cores: &[topology::GlobalThreadId]) {
+    let my_gtid = super::kcb::get_kcb().arch.id();
+    let sent = unsafe { x86::time::rdtsc() };
+
+    for gtid in cores.iter().filter(|gtid| **gtid as usize != my_gtid) {
+        let queued = IPI_WORKQUEUE[*gtid as usize]
+            .push((WorkItem::GangSchedule(pid), sent))
+            .is_ok();
+        if queued {
+            trace!("Send gang schedule for {} to gtid:{}", pid, gtid);
+            send_work_pending(topology::MACHINE_TOPOLOGY.threads[*gtid as usize].apic_id());
+        }
+    }
+}
+
+/// Sends a scheduler activation (`cmd`, `arg`) for process `pid` to `gtid`.
+///
+/// Best-effort, like `gang_schedule`.
+pub fn activate(gtid: topology::GlobalThreadId, pid: Pid, cmd: u64, arg: u64) {
+    let sent = unsafe { x86::time::rdtsc() };
+    let queued = IPI_WORKQUEUE[gtid as usize]
+        .push((WorkItem::Activation(pid, cmd, arg), sent))
+        .is_ok();
+    if queued {
+        trace!("Send activation {:#x} for {} to gtid:{}", cmd, pid, gtid);
+        send_work_pending(topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id());
+    }
+}
+
+/// Tells `gtid` that process `pid` was terminated (so it stops running its
+/// executor right away instead of on the next timer interrupt).
+///
+/// Best-effort, like `gang_schedule`.
+pub fn terminate(gtid: topology::GlobalThreadId, pid: Pid) {
+    let sent = unsafe { x86::time::rdtsc() };
+    let queued = IPI_WORKQUEUE[gtid as usize]
+        .push((WorkItem::Terminate(pid), sent))
+        .is_ok();
+    if queued {
+        trace!("Send terminate for {} to gtid:{}", pid, gtid);
+        send_work_pending(topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id());
+    }
+}
+
+/// Tells `gtid` that one of its executors can run again.
+///
+/// Best-effort, like `gang_schedule`.
+pub fn wakeup(gtid: topology::GlobalThreadId) {
+    let sent = unsafe { x86::time::rdtsc() };
+    let queued = IPI_WORKQUEUE[gtid as usize]
+        .push((WorkItem::Wakeup, sent))
+        .is_ok();
+    if queued {
+        trace!("Send wakeup to gtid:{}", gtid);
+        send_work_pending(topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id());
+    }
+}
+
+/// Asks `gtid` to halt (see `power::park`).
+///
+/// Unlike `gang_schedule` and `activate` this is not best-effort: we wait
+/// for space in the queue of `gtid`.
+pub fn park(gtid: topology::GlobalThreadId) {
+    let sent = unsafe { x86::time::rdtsc() };
+    while IPI_WORKQUEUE[gtid as usize]
+        .push((WorkItem::Park, sent))
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    trace!("Send park to gtid:{}", gtid);
+    send_work_pending(topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id());
+}
+
 fn send_ipi_multicast(ldr: u32) {
     let kcb = super::kcb::get_kcb();
     let mut apic = kcb.arch.apic();
@@ -234,7 +378,9 @@ pub fn shootdown(handle: TlbFlushHandle) {
 
     for (gtid, include) in handle.core_map.into_iter().enumerate() {
         // TODO: enumerates over all 256 potential entries...
-        if include && gtid != my_gtid {
+        // Failed cores don't run anything anymore (see `isolation`)
+        let failed = super::isolation::failed(gtid as topology::GlobalThreadId);
+        if include && gtid != my_gtid && !failed {
             let apic_id = topology::MACHINE_TOPOLOGY.threads[gtid].apic_id();
             let cluster_addr = apic_id.x2apic_logical_cluster_address();
             let cluster = apic_id.x2apic_logical_cluster_id();
@@ -276,6 +422,9 @@ pub fn shootdown(handle: TlbFlushHandle) {
 }
 
 pub fn advance_replica(gtid: topology::GlobalThreadId, log_id: usize) {
+    if super::isolation::failed(gtid) {
+        return;
+    }
     trace!("Send AdvanceReplica IPI for {} to {}", log_id, gtid);
     let apic_id = topology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id();
 
@@ -0,0 +1,148 @@
+//! Offsets between the TSCs of the cores.
+//!
+//! Trace records (see `nrtrace`) carry rdtsc timestamps of the core that
+//! recorded them. To merge the traces of all cores (and replicas) into one
+//! timeline, we estimate how far the TSC of every app core is ahead of the
+//! one of the BSP while the core boots: the BSP ([`measure`]) and the app
+//! core ([`respond`]) exchange timestamps a few times, like PTP does, and we
+//! keep the exchange with the shortest round-trip. The estimate is off by at
+//! most half of that round-trip (the error we report with it).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// How many timestamps the cores exchange.
+const ROUNDS: usize = 64;
+
+/// How long (in rdtsc cycles) we wait for the other core.
+const TIMEOUT: u64 = 100_000_000;
+
+/// The exchange is over (in `REQUEST`).
+const DONE: u64 = u64::max_value();
+
+/// Timestamp of the BSP when it sent a request (0 if there is none).
+static REQUEST: AtomicU64 = AtomicU64::new(0);
+/// Timestamp of the app core when it got the request (0 until it answered).
+static RESPONSE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// The offset and error of every app core we measured.
+    static ref OFFSETS: Mutex<HashMap<topology::GlobalThreadId, (i64, u64)>> =
+        Mutex::new(HashMap::new());
+}
+
+fn rdtsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// Offset of the remote TSC (`remote` when the request arrived) and
+/// round-trip of an exchange that was sent at `sent` and answered at
+/// `received` (both local).
+fn estimate(sent: u64, remote: u64, received: u64) -> (i64, u64) {
+    let rtt = received.saturating_sub(sent);
+    let midpoint = sent + rtt / 2;
+    (remote.wrapping_sub(midpoint) as i64, rtt)
+}
+
+/// Measures the offset of core `gtid` (on the BSP, after the core signaled
+/// that it's initialized and called `respond`).
+pub fn measure(gtid: topology::GlobalThreadId) {
+    let mut best: Option<(i64, u64)> = None;
+    for _round in 0..ROUNDS {
+        RESPONSE.store(0, Ordering::SeqCst);
+        let sent = rdtsc();
+        REQUEST.store(sent, Ordering::SeqCst);
+
+        let remote = loop {
+            let remote = RESPONSE.load(Ordering::SeqCst);
+            if remote != 0 {
+                break Some(remote);
+            }
+            if rdtsc() - sent > TIMEOUT {
+                break None;
+            }
+            core::hint::spin_loop();
+        };
+        let received = rdtsc();
+
+        match remote {
+            Some(remote) => {
+                let (offset, rtt) = estimate(sent, remote, received);
+                if best.map_or(true, |(_offset, best_rtt)| rtt < best_rtt) {
+                    best = Some((offset, rtt));
+                }
+            }
+            None => break,
+        }
+    }
+    // Wait until the core saw that we're done (it resets `REQUEST`), so the
+    // next core doesn't see a stale `DONE`
+    REQUEST.store(DONE, Ordering::SeqCst);
+    let done = rdtsc();
+    while REQUEST.load(Ordering::SeqCst) == DONE && rdtsc() - done < TIMEOUT {
+        core::hint::spin_loop();
+    }
+    REQUEST.store(0, Ordering::SeqCst);
+
+    match best {
+        Some((offset, rtt)) => {
+            debug!(
+                "TSC of core {} is {} cycles (+/- {}) ahead",
+                gtid,
+                offset,
+                rtt / 2
+            );
+            OFFSETS.lock().insert(gtid, (offset, rtt / 2));
+        }
+        None => warn!("Core {} didn't answer, don't know its TSC offset", gtid),
+    }
+}
+
+/// Answers the requests of `measure` (on the app core that is booting) until
+/// the BSP is done.
+pub fn respond() {
+    let mut seen = 0;
+    let mut last = rdtsc();
+    loop {
+        let request = REQUEST.load(Ordering::SeqCst);
+        if request == DONE {
+            break;
+        }
+        if request != 0 && request != seen {
+            RESPONSE.store(rdtsc(), Ordering::SeqCst);
+            seen = request;
+            last = rdtsc();
+        } else if rdtsc() - last > TIMEOUT {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    // Tell the BSP we're done
+    let _r = REQUEST.compare_exchange(DONE, 0, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// How far (in cycles) the TSC of core `gtid` is ahead of the one of the BSP
+/// and how far off that can be (`None` if we didn't measure it).
+pub fn offset(gtid: topology::GlobalThreadId) -> Option<(i64, u64)> {
+    if gtid == 0 {
+        return Some((0, 0));
+    }
+    OFFSETS.lock().get(&gtid).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offset_estimates() {
+        // Remote core is 500 cycles ahead, 100 cycles each way
+        assert_eq!(estimate(1000, 1600, 1200), (500, 200));
+        // Remote core is behind
+        assert_eq!(estimate(1000, 900, 1200), (-200, 200));
+        assert_eq!(offset(0), Some((0, 0)));
+    }
+}
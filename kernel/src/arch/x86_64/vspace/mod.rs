@@ -13,6 +13,9 @@ use page_table::PageTable;
 
 pub struct VSpace {
     pub mappings: BTreeMap<VAddr, MappingInfo>,
+    /// The mapped ranges (kept up to date with `mappings`), for fast checks
+    /// of user-space buffers.
+    pub regions: VRegions,
     pub page_table: PageTable,
 }
 
@@ -64,7 +67,9 @@ impl AddressSpace for VSpace {
         }
 
         self.mappings.insert(base, MappingInfo::new(frame, action));
-        self.page_table.map_frame(base, frame, action)
+        self.page_table.map_frame(base, frame, action)?;
+        self.regions.insert(base, frame.size(), action);
+        Ok(())
     }
 
     fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
@@ -77,6 +82,10 @@ impl AddressSpace for VSpace {
         self.page_table.resolve(addr)
     }
 
+    fn is_mapped(&self, base: VAddr, size: usize, access: Access) -> bool {
+        self.regions.contains(base, size, access)
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, AddressSpaceError> {
         for (&existing_base, existing_mapping) in
             self.mappings.range((Unbounded, Included(base))).rev()
@@ -91,6 +100,7 @@ impl AddressSpace for VSpace {
 
         let r = self.page_table.unmap(base)?;
         self.mappings.remove(&r.vaddr);
+        self.regions.remove(r.vaddr, r.frame.size());
         Ok(r)
     }
 
@@ -105,13 +115,14 @@ impl AddressSpace for VSpace {
             .get_mut(&r.0)
             .ok_or(AddressSpaceError::NotMapped)?;
         mapping.rights = new_rights;
+        self.regions.set_rights(r.0, r.1, new_rights);
         Ok(r)
     }
 }
 
 impl Drop for VSpace {
     fn drop(&mut self) {
-        //panic!("Drop for VSpace!");
+        self.page_table.release_user_tables();
     }
 }
 
@@ -119,6 +130,7 @@ impl VSpace {
     pub(crate) fn new() -> Self {
         VSpace {
             mappings: BTreeMap::new(),
+            regions: VRegions::new(),
             page_table: PageTable::new(),
         }
     }
@@ -135,4 +147,22 @@ impl VSpace {
     pub fn pml4_address(&self) -> PAddr {
         self.page_table.pml4_address()
     }
+
+    /// Tags all pages of the mapping at `base` with protection `key`.
+    pub fn set_key(&mut self, base: VAddr, key: u16) -> Result<(), AddressSpaceError> {
+        let size = self
+            .mappings
+            .get(&base)
+            .ok_or(AddressSpaceError::NotMapped)?
+            .frame
+            .size();
+
+        // The mapping can consist of pages of different sizes
+        let mut tagged = 0;
+        while tagged < size {
+            let (_vaddr, len) = self.page_table.set_key(base + tagged, key)?;
+            tagged += len;
+        }
+        Ok(())
+    }
 }
@@ -14,10 +14,21 @@ use crate::memory::{kernel_vaddr_to_paddr, paddr_to_kernel_vaddr, Frame, PAddr,
 enum Modify {
     /// Change rights of mapping to new MapAction.
     UpdateRights(MapAction),
+    /// Tag the mapping with a memory protection key.
+    SetKey(u16),
     /// Remove frame from page-table.
     Unmap,
 }
 
+/// The protection key bits of a leaf entry (in any level).
+const PKEY_MASK: u64 = 0xf << PKEY_SHIFT;
+const PKEY_SHIFT: u64 = 59;
+
+/// `entry` (a raw leaf entry) with its protection key set to `key`.
+fn with_key(entry: u64, key: u16) -> u64 {
+    (entry & !PKEY_MASK) | ((key as u64) << PKEY_SHIFT & PKEY_MASK)
+}
+
 pub struct PageTable {
     pub pml4: Pin<Box<PML4>>,
 }
@@ -139,6 +150,57 @@ impl PageTable {
         kernel_vaddr_to_paddr(pml4_vaddr)
     }
 
+    /// Tags the page containing `vaddr` with protection `key`.
+    ///
+    /// # Returns
+    /// The range (page) that was tagged.
+    pub fn set_key(&mut self, vaddr: VAddr, key: u16) -> Result<(VAddr, usize), AddressSpaceError> {
+        if !vaddr.is_base_page_aligned() {
+            return Err(AddressSpaceError::InvalidBase);
+        }
+        let (vaddr, _paddr, size, _rights) = self.modify_generic(vaddr, Modify::SetKey(key))?;
+        Ok((vaddr, size))
+    }
+
+    /// Gives the page-tables of the user-space part of the address-space
+    /// back to the allocators (the mapped frames themselves are not touched).
+    ///
+    /// The PML4 slots with the kernel mappings (see `Ring3Process::new`) are
+    /// shared with `init_vspace` and left alone.
+    pub(crate) fn release_user_tables(&mut self) {
+        let kcb = crate::kcb::get_kcb();
+        // TODO(numa-correctness): The tables were allocated by the core that
+        // did the mapping which is not necessarily on our node
+        let release = |base: PAddr| {
+            let frame = Frame::new(base, BASE_PAGE_SIZE, kcb.physical_memory.affinity);
+            if let Err(e) = crate::memory::KernelAllocator::release_frame(frame) {
+                warn!("Unable to release page-table {:?}: {:?}", frame, e);
+            }
+        };
+
+        for pml4_idx in 0..PAGE_SIZE_ENTRIES {
+            if (128..=135).contains(&pml4_idx) || !self.pml4[pml4_idx].is_present() {
+                continue;
+            }
+
+            let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+            for pdpt_entry in pdpt.iter() {
+                if !pdpt_entry.is_present() || pdpt_entry.is_page() {
+                    continue;
+                }
+                let pd = self.get_pd(*pdpt_entry);
+                for pd_entry in pd.iter() {
+                    if pd_entry.is_present() && !pd_entry.is_page() {
+                        release(pd_entry.address());
+                    }
+                }
+                release(pdpt_entry.address());
+            }
+            release(self.pml4[pml4_idx].address());
+            self.pml4[pml4_idx] = PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty());
+        }
+    }
+
     /// Constructs an identity map but with an offset added to the region.
     ///
     /// This can be useful for example to map physical memory above `KERNEL_BASE`.
@@ -711,7 +773,11 @@ impl PageTable {
                         }
                         Modify::UpdateRights(new_rights) => {
                             let flags = PDPTFlags::P | PDPTFlags::PS | new_rights.to_pdpt_rights();
-                            pdpt[pdpt_idx] = PDPTEntry::new(paddr_start, flags);
+                            let key = pdpt[pdpt_idx].0 & PKEY_MASK;
+                            pdpt[pdpt_idx] = PDPTEntry(PDPTEntry::new(paddr_start, flags).0 | key);
+                        }
+                        Modify::SetKey(key) => {
+                            pdpt[pdpt_idx] = PDPTEntry(with_key(pdpt[pdpt_idx].0, key));
                         }
                     };
                     return Ok((vaddr_start, paddr_start, HUGE_PAGE_SIZE, old_flags));
@@ -733,7 +799,11 @@ impl PageTable {
                                 Modify::UpdateRights(new_rights) => {
                                     let flags =
                                         PDFlags::P | PDFlags::PS | new_rights.to_pd_rights();
-                                    pd[pd_idx] = PDEntry::new(paddr_start, flags);
+                                    let key = pd[pd_idx].0 & PKEY_MASK;
+                                    pd[pd_idx] = PDEntry(PDEntry::new(paddr_start, flags).0 | key);
+                                }
+                                Modify::SetKey(key) => {
+                                    pd[pd_idx] = PDEntry(with_key(pd[pd_idx].0, key));
                                 }
                             };
                             return Ok((vaddr_start, paddr_start, LARGE_PAGE_SIZE, old_flags));
@@ -753,7 +823,12 @@ impl PageTable {
                                     }
                                     Modify::UpdateRights(new_rights) => {
                                         let flags = PTFlags::P | new_rights.to_pt_rights();
-                                        pt[pt_idx] = PTEntry::new(paddr_start, flags);
+                                        let key = pt[pt_idx].0 & PKEY_MASK;
+                                        pt[pt_idx] =
+                                            PTEntry(PTEntry::new(paddr_start, flags).0 | key);
+                                    }
+                                    Modify::SetKey(key) => {
+                                        pt[pt_idx] = PTEntry(with_key(pt[pt_idx].0, key));
                                     }
                                 };
                                 return Ok((vaddr_start, paddr_start, BASE_PAGE_SIZE, old_flags));
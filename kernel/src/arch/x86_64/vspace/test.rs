@@ -18,6 +18,7 @@ enum TestAction {
     Adjust(VAddr, MapAction),
     Resolve(VAddr),
     Unmap(VAddr),
+    IsMapped(VAddr, usize, Access),
 }
 
 fn action() -> impl Strategy<Value = TestAction> {
@@ -36,6 +37,8 @@ fn action() -> impl Strategy<Value = TestAction> {
         (vaddrs(0x60_0000), map_rights()).prop_map(|(a, b)| TestAction::Adjust(a, b)),
         vaddrs(0x60_0000).prop_map(TestAction::Unmap),
         vaddrs(0x60_0000).prop_map(TestAction::Resolve),
+        (vaddrs(0x60_0000), 0..0x40_0000usize, accesses())
+            .prop_map(|(a, b, c)| TestAction::IsMapped(a, b, c)),
     ]
 }
 
@@ -53,9 +56,14 @@ fn map_rights() -> impl Strategy<Value = MapAction> {
         Just(MapAction::ReadExecuteKernel),
         Just(MapAction::ReadWriteExecuteUser),
         Just(MapAction::ReadWriteExecuteKernel),
+        Just(MapAction::ShadowStackUser),
     ]
 }
 
+fn accesses() -> impl Strategy<Value = Access> {
+    prop_oneof![Just(Access::Read), Just(Access::Write)]
+}
+
 fn page_sizes() -> impl Strategy<Value = usize> {
     prop::sample::select(vec![BASE_PAGE_SIZE, LARGE_PAGE_SIZE])
 }
@@ -123,6 +131,12 @@ proptest! {
                     let rtotest = totest.unmap(vaddr);
                     assert_eq!(rmodel, rtotest);
                 }
+                IsMapped(vaddr, size, access) => {
+                    assert_eq!(
+                        model.is_mapped(vaddr, size, access),
+                        totest.is_mapped(vaddr, size, access)
+                    );
+                }
             }
         }
     }
@@ -4,6 +4,8 @@ use custom_error::custom_error;
 
 use kpi::SystemCallError;
 
+use crate::memory::vspace::AddressSpaceError;
+
 custom_error! {
     #[derive(PartialEq, Clone)]
     pub KError
@@ -14,15 +16,31 @@ custom_error! {
     BadAddress = "User-space pointer is not valid.",
     GlobalMemoryNotSet = "Global memory is not yet available.",
     CoreAlreadyAllocated = "The requested core is already allocated by another process.",
+    CoreFailed = "The requested core failed (its kernel panicked).",
     InvalidSyscallArgument1{a: u64} = "Invalid 1st syscall argument supplied: {}",
     InvalidVSpaceOperation{a: u64} = "Invalid VSpace Operation (2nd syscall argument) supplied: {}",
     InvalidProcessOperation{a: u64} = "Invalid Process Operation (2nd syscall argument) supplied: {}",
     InvalidSystemOperation{a: u64} = "Invalid System Operation (2nd syscall argument) supplied: {}",
+    InvalidAsyncOperation{a: u64} = "Invalid Async Operation (2nd syscall argument) supplied: {}",
+    InvalidDeviceOperation{a: u64} = "Invalid Device Operation (2nd syscall argument) supplied: {}",
     VSpace{source: crate::memory::vspace::AddressSpaceError} = "VSpace operation covers existing mapping",
     PhysicalMemory{source: crate::memory::AllocationError} = "Memory allocation failed",
     FileSystem{source: crate::fs::FileSystemError} = "FileSystem operation does file based io",
     ProcessError{source: crate::process::ProcessError} = "Process Operation failed",
     InvalidAffinityId = "Specified an invalid NUMA node ID for affinity.",
+    InvalidIrq{gsi: u64} = "Interrupt line {} does not exist or belongs to another process.",
+    InvalidCore{core: u64} = "Core {} does not exist.",
+    NoPciRoute{device: u64} = "The ACPI tables don't route an interrupt pin of PCI device {:#x}.",
+    NoMsiVectors{count: u64} = "Not enough free MSI vectors to allocate {}.",
+    NoPciDevice{id: u64} = "There is no PCI device {:#x} (vendor and device id).",
+    DeviceClaimed{id: u64} = "PCI device {:#x} was already claimed by another process.",
+    InvalidDeviceHandle{handle: u64} = "Device handle {:#x} does not exist or belongs to another process.",
+    InvalidBar{index: u64} = "The device has no BAR {} (or it can't be mapped).",
+    NoProtectionKeys = "The process already allocated all protection keys.",
+    InvalidProtectionKey{key: u64} = "Protection key {} was not allocated by the process.",
+    NotPermitted = "The operation is only allowed for privileged processes.",
+    InvalidKernelImage{reason: &'static str} = "Can't boot into the new kernel image: {}",
+    CantShareMapping{base: u64} = "The writable mapping at {:#x} can't be shared copy-on-write (only 4 KiB and 2 MiB pages can).",
 }
 
 impl Into<SystemCallError> for KError {
@@ -31,17 +49,85 @@ impl Into<SystemCallError> for KError {
     /// The idea is to reduce a big set of events into a smaller set of less precise errors.
     /// We can log the the precise errors before we return in the kernel since the conversion
     /// happens at the end of the system call.
+    ///
+    /// Errors that wrap an error of another subsystem are translated by that
+    /// error (so every subsystem decides how its errors look to user-space).
     fn into(self) -> SystemCallError {
         match self {
-            KError::VSpace { source: s } => s.into(),
+            KError::ProcessNotSet => SystemCallError::InternalError,
+            KError::ReplicaNotSet => SystemCallError::InternalError,
+            KError::NoExecutorForCore => SystemCallError::InternalError,
+            KError::NotSupported => SystemCallError::NotSupported,
+            KError::BadAddress => SystemCallError::BadAddress,
+            KError::GlobalMemoryNotSet => SystemCallError::InternalError,
+            KError::CoreAlreadyAllocated => SystemCallError::InternalError,
+            KError::CoreFailed => SystemCallError::NotSupported,
             KError::InvalidSyscallArgument1 { .. } => SystemCallError::NotSupported,
             KError::InvalidVSpaceOperation { .. } => SystemCallError::NotSupported,
             KError::InvalidProcessOperation { .. } => SystemCallError::NotSupported,
-            KError::BadAddress { .. } => SystemCallError::BadAddress,
-            KError::FileSystem { source: s } => s.into(),
-            _ => SystemCallError::InternalError,
+            KError::InvalidSystemOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidAsyncOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidDeviceOperation { .. } => SystemCallError::NotSupported,
+            KError::VSpace { source } => source.into(),
+            KError::PhysicalMemory { source } => source.into(),
+            KError::FileSystem { source } => source.into(),
+            KError::ProcessError { source } => source.into(),
+            KError::InvalidAffinityId => SystemCallError::NotSupported,
+            KError::InvalidIrq { .. } => SystemCallError::NotSupported,
+            KError::InvalidCore { .. } => SystemCallError::NotSupported,
+            KError::NoPciRoute { .. } => SystemCallError::NotSupported,
+            KError::NoMsiVectors { .. } => SystemCallError::NotSupported,
+            KError::NoPciDevice { .. } => SystemCallError::NotSupported,
+            KError::DeviceClaimed { .. } => SystemCallError::PermissionError,
+            KError::InvalidDeviceHandle { .. } => SystemCallError::BadFileDescriptor,
+            KError::InvalidBar { .. } => SystemCallError::NotSupported,
+            KError::NoProtectionKeys => SystemCallError::OutOfMemory,
+            KError::InvalidProtectionKey { .. } => SystemCallError::BadFlags,
+            KError::NotPermitted => SystemCallError::PermissionError,
+            KError::InvalidKernelImage { .. } => SystemCallError::NotSupported,
+            KError::CantShareMapping { .. } => SystemCallError::NotSupported,
+        }
+    }
+}
+
+impl KError {
+    /// An auxiliary value that is returned to user-space along with the
+    /// error code to make it easier to diagnose what went wrong.
+    ///
+    /// For invalid arguments this is the offending argument, for vspace
+    /// errors the affected virtual address, 0 if there is nothing to report.
+    pub fn detail(&self) -> u64 {
+        match self {
+            KError::InvalidSyscallArgument1 { a } => *a,
+            KError::InvalidVSpaceOperation { a } => *a,
+            KError::InvalidProcessOperation { a } => *a,
+            KError::InvalidSystemOperation { a } => *a,
+            KError::InvalidAsyncOperation { a } => *a,
+            KError::InvalidDeviceOperation { a } => *a,
+            KError::InvalidIrq { gsi } => *gsi,
+            KError::InvalidCore { core } => *core,
+            KError::NoPciRoute { device } => *device,
+            KError::NoMsiVectors { count } => *count,
+            KError::NoPciDevice { id } => *id,
+            KError::DeviceClaimed { id } => *id,
+            KError::InvalidDeviceHandle { handle } => *handle,
+            KError::InvalidBar { index } => *index,
+            KError::InvalidProtectionKey { key } => *key,
+            KError::CantShareMapping { base } => *base,
+            KError::VSpace { source } => match source {
+                AddressSpaceError::AlreadyMapped { base } => base.as_u64(),
+                AddressSpaceError::BaseOverflow { base } => *base,
+                _ => 0,
+            },
+            _ => 0,
         }
     }
+
+    /// Did the operation fail because we ran out of memory?
+    pub fn is_out_of_memory(&self) -> bool {
+        let e: SystemCallError = self.clone().into();
+        e == SystemCallError::OutOfMemory
+    }
 }
 
 impl Default for KError {
@@ -49,3 +135,80 @@ impl Default for KError {
         KError::NotSupported
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileSystemError;
+    use crate::memory::AllocationError;
+    use crate::memory::VAddr;
+    use crate::process::ProcessError;
+
+    /// Wrapping a subsystem error keeps it intact.
+    #[test]
+    fn source_preserved() {
+        let e: KError = ProcessError::BinaryNotFound {
+            binary: "init".to_string(),
+        }
+        .into();
+        assert_eq!(
+            e,
+            KError::ProcessError {
+                source: ProcessError::BinaryNotFound {
+                    binary: "init".to_string()
+                }
+            }
+        );
+
+        let e: KError = AddressSpaceError::AlreadyMapped {
+            base: VAddr::from(0x1000u64),
+        }
+        .into();
+        assert_eq!(e.detail(), 0x1000);
+    }
+
+    /// A wrapped error translates to the same system call error as the
+    /// error on its own.
+    #[test]
+    fn syscall_error_of_source() {
+        let fs = FileSystemError::PermissionError;
+        let e: SystemCallError = KError::from(fs.clone()).into();
+        let expected: SystemCallError = fs.into();
+        assert_eq!(e, expected);
+
+        let e: SystemCallError = KError::from(AllocationError::CacheExhausted).into();
+        assert_eq!(e, SystemCallError::OutOfMemory);
+
+        let e: SystemCallError = KError::from(ProcessError::NotEnoughMemory).into();
+        assert_eq!(e, SystemCallError::OutOfMemory);
+
+        let e: SystemCallError = KError::from(AddressSpaceError::AlreadyMapped {
+            base: VAddr::from(0x1000u64),
+        })
+        .into();
+        assert_eq!(e, SystemCallError::VSpaceAlreadyMapped);
+    }
+
+    #[test]
+    fn syscall_error() {
+        let e: SystemCallError = KError::NotSupported.into();
+        assert_eq!(e, SystemCallError::NotSupported);
+
+        let e: SystemCallError = KError::NotPermitted.into();
+        assert_eq!(e, SystemCallError::PermissionError);
+
+        let e: SystemCallError = KError::ReplicaNotSet.into();
+        assert_eq!(e, SystemCallError::InternalError);
+    }
+
+    /// Running out of memory looks the same no matter which allocation
+    /// failed.
+    #[test]
+    fn out_of_memory() {
+        assert!(KError::from(AllocationError::CacheExhausted).is_out_of_memory());
+        assert!(KError::from(FileSystemError::OutOfMemory).is_out_of_memory());
+        assert!(KError::from(ProcessError::NotEnoughMemory).is_out_of_memory());
+        assert!(!KError::from(FileSystemError::PermissionError).is_out_of_memory());
+        assert!(!KError::NotSupported.is_out_of_memory());
+    }
+}
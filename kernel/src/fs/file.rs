@@ -1,33 +1,182 @@
 use crate::fs::{FileSystemError, Modes};
 use alloc::vec::Vec;
+use core::fmt;
 use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
 use kpi::io::*;
-use x86::bits64::paging::BASE_PAGE_SIZE;
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+/// A page of file data. Buffers are made of pages so they are page-aligned
+/// and can be mapped into processes (see `File::pages`).
+#[repr(C, align(4096))]
+struct Page([u8; BASE_PAGE_SIZE]);
+
+/// The memory of a buffer: the first `len` bytes hold the data, the rest is
+/// always zero (a mapping of the buffer shows it).
+struct PageBuf {
+    pages: Vec<Page>,
+    len: usize,
+}
+
+impl PageBuf {
+    fn try_alloc(size: usize) -> Result<PageBuf, FileSystemError> {
+        let count = size / BASE_PAGE_SIZE;
+        let mut pages: Vec<Page> = Vec::new();
+        pages
+            .try_reserve_exact(count)
+            .map_err(|_e| FileSystemError::OutOfMemory)?;
+        // Safety: All zeros is a valid `Page`
+        unsafe {
+            core::ptr::write_bytes(pages.as_mut_ptr(), 0, count);
+            pages.set_len(count);
+        }
+        Ok(PageBuf { pages, len: 0 })
+    }
+
+    fn capacity(&self) -> usize {
+        self.pages.len() * BASE_PAGE_SIZE
+    }
+
+    /// Grows (with `value`) or shrinks the data to `new_len` bytes, it can't
+    /// grow beyond the capacity.
+    fn resize(&mut self, new_len: usize, value: u8) {
+        assert!(new_len <= self.capacity());
+        let old_len = self.len;
+        self.len = core::cmp::max(old_len, new_len);
+        if new_len > old_len {
+            if value != 0 {
+                self[old_len..new_len].fill(value);
+            }
+        } else {
+            self[new_len..old_len].fill(0);
+        }
+        self.len = new_len;
+    }
+
+    /// Kernel address of page `idx` of the buffer.
+    fn page_addr(&self, idx: usize) -> usize {
+        &self.pages[idx] as *const Page as usize
+    }
+}
+
+impl Deref for PageBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: The pages are `len` bytes (or more) of initialized memory
+        unsafe { core::slice::from_raw_parts(self.pages.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for PageBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safety: The pages are `len` bytes (or more) of initialized memory
+        unsafe { core::slice::from_raw_parts_mut(self.pages.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+impl PartialEq for PageBuf {
+    fn eq(&self, other: &PageBuf) -> bool {
+        self.capacity() == other.capacity() && self[..] == other[..]
+    }
+}
+
+impl Eq for PageBuf {}
+
+impl fmt::Debug for PageBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageBuf")
+            .field("len", &self.len)
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
-/// The buffer is used by the file. Each buffer is BASE_PAGE_SIZE
-/// long and a file consists of many such buffers.
+/// The buffer is used by the file. A buffer is either BASE_PAGE_SIZE or
+/// LARGE_PAGE_SIZE long and a file consists of many such buffers.
 struct Buffer {
-    data: Vec<u8>,
+    /// Offset of the first byte of the buffer in the file.
+    offset: usize,
+    /// The buffer is LARGE_PAGE_SIZE long.
+    large: bool,
+    data: PageBuf,
 }
 
 impl Buffer {
     /// This function tries to allocate a vector of BASE_PAGE_SIZE long
     /// and returns a buffer in case of the success; error otherwise.
     pub fn try_alloc_buffer() -> Result<Buffer, FileSystemError> {
-        let mut data = Vec::new();
-        match data.try_reserve(BASE_PAGE_SIZE) {
-            Ok(_) => Ok(Buffer { data }),
-            Err(_) => Err(FileSystemError::OutOfMemory),
+        Buffer::try_alloc(BASE_PAGE_SIZE)
+    }
+
+    /// This function tries to allocate a vector of LARGE_PAGE_SIZE long
+    /// and returns a buffer in case of the success; error otherwise.
+    pub fn try_alloc_large_buffer() -> Result<Buffer, FileSystemError> {
+        Buffer::try_alloc(LARGE_PAGE_SIZE)
+    }
+
+    fn try_alloc(size: usize) -> Result<Buffer, FileSystemError> {
+        Ok(Buffer {
+            offset: 0,
+            large: size == LARGE_PAGE_SIZE,
+            data: PageBuf::try_alloc(size)?,
+        })
+    }
+
+    /// Allocates the buffer that starts at `offset` of a file.
+    ///
+    /// The first LARGE_PAGE_SIZE bytes of a file are always kept in base
+    /// pages, most files are small. Once a file spills over, every buffer that
+    /// starts at a large-page boundary is a large one. If we can't get a large
+    /// buffer we fall back to base pages until the next boundary.
+    fn try_alloc_at(offset: usize) -> Result<Buffer, FileSystemError> {
+        let large = if offset >= LARGE_PAGE_SIZE && offset % LARGE_PAGE_SIZE == 0 {
+            Buffer::try_alloc_large_buffer().ok()
+        } else {
+            None
+        };
+
+        let mut buffer = match large {
+            Some(buffer) => buffer,
+            None => Buffer::try_alloc_buffer()?,
+        };
+        buffer.offset = offset;
+        Ok(buffer)
+    }
+
+    /// Maximum number of bytes the buffer can hold.
+    fn size(&self) -> usize {
+        if self.large {
+            LARGE_PAGE_SIZE
+        } else {
+            BASE_PAGE_SIZE
         }
     }
 }
 
+/// How many base and large pages hold the data of a file.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PageStats {
+    pub base_pages: usize,
+    pub large_pages: usize,
+}
+
+impl PageStats {
+    /// Memory (in bytes) the pages take up.
+    pub fn bytes(&self) -> usize {
+        self.base_pages * BASE_PAGE_SIZE + self.large_pages * LARGE_PAGE_SIZE
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 /// File type has a list of buffers and modes to access the file
 pub struct File {
     mcache: Vec<Buffer>,
     modes: FileModes,
+    /// How many process mappings use the buffers (see `pages`), they can't
+    /// be dropped until they are gone.
+    mappings: usize,
     // TODO: Add more file related attributes
 }
 
@@ -43,6 +192,7 @@ impl File {
         Ok(File {
             mcache: mcache,
             modes,
+            mappings: 0,
         })
     }
 
@@ -50,29 +200,10 @@ impl File {
     /// the same convention as a vector length. So, size of the file is equal
     /// to the data in it and not the max-allocated buffer-size.
     pub fn get_size(&self) -> usize {
-        let buffer_num = self.mcache.len();
-        match buffer_num {
-            0 => 0,
-            1 => self.mcache[buffer_num - 1].data.len(),
-            _ => {
-                match self.mcache[buffer_num - 1].data.len() {
-                    // If resize_file()/write() added some empty buffers to be filled
-                    // later, then scan all the buffers to get the file-size.
-                    0 => {
-                        let mut len = 0;
-                        for buf in &self.mcache {
-                            match buf.data.len() {
-                                0 => break,
-                                curr_buff_len => len += curr_buff_len,
-                            }
-                        }
-                        len
-                    }
-                    // If file is filled till last buffer
-                    last_buffer_len => ((buffer_num - 1) * BASE_PAGE_SIZE + last_buffer_len),
-                }
-            }
-        }
+        // All buffers except the last one are full.
+        self.mcache
+            .last()
+            .map_or(0, |buffer| buffer.offset + buffer.data.len())
     }
 
     /// This method returns the mode in which file is created.
@@ -80,63 +211,83 @@ impl File {
         self.modes
     }
 
+    /// Returns how many base and large pages the file uses.
+    pub fn page_stats(&self) -> PageStats {
+        let large_pages = self.mcache.iter().filter(|buffer| buffer.large).count();
+        PageStats {
+            base_pages: self.mcache.len() - large_pages,
+            large_pages,
+        }
+    }
+
     /// This method is internally used by write_file() method. The additional length
     /// is initialzed to zero.
     pub fn increase_file_size(&mut self, curr_file_len: usize, new_len: usize) -> bool {
-        if new_len == 0 {
+        if new_len <= curr_file_len {
             return true;
         }
 
         let free_in_last_buffer = match self.mcache.last() {
-            Some(buffer) => BASE_PAGE_SIZE - buffer.data.len(),
+            Some(buffer) => buffer.size() - buffer.data.len(),
             None => 0,
         };
 
         let add_new = new_len - curr_file_len;
-        match add_new <= free_in_last_buffer {
+        if add_new <= free_in_last_buffer {
             // Don't need to add new buffer
-            true => {
-                let offset = self.mcache.last().unwrap().data.len();
-                self.mcache
-                    .last_mut()
-                    .unwrap()
-                    .data
-                    .resize(offset + add_new, 0);
-                return true;
-            }
-
-            // Add new buffer
-            false => {
-                if self.mcache.len() > 0 {
-                    self.mcache
-                        .last_mut()
-                        .unwrap()
-                        .data
-                        .resize(BASE_PAGE_SIZE, 0);
-                }
-                let remaining = add_new - free_in_last_buffer;
-                let new_buffers = ceil(remaining, BASE_PAGE_SIZE);
-                let mut vec = Vec::with_capacity(new_buffers);
-                for _i in 0..new_buffers {
-                    match Buffer::try_alloc_buffer() {
-                        Ok(mut buffer) => {
-                            buffer.data.resize(BASE_PAGE_SIZE, 0);
-                            vec.push(buffer);
-                        }
-                        Err(_) => return false,
-                    }
-                }
+            let buffer = self.mcache.last_mut().unwrap();
+            let offset = buffer.data.len();
+            buffer.data.resize(offset + add_new, 0);
+            return true;
+        }
 
-                // Filled all the buffers with zeros, resize the last buffer.
-                if new_len % BASE_PAGE_SIZE != 0 {
-                    let sure_bytes_to_write = (new_buffers - 1) * BASE_PAGE_SIZE;
-                    let bytes_in_last_buffer = new_len - (self.get_size() + sure_bytes_to_write);
-                    vec.last_mut().unwrap().data.resize(bytes_in_last_buffer, 0);
+        // Allocate all the new buffers first so we don't change the file if we
+        // run out of memory.
+        let mut offset = curr_file_len + free_in_last_buffer;
+        let mut vec = Vec::new();
+        if vec
+            .try_reserve(ceil(new_len - offset, LARGE_PAGE_SIZE))
+            .is_err()
+        {
+            return false;
+        }
+        while offset < new_len {
+            match Buffer::try_alloc_at(offset) {
+                Ok(mut buffer) => {
+                    let len = core::cmp::min(buffer.size(), new_len - offset);
+                    buffer.data.resize(len, 0);
+                    offset += len;
+                    vec.push(buffer);
                 }
-                self.mcache.append(&mut vec);
-                return true;
+                Err(_) => return false,
             }
         }
+
+        if let Some(buffer) = self.mcache.last_mut() {
+            let size = buffer.size();
+            buffer.data.resize(size, 0);
+        }
+        self.mcache.append(&mut vec);
+        true
+    }
+
+    /// Finds the buffer that holds `offset`, returns its index in `mcache` and
+    /// where `offset` is inside the buffer.
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let buffer_num = if offset < LARGE_PAGE_SIZE {
+            // Always in base pages
+            offset_to_buffernum(offset, BASE_PAGE_SIZE)
+        } else {
+            match self
+                .mcache
+                .binary_search_by(|buffer| buffer.offset.cmp(&offset))
+            {
+                Ok(idx) => idx,
+                Err(idx) => idx.saturating_sub(1),
+            }
+        };
+        let buffer_offset = self.mcache.get(buffer_num).map_or(0, |b| b.offset);
+        (buffer_num, offset - buffer_offset)
     }
 
     /// This method is internally call on a read() system-call. It reads the content of the
@@ -148,8 +299,7 @@ impl File {
         start_offset: usize,
         end_offset: usize,
     ) -> Result<usize, FileSystemError> {
-        let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
-        let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
+        let (mut buffer_num, mut offset_in_buffer) = self.locate(start_offset);
         let mut copied = 0;
         let mut dst_start = 0;
         let mut dst_end;
@@ -200,14 +350,13 @@ impl File {
             }
         }
 
-        let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
-        let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
+        let (mut buffer_num, mut offset_in_buffer) = self.locate(start_offset);
         let mut copied = 0;
         let mut dst_start = 0;
         let mut dst_end;
 
         while copied < len {
-            let useful_data_curr_buffer = BASE_PAGE_SIZE - offset_in_buffer;
+            let useful_data_curr_buffer = self.mcache[buffer_num].data.len() - offset_in_buffer;
             let remaining = len - copied;
 
             let src_start = offset_in_buffer;
@@ -232,10 +381,87 @@ impl File {
         Ok(len)
     }
 
+    /// Makes sure the file has memory for its first `len` bytes, it grows
+    /// (with zeros) if it is shorter.
+    pub fn allocate(&mut self, len: usize) -> Result<(), FileSystemError> {
+        let curr_file_len = self.get_size();
+        if self.increase_file_size(curr_file_len, len) {
+            Ok(())
+        } else {
+            Err(FileSystemError::OutOfMemory)
+        }
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) {
         self.mcache.clear();
     }
+
+    /// Kernel addresses of the pages that hold `[offset, offset+len)` of the
+    /// file (`offset` is page-aligned and the range can't go past the last
+    /// page of the file).
+    ///
+    /// Every mapping of the pages counts as a mapping of the file (see
+    /// `add_mapping`).
+    pub fn pages(&self, offset: usize, len: usize) -> Result<Vec<usize>, FileSystemError> {
+        let end = offset
+            .checked_add(len)
+            .ok_or(FileSystemError::InvalidOffset)?;
+        let file_end = ceil(self.get_size(), BASE_PAGE_SIZE) * BASE_PAGE_SIZE;
+        if offset % BASE_PAGE_SIZE != 0 || end > file_end {
+            return Err(FileSystemError::InvalidOffset);
+        }
+
+        let mut pages = Vec::new();
+        pages
+            .try_reserve_exact(ceil(len, BASE_PAGE_SIZE))
+            .map_err(|_e| FileSystemError::OutOfMemory)?;
+        for page_offset in (offset..end).step_by(BASE_PAGE_SIZE) {
+            let (buffer_num, offset_in_buffer) = self.locate(page_offset);
+            let buffer = &self.mcache[buffer_num];
+            pages.push(buffer.data.page_addr(offset_in_buffer / BASE_PAGE_SIZE));
+        }
+        Ok(pages)
+    }
+
+    /// A process mapped pages of the file.
+    pub fn add_mapping(&mut self) {
+        self.mappings += 1;
+    }
+
+    /// A process unmapped pages of the file (see `add_mapping`).
+    pub fn remove_mapping(&mut self) {
+        self.mappings = self.mappings.saturating_sub(1);
+    }
+
+    /// Does a process map pages of the file? Its buffers can't be dropped
+    /// (by `file_truncate` or `evict`) then.
+    pub fn is_mapped(&self) -> bool {
+        self.mappings > 0
+    }
+
+    /// Drops buffers from the end of the file until at least `bytes` of
+    /// memory are free (or the file is empty), the file gets shorter.
+    ///
+    /// Returns the pages that got dropped.
+    pub fn evict(&mut self, bytes: usize) -> PageStats {
+        let mut evicted = PageStats::default();
+        let mut freed = 0;
+        while freed < bytes {
+            match self.mcache.pop() {
+                Some(buffer) => {
+                    freed += buffer.size();
+                    if buffer.large {
+                        evicted.large_pages += 1;
+                    } else {
+                        evicted.base_pages += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
 }
 
 /// This is used to determine, how many buffers to add dependeing on the number
@@ -403,4 +629,116 @@ pub mod test {
             assert_eq!(file.mcache[1].data[i], 0xb);
         }
     }
+
+    #[test]
+    /// Files that outgrow the first large page keep their data in large pages.
+    fn test_large_file() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(file.page_stats(), PageStats::default());
+
+        let size = 2 * LARGE_PAGE_SIZE + 100;
+        let wbuffer: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        assert_eq!(file.write_file(&wbuffer, size, 0), Ok(size));
+        assert_eq!(file.get_size(), size);
+        assert_eq!(
+            file.page_stats(),
+            PageStats {
+                base_pages: LARGE_PAGE_SIZE / BASE_PAGE_SIZE,
+                large_pages: 2,
+            }
+        );
+
+        // Reads that cross the base/large and large/large boundaries
+        for start in &[
+            0,
+            LARGE_PAGE_SIZE - 10,
+            LARGE_PAGE_SIZE + 7,
+            2 * LARGE_PAGE_SIZE - 3,
+        ] {
+            let mut rbuffer = alloc::vec![0; 4096];
+            let end = core::cmp::min(start + 4096, size);
+            assert_eq!(file.read_file(&mut rbuffer, *start, end), Ok(end - start));
+            assert_eq!(rbuffer[..end - start], wbuffer[*start..end]);
+        }
+
+        // Overwrite across a boundary
+        let obuffer = alloc::vec![0xa; 150];
+        let start = 2 * LARGE_PAGE_SIZE - 100;
+        assert_eq!(file.write_file(&obuffer, 150, start), Ok(150));
+        assert_eq!(file.get_size(), size);
+        let mut rbuffer = alloc::vec![0; 152];
+        assert_eq!(
+            file.read_file(&mut rbuffer, start - 1, start + 151),
+            Ok(152)
+        );
+        assert_eq!(rbuffer[0], wbuffer[start - 1]);
+        assert!(rbuffer[1..151].iter().all(|b| *b == 0xa));
+        assert_eq!(rbuffer[151], wbuffer[start + 150]);
+
+        file.file_truncate();
+        assert_eq!(file.page_stats(), PageStats::default());
+    }
+
+    #[test]
+    /// Growing a file in small steps past the first large page.
+    fn test_resize_file_spill() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let step = 3 * BASE_PAGE_SIZE + 5;
+        let mut len = 0;
+        while len < LARGE_PAGE_SIZE + 2 * BASE_PAGE_SIZE {
+            assert!(file.increase_file_size(len, len + step));
+            len += step;
+            assert_eq!(file.get_size(), len);
+        }
+
+        let stats = file.page_stats();
+        assert_eq!(stats.base_pages, LARGE_PAGE_SIZE / BASE_PAGE_SIZE);
+        assert_eq!(stats.large_pages, 1);
+        for buffer in &file.mcache[..stats.base_pages] {
+            assert_eq!(buffer.data.len(), BASE_PAGE_SIZE);
+        }
+        assert_eq!(file.mcache[stats.base_pages].offset, LARGE_PAGE_SIZE);
+        assert_eq!(
+            file.mcache[stats.base_pages].data.len(),
+            len - LARGE_PAGE_SIZE
+        );
+    }
+
+    #[test]
+    /// Eviction drops the end of the file, the rest stays readable.
+    fn test_evict_file() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let size = 2 * LARGE_PAGE_SIZE + 100;
+        let wbuffer: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        assert_eq!(file.write_file(&wbuffer, size, 0), Ok(size));
+
+        assert_eq!(
+            file.evict(1),
+            PageStats {
+                base_pages: 0,
+                large_pages: 1,
+            }
+        );
+        assert_eq!(file.get_size(), 2 * LARGE_PAGE_SIZE);
+
+        assert_eq!(
+            file.evict(LARGE_PAGE_SIZE + BASE_PAGE_SIZE),
+            PageStats {
+                base_pages: 1,
+                large_pages: 1,
+            }
+        );
+        assert_eq!(file.get_size(), LARGE_PAGE_SIZE - BASE_PAGE_SIZE);
+        let mut rbuffer = alloc::vec![0; file.get_size()];
+        assert_eq!(
+            file.read_file(&mut rbuffer, 0, file.get_size()),
+            Ok(file.get_size())
+        );
+        assert_eq!(rbuffer[..], wbuffer[..file.get_size()]);
+
+        file.evict(usize::max_value());
+        assert_eq!(file.get_size(), 0);
+        assert_eq!(file.page_stats(), PageStats::default());
+        assert_eq!(file.evict(1), PageStats::default());
+    }
 }
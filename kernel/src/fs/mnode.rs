@@ -1,9 +1,13 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::string::ToString;
 
+use kpi::io::FileModes;
+
 use crate::arch::process::UserSlice;
 use crate::fs::file::*;
 use crate::fs::{FileSystemError, Mnode, Modes};
+use crate::process::Credentials;
 
 /// Each memory-node can be of two types: directory or a file.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -31,6 +35,17 @@ pub struct MemNode {
     name: String,
     node_type: NodeType,
     file: Option<File>,
+    /// Access modes (for owner, group and other).
+    modes: FileModes,
+    /// User and group the mnode belongs to.
+    owner: Credentials,
+    /// The directory that contains this mnode (None for the root).
+    parent: Option<Mnode>,
+    /// Entries of a directory, by name (always empty for files).
+    children: BTreeMap<String, Mnode>,
+    /// The content of the file is a cache (see `FileFlags::O_EVICTABLE`),
+    /// it can be dropped when memory runs low.
+    evictable: bool,
 }
 
 /// Required for the testing
@@ -40,6 +55,11 @@ impl PartialEq for MemNode {
             && (self.name == other.name)
             && (self.node_type == other.node_type)
             && (self.file == other.file)
+            && (self.modes == other.modes)
+            && (self.owner == other.owner)
+            && (self.parent == other.parent)
+            && (self.children == other.children)
+            && (self.evictable == other.evictable)
     }
 }
 
@@ -51,6 +71,11 @@ impl Default for MemNode {
             name: String::from(""),
             node_type: NodeType::File,
             file: None,
+            modes: FileModes::empty(),
+            owner: Credentials::ROOT,
+            parent: None,
+            children: BTreeMap::new(),
+            evictable: false,
         }
     }
 }
@@ -76,14 +101,97 @@ impl MemNode {
             name: pathname.to_string(),
             node_type,
             file,
+            modes: FileModes::from(modes),
+            owner: Credentials::ROOT,
+            parent: None,
+            children: BTreeMap::new(),
+            evictable: false,
         })
     }
 
+    /// Get the (path)name of the mnode.
+    #[cfg(test)]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Change the (path)name of the mnode, e.g., after a rename.
+    pub fn set_name(&mut self, pathname: &str) {
+        self.name = pathname.to_string();
+    }
+
+    /// Get the user and group the mnode belongs to.
+    pub fn get_owner(&self) -> Credentials {
+        self.owner
+    }
+
+    /// Change the user and group the mnode belongs to.
+    pub fn set_owner(&mut self, owner: Credentials) {
+        self.owner = owner;
+    }
+
+    /// Check if a process running as `creds` may access the mnode as
+    /// `access` asks for (a combination of the user bits).
+    ///
+    /// The owner bits apply if the user owns the mnode, otherwise the group
+    /// bits if it is in the group of the mnode and the other bits if neither.
+    pub fn permits(&self, creds: Credentials, access: FileModes) -> bool {
+        let modes = if creds.uid == self.owner.uid {
+            self.modes
+        } else if creds.gid == self.owner.gid {
+            self.modes.group()
+        } else {
+            self.modes.other()
+        };
+        modes.contains(access)
+    }
+
+    /// Get the directory that contains this mnode.
+    pub fn get_parent(&self) -> Option<Mnode> {
+        self.parent
+    }
+
+    /// Move the mnode to directory `parent`.
+    pub fn set_parent(&mut self, parent: Mnode) {
+        self.parent = Some(parent);
+    }
+
+    /// Get the entries of a directory.
+    pub fn get_children(&self) -> &BTreeMap<String, Mnode> {
+        &self.children
+    }
+
+    /// Find the entry `name` in a directory.
+    pub fn get_child(&self, name: &str) -> Option<Mnode> {
+        self.children.get(name).copied()
+    }
+
+    /// Add the entry `name` to a directory.
+    pub fn add_child(&mut self, name: &str, mnode_num: Mnode) -> Result<(), FileSystemError> {
+        if self.node_type != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+
+        match self.children.insert(name.to_string(), mnode_num) {
+            None => Ok(()),
+            Some(_) => Err(FileSystemError::AlreadyPresent),
+        }
+    }
+
+    /// Remove the entry `name` from a directory.
+    pub fn remove_child(&mut self, name: &str) -> Option<Mnode> {
+        self.children.remove(name)
+    }
+
     /// Write to an in-memory file.
-    pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, FileSystemError> {
+    pub fn write(
+        &mut self,
+        creds: Credentials,
+        buffer: &[u8],
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
         // Return if the user doesn't have write permissions for the file.
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
-        {
+        if self.node_type != NodeType::File || !self.permits(creds, FileModes::S_IWUSR) {
             return Err(FileSystemError::PermissionError);
         }
         let len: usize = buffer.len();
@@ -91,11 +199,35 @@ impl MemNode {
         self.file.as_mut().unwrap().write_file(buffer, len, offset)
     }
 
+    /// Make sure the file has memory for `[offset, offset+len)` (it grows
+    /// with zeros if it is shorter), so writes to the range can't run out of
+    /// memory.
+    pub fn allocate(
+        &mut self,
+        creds: Credentials,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError> {
+        // Return if the user doesn't have write permissions for the file.
+        if self.node_type != NodeType::File || !self.permits(creds, FileModes::S_IWUSR) {
+            return Err(FileSystemError::PermissionError);
+        }
+        let new_len = offset
+            .checked_add(len)
+            .ok_or(FileSystemError::InvalidOffset)?;
+
+        self.file.as_mut().unwrap().allocate(new_len)
+    }
+
     /// Read from an in-memory file.
-    pub fn read(&self, buffer: &mut UserSlice, offset: usize) -> Result<usize, FileSystemError> {
+    pub fn read(
+        &self,
+        creds: Credentials,
+        buffer: &mut UserSlice,
+        offset: usize,
+    ) -> Result<usize, FileSystemError> {
         // Return if the user doesn't have read permissions for the file.
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_readable()
-        {
+        if self.node_type != NodeType::File || !self.permits(creds, FileModes::S_IRUSR) {
             return Err(FileSystemError::PermissionError);
         }
 
@@ -134,18 +266,84 @@ impl MemNode {
         self.file.as_ref().unwrap().get_size()
     }
 
+    /// Get how many base and large pages hold the data of the file.
+    pub fn get_page_stats(&self) -> PageStats {
+        self.file.as_ref().unwrap().page_stats()
+    }
+
+    /// Mark the content of the file as a cache that can be dropped.
+    pub fn set_evictable(&mut self) {
+        self.evictable = self.node_type == NodeType::File;
+    }
+
+    /// Can the content of the file be dropped when memory runs low?
+    pub fn is_evictable(&self) -> bool {
+        self.evictable
+    }
+
+    /// Drop (the end of) the content of an evictable file to free at least
+    /// `bytes` of memory, returns the pages that got dropped.
+    ///
+    /// Mapped files keep their content.
+    pub fn evict(&mut self, bytes: usize) -> PageStats {
+        match self.file.as_mut() {
+            Some(file) if self.evictable && !file.is_mapped() => file.evict(bytes),
+            _ => PageStats::default(),
+        }
+    }
+
+    /// Kernel addresses of the pages that hold `[offset, offset+len)` of the
+    /// file, for a mapping that needs `access`.
+    ///
+    /// A process that maps the pages has to call `add_mapping`.
+    pub fn pages(
+        &self,
+        creds: Credentials,
+        access: FileModes,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<usize>, FileSystemError> {
+        match self.file.as_ref() {
+            Some(file) if self.permits(creds, access) => file.pages(offset, len),
+            Some(_) => Err(FileSystemError::PermissionError),
+            None => Err(FileSystemError::DirectoryError),
+        }
+    }
+
+    /// A process mapped pages of the file (see `pages`).
+    pub fn add_mapping(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.add_mapping();
+        }
+    }
+
+    /// A process unmapped pages of the file.
+    pub fn remove_mapping(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.remove_mapping();
+        }
+    }
+
+    /// Does a process map pages of the file?
+    pub fn is_mapped(&self) -> bool {
+        self.file.as_ref().map_or(false, |file| file.is_mapped())
+    }
+
     /// Get the type of mnode; Directory or file.
     pub fn get_mnode_type(&self) -> NodeType {
         self.node_type
     }
 
     /// Truncate the file in reasponse of O_TRUNC flag.
-    pub fn file_truncate(&mut self) -> Result<bool, FileSystemError> {
-        if self.node_type != NodeType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
-        {
+    pub fn file_truncate(&mut self, creds: Credentials) -> Result<bool, FileSystemError> {
+        if self.node_type != NodeType::File || !self.permits(creds, FileModes::S_IWUSR) {
             return Err(FileSystemError::PermissionError);
         }
 
+        if self.is_mapped() {
+            return Err(FileSystemError::StillMapped);
+        }
+
         // The method doesn't fail after this point, so returning Ok().
         self.file.as_mut().unwrap().file_truncate();
         Ok(true)
@@ -157,6 +355,8 @@ pub mod test {
     use super::*;
     use kpi::io::*;
 
+    const ROOT: Credentials = Credentials::ROOT;
+
     #[test]
     /// Create mnode directory and verify the values.
     fn test_mnode_directory() {
@@ -194,7 +394,7 @@ pub mod test {
         assert_eq!(memnode.node_type, NodeType::Directory);
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
         assert_eq!(
-            memnode.write(buffer, 0),
+            memnode.write(ROOT, buffer, 0),
             Err(FileSystemError::PermissionError)
         );
     }
@@ -213,7 +413,7 @@ pub mod test {
         assert_eq!(memnode.name, filename.to_string());
         assert_eq!(memnode.node_type, NodeType::File);
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
     }
 
     #[test]
@@ -231,7 +431,7 @@ pub mod test {
         assert_eq!(memnode.node_type, NodeType::File);
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
         assert_eq!(
-            memnode.write(buffer, 0),
+            memnode.write(ROOT, buffer, 0),
             Err(FileSystemError::PermissionError)
         );
     }
@@ -250,11 +450,15 @@ pub mod test {
         assert_eq!(memnode.name, filename.to_string());
         assert_eq!(memnode.node_type, NodeType::File);
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
         let buffer: &mut [u8; 10] = &mut [0; 10];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -276,7 +480,11 @@ pub mod test {
         assert_eq!(memnode.node_type, NodeType::File);
         let buffer: &[u8; 10] = &[0xb; 10];
         assert_eq!(
-            memnode.read(&mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+            memnode.read(
+                ROOT,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                0
+            ),
             Err(FileSystemError::PermissionError)
         );
     }
@@ -288,14 +496,18 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::File).unwrap();
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
 
         for i in 0..10 {
             //assert_eq!(i, memnode.offset.load(Ordering::Relaxed));
             let buffer: &mut [u8; 1] = &mut [0; 1];
             assert_eq!(
                 memnode
-                    .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 0)
+                    .read(
+                        ROOT,
+                        &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 1) },
+                        0
+                    )
                     .unwrap(),
                 1
             );
@@ -305,7 +517,11 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 1) },
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -319,12 +535,16 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::File).unwrap();
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
 
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 9)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 1) },
+                    9
+                )
                 .unwrap(),
             1
         );
@@ -333,7 +553,11 @@ pub mod test {
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 1) },
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -347,12 +571,16 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::File).unwrap();
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
 
         let buffer: &mut [u8; 1] = &mut [0; 1];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(buffer.as_ptr() as u64, 1), 10)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 1) },
+                    10
+                )
                 .unwrap(),
             0
         );
@@ -367,13 +595,17 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::File).unwrap();
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
 
         let rbuffer: &mut [u8; 10] = &mut [0; 10];
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, 10) },
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -391,10 +623,14 @@ pub mod test {
         let buffer: &mut [u8; 10] = &mut [0xb; 10];
         let rbuffer: &mut [u8; 20] = &mut [0; 20];
 
-        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 0).unwrap(), 10);
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, 10) },
+                    0
+                )
                 .unwrap(),
             10
         );
@@ -402,10 +638,14 @@ pub mod test {
         assert_eq!(rbuffer[9], 0xb);
 
         // This will fill the file between EOF and offset with zeros
-        assert_eq!(memnode.write(buffer, 20).unwrap(), 10);
+        assert_eq!(memnode.write(ROOT, buffer, 20).unwrap(), 10);
         assert_eq!(
             memnode
-                .read(&mut UserSlice::new(rbuffer.as_ptr() as u64, 20), 10)
+                .read(
+                    ROOT,
+                    &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, 20) },
+                    10
+                )
                 .unwrap(),
             20
         );
@@ -422,7 +662,7 @@ pub mod test {
         let filename = "file.txt";
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::File).unwrap();
-        assert_eq!(memnode.file_truncate(), Ok(true));
+        assert_eq!(memnode.file_truncate(ROOT), Ok(true));
     }
 
     #[test]
@@ -432,11 +672,40 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRWXU.into(), NodeType::Directory).unwrap();
         assert_eq!(
-            memnode.file_truncate(),
+            memnode.file_truncate(ROOT),
             Err(FileSystemError::PermissionError)
         );
     }
 
+    #[test]
+    /// Add and remove entries of a directory.
+    fn test_mnode_directory_children() {
+        let mut memnode =
+            MemNode::new(1, "dir", FileModes::S_IRWXU.into(), NodeType::Directory).unwrap();
+        assert_eq!(memnode.add_child("a", 2), Ok(()));
+        assert_eq!(memnode.add_child("b", 3), Ok(()));
+        assert_eq!(
+            memnode.add_child("a", 4),
+            Err(FileSystemError::AlreadyPresent)
+        );
+        assert_eq!(memnode.get_child("a"), Some(2));
+        assert_eq!(memnode.get_children().len(), 2);
+        assert_eq!(memnode.remove_child("a"), Some(2));
+        assert_eq!(memnode.get_child("a"), None);
+    }
+
+    #[test]
+    /// Files can't have entries.
+    fn test_mnode_file_children() {
+        let mut memnode =
+            MemNode::new(1, "file.txt", FileModes::S_IRWXU.into(), NodeType::File).unwrap();
+        assert_eq!(
+            memnode.add_child("a", 2),
+            Err(FileSystemError::NotADirectory)
+        );
+        assert!(memnode.get_children().is_empty());
+    }
+
     #[test]
     /// Test file_truncate for readable file; should fail.
     fn test_file_truncate_for_nonwritable_file() {
@@ -444,8 +713,93 @@ pub mod test {
         let mut memnode =
             MemNode::new(1, filename, FileModes::S_IRUSR.into(), NodeType::File).unwrap();
         assert_eq!(
-            memnode.file_truncate(),
+            memnode.file_truncate(ROOT),
+            Err(FileSystemError::PermissionError)
+        );
+    }
+
+    #[test]
+    /// The owner, group or other bits apply depending on who accesses the file.
+    fn test_mnode_permissions() {
+        let owner = Credentials { uid: 1, gid: 1 };
+        let member = Credentials { uid: 2, gid: 1 };
+        let stranger = Credentials { uid: 3, gid: 3 };
+
+        let modes = FileModes::S_IRWXU | FileModes::S_IRGRP;
+        let mut memnode = MemNode::new(1, "file.txt", modes.into(), NodeType::File).unwrap();
+        memnode.set_owner(owner);
+        assert_eq!(memnode.get_owner(), owner);
+
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(memnode.write(owner, buffer, 0), Ok(10));
+        assert_eq!(
+            memnode.write(member, buffer, 0),
+            Err(FileSystemError::PermissionError)
+        );
+        assert_eq!(
+            memnode.read(
+                member,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                0
+            ),
+            Ok(10)
+        );
+        assert_eq!(
+            memnode.read(
+                stranger,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                0
+            ),
+            Err(FileSystemError::PermissionError)
+        );
+        // Root isn't special here
+        assert_eq!(
+            memnode.file_truncate(ROOT),
+            Err(FileSystemError::PermissionError)
+        );
+        assert_eq!(memnode.file_truncate(owner), Ok(true));
+    }
+
+    #[test]
+    /// Allocating grows the file with zeros but never shrinks it.
+    fn test_mnode_file_allocate() {
+        let mut memnode =
+            MemNode::new(1, "file.txt", FileModes::S_IRWXU.into(), NodeType::File).unwrap();
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(memnode.write(ROOT, buffer, 0), Ok(10));
+
+        assert_eq!(memnode.allocate(ROOT, 0, 5), Ok(()));
+        assert_eq!(memnode.get_file_size(), 10);
+        assert_eq!(memnode.allocate(ROOT, 4096, 8192), Ok(()));
+        assert_eq!(memnode.get_file_size(), 3 * 4096);
+        assert_eq!(memnode.get_page_stats().base_pages, 3);
+
+        let buffer: &mut [u8; 10] = &mut [0xff; 10];
+        assert_eq!(
+            memnode.read(
+                ROOT,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                5
+            ),
+            Ok(10)
+        );
+        assert_eq!(buffer, &[0xb, 0xb, 0xb, 0xb, 0xb, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            memnode.allocate(ROOT, usize::max_value(), 1),
+            Err(FileSystemError::InvalidOffset)
+        );
+    }
+
+    #[test]
+    /// Allocating needs write permissions.
+    fn test_mnode_file_allocate_permission_error() {
+        let mut memnode =
+            MemNode::new(1, "file.txt", FileModes::S_IRUSR.into(), NodeType::File).unwrap();
+        assert_eq!(
+            memnode.allocate(ROOT, 0, 10),
             Err(FileSystemError::PermissionError)
         );
+        assert_eq!(memnode.get_file_size(), 0);
     }
 }
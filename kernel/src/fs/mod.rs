@@ -1,8 +1,10 @@
 //! The core module for file management.
 
 use crate::arch::process::UserSlice;
+use crate::process::{Credentials, Pid};
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use custom_error::custom_error;
@@ -11,10 +13,12 @@ use hashbrown::HashMap;
 use kpi::io::*;
 use kpi::SystemCallError;
 
+pub use crate::fs::file::PageStats;
 pub use crate::fs::mnode::{MemNode, NodeType};
 
 mod file;
 mod mnode;
+pub mod pipe;
 #[cfg(test)]
 mod test;
 
@@ -48,8 +52,14 @@ custom_error! {
     PermissionError = "File/directory can't be read or written",
     AlreadyPresent = "Fd/File already exists",
     DirectoryError = "Can't read or write to a directory",
+    NotADirectory = "A component of the path isn't a directory",
+    DirectoryNotEmpty = "Directory isn't empty",
     OpenFileLimit = "Maximum files are opened for a process",
     OutOfMemory = "Unable to allocate memory for file",
+    WouldBlock = "Pipe is empty or full",
+    BrokenPipe = "Pipe has no reader",
+    QuotaExceeded = "Process can't store more bytes in files",
+    StillMapped = "File is mapped by a process",
 }
 
 impl Into<SystemCallError> for FileSystemError {
@@ -62,33 +72,99 @@ impl Into<SystemCallError> for FileSystemError {
             FileSystemError::PermissionError => SystemCallError::PermissionError,
             FileSystemError::AlreadyPresent => SystemCallError::PermissionError,
             FileSystemError::DirectoryError => SystemCallError::PermissionError,
+            FileSystemError::NotADirectory => SystemCallError::PermissionError,
+            FileSystemError::DirectoryNotEmpty => SystemCallError::PermissionError,
             FileSystemError::OpenFileLimit => SystemCallError::OutOfMemory,
             FileSystemError::OutOfMemory => SystemCallError::OutOfMemory,
+            FileSystemError::WouldBlock => SystemCallError::WouldBlock,
+            FileSystemError::BrokenPipe => SystemCallError::BrokenPipe,
+            FileSystemError::QuotaExceeded => SystemCallError::QuotaExceeded,
+            FileSystemError::StillMapped => SystemCallError::StillMapped,
         }
     }
 }
 
 /// Abstract definition of file-system interface operations.
+///
+/// Operations that access or change files take the credentials of the
+/// process they are done for, they fail with `PermissionError` if the modes
+/// of the file (or of the directory that contains it) don't allow them.
 pub trait FileSystem {
-    fn create(&mut self, pathname: &str, modes: Modes) -> Result<u64, FileSystemError>;
+    fn create(
+        &mut self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<u64, FileSystemError>;
     fn write(
         &mut self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &[u8],
         offset: usize,
     ) -> Result<usize, FileSystemError>;
     fn read(
         &self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &mut UserSlice,
         offset: usize,
     ) -> Result<usize, FileSystemError>;
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>>;
     fn file_info(&self, mnode: Mnode) -> FileInfo;
-    fn delete(&mut self, pathname: &str) -> Result<bool, FileSystemError>;
-    fn truncate(&mut self, pathname: &str) -> Result<bool, FileSystemError>;
-    fn rename(&mut self, oldname: &str, newname: &str) -> Result<bool, FileSystemError>;
-    fn mkdir(&mut self, pathname: &str, modes: Modes) -> Result<bool, FileSystemError>;
+    fn delete(&mut self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError>;
+    fn truncate(&mut self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError>;
+    fn allocate(
+        &mut self,
+        creds: Credentials,
+        mnode_num: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError>;
+    fn rename(
+        &mut self,
+        creds: Credentials,
+        oldname: &str,
+        newname: &str,
+    ) -> Result<bool, FileSystemError>;
+    fn mkdir(
+        &mut self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<bool, FileSystemError>;
+    fn readdir(&self, pathname: &str) -> Result<Vec<DirEntry>, FileSystemError>;
+}
+
+/// Brings `pathname` in the form that is used to look up files: the path
+/// components are separated by a single '/', there is no leading or trailing
+/// '/', and "." and ".." are resolved. The root directory is "/".
+pub fn canonicalize(pathname: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in pathname.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    if components.is_empty() {
+        String::from("/")
+    } else {
+        components.join("/")
+    }
+}
+
+/// Splits a canonical path (that isn't the root) in the path of the parent
+/// directory and the name of the entry.
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("/", path),
+    }
 }
 
 /// Abstract definition of a file descriptor.
@@ -99,6 +175,12 @@ pub trait FileDescriptor {
     fn get_flags(&self) -> FileFlags;
     fn get_offset(&self) -> usize;
     fn update_offset(&self, new_offset: usize);
+    fn seek(
+        &self,
+        offset: i64,
+        whence: SeekWhence,
+        file_size: usize,
+    ) -> Result<usize, FileSystemError>;
 }
 
 /// A file descriptor representaion.
@@ -139,9 +221,159 @@ impl FileDescriptor for Fd {
     fn update_offset(&self, new_offset: usize) {
         self.offset.store(new_offset, Ordering::Release);
     }
+
+    /// Move the offset relative to the start, the current offset or the end
+    /// of the file (of size `file_size`). It's fine to move past the end of
+    /// the file, but not before the start.
+    fn seek(
+        &self,
+        offset: i64,
+        whence: SeekWhence,
+        file_size: usize,
+    ) -> Result<usize, FileSystemError> {
+        let base = match whence {
+            SeekWhence::Set => 0,
+            SeekWhence::Cur => self.get_offset() as i64,
+            SeekWhence::End => file_size as i64,
+        };
+
+        match base.checked_add(offset) {
+            Some(new_offset) if new_offset >= 0 => {
+                self.update_offset(new_offset as usize);
+                Ok(new_offset as usize)
+            }
+            _ => Err(FileSystemError::InvalidOffset),
+        }
+    }
+}
+
+/// Duplicates a file descriptor (e.g., when a process forks), the copy starts
+/// at the current offset but moves independently of the original.
+impl Clone for Fd {
+    fn clone(&self) -> Fd {
+        Fd {
+            mnode: self.mnode,
+            flags: self.flags,
+            offset: AtomicUsize::new(self.get_offset()),
+        }
+    }
+}
+
+/// Keeps track of how many bytes every process stored in files.
+///
+/// A file is charged to the process that created it (no matter who writes
+/// to it later). With a quota (`fsquota=` on the command-line), changes that
+/// would grow the files of a process beyond it fail with `QuotaExceeded`.
+///
+/// Lives next to the file-system in the replicated state, so callers have
+/// to update it in the same operation that changes a file.
+#[derive(Debug, Default)]
+pub struct FsAccounting {
+    /// The process every (regular) file is charged to.
+    owners: HashMap<Mnode, Pid>,
+    /// Bytes charged to every process.
+    bytes: HashMap<Pid, usize>,
+    /// How many bytes a single process may store.
+    quota: Option<usize>,
 }
 
+impl FsAccounting {
+    pub fn new(quota: Option<usize>) -> FsAccounting {
+        FsAccounting {
+            quota,
+            ..Default::default()
+        }
+    }
+
+    /// The quota of the kernel command-line (if there is one).
+    pub fn with_cmdline_quota() -> FsAccounting {
+        FsAccounting::new(crate::kcb::try_get_kcb().and_then(|kcb| kcb.cmdline.fs_quota))
+    }
+
+    /// Charge the (new and empty) file `mnode` to `pid`.
+    pub fn add_file(&mut self, mnode: Mnode, pid: Pid) {
+        self.owners.insert(mnode, pid);
+    }
+
+    /// Charge the bytes file `mnode` grows by if `len` bytes are written at
+    /// `offset`, returns how many bytes got charged.
+    ///
+    /// `file_size` is only called for files that are charged to a process
+    /// (so it can rely on `mnode` still existing). Fails (and doesn't charge
+    /// anything) if this would grow the files of the owner beyond its quota.
+    pub fn charge_write<F: FnOnce() -> usize>(
+        &mut self,
+        mnode: Mnode,
+        file_size: F,
+        offset: usize,
+        len: usize,
+    ) -> Result<usize, FileSystemError> {
+        let owner = match self.owners.get(&mnode) {
+            Some(owner) => *owner,
+            // Files created outside of a process (or pipes) aren't accounted
+            None => return Ok(0),
+        };
+
+        let size = file_size();
+        let end = offset
+            .checked_add(len)
+            .ok_or(FileSystemError::InvalidOffset)?;
+        let growth = end.saturating_sub(size);
+
+        let bytes = self.bytes.entry(owner).or_insert(0);
+        let charged = bytes
+            .checked_add(growth)
+            .ok_or(FileSystemError::QuotaExceeded)?;
+        if self.quota.map_or(false, |quota| charged > quota) {
+            return Err(FileSystemError::QuotaExceeded);
+        }
+        *bytes = charged;
+
+        Ok(growth)
+    }
+
+    /// Give `size` bytes of file `mnode` back to the owner (e.g., because
+    /// it got truncated or a write failed).
+    pub fn uncharge(&mut self, mnode: Mnode, size: usize) {
+        if let Some(owner) = self.owners.get(&mnode) {
+            if let Some(bytes) = self.bytes.get_mut(owner) {
+                *bytes = bytes.saturating_sub(size);
+            }
+        }
+    }
+
+    /// Remove file `mnode` (of `size` bytes) and give its bytes back to the
+    /// owner.
+    pub fn remove_file(&mut self, mnode: Mnode, size: usize) {
+        self.uncharge(mnode, size);
+        self.owners.remove(&mnode);
+    }
+
+    /// Bytes stored in the files charged to `pid`.
+    pub fn usage(&self, pid: Pid) -> usize {
+        self.bytes.get(&pid).cloned().unwrap_or(0)
+    }
+
+    /// How many bytes a process may store (None if it's unlimited).
+    pub fn quota(&self) -> Option<usize> {
+        self.quota
+    }
+}
+
+/// Modes of the root directory, everyone can create files in it.
+pub const ROOT_DIRECTORY_MODES: FileModes = FileModes::all();
+
 /// The in-memory file-system representation.
+///
+/// The directory tree is kept in the mnodes (every directory knows its
+/// entries, every mnode its parent), `files` maps the canonical path of an
+/// mnode to its number.
+///
+/// There are no per-mnode locks: every change goes through the NR log and
+/// NR applies the log to a replica one operation at a time, so locking
+/// single files wouldn't let two writes run at the same time (reads already
+/// share the replica). The file system that synchronizes every file on its
+/// own is `mlnrfs::MlnrFS` (feature `mlnrfs`).
 #[derive(Debug)]
 pub struct MemFS {
     mnodes: HashMap<Mnode, MemNode>,
@@ -155,6 +387,160 @@ impl MemFS {
     fn get_next_mno(&mut self) -> usize {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Find the mnode of a (canonical) path by walking the directory tree
+    /// from the root, one path component at a time.
+    fn resolve(&self, path: &str) -> Result<Mnode, FileSystemError> {
+        let mut current = self.root.1;
+        if path == self.root.0 {
+            return Ok(current);
+        }
+
+        for component in path.split('/') {
+            let memnode = self
+                .mnodes
+                .get(&current)
+                .ok_or(FileSystemError::InvalidFile)?;
+            if memnode.get_mnode_type() != NodeType::Directory {
+                return Err(FileSystemError::NotADirectory);
+            }
+            current = memnode
+                .get_child(component)
+                .ok_or(FileSystemError::InvalidFile)?;
+        }
+
+        Ok(current)
+    }
+
+    /// Find the directory that should contain the (canonical) path.
+    fn resolve_parent(&self, path: &str) -> Result<Mnode, FileSystemError> {
+        let parent = self.resolve(split_path(path).0)?;
+        match self.mnodes.get(&parent) {
+            Some(memnode) if memnode.get_mnode_type() == NodeType::Directory => Ok(parent),
+            Some(_) => Err(FileSystemError::NotADirectory),
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// Check that `creds` may add or remove entries of directory `dir`.
+    fn check_dir_writable(&self, creds: Credentials, dir: Mnode) -> Result<(), FileSystemError> {
+        match self.mnodes.get(&dir) {
+            Some(memnode) if memnode.permits(creds, FileModes::S_IWUSR) => Ok(()),
+            Some(_) => Err(FileSystemError::PermissionError),
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// Create a new file or directory (owned by `creds`) and add it to its
+    /// parent directory.
+    fn insert(
+        &mut self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+        node_type: NodeType,
+    ) -> Result<Mnode, FileSystemError> {
+        let path = canonicalize(pathname);
+        // Check if the file with the same name already exists.
+        if self.files.contains_key(&path) {
+            return Err(FileSystemError::AlreadyPresent);
+        }
+        let parent = self.resolve_parent(&path)?;
+        self.check_dir_writable(creds, parent)?;
+
+        let mnode_num = self.get_next_mno() as u64;
+        let mut memnode = MemNode::new(mnode_num, &path, modes, node_type)?;
+        memnode.set_owner(creds);
+        memnode.set_parent(parent);
+        self.mnodes
+            .get_mut(&parent)
+            .ok_or(FileSystemError::InvalidFile)?
+            .add_child(split_path(&path).1, mnode_num)?;
+        self.files.insert(path, Arc::new(mnode_num));
+        self.mnodes.insert(mnode_num, memnode);
+
+        Ok(mnode_num)
+    }
+
+    /// Mark the content of a file as a cache (it was opened with
+    /// `O_EVICTABLE`), `evict` may drop it.
+    pub fn set_evictable(&mut self, mnode_num: Mnode) -> Result<(), FileSystemError> {
+        match self.mnodes.get_mut(&mnode_num) {
+            Some(mnode) => {
+                mnode.set_evictable();
+                Ok(())
+            }
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// Drop clean pages of evictable files until at least `bytes` of memory
+    /// are free (or there is nothing left to drop).
+    ///
+    /// The pages of the files in `busy` (that are open for writing) are
+    /// dirty and stay. The oldest files lose their content first and every
+    /// file is cut from the end, so it just gets shorter.
+    ///
+    /// Returns the files that got shorter with how many bytes of data and
+    /// which pages they lost.
+    pub fn evict(&mut self, bytes: usize, busy: &[Mnode]) -> Vec<(Mnode, usize, PageStats)> {
+        let mut candidates: Vec<Mnode> = self
+            .mnodes
+            .iter()
+            .filter(|(mnode_num, mnode)| !busy.contains(*mnode_num) && mnode.is_evictable())
+            .map(|(mnode_num, _mnode)| *mnode_num)
+            .collect();
+        // Every replica has to pick the same files
+        candidates.sort_unstable();
+
+        let mut evicted = Vec::new();
+        let mut freed = 0;
+        for mnode_num in candidates {
+            if freed >= bytes {
+                break;
+            }
+            if let Some(mnode) = self.mnodes.get_mut(&mnode_num) {
+                let size = mnode.get_file_size();
+                let pages = mnode.evict(bytes - freed);
+                freed += pages.bytes();
+                if pages != PageStats::default() {
+                    evicted.push((mnode_num, size - mnode.get_file_size(), pages));
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Kernel addresses of the pages that hold `[offset, offset+len)` of a
+    /// file, for a mapping that needs `access` (see `MemNode::pages`).
+    pub fn pages(
+        &self,
+        creds: Credentials,
+        mnode_num: Mnode,
+        access: FileModes,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<usize>, FileSystemError> {
+        match self.mnodes.get(&mnode_num) {
+            Some(mnode) => mnode.pages(creds, access, offset, len),
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    /// A process mapped pages of a file, it can't be deleted, truncated or
+    /// evicted until `remove_mapping` is called as often.
+    pub fn add_mapping(&mut self, mnode_num: Mnode) {
+        if let Some(mnode) = self.mnodes.get_mut(&mnode_num) {
+            mnode.add_mapping();
+        }
+    }
+
+    /// A process unmapped pages of a file.
+    pub fn remove_mapping(&mut self, mnode_num: Mnode) {
+        if let Some(mnode) = self.mnodes.get_mut(&mnode_num) {
+            mnode.remove_mapping();
+        }
+    }
 }
 
 impl Default for MemFS {
@@ -169,7 +555,7 @@ impl Default for MemFS {
             MemNode::new(
                 rootmnode,
                 rootdir,
-                FileModes::S_IRWXU.into(),
+                ROOT_DIRECTORY_MODES.into(),
                 NodeType::Directory,
             )
             .unwrap(),
@@ -189,35 +575,27 @@ impl Default for MemFS {
 
 impl FileSystem for MemFS {
     /// Create a file relative to the root directory.
-    fn create(&mut self, pathname: &str, modes: Modes) -> Result<u64, FileSystemError> {
-        // Check if the file with the same name already exists.
-        match self.files.get(&pathname.to_string()) {
-            Some(_) => return Err(FileSystemError::AlreadyPresent),
-            None => {}
-        }
-
-        let mnode_num = self.get_next_mno() as u64;
+    fn create(
+        &mut self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<u64, FileSystemError> {
         //TODO: For now all newly created mnode are for file. How to differentiate
         // between a file and a directory. Take input from the user?
-        let memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::File) {
-            Ok(memnode) => memnode,
-            Err(e) => return Err(e),
-        };
-        self.files.insert(pathname.to_string(), Arc::new(mnode_num));
-        self.mnodes.insert(mnode_num, memnode);
-
-        Ok(mnode_num)
+        self.insert(creds, pathname, modes, NodeType::File)
     }
 
     /// Write data to a file.
     fn write(
         &mut self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &[u8],
         offset: usize,
     ) -> Result<usize, FileSystemError> {
         match self.mnodes.get_mut(&mnode_num) {
-            Some(mnode) => mnode.write(buffer, offset),
+            Some(mnode) => mnode.write(creds, buffer, offset),
             None => Err(FileSystemError::InvalidFile),
         }
     }
@@ -225,12 +603,13 @@ impl FileSystem for MemFS {
     /// Read data from a file.
     fn read(
         &self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &mut UserSlice,
         offset: usize,
     ) -> Result<usize, FileSystemError> {
         match self.mnodes.get(&mnode_num) {
-            Some(mnode) => mnode.read(buffer, offset),
+            Some(mnode) => mnode.read(creds, buffer, offset),
             None => Err(FileSystemError::InvalidFile),
         }
     }
@@ -238,7 +617,7 @@ impl FileSystem for MemFS {
     /// Check if a file exists in the file system or not.
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
         self.files
-            .get(&pathname.to_string())
+            .get(&canonicalize(pathname))
             .map(|mnode| Arc::clone(mnode))
     }
 
@@ -249,81 +628,185 @@ impl FileSystem for MemFS {
                 NodeType::Directory => FileInfo {
                     fsize: 0,
                     ftype: NodeType::Directory.into(),
+                    ..Default::default()
                 },
-                NodeType::File => FileInfo {
-                    fsize: mnode.get_file_size() as u64,
-                    ftype: NodeType::File.into(),
-                },
+                NodeType::File => {
+                    let stats = mnode.get_page_stats();
+                    FileInfo {
+                        fsize: mnode.get_file_size() as u64,
+                        ftype: NodeType::File.into(),
+                        base_pages: stats.base_pages as u64,
+                        large_pages: stats.large_pages as u64,
+                    }
+                }
             },
             None => unreachable!("file_info: shouldn't reach here"),
         }
     }
 
-    /// Delete a file from the file-system.
-    fn delete(&mut self, pathname: &str) -> Result<bool, FileSystemError> {
-        match self.files.remove(&pathname.to_string()) {
-            Some(mnode) => {
-                // If the pathname is the only link to the memnode, then remove it.
-                match Arc::strong_count(&mnode) {
-                    1 => {
-                        self.mnodes.remove(&mnode);
-                        return Ok(true);
-                    }
-                    _ => {
-                        self.files.insert(pathname.to_string(), mnode);
-                        return Err(FileSystemError::PermissionError);
-                    }
-                }
+    /// Delete a file or an (empty) directory from the file-system.
+    ///
+    /// This needs write permission for the directory that contains it.
+    fn delete(&mut self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
+        let path = canonicalize(pathname);
+        if path == self.root.0 {
+            return Err(FileSystemError::PermissionError);
+        }
+
+        let mnode_num = match self.files.get(&path) {
+            // If the pathname is the only link to the memnode, then remove it.
+            Some(mnode) if Arc::strong_count(mnode) == 1 => **mnode,
+            Some(_) => return Err(FileSystemError::PermissionError),
+            None => return Err(FileSystemError::InvalidFile),
+        };
+
+        let parent = match self.mnodes.get(&mnode_num) {
+            Some(memnode) if !memnode.get_children().is_empty() => {
+                return Err(FileSystemError::DirectoryNotEmpty)
             }
+            Some(memnode) if memnode.is_mapped() => return Err(FileSystemError::StillMapped),
+            Some(memnode) => memnode.get_parent(),
             None => return Err(FileSystemError::InvalidFile),
         };
+        if let Some(parent) = parent {
+            self.check_dir_writable(creds, parent)?;
+        }
+
+        if let Some(parent) = parent.and_then(|parent| self.mnodes.get_mut(&parent)) {
+            parent.remove_child(split_path(&path).1);
+        }
+        self.files.remove(&path);
+        self.mnodes.remove(&mnode_num);
+
+        Ok(true)
     }
 
-    fn truncate(&mut self, pathname: &str) -> Result<bool, FileSystemError> {
-        match self.files.get(&pathname.to_string()) {
+    fn truncate(&mut self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
+        match self.files.get(&canonicalize(pathname)) {
             Some(mnode) => match self.mnodes.get_mut(mnode) {
-                Some(memnode) => memnode.file_truncate(),
+                Some(memnode) => memnode.file_truncate(creds),
                 None => return Err(FileSystemError::InvalidFile),
             },
             None => return Err(FileSystemError::InvalidFile),
         }
     }
 
-    /// Rename a file from oldname to newname.
-    fn rename(&mut self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
-        if self.files.get(oldname).is_none() {
-            return Err(FileSystemError::InvalidFile);
+    /// Reserve memory for `[offset, offset+len)` of a file.
+    fn allocate(
+        &mut self,
+        creds: Credentials,
+        mnode_num: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError> {
+        match self.mnodes.get_mut(&mnode_num) {
+            Some(mnode) => mnode.allocate(creds, offset, len),
+            None => Err(FileSystemError::InvalidFile),
         }
+    }
 
-        // If the newfile exists then overwrite it with the oldfile.
-        if self.files.get(newname).is_some() {
-            self.delete(newname).unwrap();
+    /// Rename (or move) a file or directory from oldname to newname.
+    ///
+    /// This needs write permission for both directories involved.
+    fn rename(
+        &mut self,
+        creds: Credentials,
+        oldname: &str,
+        newname: &str,
+    ) -> Result<bool, FileSystemError> {
+        let oldpath = canonicalize(oldname);
+        let newpath = canonicalize(newname);
+        let mnode_num = match self.files.get(&oldpath) {
+            Some(mnode) => **mnode,
+            None => return Err(FileSystemError::InvalidFile),
+        };
+
+        if oldpath == self.root.0 || newpath == self.root.0 {
+            return Err(FileSystemError::PermissionError);
+        }
+        if oldpath == newpath {
+            return Ok(true);
         }
+        // A directory can't be moved into itself.
+        let prefix = alloc::format!("{}/", oldpath);
+        if newpath.starts_with(&prefix) {
+            return Err(FileSystemError::PermissionError);
+        }
+        let new_parent = self.resolve_parent(&newpath)?;
+        let old_parent = self
+            .mnodes
+            .get(&mnode_num)
+            .and_then(|memnode| memnode.get_parent());
+        if let Some(parent) = old_parent {
+            self.check_dir_writable(creds, parent)?;
+        }
+        self.check_dir_writable(creds, new_parent)?;
 
-        let (_key, value) = self.files.remove_entry(oldname).unwrap();
-        match self.files.insert(newname.to_string(), value) {
-            None => return Ok(true),
-            Some(_) => return Err(FileSystemError::PermissionError),
+        // If the newfile exists then overwrite it with the oldfile.
+        if self.files.contains_key(&newpath) {
+            self.delete(creds, &newpath)?;
         }
-    }
 
-    /// Create a directory. The implementation is quite simplistic for now, and only used
-    /// by leveldb benchmark.
-    fn mkdir(&mut self, pathname: &str, modes: Modes) -> Result<bool, FileSystemError> {
-        // Check if the file with the same name already exists.
-        match self.files.get(&pathname.to_string()) {
-            Some(_) => return Err(FileSystemError::AlreadyPresent),
-            None => {}
+        // Move the mnode to the new directory.
+        if let Some(parent) = old_parent.and_then(|parent| self.mnodes.get_mut(&parent)) {
+            parent.remove_child(split_path(&oldpath).1);
+        }
+        if let Some(parent) = self.mnodes.get_mut(&new_parent) {
+            parent.add_child(split_path(&newpath).1, mnode_num)?;
+        }
+        if let Some(memnode) = self.mnodes.get_mut(&mnode_num) {
+            memnode.set_parent(new_parent);
         }
 
-        let mnode_num = self.get_next_mno() as u64;
-        let memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::Directory) {
-            Ok(memnode) => memnode,
-            Err(e) => return Err(e),
-        };
-        self.files.insert(pathname.to_string(), Arc::new(mnode_num));
-        self.mnodes.insert(mnode_num, memnode);
+        // Update the path of the mnode and of everything below it.
+        let moved: Vec<String> = self
+            .files
+            .keys()
+            .filter(|path| **path == oldpath || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in moved {
+            let renamed = alloc::format!("{}{}", newpath, &path[oldpath.len()..]);
+            if let Some(mnode) = self.files.remove(&path) {
+                if let Some(memnode) = self.mnodes.get_mut(&mnode) {
+                    memnode.set_name(&renamed);
+                }
+                self.files.insert(renamed, mnode);
+            }
+        }
 
         Ok(true)
     }
+
+    /// Create a directory.
+    fn mkdir(
+        &mut self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<bool, FileSystemError> {
+        self.insert(creds, pathname, modes, NodeType::Directory)
+            .map(|_mnode_num| true)
+    }
+
+    /// List the entries of a directory.
+    fn readdir(&self, pathname: &str) -> Result<Vec<DirEntry>, FileSystemError> {
+        let mnode = self.resolve(&canonicalize(pathname))?;
+        let memnode = self
+            .mnodes
+            .get(&mnode)
+            .ok_or(FileSystemError::InvalidFile)?;
+        if memnode.get_mnode_type() != NodeType::Directory {
+            return Err(FileSystemError::NotADirectory);
+        }
+
+        Ok(memnode
+            .get_children()
+            .iter()
+            .map(|(name, mnode)| DirEntry {
+                name: name.clone(),
+                ftype: self.file_info(*mnode).ftype,
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,367 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte buffer with a read and a write end. The ends are
+//! regular file descriptors of the process (allocated through NR, so they
+//! don't collide with files, their mnode is `PIPE_MNODE`), but the buffer
+//! isn't part of the replicated file-system state: reading consumes the data,
+//! so it has to happen exactly once and not once per replica. That's why the
+//! system call layer checks `is_pipe` and handles pipe I/O here, before it
+//! goes to NR.
+//!
+//! Pipes never block in the kernel: reading from an empty pipe (that still has
+//! a writer) or writing to a full pipe fails with `WouldBlock` and user-space
+//! lets another thread run before it tries again.
+
+use alloc::collections::VecDeque;
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::fs::{FileSystemError, Mnode, FD};
+use crate::process::Pid;
+
+/// How many bytes a pipe can hold.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// The mnode of file descriptors that refer to a pipe end.
+pub const PIPE_MNODE: Mnode = u64::MAX - 1;
+
+/// Identifies a pipe.
+type PipeId = u64;
+
+/// Which end of a pipe a file descriptor refers to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PipeEnd {
+    Read,
+    Write,
+}
+
+/// The buffer of a pipe.
+#[derive(Debug)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    /// Open read ends.
+    readers: usize,
+    /// Open write ends.
+    writers: usize,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            buffer: VecDeque::new(),
+            readers: 1,
+            writers: 1,
+        }
+    }
+
+    /// Reads up to `buf.len()` bytes, 0 means the pipe is empty and nobody
+    /// can write to it anymore.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+        if self.buffer.is_empty() {
+            return if self.writers == 0 || buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(FileSystemError::WouldBlock)
+            };
+        }
+
+        let len = core::cmp::min(buf.len(), self.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.buffer.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    /// Writes as much of `buf` as fits in the pipe.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, FileSystemError> {
+        if self.readers == 0 {
+            return Err(FileSystemError::BrokenPipe);
+        }
+
+        let len = core::cmp::min(buf.len(), PIPE_CAPACITY - self.buffer.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(FileSystemError::WouldBlock);
+        }
+        self.buffer
+            .try_reserve(len)
+            .map_err(|_e| FileSystemError::OutOfMemory)?;
+        self.buffer.extend(&buf[..len]);
+        Ok(len)
+    }
+}
+
+#[derive(Default)]
+struct Pipes {
+    next_id: PipeId,
+    pipes: HashMap<PipeId, Pipe>,
+    /// The pipe (end) every pipe file descriptor refers to.
+    ends: HashMap<(Pid, FD), (PipeId, PipeEnd)>,
+}
+
+impl Pipes {
+    fn create(&mut self, pid: Pid, read_fd: FD, write_fd: FD) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pipes.insert(id, Pipe::new());
+        self.ends.insert((pid, read_fd), (id, PipeEnd::Read));
+        self.ends.insert((pid, write_fd), (id, PipeEnd::Write));
+    }
+
+    fn pipe(&mut self, pid: Pid, fd: FD, end: PipeEnd) -> Result<&mut Pipe, FileSystemError> {
+        match self.ends.get(&(pid, fd)) {
+            Some((id, e)) if *e == end => self
+                .pipes
+                .get_mut(id)
+                .ok_or(FileSystemError::InvalidFileDescriptor),
+            Some(_) => Err(FileSystemError::PermissionError),
+            None => Err(FileSystemError::InvalidFileDescriptor),
+        }
+    }
+
+    fn fork(&mut self, parent: Pid, child: Pid) {
+        let ends: alloc::vec::Vec<(FD, (PipeId, PipeEnd))> = self
+            .ends
+            .iter()
+            .filter(|((p, _fd), _end)| *p == parent)
+            .map(|((_p, fd), end)| (*fd, *end))
+            .collect();
+        for (fd, (id, end)) in ends {
+            if let Some(pipe) = self.pipes.get_mut(&id) {
+                match end {
+                    PipeEnd::Read => pipe.readers += 1,
+                    PipeEnd::Write => pipe.writers += 1,
+                }
+                self.ends.insert((child, fd), (id, end));
+            }
+        }
+    }
+
+    fn transfer(&mut self, from: Pid, to: Pid) {
+        let ends: alloc::vec::Vec<FD> = self
+            .ends
+            .keys()
+            .filter(|(p, _fd)| *p == from)
+            .map(|(_p, fd)| *fd)
+            .collect();
+        for fd in ends {
+            if let Some(end) = self.ends.remove(&(from, fd)) {
+                self.ends.insert((to, fd), end);
+            }
+        }
+    }
+
+    fn close(&mut self, pid: Pid, fd: FD) {
+        if let Some((id, end)) = self.ends.remove(&(pid, fd)) {
+            let unused = match self.pipes.get_mut(&id) {
+                Some(pipe) => {
+                    match end {
+                        PipeEnd::Read => pipe.readers -= 1,
+                        PipeEnd::Write => pipe.writers -= 1,
+                    }
+                    pipe.readers == 0 && pipe.writers == 0
+                }
+                None => false,
+            };
+            if unused {
+                self.pipes.remove(&id);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref PIPES: Mutex<Pipes> = Mutex::new(Default::default());
+}
+
+/// Registers a new pipe with the (already allocated) file descriptors
+/// `read_fd` and `write_fd` of process `pid`.
+pub fn create(pid: Pid, read_fd: FD, write_fd: FD) {
+    PIPES.lock().create(pid, read_fd, write_fd);
+}
+
+/// Does `fd` of process `pid` refer to a pipe?
+pub fn is_pipe(pid: Pid, fd: FD) -> bool {
+    PIPES.lock().ends.contains_key(&(pid, fd))
+}
+
+/// Reads from the pipe `fd` into `buf`, returns how many bytes were read.
+pub fn read(pid: Pid, fd: FD, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+    PIPES.lock().pipe(pid, fd, PipeEnd::Read)?.read(buf)
+}
+
+/// Writes `buf` to the pipe `fd`, returns how many bytes were written.
+pub fn write(pid: Pid, fd: FD, buf: &[u8]) -> Result<usize, FileSystemError> {
+    PIPES.lock().pipe(pid, fd, PipeEnd::Write)?.write(buf)
+}
+
+/// Closes the pipe end `fd` (the file descriptor itself is released by NR).
+pub fn close(pid: Pid, fd: FD) {
+    PIPES.lock().close(pid, fd);
+}
+
+/// Gives the forked process `child` the pipe ends of `parent` (at the same
+/// file descriptors).
+pub fn fork(parent: Pid, child: Pid) {
+    PIPES.lock().fork(parent, child);
+}
+
+/// Hands the pipe ends of the exited process `from` to the process `to` that
+/// replaces it (at the same file descriptors).
+pub fn transfer(from: Pid, to: Pid) {
+    PIPES.lock().transfer(from, to);
+}
+
+/// Closes all pipe ends of an exited process.
+pub fn close_all(pid: Pid) {
+    let mut pipes = PIPES.lock();
+    let fds: alloc::vec::Vec<FD> = pipes
+        .ends
+        .keys()
+        .filter(|(p, _fd)| *p == pid)
+        .map(|(_p, fd)| *fd)
+        .collect();
+    for fd in fds {
+        pipes.close(pid, fd);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pipe_read_write() {
+        let mut pipes: Pipes = Default::default();
+        pipes.create(1, 3, 4);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf),
+            Err(FileSystemError::WouldBlock)
+        );
+        assert_eq!(
+            pipes.pipe(1, 4, PipeEnd::Write).unwrap().write(b"hello"),
+            Ok(5)
+        );
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf[..3]),
+            Ok(3)
+        );
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf),
+            Ok(2)
+        );
+        assert_eq!(&buf[..2], b"lo");
+
+        // Wrong ends and unknown fds
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Write).unwrap_err(),
+            FileSystemError::PermissionError
+        );
+        assert_eq!(
+            pipes.pipe(2, 3, PipeEnd::Read).unwrap_err(),
+            FileSystemError::InvalidFileDescriptor
+        );
+    }
+
+    #[test]
+    fn pipe_full() {
+        let mut pipes: Pipes = Default::default();
+        pipes.create(1, 3, 4);
+
+        let data = alloc::vec![0xa; PIPE_CAPACITY + 1];
+        let pipe = pipes.pipe(1, 4, PipeEnd::Write).unwrap();
+        assert_eq!(pipe.write(&data), Ok(PIPE_CAPACITY));
+        assert_eq!(pipe.write(&data), Err(FileSystemError::WouldBlock));
+    }
+
+    #[test]
+    fn pipe_close() {
+        let mut pipes: Pipes = Default::default();
+        pipes.create(1, 3, 4);
+        pipes
+            .pipe(1, 4, PipeEnd::Write)
+            .unwrap()
+            .write(b"x")
+            .unwrap();
+
+        // Readers get the rest of the data, then EOF
+        pipes.close(1, 4);
+        let mut buf = [0u8; 8];
+        let pipe = pipes.pipe(1, 3, PipeEnd::Read).unwrap();
+        assert_eq!(pipe.read(&mut buf), Ok(1));
+        assert_eq!(pipe.read(&mut buf), Ok(0));
+
+        pipes.close(1, 3);
+        assert!(pipes.pipes.is_empty());
+        assert!(pipes.ends.is_empty());
+
+        // Writing without a reader
+        pipes.create(1, 3, 4);
+        pipes.close(1, 3);
+        assert_eq!(
+            pipes.pipe(1, 4, PipeEnd::Write).unwrap().write(b"x"),
+            Err(FileSystemError::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn pipe_fork() {
+        let mut pipes: Pipes = Default::default();
+        pipes.create(1, 3, 4);
+        pipes.fork(1, 2);
+
+        // The child writes, the parent reads
+        pipes.close(1, 4);
+        pipes
+            .pipe(2, 4, PipeEnd::Write)
+            .unwrap()
+            .write(b"x")
+            .unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf),
+            Ok(1)
+        );
+
+        // The pipe stays around until both processes closed it
+        pipes.close(2, 4);
+        pipes.close(2, 3);
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf),
+            Ok(0)
+        );
+        pipes.close(1, 3);
+        assert!(pipes.pipes.is_empty());
+    }
+
+    #[test]
+    fn pipe_transfer() {
+        let mut pipes: Pipes = Default::default();
+        pipes.create(1, 3, 4);
+        pipes.fork(1, 2);
+
+        // 2 went away, 3 took over its ends
+        pipes.transfer(2, 3);
+        assert!(pipes.pipe(2, 4, PipeEnd::Write).is_err());
+        pipes
+            .pipe(3, 4, PipeEnd::Write)
+            .unwrap()
+            .write(b"x")
+            .unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            pipes.pipe(1, 3, PipeEnd::Read).unwrap().read(&mut buf),
+            Ok(1)
+        );
+
+        pipes.close(1, 3);
+        pipes.close(1, 4);
+        pipes.close(3, 3);
+        pipes.close(3, 4);
+        assert!(pipes.pipes.is_empty());
+    }
+}
@@ -12,8 +12,12 @@ use proptest::prelude::*;
 use kpi::io::*;
 
 use super::*;
+use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use crate::*;
 
+/// The tests run everything as root (which owns all files).
+const ROOT: Credentials = Credentials::ROOT;
+
 /// What operations that the model needs to keep track of.
 ///
 /// We don't need to log reads or lookups.
@@ -127,10 +131,23 @@ impl ModelFS {
 
 impl FileSystem for ModelFS {
     // Create just puts the file in the oplop and increases mnode counter.
-    fn create(&mut self, pathname: &str, mode: Modes) -> Result<u64, FileSystemError> {
-        let path = String::from(pathname);
+    fn create(
+        &mut self,
+        _creds: Credentials,
+        pathname: &str,
+        mode: Modes,
+    ) -> Result<u64, FileSystemError> {
+        let path = canonicalize(pathname);
         if self.file_exists(&path) {
             Err(FileSystemError::AlreadyPresent)
+        } else if let Some(idx) = path.find('/') {
+            // The model has no directories (except the root), so the path
+            // either starts with a file or with something that doesn't exist.
+            if self.file_exists(&String::from(&path[..idx])) {
+                Err(FileSystemError::NotADirectory)
+            } else {
+                Err(FileSystemError::InvalidFile)
+            }
         } else {
             self.mnode_counter += 1;
             self.oplog
@@ -144,6 +161,7 @@ impl FileSystem for ModelFS {
     /// Our model assumes that the buffer repeats the first byte for its entire length.
     fn write(
         &mut self,
+        _creds: Credentials,
         mnode_num: Mnode,
         buffer: &[u8],
         offset: usize,
@@ -184,6 +202,7 @@ impl FileSystem for ModelFS {
     /// This is the hardest operation to represent in the model.
     fn read(
         &self,
+        _creds: Credentials,
         mnode_num: Mnode,
         buffer: &mut UserSlice,
         offset: usize,
@@ -276,12 +295,15 @@ impl FileSystem for ModelFS {
 
     /// Lookup just returns the mnode.
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
-        self.path_to_mnode(&String::from(pathname)).map(Arc::from)
+        self.path_to_mnode(&canonicalize(pathname)).map(Arc::from)
     }
 
     /// Delete finds and removes a path from the oplog again.
-    fn delete(&mut self, pathname: &str) -> Result<bool, FileSystemError> {
-        if let Some(idx) = self.path_to_idx(&String::from(pathname)) {
+    fn delete(&mut self, _creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
+        let path = canonicalize(pathname);
+        if path == "/" {
+            Err(FileSystemError::PermissionError)
+        } else if let Some(idx) = self.path_to_idx(&path) {
             self.oplog.remove(idx);
             // We leave corresponding ModelOperation::Write entries
             // in the log for now...
@@ -293,22 +315,49 @@ impl FileSystem for ModelFS {
 
     /// Returns a `dummy` file-info.
     fn file_info(&self, _mnode: Mnode) -> FileInfo {
-        FileInfo { ftype: 0, fsize: 0 }
+        Default::default()
     }
 
     /// Return a `dummy` response as this function is only used for open with O_TRUNC flag.
-    fn truncate(&mut self, pathname: &str) -> Result<bool, FileSystemError> {
+    fn truncate(&mut self, _creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
         Ok(true)
     }
 
+    /// Return a `dummy` response, the model can't tell allocated memory
+    /// apart from a hole.
+    fn allocate(
+        &mut self,
+        _creds: Credentials,
+        _mnode_num: Mnode,
+        _offset: usize,
+        _len: usize,
+    ) -> Result<(), FileSystemError> {
+        Ok(())
+    }
+
     /// Return a `dummy` response for rename operation
-    fn rename(&mut self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
+    fn rename(
+        &mut self,
+        _creds: Credentials,
+        oldname: &str,
+        newname: &str,
+    ) -> Result<bool, FileSystemError> {
         Ok(true)
     }
 
-    fn mkdir(&mut self, pathname: &str, mode: Modes) -> Result<bool, FileSystemError> {
+    fn mkdir(
+        &mut self,
+        _creds: Credentials,
+        pathname: &str,
+        mode: Modes,
+    ) -> Result<bool, FileSystemError> {
         Ok(true)
     }
+
+    /// Return a `dummy` response for readdir operation
+    fn readdir(&self, pathname: &str) -> Result<Vec<DirEntry>, FileSystemError> {
+        Ok(Vec::new())
+    }
 }
 
 /// Two writes/reads at different offsets should return
@@ -316,27 +365,27 @@ impl FileSystem for ModelFS {
 #[test]
 fn model_read() {
     let mut mfs: ModelFS = Default::default();
-    mfs.create("/bla", FileModes::S_IRWXU.into());
+    mfs.create(ROOT, "/bla", FileModes::S_IRWXU.into());
     let mnode = mfs.lookup("/bla").unwrap();
 
     let mut wdata1 = [1, 1];
     let mut buffer = UserSlice::from_slice(&mut wdata1);
-    mfs.write(*mnode, &mut buffer, 0);
+    mfs.write(ROOT, *mnode, &mut buffer, 0);
 
     let mut wdata = [2, 2];
     let mut wbuffer = UserSlice::from_slice(&mut wdata);
-    let r = mfs.write(*mnode, &mut wbuffer, 4);
+    let r = mfs.write(ROOT, *mnode, &mut wbuffer, 4);
     assert_eq!(r, Ok(2));
 
     let mut rdata = [0, 0];
 
     let mut rbuffer = UserSlice::from_slice(&mut rdata);
-    let r = mfs.read(*mnode, &mut rbuffer, 0);
+    let r = mfs.read(ROOT, *mnode, &mut rbuffer, 0);
     assert_eq!(rdata, [1, 1]);
     assert_eq!(r, Ok(2));
 
     let mut rbuffer = UserSlice::from_slice(&mut rdata);
-    let r = mfs.read(*mnode, &mut rbuffer, 4);
+    let r = mfs.read(ROOT, *mnode, &mut rbuffer, 4);
     assert_eq!(rdata, [2, 2]);
     assert_eq!(r, Ok(2));
 }
@@ -348,20 +397,20 @@ fn model_read() {
 #[test]
 fn model_overlapping_writes() {
     let mut mfs: ModelFS = Default::default();
-    mfs.create("/bla", FileModes::S_IRWXU.into());
+    mfs.create(ROOT, "/bla", FileModes::S_IRWXU.into());
     let mnode = mfs.lookup("/bla").unwrap();
 
     let mut data = [1, 1, 1];
     let mut buffer = UserSlice::from_slice(&mut data);
-    mfs.write(*mnode, &mut buffer, 0);
+    mfs.write(ROOT, *mnode, &mut buffer, 0);
 
     let mut wdata = [2, 2, 2];
     let mut wbuffer = UserSlice::from_slice(&mut wdata);
-    mfs.write(*mnode, &mut wbuffer, 2);
+    mfs.write(ROOT, *mnode, &mut wbuffer, 2);
 
     let mut rdata = [0, 0, 0, 0, 0, 0];
     let mut rbuffer = UserSlice::from_slice(&mut rdata);
-    let r = mfs.read(*mnode, &mut rbuffer, 0);
+    let r = mfs.read(ROOT, *mnode, &mut rbuffer, 0);
     assert_eq!(r, Ok(5));
     assert_eq!(rdata, [1, 1, 2, 2, 2, 0]);
 }
@@ -472,8 +521,8 @@ proptest! {
                     let mut buffer1: Vec<u8> = Vec::with_capacity(len);
                     let mut buffer2: Vec<u8> = Vec::with_capacity(len);
 
-                    let rmodel = model.read(mnode, &mut UserSlice::from_slice(buffer1.as_mut_slice()), offset);
-                    let rtotest = totest.read(mnode, &mut UserSlice::from_slice(buffer2.as_mut_slice()), offset);
+                    let rmodel = model.read(ROOT, mnode, &mut UserSlice::from_slice(buffer1.as_mut_slice()), offset);
+                    let rtotest = totest.read(ROOT, mnode, &mut UserSlice::from_slice(buffer2.as_mut_slice()), offset);
                     assert_eq!(rmodel, rtotest);
                     assert_eq!(buffer1, buffer2);
                 }
@@ -483,22 +532,22 @@ proptest! {
                         buffer.push(pattern as u8);
                     }
 
-                    let rmodel = model.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
-                    let rtotest = totest.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
+                    let rmodel = model.write(ROOT, mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
+                    let rtotest = totest.write(ROOT, mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
                     assert_eq!(rmodel, rtotest);
                 }
                 Create(path, mode) => {
                     let path_str = path.join("/");
 
-                    let rmodel = model.create(path_str.as_str(), mode);
-                    let rtotest = totest.create(path_str.as_str(), mode);
+                    let rmodel = model.create(ROOT, path_str.as_str(), mode);
+                    let rtotest = totest.create(ROOT, path_str.as_str(), mode);
                     assert_eq!(rmodel, rtotest);
                 }
                 Delete(path) => {
                     let path_str = path.join("/");
 
-                    let rmodel = model.delete(path_str.as_str());
-                    let rtotest = totest.delete(path_str.as_str());
+                    let rmodel = model.delete(ROOT, path_str.as_str());
+                    let rtotest = totest.delete(ROOT, path_str.as_str());
                     assert_eq!(rmodel, rtotest);
                 }
                 Lookup(path) => {
@@ -525,6 +574,43 @@ fn test_file_descriptor() {
     assert_eq!(fd.get_flags(), FileFlags::O_RDWR);
 }
 
+/// Move the offset of a file descriptor.
+#[test]
+fn test_file_descriptor_seek() {
+    let fd = Fd::init_fd();
+    assert_eq!(fd.seek(10, SeekWhence::Set, 0), Ok(10));
+    assert_eq!(fd.get_offset(), 10);
+    assert_eq!(fd.seek(-4, SeekWhence::Cur, 0), Ok(6));
+    assert_eq!(fd.seek(2, SeekWhence::End, 100), Ok(102));
+    assert_eq!(fd.seek(-100, SeekWhence::End, 100), Ok(0));
+
+    assert_eq!(
+        fd.seek(-1, SeekWhence::Set, 100),
+        Err(FileSystemError::InvalidOffset)
+    );
+    assert_eq!(
+        fd.seek(-101, SeekWhence::End, 100),
+        Err(FileSystemError::InvalidOffset)
+    );
+    assert_eq!(fd.get_offset(), 0);
+}
+
+/// A duplicated file descriptor has its own offset.
+#[test]
+fn test_file_descriptor_clone() {
+    let mut fd = Fd::init_fd();
+    fd.update_fd(1, FileFlags::O_RDWR);
+    fd.update_offset(10);
+
+    let copy = fd.clone();
+    assert_eq!(copy.get_mnode(), 1);
+    assert_eq!(copy.get_flags(), FileFlags::O_RDWR);
+    assert_eq!(copy.get_offset(), 10);
+
+    copy.update_offset(20);
+    assert_eq!(fd.get_offset(), 10);
+}
+
 /// Initialize memfs for root and verify the values.
 #[test]
 fn test_memfs_init() {
@@ -535,7 +621,7 @@ fn test_memfs_init() {
     assert_eq!(memfs.files.get(&root), Some(&Arc::new(1)));
     assert_eq!(
         memfs.mnodes.get(&1),
-        Some(&MemNode::new(1, "/", FileModes::S_IRWXU.into(), NodeType::Directory).unwrap())
+        Some(&MemNode::new(1, "/", ROOT_DIRECTORY_MODES.into(), NodeType::Directory).unwrap())
     );
 }
 
@@ -544,7 +630,9 @@ fn test_memfs_init() {
 fn test_file_create() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRUSR.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -560,7 +648,9 @@ fn test_file_read_permission_error() {
     let buffer = &[0; 10];
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IWUSR.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IWUSR.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -570,7 +660,12 @@ fn test_file_read_permission_error() {
     // On error read returns 0.
     assert_eq!(
         memfs
-            .read(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+            .read(
+                ROOT,
+                2,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                0
+            )
             .is_err(),
         true
     );
@@ -582,7 +677,9 @@ fn test_file_write_permission_error() {
     let buffer = &[0; 10];
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRUSR.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -591,7 +688,12 @@ fn test_file_write_permission_error() {
     );
     // On error read returns 0.
     assert_eq!(
-        memfs.write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            ROOT,
+            2,
+            &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+            0
+        ),
         Err(FileSystemError::PermissionError)
     );
 }
@@ -602,7 +704,9 @@ fn test_file_write() {
     let buffer = &[0; 10];
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -611,7 +715,12 @@ fn test_file_write() {
     );
     assert_eq!(
         memfs
-            .write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
+            .write(
+                ROOT,
+                2,
+                &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+                0
+            )
             .unwrap(),
         10
     );
@@ -626,7 +735,9 @@ fn test_file_read() {
 
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -635,13 +746,23 @@ fn test_file_read() {
     );
     assert_eq!(
         memfs
-            .write(2, &mut UserSlice::new(wbuffer.as_ptr() as u64, len), 0)
+            .write(
+                ROOT,
+                2,
+                &mut unsafe { UserSlice::new(wbuffer.as_ptr() as u64, len) },
+                0
+            )
             .unwrap(),
         len
     );
     assert_eq!(
         memfs
-            .read(2, &mut UserSlice::new(rbuffer.as_ptr() as u64, len), 0)
+            .read(
+                ROOT,
+                2,
+                &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, len) },
+                0
+            )
             .unwrap(),
         len
     );
@@ -654,7 +775,9 @@ fn test_file_read() {
 fn test_file_lookup() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -670,7 +793,9 @@ fn test_file_lookup() {
 fn test_file_fake_lookup() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -686,7 +811,9 @@ fn test_file_fake_lookup() {
 fn test_file_duplicate_create() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -694,7 +821,7 @@ fn test_file_duplicate_create() {
         Some(&Arc::new(2))
     );
     assert_eq!(
-        memfs.create(filename, FileModes::S_IRWXU.into()),
+        memfs.create(ROOT, filename, FileModes::S_IRWXU.into()),
         Err(FileSystemError::AlreadyPresent)
     );
 }
@@ -704,14 +831,43 @@ fn test_file_duplicate_create() {
 fn test_file_info() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
         memfs.files.get(&String::from("file.txt")),
         Some(&Arc::new(2))
     );
-    assert_eq!(memfs.file_info(2), FileInfo { ftype: 2, fsize: 0 });
+    assert_eq!(
+        memfs.file_info(2),
+        FileInfo {
+            ftype: 2,
+            fsize: 0,
+            base_pages: 0,
+            large_pages: 0
+        }
+    );
+}
+
+/// Test that file_info reports the pages of a file that spilled into large
+/// pages.
+#[test]
+fn test_file_info_large_pages() {
+    let mut memfs: MemFS = Default::default();
+    let mnode = memfs
+        .create(ROOT, "file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    let len = LARGE_PAGE_SIZE + BASE_PAGE_SIZE;
+    let buffer = alloc::vec![0xb; len];
+    assert_eq!(memfs.write(ROOT, mnode, &buffer, 0), Ok(len));
+
+    let finfo = memfs.file_info(mnode);
+    assert_eq!(finfo.fsize, len as u64);
+    assert_eq!(finfo.base_pages, (LARGE_PAGE_SIZE / BASE_PAGE_SIZE) as u64);
+    assert_eq!(finfo.large_pages, 1);
 }
 
 /// Test file deletion.
@@ -721,17 +877,29 @@ fn test_file_delete() {
     let filename = "file.txt";
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
 
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_eq!(mnode, 2);
-    assert_eq!(memfs.delete(filename), Ok(true));
-    assert_eq!(memfs.delete(filename).is_err(), true);
+    assert_eq!(memfs.delete(ROOT, filename), Ok(true));
+    assert_eq!(memfs.delete(ROOT, filename).is_err(), true);
     assert_eq!(memfs.lookup(filename), None);
     assert_eq!(
-        memfs.write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            ROOT,
+            2,
+            &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+            0
+        ),
         Err(FileSystemError::InvalidFile)
     );
     assert_eq!(
-        memfs.read(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.read(
+            ROOT,
+            2,
+            &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+            0
+        ),
         Err(FileSystemError::InvalidFile)
     );
 }
@@ -741,8 +909,10 @@ fn test_file_rename() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
-    memfs.rename(filename, newname);
+    let oldmnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs.rename(ROOT, filename, newname);
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(oldmnode, *mnode);
 }
@@ -752,19 +922,31 @@ fn test_file_rename_and_read() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
 
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
     assert_eq!(
-        memfs.write(mnode, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            ROOT,
+            mnode,
+            &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+            0
+        ),
         Ok(10)
     );
 
     let rbuffer: &mut [u8; 10] = &mut [0x0; 10];
-    memfs.rename(filename, newname);
+    memfs.rename(ROOT, filename, newname);
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(
-        memfs.read(*mnode, &mut UserSlice::new(rbuffer.as_ptr() as u64, 10), 0),
+        memfs.read(
+            ROOT,
+            *mnode,
+            &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, 10) },
+            0
+        ),
         Ok(10)
     );
     assert_eq!(rbuffer[0], 0xb);
@@ -776,8 +958,10 @@ fn test_file_rename_and_write() {
     let mut memfs: MemFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
-    memfs.rename(filename, newname);
+    let oldmnode = memfs
+        .create(ROOT, filename, FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs.rename(ROOT, filename, newname);
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(oldmnode, *mnode);
 
@@ -785,11 +969,17 @@ fn test_file_rename_and_write() {
     assert_eq!(finfo.fsize, 0);
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
     assert_eq!(
-        memfs.write(*mnode, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
+        memfs.write(
+            ROOT,
+            *mnode,
+            &mut unsafe { UserSlice::new(buffer.as_ptr() as u64, 10) },
+            0
+        ),
         Ok(10)
     );
     let finfo = memfs.file_info(*mnode);
     assert_eq!(finfo.fsize, 10);
+    assert_eq!(finfo.base_pages, 1);
 }
 
 #[test]
@@ -798,7 +988,7 @@ fn test_file_rename_nonexistent_file() {
     let oldname = "file.txt";
     let newname = "filenew.txt";
     assert_eq!(
-        memfs.rename(oldname, newname),
+        memfs.rename(ROOT, oldname, newname),
         Err(FileSystemError::InvalidFile)
     );
 }
@@ -808,13 +998,384 @@ fn test_file_rename_to_existent_file() {
     let mut memfs: MemFS = Default::default();
     let oldname = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(oldname, FileModes::S_IRWXU.into()).unwrap();
-    let newmnode = memfs.create(newname, FileModes::S_IRWXU.into()).unwrap();
+    let oldmnode = memfs
+        .create(ROOT, oldname, FileModes::S_IRWXU.into())
+        .unwrap();
+    let newmnode = memfs
+        .create(ROOT, newname, FileModes::S_IRWXU.into())
+        .unwrap();
     assert_ne!(oldmnode, newmnode);
-    assert_eq!(memfs.rename(oldname, newname), Ok(true));
+    assert_eq!(memfs.rename(ROOT, oldname, newname), Ok(true));
 
     // Old file is removed.
     assert_eq!(memfs.lookup(oldname), None);
     // New file points to old mnode.
     assert_eq!(*memfs.lookup(newname).unwrap(), oldmnode);
 }
+
+#[test]
+fn test_canonicalize() {
+    assert_eq!(canonicalize("/"), "/");
+    assert_eq!(canonicalize(""), "/");
+    assert_eq!(canonicalize("file.txt"), "file.txt");
+    assert_eq!(canonicalize("/file.txt"), "file.txt");
+    assert_eq!(canonicalize("//dir///file.txt/"), "dir/file.txt");
+    assert_eq!(canonicalize("/dir/./sub/../file.txt"), "dir/file.txt");
+    assert_eq!(canonicalize("/.."), "/");
+    assert_eq!(split_path("dir/sub/file.txt"), ("dir/sub", "file.txt"));
+    assert_eq!(split_path("file.txt"), ("/", "file.txt"));
+}
+
+/// Create files in nested directories and look them up.
+#[test]
+fn test_dir_create_nested() {
+    let mut memfs: MemFS = Default::default();
+    assert_eq!(
+        memfs.mkdir(ROOT, "/dir", FileModes::S_IRWXU.into()),
+        Ok(true)
+    );
+    assert_eq!(
+        memfs.mkdir(ROOT, "/dir/sub", FileModes::S_IRWXU.into()),
+        Ok(true)
+    );
+    let mnode = memfs
+        .create(ROOT, "/dir/sub/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    assert_eq!(memfs.lookup("dir/sub/file.txt"), Some(Arc::new(mnode)));
+    assert_eq!(memfs.lookup("/dir//sub/./file.txt"), Some(Arc::new(mnode)));
+    assert_eq!(memfs.resolve("dir/sub/file.txt"), Ok(mnode));
+    assert_eq!(
+        memfs.mnodes.get(&mnode).unwrap().get_parent(),
+        memfs.lookup("/dir/sub").map(|m| *m)
+    );
+}
+
+/// Files can only be created in directories that exist.
+#[test]
+fn test_dir_create_missing_parent() {
+    let mut memfs: MemFS = Default::default();
+    assert_eq!(
+        memfs.create(ROOT, "/dir/file.txt", FileModes::S_IRWXU.into()),
+        Err(FileSystemError::InvalidFile)
+    );
+    assert_eq!(
+        memfs.mkdir(ROOT, "/dir/sub", FileModes::S_IRWXU.into()),
+        Err(FileSystemError::InvalidFile)
+    );
+
+    memfs
+        .create(ROOT, "/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(
+        memfs.create(ROOT, "/file.txt/file.txt", FileModes::S_IRWXU.into()),
+        Err(FileSystemError::NotADirectory)
+    );
+    assert_eq!(
+        memfs.create(ROOT, "/file.txt/a/b", FileModes::S_IRWXU.into()),
+        Err(FileSystemError::NotADirectory)
+    );
+}
+
+/// List the entries of directories.
+#[test]
+fn test_readdir() {
+    let mut memfs: MemFS = Default::default();
+    assert_eq!(memfs.readdir("/"), Ok(Vec::new()));
+
+    memfs
+        .mkdir(ROOT, "/dir", FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs
+        .create(ROOT, "/b.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs
+        .create(ROOT, "/dir/a.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    let entries = memfs.readdir("/").unwrap();
+    assert_eq!(
+        entries,
+        alloc::vec![
+            DirEntry {
+                name: String::from("b.txt"),
+                ftype: NodeType::File.into()
+            },
+            DirEntry {
+                name: String::from("dir"),
+                ftype: NodeType::Directory.into()
+            },
+        ]
+    );
+    let entries = memfs.readdir("dir").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "a.txt");
+
+    assert_eq!(memfs.readdir("/b.txt"), Err(FileSystemError::NotADirectory));
+    assert_eq!(memfs.readdir("/nodir"), Err(FileSystemError::InvalidFile));
+}
+
+/// Directories can only be deleted once they are empty.
+#[test]
+fn test_dir_delete() {
+    let mut memfs: MemFS = Default::default();
+    memfs
+        .mkdir(ROOT, "/dir", FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs
+        .create(ROOT, "/dir/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    assert_eq!(
+        memfs.delete(ROOT, "/dir"),
+        Err(FileSystemError::DirectoryNotEmpty)
+    );
+    assert_eq!(memfs.delete(ROOT, "/dir/file.txt"), Ok(true));
+    assert_eq!(memfs.readdir("/dir"), Ok(Vec::new()));
+    assert_eq!(memfs.delete(ROOT, "/dir"), Ok(true));
+    assert_eq!(memfs.lookup("/dir"), None);
+    assert_eq!(memfs.readdir("/"), Ok(Vec::new()));
+
+    assert_eq!(
+        memfs.delete(ROOT, "/"),
+        Err(FileSystemError::PermissionError)
+    );
+}
+
+/// Moving a directory moves everything below it.
+#[test]
+fn test_dir_rename() {
+    let mut memfs: MemFS = Default::default();
+    memfs
+        .mkdir(ROOT, "/dir", FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs
+        .mkdir(ROOT, "/other", FileModes::S_IRWXU.into())
+        .unwrap();
+    let mnode = memfs
+        .create(ROOT, "/dir/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    assert_eq!(memfs.rename(ROOT, "/dir", "/other/moved"), Ok(true));
+    assert_eq!(memfs.lookup("/dir"), None);
+    assert_eq!(memfs.lookup("/dir/file.txt"), None);
+    assert_eq!(memfs.lookup("/other/moved/file.txt"), Some(Arc::new(mnode)));
+    assert_eq!(
+        memfs.mnodes.get(&mnode).unwrap().get_name(),
+        "other/moved/file.txt"
+    );
+    assert_eq!(memfs.readdir("/").unwrap().len(), 1);
+    assert_eq!(memfs.readdir("/other").unwrap()[0].name, "moved");
+
+    assert_eq!(
+        memfs.rename(ROOT, "/other", "/other/moved/inside"),
+        Err(FileSystemError::PermissionError)
+    );
+    assert_eq!(
+        memfs.rename(ROOT, "/other/moved/file.txt", "/nodir/file.txt"),
+        Err(FileSystemError::InvalidFile)
+    );
+}
+
+/// Files and directories are checked against the credentials of the caller.
+#[test]
+fn test_permissions() {
+    let mut memfs: MemFS = Default::default();
+    let user = Credentials {
+        uid: 1000,
+        gid: 100,
+    };
+    let buffer = [0xb; 10];
+
+    memfs
+        .mkdir(ROOT, "/dir", FileModes::S_IRWXU.into())
+        .unwrap();
+    let rootfile = memfs
+        .create(ROOT, "/dir/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(
+        memfs.create(user, "/dir/other.txt", FileModes::S_IRWXU.into()),
+        Err(FileSystemError::PermissionError)
+    );
+    assert_eq!(
+        memfs.delete(user, "/dir/file.txt"),
+        Err(FileSystemError::PermissionError)
+    );
+    assert_eq!(
+        memfs.rename(user, "/dir/file.txt", "/file.txt"),
+        Err(FileSystemError::PermissionError)
+    );
+    assert_eq!(
+        memfs.write(user, rootfile, &buffer, 0),
+        Err(FileSystemError::PermissionError)
+    );
+    assert_eq!(
+        memfs.truncate(user, "/dir/file.txt"),
+        Err(FileSystemError::PermissionError)
+    );
+
+    // Everyone can create files in the root directory, they belong to whoever
+    // created them.
+    let modes = FileModes::S_IRWXU | FileModes::S_IROTH;
+    let userfile = memfs.create(user, "/user.txt", modes.into()).unwrap();
+    assert_eq!(memfs.mnodes.get(&userfile).unwrap().get_owner(), user);
+    assert_eq!(memfs.write(user, userfile, &buffer, 0), Ok(10));
+    assert_eq!(
+        memfs.write(ROOT, userfile, &buffer, 0),
+        Err(FileSystemError::PermissionError)
+    );
+    let rbuffer: &mut [u8; 10] = &mut [0; 10];
+    assert_eq!(
+        memfs.read(
+            ROOT,
+            userfile,
+            &mut unsafe { UserSlice::new(rbuffer.as_ptr() as u64, 10) },
+            0
+        ),
+        Ok(10)
+    );
+    assert_eq!(memfs.delete(user, "/user.txt"), Ok(true));
+}
+
+/// Files are charged to the process that created them, up to the quota.
+#[test]
+fn test_fs_accounting() {
+    let mut memfs: MemFS = Default::default();
+    let mut accounting = FsAccounting::new(Some(2 * BASE_PAGE_SIZE));
+    let mnode = memfs
+        .create(ROOT, "/file", FileModes::S_IRWXU.into())
+        .unwrap();
+    accounting.add_file(mnode, 1);
+    assert_eq!(accounting.usage(1), 0);
+    assert_eq!(accounting.quota(), Some(2 * BASE_PAGE_SIZE));
+
+    let buffer = [0xb; BASE_PAGE_SIZE];
+    for offset in &[0, BASE_PAGE_SIZE] {
+        let size = memfs.file_info(mnode).fsize as usize;
+        assert_eq!(
+            accounting.charge_write(mnode, || size, *offset, BASE_PAGE_SIZE),
+            Ok(BASE_PAGE_SIZE)
+        );
+        assert_eq!(
+            memfs.write(ROOT, mnode, &buffer, *offset),
+            Ok(BASE_PAGE_SIZE)
+        );
+    }
+    assert_eq!(accounting.usage(1), 2 * BASE_PAGE_SIZE);
+
+    // Overwriting doesn't grow the file, growing it exceeds the quota
+    let size = memfs.file_info(mnode).fsize as usize;
+    assert_eq!(
+        accounting.charge_write(mnode, || size, 0, BASE_PAGE_SIZE),
+        Ok(0)
+    );
+    assert_eq!(
+        accounting.charge_write(mnode, || size, size, 1),
+        Err(FileSystemError::QuotaExceeded)
+    );
+    assert_eq!(accounting.usage(1), 2 * BASE_PAGE_SIZE);
+
+    // Other processes (and files nobody owns) aren't charged
+    assert_eq!(accounting.usage(2), 0);
+    assert_eq!(
+        accounting.charge_write(mnode + 1, || unreachable!(), 0, usize::max_value()),
+        Ok(0)
+    );
+
+    accounting.uncharge(mnode, BASE_PAGE_SIZE);
+    assert_eq!(accounting.usage(1), BASE_PAGE_SIZE);
+    accounting.remove_file(mnode, BASE_PAGE_SIZE);
+    assert_eq!(accounting.usage(1), 0);
+    assert_eq!(accounting.charge_write(mnode, || 0, 0, 1), Ok(0));
+}
+
+/// Only evictable files that aren't busy lose (the end of) their content,
+/// the oldest ones first.
+#[test]
+fn test_evict() {
+    let mut memfs: MemFS = Default::default();
+    let buffer = [0xb; 2 * BASE_PAGE_SIZE];
+    let mut mnodes = Vec::new();
+    for name in &["/cache0", "/cache1", "/busy", "/file"] {
+        let mnode = memfs.create(ROOT, name, FileModes::S_IRWXU.into()).unwrap();
+        assert_eq!(memfs.write(ROOT, mnode, &buffer, 0), Ok(2 * BASE_PAGE_SIZE));
+        mnodes.push(mnode);
+    }
+    for mnode in &mnodes[..3] {
+        assert_eq!(memfs.set_evictable(*mnode), Ok(()));
+    }
+    let busy = [mnodes[2]];
+
+    let one_page = PageStats {
+        base_pages: 1,
+        large_pages: 0,
+    };
+    assert_eq!(
+        memfs.evict(1, &busy),
+        alloc::vec![(mnodes[0], BASE_PAGE_SIZE, one_page)]
+    );
+    assert_eq!(
+        memfs.evict(2 * BASE_PAGE_SIZE, &busy),
+        alloc::vec![
+            (mnodes[0], BASE_PAGE_SIZE, one_page),
+            (mnodes[1], BASE_PAGE_SIZE, one_page)
+        ]
+    );
+    assert_eq!(memfs.file_info(mnodes[0]).fsize, 0);
+    assert_eq!(memfs.file_info(mnodes[1]).fsize, BASE_PAGE_SIZE as u64);
+
+    // Nothing else can go
+    assert_eq!(memfs.evict(usize::max_value(), &busy).len(), 1);
+    assert!(memfs.evict(usize::max_value(), &busy).is_empty());
+    assert_eq!(memfs.file_info(mnodes[2]).fsize, 2 * BASE_PAGE_SIZE as u64);
+    assert_eq!(memfs.file_info(mnodes[3]).fsize, 2 * BASE_PAGE_SIZE as u64);
+}
+
+/// The pages of a file can be mapped, a mapped file keeps its content.
+#[test]
+fn test_mapped_pages() {
+    let mut memfs: MemFS = Default::default();
+    let buffer = [0xb; BASE_PAGE_SIZE + 1];
+    let mnode = memfs
+        .create(ROOT, "/mapped", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(memfs.write(ROOT, mnode, &buffer, 0), Ok(BASE_PAGE_SIZE + 1));
+    assert_eq!(memfs.set_evictable(mnode), Ok(()));
+
+    let pages = memfs
+        .pages(ROOT, mnode, FileModes::S_IRUSR, 0, 2 * BASE_PAGE_SIZE)
+        .unwrap();
+    assert_eq!(pages.len(), 2);
+    for page in pages.iter() {
+        assert_eq!(page % BASE_PAGE_SIZE, 0);
+    }
+    // Safety: The file is not dropped or changed while we look at it
+    let second = unsafe { core::slice::from_raw_parts(pages[1] as *const u8, BASE_PAGE_SIZE) };
+    assert_eq!(second[0], 0xb);
+    assert!(second[1..].iter().all(|b| *b == 0));
+
+    // Only (whole) pages of the file can be mapped
+    assert_eq!(
+        memfs.pages(ROOT, mnode, FileModes::S_IRUSR, 1, BASE_PAGE_SIZE),
+        Err(FileSystemError::InvalidOffset)
+    );
+    assert_eq!(
+        memfs.pages(ROOT, mnode, FileModes::S_IRUSR, 0, 2 * BASE_PAGE_SIZE + 1),
+        Err(FileSystemError::InvalidOffset)
+    );
+
+    memfs.add_mapping(mnode);
+    assert_eq!(
+        memfs.truncate(ROOT, "/mapped"),
+        Err(FileSystemError::StillMapped)
+    );
+    assert_eq!(
+        memfs.delete(ROOT, "/mapped"),
+        Err(FileSystemError::StillMapped)
+    );
+    assert!(memfs.evict(usize::max_value(), &[]).is_empty());
+
+    memfs.remove_mapping(mnode);
+    assert_eq!(memfs.truncate(ROOT, "/mapped"), Ok(true));
+    assert_eq!(memfs.delete(ROOT, "/mapped"), Ok(true));
+}
@@ -159,6 +159,13 @@ pub fn xmain() {
         };
     }
 
+    // The PCI root bridge is only declared in the DSDT, so are the
+    // interrupt lines of PCI devices
+    assert!(arch::acpi::devices()
+        .iter()
+        .any(|device| device.is_pci_root_bridge()));
+    assert!(!arch::steering::pci_routes().is_empty());
+
     arch::debug::shutdown(ExitReason::Ok);
 }
 
@@ -252,6 +259,7 @@ pub fn xmain() {
     pub fn bespin_init_ap(arg1: Arc<u64>, initialized: &AtomicBool) {
         crate::arch::enable_sse();
         crate::arch::enable_fsgsbase();
+        coreboot::set_boot_stage(coreboot::BootStage::Entered);
 
         // Check that we can pass arguments:
         assert_eq!(*arg1, 0xfefe);
@@ -287,20 +295,12 @@ pub fn xmain() {
         );
 
         // Wait until core is up or we time out
-        let timeout = x86::time::rdtsc() + 10_000_000;
-        loop {
-            // Did the core signal us initialization completed?
-            if initialized.load(Ordering::SeqCst) {
-                break;
-            }
-
-            // Have we waited long enough?
-            if x86::time::rdtsc() > timeout {
-                panic!("Core didn't boot properly...");
-            }
+        if let Err(stage) = coreboot::wait_until_initialized(&initialized, 10_000_000) {
+            panic!("Core didn't boot properly, stuck at {:?}...", stage);
         }
 
         assert!(initialized.load(Ordering::SeqCst));
+        assert_eq!(coreboot::boot_stage(), Some(coreboot::BootStage::Entered));
         // Don't change this string otherwise the test will fail:
         info!("Core has started");
     }
@@ -361,17 +361,8 @@ pub fn xmain() {
         );
 
         // Wait until core is up or we time out
-        let timeout = x86::time::rdtsc() + 10_000_000;
-        loop {
-            // Did the core signal us initialization completed?
-            if initialized.load(Ordering::SeqCst) {
-                break;
-            }
-
-            // Have we waited long enough?
-            if x86::time::rdtsc() > timeout {
-                panic!("Core didn't boot properly...");
-            }
+        if let Err(stage) = coreboot::wait_until_initialized(&initialized, 10_000_000) {
+            panic!("Core didn't boot properly, stuck at {:?}...", stage);
         }
 
         assert!(initialized.load(Ordering::SeqCst));
@@ -397,7 +388,8 @@ pub fn xmain() {
 ))]
 pub fn xmain() {
     let kcb = kcb::get_kcb();
-    crate::arch::process::spawn(kcb.cmdline.test_binary);
+    crate::arch::process::spawn(kcb.cmdline.test_binary, "", None, kcb.cmdline.policy)
+        .expect("Can't launch test binary");
     crate::scheduler::schedule()
 }
 
@@ -7,6 +7,7 @@ use core::convert::TryInto;
 use core::slice::from_raw_parts;
 
 use arr_macro::arr;
+use kpi::process::SchedulingPolicy;
 use logos::Logos;
 use node_replication::{Replica, ReplicaToken};
 use slabmalloc::ZoneAllocator;
@@ -21,7 +22,9 @@ use crate::memory::{
     GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider,
 };
 use crate::nr::KernelNode;
-use crate::process::Process;
+use crate::nrtrace::TraceBuffer;
+use crate::process::{Credentials, Process};
+use crate::stats::Histogram;
 
 pub use crate::arch::kcb::{get_kcb, try_get_kcb};
 
@@ -55,6 +58,26 @@ enum CmdToken {
     #[token = "appcmd="]
     AppCmd,
 
+    /// Scheduling policy for the initial process.
+    #[token = "policy="]
+    Policy,
+
+    /// Tuning knobs for the user-space allocator.
+    #[token = "malloc="]
+    Malloc,
+
+    /// Rate limit for the output of processes.
+    #[token = "printlimit="]
+    PrintLimit,
+
+    /// How many bytes a process may store in files.
+    #[token = "fsquota="]
+    FsQuota,
+
+    /// What happens if the kernel panics on an app core.
+    #[token = "panic="]
+    Panic,
+
     /// Log token.
     #[token = "log="]
     Log,
@@ -81,6 +104,11 @@ enum CmdToken {
     #[regex = "'[0-9a-zA-Z =,-_]+'"]
     AppCmdLine,
 
+    /// Settings as key:value pairs (e.g., 'slab_limit:4096,refill:8' for
+    /// malloc= or 'drop:4096' for printlimit=)
+    #[regex = "[a-z_]+:[0-9]+(,[a-z_]+:[0-9]+)*"]
+    KeyValues,
+
     /// Anything not properly encoded
     #[error]
     Error,
@@ -94,6 +122,13 @@ pub struct BootloaderArguments {
     pub test_binary: &'static str,
     pub test_cmdline: &'static str,
     pub app_cmdline: &'static str,
+    pub policy: SchedulingPolicy,
+    pub malloc_conf: &'static str,
+    pub print_limit: &'static str,
+    pub fs_quota: Option<usize>,
+    /// Take an app core whose kernel panics out of service instead of
+    /// shutting the machine down (`panic=isolate`).
+    pub isolate_panics: bool,
 }
 
 impl BootloaderArguments {
@@ -158,6 +193,59 @@ impl BootloaderArguments {
                         ),
                     };
                 }
+                (CmdToken::Policy, _) => {
+                    lexer.advance();
+                    parsed_args.policy = match (lexer.token, lexer.slice()) {
+                        (CmdToken::File, "fair") => SchedulingPolicy::Fair,
+                        (CmdToken::File, "gang") => SchedulingPolicy::Gang,
+                        (key, v) => unreachable!(
+                            "Malformed command-line parsing policy: {:?} -> {:?}",
+                            key, v
+                        ),
+                    };
+                }
+                (CmdToken::Malloc, _) => {
+                    lexer.advance();
+                    parsed_args.malloc_conf = match (lexer.token, lexer.slice()) {
+                        (CmdToken::KeyValues, malloc_conf) => malloc_conf,
+                        (key, v) => unreachable!(
+                            "Malformed command-line parsing malloc: {:?} -> {:?}",
+                            key, v
+                        ),
+                    };
+                }
+                (CmdToken::PrintLimit, _) => {
+                    lexer.advance();
+                    parsed_args.print_limit = match (lexer.token, lexer.slice()) {
+                        (CmdToken::KeyValues, print_limit) => print_limit,
+                        (key, v) => unreachable!(
+                            "Malformed command-line parsing printlimit: {:?} -> {:?}",
+                            key, v
+                        ),
+                    };
+                }
+                (CmdToken::FsQuota, _) => {
+                    lexer.advance();
+                    parsed_args.fs_quota = match (lexer.token, lexer.slice().parse()) {
+                        (CmdToken::CmdLine, Ok(bytes)) => Some(bytes),
+                        (key, _) => unreachable!(
+                            "Malformed command-line parsing fsquota: {:?} -> {:?}",
+                            key,
+                            lexer.slice()
+                        ),
+                    };
+                }
+                (CmdToken::Panic, _) => {
+                    lexer.advance();
+                    parsed_args.isolate_panics = match (lexer.token, lexer.slice()) {
+                        (CmdToken::File, "isolate") => true,
+                        (CmdToken::File, "shutdown") => false,
+                        (key, v) => unreachable!(
+                            "Malformed command-line parsing panic: {:?} -> {:?}",
+                            key, v
+                        ),
+                    };
+                }
                 (CmdToken::End, _) => break,
                 (_, _) => continue,
             };
@@ -174,10 +262,34 @@ impl Default for BootloaderArguments {
             test_binary: "init",
             test_cmdline: "init",
             app_cmdline: "",
+            policy: SchedulingPolicy::Fair,
+            malloc_conf: "",
+            print_limit: "",
+            fs_quota: None,
+            isolate_panics: false,
         }
     }
 }
 
+/// The phases memory management of a core goes through while it boots.
+///
+/// A core only moves forward (`Early` -> `NumaReady` -> `Full`) and every
+/// step swaps the allocators in one go (see `set_global_memory` and
+/// `set_physical_memory_manager`). Code that needs a later phase can check
+/// for it with `Kcb::require_memory_phase`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum MemoryPhase {
+    /// Only the early allocator (`emanager`) works, we don't know the NUMA
+    /// topology yet.
+    Early,
+    /// `GlobalMemory` exists (all memory is annotated with its NUMA node)
+    /// but the core still allocates from the early allocator.
+    NumaReady,
+    /// The core allocates from its own `TCache` (refilled from
+    /// `GlobalMemory`).
+    Full,
+}
+
 /// State which allows to do memory management for a particular
 /// NUMA node on a given core.
 pub struct PhysicalMemoryArena {
@@ -236,6 +348,9 @@ pub struct Kcb<A: ArchSpecificKcb> {
     /// A handle to the early page-allocator.
     pub emanager: RefCell<TCacheSp>,
 
+    /// Which allocators memory management of the core uses.
+    memory_phase: MemoryPhase,
+
     /// A handle to a bump-style emergency Allocator.
     pub ezone_allocator: RefCell<EmergencyAllocator>,
 
@@ -260,6 +375,12 @@ pub struct Kcb<A: ArchSpecificKcb> {
 
     /// Measures cycles spent in TLB shootdown handler for responder.
     pub tlb_time: u64,
+
+    /// Cycles spent in the TLB shootdown handler (per invocation).
+    pub tlb_handler: Histogram,
+
+    /// Records operations dispatched on this core (with `nrtrace` feature).
+    pub nr_trace: Option<TraceBuffer>,
 }
 
 impl<A: ArchSpecificKcb> Kcb<A> {
@@ -276,6 +397,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             in_panic_mode: false,
             kernel_binary,
             emanager: RefCell::new(emanager),
+            memory_phase: MemoryPhase::Early,
             ezone_allocator: RefCell::new(EmergencyAllocator::default()),
             node,
             memory_arenas: arr![None; 12], // crate::arch::MAX_NUMA_NODES
@@ -286,6 +408,8 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             print_buffer: None,
             replica: None,
             tlb_time: 0,
+            tlb_handler: Histogram::new(),
+            nr_trace: None,
         }
     }
 
@@ -310,11 +434,36 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         init_kcb(self);
     }
 
+    /// Moves the core to `MemoryPhase::NumaReady`.
     pub fn set_global_memory(&mut self, gm: &'static GlobalMemory) {
+        assert_eq!(
+            self.memory_phase,
+            MemoryPhase::Early,
+            "GlobalMemory is set twice"
+        );
         self.physical_memory.gmanager = Some(gm);
+        self.memory_phase = MemoryPhase::NumaReady;
+    }
+
+    /// In which phase memory management of the core is.
+    pub fn memory_phase(&self) -> MemoryPhase {
+        self.memory_phase
+    }
+
+    /// Panics if the core didn't reach `phase` yet (i.e., something that
+    /// needs it runs too early during boot).
+    #[track_caller]
+    pub fn require_memory_phase(&self, phase: MemoryPhase) {
+        assert!(
+            self.memory_phase >= phase,
+            "Needs memory phase {:?} but the core is in {:?}",
+            phase,
+            self.memory_phase
+        );
     }
 
     pub fn set_allocation_affinity(&mut self, node: topology::NodeId) -> Result<(), KError> {
+        self.require_memory_phase(MemoryPhase::NumaReady);
         let node_idx: usize = node.try_into().unwrap();
         if node == self.physical_memory.affinity {
             // Allocation affinity is already set to correct NUMA node
@@ -344,8 +493,25 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         }
     }
 
+    /// Moves the core to `MemoryPhase::Full`, from now on it allocates from
+    /// `pmanager`.
+    ///
+    /// # Panics
+    /// If the core isn't `MemoryPhase::NumaReady` or the early allocator is
+    /// still in use (e.g., by an allocation that logs and allocates again),
+    /// we'd switch allocators in the middle of it.
     pub fn set_physical_memory_manager(&mut self, pmanager: TCache) {
+        assert_eq!(
+            self.memory_phase,
+            MemoryPhase::NumaReady,
+            "Physical memory manager set before GlobalMemory"
+        );
+        assert!(
+            self.emanager.try_borrow_mut().is_ok(),
+            "Early allocator is in use"
+        );
         self.physical_memory.pmanager = Some(RefCell::new(pmanager));
+        self.memory_phase = MemoryPhase::Full;
     }
 
     pub fn enable_print_buffering(&mut self, buffer: String) {
@@ -411,7 +577,11 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     /// overhead. Accessing the memfs in multiple threads is unsafe.
     pub fn init_memfs(&mut self) {
         self.memfs = Some(Default::default());
-        let _result = self.memfs.as_mut().unwrap().create("bespin", 0x007);
+        let _result = self
+            .memfs
+            .as_mut()
+            .unwrap()
+            .create(Credentials::ROOT, "bespin", 0x007);
     }
 }
 
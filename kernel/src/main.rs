@@ -22,7 +22,8 @@
     alloc_prelude,
     try_reserve,
     new_uninit,
-    get_mut_unchecked
+    get_mut_unchecked,
+    thread_local
 )]
 #![cfg_attr(
     all(not(test), not(feature = "integration-test"), target_os = "none"),
@@ -65,11 +66,13 @@ mod memory;
 mod mlnr;
 mod mlnrfs;
 mod nr;
+mod nrtrace;
 #[macro_use]
 mod prelude;
 mod process;
 mod scheduler;
 mod stack;
+mod stats;
 
 pub mod panic;
 
@@ -106,7 +109,8 @@ pub enum ExitReason {
 #[no_mangle]
 #[cfg(not(feature = "integration-test"))]
 pub fn xmain() {
-    let _r = arch::process::spawn("init").expect("Can't launch init");
+    let kcb = kcb::get_kcb();
+    let _r = arch::process::spawn("init", "", None, kcb.cmdline.policy).expect("Can't launch init");
     crate::scheduler::schedule()
 }
 
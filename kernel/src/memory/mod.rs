@@ -20,6 +20,7 @@ use core::sync::atomic::AtomicU64;
 
 use arrayvec::ArrayVec;
 use custom_error::custom_error;
+use kpi::SystemCallError;
 use slabmalloc::{Allocator, ZoneAllocator};
 use spin::Mutex;
 use x86::bits64::paging;
@@ -32,8 +33,8 @@ pub mod vspace;
 
 /// Re-export arch specific memory definitions
 pub use crate::arch::memory::{
-    kernel_vaddr_to_paddr, paddr_to_kernel_vaddr, PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE,
-    LARGE_PAGE_SIZE,
+    kernel_vaddr_to_paddr, paddr_to_kernel_vaddr, PAddr, VAddr, BASE_PAGE_SIZE, HUGE_PAGE_SIZE,
+    KERNEL_BASE, LARGE_PAGE_SIZE,
 };
 
 use crate::kcb;
@@ -52,6 +53,19 @@ custom_error! {
     ManagerAlreadyBorrowed = "The memory manager was already borrowed (this is a bug).",
 }
 
+impl Into<SystemCallError> for AllocationError {
+    fn into(self) -> SystemCallError {
+        match self {
+            AllocationError::InvalidLayout => SystemCallError::InternalError,
+            AllocationError::CacheExhausted => SystemCallError::OutOfMemory,
+            AllocationError::CacheFull => SystemCallError::OutOfMemory,
+            AllocationError::CantGrowFurther { .. } => SystemCallError::OutOfMemory,
+            AllocationError::KcbUnavailable => SystemCallError::InternalError,
+            AllocationError::ManagerAlreadyBorrowed => SystemCallError::InternalError,
+        }
+    }
+}
+
 impl From<slabmalloc::AllocationError> for AllocationError {
     fn from(err: slabmalloc::AllocationError) -> AllocationError {
         match err {
@@ -90,10 +104,28 @@ enum AllocatorType {
 }
 
 /// Implements the kernel memory allocation strategy.
+///
+/// Small objects (e.g., `Fd`s, TLB shootdowns or executors) don't take a
+/// frame each: they come from the slabs of the core-local
+/// `slabmalloc::ZoneAllocator`, which gets its pages from the TCache. So
+/// there is no separate object cache for fixed-size kernel objects.
 pub struct KernelAllocator {
     big_objects_sbrk: AtomicU64,
 }
 
+/// Calculate how many huge pages we can use to map `size` bytes at `base`.
+///
+/// # Returns
+/// The number of huge-pages (0 if `base` is not aligned to `HUGE_PAGE_SIZE`),
+/// the rest of `size` should be mapped with `size_to_pages`.
+pub fn size_to_huge_pages(base: VAddr, size: usize) -> usize {
+    if base % HUGE_PAGE_SIZE == 0 {
+        size / HUGE_PAGE_SIZE
+    } else {
+        0
+    }
+}
+
 /// Calculate how many base and large pages we need to fit a given size.
 ///
 /// # Returns
@@ -294,12 +326,12 @@ impl KernelAllocator {
         needed_large_pages: usize,
     ) -> Result<(), AllocationError> {
         let kcb = kcb::try_get_kcb().ok_or(AllocationError::KcbUnavailable)?;
-        if kcb.physical_memory.gmanager.is_none() {
-            // No gmanager, can't refill then, let's hope it works anyways...
+        if kcb.memory_phase() < kcb::MemoryPhase::NumaReady {
+            // No gmanager yet, can't refill then, let's hope it works anyways...
             return Ok(());
         }
 
-        let gmanager = kcb.physical_memory.gmanager.unwrap(); // Ok because of check above.
+        let gmanager = kcb.physical_memory.gmanager.unwrap(); // Ok because of the phase.
         let mut ncache = gmanager.node_caches[kcb.physical_memory.affinity as usize].lock();
         let mut mem_manager = kcb.try_mem_manager()?;
 
@@ -326,6 +358,51 @@ impl KernelAllocator {
         Ok(())
     }
 
+    /// Allocate a huge-page from the NCache of our NUMA node.
+    ///
+    /// Huge-pages are too big to be cached in the TCache so we
+    /// always go to the NCache for them.
+    pub fn allocate_huge_page() -> Result<Frame, AllocationError> {
+        let kcb = kcb::try_get_kcb().ok_or(AllocationError::KcbUnavailable)?;
+        let gmanager = kcb
+            .physical_memory
+            .gmanager
+            .ok_or(AllocationError::CacheExhausted)?;
+        let mut ncache = gmanager.node_caches[kcb.physical_memory.affinity as usize].lock();
+        ncache.allocate_huge_page()
+    }
+
+    /// Give a frame (of any page-size) back to the allocators.
+    ///
+    /// Frames of our NUMA node go to the TCache (or the NCache if the TCache
+    /// is full), the other ones go straight to the NCache of their node.
+    pub fn release_frame(frame: Frame) -> Result<(), AllocationError> {
+        let kcb = kcb::try_get_kcb().ok_or(AllocationError::KcbUnavailable)?;
+        if frame.affinity == kcb.physical_memory.affinity {
+            let mut mem_manager = kcb.try_mem_manager()?;
+            let r = match frame.size() {
+                BASE_PAGE_SIZE => mem_manager.release_base_page(frame),
+                LARGE_PAGE_SIZE => mem_manager.release_large_page(frame),
+                _ => Err(AllocationError::CacheFull),
+            };
+            if r.is_ok() {
+                return Ok(());
+            }
+        }
+
+        let gmanager = kcb
+            .physical_memory
+            .gmanager
+            .ok_or(AllocationError::CacheFull)?;
+        let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+        match frame.size() {
+            BASE_PAGE_SIZE => ncache.release_base_page(frame),
+            LARGE_PAGE_SIZE => ncache.release_large_page(frame),
+            HUGE_PAGE_SIZE => ncache.release_huge_page(frame),
+            _ => Err(AllocationError::InvalidLayout),
+        }
+    }
+
     /// Refill TCache only if the layout will exhaust the cache's current
     /// stored memory
     ///
@@ -538,9 +615,21 @@ unsafe impl GlobalAlloc for KernelAllocator {
                             kcb.physical_memory.affinity,
                         );
 
-                        fmanager
-                            .release_large_page(frame)
-                            .expect("Can't deallocate frame");
+                        match fmanager.release_large_page(frame) {
+                            Ok(_) => { /* Frame addition to tcache as successful.*/ }
+                            // A lot of memory can come back at once (e.g., when
+                            // files get evicted), the rest goes to the ncache.
+                            Err(_e) => match kcb.physical_memory.gmanager {
+                                Some(gmanager) => {
+                                    let mut ncache =
+                                        gmanager.node_caches[frame.affinity as usize].lock();
+                                    ncache
+                                        .release_large_page(frame)
+                                        .expect("Can't deallocate frame");
+                                }
+                                None => unreachable!("Unable to access global memory manager"),
+                            },
+                        }
                     } else {
                         error!("Loosing large memory region. Oh well.")
                     }
@@ -791,6 +880,18 @@ pub trait PhysicalPageProvider {
     fn allocate_large_page(&mut self) -> Result<Frame, AllocationError>;
     /// Release a `LARGE_PAGE_SIZE` for the given architecture back to the allocator.
     fn release_large_page(&mut self, f: Frame) -> Result<(), AllocationError>;
+
+    /// Allocate a `HUGE_PAGE_SIZE` for the given architecture from the allocator.
+    ///
+    /// Not every allocator keeps huge-pages around (e.g., a core-local cache
+    /// is too small to hold one), these just report that they are exhausted.
+    fn allocate_huge_page(&mut self) -> Result<Frame, AllocationError> {
+        Err(AllocationError::CacheExhausted)
+    }
+    /// Release a `HUGE_PAGE_SIZE` for the given architecture back to the allocator.
+    fn release_huge_page(&mut self, _f: Frame) -> Result<(), AllocationError> {
+        Err(AllocationError::CacheFull)
+    }
 }
 
 /// The backend implementation necessary to implement if we want a client to be
@@ -860,6 +961,16 @@ pub trait AllocatorStatistics {
     fn free_large_pages(&self) -> usize {
         0
     }
+
+    /// External fragmentation of the free memory (in per-mille).
+    ///
+    /// This is the part of the free memory that can't be handed out in the
+    /// biggest blocks the allocator usually deals in (e.g., free base pages
+    /// that don't help an allocation that needs a large page). Some
+    /// allocators may not be able to calculate it.
+    fn external_fragmentation(&self) -> usize {
+        0
+    }
 }
 
 pub trait PhysicalAllocator {
@@ -1053,6 +1164,10 @@ impl Frame {
         self.base % LARGE_PAGE_SIZE == 0
     }
 
+    pub fn is_huge_page_aligned(&self) -> bool {
+        self.base % HUGE_PAGE_SIZE == 0
+    }
+
     /// Size of the region (in bytes).
     pub fn size(&self) -> usize {
         self.size
@@ -1287,6 +1402,26 @@ mod tests {
         assert!(f.is_large_page_aligned());
     }
 
+    #[test]
+    fn frame_huge_page_aligned() {
+        let f = Frame::new(PAddr::from(LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        assert!(!f.is_huge_page_aligned());
+
+        let f = Frame::new(PAddr::from(2 * HUGE_PAGE_SIZE), HUGE_PAGE_SIZE, 0);
+        assert!(f.is_huge_page_aligned());
+    }
+
+    #[test]
+    fn size_to_huge_pages_needs_alignment() {
+        let size = 2 * HUGE_PAGE_SIZE + LARGE_PAGE_SIZE;
+        assert_eq!(size_to_huge_pages(VAddr::from(HUGE_PAGE_SIZE), size), 2);
+        assert_eq!(size_to_huge_pages(VAddr::from(LARGE_PAGE_SIZE), size), 0);
+        assert_eq!(
+            size_to_huge_pages(VAddr::from(HUGE_PAGE_SIZE), LARGE_PAGE_SIZE),
+            0
+        );
+    }
+
     #[test]
     fn frame_split_at() {
         let f = Frame::new(PAddr::from(0xf000), 4096 * 10, 0);
@@ -6,6 +6,7 @@
 //!
 //! - Fits in a 2 MiB page
 //! - Can allocate and free 2 MiB and 4 KiB Frames very quickly using stacks.
+//! - Can hand out 1 GiB Frames (slowly) by combining contiguous 2 MiB Frames.
 //! - Is not thread-safe, need to wrap it in a Mutex (ideally we have two
 //!   interior Mutex for base-page and large-page arrays but that's problematic
 //!   because our traits are currently using &mut self).
@@ -123,6 +124,10 @@ impl NCache {
         Frame::new(pa, LARGE_PAGE_SIZE, self.node)
     }
 
+    fn paddr_to_huge_page(&self, pa: PAddr) -> Frame {
+        Frame::new(pa, HUGE_PAGE_SIZE, self.node)
+    }
+
     /// How much free memory we can maintain.
     fn capacity(&self) -> usize {
         self.base_page_addresses.capacity() * BASE_PAGE_SIZE
@@ -183,6 +188,16 @@ impl AllocatorStatistics for NCache {
     fn free_large_pages(&self) -> usize {
         self.large_page_addresses.len()
     }
+
+    /// Free memory we can only hand out as base-pages.
+    fn external_fragmentation(&self) -> usize {
+        let free = AllocatorStatistics::free(self);
+        if free == 0 {
+            0
+        } else {
+            (self.base_page_addresses.len() * BASE_PAGE_SIZE * 1000) / free
+        }
+    }
 }
 
 impl PhysicalPageProvider for NCache {
@@ -221,6 +236,49 @@ impl PhysicalPageProvider for NCache {
             .try_push(frame.base)
             .map_err(|_e| AllocationError::CacheFull)
     }
+
+    /// Allocate a huge-page by taking out enough large-pages to form a
+    /// physically contiguous, aligned 1 GiB region.
+    ///
+    /// We don't keep a separate stack for huge-pages (they would be
+    /// lost for large-page allocations). Instead we sort the large-page
+    /// stack, this is slow but huge-page allocations should be rare.
+    fn allocate_huge_page(&mut self) -> Result<Frame, AllocationError> {
+        const LARGE_PAGES_PER_HUGE_PAGE: usize = HUGE_PAGE_SIZE / LARGE_PAGE_SIZE;
+        self.large_page_addresses.sort_unstable();
+
+        // Addresses are unique and large-page aligned, so if the first and
+        // last large-page of a window are 1 GiB apart the window is contiguous
+        let mut idx = 0;
+        while idx + LARGE_PAGES_PER_HUGE_PAGE <= self.large_page_addresses.len() {
+            let base = self.large_page_addresses[idx];
+            let last = self.large_page_addresses[idx + LARGE_PAGES_PER_HUGE_PAGE - 1];
+            if base % HUGE_PAGE_SIZE == 0 && last == base + (HUGE_PAGE_SIZE - LARGE_PAGE_SIZE) {
+                self.large_page_addresses
+                    .drain(idx..idx + LARGE_PAGES_PER_HUGE_PAGE);
+                return Ok(self.paddr_to_huge_page(base));
+            }
+            idx += 1;
+        }
+
+        Err(AllocationError::CacheExhausted)
+    }
+
+    /// Release a huge-page by splitting it back into large-pages.
+    fn release_huge_page(&mut self, frame: Frame) -> Result<(), AllocationError> {
+        assert_eq!(frame.size(), HUGE_PAGE_SIZE);
+        assert_eq!(frame.base % HUGE_PAGE_SIZE, 0);
+        assert_eq!(frame.affinity, self.node);
+
+        if self.large_page_addresses.remaining_capacity() < HUGE_PAGE_SIZE / LARGE_PAGE_SIZE {
+            return Err(AllocationError::CacheFull);
+        }
+        for offset in (0..HUGE_PAGE_SIZE).step_by(LARGE_PAGE_SIZE) {
+            self.large_page_addresses.push(frame.base + offset);
+        }
+
+        Ok(())
+    }
 }
 
 impl GrowBackend for NCache {
@@ -293,8 +351,9 @@ mod test {
             use core::alloc::Layout;
             let layout = Layout::new::<NCache>();
             let global_alloc = std::alloc::System;
-            let ptr = global_alloc.alloc_zeroed(layout);
-            core::mem::transmute(ptr as *mut NCache)
+            let ptr = global_alloc.alloc_zeroed(layout) as *mut NCache;
+            assert!(!ptr.is_null());
+            &mut *ptr
         }
     }
 
@@ -443,4 +502,83 @@ mod test {
             .allocate_base_page()
             .expect_err("Can't allocate more than we gave it");
     }
+
+    /// Test that we can combine large-pages into huge-pages and split them
+    /// up again on release.
+    #[test]
+    fn ncache_huge_page() {
+        let mut ncache = get_an_ncache();
+        ncache.node = 1;
+        let large_pages_per_huge_page = HUGE_PAGE_SIZE / LARGE_PAGE_SIZE;
+
+        // Not enough memory for a huge-page
+        ncache
+            .release_large_page(Frame::new(PAddr::from(HUGE_PAGE_SIZE), LARGE_PAGE_SIZE, 1))
+            .expect("release");
+        ncache
+            .allocate_huge_page()
+            .expect_err("Can't allocate a huge-page");
+
+        // Add the rest of the huge-page (in reverse order) and a large-page
+        // before it which makes the stack unaligned
+        for i in (1..large_pages_per_huge_page).rev() {
+            ncache
+                .release_large_page(Frame::new(
+                    PAddr::from(HUGE_PAGE_SIZE + i * LARGE_PAGE_SIZE),
+                    LARGE_PAGE_SIZE,
+                    1,
+                ))
+                .expect("release");
+        }
+        ncache
+            .release_large_page(Frame::new(
+                PAddr::from(HUGE_PAGE_SIZE - LARGE_PAGE_SIZE),
+                LARGE_PAGE_SIZE,
+                1,
+            ))
+            .expect("release");
+        assert_eq!(ncache.free(), HUGE_PAGE_SIZE + LARGE_PAGE_SIZE);
+
+        let f = ncache.allocate_huge_page().expect("Can allocate");
+        assert_eq!(f.base.as_usize(), HUGE_PAGE_SIZE);
+        assert_eq!(f.size, HUGE_PAGE_SIZE);
+        assert_eq!(f.affinity, 1);
+        assert_eq!(ncache.free(), LARGE_PAGE_SIZE);
+        ncache
+            .allocate_huge_page()
+            .expect_err("Can't allocate a second huge-page");
+
+        let lp = ncache.allocate_large_page().expect("Can allocate");
+        assert_eq!(lp.base.as_usize(), HUGE_PAGE_SIZE - LARGE_PAGE_SIZE);
+
+        ncache.release_huge_page(f).expect("release");
+        assert_eq!(ncache.free(), HUGE_PAGE_SIZE);
+        assert_eq!(ncache.free_large_pages(), large_pages_per_huge_page);
+        let f = ncache.allocate_huge_page().expect("Can allocate again");
+        assert_eq!(f.base.as_usize(), HUGE_PAGE_SIZE);
+        assert_eq!(ncache.free(), 0);
+    }
+
+    /// Test that free memory in base-pages counts as fragmented.
+    #[test]
+    fn ncache_external_fragmentation() {
+        let mut ncache = get_an_ncache();
+        assert_eq!(ncache.external_fragmentation(), 0);
+
+        ncache
+            .release_large_page(Frame::new(PAddr::from(LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0))
+            .expect("release");
+        assert_eq!(ncache.external_fragmentation(), 0);
+
+        ncache
+            .release_base_page(Frame::new(PAddr::from(0x1000), BASE_PAGE_SIZE, 0))
+            .expect("release");
+        assert_eq!(
+            ncache.external_fragmentation(),
+            (BASE_PAGE_SIZE * 1000) / (LARGE_PAGE_SIZE + BASE_PAGE_SIZE)
+        );
+
+        let _lp = ncache.allocate_large_page().expect("Can allocate");
+        assert_eq!(ncache.external_fragmentation(), 1000);
+    }
 }
@@ -4,6 +4,7 @@
 //!
 //! - Fits in a 4 KiB page
 //! - Can allocate and free 2 MiB and 4 KiB Frames very quickly using page-inlined stacks.
+//! - Doesn't hold 1 GiB Frames (these are allocated from the NCache directly)
 //! - Is not thread-safe (intended to be used on a single CPU)
 
 use super::*;
@@ -1,5 +1,6 @@
 //! An trait defining architecture specific address spaces.
 
+use alloc::collections::BTreeMap;
 use alloc::string::ToString;
 use alloc::vec::Vec;
 use core::cmp::PartialEq;
@@ -8,10 +9,11 @@ use core::fmt;
 
 use bit_vec::BitVec;
 use custom_error::custom_error;
+use kpi::process::MemoryRights;
 use kpi::SystemCallError;
 use x86::current::paging::{PDFlags, PDPTFlags, PTFlags};
 
-use super::{Frame, PAddr, VAddr};
+use super::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct TlbFlushHandle {
@@ -35,12 +37,27 @@ impl TlbFlushHandle {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum MappingType {
     ElfText,
     ElfData,
     Executor,
     Heap,
+    /// Device memory, it doesn't belong to the process (or any allocator).
+    Device,
+    /// Memory the kernel shares with the process (e.g., an event ring), the
+    /// kernel gives it back, not the process.
+    Kernel,
+    /// A page of a MemFS file (see `Op::FileMmap`), it belongs to the file.
+    File,
+}
+
+impl MappingType {
+    /// Whether the frame of the mapping belongs to the process (and is given
+    /// back to the allocators once it exited).
+    pub fn is_owned(&self) -> bool {
+        *self != MappingType::Device && *self != MappingType::Kernel && *self != MappingType::File
+    }
 }
 
 pub struct MappingInfo {
@@ -74,6 +91,120 @@ impl fmt::Debug for MappingInfo {
     }
 }
 
+/// The mapped regions of an address space, with their rights.
+///
+/// Adjacent mappings with the same rights are merged into one region, so
+/// checking a range usually needs a single lookup (instead of a page-table
+/// walk for every page in it).
+#[derive(Debug, Default)]
+pub struct VRegions {
+    /// Base of a region -> length and rights.
+    regions: BTreeMap<VAddr, (usize, MapAction)>,
+}
+
+impl VRegions {
+    pub fn new() -> Self {
+        VRegions {
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// The region that contains `vaddr` (base, length and rights).
+    pub fn lookup(&self, vaddr: VAddr) -> Option<(VAddr, usize, MapAction)> {
+        let (base, (len, rights)) = self.regions.range(..=vaddr).next_back()?;
+        if vaddr.as_usize() - base.as_usize() < *len {
+            Some((*base, *len, *rights))
+        } else {
+            None
+        }
+    }
+
+    /// Adds `[base, base+len)`, which must not overlap an existing region.
+    pub fn insert(&mut self, base: VAddr, len: usize, rights: MapAction) {
+        if len == 0 {
+            return;
+        }
+        let (mut base, mut len) = (base.as_usize(), len);
+
+        let before = self
+            .regions
+            .range(..VAddr::from(base))
+            .next_back()
+            .map(|(b, r)| (*b, *r));
+        if let Some((prev, (prev_len, prev_rights))) = before {
+            if prev.as_usize() + prev_len == base && prev_rights == rights {
+                self.regions.remove(&prev);
+                base = prev.as_usize();
+                len += prev_len;
+            }
+        }
+
+        let end = VAddr::from(base + len);
+        if let Some((next_len, next_rights)) = self.regions.get(&end).copied() {
+            if next_rights == rights {
+                self.regions.remove(&end);
+                len += next_len;
+            }
+        }
+
+        self.regions.insert(VAddr::from(base), (len, rights));
+    }
+
+    /// Removes `[base, base+len)`, regions that are only partly in the range
+    /// get split.
+    pub fn remove(&mut self, base: VAddr, len: usize) {
+        let (base, end) = (base.as_usize(), base.as_usize() + len);
+        while let Some((region, (region_len, rights))) = self
+            .regions
+            .range(..VAddr::from(end))
+            .next_back()
+            .map(|(b, r)| (b.as_usize(), *r))
+        {
+            let region_end = region + region_len;
+            if region_end <= base {
+                break;
+            }
+
+            self.regions.remove(&VAddr::from(region));
+            if region < base {
+                self.regions
+                    .insert(VAddr::from(region), (base - region, rights));
+            }
+            if region_end > end {
+                self.regions
+                    .insert(VAddr::from(end), (region_end - end, rights));
+            }
+        }
+    }
+
+    /// Changes the rights of `[base, base+len)`, which must be mapped.
+    pub fn set_rights(&mut self, base: VAddr, len: usize, rights: MapAction) {
+        self.remove(base, len);
+        self.insert(base, len, rights);
+    }
+
+    /// Checks that every byte of `[base, base+size)` is in a region (a
+    /// `size` of 0 checks only `base`) whose rights allow user-space the
+    /// `access` (see `MapAction::allows`).
+    pub fn contains(&self, base: VAddr, size: usize, access: Access) -> bool {
+        let end = match base.as_usize().checked_add(core::cmp::max(size, 1)) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        let mut addr = base.as_usize();
+        while addr < end {
+            match self.lookup(VAddr::from(addr)) {
+                Some((region, len, rights)) if rights.allows(access) => {
+                    addr = region.as_usize() + len
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
 /// Generic address space functionality.
 pub trait AddressSpace {
     /// Maps a list of `frames` at `base` in the address space
@@ -136,6 +267,30 @@ pub trait AddressSpace {
     /// invoked to flush the TLB.
     fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, AddressSpaceError>;
 
+    /// Checks that every byte of `[base, base+size)` is mapped (a `size` of
+    /// 0 checks only `base`) with rights that allow user-space the `access`.
+    ///
+    /// The default implementation resolves every page in the range.
+    fn is_mapped(&self, base: VAddr, size: usize, access: Access) -> bool {
+        let last = match base.as_usize().checked_add(size) {
+            Some(_upper) if size == 0 => base.as_usize(),
+            Some(upper) => upper - 1,
+            None => return false,
+        };
+
+        let mut addr = base.as_usize();
+        loop {
+            match self.resolve(VAddr::from(addr)) {
+                Ok((_paddr, rights)) if rights.allows(access) => {}
+                _ => return false,
+            }
+            match (addr & !(BASE_PAGE_SIZE - 1)).checked_add(BASE_PAGE_SIZE) {
+                Some(next) if next <= last => addr = next,
+                _ => return true,
+            }
+        }
+    }
+
     // Returns an iterator of all currently mapped memory regions.
     //fn mappings()
 }
@@ -149,21 +304,31 @@ pub AddressSpaceError
     NotMapped = "The requested mapping was not found",
     InvalidLength = "The supplied length was invalid",
     InvalidBase = "The supplied base was invalid (alignment?)",
+    InvalidRights{rights: u64} = "The supplied access rights {:#x} are invalid",
 }
 
 impl Into<SystemCallError> for AddressSpaceError {
     fn into(self) -> SystemCallError {
         match self {
             AddressSpaceError::InvalidFrame => SystemCallError::InternalError,
-            AddressSpaceError::AlreadyMapped { .. } => SystemCallError::InternalError,
-            AddressSpaceError::BaseOverflow { .. } => SystemCallError::InternalError,
-            AddressSpaceError::NotMapped => SystemCallError::InternalError,
-            AddressSpaceError::InvalidLength => SystemCallError::InternalError,
-            AddressSpaceError::InvalidBase => SystemCallError::InternalError,
+            AddressSpaceError::AlreadyMapped { .. } => SystemCallError::VSpaceAlreadyMapped,
+            AddressSpaceError::BaseOverflow { .. } => SystemCallError::BadAddress,
+            AddressSpaceError::NotMapped => SystemCallError::BadAddress,
+            AddressSpaceError::InvalidLength => SystemCallError::BadAddress,
+            AddressSpaceError::InvalidBase => SystemCallError::BadAddress,
+            AddressSpaceError::InvalidRights { .. } => SystemCallError::BadFlags,
         }
     }
 }
 
+/// How the kernel accesses the memory of a process on its behalf (e.g., a
+/// buffer of a system call).
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+}
+
 /// Mapping rights to give to address translation.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[allow(unused)]
@@ -188,9 +353,30 @@ pub enum MapAction {
     ReadWriteExecuteUser,
     /// Map region read-write-executable for kernel.
     ReadWriteExecuteKernel,
+    /// Map region as a (CET) shadow stack, only calls and returns of
+    /// user-space can write it.
+    ShadowStackUser,
 }
 
 impl MapAction {
+    /// Whether user-space may do `access` to memory mapped with these rights.
+    ///
+    /// Guard pages, kernel memory and shadow stacks (only calls and returns
+    /// write them) don't allow any.
+    pub fn allows(&self, access: Access) -> bool {
+        use MapAction::*;
+        match self {
+            ReadUser | ReadExecuteUser => access == Access::Read,
+            ReadWriteUser | ReadWriteUserNoCache | ReadWriteExecuteUser => true,
+            None
+            | ReadKernel
+            | ReadWriteKernel
+            | ReadExecuteKernel
+            | ReadWriteExecuteKernel
+            | ShadowStackUser => false,
+        }
+    }
+
     /// Transform MapAction into rights for 1 GiB page.
     pub fn to_pdpt_rights(&self) -> PDPTFlags {
         use MapAction::*;
@@ -205,6 +391,8 @@ impl MapAction {
             ReadExecuteKernel => PDPTFlags::empty(),
             ReadWriteExecuteUser => PDPTFlags::RW | PDPTFlags::US,
             ReadWriteExecuteKernel => PDPTFlags::RW,
+            // Read-only but dirty is how a shadow stack page looks like
+            ShadowStackUser => PDPTFlags::XD | PDPTFlags::US | PDPTFlags::D,
         }
     }
 
@@ -222,6 +410,8 @@ impl MapAction {
             ReadExecuteKernel => PDFlags::empty(),
             ReadWriteExecuteUser => PDFlags::RW | PDFlags::US,
             ReadWriteExecuteKernel => PDFlags::RW,
+            // Read-only but dirty is how a shadow stack page looks like
+            ShadowStackUser => PDFlags::XD | PDFlags::US | PDFlags::D,
         }
     }
 
@@ -239,6 +429,35 @@ impl MapAction {
             ReadExecuteKernel => PTFlags::empty(),
             ReadWriteExecuteUser => PTFlags::RW | PTFlags::US,
             ReadWriteExecuteKernel => PTFlags::RW,
+            // Read-only but dirty is how a shadow stack page looks like
+            ShadowStackUser => PTFlags::XD | PTFlags::US | PTFlags::D,
+        }
+    }
+
+    /// The rights a writable user mapping has while it is shared
+    /// copy-on-write, `None` if the mapping can't be shared that way.
+    pub fn copy_on_write(&self) -> Option<MapAction> {
+        use MapAction::*;
+        match self {
+            ReadWriteUser => Some(ReadUser),
+            ReadWriteExecuteUser => Some(ReadExecuteUser),
+            _ => Option::None,
+        }
+    }
+}
+
+/// Translate the access rights user-space asks for to a user mapping (x86
+/// can't map memory write- or execute-only, so it will also be readable).
+impl From<MemoryRights> for MapAction {
+    fn from(rights: MemoryRights) -> MapAction {
+        let writable = rights.contains(MemoryRights::WRITE);
+        let executable = rights.contains(MemoryRights::EXECUTE);
+        match (writable, executable) {
+            _ if rights.is_empty() => MapAction::None,
+            (false, false) => MapAction::ReadUser,
+            (true, false) => MapAction::ReadWriteUser,
+            (false, true) => MapAction::ReadExecuteUser,
+            (true, true) => MapAction::ReadWriteExecuteUser,
         }
     }
 }
@@ -253,7 +472,9 @@ impl From<PTFlags> for MapAction {
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PTFlags::P | PTFlags::US | PTFlags::XD {
+        if cleaned == PTFlags::P | PTFlags::US | PTFlags::XD && f.contains(PTFlags::D) {
+            ShadowStackUser
+        } else if cleaned == PTFlags::P | PTFlags::US | PTFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PTFlags::XD | PTFlags::P {
             MapAction::ReadKernel
@@ -288,7 +509,9 @@ impl From<PDFlags> for MapAction {
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PDFlags::P | PDFlags::US | PDFlags::XD {
+        if cleaned == PDFlags::P | PDFlags::US | PDFlags::XD && f.contains(PDFlags::D) {
+            ShadowStackUser
+        } else if cleaned == PDFlags::P | PDFlags::US | PDFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PDFlags::XD | PDFlags::P {
             MapAction::ReadKernel
@@ -327,7 +550,9 @@ impl From<PDPTFlags> for MapAction {
         cleaned.remove(irrelevant_bits);
 
         // Ugly if else (due to https://github.com/bitflags/bitflags/issues/201)
-        if cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD {
+        if cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD && f.contains(PDPTFlags::D) {
+            ShadowStackUser
+        } else if cleaned == PDPTFlags::P | PDPTFlags::US | PDPTFlags::XD {
             MapAction::ReadUser
         } else if cleaned == PDPTFlags::XD | PDPTFlags::P {
             MapAction::ReadKernel
@@ -367,6 +592,7 @@ impl fmt::Display for MapAction {
             ReadExecuteKernel => write!(f, "kR-X"),
             ReadWriteExecuteUser => write!(f, "uRWX"),
             ReadWriteExecuteKernel => write!(f, "kRWX"),
+            ShadowStackUser => write!(f, "uSS-"),
         }
     }
 }
@@ -755,3 +981,129 @@ pub(crate) mod model {
         assert!(ModelAddressSpace::intersection(r1, r2).is_none());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: usize = BASE_PAGE_SIZE;
+
+    fn va(addr: usize) -> VAddr {
+        VAddr::from(addr)
+    }
+
+    #[test]
+    fn vregions_merge_and_split() {
+        let mut regions = VRegions::new();
+        regions.insert(va(PAGE), PAGE, MapAction::ReadUser);
+        regions.insert(va(3 * PAGE), PAGE, MapAction::ReadUser);
+        assert!(!regions.contains(va(PAGE), 3 * PAGE, Access::Read));
+
+        // Fills the hole, everything becomes one region
+        regions.insert(va(2 * PAGE), PAGE, MapAction::ReadUser);
+        assert_eq!(regions.regions.len(), 1);
+        assert_eq!(
+            regions.lookup(va(2 * PAGE + 5)),
+            Some((va(PAGE), 3 * PAGE, MapAction::ReadUser))
+        );
+        assert!(regions.contains(va(PAGE), 3 * PAGE, Access::Read));
+        assert!(regions.contains(va(4 * PAGE - 1), 0, Access::Read));
+        assert!(!regions.contains(va(PAGE), 3 * PAGE + 1, Access::Read));
+        assert!(!regions.contains(va(PAGE - 1), 2, Access::Read));
+        assert!(!regions.contains(va(PAGE), PAGE, Access::Write));
+
+        // Different rights stay separate regions (but the range is mapped)
+        regions.set_rights(va(2 * PAGE), PAGE, MapAction::ReadWriteUser);
+        assert_eq!(regions.regions.len(), 3);
+        assert_eq!(
+            regions.lookup(va(2 * PAGE)),
+            Some((va(2 * PAGE), PAGE, MapAction::ReadWriteUser))
+        );
+        assert!(regions.contains(va(PAGE), 3 * PAGE, Access::Read));
+        assert!(regions.contains(va(2 * PAGE), PAGE, Access::Write));
+        assert!(!regions.contains(va(PAGE), 3 * PAGE, Access::Write));
+        regions.set_rights(va(2 * PAGE), PAGE, MapAction::ReadUser);
+        assert_eq!(regions.regions.len(), 1);
+
+        // Unmapping in the middle splits the region
+        regions.remove(va(2 * PAGE), PAGE);
+        assert_eq!(regions.lookup(va(2 * PAGE)), None);
+        assert_eq!(
+            regions.lookup(va(PAGE)),
+            Some((va(PAGE), PAGE, MapAction::ReadUser))
+        );
+        assert_eq!(
+            regions.lookup(va(3 * PAGE)),
+            Some((va(3 * PAGE), PAGE, MapAction::ReadUser))
+        );
+
+        // Removing a range that covers several regions
+        regions.remove(va(0), 8 * PAGE);
+        assert_eq!(regions.regions.len(), 0);
+        assert!(!regions.contains(va(PAGE), 0, Access::Read));
+    }
+
+    #[test]
+    fn vregions_guard_page() {
+        let mut regions = VRegions::new();
+        regions.insert(va(PAGE), 3 * PAGE, MapAction::ReadWriteUser);
+        regions.set_rights(va(2 * PAGE), PAGE, MapAction::None);
+        assert!(regions.contains(va(PAGE), PAGE, Access::Write));
+        assert!(!regions.contains(va(PAGE), 2 * PAGE, Access::Read));
+        assert!(!regions.contains(va(2 * PAGE), 0, Access::Read));
+        assert!(regions.contains(va(3 * PAGE), PAGE, Access::Write));
+    }
+
+    #[test]
+    fn vregions_overflow() {
+        let mut regions = VRegions::new();
+        regions.insert(va(PAGE), PAGE, MapAction::ReadUser);
+        assert!(!regions.contains(va(PAGE), usize::max_value(), Access::Read));
+        assert!(!regions.contains(va(usize::max_value()), 0, Access::Read));
+    }
+
+    #[test]
+    fn user_rights() {
+        assert_eq!(MapAction::from(MemoryRights::NONE), MapAction::None);
+        assert_eq!(MapAction::from(MemoryRights::READ), MapAction::ReadUser);
+        assert_eq!(
+            MapAction::from(MemoryRights::WRITE),
+            MapAction::ReadWriteUser
+        );
+        assert_eq!(
+            MapAction::from(MemoryRights::READ | MemoryRights::EXECUTE),
+            MapAction::ReadExecuteUser
+        );
+        assert_eq!(
+            MapAction::from(MemoryRights::all()),
+            MapAction::ReadWriteExecuteUser
+        );
+    }
+
+    #[test]
+    fn shadow_stack_rights() {
+        let action = MapAction::ShadowStackUser;
+        assert!(!action.to_pt_rights().contains(PTFlags::RW));
+        assert_eq!(MapAction::from(PTFlags::P | action.to_pt_rights()), action);
+        assert_eq!(MapAction::from(PDFlags::P | action.to_pd_rights()), action);
+        assert_eq!(
+            MapAction::from(PTFlags::P | MapAction::ReadUser.to_pt_rights()),
+            MapAction::ReadUser
+        );
+        assert_eq!(action.copy_on_write(), None);
+        assert!(!action.allows(Access::Read));
+        assert!(!action.allows(Access::Write));
+    }
+
+    #[test]
+    fn vregions_access() {
+        let mut regions = VRegions::new();
+        regions.insert(va(PAGE), PAGE, MapAction::ReadUser);
+        regions.insert(va(2 * PAGE), PAGE, MapAction::ShadowStackUser);
+        regions.insert(va(3 * PAGE), PAGE, MapAction::ReadWriteKernel);
+        assert!(regions.contains(va(PAGE), PAGE, Access::Read));
+        assert!(!regions.contains(va(PAGE), PAGE, Access::Write));
+        assert!(!regions.contains(va(2 * PAGE), PAGE, Access::Read));
+        assert!(!regions.contains(va(3 * PAGE), PAGE, Access::Read));
+    }
+}
@@ -2,16 +2,22 @@
 
 use crate::arch::process::{UserPtr, UserSlice};
 use crate::error::KError;
+use crate::fs::pipe::PIPE_MNODE;
 use crate::fs::{
-    Buffer, FileDescriptor, FileSystem, FileSystemError, Filename, Flags, Len, Modes, Offset, FD,
+    Buffer, FileDescriptor, FileSystem, FileSystemError, Filename, Flags, FsAccounting, Len, Mnode,
+    Modes, Offset, FD,
 };
 use crate::memory::VAddr;
 use crate::mlnrfs::{fd::FileDesc, MlnrFS, NrLock, MNODE_OFFSET};
+use crate::nrtrace::{OpClass, Span};
 use crate::prelude::*;
-use crate::process::{userptr_to_str, Eid, Executor, KernSlice, Pid, Process, ProcessError};
+use crate::process::{
+    userptr_to_str, Credentials, Eid, Executor, KernSlice, Pid, Process, ProcessError,
+};
 
 use alloc::sync::Arc;
 use cnr::{Dispatch, LogMapper, ReplicaToken};
+use core::intrinsics::discriminant_value;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use hashbrown::HashMap;
 use kpi::{io::*, FileOperation};
@@ -23,6 +29,8 @@ pub struct MlnrKernelNode {
     process_map: NrLock<HashMap<Pid, FileDesc>>,
     /// MLNR kernel node primarily replicates the in-memory filesystem.
     fs: MlnrFS,
+    /// Bytes every process stored in files.
+    fs_usage: NrLock<FsAccounting>,
 }
 
 impl Default for MlnrKernelNode {
@@ -30,6 +38,7 @@ impl Default for MlnrKernelNode {
         MlnrKernelNode {
             process_map: NrLock::<HashMap<Pid, FileDesc>>::default(),
             fs: MlnrFS::default(),
+            fs_usage: NrLock::new(FsAccounting::with_cmdline_quota()),
         }
     }
 }
@@ -37,13 +46,23 @@ impl Default for MlnrKernelNode {
 #[derive(Hash, Clone, Debug, PartialEq)]
 pub enum Modify {
     ProcessAdd(Pid),
+    /// Give a forked process (second) a copy of the file descriptors of its
+    /// parent (first).
+    ProcessFork(Pid, Pid),
     ProcessRemove(Pid),
     FileOpen(Pid, String, Flags, Modes),
     FileWrite(Pid, FD, Arc<[u8]>, Len, Offset),
     FileClose(Pid, FD),
+    /// Allocate the file descriptors for both ends of a pipe.
+    PipeOpen(Pid),
+    FileSeek(Pid, FD, i64, SeekWhence),
+    /// Reserve memory for a range (offset, length) of a file.
+    FileAllocate(Pid, FD, u64, u64),
     FileDelete(Pid, String),
     FileRename(Pid, String, String),
     MkDir(Pid, String, Modes),
+    /// Change the user and group the process accesses files as.
+    SetCredentials(Pid, Credentials),
     Invalid,
 }
 
@@ -52,6 +71,7 @@ impl LogMapper for Modify {
     fn hash(&self) -> usize {
         match self {
             Modify::ProcessAdd(_pid) => 0,
+            Modify::ProcessFork(_parent, _child) => 0,
             Modify::ProcessRemove(_pid) => 0,
             Modify::FileOpen(_pid, _filename, _flags, _modes) => 0,
             Modify::FileWrite(pid, fd, _kernslice, _len, _offset) => {
@@ -61,9 +81,24 @@ impl LogMapper for Modify {
                 }
             }
             Modify::FileClose(pid, fd) => 0,
+            Modify::PipeOpen(_pid) => 0,
+            // Same log as the writes, they use (and update) the offset too.
+            Modify::FileSeek(pid, fd, _offset, _whence) => {
+                match MlnrKernelNode::fd_to_mnode(*pid, *fd) {
+                    Ok((mnode, _)) => mnode as usize - MNODE_OFFSET,
+                    Err(_) => 0,
+                }
+            }
+            Modify::FileAllocate(pid, fd, _offset, _len) => {
+                match MlnrKernelNode::fd_to_mnode(*pid, *fd) {
+                    Ok((mnode, _)) => mnode as usize - MNODE_OFFSET,
+                    Err(_) => 0,
+                }
+            }
             Modify::FileDelete(_pid, _filename) => 0,
             Modify::FileRename(_pid, _oldname, _newname) => 0,
             Modify::MkDir(_pid, _name, _modes) => 0,
+            Modify::SetCredentials(_pid, _credentials) => 0,
             Modify::Invalid => unreachable!("Invalid operation"),
         }
     }
@@ -79,8 +114,11 @@ impl Default for Modify {
 pub enum Access {
     FileRead(Pid, FD, Buffer, Len, Offset),
     FileInfo(Pid, Filename, u64),
+    ReadDir(Pid, String),
     FdToMnode(Pid, FD),
     FileNameToMnode(Pid, Filename),
+    /// Bytes the process stored in files (and its quota).
+    FsUsage(Pid),
     Synchronize(usize),
 }
 
@@ -101,8 +139,10 @@ impl LogMapper for Access {
                 }
             }
             // TODO: Assume that all metadata modifying operations go through log 0.
+            Access::ReadDir(_pid, _dirname) => 0,
             Access::FdToMnode(_pid, _fd) => 0,
             Access::FileNameToMnode(_pid, _filename) => 0,
+            Access::FsUsage(_pid) => 0,
             // Log number start with 1 in CNR, however, replica uses mod
             // operation which starts with 0; hence `log_id - 1`.
             Access::Synchronize(log_id) => (*log_id - 1),
@@ -113,14 +153,21 @@ impl LogMapper for Access {
 #[derive(Clone, Debug)]
 pub enum MlnrNodeResult {
     ProcessAdded(Pid),
+    ProcessRemoved,
     FileOpened(FD),
+    PipeOpened(FD, FD),
     FileAccessed(Len),
     FileClosed(u64),
+    FileSeeked(u64),
+    FileAllocated,
     FileDeleted(bool),
     FileInfo(u64),
+    DirEntries(Vec<DirEntry>),
     FileRenamed(bool),
     DirCreated(bool),
+    CredentialsSet,
     MappedFileToMnode(u64),
+    FsUsage(usize, Option<usize>),
     Synchronized,
 }
 
@@ -142,6 +189,39 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Registers the forked process `child` with the file descriptors and
+    /// credentials of `parent`.
+    pub fn fork_process(parent: Pid, child: Pid) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Modify::ProcessFork(parent, child), *token);
+                match &response {
+                    Ok(MlnrNodeResult::ProcessAdded(pid)) => Ok((*pid, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(e) => Err(e.clone()),
+                }
+            })
+    }
+
+    /// Forgets about the file descriptors of an exited process.
+    pub fn remove_process(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Modify::ProcessRemove(pid), *token);
+                match &response {
+                    Ok(MlnrNodeResult::ProcessRemoved) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(e) => Err(e.clone()),
+                }
+            })
+    }
+
     pub fn map_fd(pid: Pid, pathname: u64, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -207,6 +287,61 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn file_seek(
+        pid: Pid,
+        fd: u64,
+        offset: i64,
+        whence: SeekWhence,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Modify::FileSeek(pid, fd, offset, whence), *token);
+
+                match &response {
+                    Ok(MlnrNodeResult::FileSeeked(new_offset)) => Ok((*new_offset, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    pub fn file_allocate(pid: Pid, fd: u64, offset: u64, len: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Modify::FileAllocate(pid, fd, offset, len), *token);
+
+                match &response {
+                    Ok(MlnrNodeResult::FileAllocated) => Ok((0, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    pub fn pipe(pid: Pid) -> Result<(FD, FD), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Modify::PipeOpen(pid), *token);
+
+                match &response {
+                    Ok(MlnrNodeResult::PipeOpened(read_fd, write_fd)) => Ok((*read_fd, *write_fd)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
     pub fn unmap_fd(pid: Pid, fd: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -260,6 +395,27 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn readdir(pid: Pid, name: u64) -> Result<Vec<DirEntry>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let dirname;
+                match userptr_to_str(name) {
+                    Ok(user_str) => dirname = user_str,
+                    Err(e) => return Err(e.clone()),
+                }
+
+                let response = replica.execute(Access::ReadDir(pid, dirname), *token);
+                match response {
+                    Ok(MlnrNodeResult::DirEntries(entries)) => Ok(entries),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
     pub fn file_rename(pid: Pid, oldname: u64, newname: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -310,6 +466,30 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn set_credentials(pid: Pid, credentials: Credentials) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Modify::SetCredentials(pid, credentials), *token);
+
+                match &response {
+                    Ok(MlnrNodeResult::CredentialsSet) => Ok((0, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// The mnode and size of file `filename` (if it exists).
+    fn file_size(&self, filename: &str) -> Option<(Mnode, usize)> {
+        self.fs
+            .lookup(filename)
+            .map(|mnode| (*mnode, self.fs.file_info(*mnode).fsize as usize))
+    }
+
     #[inline(always)]
     pub fn fd_to_mnode(pid: Pid, fd: FD) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
@@ -344,6 +524,22 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Bytes `pid` stored in files and how many it may store.
+    pub fn fs_usage(pid: Pid) -> Result<(usize, Option<usize>), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .mlnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::FsUsage(pid), *token);
+                match &response {
+                    Ok(MlnrNodeResult::FsUsage(bytes, quota)) => Ok((*bytes, *quota)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
     pub fn synchronize_log(log_id: usize) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -366,9 +562,12 @@ impl Dispatch for MlnrKernelNode {
     type Response = Result<MlnrNodeResult, KError>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let _span = Span::new(OpClass::MlnrRead, discriminant_value(&op) as u16);
         match op {
             Access::FileRead(pid, fd, buffer, len, offset) => {
-                let mut userslice = UserSlice::new(buffer, len as usize);
+                // Safety: The syscall handler checked that the buffer is mapped
+                // (see `handle_fileio`) and reads only run on the local replica
+                let mut userslice = unsafe { UserSlice::new(buffer, len as usize) };
                 let process_lookup = self.process_map.read();
                 let p = process_lookup
                     .get(&pid)
@@ -399,7 +598,10 @@ impl Dispatch for MlnrKernelNode {
                     curr_offset = fd.get_offset();
                 }
 
-                match self.fs.read(mnode_num, &mut userslice, curr_offset) {
+                match self
+                    .fs
+                    .read(p.credentials(), mnode_num, &mut userslice, curr_offset)
+                {
                     Ok(len) => {
                         // Update the FD associated offset only when the
                         // offset wasn't given in the arguments.
@@ -408,7 +610,7 @@ impl Dispatch for MlnrKernelNode {
                         }
                         Ok(MlnrNodeResult::FileAccessed(len as u64))
                     }
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => Err(e.into()),
                 }
             }
 
@@ -439,6 +641,14 @@ impl Dispatch for MlnrKernelNode {
                 None => Err(ProcessError::NoProcessFoundForPid.into()),
             },
 
+            Access::ReadDir(pid, dirname) => match self.process_map.read().get(&pid) {
+                Some(_) => match self.fs.readdir(&dirname) {
+                    Ok(entries) => Ok(MlnrNodeResult::DirEntries(entries)),
+                    Err(e) => Err(e.into()),
+                },
+                None => Err(ProcessError::NoProcessFoundForPid.into()),
+            },
+
             Access::FdToMnode(pid, fd) => match self.process_map.read().get(&pid) {
                 Some(p) => {
                     let fd = match p.get_fd(fd as usize) {
@@ -474,6 +684,14 @@ impl Dispatch for MlnrKernelNode {
                 None => Err(ProcessError::NoProcessFoundForPid.into()),
             },
 
+            Access::FsUsage(pid) => {
+                let fs_usage = self.fs_usage.read();
+                Ok(MlnrNodeResult::FsUsage(
+                    fs_usage.usage(pid),
+                    fs_usage.quota(),
+                ))
+            }
+
             Access::Synchronize(_log_id) => {
                 // A NOP that just makes sure we've advanced the replica
                 Ok(MlnrNodeResult::Synchronized)
@@ -482,17 +700,31 @@ impl Dispatch for MlnrKernelNode {
     }
 
     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        let _span = Span::new(OpClass::MlnrWrite, discriminant_value(&op) as u16);
         match op {
             Modify::ProcessAdd(pid) => {
                 match self.process_map.write().insert(pid, FileDesc::default()) {
-                    Some(_) => Err(KError::ProcessError {
-                        source: crate::process::ProcessError::NotEnoughMemory,
-                    }),
+                    Some(_) => Err(ProcessError::NotEnoughMemory.into()),
                     None => Ok(MlnrNodeResult::ProcessAdded(pid)),
                 }
             }
 
-            Modify::ProcessRemove(pid) => unimplemented!("Process Remove"),
+            Modify::ProcessFork(parent, child) => {
+                let mut process_map = self.process_map.write();
+                let fds = process_map
+                    .get(&parent)
+                    .cloned()
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                match process_map.insert(child, fds) {
+                    Some(_) => Err(ProcessError::NotEnoughMemory.into()),
+                    None => Ok(MlnrNodeResult::ProcessAdded(child)),
+                }
+            }
+
+            Modify::ProcessRemove(pid) => match self.process_map.write().remove(&pid) {
+                Some(_fds) => Ok(MlnrNodeResult::ProcessRemoved),
+                None => Err(ProcessError::NoProcessFoundForPid.into()),
+            },
 
             Modify::FileOpen(pid, filename, flags, modes) => {
                 let flags = FileFlags::from(flags);
@@ -506,6 +738,7 @@ impl Dispatch for MlnrKernelNode {
                 let p = process_lookup
                     .get_mut(&pid)
                     .expect("TODO: FileOpen process lookup failed");
+                let creds = p.credentials();
                 let fd = p.allocate_fd();
 
                 match fd {
@@ -513,20 +746,29 @@ impl Dispatch for MlnrKernelNode {
                     Some(mut fd) => {
                         let mnode_num;
                         if mnode.is_none() {
-                            match self.fs.create(&filename, modes) {
-                                Ok(m_num) => mnode_num = m_num,
+                            match self.fs.create(creds, &filename, modes) {
+                                Ok(m_num) => {
+                                    mnode_num = m_num;
+                                    self.fs_usage.write().add_file(mnode_num, pid);
+                                }
                                 Err(e) => {
                                     let fdesc = fd.0 as usize;
                                     process_lookup.get_mut(&pid).unwrap().deallocate_fd(fdesc);
-                                    return Err(KError::FileSystem { source: e });
+                                    return Err(e.into());
                                 }
                             }
                         } else {
                             // File exists and FileOpen is called with O_TRUNC flag.
+                            mnode_num = *mnode.unwrap();
                             if flags.is_truncate() {
-                                self.fs.truncate(&filename);
+                                let size = self.fs.file_info(mnode_num).fsize as usize;
+                                if let Err(e) = self.fs.truncate(creds, &filename) {
+                                    let fdesc = fd.0 as usize;
+                                    process_lookup.get_mut(&pid).unwrap().deallocate_fd(fdesc);
+                                    return Err(e.into());
+                                }
+                                self.fs_usage.write().uncharge(mnode_num, size);
                             }
-                            mnode_num = *mnode.unwrap();
                         }
                         fd.1.update_fd(mnode_num, flags);
                         Ok(MlnrNodeResult::FileOpened(fd.0))
@@ -570,7 +812,18 @@ impl Dispatch for MlnrKernelNode {
                     }
                 }
 
-                match self.fs.write(mnode_num, &kernslice.clone(), curr_offset) {
+                // Charge the bytes the file grows by before writing them
+                let charged = self.fs_usage.write().charge_write(
+                    mnode_num,
+                    || self.fs.file_info(mnode_num).fsize as usize,
+                    curr_offset,
+                    kernslice.len(),
+                )?;
+
+                match self
+                    .fs
+                    .write(p.credentials(), mnode_num, &kernslice.clone(), curr_offset)
+                {
                     Ok(len) => {
                         if offset == -1 {
                             // Update offset when FileWrite doesn't give an explicit offset value.
@@ -578,7 +831,103 @@ impl Dispatch for MlnrKernelNode {
                         }
                         Ok(MlnrNodeResult::FileAccessed(len as u64))
                     }
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => {
+                        self.fs_usage.write().uncharge(mnode_num, charged);
+                        Err(e.into())
+                    }
+                }
+            }
+
+            Modify::FileSeek(pid, fd, offset, whence) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .expect("TODO: FileSeek process lookup failed");
+                let fd = match p.get_fd(fd as usize) {
+                    Some(fd) => fd,
+                    None => {
+                        return Err(KError::FileSystem {
+                            source: FileSystemError::InvalidFileDescriptor,
+                        })
+                    }
+                };
+                let file_size = self.fs.file_info(fd.get_mnode()).fsize as usize;
+
+                match fd.seek(offset, whence, file_size) {
+                    Ok(new_offset) => Ok(MlnrNodeResult::FileSeeked(new_offset as u64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            Modify::FileAllocate(pid, fd, offset, len) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .expect("TODO: FileAllocate process lookup failed");
+                let fd = match p.get_fd(fd as usize) {
+                    Some(fd) => fd,
+                    None => {
+                        return Err(KError::FileSystem {
+                            source: FileSystemError::InvalidFileDescriptor,
+                        })
+                    }
+                };
+
+                // Like writes, this needs a file descriptor that allows writing.
+                if !fd.get_flags().is_write() {
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::PermissionError,
+                    });
+                }
+
+                let mnode_num = fd.get_mnode();
+                let charged = self.fs_usage.write().charge_write(
+                    mnode_num,
+                    || self.fs.file_info(mnode_num).fsize as usize,
+                    offset as usize,
+                    len as usize,
+                )?;
+
+                match self
+                    .fs
+                    .allocate(p.credentials(), mnode_num, offset as usize, len as usize)
+                {
+                    Ok(()) => Ok(MlnrNodeResult::FileAllocated),
+                    Err(e) => {
+                        self.fs_usage.write().uncharge(mnode_num, charged);
+                        Err(e.into())
+                    }
+                }
+            }
+
+            Modify::PipeOpen(pid) => {
+                let mut process_lookup = self.process_map.write();
+                let p = process_lookup
+                    .get_mut(&pid)
+                    .expect("TODO: PipeOpen process lookup failed");
+
+                let read_fd = match p.allocate_fd() {
+                    Some((fd, filedesc)) => {
+                        filedesc.update_fd(PIPE_MNODE, FileFlags::O_RDONLY);
+                        fd
+                    }
+                    None => {
+                        return Err(KError::FileSystem {
+                            source: FileSystemError::OpenFileLimit,
+                        })
+                    }
+                };
+                match p.allocate_fd() {
+                    Some((write_fd, filedesc)) => {
+                        filedesc.update_fd(PIPE_MNODE, FileFlags::O_WRONLY);
+                        Ok(MlnrNodeResult::PipeOpened(read_fd, write_fd))
+                    }
+                    None => {
+                        p.deallocate_fd(read_fd as usize);
+                        Err(KError::FileSystem {
+                            source: FileSystemError::OpenFileLimit,
+                        })
+                    }
                 }
             }
 
@@ -598,29 +947,60 @@ impl Dispatch for MlnrKernelNode {
             }
 
             Modify::FileDelete(pid, filename) => match self.process_map.read().get(&pid) {
-                Some(_) => match self.fs.delete(&filename) {
-                    Ok(is_deleted) => Ok(MlnrNodeResult::FileDeleted(is_deleted)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
-                },
+                Some(p) => {
+                    let file = self.file_size(&filename);
+                    match self.fs.delete(p.credentials(), &filename) {
+                        Ok(is_deleted) => {
+                            if let Some((mnode, size)) = file {
+                                self.fs_usage.write().remove_file(mnode, size);
+                            }
+                            Ok(MlnrNodeResult::FileDeleted(is_deleted))
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                }
                 None => Err(ProcessError::NoProcessFoundForPid.into()),
             },
 
             Modify::FileRename(pid, oldname, newname) => match self.process_map.read().get(&pid) {
-                Some(_) => match self.fs.rename(&oldname, &newname) {
-                    Ok(is_renamed) => Ok(MlnrNodeResult::FileRenamed(is_renamed)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
-                },
+                Some(p) => {
+                    // A file that exists at `newname` gets overwritten
+                    let old_mnode = self.fs.lookup(&oldname).map(|mnode| *mnode);
+                    let overwritten = self
+                        .file_size(&newname)
+                        .filter(|(mnode, _size)| Some(*mnode) != old_mnode);
+                    match self.fs.rename(p.credentials(), &oldname, &newname) {
+                        Ok(is_renamed) => {
+                            if let Some((mnode, size)) = overwritten {
+                                self.fs_usage.write().remove_file(mnode, size);
+                            }
+                            Ok(MlnrNodeResult::FileRenamed(is_renamed))
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                }
                 None => Err(ProcessError::NoProcessFoundForPid.into()),
             },
 
             Modify::MkDir(pid, filename, modes) => match self.process_map.read().get(&pid) {
-                Some(_) => match self.fs.mkdir(&filename, modes) {
+                Some(p) => match self.fs.mkdir(p.credentials(), &filename, modes) {
                     Ok(is_created) => Ok(MlnrNodeResult::DirCreated(is_created)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => Err(e.into()),
                 },
                 None => Err(ProcessError::NoProcessFoundForPid.into()),
             },
 
+            Modify::SetCredentials(pid, credentials) => {
+                match self.process_map.write().get_mut(&pid) {
+                    Some(p) if !p.credentials().is_root() => Err(KError::NotPermitted),
+                    Some(p) => {
+                        p.set_credentials(credentials);
+                        Ok(MlnrNodeResult::CredentialsSet)
+                    }
+                    None => Err(ProcessError::NoProcessFoundForPid.into()),
+                }
+            }
+
             Modify::Invalid => unreachable!("Got invalid OP"),
         }
     }
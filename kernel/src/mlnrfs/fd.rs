@@ -1,19 +1,32 @@
 use crate::fs::{Fd, FileDescriptor, MAX_FILES_PER_PROCESS};
+use crate::process::Credentials;
 use arr_macro::arr;
 
+#[derive(Clone)]
 pub struct FileDesc {
     fds: arrayvec::ArrayVec<[Option<Fd>; MAX_FILES_PER_PROCESS]>,
+    /// User and group the process accesses files as.
+    credentials: Credentials,
 }
 
 impl Default for FileDesc {
     fn default() -> Self {
         FileDesc {
             fds: arrayvec::ArrayVec::from(arr![None; 4096]), // MAX_FILES_PER_PROCESS
+            credentials: Default::default(),
         }
     }
 }
 
 impl FileDesc {
+    pub fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
     pub fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
         let mut fd: i64 = -1;
         for i in 0..MAX_FILES_PER_PROCESS {
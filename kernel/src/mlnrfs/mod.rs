@@ -1,10 +1,15 @@
 #![allow(unused)]
 
 use crate::arch::process::UserSlice;
-use crate::fs::{FileSystem, FileSystemError, MemNode, Mnode, Modes, NodeType};
+use crate::fs::{
+    canonicalize, split_path, FileSystem, FileSystemError, MemNode, Mnode, Modes, NodeType,
+    ROOT_DIRECTORY_MODES,
+};
+use crate::process::Credentials;
 
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 use custom_error::custom_error;
@@ -46,7 +51,7 @@ impl Default for MlnrFS {
                 MemNode::new(
                     rootmnode,
                     rootdir,
-                    FileModes::S_IRWXU.into(),
+                    ROOT_DIRECTORY_MODES.into(),
                     NodeType::Directory,
                 )
                 .unwrap(),
@@ -71,20 +76,66 @@ impl MlnrFS {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
 
-    pub fn create(&self, pathname: &str, modes: Modes) -> Result<u64, FileSystemError> {
+    /// Find the directory that contains `pathname` (paths don't need to
+    /// have one here, so this can be None).
+    fn parent(&self, pathname: &str) -> Option<Mnode> {
+        let path = canonicalize(pathname);
+        if path == self.root.0 {
+            return None;
+        }
+
+        let dir = split_path(&path).0;
+        if dir == self.root.0 {
+            return Some(self.root.1);
+        }
+        self.files
+            .read()
+            .iter()
+            .find(|(name, _mnode)| canonicalize(name) == dir)
+            .map(|(_name, mnode)| **mnode)
+    }
+
+    /// Check that `creds` may add or remove entries of the directory that
+    /// contains `pathname`.
+    fn check_parent_writable(
+        &self,
+        creds: Credentials,
+        pathname: &str,
+    ) -> Result<(), FileSystemError> {
+        let writable = self.parent(pathname).and_then(|dir| {
+            self.mnodes
+                .read()
+                .get(&dir)
+                .map(|memnode| memnode.read().permits(creds, FileModes::S_IWUSR))
+        });
+
+        match writable {
+            Some(false) => Err(FileSystemError::PermissionError),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn create(
+        &self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<u64, FileSystemError> {
         // Check if the file with the same name already exists.
         match self.files.read().get(&pathname.to_string()) {
             Some(_) => return Err(FileSystemError::AlreadyPresent),
             None => {}
         }
+        self.check_parent_writable(creds, pathname)?;
 
         let mnode_num = self.get_next_mno() as u64;
         //TODO: For now all newly created mnode are for file. How to differentiate
         // between a file and a directory. Take input from the user?
-        let memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::File) {
+        let mut memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::File) {
             Ok(memnode) => memnode,
             Err(e) => return Err(e),
         };
+        memnode.set_owner(creds);
         self.files
             .write()
             .insert(pathname.to_string(), Arc::new(mnode_num));
@@ -95,24 +146,26 @@ impl MlnrFS {
 
     pub fn write(
         &self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &[u8],
         offset: usize,
     ) -> Result<usize, FileSystemError> {
         match self.mnodes.read().get(&mnode_num) {
-            Some(mnode) => mnode.write().write(buffer, offset),
+            Some(mnode) => mnode.write().write(creds, buffer, offset),
             None => Err(FileSystemError::InvalidFile),
         }
     }
 
     pub fn read(
         &self,
+        creds: Credentials,
         mnode_num: Mnode,
         buffer: &mut UserSlice,
         offset: usize,
     ) -> Result<usize, FileSystemError> {
         match self.mnodes.read().get(&mnode_num) {
-            Some(mnode) => mnode.read().read(buffer, offset),
+            Some(mnode) => mnode.read().read(creds, buffer, offset),
             None => Err(FileSystemError::InvalidFile),
         }
     }
@@ -130,17 +183,55 @@ impl MlnrFS {
                 NodeType::Directory => FileInfo {
                     fsize: 0,
                     ftype: NodeType::Directory.into(),
+                    ..Default::default()
                 },
-                NodeType::File => FileInfo {
-                    fsize: mnode.read().get_file_size() as u64,
-                    ftype: NodeType::File.into(),
-                },
+                NodeType::File => {
+                    let stats = mnode.read().get_page_stats();
+                    FileInfo {
+                        fsize: mnode.read().get_file_size() as u64,
+                        ftype: NodeType::File.into(),
+                        base_pages: stats.base_pages as u64,
+                        large_pages: stats.large_pages as u64,
+                    }
+                }
             },
             None => unreachable!("file_info: shouldn't reach here"),
         }
     }
 
-    pub fn delete(&self, pathname: &str) -> Result<bool, FileSystemError> {
+    fn is_directory(&self, mnode: Mnode) -> bool {
+        match self.mnodes.read().get(&mnode) {
+            Some(memnode) => memnode.read().get_mnode_type() == NodeType::Directory,
+            None => false,
+        }
+    }
+
+    /// Find the entries of the directory `path` (paths are kept flat here, so
+    /// this goes through all files).
+    fn children(&self, path: &str) -> Vec<(String, Mnode)> {
+        let dir = canonicalize(path);
+        self.files
+            .read()
+            .iter()
+            .filter_map(|(name, mnode)| {
+                let name = canonicalize(name);
+                if name != "/" && split_path(&name).0 == dir {
+                    Some((split_path(&name).1.to_string(), **mnode))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn delete(&self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
+        if let Some(mnode) = self.lookup(pathname) {
+            if self.is_directory(*mnode) && !self.children(pathname).is_empty() {
+                return Err(FileSystemError::DirectoryNotEmpty);
+            }
+            self.check_parent_writable(creds, pathname)?;
+        }
+
         match self.files.write().remove(&pathname.to_string()) {
             Some(mnode) => {
                 // If the pathname is the only link to the memnode, then remove it.
@@ -159,24 +250,44 @@ impl MlnrFS {
         };
     }
 
-    pub fn truncate(&self, pathname: &str) -> Result<bool, FileSystemError> {
+    pub fn truncate(&self, creds: Credentials, pathname: &str) -> Result<bool, FileSystemError> {
         match self.files.read().get(&pathname.to_string()) {
             Some(mnode) => match self.mnodes.read().get(mnode) {
-                Some(memnode) => memnode.write().file_truncate(),
+                Some(memnode) => memnode.write().file_truncate(creds),
                 None => return Err(FileSystemError::InvalidFile),
             },
             None => return Err(FileSystemError::InvalidFile),
         }
     }
 
-    pub fn rename(&self, oldname: &str, newname: &str) -> Result<bool, FileSystemError> {
+    pub fn allocate(
+        &self,
+        creds: Credentials,
+        mnode_num: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FileSystemError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.write().allocate(creds, offset, len),
+            None => Err(FileSystemError::InvalidFile),
+        }
+    }
+
+    pub fn rename(
+        &self,
+        creds: Credentials,
+        oldname: &str,
+        newname: &str,
+    ) -> Result<bool, FileSystemError> {
         if self.files.read().get(oldname).is_none() {
             return Err(FileSystemError::InvalidFile);
         }
+        self.check_parent_writable(creds, oldname)?;
+        self.check_parent_writable(creds, newname)?;
 
         // If the newfile exists then overwrite it with the oldfile.
         if self.files.read().get(newname).is_some() {
-            self.delete(newname).unwrap();
+            self.delete(creds, newname).unwrap();
         }
 
         // TODO: Can we optimize it somehow?
@@ -192,18 +303,25 @@ impl MlnrFS {
 
     /// Create a directory. The implementation is quite simplistic for now, and only used
     /// by leveldb benchmark.
-    pub fn mkdir(&self, pathname: &str, modes: Modes) -> Result<bool, FileSystemError> {
+    pub fn mkdir(
+        &self,
+        creds: Credentials,
+        pathname: &str,
+        modes: Modes,
+    ) -> Result<bool, FileSystemError> {
         // Check if the file with the same name already exists.
         match self.files.read().get(&pathname.to_string()) {
             Some(_) => return Err(FileSystemError::AlreadyPresent),
             None => {}
         }
+        self.check_parent_writable(creds, pathname)?;
 
         let mnode_num = self.get_next_mno() as u64;
-        let memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::Directory) {
+        let mut memnode = match MemNode::new(mnode_num, pathname, modes, NodeType::Directory) {
             Ok(memnode) => memnode,
             Err(e) => return Err(e),
         };
+        memnode.set_owner(creds);
         self.files
             .write()
             .insert(pathname.to_string(), Arc::new(mnode_num));
@@ -211,4 +329,27 @@ impl MlnrFS {
 
         Ok(true)
     }
+
+    /// List the entries of a directory.
+    pub fn readdir(&self, pathname: &str) -> Result<Vec<DirEntry>, FileSystemError> {
+        let mnode = match self.lookup(pathname) {
+            Some(mnode) => mnode,
+            None => return Err(FileSystemError::InvalidFile),
+        };
+        if !self.is_directory(*mnode) {
+            return Err(FileSystemError::NotADirectory);
+        }
+
+        let mut entries: Vec<DirEntry> = self
+            .children(pathname)
+            .into_iter()
+            .map(|(name, mnode)| DirEntry {
+                name,
+                ftype: self.file_info(mnode).ftype,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(entries)
+    }
 }
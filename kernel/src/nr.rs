@@ -5,8 +5,9 @@ use alloc::string::{String, ToString};
 use alloc::sync::{Arc, Weak};
 use alloc::vec;
 use alloc::vec::Vec;
+use core::intrinsics::discriminant_value;
 use hashbrown::HashMap;
-use kpi::process::{FrameId, ProcessInfo};
+use kpi::process::{FrameId, ProcessEntry, ProcessInfo, SchedulingPolicy};
 use kpi::{io::*, FileOperation};
 
 use node_replication::Dispatch;
@@ -15,31 +16,121 @@ use node_replication::ReplicaToken;
 use crate::arch::process::{UserPtr, UserSlice};
 use crate::arch::Module;
 use crate::error::KError;
+use crate::fs::pipe::PIPE_MNODE;
 use crate::fs::{
-    Buffer, FileDescriptor, FileSystem, FileSystemError, Filename, Flags, Len, MemFS, Modes,
-    Offset, FD, MAX_FILES_PER_PROCESS,
+    Buffer, Fd, FileDescriptor, FileSystem, FileSystemError, Filename, Flags, FsAccounting, Len,
+    MemFS, Mnode, Modes, Offset, FD, MAX_FILES_PER_PROCESS,
+};
+use crate::memory::vspace::{
+    Access, AddressSpace, AddressSpaceError, MapAction, MappingType, TlbFlushHandle,
+};
+use crate::memory::{kernel_vaddr_to_paddr, Frame, PAddr, VAddr, BASE_PAGE_SIZE};
+use crate::nrtrace::{OpClass, Span};
+use crate::process::{
+    userptr_to_str, Credentials, Eid, Executor, KernSlice, Pid, Process, ProcessError,
 };
-use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
-use crate::memory::{Frame, PAddr, VAddr};
-use crate::process::{userptr_to_str, Eid, Executor, KernSlice, Pid, Process, ProcessError};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ReadOps {
     CurrentExecutor(topology::GlobalThreadId),
+    /// All executors that share the given core.
+    CoreExecutors(topology::GlobalThreadId),
+    /// Scheduling policy of a process and the cores that run its executors.
+    ProcessCores(Pid),
+    /// Scheduling weight of a process (see `scheduler::fair`).
+    ProcessWeight(Pid),
     ProcessInfo(Pid),
+    /// Arguments a process was spawned with (see `ProcessInfo::app_cmdline`).
+    ProcessArgs(Pid),
+    /// Exit code of a child process (None if it's still running), asked for
+    /// by its parent.
+    ProcessExitStatus(Pid, Pid),
+    /// How often the futexes of a process were woken up so far (see
+    /// `Op::FutexWait`).
+    FutexSequence(Pid),
+    /// The processes that subscribed to an event (see `kpi::upcall`) and a
+    /// core each of them runs on.
+    EventSubscribers(u64),
+    /// All processes that are currently running.
+    ProcessList,
+    /// The boot module a supervised process is restarted from (see
+    /// `Op::ProcRestart`, None while it's still running).
+    ProcessRestartable(Pid, Pid),
+    /// Physical memory that holds the executors of a process.
+    ProcessExecutorFrames(Pid),
     FileRead(Pid, FD, Buffer, Len, Offset),
     FileInfo(Pid, Filename, u64),
+    /// Entries of a directory.
+    FileReadDir(Pid, Filename),
+    /// Bytes stored in the files of a process (and the quota).
+    FsUsage(Pid),
+    /// How many pages `Op::FileEvict` dropped so far.
+    FsEvictedPages,
     MemResolve(Pid, VAddr),
+    /// The copy-on-write mapping an address is in (and whether another
+    /// process still uses its frame).
+    MemCowMapping(Pid, VAddr),
+    /// Checks that a range is mapped (and whether it might overlap a
+    /// copy-on-write mapping).
+    MemValidRange(Pid, VAddr, usize, Access),
     Synchronize,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
-    ProcCreate(&'static Module, Vec<Frame>),
+    /// Create a process from a boot module (with its arguments, see
+    /// `ProcessInfo::app_cmdline`, and its parent).
+    ProcCreate(
+        &'static Module,
+        Vec<Frame>,
+        SchedulingPolicy,
+        Arc<str>,
+        Option<Pid>,
+    ),
     ProcDestroy(Pid),
+    /// A process exited with the given exit code.
+    ProcExit(Pid, u64),
+    /// Move a process (the caller or one of its children) into a process
+    /// group (0 creates a new one).
+    ProcSetGroup(Pid, Pid, Pid),
+    /// Change the scheduling weight of a process.
+    ProcSetWeight(Pid, u64),
+    /// Terminate all processes of a process group (on behalf of a process)
+    /// with the given exit code.
+    ProcTerminateGroup(Pid, Pid, u64),
+    /// A process supervises its child: it restarts it from a boot module
+    /// (with arguments) once it exited.
+    ProcSupervise(Pid, Pid, &'static str, Arc<str>),
+    /// Start a supervised process that exited (on behalf of its supervisor)
+    /// again from the given boot module.
+    ProcRestart(Pid, Pid, &'static Module, Vec<Frame>),
+    /// Duplicate a process: the frames are copies of its executor frames and
+    /// the executor (with the Eid) is assigned to the core of the caller.
+    ProcFork(Pid, Vec<Frame>, Eid, topology::GlobalThreadId),
+    /// Replace the image of a process (that only runs on the given core)
+    /// with a boot module, the frames hold its writeable sections.
+    ProcExec(Pid, &'static Module, Vec<Frame>, topology::GlobalThreadId),
+    /// Collect the exit code of a child process. If the child is still
+    /// running, the executor of the parent waits (isn't scheduled) until it
+    /// exited.
+    ProcWaitChild(Pid, Eid, Pid),
+    /// The executor of a process waits (isn't scheduled) until the futex at
+    /// an address gets woken up, unless a futex of the process was woken up
+    /// since the caller read the sequence number (see
+    /// `ReadOps::FutexSequence`).
+    FutexWait(Pid, Eid, u64, u64),
+    /// Wake up (at most) the given number of executors of a process that
+    /// wait on the futex at an address.
+    FutexWake(Pid, u64, usize),
+    /// A process subscribes to (true) or unsubscribes from (false) an event
+    /// (see `kpi::upcall`).
+    ProcSubscribe(Pid, u64, bool),
     ProcInstallVCpuArea(Pid, u64),
     ProcAllocIrqVector,
     ProcRaiseIrq,
+    /// A core failed (see `arch::isolate_core`): it loses its executors and
+    /// doesn't get new ones.
+    CoreFailed(topology::GlobalThreadId),
     /// Assign a core to a process.
     ProcAllocateCore(
         Pid,
@@ -49,21 +140,52 @@ pub enum Op {
     ),
     /// Assign a physical frame to a process (returns a FrameId).
     AllocateFrameToProcess(Pid, Frame),
+    /// Unregister a physical frame (of the given size) from a process.
+    ReleaseFrameFromProcess(Pid, FrameId, usize),
     DispatcherAllocation(Pid, Frame),
     DispatcherDeallocation,
     DispatcherSchedule,
     MemMapFrames(Pid, VAddr, Frame, MapAction), // Vec<Frame> doesn't implement copy
     MemMapFrame(Pid, VAddr, Frame, MapAction),
     MemMapDevice(Pid, Frame, MapAction),
+    /// Map a frame of the kernel into a process (it stays with the kernel
+    /// once the process exited).
+    MemMapKernel(Pid, VAddr, Frame, MapAction),
     MemMapFrameId(Pid, VAddr, FrameId, MapAction),
-    MemAdjust,
+    /// Change the rights of the mappings in a range (see `Process::protect`).
+    MemAdjust(Pid, VAddr, usize, MapAction),
+    /// Tag the mappings in a range with a protection key (see
+    /// `Process::set_key`).
+    MemSetKey(Pid, VAddr, usize, u16),
     MemUnmap(Pid, VAddr),
+    /// Make a copy-on-write mapping writable (with a copy of the frame if
+    /// another process still uses it).
+    MemCowResolve(Pid, VAddr, Option<Frame>),
     FileOpen(Pid, String, Flags, Modes),
     FileWrite(Pid, FD, Arc<[u8]>, Len, Offset),
     FileClose(Pid, FD),
+    /// Allocate the file descriptors for both ends of a pipe (the pipe
+    /// itself lives in `fs::pipe`).
+    PipeOpen(Pid),
+    /// Move the offset of a file descriptor (goes through the log so all
+    /// replicas agree on it).
+    FileSeek(Pid, FD, i64, SeekWhence),
+    /// Reserve memory for a range (offset, length) of a file.
+    FileAllocate(Pid, FD, u64, u64),
+    /// Map (at most `MMAP_MAX_PAGES` of) the pages of a file read-only into
+    /// a process: at an address, the length and the (page-aligned) offset in
+    /// the file.
+    FileMmap(Pid, FD, VAddr, usize, usize),
+    /// Remove the mappings `Op::FileMmap` made in a range (address, length).
+    FileMunmap(Pid, VAddr, usize),
+    /// Drop clean pages of evictable files to free (at least) the given
+    /// number of bytes.
+    FileEvict(usize),
     FileDelete(Pid, String),
     FileRename(Pid, String, String),
     MkDir(Pid, String, Modes),
+    /// Change the user and group a process accesses files as.
+    SetCredentials(Pid, Credentials),
     Invalid,
 }
 
@@ -76,25 +198,73 @@ impl Default for Op {
 #[derive(Debug, Clone)]
 pub enum NodeResult<E: Executor> {
     ProcCreated(Pid),
+    ProcForked(Pid, Weak<E>, TlbFlushHandle),
+    ProcReplaced(Vec<Frame>),
     ProcDestroyed,
+    /// The process is gone, the frames (shared by all replicas) are no longer
+    /// in use and have to be given back. So do the resources outside of NR
+    /// of the processes (see `KernelNode::remove_process`).
+    ProcExited(Vec<Frame>, Vec<Pid>),
+    GroupSet(Pid),
+    WeightSet,
+    Weight(u64),
+    /// The processes that were terminated, the frames and the processes
+    /// whose resources have to be given back (see `ProcExited`).
+    GroupTerminated(Vec<Pid>, Vec<Frame>, Vec<Pid>),
+    Supervised,
+    Restartable(Option<&'static str>),
+    /// The new process, the exit code of the old one and the core the old
+    /// one ran on.
+    ProcRestarted(Pid, u64, Option<topology::GlobalThreadId>),
     ProcessInfo(ProcessInfo),
+    ProcessArgs(Option<Arc<str>>),
+    ExitStatus(Option<u64>),
+    FutexSequence(u64),
+    /// Does the executor wait now?
+    FutexWaiting(bool),
+    /// The cores of the executors that were woken up (one per executor).
+    FutexWoken(Vec<topology::GlobalThreadId>),
+    Subscribed,
+    Subscribers(Vec<(Pid, topology::GlobalThreadId)>),
+    Processes(Vec<ProcessEntry>),
     CoreAllocated(topology::GlobalThreadId, Eid),
+    /// The processes that had an executor on the failed core.
+    CoreFailed(Vec<Pid>),
     VectorAllocated(u64),
     ExecutorsCreated(usize),
     Mapped,
     MappedFrameId(PAddr, usize),
-    Adjusted,
+    Adjusted(TlbFlushHandle),
     Unmapped(TlbFlushHandle),
     Resolved(PAddr, MapAction),
+    CowMapping(Option<(VAddr, Frame, bool)>),
+    ValidRange(bool),
+    CowResolved(Option<TlbFlushHandle>),
     FileOpened(FD),
+    PipeOpened(FD, FD),
     FileClosed(u64),
     FileAccessed(Len),
+    FileSeeked(u64),
+    FileAllocated,
+    FileMapped,
+    FileUnmapped(TlbFlushHandle),
     FileInfo(u64),
+    FsUsage(usize, Option<usize>),
+    /// Pages of evictable files that were dropped.
+    FileEvicted(usize),
+    DirEntries(Vec<DirEntry>),
     FileDeleted(bool),
     FileRenamed(bool),
     DirCreated(bool),
+    CredentialsSet,
     Executor(Weak<E>),
+    Executors(Vec<Weak<E>>),
+    Cores(SchedulingPolicy, Vec<topology::GlobalThreadId>),
     FrameId(usize),
+    /// The frame is no longer registered with the process (it has to be
+    /// given back if no other process uses it anymore).
+    FrameReleased(Option<Frame>),
+    Frames(Vec<Frame>),
     Invalid,
     Synchronized,
 }
@@ -105,20 +275,109 @@ impl<E: Executor> Default for NodeResult<E> {
     }
 }
 
+/// How many pages `Op::FileMmap` maps at most (larger mappings take several
+/// operations).
+pub const MMAP_MAX_PAGES: usize = 512;
+
+/// Pages of a file that `Op::FileMmap` mapped into a process.
+#[derive(Debug, Clone, Copy)]
+struct MmapRegion {
+    base: VAddr,
+    len: usize,
+    mnode: Mnode,
+}
+
+impl MmapRegion {
+    fn end(&self) -> VAddr {
+        self.base + self.len
+    }
+}
+
+/// What a supervised process leaves behind when it exits, so its supervisor
+/// can start it again (see `Op::ProcRestart`).
+#[derive(Debug)]
+struct Restartable {
+    supervisor: Pid,
+    binary: &'static str,
+    args: Arc<str>,
+    policy: SchedulingPolicy,
+    fds: Vec<Option<Fd>>,
+    credentials: Credentials,
+    group: Pid,
+    cores: Vec<topology::GlobalThreadId>,
+}
+
 pub struct KernelNode<P: Process> {
     current_pid: Pid,
     process_map: HashMap<Pid, Box<P>>,
-    scheduler_map: HashMap<topology::GlobalThreadId, Arc<P::E>>,
+    /// Name of the boot module every process was created from.
+    binaries: HashMap<Pid, &'static str>,
+    /// Arguments of processes that didn't get the ones of the kernel
+    /// command-line (they're freed once the last process that uses them is
+    /// gone).
+    args: HashMap<Pid, Arc<str>>,
+    /// Exit codes of processes that terminated.
+    exited: HashMap<Pid, u64>,
+    /// The parent of every process that was spawned or forked (until the
+    /// parent collected its exit code or exited itself).
+    parents: HashMap<Pid, Pid>,
+    /// The process group of every process.
+    groups: HashMap<Pid, Pid>,
+    /// Processes that don't use the default scheduling weight
+    /// (`fair::DEFAULT_WEIGHT`).
+    weights: HashMap<Pid, u64>,
+    /// The supervisor of a process and what it restarts it as (boot module
+    /// and arguments).
+    supervised: HashMap<Pid, (Pid, &'static str, Arc<str>)>,
+    /// Supervised processes that exited and weren't restarted yet.
+    restartable: HashMap<Pid, Restartable>,
+    /// Executors that wait for a child process to exit.
+    waiting: HashMap<(Pid, Eid), Pid>,
+    /// Executors that wait on a futex (its address).
+    futex_waiting: HashMap<(Pid, Eid), u64>,
+    /// How often the futexes of every process were woken up. There is one
+    /// counter per process (not per futex) so this doesn't grow with the
+    /// addresses a process used as futexes.
+    futex_sequence: HashMap<Pid, u64>,
+    /// The processes that subscribed to an event (sorted).
+    subscribers: HashMap<u64, Vec<Pid>>,
+    /// Executors assigned to a core (more than one if the core is time-shared).
+    scheduler_map: HashMap<topology::GlobalThreadId, Vec<Arc<P::E>>>,
+    /// Cores that failed, nothing runs on them anymore.
+    failed_cores: Vec<topology::GlobalThreadId>,
     fs: MemFS,
+    /// Bytes every process stored in `fs`.
+    fs_usage: FsAccounting,
+    /// Pages of evictable files in `fs` that were dropped so far.
+    evicted_pages: usize,
+    /// The file mappings of every process (see `Op::FileMmap`), only these
+    /// can be removed with `Op::FileMunmap`.
+    mmaps: HashMap<Pid, Vec<MmapRegion>>,
 }
 
 impl<P: Process> Default for KernelNode<P> {
     fn default() -> KernelNode<P> {
         KernelNode {
-            current_pid: 1,
+            current_pid: crate::process::INIT_PID,
             process_map: HashMap::with_capacity(256),
+            binaries: HashMap::with_capacity(256),
+            args: HashMap::new(),
+            exited: HashMap::new(),
+            parents: HashMap::new(),
+            groups: HashMap::new(),
+            weights: HashMap::new(),
+            supervised: HashMap::new(),
+            restartable: HashMap::new(),
+            waiting: HashMap::new(),
+            futex_waiting: HashMap::new(),
+            futex_sequence: HashMap::new(),
+            subscribers: HashMap::new(),
             scheduler_map: HashMap::with_capacity(256),
+            failed_cores: Vec::new(),
             fs: Default::default(),
+            fs_usage: FsAccounting::with_cmdline_quota(),
+            evicted_pages: 0,
+            mmaps: HashMap::new(),
         }
     }
 }
@@ -167,7 +426,8 @@ impl<P: Process> KernelNode<P> {
 
                 match response {
                     Ok(NodeResult::Mapped) => Ok((frame.base.as_u64(), frame.size() as u64)),
-                    _ => unreachable!("Got unexpected response"),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
                 }
             })
     }
@@ -181,7 +441,114 @@ impl<P: Process> KernelNode<P> {
 
                 match response {
                     Ok(NodeResult::Unmapped(handle)) => Ok(handle),
-                    _ => unreachable!("Got unexpected response"),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Changes the rights of `[base, base+len)` in process `pid` to `rights`,
+    /// returns the `TlbFlushHandle` for the range.
+    pub fn protect(
+        pid: Pid,
+        base: VAddr,
+        len: usize,
+        rights: MapAction,
+    ) -> Result<TlbFlushHandle, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::MemAdjust(pid, base, len, rights), *token);
+
+                match response {
+                    Ok(NodeResult::Adjusted(handle)) => Ok(handle),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Tags `[base, base+len)` in process `pid` with protection `key`, returns
+    /// the `TlbFlushHandle` for the range.
+    pub fn set_key(pid: Pid, base: VAddr, len: usize, key: u16) -> Result<TlbFlushHandle, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::MemSetKey(pid, base, len, key), *token);
+
+                match response {
+                    Ok(NodeResult::Adjusted(handle)) => Ok(handle),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Returns the base, frame and whether another process shares it, of the
+    /// copy-on-write mapping `vaddr` is in.
+    pub fn cow_mapping(pid: Pid, vaddr: VAddr) -> Result<Option<(VAddr, Frame, bool)>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::MemCowMapping(pid, vaddr), *token);
+
+                match response {
+                    Ok(NodeResult::CowMapping(mapping)) => Ok(mapping),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Checks that every byte of `[base, base+len)` is mapped in process
+    /// `pid` (a `len` of 0 checks only `base`) with rights that allow the
+    /// `access`.
+    ///
+    /// Returns whether the range might overlap a copy-on-write mapping, for
+    /// an `Access::Write` these pages pass (they become writable once they're
+    /// resolved).
+    pub fn validate_range(
+        pid: Pid,
+        base: VAddr,
+        len: usize,
+        access: Access,
+    ) -> Result<bool, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute(ReadOps::MemValidRange(pid, base, len, access), *token);
+
+                match response {
+                    Ok(NodeResult::ValidRange(cow)) => Ok(cow),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Makes the copy-on-write mapping at `base` writable, returns None if
+    /// there is nothing to do (anymore) or if the frame is still shared but
+    /// `copy` is None.
+    pub fn resolve_cow(
+        pid: Pid,
+        base: VAddr,
+        copy: Option<Frame>,
+    ) -> Result<Option<TlbFlushHandle>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::MemCowResolve(pid, base, copy), *token);
+
+                match response {
+                    Ok(NodeResult::CowResolved(handle)) => Ok(handle),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
                 }
             })
     }
@@ -206,6 +573,23 @@ impl<P: Process> KernelNode<P> {
             })
     }
 
+    /// Maps `frame` at `base` in process `pid`.
+    pub fn map_frame(pid: Pid, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Op::MemMapFrame(pid, base, frame, action), *token);
+
+                match response {
+                    Ok(NodeResult::Mapped) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
     pub fn map_frames(
         pid: Pid,
         base: VAddr,
@@ -263,6 +647,21 @@ impl<P: Process> KernelNode<P> {
             })
     }
 
+    pub fn pipe(pid: Pid) -> Result<(FD, FD), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::PipeOpen(pid), *token);
+
+                match &response {
+                    Ok(NodeResult::PipeOpened(read_fd, write_fd)) => Ok((*read_fd, *write_fd)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
     pub fn unmap_fd(pid: Pid, fd: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
@@ -319,6 +718,82 @@ impl<P: Process> KernelNode<P> {
             })
     }
 
+    pub fn file_seek(
+        pid: Pid,
+        fd: u64,
+        offset: i64,
+        whence: SeekWhence,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FileSeek(pid, fd, offset, whence), *token);
+
+                match &response {
+                    Ok(NodeResult::FileSeeked(new_offset)) => Ok((*new_offset, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    pub fn file_allocate(pid: Pid, fd: u64, offset: u64, len: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FileAllocate(pid, fd, offset, len), *token);
+
+                match &response {
+                    Ok(NodeResult::FileAllocated) => Ok((0, 0)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Maps `len` bytes (at most `MMAP_MAX_PAGES` pages) of file `fd`
+    /// starting at `offset` read-only at `base` in process `pid`.
+    pub fn file_mmap(
+        pid: Pid,
+        fd: u64,
+        base: VAddr,
+        len: usize,
+        offset: usize,
+    ) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Op::FileMmap(pid, fd, base, len, offset), *token);
+
+                match &response {
+                    Ok(NodeResult::FileMapped) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Removes the file mappings in `[base, base+len)` of process `pid`,
+    /// returns the `TlbFlushHandle` for the range.
+    pub fn file_munmap(pid: Pid, base: VAddr, len: usize) -> Result<TlbFlushHandle, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FileMunmap(pid, base, len), *token);
+
+                match response {
+                    Ok(NodeResult::FileUnmapped(handle)) => Ok(handle),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
     pub fn file_info(pid: Pid, name: u64, info_ptr: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
@@ -334,6 +809,71 @@ impl<P: Process> KernelNode<P> {
             })
     }
 
+    /// Returns the bytes stored in the files of process `pid` and its quota.
+    pub fn fs_usage(pid: Pid) -> Result<(usize, Option<usize>), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::FsUsage(pid), *token);
+
+                match response {
+                    Ok(NodeResult::FsUsage(bytes, quota)) => Ok((bytes, quota)),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Drops clean pages of evictable files (see `FileFlags::O_EVICTABLE`)
+    /// to free at least `bytes` of memory, returns how many pages got
+    /// dropped (0 if there was nothing to drop).
+    pub fn evict(bytes: usize) -> Result<usize, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FileEvict(bytes), *token);
+
+                match response {
+                    Ok(NodeResult::FileEvicted(pages)) => Ok(pages),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Returns how many pages of evictable files were dropped so far.
+    pub fn evicted_pages() -> Result<usize, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::FsEvictedPages, *token);
+
+                match response {
+                    Ok(NodeResult::FileEvicted(pages)) => Ok(pages),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    pub fn readdir(pid: Pid, name: u64) -> Result<Vec<DirEntry>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::FileReadDir(pid, name), *token);
+
+                match response {
+                    Ok(NodeResult::DirEntries(entries)) => Ok(entries),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
     pub fn file_delete(pid: Pid, name: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
@@ -402,86 +942,685 @@ impl<P: Process> KernelNode<P> {
             })
     }
 
-    pub fn pinfo(pid: Pid) -> Result<ProcessInfo, KError> {
+    pub fn set_credentials(pid: Pid, credentials: Credentials) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let response = replica.execute(ReadOps::ProcessInfo(pid), *token);
+                let response = replica.execute_mut(Op::SetCredentials(pid, credentials), *token);
 
                 match &response {
-                    Ok(NodeResult::ProcessInfo(pinfo)) => Ok(*pinfo),
+                    Ok(NodeResult::CredentialsSet) => Ok((0, 0)),
                     Ok(_) => unreachable!("Got unexpected response"),
                     Err(r) => Err(r.clone()),
                 }
             })
     }
 
-    pub fn allocate_core_to_process(
-        pid: Pid,
-        entry_point: VAddr,
-        affinity: Option<topology::NodeId>,
-        gtid: Option<topology::GlobalThreadId>,
-    ) -> Result<(topology::GlobalThreadId, Eid), KError> {
+    pub fn pinfo(pid: Pid) -> Result<ProcessInfo, KError> {
         let kcb = super::kcb::get_kcb();
-
         kcb.replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let response = replica.execute_mut(
-                    Op::ProcAllocateCore(pid, gtid, affinity, entry_point),
-                    *token,
-                );
+                let response = replica.execute(ReadOps::ProcessInfo(pid), *token);
 
                 match &response {
-                    Ok(NodeResult::CoreAllocated(rgtid, eid)) => {
-                        let _r = gtid.map(|gtid| debug_assert_eq!(gtid, *rgtid));
-                        Ok((*rgtid, *eid))
-                    }
+                    Ok(NodeResult::ProcessInfo(pinfo)) => Ok(*pinfo),
                     Ok(_) => unreachable!("Got unexpected response"),
                     Err(r) => Err(r.clone()),
                 }
             })
     }
 
-    pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
+    /// The arguments process `pid` was spawned with (None if it uses the
+    /// ones of the kernel command-line).
+    pub fn args(pid: Pid) -> Result<Option<Arc<str>>, KError> {
         let kcb = super::kcb::get_kcb();
-
         kcb.replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let response = replica.execute_mut(Op::AllocateFrameToProcess(pid, frame), *token);
+                let response = replica.execute(ReadOps::ProcessArgs(pid), *token);
+
                 match response {
-                    Ok(NodeResult::FrameId(fid)) => Ok(fid),
+                    Ok(NodeResult::ProcessArgs(args)) => Ok(args),
                     Ok(_) => unreachable!("Got unexpected response"),
-                    Err(r) => Err(r.clone()),
+                    Err(r) => Err(r),
                 }
             })
     }
-}
 
-impl<P> Dispatch for KernelNode<P>
-where
-    P: Process,
-    P::E: Copy,
-{
-    type ReadOperation = ReadOps;
-    type WriteOperation = Op;
-    type Response = Result<NodeResult<P::E>, KError>;
+    /// The cores that run executors of process `pid`.
+    pub fn process_cores(pid: Pid) -> Result<Vec<topology::GlobalThreadId>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::ProcessCores(pid), *token);
 
-    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
-        match op {
-            ReadOps::Synchronize => {
-                // A NOP that just makes sure we've advanced the replica
-                Ok(NodeResult::Synchronized)
-            }
-            ReadOps::FileRead(pid, fd, buffer, len, offset) => {
-                let mut userslice = UserSlice::new(buffer, len as usize);
-                let process_lookup = self.process_map.get(&pid);
-                let mut p = process_lookup.expect("TODO: FileCreate process lookup failed");
-                let fd = p.get_fd(fd as usize);
-                let mnode_num = fd.get_mnode();
-                let flags = fd.get_flags();
+                match response {
+                    Ok(NodeResult::Cores(_policy, cores)) => Ok(cores),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Replaces the image of process `pid` with `module` and removes its
+    /// executors (see `Process::exec`), the process may only run on `gtid`.
+    ///
+    /// The memory of the old image goes back to the allocators (like the
+    /// memory of a process that exited, see `exit`).
+    pub fn exec(
+        pid: Pid,
+        module: &'static Module,
+        writeable_sections: Vec<Frame>,
+        gtid: topology::GlobalThreadId,
+    ) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica
+                    .execute_mut(Op::ProcExec(pid, module, writeable_sections, gtid), *token);
+
+                match &response {
+                    Ok(NodeResult::ProcReplaced(frames)) => {
+                        // Outside of NR, every replica returns the same frames
+                        for frame in frames {
+                            if let Err(e) = crate::memory::KernelAllocator::release_frame(*frame) {
+                                warn!("Unable to release {:?} of {}: {:?}", frame, pid, e);
+                            }
+                        }
+                        Ok(())
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Removes the process `pid` (and all its executors), records its
+    /// exit `code` and gives its memory back to the allocators.
+    ///
+    /// Returns the processes whose resources outside of NR (e.g., pipes)
+    /// have to be released now.
+    pub fn exit(pid: Pid, code: u64) -> Result<Vec<Pid>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ProcExit(pid, code), *token);
+
+                match &response {
+                    Ok(NodeResult::ProcExited(frames, released)) => {
+                        // Outside of NR, every replica returns the same frames
+                        for frame in frames {
+                            if let Err(e) = crate::memory::KernelAllocator::release_frame(*frame) {
+                                warn!("Unable to release {:?} of {}: {:?}", frame, pid, e);
+                            }
+                        }
+                        Ok(released.clone())
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Moves process `target` (`pid` itself or one of its children) into
+    /// process group `group` (0 creates a new one), returns the group id.
+    pub fn set_group(pid: Pid, target: Pid, group: Pid) -> Result<Pid, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ProcSetGroup(pid, target, group), *token);
+
+                match &response {
+                    Ok(NodeResult::GroupSet(group)) => Ok(*group),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Changes the scheduling weight of process `pid`.
+    pub fn set_weight(pid: Pid, weight: u64) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ProcSetWeight(pid, weight), *token);
+
+                match &response {
+                    Ok(NodeResult::WeightSet) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Removes all processes of process `group` (on behalf of `pid`) with
+    /// exit `code` and gives their memory back to the allocators, returns
+    /// the processes that were terminated and the ones whose resources have
+    /// to be released (see `exit`).
+    pub fn terminate_group(
+        pid: Pid,
+        group: Pid,
+        code: u64,
+    ) -> Result<(Vec<Pid>, Vec<Pid>), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Op::ProcTerminateGroup(pid, group, code), *token);
+
+                match response {
+                    Ok(NodeResult::GroupTerminated(members, frames, released)) => {
+                        // Outside of NR, every replica returns the same frames
+                        for frame in frames {
+                            if let Err(e) = crate::memory::KernelAllocator::release_frame(frame) {
+                                warn!("Unable to release {:?} of group {}: {:?}", frame, group, e);
+                            }
+                        }
+                        Ok((members, released))
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Process `pid` restarts its child `child` as boot module `binary`
+    /// (with `args`) once it exited.
+    pub fn supervise(pid: Pid, child: Pid, binary: &'static str, args: &str) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(
+                    Op::ProcSupervise(pid, child, binary, Arc::from(args)),
+                    *token,
+                );
+
+                match &response {
+                    Ok(NodeResult::Supervised) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// The boot module process `pid` restarts its supervised child `child`
+    /// from, None if `child` is still running.
+    pub fn restartable(pid: Pid, child: Pid) -> Result<Option<&'static str>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::ProcessRestartable(pid, child), *token);
+
+                match response {
+                    Ok(NodeResult::Restartable(binary)) => Ok(binary),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Starts the exited, supervised process `child` of `pid` again as
+    /// `module`. Returns the new process, the exit code of `child` and the
+    /// core `child` ran on.
+    pub fn restart(
+        pid: Pid,
+        child: Pid,
+        module: &'static Module,
+        writeable_sections: Vec<Frame>,
+    ) -> Result<(Pid, u64, Option<topology::GlobalThreadId>), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(
+                    Op::ProcRestart(pid, child, module, writeable_sections),
+                    *token,
+                );
+
+                match &response {
+                    Ok(NodeResult::ProcRestarted(new_pid, code, gtid)) => {
+                        Ok((*new_pid, *code, *gtid))
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Returns the exit code of the child `child` of process `pid` (and
+    /// forgets about `child`) or None if it's still running. In that case
+    /// the executor `eid` isn't scheduled anymore until `child` exited.
+    pub fn wait_child(pid: Pid, eid: Eid, child: Pid) -> Result<Option<u64>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ProcWaitChild(pid, eid, child), *token);
+
+                match &response {
+                    Ok(NodeResult::ExitStatus(status)) => Ok(*status),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Lets executor `eid` of process `pid` wait until the futex at `addr`
+    /// gets woken up (see `futex_wake`). Returns `false` if it was woken up
+    /// since `futex_sequence` returned `seq`: it doesn't wait then.
+    pub fn futex_wait(pid: Pid, eid: Eid, addr: u64, seq: u64) -> Result<bool, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FutexWait(pid, eid, addr, seq), *token);
+
+                match &response {
+                    Ok(NodeResult::FutexWaiting(waiting)) => Ok(*waiting),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Wakes up at most `count` executors of `pid` that wait on the futex at
+    /// `addr`, returns the cores they are assigned to (one per executor).
+    pub fn futex_wake(
+        pid: Pid,
+        addr: u64,
+        count: usize,
+    ) -> Result<Vec<topology::GlobalThreadId>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FutexWake(pid, addr, count), *token);
+
+                match &response {
+                    Ok(NodeResult::FutexWoken(cores)) => Ok(cores.clone()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// How often the futexes of `pid` were woken up so far.
+    pub fn futex_sequence(pid: Pid) -> Result<u64, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::FutexSequence(pid), *token);
+
+                match &response {
+                    Ok(NodeResult::FutexSequence(seq)) => Ok(*seq),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Subscribes `pid` to `event` (or unsubscribes it if `subscribe` is
+    /// false).
+    pub fn subscribe(pid: Pid, event: u64, subscribe: bool) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Op::ProcSubscribe(pid, event, subscribe), *token);
+
+                match &response {
+                    Ok(NodeResult::Subscribed) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// The processes that subscribed to `event`, each with a core it runs
+    /// on (processes that don't run anywhere right now are left out).
+    pub fn subscribers(event: u64) -> Result<Vec<(Pid, topology::GlobalThreadId)>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::EventSubscribers(event), *token);
+
+                match response {
+                    Ok(NodeResult::Subscribers(subscribers)) => Ok(subscribers),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    /// Returns the exit code of `child` or None if it's still running.
+    ///
+    /// `pid` has to be the parent of `child`.
+    pub fn exit_status(pid: Pid, child: Pid) -> Result<Option<u64>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::ProcessExitStatus(pid, child), *token);
+
+                match &response {
+                    Ok(NodeResult::ExitStatus(status)) => Ok(*status),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    pub fn processes() -> Result<Vec<ProcessEntry>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::ProcessList, *token);
+
+                match response {
+                    Ok(NodeResult::Processes(processes)) => Ok(processes),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    pub fn allocate_core_to_process(
+        pid: Pid,
+        entry_point: VAddr,
+        affinity: Option<topology::NodeId>,
+        gtid: Option<topology::GlobalThreadId>,
+    ) -> Result<(topology::GlobalThreadId, Eid), KError> {
+        let kcb = super::kcb::get_kcb();
+
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(
+                    Op::ProcAllocateCore(pid, gtid, affinity, entry_point),
+                    *token,
+                );
+
+                match &response {
+                    Ok(NodeResult::CoreAllocated(rgtid, eid)) => {
+                        let _r = gtid.map(|gtid| debug_assert_eq!(gtid, *rgtid));
+                        Ok((*rgtid, *eid))
+                    }
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Takes the executors away from core `gtid` that failed, returns the
+    /// processes they belonged to.
+    pub fn core_failed(gtid: topology::GlobalThreadId) -> Result<Vec<Pid>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::CoreFailed(gtid), *token);
+
+                match response {
+                    Ok(NodeResult::CoreFailed(pids)) => Ok(pids),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    pub fn executor_frames(pid: Pid) -> Result<Vec<Frame>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::ProcessExecutorFrames(pid), *token);
+
+                match response {
+                    Ok(NodeResult::Frames(frames)) => Ok(frames),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r),
+                }
+            })
+    }
+
+    pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
+        let kcb = super::kcb::get_kcb();
+
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::AllocateFrameToProcess(pid, frame), *token);
+                match response {
+                    Ok(NodeResult::FrameId(fid)) => Ok(fid),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+
+    /// Unregisters the frame `frame_id` (of `size` bytes) from process `pid`
+    /// and gives it back to the allocators (unless a forked process still
+    /// uses it).
+    pub fn release_frame_from_process(
+        pid: Pid,
+        frame_id: FrameId,
+        size: usize,
+    ) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut(Op::ReleaseFrameFromProcess(pid, frame_id, size), *token);
+                match response {
+                    Ok(NodeResult::FrameReleased(Some(frame))) => {
+                        // Outside of NR, every replica returns the same frame
+                        crate::memory::KernelAllocator::release_frame(frame)?;
+                        Ok(())
+                    }
+                    Ok(NodeResult::FrameReleased(None)) => Ok(()),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                    Err(r) => Err(r.clone()),
+                }
+            })
+    }
+}
+
+impl<P: Process> KernelNode<P> {
+    /// The mnode and size of file `filename` (if it exists).
+    fn file_size(&self, filename: &str) -> Option<(Mnode, usize)> {
+        self.fs
+            .lookup(filename)
+            .map(|mnode| (*mnode, self.fs.file_info(*mnode).fsize as usize))
+    }
+
+    /// Does any process besides `pid` have `frame` mapped copy-on-write at
+    /// `base`?
+    fn cow_shared(&self, pid: Pid, base: VAddr, frame: Frame) -> bool {
+        self.process_map
+            .iter()
+            .any(|(other, p)| *other != pid && p.cow_mapping(base) == Some((base, frame)))
+    }
+    /// Can `executor` be scheduled (it doesn't wait for a child to exit or
+    /// on a futex)?
+    fn is_runnable(&self, executor: &P::E) -> bool {
+        let key = (executor.pid(), executor.id());
+        !self.waiting.contains_key(&key) && !self.futex_waiting.contains_key(&key)
+    }
+
+    /// Removes process `pid` (and its executors) and records its exit
+    /// `code`, returns the frames that have to be given back (see
+    /// `release_memory`) and the processes whose resources outside of NR
+    /// can go away.
+    ///
+    /// That's `pid` itself unless it is supervised (its supervisor might
+    /// still restart it with its file descriptors), and the exited processes
+    /// `pid` supervised.
+    fn remove_process(
+        &mut self,
+        pid: Pid,
+        code: u64,
+    ) -> Result<(Vec<Frame>, Vec<Pid>), ProcessError> {
+        let process = self
+            .process_map
+            .remove(&pid)
+            .ok_or(ProcessError::NoProcessFoundForPid)?;
+        let mut cores: Vec<topology::GlobalThreadId> = Vec::new();
+        for (gtid, executors) in self.scheduler_map.iter_mut() {
+            if executors.iter().any(|e| e.pid() == pid) {
+                cores.push(*gtid);
+            }
+            executors.retain(|e| e.pid() != pid);
+        }
+        cores.sort_unstable();
+        let group = self.groups.remove(&pid).unwrap_or(pid);
+        self.weights.remove(&pid);
+        self.binaries.remove(&pid);
+        self.args.remove(&pid);
+        self.exited.insert(pid, code);
+        // Wake up the parent, nobody can wait for our children
+        // anymore
+        self.waiting
+            .retain(|(waiter, _eid), child| *child != pid && *waiter != pid);
+        self.parents.retain(|_child, parent| *parent != pid);
+        self.futex_waiting
+            .retain(|(waiter, _eid), _addr| *waiter != pid);
+        self.futex_sequence.remove(&pid);
+        for pids in self.subscribers.values_mut() {
+            pids.retain(|subscriber| *subscriber != pid);
+        }
+        self.drop_mmaps(pid);
+
+        let mut released = Vec::new();
+        match self.supervised.remove(&pid) {
+            Some((supervisor, binary, args)) => {
+                let restartable = Restartable {
+                    supervisor,
+                    binary,
+                    args,
+                    policy: process.pinfo().policy,
+                    fds: process.fds(),
+                    credentials: process.credentials(),
+                    group,
+                    cores,
+                };
+                self.restartable.insert(pid, restartable);
+            }
+            None => released.push(pid),
+        }
+        // Nobody restarts our children anymore
+        self.supervised
+            .retain(|_child, (supervisor, _binary, _args)| *supervisor != pid);
+        let mut abandoned: Vec<Pid> = self
+            .restartable
+            .iter()
+            .filter(|(_child, r)| r.supervisor == pid)
+            .map(|(child, _r)| *child)
+            .collect();
+        abandoned.sort_unstable();
+        for child in abandoned {
+            self.restartable.remove(&child);
+            released.push(child);
+        }
+
+        Ok((self.release_memory(process), released))
+    }
+
+    /// Forgets the file mappings of `pid` (the files can change again), the
+    /// pages have to be unmapped already or go away with the address space.
+    fn drop_mmaps(&mut self, pid: Pid) {
+        for region in self.mmaps.remove(&pid).unwrap_or_default() {
+            self.fs.remove_mapping(region.mnode);
+        }
+    }
+
+    /// Is any part of `[base, base+len)` mapped by `Op::FileMmap` in `pid`?
+    fn overlaps_mmap(&self, pid: Pid, base: VAddr, len: usize) -> bool {
+        self.mmaps.get(&pid).map_or(false, |regions| {
+            regions
+                .iter()
+                .any(|r| r.base < base + len && base < r.end())
+        })
+    }
+
+    /// Tears down an exited (or replaced, see `Op::ProcExec`) `process`: the
+    /// frames only this replica has (and the page-tables) go back to the
+    /// allocators right away, the frames shared by all replicas that no other
+    /// process uses anymore are returned (they have to be given back once,
+    /// not once per replica).
+    ///
+    /// TODO(correctness): Cores of other replicas might still run an
+    /// executor of the process until their replica caught up with the log.
+    fn release_memory(&self, process: Box<P>) -> Vec<Frame> {
+        let in_use: hashbrown::HashSet<PAddr> = self
+            .process_map
+            .values()
+            .flat_map(|p| p.owned_frames())
+            .map(|frame| frame.base)
+            .collect();
+
+        let mut shared = Vec::new();
+        for frame in process.owned_frames() {
+            if in_use.contains(&frame.base) {
+                continue;
+            }
+            if process.replica_frames().contains(&frame) {
+                if let Err(e) = crate::memory::KernelAllocator::release_frame(frame) {
+                    warn!("Unable to release {:?}: {:?}", frame, e);
+                }
+            } else {
+                shared.push(frame);
+            }
+        }
+
+        // Gives the page-tables back
+        drop(process);
+        shared
+    }
+}
+
+impl<P> Dispatch for KernelNode<P>
+where
+    P: Process,
+    P::E: Copy,
+{
+    type ReadOperation = ReadOps;
+    type WriteOperation = Op;
+    type Response = Result<NodeResult<P::E>, KError>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let _span = Span::new(OpClass::NrRead, discriminant_value(&op) as u16);
+        match op {
+            ReadOps::Synchronize => {
+                // A NOP that just makes sure we've advanced the replica
+                Ok(NodeResult::Synchronized)
+            }
+            ReadOps::FileRead(pid, fd, buffer, len, offset) => {
+                // Safety: The syscall handler checked that the buffer is mapped
+                // (see `handle_fileio`) and reads only run on the local replica
+                let mut userslice = unsafe { UserSlice::new(buffer, len as usize) };
+                let process_lookup = self.process_map.get(&pid);
+                let mut p = process_lookup.expect("TODO: FileCreate process lookup failed");
+                let fd = p
+                    .get_fd(fd as usize)
+                    .ok_or(FileSystemError::InvalidFileDescriptor)?;
+                let mnode_num = fd.get_mnode();
+                let flags = fd.get_flags();
 
                 // Check if the file has read-only or read-write permissions before reading it.
                 if !flags.is_read() {
@@ -497,7 +1636,10 @@ where
                     curr_offset = fd.get_offset();
                 }
 
-                match self.fs.read(mnode_num, &mut userslice, curr_offset) {
+                match self
+                    .fs
+                    .read(p.credentials(), mnode_num, &mut userslice, curr_offset)
+                {
                     Ok(len) => {
                         // Update the FD associated offset only when the
                         // offset wasn't given in the arguments.
@@ -506,7 +1648,7 @@ where
                         }
                         Ok(NodeResult::FileAccessed(len as u64))
                     }
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => Err(e.into()),
                 }
             }
             ReadOps::FileInfo(pid, name, info_ptr) => {
@@ -535,18 +1677,193 @@ where
                     }),
                 }
             }
+            ReadOps::FileReadDir(pid, name) => {
+                let process_lookup = self.process_map.get(&pid);
+                let p = process_lookup.expect("TODO: FileReadDir process lookup failed");
+
+                let dirname;
+                match userptr_to_str(name) {
+                    Ok(user_str) => dirname = user_str,
+                    Err(e) => return Err(e.clone()),
+                }
+
+                match self.fs.readdir(&dirname) {
+                    Ok(entries) => Ok(NodeResult::DirEntries(entries)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            ReadOps::FsUsage(pid) => Ok(NodeResult::FsUsage(
+                self.fs_usage.usage(pid),
+                self.fs_usage.quota(),
+            )),
+            ReadOps::FsEvictedPages => Ok(NodeResult::FileEvicted(self.evicted_pages)),
             ReadOps::ProcessInfo(pid) => {
                 let process_lookup = self.process_map.get(&pid);
                 let p = process_lookup.expect("TODO: process lookup failed");
                 Ok(NodeResult::ProcessInfo(*p.pinfo()))
             }
+            ReadOps::ProcessArgs(pid) => Ok(NodeResult::ProcessArgs(self.args.get(&pid).cloned())),
+            ReadOps::ProcessExitStatus(pid, child) => {
+                if self.parents.get(&child) != Some(&pid) {
+                    return Err(ProcessError::NotAChild.into());
+                }
+                if let Some(code) = self.exited.get(&child) {
+                    Ok(NodeResult::ExitStatus(Some(*code)))
+                } else if self.process_map.contains_key(&child) {
+                    Ok(NodeResult::ExitStatus(None))
+                } else {
+                    Err(ProcessError::NoProcessFoundForPid.into())
+                }
+            }
+            ReadOps::FutexSequence(pid) => {
+                let seq = self.futex_sequence.get(&pid).copied();
+                Ok(NodeResult::FutexSequence(seq.unwrap_or(0)))
+            }
+            ReadOps::EventSubscribers(event) => {
+                let subscribers = self
+                    .subscribers
+                    .get(&event)
+                    .map(|pids| {
+                        pids.iter()
+                            .filter_map(|pid| {
+                                self.scheduler_map
+                                    .iter()
+                                    .filter(|(_gtid, executors)| {
+                                        executors.iter().any(|e| e.pid() == *pid)
+                                    })
+                                    .map(|(gtid, _executors)| *gtid)
+                                    .min()
+                                    .map(|gtid| (*pid, gtid))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Ok(NodeResult::Subscribers(subscribers))
+            }
+            ReadOps::ProcessRestartable(pid, child) => match self.restartable.get(&child) {
+                Some(r) if r.supervisor == pid => Ok(NodeResult::Restartable(Some(r.binary))),
+                Some(_) => Err(ProcessError::NotAChild.into()),
+                None => match self.supervised.get(&child) {
+                    Some((supervisor, _binary, _args)) if *supervisor == pid => {
+                        Ok(NodeResult::Restartable(None))
+                    }
+                    _ => Err(ProcessError::NotAChild.into()),
+                },
+            },
+            ReadOps::ProcessList => {
+                let mut processes: Vec<ProcessEntry> = self
+                    .process_map
+                    .iter()
+                    .map(|(pid, p)| ProcessEntry {
+                        pid: *pid,
+                        binary: self.binaries.get(pid).unwrap_or(&"").to_string(),
+                        group: self.groups.get(pid).copied().unwrap_or(*pid),
+                        policy: p.pinfo().policy,
+                        cores: self
+                            .scheduler_map
+                            .iter()
+                            .filter(|(_gtid, executors)| executors.iter().any(|e| e.pid() == *pid))
+                            .map(|(gtid, _executors)| *gtid)
+                            .collect(),
+                        // Output isn't replicated, the syscall layer fills these in:
+                        printed_bytes: 0,
+                        dropped_bytes: 0,
+                    })
+                    .collect();
+                processes.sort_by_key(|p| p.pid);
+                Ok(NodeResult::Processes(processes))
+            }
             ReadOps::CurrentExecutor(gtid) => {
                 let executor = self
                     .scheduler_map
                     .get(&gtid)
+                    .and_then(|executors| executors.iter().find(|e| self.is_runnable(e)))
                     .ok_or(KError::NoExecutorForCore)?;
                 Ok(NodeResult::Executor(Arc::downgrade(executor)))
             }
+            ReadOps::CoreExecutors(gtid) => {
+                let executors: Vec<Weak<P::E>> = self
+                    .scheduler_map
+                    .get(&gtid)
+                    .map(|executors| {
+                        executors
+                            .iter()
+                            .filter(|e| self.is_runnable(e))
+                            .map(Arc::downgrade)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if executors.is_empty() {
+                    return Err(KError::NoExecutorForCore);
+                }
+                Ok(NodeResult::Executors(executors))
+            }
+            ReadOps::ProcessCores(pid) => {
+                let p = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let cores = self
+                    .scheduler_map
+                    .iter()
+                    .filter(|(_gtid, executors)| executors.iter().any(|e| e.pid() == pid))
+                    .map(|(gtid, _executors)| *gtid)
+                    .collect();
+                Ok(NodeResult::Cores(p.pinfo().policy, cores))
+            }
+            ReadOps::ProcessWeight(pid) => {
+                let weight = self
+                    .weights
+                    .get(&pid)
+                    .copied()
+                    .unwrap_or(crate::scheduler::fair::DEFAULT_WEIGHT);
+                Ok(NodeResult::Weight(weight))
+            }
+            ReadOps::ProcessExecutorFrames(pid) => {
+                let p = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                Ok(NodeResult::Frames(p.executor_frames()))
+            }
+            ReadOps::MemCowMapping(pid, vaddr) => {
+                let p = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let mapping = p
+                    .cow_mapping(vaddr)
+                    .map(|(base, frame)| (base, frame, self.cow_shared(pid, base, frame)));
+                Ok(NodeResult::CowMapping(mapping))
+            }
+            ReadOps::MemValidRange(pid, base, len, access) => {
+                let p = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let cow = p.has_cow_mapping(base, len);
+                if !cow || access == Access::Read {
+                    if !p.vspace().is_mapped(base, len, access) {
+                        return Err(KError::BadAddress);
+                    }
+                    return Ok(NodeResult::ValidRange(cow));
+                }
+
+                // Only after a fork, check the pages one by one
+                if !p.vspace().is_mapped(base, len, Access::Read) {
+                    return Err(KError::BadAddress);
+                }
+                let last = base.as_usize() + core::cmp::max(len, 1) - 1;
+                let mut page = base.as_usize() & !(BASE_PAGE_SIZE - 1);
+                while page <= last {
+                    let (_paddr, rights) = p.vspace().resolve(VAddr::from(page))?;
+                    if !rights.allows(Access::Write) && p.cow_mapping(VAddr::from(page)).is_none() {
+                        return Err(KError::BadAddress);
+                    }
+                    page += BASE_PAGE_SIZE;
+                }
+                Ok(NodeResult::ValidRange(cow))
+            }
             ReadOps::MemResolve(pid, base) => {
                 let process_lookup = self.process_map.get(&pid);
                 let kcb = crate::kcb::get_kcb();
@@ -559,18 +1876,180 @@ where
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        let _span = Span::new(OpClass::NrWrite, discriminant_value(&op) as u16);
         match op {
-            Op::ProcCreate(module, writeable_sections) => {
-                P::new(module, self.current_pid, writeable_sections)
+            Op::ProcCreate(module, writeable_sections, policy, args, parent) => {
+                P::new(module, self.current_pid, writeable_sections, policy)
                     .and_then(|process| {
                         //self.process_map.try_reserve(1);
                         let pid = self.current_pid;
                         self.process_map.insert(pid, Box::new(process));
+                        self.binaries.insert(pid, module.name());
+                        if !args.is_empty() {
+                            self.args.insert(pid, args);
+                        }
+                        if let Some(parent) = parent {
+                            self.parents.insert(pid, parent);
+                        }
+                        // Processes start in the group of their parent
+                        let group = parent
+                            .and_then(|parent| self.groups.get(&parent).copied())
+                            .unwrap_or(pid);
+                        self.groups.insert(pid, group);
                         self.current_pid += 1;
                         Ok(NodeResult::ProcCreated(pid))
                     })
                     .map_err(|e| e.into())
             }
+            Op::ProcFork(pid, executor_frames, eid, gtid) => {
+                let child_pid = self.current_pid;
+                let process = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let (child, executor) = process.fork(child_pid, executor_frames, eid)?;
+                let binary = self.binaries.get(&pid).copied().unwrap_or("");
+                self.process_map.insert(child_pid, Box::new(child));
+                self.binaries.insert(child_pid, binary);
+                if let Some(args) = self.args.get(&pid).cloned() {
+                    self.args.insert(child_pid, args);
+                }
+                self.parents.insert(child_pid, pid);
+                let group = self.groups.get(&pid).copied().unwrap_or(pid);
+                self.groups.insert(child_pid, group);
+                if let Some(weight) = self.weights.get(&pid).copied() {
+                    self.weights.insert(child_pid, weight);
+                }
+                // The child has the same file mappings
+                if let Some(regions) = self.mmaps.get(&pid).cloned() {
+                    for region in regions.iter() {
+                        self.fs.add_mapping(region.mnode);
+                    }
+                    self.mmaps.insert(child_pid, regions);
+                }
+                self.current_pid += 1;
+
+                let executor: Arc<P::E> = executor.into();
+                let weak_executor = Arc::downgrade(&executor);
+                self.scheduler_map
+                    .entry(gtid)
+                    .or_insert_with(Vec::new)
+                    .push(executor);
+
+                // The parent lost write access to its memory, flush all of
+                // user-space on the cores it runs on:
+                let mut shootdown_handle = TlbFlushHandle::new(
+                    VAddr::zero(),
+                    Frame::new(PAddr::zero(), crate::arch::memory::KERNEL_BASE as usize, 0),
+                );
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
+                        shootdown_handle.add_core(*gtid);
+                    }
+                }
+
+                Ok(NodeResult::ProcForked(
+                    child_pid,
+                    weak_executor,
+                    shootdown_handle,
+                ))
+            }
+            Op::ProcExec(pid, module, writeable_sections, gtid) => {
+                // We can't stop executors on other cores (yet)
+                let elsewhere = self.scheduler_map.iter().any(|(core, executors)| {
+                    *core != gtid && executors.iter().any(|e| e.pid() == pid)
+                });
+                if elsewhere {
+                    return Err(KError::NotSupported);
+                }
+
+                let process = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let old_image = process.exec(module, writeable_sections)?;
+                for executors in self.scheduler_map.values_mut() {
+                    executors.retain(|e| e.pid() != pid);
+                }
+                self.binaries.insert(pid, module.name());
+                self.args.remove(&pid);
+                self.drop_mmaps(pid);
+                let frames = self.release_memory(Box::new(old_image));
+                Ok(NodeResult::ProcReplaced(frames))
+            }
+            Op::ProcWaitChild(pid, eid, child) => {
+                if self.parents.get(&child) != Some(&pid) {
+                    return Err(ProcessError::NotAChild.into());
+                }
+                // `Op::ProcRestart` collects the exit code
+                if self.restartable.contains_key(&child) {
+                    return Ok(NodeResult::ExitStatus(self.exited.get(&child).copied()));
+                }
+
+                match self.exited.remove(&child) {
+                    Some(code) => {
+                        self.parents.remove(&child);
+                        Ok(NodeResult::ExitStatus(Some(code)))
+                    }
+                    None => {
+                        self.waiting.insert((pid, eid), child);
+                        Ok(NodeResult::ExitStatus(None))
+                    }
+                }
+            }
+            Op::FutexWait(pid, eid, addr, seq) => {
+                if !self.process_map.contains_key(&pid) {
+                    return Err(ProcessError::NoProcessFoundForPid.into());
+                }
+                // A futex got woken up in the meantime, the caller has to look again
+                if self.futex_sequence.get(&pid).copied().unwrap_or(0) != seq {
+                    return Ok(NodeResult::FutexWaiting(false));
+                }
+                self.futex_waiting.insert((pid, eid), addr);
+                Ok(NodeResult::FutexWaiting(true))
+            }
+            Op::FutexWake(pid, addr, count) => {
+                *self.futex_sequence.entry(pid).or_insert(0) += 1;
+
+                // Oldest executors first, so every replica wakes the same
+                let mut waiters: Vec<Eid> = self
+                    .futex_waiting
+                    .iter()
+                    .filter(|((waiter, _eid), waits_on)| *waiter == pid && **waits_on == addr)
+                    .map(|((_pid, eid), _addr)| *eid)
+                    .collect();
+                waiters.sort_unstable();
+                waiters.truncate(count);
+                for eid in waiters.iter() {
+                    self.futex_waiting.remove(&(pid, *eid));
+                }
+                let cores = waiters
+                    .iter()
+                    .filter_map(|eid| {
+                        self.scheduler_map
+                            .iter()
+                            .find(|(_gtid, executors)| {
+                                executors.iter().any(|e| e.pid() == pid && e.id() == *eid)
+                            })
+                            .map(|(gtid, _executors)| *gtid)
+                    })
+                    .collect();
+                Ok(NodeResult::FutexWoken(cores))
+            }
+            Op::ProcSubscribe(pid, event, subscribe) => {
+                if !self.process_map.contains_key(&pid) {
+                    return Err(ProcessError::NoProcessFoundForPid.into());
+                }
+                let pids = self.subscribers.entry(event).or_insert_with(Vec::new);
+                match pids.binary_search(&pid) {
+                    Ok(idx) if !subscribe => {
+                        pids.remove(idx);
+                    }
+                    Err(idx) if subscribe => pids.insert(idx, pid),
+                    _ => {}
+                }
+                Ok(NodeResult::Subscribed)
+            }
             Op::ProcDestroy(pid) => {
                 // TODO(correctness): This is just a trivial,
                 // wrong implementation at the moment
@@ -583,6 +2062,118 @@ where
                     Err(ProcessError::NoProcessFoundForPid.into())
                 }
             }
+            Op::ProcExit(pid, code) => {
+                let (frames, released) = self.remove_process(pid, code)?;
+                Ok(NodeResult::ProcExited(frames, released))
+            }
+            Op::ProcSetGroup(pid, target, group) => {
+                if target != pid && self.parents.get(&target) != Some(&pid) {
+                    return Err(ProcessError::NotAChild.into());
+                }
+                if !self.process_map.contains_key(&target) {
+                    return Err(ProcessError::NoProcessFoundForPid.into());
+                }
+
+                let group = if group == 0 {
+                    target
+                } else if self.groups.values().any(|g| *g == group) {
+                    group
+                } else {
+                    return Err(ProcessError::NoSuchGroup.into());
+                };
+                self.groups.insert(target, group);
+                Ok(NodeResult::GroupSet(group))
+            }
+            Op::ProcSetWeight(pid, weight) => {
+                if !self.process_map.contains_key(&pid) {
+                    return Err(ProcessError::NoProcessFoundForPid.into());
+                }
+                self.weights.insert(pid, weight);
+                Ok(NodeResult::WeightSet)
+            }
+            Op::ProcTerminateGroup(pid, group, code) => {
+                let credentials = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?
+                    .credentials();
+
+                let mut members: Vec<Pid> = self
+                    .groups
+                    .iter()
+                    .filter(|(_member, g)| **g == group)
+                    .map(|(member, _g)| *member)
+                    .collect();
+                if members.is_empty() {
+                    return Err(ProcessError::NoSuchGroup.into());
+                }
+                // Exiting init shuts the machine down
+                if members.contains(&crate::process::INIT_PID) {
+                    return Err(KError::NotPermitted);
+                }
+                // All or nothing:
+                for member in members.iter() {
+                    let p = self
+                        .process_map
+                        .get(member)
+                        .ok_or(ProcessError::NoProcessFoundForPid)?;
+                    if !credentials.is_root() && p.credentials().uid != credentials.uid {
+                        return Err(KError::NotPermitted);
+                    }
+                }
+
+                // Same order on every replica
+                members.sort_unstable();
+                let mut frames = Vec::new();
+                let mut released = Vec::new();
+                for member in members.iter() {
+                    let (member_frames, member_released) = self.remove_process(*member, code)?;
+                    frames.extend(member_frames);
+                    released.extend(member_released);
+                }
+                Ok(NodeResult::GroupTerminated(members, frames, released))
+            }
+            Op::ProcSupervise(pid, child, binary, args) => {
+                if self.parents.get(&child) != Some(&pid) || !self.process_map.contains_key(&child)
+                {
+                    return Err(ProcessError::NotAChild.into());
+                }
+                self.supervised.insert(child, (pid, binary, args));
+                Ok(NodeResult::Supervised)
+            }
+            Op::ProcRestart(pid, child, module, writeable_sections) => {
+                let policy = match self.restartable.get(&child) {
+                    Some(r) if r.supervisor == pid => r.policy,
+                    _ => return Err(ProcessError::NotAChild.into()),
+                };
+                let new_pid = self.current_pid;
+                let mut process = P::new(module, new_pid, writeable_sections, policy)?;
+                let r = self
+                    .restartable
+                    .remove(&child)
+                    .ok_or(ProcessError::NotAChild)?;
+                // It inherits what the old one had open and who it ran as
+                process.set_fds(r.fds);
+                process.set_credentials(r.credentials);
+                self.process_map.insert(new_pid, Box::new(process));
+                self.binaries.insert(new_pid, module.name());
+                if !r.args.is_empty() {
+                    self.args.insert(new_pid, r.args.clone());
+                }
+                self.parents.insert(new_pid, pid);
+                self.groups.insert(new_pid, r.group);
+                self.supervised.insert(new_pid, (pid, r.binary, r.args));
+                self.current_pid += 1;
+
+                // The supervisor collected the exit code of the old one
+                let code = self.exited.remove(&child).unwrap_or(0);
+                self.parents.remove(&child);
+                Ok(NodeResult::ProcRestarted(
+                    new_pid,
+                    code,
+                    r.cores.first().copied(),
+                ))
+            }
             Op::ProcInstallVCpuArea(_, _) => unreachable!(),
             Op::ProcAllocIrqVector => unreachable!(),
             Op::ProcRaiseIrq => unreachable!(),
@@ -607,14 +2198,22 @@ where
                 Ok(NodeResult::Mapped)
             }
             Op::MemMapDevice(pid, frame, action) => {
-                let process_lookup = self.process_map.get_mut(&pid);
-                let kcb = crate::kcb::get_kcb();
-                let p = process_lookup.expect("TODO: MemMapFrame process lookup failed");
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
 
                 let base = VAddr::from(frame.base.as_u64());
-                p.vspace_mut()
-                    .map_frame(base, frame, action)
-                    .expect("TODO: MemMapFrame map_frame failed");
+                p.map_foreign(base, frame, action, MappingType::Device)?;
+                Ok(NodeResult::Mapped)
+            }
+            Op::MemMapKernel(pid, base, frame, action) => {
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
+                p.map_foreign(base, frame, action, MappingType::Kernel)?;
                 Ok(NodeResult::Mapped)
             }
             Op::MemMapFrameId(pid, base, frame_id, action) => {
@@ -630,8 +2229,48 @@ where
                 p.vspace_mut().map_frame(base, frame, action)?;
                 Ok(NodeResult::MappedFrameId(frame.base, frame.size))
             }
-            Op::MemAdjust => unreachable!(),
+            Op::MemAdjust(pid, base, len, rights) => {
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                p.protect(base, len, rights)?;
+
+                // Cores that run the process might still have the old rights
+                // cached
+                let mut shootdown_handle =
+                    TlbFlushHandle::new(base, Frame::new(PAddr::zero(), len, 0));
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
+                        shootdown_handle.add_core(*gtid);
+                    }
+                }
+
+                Ok(NodeResult::Adjusted(shootdown_handle))
+            }
+            Op::MemSetKey(pid, base, len, key) => {
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                p.set_key(base, len, key)?;
+
+                // The key is cached in the TLB like the rights
+                let mut shootdown_handle =
+                    TlbFlushHandle::new(base, Frame::new(PAddr::zero(), len, 0));
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
+                        shootdown_handle.add_core(*gtid);
+                    }
+                }
+
+                Ok(NodeResult::Adjusted(shootdown_handle))
+            }
             Op::MemUnmap(pid, vaddr) => {
+                // File mappings go away with `Op::FileMunmap`
+                if self.overlaps_mmap(pid, vaddr, BASE_PAGE_SIZE) {
+                    return Err(AddressSpaceError::InvalidBase.into());
+                }
                 let p = self
                     .process_map
                     .get_mut(&pid)
@@ -641,14 +2280,44 @@ where
                 let mut shootdown_handle = p.vspace_mut().unmap(vaddr)?;
                 // Figure out which cores are running our current process
                 // (this is where we send IPIs later)
-                for (gtid, e) in self.scheduler_map.iter() {
-                    if pid == e.pid() {
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
                         shootdown_handle.add_core(*gtid);
                     }
                 }
 
                 Ok(NodeResult::Unmapped(shootdown_handle))
             }
+            Op::MemCowResolve(pid, base, copy) => {
+                let p = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let frame = match p.cow_mapping(base) {
+                    Some((cow_base, frame)) if cow_base == base => frame,
+                    // Already resolved (by a fault on another core)
+                    _ => return Ok(NodeResult::CowResolved(None)),
+                };
+                if copy.is_none() && self.cow_shared(pid, base, frame) {
+                    // Another process started to share the frame since the
+                    // caller looked at it
+                    return Ok(NodeResult::CowResolved(None));
+                }
+
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let mut shootdown_handle = p.resolve_cow(base, copy)?;
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
+                        shootdown_handle.add_core(*gtid);
+                    }
+                }
+
+                Ok(NodeResult::CowResolved(Some(shootdown_handle)))
+            }
             Op::FileOpen(pid, filename, flags, modes) => {
                 let process_lookup = self.process_map.get_mut(&pid);
                 let mut p = process_lookup.expect("TODO: FileOpen process lookup failed");
@@ -661,26 +2330,40 @@ where
                     });
                 }
 
+                let creds = p.credentials();
                 let fd = p.allocate_fd();
                 match fd {
                     None => Err(KError::NotSupported),
                     Some(mut fd) => {
                         let mnode_num;
                         if mnode.is_none() {
-                            match self.fs.create(&filename, modes) {
-                                Ok(m_num) => mnode_num = m_num,
+                            match self.fs.create(creds, &filename, modes) {
+                                Ok(m_num) => {
+                                    mnode_num = m_num;
+                                    self.fs_usage.add_file(mnode_num, pid);
+                                }
                                 Err(e) => {
                                     let fdesc = fd.0 as usize;
                                     p.deallocate_fd(fdesc);
-                                    return Err(KError::FileSystem { source: e });
+                                    return Err(e.into());
                                 }
                             }
                         } else {
+                            mnode_num = *mnode.unwrap();
                             // File exists and FileOpen is called with O_TRUNC flag.
                             if flags.is_truncate() {
-                                self.fs.truncate(&filename);
+                                let size = self.fs.file_info(mnode_num).fsize as usize;
+                                if let Err(e) = self.fs.truncate(creds, &filename) {
+                                    let fdesc = fd.0 as usize;
+                                    p.deallocate_fd(fdesc);
+                                    return Err(e.into());
+                                }
+                                self.fs_usage.uncharge(mnode_num, size);
                             }
-                            mnode_num = *mnode.unwrap();
+                        }
+                        if flags.is_evictable() {
+                            // Can't fail, we just looked the file up
+                            let _r = self.fs.set_evictable(mnode_num);
                         }
                         fd.1.update_fd(mnode_num, flags);
                         Ok(NodeResult::FileOpened(fd.0))
@@ -690,7 +2373,9 @@ where
             Op::FileWrite(pid, fd, kernslice, len, offset) => {
                 let process_lookup = self.process_map.get_mut(&pid);
                 let mut p = process_lookup.expect("TODO: FileWrite process lookup failed");
-                let fd = p.get_fd(fd as usize);
+                let fd = p
+                    .get_fd(fd as usize)
+                    .ok_or(FileSystemError::InvalidFileDescriptor)?;
                 let mnode_num = fd.get_mnode();
                 let flags = fd.get_flags();
 
@@ -713,7 +2398,19 @@ where
                     }
                 }
 
-                match self.fs.write(mnode_num, &kernslice.clone(), curr_offset) {
+                // Charge the bytes the file grows by before writing them
+                let fs = &self.fs;
+                let charged = self.fs_usage.charge_write(
+                    mnode_num,
+                    || fs.file_info(mnode_num).fsize as usize,
+                    curr_offset,
+                    kernslice.len(),
+                )?;
+
+                match self
+                    .fs
+                    .write(p.credentials(), mnode_num, &kernslice.clone(), curr_offset)
+                {
                     Ok(len) => {
                         if offset == -1 {
                             // Update offset when FileWrite doesn't give an explicit offset value.
@@ -721,7 +2418,188 @@ where
                         }
                         Ok(NodeResult::FileAccessed(len as u64))
                     }
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => {
+                        self.fs_usage.uncharge(mnode_num, charged);
+                        Err(e.into())
+                    }
+                }
+            }
+            Op::FileSeek(pid, fd, offset, whence) => {
+                let process_lookup = self.process_map.get_mut(&pid);
+                let p = process_lookup.expect("TODO: FileSeek process lookup failed");
+                let fd = p
+                    .get_fd(fd as usize)
+                    .ok_or(FileSystemError::InvalidFileDescriptor)?;
+                let file_size = self.fs.file_info(fd.get_mnode()).fsize as usize;
+
+                match fd.seek(offset, whence, file_size) {
+                    Ok(new_offset) => Ok(NodeResult::FileSeeked(new_offset as u64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Op::FileAllocate(pid, fd, offset, len) => {
+                let process_lookup = self.process_map.get(&pid);
+                let p = process_lookup.expect("TODO: FileAllocate process lookup failed");
+                let fd = p
+                    .get_fd(fd as usize)
+                    .ok_or(FileSystemError::InvalidFileDescriptor)?;
+
+                // Like writes, this needs a file descriptor that allows writing.
+                if !fd.get_flags().is_write() {
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::PermissionError,
+                    });
+                }
+
+                let mnode_num = fd.get_mnode();
+                let fs = &self.fs;
+                let charged = self.fs_usage.charge_write(
+                    mnode_num,
+                    || fs.file_info(mnode_num).fsize as usize,
+                    offset as usize,
+                    len as usize,
+                )?;
+
+                match self
+                    .fs
+                    .allocate(p.credentials(), mnode_num, offset as usize, len as usize)
+                {
+                    Ok(()) => Ok(NodeResult::FileAllocated),
+                    Err(e) => {
+                        self.fs_usage.uncharge(mnode_num, charged);
+                        Err(e.into())
+                    }
+                }
+            }
+            Op::FileMmap(pid, fd, base, len, offset) => {
+                if len == 0 || len > MMAP_MAX_PAGES * BASE_PAGE_SIZE {
+                    return Err(AddressSpaceError::InvalidLength.into());
+                }
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                let fd = p
+                    .get_fd(fd as usize)
+                    .ok_or(FileSystemError::InvalidFileDescriptor)?;
+                // The mapping is read-only, writes go through `FileWrite`
+                if !fd.get_flags().is_read() {
+                    return Err(KError::FileSystem {
+                        source: FileSystemError::PermissionError,
+                    });
+                }
+                let mnode_num = fd.get_mnode();
+                let pages =
+                    self.fs
+                        .pages(p.credentials(), mnode_num, FileModes::S_IRUSR, offset, len)?;
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
+
+                // Every replica maps its own copy of the file
+                let affinity = crate::kcb::get_kcb().physical_memory.affinity;
+                for (idx, page) in pages.iter().enumerate() {
+                    let vaddr = base + idx * BASE_PAGE_SIZE;
+                    let frame = Frame::new(
+                        kernel_vaddr_to_paddr(VAddr::from(*page)),
+                        BASE_PAGE_SIZE,
+                        affinity,
+                    );
+                    if let Err(e) =
+                        p.map_foreign(vaddr, frame, MapAction::ReadUser, MappingType::File)
+                    {
+                        // Nothing ran with the new pages yet, no need to
+                        // flush the TLBs
+                        for mapped in 0..idx {
+                            let _ = p.vspace_mut().unmap(base + mapped * BASE_PAGE_SIZE);
+                        }
+                        return Err(e.into());
+                    }
+                }
+
+                self.fs.add_mapping(mnode_num);
+                self.mmaps
+                    .entry(pid)
+                    .or_insert_with(Vec::new)
+                    .push(MmapRegion {
+                        base,
+                        len: pages.len() * BASE_PAGE_SIZE,
+                        mnode: mnode_num,
+                    });
+                Ok(NodeResult::FileMapped)
+            }
+            Op::FileMunmap(pid, base, len) => {
+                let end = base
+                    .as_usize()
+                    .checked_add(len)
+                    .ok_or(AddressSpaceError::InvalidLength)?;
+                let regions = self
+                    .mmaps
+                    .get_mut(&pid)
+                    .ok_or(AddressSpaceError::NotMapped)?;
+                let inside = |r: &MmapRegion| r.base >= base && r.end().as_usize() <= end;
+                // Only whole mappings can be removed
+                let partial = regions
+                    .iter()
+                    .any(|r| !inside(r) && r.base.as_usize() < end && base < r.end());
+                if partial {
+                    return Err(AddressSpaceError::InvalidLength.into());
+                }
+                if !regions.iter().any(|r| inside(r)) {
+                    return Err(AddressSpaceError::NotMapped.into());
+                }
+                let (unmapped, kept): (Vec<MmapRegion>, Vec<MmapRegion>) =
+                    regions.drain(..).partition(|r| inside(r));
+                *regions = kept;
+
+                let p = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                for region in unmapped.iter() {
+                    for vaddr in
+                        (region.base.as_usize()..region.end().as_usize()).step_by(BASE_PAGE_SIZE)
+                    {
+                        if let Err(e) = p.vspace_mut().unmap(VAddr::from(vaddr)) {
+                            warn!("Can't unmap {:#x} of file mapping: {:?}", vaddr, e);
+                        }
+                    }
+                    self.fs.remove_mapping(region.mnode);
+                }
+
+                let mut shootdown_handle =
+                    TlbFlushHandle::new(base, Frame::new(PAddr::zero(), len, 0));
+                for (gtid, executors) in self.scheduler_map.iter() {
+                    if executors.iter().any(|e| e.pid() == pid) {
+                        shootdown_handle.add_core(*gtid);
+                    }
+                }
+                Ok(NodeResult::FileUnmapped(shootdown_handle))
+            }
+            Op::PipeOpen(pid) => {
+                let process_lookup = self.process_map.get_mut(&pid);
+                let mut p = process_lookup.expect("TODO: PipeOpen process lookup failed");
+
+                let read_fd = match p.allocate_fd() {
+                    Some((fd, filedesc)) => {
+                        filedesc.update_fd(PIPE_MNODE, FileFlags::O_RDONLY);
+                        fd
+                    }
+                    None => {
+                        return Err(KError::FileSystem {
+                            source: FileSystemError::OpenFileLimit,
+                        })
+                    }
+                };
+                match p.allocate_fd() {
+                    Some((write_fd, filedesc)) => {
+                        filedesc.update_fd(PIPE_MNODE, FileFlags::O_WRONLY);
+                        Ok(NodeResult::PipeOpened(read_fd, write_fd))
+                    }
+                    None => {
+                        p.deallocate_fd(read_fd as usize);
+                        Err(KError::FileSystem {
+                            source: FileSystemError::OpenFileLimit,
+                        })
+                    }
                 }
             }
             Op::FileClose(pid, fd) => {
@@ -737,32 +2615,97 @@ where
                     })
                 }
             }
+            Op::FileEvict(bytes) => {
+                // Files that are open for writing might change any moment
+                let busy: Vec<Mnode> = self
+                    .process_map
+                    .values()
+                    .flat_map(|p| p.open_fds())
+                    .filter(|fd| fd.get_flags().is_write())
+                    .map(|fd| fd.get_mnode())
+                    .collect();
+
+                let mut pages = 0;
+                for (mnode_num, len, evicted) in self.fs.evict(bytes, &busy) {
+                    self.fs_usage.uncharge(mnode_num, len);
+                    pages += evicted.base_pages + evicted.large_pages;
+                }
+                self.evicted_pages += pages;
+                Ok(NodeResult::FileEvicted(pages))
+            }
             Op::FileDelete(pid, filename) => {
+                let file = self.file_size(&filename);
                 let process_lookup = self.process_map.get_mut(&pid);
                 let mut p = process_lookup.expect("TODO: FileDelete process lookup failed");
-                match self.fs.delete(&filename) {
-                    Ok(is_deleted) => Ok(NodeResult::FileDeleted(is_deleted)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                match self.fs.delete(p.credentials(), &filename) {
+                    Ok(is_deleted) => {
+                        if let Some((mnode, size)) = file {
+                            self.fs_usage.remove_file(mnode, size);
+                        }
+                        Ok(NodeResult::FileDeleted(is_deleted))
+                    }
+                    Err(e) => Err(e.into()),
                 }
             }
             Op::FileRename(pid, oldname, newname) => {
+                // A file that exists at `newname` gets overwritten
+                let old_mnode = self.fs.lookup(&oldname).map(|mnode| *mnode);
+                let overwritten = self
+                    .file_size(&newname)
+                    .filter(|(mnode, _size)| Some(*mnode) != old_mnode);
                 let process_lookup = self.process_map.get_mut(&pid);
                 let mut p = process_lookup.expect("TODO: FileRename process lookup failed");
-                match self.fs.rename(&oldname, &newname) {
-                    Ok(is_renamed) => Ok(NodeResult::FileRenamed(is_renamed)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                match self.fs.rename(p.credentials(), &oldname, &newname) {
+                    Ok(is_renamed) => {
+                        if let Some((mnode, size)) = overwritten {
+                            self.fs_usage.remove_file(mnode, size);
+                        }
+                        Ok(NodeResult::FileRenamed(is_renamed))
+                    }
+                    Err(e) => Err(e.into()),
                 }
             }
             Op::MkDir(pid, filename, modes) => {
                 let process_lookup = self.process_map.get_mut(&pid);
                 let mut p = process_lookup.expect("TODO: MkDir process lookup failed");
-                match self.fs.mkdir(&filename, modes) {
+                match self.fs.mkdir(p.credentials(), &filename, modes) {
                     Ok(is_created) => Ok(NodeResult::DirCreated(is_created)),
-                    Err(e) => Err(KError::FileSystem { source: e }),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Op::SetCredentials(pid, credentials) => {
+                let process_lookup = self.process_map.get_mut(&pid);
+                let p = process_lookup.expect("TODO: SetCredentials process lookup failed");
+                // Only root can become someone else
+                if !p.credentials().is_root() {
+                    return Err(KError::NotPermitted);
+                }
+                p.set_credentials(credentials);
+                Ok(NodeResult::CredentialsSet)
+            }
+            Op::CoreFailed(gtid) => {
+                if !self.failed_cores.contains(&gtid) {
+                    self.failed_cores.push(gtid);
                 }
+                let mut pids: Vec<Pid> = self
+                    .scheduler_map
+                    .remove(&gtid)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|e| e.pid())
+                    .collect();
+                pids.sort_unstable();
+                pids.dedup();
+                Ok(NodeResult::CoreFailed(pids))
             }
             Op::ProcAllocateCore(pid, Some(gtid), Some(region), entry_point) => {
-                match self.scheduler_map.get(&gtid) {
+                if self.failed_cores.contains(&gtid) {
+                    return Err(KError::CoreFailed);
+                }
+                // A core can be time-shared between processes, but a
+                // process gets at most one executor per core:
+                let executors = self.scheduler_map.entry(gtid).or_insert_with(Vec::new);
+                match executors.iter().find(|e| e.pid() == pid) {
                     Some(executor) => {
                         error!("Core {} already used by {}", gtid, executor.id());
                         Err(KError::CoreAlreadyAllocated)
@@ -776,8 +2719,10 @@ where
                         let eid = executor.id();
                         unsafe {
                             (*executor.vcpu_kernel()).resume_with_upcall = entry_point;
+                            // Until the core is time-shared:
+                            (*executor.vcpu_kernel()).quantum_end = u64::max_value();
                         }
-                        self.scheduler_map.insert(gtid, executor.into());
+                        executors.push(executor.into());
                         Ok(NodeResult::CoreAllocated(gtid, eid))
                     }
                 }
@@ -792,6 +2737,27 @@ where
 
                 Ok(NodeResult::FrameId(fid))
             }
+            Op::ReleaseFrameFromProcess(pid, frame_id, size) => {
+                let process = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(ProcessError::NoProcessFoundForPid)?;
+                if process.get_frame(frame_id)?.size != size {
+                    return Err(ProcessError::InvalidFrameId.into());
+                }
+                let frame = process.remove_frame(frame_id)?;
+
+                // A forked process has the same frames registered
+                let in_use = self
+                    .process_map
+                    .values()
+                    .any(|p| p.owned_frames().iter().any(|f| f.base == frame.base));
+                if in_use {
+                    Ok(NodeResult::FrameReleased(None))
+                } else {
+                    Ok(NodeResult::FrameReleased(Some(frame)))
+                }
+            }
             Op::Invalid => unreachable!("Got invalid OP"),
         }
     }
@@ -0,0 +1,261 @@
+//! Per-core tracing of operations dispatched by the NR and MLNR replicas.
+//!
+//! When the kernel is compiled with the `nrtrace` feature, every call to
+//! `dispatch` / `dispatch_mut` of `nr::KernelNode` and `mlnr::MlnrKernelNode`
+//! records a [`TraceRecord`] in a core-local ring buffer (stored in the KCB).
+//! Without the feature a [`Span`] is a no-op and compiles away.
+//!
+//! The buffer can be dumped to the serial line with [`dump`] (this happens
+//! as part of the `SystemOperation::Stats` system call). The dump consists
+//! of lines with the following form:
+//!
+//! `NRTRACE <core> <hex-encoded records>`
+//!
+//! The timestamps are rdtsc values of the core that recorded them. Before its
+//! records, a line `NRTRACE-TSC <core> <offset> <error>` tells how many
+//! cycles the TSC of the core is ahead of the one of the BSP (give or take
+//! `error` cycles, see `arch::tscsync`), so the traces of all cores can be
+//! merged into one timeline.
+//!
+//! `kernel/nrtrace.py` decodes a dump, it gets the operation names from the
+//! variants of the enums in the source (so the order of the variants is all
+//! it relies on).
+//!
+//! Every record is [`TraceRecord::SIZE`] bytes, encoded in little-endian:
+//!
+//! | bytes  | field  | description                                        |
+//! |--------|--------|----------------------------------------------------|
+//! | 0..8   | start  | rdtsc when the operation was dispatched            |
+//! | 8..16  | cycles | rdtsc cycles spent in dispatch                     |
+//! | 16..20 | core   | hardware thread that applied the operation         |
+//! | 20     | class  | [`OpClass`] of the operation                       |
+//! | 21     | -      | padding                                            |
+//! | 22..24 | op     | index of the enum variant within its `OpClass`     |
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::kcb::ArchSpecificKcb;
+
+/// How many records we keep per core before we start overwriting the
+/// oldest ones.
+pub const TRACE_CAPACITY: usize = 4096;
+
+/// The type of operation that was recorded.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum OpClass {
+    /// A `nr::ReadOps` operation.
+    NrRead = 0,
+    /// A `nr::Op` operation.
+    NrWrite = 1,
+    /// A `mlnr::Access` operation.
+    MlnrRead = 2,
+    /// A `mlnr::Modify` operation.
+    MlnrWrite = 3,
+}
+
+/// A single entry in the trace buffer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    pub start: u64,
+    pub cycles: u64,
+    pub core: u32,
+    pub class: OpClass,
+    pub op: u16,
+}
+
+impl TraceRecord {
+    /// Size of an encoded record in bytes.
+    pub const SIZE: usize = 24;
+
+    /// Encode the record in the (little-endian) binary trace format.
+    pub fn encode(&self) -> [u8; TraceRecord::SIZE] {
+        let mut buf = [0u8; TraceRecord::SIZE];
+        buf[0..8].copy_from_slice(&self.start.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.cycles.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.core.to_le_bytes());
+        buf[20] = self.class as u8;
+        buf[22..24].copy_from_slice(&self.op.to_le_bytes());
+        buf
+    }
+}
+
+/// A fixed-size ring buffer of trace records.
+pub struct TraceBuffer {
+    records: Vec<TraceRecord>,
+    /// Next slot to write to (once `records` is full).
+    head: usize,
+}
+
+impl TraceBuffer {
+    pub fn new() -> TraceBuffer {
+        TraceBuffer {
+            records: Vec::with_capacity(TRACE_CAPACITY),
+            head: 0,
+        }
+    }
+
+    /// Add a record, overwrites the oldest record if the buffer is full.
+    pub fn push(&mut self, record: TraceRecord) {
+        if self.records.len() < TRACE_CAPACITY {
+            self.records.push(record);
+        } else {
+            self.records[self.head] = record;
+            self.head = (self.head + 1) % TRACE_CAPACITY;
+        }
+    }
+
+    /// Number of records currently stored.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Iterate over the records, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &TraceRecord> {
+        let (newer, older) = self.records.split_at(self.head);
+        older.iter().chain(newer.iter())
+    }
+
+    /// Remove all records.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.head = 0;
+    }
+}
+
+/// Measures the time spent dispatching a single operation.
+///
+/// The record is written to the core-local trace buffer when the span
+/// is dropped (i.e., at the end of `dispatch`).
+pub struct Span {
+    #[cfg(feature = "nrtrace")]
+    class: OpClass,
+    #[cfg(feature = "nrtrace")]
+    op: u16,
+    #[cfg(feature = "nrtrace")]
+    start: u64,
+}
+
+impl Span {
+    /// Start tracing an operation of `class` (`op` is the index of
+    /// the enum variant, see `core::intrinsics::discriminant_value`).
+    #[inline(always)]
+    #[allow(unused_variables)]
+    pub fn new(class: OpClass, op: u16) -> Span {
+        #[cfg(target_family = "unix")]
+        crate::arch::replay::event(crate::arch::replay::Event::Dispatch { class, op });
+
+        Span {
+            #[cfg(feature = "nrtrace")]
+            class,
+            #[cfg(feature = "nrtrace")]
+            op,
+            #[cfg(feature = "nrtrace")]
+            start: unsafe { x86::time::rdtsc() },
+        }
+    }
+}
+
+#[cfg(feature = "nrtrace")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        let end = unsafe { x86::time::rdtsc() };
+        if let Some(kcb) = crate::kcb::try_get_kcb() {
+            let record = TraceRecord {
+                start: self.start,
+                cycles: end - self.start,
+                core: kcb.arch.hwthread_id() as u32,
+                class: self.class,
+                op: self.op,
+            };
+            kcb.nr_trace.get_or_insert_with(TraceBuffer::new).push(record);
+        }
+
+        #[cfg(target_os = "none")]
+        crate::arch::eventring::traced(self.class as u8, self.op, end - self.start);
+    }
+}
+
+/// Writes the trace buffer of the current core to the serial line
+/// and resets it.
+pub fn dump() {
+    if !cfg!(feature = "nrtrace") {
+        return;
+    }
+
+    if let Some(kcb) = crate::kcb::try_get_kcb() {
+        let core = kcb.arch.hwthread_id();
+        if let Some(buffer) = kcb.nr_trace.as_mut() {
+            #[cfg(target_os = "none")]
+            if let Some((offset, error)) = crate::arch::tscsync::offset(core as _) {
+                sprintln!("NRTRACE-TSC {} {} {}", core, offset, error);
+            }
+
+            // Print a couple of records per line to keep the lines short:
+            const RECORDS_PER_LINE: usize = 8;
+            let mut line = String::with_capacity(RECORDS_PER_LINE * TraceRecord::SIZE * 2);
+            for (idx, record) in buffer.iter().enumerate() {
+                for byte in record.encode().iter() {
+                    let _r = write!(line, "{:02x}", byte);
+                }
+                if (idx + 1) % RECORDS_PER_LINE == 0 {
+                    sprintln!("NRTRACE {} {}", core, line);
+                    line.clear();
+                }
+            }
+            if !line.is_empty() {
+                sprintln!("NRTRACE {} {}", core, line);
+            }
+            buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(start: u64) -> TraceRecord {
+        TraceRecord {
+            start,
+            cycles: 10,
+            core: 1,
+            class: OpClass::MlnrWrite,
+            op: 3,
+        }
+    }
+
+    #[test]
+    fn encode_record() {
+        let r = TraceRecord {
+            start: 0x0102,
+            cycles: 0x03,
+            core: 0x04,
+            class: OpClass::NrWrite,
+            op: 0x0506,
+        };
+        let buf = r.encode();
+        assert_eq!(&buf[0..8], &[0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[8..16], &[0x03, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[16..20], &[0x04, 0, 0, 0]);
+        assert_eq!(buf[20], 1);
+        assert_eq!(&buf[22..24], &[0x06, 0x05]);
+    }
+
+    #[test]
+    fn ring_overwrites_oldest() {
+        let mut tb = TraceBuffer::new();
+        for i in 0..(TRACE_CAPACITY + 2) {
+            tb.push(record(i as u64));
+        }
+        assert_eq!(tb.len(), TRACE_CAPACITY);
+
+        let starts: Vec<u64> = tb.iter().map(|r| r.start).collect();
+        assert_eq!(starts[0], 2);
+        assert_eq!(starts[TRACE_CAPACITY - 1], TRACE_CAPACITY as u64 + 1);
+        assert!(starts.windows(2).all(|w| w[0] < w[1]));
+    }
+}
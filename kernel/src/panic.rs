@@ -180,6 +180,10 @@ pub fn backtrace_no_context() {
 #[cfg_attr(target_os = "none", panic_handler)]
 #[no_mangle]
 pub fn panic_impl(info: &PanicInfo) -> ! {
+    // Print pending user-space output first, the panicking core may hold
+    // the serial line so we can't wait for it.
+    unsafe { crate::arch::printq::flush_unlocked() };
+
     sprint!(
         "System panic encountered (On H/W thread {})",
         topology::MACHINE_TOPOLOGY.current_thread().id
@@ -208,6 +212,9 @@ pub fn panic_impl(info: &PanicInfo) -> ! {
         }
     });
 
+    // Keep the rest of the system running if we can (`panic=isolate`)
+    arch::isolate_core();
+
     arch::debug::shutdown(ExitReason::KernelPanic);
 }
 
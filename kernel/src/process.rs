@@ -1,14 +1,19 @@
 //! Generic process traits
 use alloc::boxed::Box;
-use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use crossbeam_utils::CachePadded;
 use cstr_core::CStr;
 use custom_error::custom_error;
-use kpi::process::FrameId;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use kpi::process::{FrameId, SchedulingPolicy};
+use kpi::SystemCallError;
+use spin::RwLock;
 
 use crate::arch::memory::paddr_to_kernel_vaddr;
 use crate::arch::memory::LARGE_PAGE_SIZE;
@@ -17,7 +22,9 @@ use crate::arch::Module;
 use crate::error::KError;
 use crate::fs::Fd;
 use crate::kcb;
-use crate::memory::vspace::AddressSpace;
+use crate::memory::vspace::{
+    AddressSpace, AddressSpaceError, MapAction, MappingType, TlbFlushHandle,
+};
 use crate::memory::KernelAllocator;
 use crate::memory::{Frame, PhysicalPageProvider, VAddr};
 use crate::prelude::overlaps;
@@ -39,7 +46,9 @@ impl KernSlice {
         let slice_ptr = UserPtr::new(&mut user_ptr);
         let user_slice: &mut [u8] =
             unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        unsafe { Arc::get_mut_unchecked(&mut buffer).copy_from_slice(&user_slice[0..len]) };
+        Arc::get_mut(&mut buffer)
+            .expect("buffer was just allocated")
+            .copy_from_slice(&user_slice[0..len]);
         KernSlice { buffer }
     }
 }
@@ -63,9 +72,83 @@ pub fn userptr_to_str(useraddr: u64) -> Result<String, KError> {
 /// Process ID.
 pub type Pid = u64;
 
+/// Pid of the first process we create (the one started at boot).
+pub const INIT_PID: Pid = 1;
+
+/// How many processes we keep CPU usage statistics for.
+pub(crate) const MAX_ACCOUNTED_PROCESSES: usize = 256;
+
+/// CPU usage of a single process.
+#[derive(Default)]
+struct CpuUsage {
+    cycles: AtomicU64,
+    dispatches: AtomicU64,
+}
+
+lazy_static! {
+    /// CPU usage of every process that ran in user-space so far.
+    static ref CPU_USAGE: RwLock<HashMap<Pid, CachePadded<CpuUsage>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Charge `cycles` spent running in user-space to process `pid`.
+pub fn account_cpu_time(pid: Pid, cycles: u64) {
+    {
+        let usage = CPU_USAGE.read();
+        if let Some(usage) = usage.get(&pid) {
+            usage.cycles.fetch_add(cycles, Ordering::Relaxed);
+            usage.dispatches.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    // First time `pid` ran
+    let mut usage = CPU_USAGE.write();
+    let usage = usage.entry(pid).or_insert_with(Default::default);
+    usage.cycles.fetch_add(cycles, Ordering::Relaxed);
+    usage.dispatches.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the CPU usage of process `pid` so far.
+pub fn cpu_usage(pid: Pid) -> kpi::process::ProcessUsage {
+    CPU_USAGE
+        .read()
+        .get(&pid)
+        .map_or(Default::default(), |usage| kpi::process::ProcessUsage {
+            cpu_cycles: usage.cycles.load(Ordering::Relaxed),
+            dispatches: usage.dispatches.load(Ordering::Relaxed),
+        })
+}
+
+/// Drops the CPU usage of process `pid` (once it exited).
+pub fn forget_cpu_usage(pid: Pid) {
+    CPU_USAGE.write().remove(&pid);
+}
+
 /// Executor ID.
 pub type Eid = u64;
 
+/// User and group a process runs as, files are checked against them.
+///
+/// Processes start as root (uid and gid 0). Root can change the credentials
+/// of a process (`ProcessOperation::SetCredentials`), but file modes apply to
+/// it like to any other user.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// Credentials of the superuser.
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+
+    /// Is this the superuser?
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
 custom_error! {
 #[derive(PartialEq, Clone)]
 pub ProcessError
@@ -81,6 +164,33 @@ pub ProcessError
     ExecutorAlreadyBorrowed = "The executor on the core was already borrowed (that's a bug).",
     NotEnoughMemory = "Unable to reserve memory for internal process data-structures.",
     InvalidFrameId = "The provided FrameId is not registered with the process",
+    FrameStillMapped = "The frame is still mapped in the address-space of the process",
+    BinaryNotFound{binary: String} = "Couldn't find the binary '{binary}' in the boot modules.",
+    NotAChild = "The process isn't a child of the caller (or its exit code was collected already).",
+    NoSuchGroup = "There is no process in the given process group.",
+}
+
+impl Into<SystemCallError> for ProcessError {
+    fn into(self) -> SystemCallError {
+        match self {
+            ProcessError::ProcessCreate { .. } => SystemCallError::InternalError,
+            ProcessError::ProcessNotSet => SystemCallError::InternalError,
+            ProcessError::NoProcessFoundForPid => SystemCallError::NotSupported,
+            ProcessError::UnableToLoad => SystemCallError::NotSupported,
+            ProcessError::UnableToParseElf => SystemCallError::NotSupported,
+            ProcessError::NoExecutorAllocated => SystemCallError::OutOfMemory,
+            ProcessError::ExecutorCacheExhausted => SystemCallError::OutOfMemory,
+            ProcessError::InvalidGlobalThreadId => SystemCallError::NotSupported,
+            ProcessError::ExecutorNoLongerValid => SystemCallError::InternalError,
+            ProcessError::ExecutorAlreadyBorrowed => SystemCallError::InternalError,
+            ProcessError::NotEnoughMemory => SystemCallError::OutOfMemory,
+            ProcessError::InvalidFrameId => SystemCallError::NotSupported,
+            ProcessError::FrameStillMapped => SystemCallError::StillMapped,
+            ProcessError::NotAChild => SystemCallError::NotSupported,
+            ProcessError::NoSuchGroup => SystemCallError::NotSupported,
+            ProcessError::BinaryNotFound { .. } => SystemCallError::NotSupported,
+        }
+    }
 }
 
 impl From<&str> for ProcessError {
@@ -100,7 +210,12 @@ pub trait Process {
     type E: Executor + Copy + Sync + Send;
     type A: AddressSpace;
 
-    fn new(module: &Module, pid: Pid, writable_sections: Vec<Frame>) -> Result<Self, ProcessError>
+    fn new(
+        module: &Module,
+        pid: Pid,
+        writable_sections: Vec<Frame>,
+        policy: SchedulingPolicy,
+    ) -> Result<Self, ProcessError>
     where
         Self: core::marker::Sized;
 
@@ -121,12 +236,103 @@ pub trait Process {
 
     fn deallocate_fd(&mut self, fd: usize) -> usize;
 
-    fn get_fd(&self, index: usize) -> &Fd;
+    /// The open file descriptor `index` (None if there is none).
+    fn get_fd(&self, index: usize) -> Option<&Fd>;
+
+    /// All file descriptors the process has open.
+    fn open_fds(&self) -> Vec<&Fd>;
+
+    /// The file descriptor table of the process (None for unused FDs).
+    fn fds(&self) -> Vec<Option<Fd>>;
+
+    /// Replaces the file descriptor table of the process (see `fds`).
+    fn set_fds(&mut self, fds: Vec<Option<Fd>>);
 
     fn pinfo(&self) -> &kpi::process::ProcessInfo;
 
+    fn credentials(&self) -> Credentials;
+
+    fn set_credentials(&mut self, credentials: Credentials);
+
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, ProcessError>;
     fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, ProcessError>;
+    /// Unregisters the frame `frame_id` from the process (the FrameIds of
+    /// the other frames stay the same), fails if it is still mapped.
+    fn remove_frame(&mut self, frame_id: FrameId) -> Result<Frame, ProcessError>;
+
+    /// Physical memory that holds the executors (stacks and vCPU areas).
+    fn executor_frames(&self) -> Vec<Frame>;
+
+    /// Duplicates the process as `pid`.
+    ///
+    /// Writable memory is shared copy-on-write between the two processes,
+    /// `executor_frames` are copies of `executor_frames()` for the child.
+    /// Returns the child and its copy of the executor `eid`.
+    fn fork(
+        &mut self,
+        pid: Pid,
+        executor_frames: Vec<Frame>,
+        eid: Eid,
+    ) -> Result<(Self, Box<Self::E>), KError>
+    where
+        Self: core::marker::Sized;
+
+    /// The base and frame of the copy-on-write mapping `vaddr` is in.
+    fn cow_mapping(&self, vaddr: VAddr) -> Option<(VAddr, Frame)>;
+
+    /// Whether `[base, base+len)` might overlap a copy-on-write mapping.
+    fn has_cow_mapping(&self, base: VAddr, len: usize) -> bool;
+
+    /// Makes the copy-on-write mapping at `base` writable again, either by
+    /// replacing it with `copy` or by keeping the frame if `copy` is None.
+    fn resolve_cow(
+        &mut self,
+        base: VAddr,
+        copy: Option<Frame>,
+    ) -> Result<TlbFlushHandle, AddressSpaceError>;
+
+    /// Changes the rights of the mappings in `[base, base+len)` to `rights`,
+    /// the range has to start and end at the boundaries of mappings.
+    ///
+    /// Copy-on-write mappings stay that way: they only become writable once
+    /// they're resolved (see `resolve_cow`).
+    fn protect(
+        &mut self,
+        base: VAddr,
+        len: usize,
+        rights: MapAction,
+    ) -> Result<(), AddressSpaceError>;
+
+    /// Tags the mappings in `[base, base+len)` with memory protection `key`
+    /// (same range restrictions as `protect`).
+    fn set_key(&mut self, base: VAddr, len: usize, key: u16) -> Result<(), AddressSpaceError>;
+
+    /// Replaces the image of the process with `module` (`writeable_sections`
+    /// hold its data sections, see `load_binary`).
+    ///
+    /// Keeps the pid, file descriptors and credentials, the executors have
+    /// to be allocated again. Returns the old image, its memory still has to
+    /// be given back.
+    fn exec(&mut self, module: &Module, writeable_sections: Vec<Frame>) -> Result<Self, KError>;
+
+    /// Maps `frame` at `base` like `AddressSpace::map_frame`, but the frame
+    /// doesn't belong to the process (`typ` says whose it is).
+    fn map_foreign(
+        &mut self,
+        base: VAddr,
+        frame: Frame,
+        action: MapAction,
+        typ: MappingType,
+    ) -> Result<(), AddressSpaceError>;
+
+    /// Physical memory that belongs to the process: every frame that is
+    /// mapped (see `MappingType::is_owned`) or registered with `add_frame`.
+    fn owned_frames(&self) -> Vec<Frame>;
+
+    /// The frames of `owned_frames` this replica allocated for its own copy
+    /// of the process (the read-only sections of the binary), the others are
+    /// shared by all replicas.
+    fn replica_frames(&self) -> &[Frame];
 }
 
 /// ResumeHandle is the HW specific logic that switches the CPU
@@ -338,7 +544,46 @@ impl elfloader::ElfLoader for DataSecAllocator {
 ///
 /// Parse & relocate ELF
 /// Create an initial VSpace
-pub fn make_process(binary: &'static str) -> Result<Pid, KError> {
+///
+/// The process is a child of `parent` (if any).
+pub fn make_process(
+    binary: &'static str,
+    args: &str,
+    parent: Option<Pid>,
+    policy: SchedulingPolicy,
+) -> Result<Pid, KError> {
+    let (mod_file, data_frames) = load_binary(binary)?;
+
+    // Create a new process
+    let kcb = kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute_mut(
+                nr::Op::ProcCreate(mod_file, data_frames, policy, Arc::from(args), parent),
+                *token,
+            );
+            match response {
+                Ok(nr::NodeResult::ProcCreated(pid)) => {
+                    if cfg!(feature = "mlnrfs") {
+                        match mlnr::MlnrKernelNode::add_process(pid) {
+                            Ok(pid) => Ok(pid.0),
+                            Err(e) => unreachable!("{}", e),
+                        }
+                    } else {
+                        Ok(pid)
+                    }
+                }
+                _ => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+/// Finds the boot module `binary` and loads the writeable sections of its
+/// ELF file (these are not replicated by NR).
+///
+/// Returns the module and the frames that hold the sections.
+pub fn load_binary(binary: &'static str) -> Result<(&'static Module, Vec<Frame>), KError> {
     KernelAllocator::try_refill_tcache(7, 1)?;
     let kcb = kcb::get_kcb();
 
@@ -350,7 +595,9 @@ pub fn make_process(binary: &'static str) -> Result<Pid, KError> {
         }
     }
 
-    let mod_file = mod_file.expect(format!("Couldn't find '{}' binary.", binary).as_str());
+    let mod_file = mod_file.ok_or_else(|| ProcessError::BinaryNotFound {
+        binary: binary.to_string(),
+    })?;
     info!(
         "binary={} cmdline={} module={:?}",
         binary, kcb.cmdline.test_cmdline, mod_file
@@ -375,27 +622,7 @@ pub fn make_process(binary: &'static str) -> Result<Pid, KError> {
     elf_module
         .load(&mut data_sec_loader)
         .map_err(|_e| ProcessError::UnableToLoad)?;
-    let data_frames: Vec<Frame> = data_sec_loader.finish();
-
-    // Create a new process
-    kcb.replica
-        .as_ref()
-        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-            let response = replica.execute_mut(nr::Op::ProcCreate(&mod_file, data_frames), *token);
-            match response {
-                Ok(nr::NodeResult::ProcCreated(pid)) => {
-                    if cfg!(feature = "mlnrfs") {
-                        match mlnr::MlnrKernelNode::add_process(pid) {
-                            Ok(pid) => Ok(pid.0),
-                            Err(e) => unreachable!("{}", e),
-                        }
-                    } else {
-                        Ok(pid)
-                    }
-                }
-                _ => unreachable!("Got unexpected response"),
-            }
-        })
+    Ok((mod_file, data_sec_loader.finish()))
 }
 
 /// Create dispatchers for a given Pid to run on all cores.
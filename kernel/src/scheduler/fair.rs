@@ -0,0 +1,274 @@
+//! Fair time-slicing of a single core between multiple executors.
+//!
+//! Every executor that is assigned to a core keeps track of its (weighted)
+//! virtual runtime. When the quantum of the running executor expires, we
+//! switch to the executor with the smallest virtual runtime. Executors of a
+//! process with a higher weight accumulate virtual runtime more slowly and
+//! therefore get a proportionally larger share of the core.
+//!
+//! Processes that use gang scheduling (`SchedulingPolicy::Gang`) can ask a
+//! core to dispatch one of their executors immediately (see `request`). This
+//! way all cores of the process start (and therefore also end) their time
+//! slice for it at roughly the same time.
+
+use alloc::vec::Vec;
+
+use crate::process::{Eid, Pid};
+
+pub use kpi::process::{DEFAULT_WEIGHT, MAX_WEIGHT};
+
+/// Length of a time slice (in rdtsc cycles).
+pub const QUANTUM: u64 = 20_000_000;
+
+/// Identifies an executor (executor ids are only unique within a process).
+pub type ExecutorKey = (Pid, Eid);
+
+struct Entity {
+    key: ExecutorKey,
+    weight: u64,
+    vruntime: u64,
+    /// Did this executor run on the core before (and needs to be resumed
+    /// instead of started)?
+    started: bool,
+}
+
+/// Per-core run-queue with fair (weighted) time-slicing.
+pub struct FairScheduler {
+    entities: Vec<Entity>,
+    /// The executor that is currently dispatched on the core.
+    current: Option<ExecutorKey>,
+    /// rdtsc when `current` was dispatched.
+    slice_start: u64,
+    /// Up to when we charged `current` for its time on the core.
+    charged_until: u64,
+    /// A gang-scheduled process that wants to run on this core next.
+    requested: Option<Pid>,
+}
+
+impl Default for FairScheduler {
+    fn default() -> FairScheduler {
+        FairScheduler::new()
+    }
+}
+
+impl FairScheduler {
+    pub const fn new() -> FairScheduler {
+        FairScheduler {
+            entities: Vec::new(),
+            current: None,
+            slice_start: 0,
+            charged_until: 0,
+            requested: None,
+        }
+    }
+
+    /// Number of executors that share the core.
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    fn min_vruntime(&self) -> u64 {
+        self.entities.iter().map(|e| e.vruntime).min().unwrap_or(0)
+    }
+
+    /// Update the run-queue to contain exactly the executors in `runnable`.
+    ///
+    /// Newly added executors start with the smallest virtual runtime of the
+    /// queue (so they don't monopolize the core to "catch up").
+    pub fn sync(&mut self, runnable: &[ExecutorKey]) {
+        self.entities.retain(|e| runnable.contains(&e.key));
+        let min_vruntime = self.min_vruntime();
+        for key in runnable {
+            if !self.entities.iter().any(|e| e.key == *key) {
+                self.entities.push(Entity {
+                    key: *key,
+                    weight: DEFAULT_WEIGHT,
+                    vruntime: min_vruntime,
+                    started: false,
+                });
+            }
+        }
+    }
+
+    /// Set the weight for all executors of process `pid`.
+    pub fn set_weight(&mut self, pid: Pid, weight: u64) {
+        let weight = core::cmp::min(MAX_WEIGHT, core::cmp::max(1, weight));
+        for e in self.entities.iter_mut().filter(|e| e.key.0 == pid) {
+            e.weight = weight;
+        }
+    }
+
+    /// The length of the time slice for `key` (scaled by its weight).
+    pub fn quantum(&self, key: ExecutorKey) -> u64 {
+        self.entities
+            .iter()
+            .find(|e| e.key == key)
+            .map_or(QUANTUM, |e| QUANTUM * e.weight / DEFAULT_WEIGHT)
+    }
+
+    /// The executor that is currently dispatched.
+    pub fn current(&self) -> Option<ExecutorKey> {
+        self.current
+    }
+
+    /// Charge the time since the last call to the current executor.
+    pub fn charge(&mut self, now: u64) {
+        let ran = now.saturating_sub(self.charged_until);
+        self.charged_until = now;
+        if let Some(current) = self.current {
+            if let Some(e) = self.entities.iter_mut().find(|e| e.key == current) {
+                e.vruntime += ran * DEFAULT_WEIGHT / e.weight;
+            }
+        }
+    }
+
+    /// Ask for an executor of `pid` to be dispatched at the next opportunity
+    /// (preempting the current executor).
+    pub fn request(&mut self, pid: Pid) {
+        self.requested = Some(pid);
+    }
+
+    /// Is there a pending request from a gang-scheduled process?
+    pub fn has_request(&self) -> bool {
+        self.requested.is_some()
+    }
+
+    /// Has the current executor used up its time slice at `now`?
+    pub fn expired(&self, now: u64) -> bool {
+        if self.requested.is_some() {
+            return true;
+        }
+        self.current.map_or(true, |current| {
+            now.saturating_sub(self.slice_start) >= self.quantum(current)
+        })
+    }
+
+    /// The executor that should run next.
+    pub fn pick_next(&self) -> Option<ExecutorKey> {
+        let requested = self
+            .requested
+            .and_then(|pid| self.entities.iter().find(|e| e.key.0 == pid));
+        if let Some(e) = requested {
+            return Some(e.key);
+        }
+
+        self.entities
+            .iter()
+            .min_by_key(|e| e.vruntime)
+            .map(|e| e.key)
+    }
+
+    /// Record that `key` got dispatched at `now`.
+    ///
+    /// Returns true if the executor ran before on this core.
+    pub fn dispatch(&mut self, key: ExecutorKey, now: u64) -> bool {
+        self.requested = None;
+        self.current = Some(key);
+        self.slice_start = now;
+        self.charged_until = now;
+        self.entities
+            .iter_mut()
+            .find(|e| e.key == key)
+            .map_or(false, |e| core::mem::replace(&mut e.started, true))
+    }
+
+    /// rdtsc value at which the time slice of the current executor ends.
+    pub fn slice_end(&self) -> u64 {
+        self.current
+            .map_or(u64::MAX, |key| self.slice_start + self.quantum(key))
+    }
+
+    /// Restart the time slice of the current executor at `now` (e.g., after
+    /// the timestamp counter got reset by a suspend/resume cycle).
+    pub fn reset_clock(&mut self, now: u64) {
+        self.slice_start = now;
+        self.charged_until = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_robin_with_equal_weights() {
+        let mut fs = FairScheduler::new();
+        fs.sync(&[(1, 0), (2, 0)]);
+        assert_eq!(fs.len(), 2);
+
+        let mut now = 0;
+        let mut runs = [0; 3];
+        for _i in 0..10 {
+            let next = fs.pick_next().unwrap();
+            runs[next.0 as usize] += 1;
+            fs.dispatch(next, now);
+            now += QUANTUM;
+            assert!(fs.expired(now));
+            fs.charge(now);
+        }
+        assert_eq!(runs[1], 5);
+        assert_eq!(runs[2], 5);
+    }
+
+    #[test]
+    fn weights_give_proportional_share() {
+        let mut fs = FairScheduler::new();
+        fs.sync(&[(1, 0), (2, 0)]);
+        fs.set_weight(2, 4 * DEFAULT_WEIGHT);
+        assert_eq!(fs.quantum((2, 0)), 4 * QUANTUM);
+
+        let mut now = 0;
+        let mut cycles = [0; 3];
+        for _i in 0..40 {
+            let next = fs.pick_next().unwrap();
+            fs.dispatch(next, now);
+            // Everyone runs for a fixed time (e.g., timer granularity)
+            now += QUANTUM;
+            cycles[next.0 as usize] += QUANTUM;
+            fs.charge(now);
+        }
+        assert_eq!(cycles[2], 4 * cycles[1]);
+    }
+
+    #[test]
+    fn dispatch_tracks_started() {
+        let mut fs = FairScheduler::new();
+        fs.sync(&[(1, 0)]);
+        assert!(!fs.dispatch((1, 0), 10));
+        assert!(fs.dispatch((1, 0), 20));
+        assert_eq!(fs.slice_end(), 20 + QUANTUM);
+
+        // New executors don't get an advantage from their 0 vruntime:
+        fs.charge(20 + 5 * QUANTUM);
+        fs.sync(&[(1, 0), (3, 1)]);
+        fs.dispatch((3, 1), 20 + 5 * QUANTUM);
+        fs.charge(20 + 6 * QUANTUM);
+        assert_eq!(fs.pick_next(), Some((1, 0)));
+
+        fs.sync(&[(3, 1)]);
+        assert_eq!(fs.len(), 1);
+        assert_eq!(fs.pick_next(), Some((3, 1)));
+    }
+
+    #[test]
+    fn gang_request_preempts() {
+        let mut fs = FairScheduler::new();
+        fs.sync(&[(1, 0), (2, 3)]);
+        fs.dispatch((1, 0), 0);
+        fs.charge(QUANTUM / 2);
+        assert!(!fs.expired(QUANTUM / 2));
+
+        // (2, 3) had no CPU time yet but we're in the middle of a slice:
+        fs.request(2);
+        assert!(fs.has_request());
+        assert!(fs.expired(QUANTUM / 2));
+        assert_eq!(fs.pick_next(), Some((2, 3)));
+        fs.dispatch((2, 3), QUANTUM / 2);
+        assert!(!fs.has_request());
+
+        // Requests for processes that don't run here are ignored:
+        fs.charge(2 * QUANTUM);
+        fs.request(7);
+        assert_eq!(fs.pick_next(), Some((1, 0)));
+    }
+}
@@ -1,5 +1,7 @@
 //! Scheduling logic
 
+pub mod fair;
+
 use alloc::sync::Weak;
 use core::intrinsics::unlikely;
 
@@ -46,12 +48,11 @@ pub fn schedule() -> ! {
                             .arch
                             .swap_current_process(Weak::upgrade(&e).unwrap());
                         assert!(no.is_none(), "Handle the case where we replace a process.");
-                        if is_replica_main_thread {
-                            // Make sure we periodically try and advance the replica on main-thread
-                            // even if we're running something (e.g., if everything polls in
-                            // user-space we can livelock)
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
-                        }
+                        // Make sure we periodically try and advance the replica on main-thread
+                        // even if we're running something (e.g., if everything polls in
+                        // user-space we can livelock). On all cores the timer is also
+                        // used to time-slice between executors that share the core.
+                        timer::set(timer::DEFAULT_TIMER_DEADLINE);
                         break;
                     }
                     Err(KError::NoExecutorForCore) => {
@@ -87,6 +88,9 @@ pub fn schedule() -> ! {
     // If we come here, we have a new process, dispatch it:
     unsafe {
         let rh = kcb::get_kcb().arch.current_process().map(|p| p.start());
+        // The address space of the process is active now, execute what it
+        // submitted asynchronously in the meantime:
+        crate::arch::poll_async_ring();
         rh.unwrap().resume()
     }
 }
@@ -0,0 +1,209 @@
+//! Simple statistics the kernel collects about itself.
+//!
+//! Everything in here is meant to be updated on hot paths (i.e., in interrupt
+//! handlers), so we avoid memory allocations when recording values.
+
+use alloc::vec::Vec;
+
+/// Number of buckets in a [`Histogram`].
+pub const HISTOGRAM_BUCKETS: usize = 64;
+
+/// A histogram with power-of-two buckets.
+///
+/// Bucket `i` counts values `v` with `2^(i-1) <= v < 2^i` (bucket 0 counts
+/// the value 0).
+#[derive(Copy, Clone)]
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        Histogram {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn bucket(value: u64) -> usize {
+        let idx = (64 - value.leading_zeros()) as usize;
+        core::cmp::min(idx, HISTOGRAM_BUCKETS - 1)
+    }
+
+    /// Add a new value to the histogram.
+    pub fn record(&mut self, value: u64) {
+        self.buckets[Histogram::bucket(value)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.min = core::cmp::min(self.min, value);
+        self.max = core::cmp::max(self.max, value);
+    }
+
+    /// How many values were recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Smallest recorded value (0 if the histogram is empty).
+    pub fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    /// Largest recorded value.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Average of all recorded values (0 if the histogram is empty).
+    #[cfg(test)]
+    pub fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.sum / self.count
+        }
+    }
+
+    /// Returns an upper bound for the value at the given percentile
+    /// (in per-mille, i.e., `990` is the 99th percentile).
+    ///
+    /// Since we only track power-of-two buckets the result is the upper
+    /// end of the bucket the percentile falls in (but never above `max`).
+    pub fn percentile(&self, per_mille: u64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let per_mille = core::cmp::min(per_mille, 1000);
+        // Rank of the value we're looking for (1-based, rounded up):
+        let rank = core::cmp::max(1, (self.count * per_mille + 999) / 1000);
+
+        let mut seen = 0;
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                let upper = if idx == 0 { 0 } else { (1u64 << idx) - 1 };
+                return core::cmp::max(core::cmp::min(upper, self.max), self.min());
+            }
+        }
+
+        self.max
+    }
+
+    /// Remove all recorded values.
+    #[cfg(test)]
+    pub fn reset(&mut self) {
+        *self = Histogram::new();
+    }
+}
+
+impl From<&Histogram> for kpi::system::Histogram {
+    fn from(h: &Histogram) -> kpi::system::Histogram {
+        // Don't ship the trailing empty buckets to user-space:
+        let used = h
+            .buckets
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |idx| idx + 1);
+
+        kpi::system::Histogram {
+            count: h.count(),
+            sum: h.sum,
+            min: h.min(),
+            max: h.max(),
+            p50: h.percentile(500),
+            p90: h.percentile(900),
+            p99: h.percentile(990),
+            p999: h.percentile(999),
+            buckets: h.buckets[..used].iter().cloned().collect::<Vec<u64>>(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut h = Histogram::new();
+        assert_eq!(h.min(), 0);
+        assert_eq!(h.mean(), 0);
+
+        h.record(0);
+        h.record(1);
+        h.record(2);
+        h.record(3);
+        h.record(1024);
+        h.record(u64::MAX);
+
+        assert_eq!(h.buckets[0], 1);
+        assert_eq!(h.buckets[1], 1);
+        assert_eq!(h.buckets[2], 2);
+        assert_eq!(h.buckets[11], 1);
+        assert_eq!(h.buckets[HISTOGRAM_BUCKETS - 1], 1);
+        assert_eq!(h.count(), 6);
+        assert_eq!(h.min(), 0);
+        assert_eq!(h.max(), u64::MAX);
+
+        h.reset();
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.max(), 0);
+    }
+
+    #[test]
+    fn histogram_to_kpi() {
+        let mut h = Histogram::new();
+        h.record(4);
+        h.record(6);
+
+        let k: kpi::system::Histogram = (&h).into();
+        assert_eq!(k.count, 2);
+        assert_eq!(k.sum, 10);
+        assert_eq!(k.min, 4);
+        assert_eq!(k.max, 6);
+        assert_eq!(k.buckets, alloc::vec![0, 0, 0, 2]);
+        assert_eq!(k.p50, 6);
+        assert_eq!(k.p999, 6);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(500), 0);
+
+        for _i in 0..90 {
+            h.record(10);
+        }
+        for _i in 0..9 {
+            h.record(100);
+        }
+        h.record(5000);
+
+        // 10 is in bucket [8, 16), 100 in [64, 128), 5000 in [4096, 8192)
+        assert_eq!(h.percentile(0), 15);
+        assert_eq!(h.percentile(500), 15);
+        assert_eq!(h.percentile(900), 15);
+        assert_eq!(h.percentile(950), 127);
+        assert_eq!(h.percentile(990), 127);
+        assert_eq!(h.percentile(999), 5000);
+        assert_eq!(h.percentile(1000), 5000);
+    }
+}
@@ -33,6 +33,9 @@ const REDIS_PORT: u16 = 6379;
 /// Line we use to tell if Redis has started.
 const REDIS_START_MATCH: &'static str = "# Server initialized";
 
+/// Line we use to tell if kvstore has started.
+const KVSTORE_START_MATCH: &'static str = "kvstore: listening on port";
+
 /// Line we use in dhcpd to match for giving IP to qemu VM.
 const DHCP_ACK_MATCH: &'static str = "DHCPACK on 172.31.0.10 to 52:54:00:12:34:56 (btest) via tap0";
 
@@ -937,6 +940,10 @@ fn s03_userspace_smoke() {
         "test-print",
         "test-map",
         "test-alloc",
+        "test-fork",
+        "test-waitpid",
+        "test-exec",
+        "test-eventring",
         "test-upcall",
         "test-scheduler",
     ]);
@@ -949,6 +956,10 @@ fn s03_userspace_smoke() {
         output += p.exp_string("upcall_test OK")?.as_str();
         output += p.exp_string("map_test OK")?.as_str();
         output += p.exp_string("alloc_test OK")?.as_str();
+        output += p.exp_string("fork_test OK")?.as_str();
+        output += p.exp_string("waitpid_test OK")?.as_str();
+        output += p.exp_string("exec_test OK")?.as_str();
+        output += p.exp_string("eventring_test OK")?.as_str();
         output += p.exp_string("scheduler_test OK")?.as_str();
         output += p.exp_eof()?.as_str();
         p.process.exit()
@@ -957,6 +968,263 @@ fn s03_userspace_smoke() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Tests that user-space can allocate and map 1 GiB pages.
+#[test]
+fn s03_userspace_huge_page() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-huge-page")
+        .memory(4096);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("huge_page_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that user-space can give physical pages back (but not the ones it
+/// still has mapped).
+#[test]
+fn s03_userspace_release_physical() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-release-physical")
+        .memory(512);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("release_physical_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the memory of a process is given back when it exits (the
+/// children of the test together use more memory than the machine has).
+#[test]
+fn s03_userspace_exit_reclaim() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-exit-reclaim")
+        .memory(512)
+        .timeout(60_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("exit_reclaim_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process group (a process and its child) can be terminated
+/// at once.
+#[test]
+fn s03_userspace_process_group() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-process-group");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("process_group_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that large maps and file writes (which the kernel splits up to give
+/// the core back in between) still complete.
+#[test]
+fn s03_userspace_long_syscall() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-long-syscall");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("long_syscall_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that user-space can change the access rights of its memory (and
+/// turn it into a guard page).
+#[test]
+fn s03_userspace_protect() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-protect");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("protect_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a lineup thread that overflows its (guarded) stack gets
+/// reported instead of corrupting memory.
+#[test]
+fn s03_userspace_stack_guard() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-stack-guard");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("stack overflow on thread")?.as_str();
+        output += p.exp_string("stack_guard_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that lineup threads can start with a small stack that grows on
+/// demand.
+#[test]
+fn s03_userspace_stack_growth() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-stack-growth");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("stack_growth_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a lineup thread that never yields gets preempted so other
+/// threads on the same core can run.
+#[test]
+fn s03_userspace_preemption() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-preemption");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("preemption_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that idle cores of a process give up their hardware thread (wait
+/// on a futex) and get woken up once there is work for them.
+#[test]
+fn s03_userspace_futex() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-futex")
+        .cores(2);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("futex_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that processes get upcalls for the events they subscribed to (low
+/// memory).
+#[test]
+fn s03_userspace_event() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-event");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("event_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a supervisor can restart an RPC server (that talks to it over
+/// pipes) over and over again after it was terminated or crashed.
+#[test]
+fn s03_userspace_supervisor() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-supervisor");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("supervisor_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Measures the cost of entering and leaving the kernel with the
+/// `SystemOperation::Null` and `GetThreadId` system calls.
+///
+/// Fails if one takes more than `SYSCALL_CYCLE_BUDGET` cycles, e.g., because
+/// `syscall_enter` or the save area handling got slower.
+#[test]
+fn s03_userspace_syscall_cost() {
+    /// Generous, as we run in a VM.
+    const SYSCALL_CYCLE_BUDGET: u64 = 10_000;
+
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_features(&["test-syscall-cost"])
+        .release();
+    let mut output = String::new();
+    let mut costs: Vec<u64> = Vec::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+
+        let (prev, matched) = p.exp_regex(r#"syscall_cost_test null=(\d+) gettid=(\d+) cycles"#)?;
+        output += prev.as_str();
+        output += matched.as_str();
+        costs = matched
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|n| n.parse().ok())
+            .collect();
+
+        output += p.exp_string("syscall_cost_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+    assert_eq!(costs.len(), 2, "Can't parse system call costs");
+    for cycles in costs.iter() {
+        assert!(
+            *cycles <= SYSCALL_CYCLE_BUDGET,
+            "A system call took {} cycles (budget is {})",
+            cycles,
+            SYSCALL_CYCLE_BUDGET
+        );
+    }
+}
+
 /// Tests the lineup scheduler multi-core ability.
 ///
 /// Makes sure we can request cores and spawn threads on said cores.
@@ -1056,6 +1324,48 @@ fn s04_userspace_rumprt_fs() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Tests the interactive shell in init.
+///
+/// Sends commands over the serial console and checks that the shell can
+/// read them, list processes, show memory statistics and the kernel log,
+/// refuses to run modules that don't exist, and exit.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s04_userspace_shell() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("shell");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+        output += p.exp_string("bespin> ")?.as_str();
+
+        p.send_line("ps")?;
+        output += p.exp_regex(r#"\s+1\s+Fair\s+init"#)?.0.as_str();
+        output += p.exp_string("bespin> ")?.as_str();
+
+        p.send_line("mem")?;
+        output += p.exp_string("NODE")?.as_str();
+        output += p.exp_string("bespin> ")?.as_str();
+
+        p.send_line("dmesg")?;
+        output += p
+            .exp_regex(r#"\[\s*\d+\.\d{6}\] INFO\s+bespin"#)?
+            .0
+            .as_str();
+        output += p.exp_string("bespin> ")?.as_str();
+
+        p.send_line("run nosuch --threads=1 --verbose")?;
+        output += p.exp_string("run: can't start nosuch")?.as_str();
+        output += p.exp_string("bespin> ")?.as_str();
+
+        p.send_line("exit")?;
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 /// Checks vspace debug functionality.
 #[test]
 fn s02_vspace_debug() {
@@ -1308,6 +1618,90 @@ fn s06_redis_benchmark_e1000() {
     wait_for_sigterm(&cmdline, qemu_run(), output);
 }
 
+/// Tests that the kvstore user application serves requests and benchmarks it
+/// with `redis-benchmark` (results are written to `kvstore_benchmark.csv`).
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s06_kvstore_benchmark() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .module("kvstore")
+        .cmd("testbinary=kvstore")
+        .use_virtio()
+        .user_feature("virtio")
+        .release()
+        .timeout(45_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut dhcp_server = spawn_dhcpd()?;
+        let mut p = spawn_bespin(&cmdline)?;
+
+        dhcp_server.exp_string(DHCP_ACK_MATCH)?;
+        output += p.exp_string(KVSTORE_START_MATCH)?.as_str();
+
+        let mut client = spawn_nc(REDIS_PORT)?;
+        client.send_line("ping")?;
+        client.exp_string("+PONG")?;
+        client.send_line("set msg hello")?;
+        client.exp_string("+OK")?;
+        client.send_line("get msg")?;
+        client.exp_string("$5")?;
+        client.exp_string("hello")?;
+        client.send_line("del msg")?;
+        client.exp_string(":1")?;
+        client.send_line("info memory")?;
+        client.exp_string("# Memory")?;
+        client.exp_string("node0:free=")?;
+        client.process.kill(SIGTERM)?;
+
+        let mut bencher = spawn(
+            format!(
+                "redis-benchmark -h 172.31.0.10 -p {} -t set,get -n {} -P 30 --csv",
+                REDIS_PORT, 1_000_000
+            )
+            .as_str(),
+            Some(25000),
+        )?;
+        bencher.exp_string("\"SET\",\"")?;
+        let (_line, set_tput) = bencher.exp_regex("[-+]?[0-9]*\\.?[0-9]+")?;
+        bencher.exp_string("\"GET\",\"")?;
+        let (_line, get_tput) = bencher.exp_regex("[-+]?[0-9]*\\.?[0-9]+")?;
+
+        let file_name = "kvstore_benchmark.csv";
+        let write_headers = !Path::new(file_name).exists();
+        let csv_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(file_name)
+            .expect("Can't open file");
+        let mut wtr = WriterBuilder::new()
+            .has_headers(write_headers)
+            .from_writer(csv_file);
+
+        #[derive(Serialize)]
+        struct Record {
+            git_rev: &'static str,
+            set: f64,
+            get: f64,
+        }
+
+        let record = Record {
+            git_rev: env!("GIT_HASH"),
+            set: set_tput.parse().unwrap_or(404.0),
+            get: get_tput.parse().unwrap_or(404.0),
+        };
+        println!("git_rev,set,get");
+        println!("{},{},{}", record.git_rev, record.set, record.get);
+        wtr.serialize(record).expect("Can't write results");
+
+        dhcp_server.send_control('c')?;
+        bencher.process.kill(SIGTERM)?;
+        p.process.kill(SIGTERM)
+    };
+
+    wait_for_sigterm(&cmdline, qemu_run(), output);
+}
+
 pub fn thread_defaults(max_cores: usize) -> Vec<usize> {
     let mut threads = Vec::with_capacity(12);
 
@@ -1426,6 +1820,99 @@ fn s06_vmops_benchmark() {
     }
 }
 
+/// Runs the allocator micro-benchmarks with different allocator configurations.
+#[test]
+fn s06_alloc_benchmark() {
+    let machine = get_machine_from_env();
+
+    let threads = if cfg!(feature = "smoke") {
+        vec![1, 4]
+    } else {
+        thread_defaults(machine.max_cores())
+    };
+    // "default" uses the allocator as is, the rest is passed as `malloc=`
+    let configs = ["default", "refill:8,refill_large:2", "slab_limit:4096"];
+
+    let file_name = "alloc_benchmark.csv";
+    let _r = std::fs::remove_file(file_name);
+
+    for &config in configs.iter() {
+        for &cores in threads.iter() {
+            let kernel_cmdline = if config == "default" {
+                format!("testcmd={}", cores)
+            } else {
+                format!("testcmd={} malloc={}", cores, config)
+            };
+            let mut cmdline = RunnerArgs::new("test-userspace-smp")
+                .module("init")
+                .user_feature("bench-alloc")
+                .cores(machine.max_cores())
+                .nodes(machine.max_sockets())
+                .setaffinity()
+                .timeout(25_000 + cores as u64 * 3000)
+                .release()
+                .cmd(kernel_cmdline.as_str());
+
+            if cfg!(feature = "smoke") {
+                cmdline = cmdline.user_feature("smoke").memory(8192);
+            } else {
+                cmdline = cmdline
+                    .memory(48 * 1024)
+                    .timeout(120_000 + cores as u64 * 3000);
+            }
+
+            let mut output = String::new();
+            let mut qemu_run = |with_cores: usize| -> Result<WaitStatus> {
+                let mut p = spawn_bespin(&cmdline)?;
+
+                // Parse lines like
+                // `init::allocbench: 1,fixed,4,64,10000,1000,9123456`
+                // write them to a CSV file
+                let seconds = if cfg!(feature = "smoke") { 1 } else { 10 };
+                let benchmarks = 7;
+                let expected_lines = with_cores * benchmarks * seconds;
+
+                for _i in 0..expected_lines {
+                    let (prev, matched) = p.exp_regex(
+                        r#"init::allocbench: (\d+),(.*),(\d+),(\d+),(\d+),(\d+),(\d+)"#,
+                    )?;
+                    output += prev.as_str();
+                    output += matched.as_str();
+
+                    // Append parsed results to a CSV file
+                    let write_headers = !Path::new(file_name).exists();
+                    let mut csv_file = OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(file_name)
+                        .expect("Can't open file");
+                    if write_headers {
+                        let row = "git_rev,config,thread_id,benchmark,ncores,objsize,duration_total,duration,operations\n";
+                        let r = csv_file.write(row.as_bytes());
+                        assert!(r.is_ok());
+                    }
+
+                    let parts: Vec<&str> = matched.split("init::allocbench: ").collect();
+                    let r = csv_file.write(
+                        format!("{},{},", env!("GIT_HASH"), config.replace(",", ";")).as_bytes(),
+                    );
+                    assert!(r.is_ok());
+                    let r = csv_file.write(parts[1].as_bytes());
+                    assert!(r.is_ok());
+                    let r = csv_file.write("\n".as_bytes());
+                    assert!(r.is_ok());
+                }
+
+                output += p.exp_string("allocbench OK")?.as_str();
+                output += p.exp_eof()?.as_str();
+                p.process.exit()
+            };
+
+            check_for_successful_exit(&cmdline, qemu_run(cores), output);
+        }
+    }
+}
+
 #[test]
 fn s06_shootdown_simple() {
     let max_cores = num_cpus::get() / 2;
@@ -1810,6 +2297,52 @@ fn s06_test_fs() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Tests that the bytes a process stores in files are accounted and that
+/// writes beyond the quota (set on the command-line) fail.
+#[test]
+fn s06_test_fs_quota() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .module("init")
+        .user_feature("test-fs-quota")
+        .cmd("fsquota=65536")
+        .release()
+        .timeout(20_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+
+        p.exp_string("fs_quota_test OK")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that clean pages of evictable files are dropped when memory runs
+/// low (instead of failing the allocation).
+#[test]
+fn s06_test_fs_evict() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .module("init")
+        .user_feature("test-fs-evict")
+        .memory(512)
+        .release()
+        .timeout(30_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_bespin(&cmdline)?;
+
+        p.exp_string("fs_evict_test OK")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 fn memcached_benchmark(
     driver: &'static str,
     cores: usize,
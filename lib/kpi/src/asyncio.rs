@@ -0,0 +1,133 @@
+//! Shared-memory rings for asynchronous system calls.
+//!
+//! A process can submit file and vspace operations without a system call per
+//! operation: It registers a ring with `AsyncOperation::Setup` and writes
+//! `Submission`s into it, the kernel executes them and writes a `Completion`
+//! for each one. The kernel looks at the ring whenever it schedules the
+//! process (e.g., on a timer interrupt) and when the process asks for it with
+//! `AsyncOperation::Enter`.
+//!
+//! The ring is a single memory region:
+//!
+//! `[RingHeader][Submission; entries][Completion; entries]`
+//!
+//! The indices in the header only ever increase, index `i` refers to entry
+//! `i % entries`. User-space produces submissions (`sq_tail`) and consumes
+//! completions (`cq_head`), the kernel consumes submissions (`sq_head`) and
+//! produces completions (`cq_tail`).
+
+use core::mem::size_of;
+use core::sync::atomic::AtomicU64;
+
+use crate::SystemCallError;
+
+/// Maximum number of entries in a ring.
+pub const MAX_RING_ENTRIES: u64 = 4096;
+
+/// Indices of the submission and completion queue.
+#[derive(Debug, Default)]
+#[repr(C, align(64))]
+pub struct RingHeader {
+    /// Next submission the kernel will execute.
+    pub sq_head: AtomicU64,
+    /// Next free submission entry.
+    pub sq_tail: AtomicU64,
+    /// Next completion user-space will look at.
+    pub cq_head: AtomicU64,
+    /// Next free completion entry.
+    pub cq_tail: AtomicU64,
+}
+
+/// A system call to execute.
+///
+/// Only `SystemCall::FileIO` and `SystemCall::VSpace` can be submitted, they
+/// take the same arguments as the corresponding system call.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct Submission {
+    /// The `SystemCall` class.
+    pub syscall: u64,
+    /// Arguments (starting with the operation).
+    pub args: [u64; 5],
+    /// Passed back in the completion.
+    pub user_data: u64,
+}
+
+/// The result of a `Submission`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct Completion {
+    /// `user_data` of the submission.
+    pub user_data: u64,
+    /// 0 or the error word (see `SystemCallError::with_detail`).
+    pub error: u64,
+    /// The two return values of the system call.
+    pub ret: [u64; 2],
+}
+
+impl Completion {
+    /// Return values of the system call or the error it failed with.
+    pub fn result(&self) -> Result<(u64, u64), SystemCallError> {
+        if self.error == 0 {
+            Ok((self.ret[0], self.ret[1]))
+        } else {
+            Err(SystemCallError::from(self.error))
+        }
+    }
+}
+
+/// Size (in bytes) of a ring with `entries` entries.
+pub const fn ring_size(entries: u64) -> usize {
+    size_of::<RingHeader>() + entries as usize * (size_of::<Submission>() + size_of::<Completion>())
+}
+
+/// Offset (in bytes) of the submission with index `idx` in a ring.
+pub const fn submission_offset(entries: u64, idx: u64) -> usize {
+    size_of::<RingHeader>() + (idx % entries) as usize * size_of::<Submission>()
+}
+
+/// Offset (in bytes) of the completion with index `idx` in a ring.
+pub const fn completion_offset(entries: u64, idx: u64) -> usize {
+    size_of::<RingHeader>()
+        + entries as usize * size_of::<Submission>()
+        + (idx % entries) as usize * size_of::<Completion>()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring_layout() {
+        assert_eq!(size_of::<RingHeader>(), 64);
+        assert_eq!(size_of::<Submission>(), 56);
+        assert_eq!(size_of::<Completion>(), 32);
+
+        assert_eq!(ring_size(4), 64 + 4 * 56 + 4 * 32);
+        assert_eq!(submission_offset(4, 0), 64);
+        assert_eq!(submission_offset(4, 5), 64 + 56);
+        assert_eq!(completion_offset(4, 0), 64 + 4 * 56);
+        assert_eq!(completion_offset(4, 7), 64 + 4 * 56 + 3 * 32);
+        assert_eq!(
+            completion_offset(4, 3) + size_of::<Completion>(),
+            ring_size(4)
+        );
+    }
+
+    #[test]
+    fn completion_result() {
+        let c = Completion {
+            user_data: 1,
+            error: 0,
+            ret: [2, 3],
+        };
+        assert_eq!(c.result(), Ok((2, 3)));
+
+        let c = Completion {
+            user_data: 1,
+            error: SystemCallError::WouldBlock.with_detail(7),
+            ret: [0, 0],
+        };
+        assert_eq!(c.result(), Err(SystemCallError::WouldBlock));
+    }
+}
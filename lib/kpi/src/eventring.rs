@@ -0,0 +1,215 @@
+//! Read-only rings the kernel streams events into.
+//!
+//! A process (e.g., a monitoring tool) asks the kernel to map an event ring
+//! with `SystemOperation::OpenEventRing` and picks the `EventTopics` it is
+//! interested in. The kernel appends an `Event` for everything that happens
+//! in these topics and the process reads them without entering the kernel.
+//!
+//! The ring is a single large page, mapped read-only:
+//!
+//! `[EventRingHeader][Event; EVENT_RING_ENTRIES]`
+//!
+//! `tail` only ever increases, event `i` is in entry `i % EVENT_RING_ENTRIES`.
+//! The kernel doesn't know how far the process got, it just overwrites the
+//! oldest events. `read_event` notices if an event got overwritten (or is
+//! written right now) because the `seq` of the entry doesn't match.
+//!
+//! Instead of polling the ring, a process can arm the doorbell with
+//! `SystemOperation::ArmEventRing`: As long as the ring has events the process
+//! hasn't seen, the kernel periodically sends it an `upcall::EVENT_RING`
+//! upcall. Like all upcalls this is best-effort.
+
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use bitflags::*;
+
+/// Size (in bytes) of a ring, the ring has to be mapped at an address that
+/// is aligned to it.
+pub const EVENT_RING_SIZE: usize = 2 * 1024 * 1024;
+
+/// Number of events in a ring.
+pub const EVENT_RING_ENTRIES: u64 =
+    ((EVENT_RING_SIZE - size_of::<EventRingHeader>()) / size_of::<Event>()) as u64;
+
+/// How many bytes of data an event can carry.
+pub const EVENT_DATA_LEN: usize = 72;
+
+bitflags! {
+    /// Kinds of events a ring can receive.
+    pub struct EventTopics: u64 {
+        /// Kernel log records: `args[0]` is the level (1 = error, 5 = trace),
+        /// `data` is the module and message.
+        const LOG = 0x1;
+        /// Operations dispatched by the kernel replicas (needs a kernel
+        /// with the `nrtrace` feature): `args[0]` is the operation class
+        /// (upper 32 bits) and operation, `args[1]` the cycles it took.
+        const TRACE = 0x2;
+        /// An executor got dispatched on `core`: `args[0]` is the pid,
+        /// `args[1]` the executor id.
+        const SCHEDULER = 0x4;
+    }
+}
+
+/// Index of the next event.
+#[derive(Debug, Default)]
+#[repr(C, align(64))]
+pub struct EventRingHeader {
+    /// Next event the kernel will write.
+    pub tail: AtomicU64,
+}
+
+/// An event in the ring.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Event {
+    /// Index of the event (`u64::MAX` while the kernel writes it).
+    pub seq: u64,
+    /// The `EventTopics` bit of the event.
+    pub topic: u64,
+    /// Nanoseconds since boot.
+    pub timestamp: u64,
+    /// Hardware thread that produced the event.
+    pub core: u64,
+    /// Values that depend on the topic.
+    pub args: [u64; 2],
+    /// How many bytes of `data` are valid.
+    pub len: u64,
+    /// Bytes that depend on the topic (truncated if they don't fit).
+    pub data: [u8; EVENT_DATA_LEN],
+}
+
+impl Event {
+    pub const EMPTY: Event = Event {
+        seq: 0,
+        topic: 0,
+        timestamp: 0,
+        core: 0,
+        args: [0, 0],
+        len: 0,
+        data: [0; EVENT_DATA_LEN],
+    };
+
+    /// The topic of the event.
+    pub fn topic(&self) -> EventTopics {
+        EventTopics::from_bits_truncate(self.topic)
+    }
+
+    /// The valid bytes of `data`.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..core::cmp::min(self.len as usize, EVENT_DATA_LEN)]
+    }
+}
+
+/// Offset (in bytes) of the event with index `idx` in a ring.
+pub const fn event_offset(idx: u64) -> usize {
+    size_of::<EventRingHeader>() + (idx % EVENT_RING_ENTRIES) as usize * size_of::<Event>()
+}
+
+/// Index of the oldest event that is still in a ring with `tail`.
+pub fn oldest(tail: u64) -> u64 {
+    tail.saturating_sub(EVENT_RING_ENTRIES)
+}
+
+/// Appends an event to the ring at `base`, `fill` sets everything except
+/// `seq`. Returns the index of the event.
+///
+/// # Safety
+/// `base` has to point to a ring and there can only be one writer at a time.
+pub unsafe fn write_event<F: FnOnce(&mut Event)>(base: *mut u8, fill: F) -> u64 {
+    let header = &*(base as *const EventRingHeader);
+    let idx = header.tail.load(Ordering::Relaxed);
+    let event = &mut *(base.add(event_offset(idx)) as *mut Event);
+    let seq = &*(&event.seq as *const u64 as *const AtomicU64);
+
+    seq.store(u64::max_value(), Ordering::Relaxed);
+    fence(Ordering::Release);
+    fill(event);
+    seq.store(idx, Ordering::Release);
+    header.tail.store(idx + 1, Ordering::Release);
+    idx
+}
+
+/// Copies the event with index `idx` out of the ring at `base`.
+///
+/// Returns `None` if the kernel is writing it right now or already overwrote
+/// it. Only events below `tail` are valid.
+///
+/// # Safety
+/// `base` has to point to a ring.
+pub unsafe fn read_event(base: *const u8, idx: u64) -> Option<Event> {
+    let entry = base.add(event_offset(idx)) as *const Event;
+    let seq = &*(entry as *const AtomicU64);
+    if seq.load(Ordering::Acquire) != idx {
+        return None;
+    }
+
+    let event = core::ptr::read_volatile(entry);
+    fence(Ordering::Acquire);
+    if seq.load(Ordering::Relaxed) != idx {
+        return None;
+    }
+    Some(event)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn ring_layout() {
+        assert_eq!(size_of::<EventRingHeader>(), 64);
+        assert_eq!(size_of::<Event>(), 128);
+        assert_eq!(EVENT_RING_ENTRIES, 16383);
+
+        assert_eq!(event_offset(0), 64);
+        assert_eq!(event_offset(1), 64 + 128);
+        assert_eq!(event_offset(EVENT_RING_ENTRIES), 64);
+        // The last 64 bytes are unused
+        assert_eq!(
+            event_offset(EVENT_RING_ENTRIES - 1) + size_of::<Event>(),
+            EVENT_RING_SIZE - 64
+        );
+
+        assert_eq!(oldest(10), 0);
+        assert_eq!(oldest(EVENT_RING_ENTRIES + 10), 10);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut ring = vec![0u64; EVENT_RING_SIZE / size_of::<u64>()];
+        let base = ring.as_mut_ptr() as *mut u8;
+
+        unsafe {
+            assert!(read_event(base, 1).is_none());
+
+            for i in 0..3 {
+                let idx = write_event(base, |e| {
+                    e.topic = EventTopics::LOG.bits();
+                    e.args = [i, 0];
+                    e.data[..2].copy_from_slice(b"hi");
+                    e.len = 2;
+                });
+                assert_eq!(idx, i);
+            }
+
+            let header = &*(base as *const EventRingHeader);
+            assert_eq!(header.tail.load(Ordering::Relaxed), 3);
+
+            let e = read_event(base, 2).expect("event was written");
+            assert_eq!(e.seq, 2);
+            assert_eq!(e.topic(), EventTopics::LOG);
+            assert_eq!(e.args, [2, 0]);
+            assert_eq!(e.data(), b"hi");
+            assert!(read_event(base, 3).is_none());
+
+            // Wrap around, the first events get overwritten
+            for _i in 0..EVENT_RING_ENTRIES {
+                write_event(base, |e| e.len = 0);
+            }
+            assert!(read_event(base, 2).is_none());
+            assert!(read_event(base, EVENT_RING_ENTRIES + 2).is_some());
+        }
+    }
+}
@@ -1,10 +1,58 @@
+use alloc::string::String;
+use core::convert::TryFrom;
+
 use bitflags::*;
+use serde::{Deserialize, Serialize};
+
+use crate::SystemCallError;
+
+/// Interrupt line `gsi` (see `Irq::irqalloc`) arrives as upcall with vector
+/// `gsi + IRQ_VECTOR_BASE`.
+pub const IRQ_VECTOR_BASE: u64 = 32;
 
 /// Struct used in `file_getinfo` systemcall.
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct FileInfo {
     pub ftype: u64,
     pub fsize: u64,
+    /// Number of base pages that hold the data of the file.
+    pub base_pages: u64,
+    /// Number of large pages that hold the data of the file.
+    pub large_pages: u64,
+}
+
+/// An entry of a directory (see `FileOperation::ReadDir`).
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Name of the entry (without the path of the directory).
+    pub name: String,
+    /// Directory or file (same as `FileInfo::ftype`).
+    pub ftype: u64,
+}
+
+/// What the offset of `FileOperation::Seek` is relative to (`whence` of lseek).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u64)]
+pub enum SeekWhence {
+    /// Relative to the start of the file (SEEK_SET).
+    Set = 0,
+    /// Relative to the current offset (SEEK_CUR).
+    Cur = 1,
+    /// Relative to the end of the file (SEEK_END).
+    End = 2,
+}
+
+impl TryFrom<u64> for SeekWhence {
+    type Error = SystemCallError;
+
+    fn try_from(whence: u64) -> Result<SeekWhence, SystemCallError> {
+        match whence {
+            0 => Ok(SeekWhence::Set),
+            1 => Ok(SeekWhence::Cur),
+            2 => Ok(SeekWhence::End),
+            _ => Err(SystemCallError::BadFlags),
+        }
+    }
 }
 
 bitflags! {
@@ -17,6 +65,7 @@ bitflags! {
         const O_CREAT = 0x0200; /* create if nonexistant */
         const O_TRUNC = 0x0400; /* truncate to zero length */
         const O_APPEND = 0x02000; /* append at the EOF */
+        const O_EVICTABLE = 0x100000; /* content is a cache, can be dropped when memory is low */
     }
 }
 
@@ -63,16 +112,31 @@ impl FileFlags {
     pub fn is_append(&self) -> bool {
         (*self & FileFlags::O_APPEND) == FileFlags::O_APPEND
     }
+
+    pub fn is_evictable(&self) -> bool {
+        (*self & FileFlags::O_EVICTABLE) == FileFlags::O_EVICTABLE
+    }
 }
 
 bitflags! {
     /// FileModes to store the file in the memory. A file can be stored in
     /// readable, writable or executable mode.
+    ///
+    /// The bits for the owner of a file come first, followed by the ones for
+    /// its group and everyone else (each shifted by 3).
     pub struct FileModes: u64 {
         const S_IRWXU = 0x007; /* RWX mask for user */
         const S_IRUSR = 0x004; /* R for user */
         const S_IWUSR = 0x002; /* W for user */
         const S_IXUSR = 0x001; /* X for user */
+        const S_IRWXG = 0x038; /* RWX mask for group */
+        const S_IRGRP = 0x020; /* R for group */
+        const S_IWGRP = 0x010; /* W for group */
+        const S_IXGRP = 0x008; /* X for group */
+        const S_IRWXO = 0x1c0; /* RWX mask for other */
+        const S_IROTH = 0x100; /* R for other */
+        const S_IWOTH = 0x080; /* W for other */
+        const S_IXOTH = 0x040; /* X for other */
     }
 }
 
@@ -103,4 +167,75 @@ impl FileModes {
     pub fn is_executable(&self) -> bool {
         (*self & FileModes::S_IXUSR) == FileModes::S_IXUSR
     }
+
+    /// The group bits, moved to where the user bits are.
+    pub fn group(&self) -> FileModes {
+        FileModes::from((self.bits() & FileModes::S_IRWXG.bits()) >> 3)
+    }
+
+    /// The bits for other users, moved to where the user bits are.
+    pub fn other(&self) -> FileModes {
+        FileModes::from((self.bits() & FileModes::S_IRWXO.bits()) >> 6)
+    }
+}
+
+/// A base address register (BAR) of a PCI device (see `DeviceOperation::Bar`).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PciBar {
+    /// Physical address (or I/O port) of the region.
+    pub base: u64,
+    /// Size of the region in bytes.
+    pub size: u64,
+    /// The region is in the I/O port space (and can't be mapped).
+    pub io: bool,
+    /// Reads from the region have no side-effects.
+    pub prefetchable: bool,
+}
+
+impl PciBar {
+    /// Set in the encoded base of I/O BARs.
+    const IO: u64 = 0x1;
+    /// Set in the encoded base of prefetchable BARs.
+    const PREFETCHABLE: u64 = 0x8;
+
+    /// The base with the flags in the low bits (like in the BAR itself),
+    /// that's how the kernel returns it.
+    pub fn encoded_base(&self) -> u64 {
+        let mut encoded = self.base;
+        if self.io {
+            encoded |= PciBar::IO;
+        }
+        if self.prefetchable {
+            encoded |= PciBar::PREFETCHABLE;
+        }
+        encoded
+    }
+
+    /// Reverses `encoded_base`.
+    pub fn from_encoded(encoded: u64, size: u64) -> PciBar {
+        let io = encoded & PciBar::IO != 0;
+        PciBar {
+            base: if io { encoded & !0x3 } else { encoded & !0xf },
+            size,
+            io,
+            prefetchable: !io && encoded & PciBar::PREFETCHABLE != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn pci_bar_encoding() {
+    let bar = PciBar {
+        base: 0xfebf_0000,
+        size: 0x1000,
+        io: false,
+        prefetchable: true,
+    };
+    assert_eq!(bar.encoded_base(), 0xfebf_0008);
+    assert_eq!(PciBar::from_encoded(bar.encoded_base(), bar.size), bar);
+
+    let ports = PciBar::from_encoded(0xc041, 0x20);
+    assert_eq!(ports.base, 0xc040);
+    assert!(ports.io && !ports.prefetchable);
 }
@@ -4,9 +4,10 @@
 #![feature(llvm_asm)]
 
 #[allow(non_snake_case)]
-#[cfg(target_os = "bespin")]
 extern crate alloc;
 
+pub mod asyncio;
+pub mod eventring;
 pub mod io;
 pub mod process;
 pub mod system;
@@ -26,6 +27,9 @@ pub mod arch {
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[repr(u64)]
 /// Errors returned by system calls.
+///
+/// A new error also needs an arm in `From<u64>`, in the `KError`
+/// conversion of the kernel and an errno in vibrio (`rumprt::fs::to_errno`).
 pub enum SystemCallError {
     /// This means no error and should never be created.
     Ok = 0,
@@ -49,14 +53,47 @@ pub enum SystemCallError {
     PermissionError = 9,
     /// Bad offset
     OffsetError = 10,
+    /// The operation can't make progress right now (e.g., reading from an
+    /// empty pipe), try again later.
+    WouldBlock = 11,
+    /// Writing to a pipe that nobody can read from anymore.
+    BrokenPipe = 12,
+    /// The process can't store more bytes in files (see `UsageKind::FileSystem`).
+    QuotaExceeded = 13,
+    /// The memory is still mapped in the address-space (unmap it first).
+    StillMapped = 14,
     /// Placeholder for an invalid, unknown error code.
     Unknown,
 }
 
+/// Number of low bits in the error word (returned in `rax`) that hold the
+/// `SystemCallError` code, the remaining upper bits carry an (error specific)
+/// detail value.
+pub const SYSCALL_ERROR_CODE_BITS: u64 = 16;
+
+impl SystemCallError {
+    /// Encode the error and an auxiliary `detail` value into an error word.
+    ///
+    /// `detail` is truncated to 48 bits (enough for a user-space address).
+    pub fn with_detail(self, detail: u64) -> u64 {
+        (self as u64) | (detail << SYSCALL_ERROR_CODE_BITS)
+    }
+
+    /// Extract the detail value from an error word returned by a system call.
+    ///
+    /// What the detail means depends on the error, e.g., the invalid argument
+    /// for `NotSupported` or the virtual address that was already mapped for
+    /// `VSpaceAlreadyMapped`. It's 0 if the kernel didn't provide any.
+    pub fn detail(e: u64) -> u64 {
+        e >> SYSCALL_ERROR_CODE_BITS
+    }
+}
+
 impl From<u64> for SystemCallError {
-    /// Construct a `SystemCallError` enum based on a 64-bit value.
+    /// Construct a `SystemCallError` enum based on a 64-bit value
+    /// (ignores the detail bits).
     fn from(e: u64) -> SystemCallError {
-        match e {
+        match e & ((1 << SYSCALL_ERROR_CODE_BITS) - 1) {
             1 => SystemCallError::NotLogged,
             2 => SystemCallError::NotSupported,
             3 => SystemCallError::VSpaceAlreadyMapped,
@@ -67,222 +104,562 @@ impl From<u64> for SystemCallError {
             8 => SystemCallError::BadFlags,
             9 => SystemCallError::PermissionError,
             10 => SystemCallError::OffsetError,
+            11 => SystemCallError::WouldBlock,
+            12 => SystemCallError::BrokenPipe,
+            13 => SystemCallError::QuotaExceeded,
+            14 => SystemCallError::StillMapped,
             _ => SystemCallError::Unknown,
         }
     }
 }
 
-/// Flags for the process system call
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
-#[repr(u64)]
-pub enum ProcessOperation {
-    /// Exit the process.
-    Exit = 1,
-    /// Log to console.
-    Log = 2,
-    /// Sets the process control and save area for trap/IRQ forwarding
-    /// to user-space for this process and CPU.
-    GetVCpuArea = 3,
-    /// Allocate a device interrupt vector.
-    AllocateVector = 4,
-    /// Subscribe to a trap and/or interrupt events.
-    SubscribeEvent = 5,
-    /// Query info about the current process.
-    GetProcessInfo = 6,
-    /// Request a new core for the process.
-    RequestCore = 7,
-    /// Allocate a physical memory page as a mem object to the process.
-    AllocatePhysical = 8,
-    Unknown,
+#[cfg(test)]
+#[test]
+fn error_detail() {
+    let e = SystemCallError::VSpaceAlreadyMapped.with_detail(0x7fff_dead_b000);
+    assert_eq!(SystemCallError::from(e), SystemCallError::VSpaceAlreadyMapped);
+    assert_eq!(SystemCallError::detail(e), 0x7fff_dead_b000);
+    assert_eq!(SystemCallError::detail(SystemCallError::BadAddress as u64), 0);
 }
 
-impl From<u64> for ProcessOperation {
-    /// Construct a ProcessOperation enum based on a 64-bit value.
-    fn from(op: u64) -> ProcessOperation {
-        match op {
-            1 => ProcessOperation::Exit,
-            2 => ProcessOperation::Log,
-            3 => ProcessOperation::GetVCpuArea,
-            4 => ProcessOperation::AllocateVector,
-            5 => ProcessOperation::SubscribeEvent,
-            6 => ProcessOperation::GetProcessInfo,
-            7 => ProcessOperation::RequestCore,
-            8 => ProcessOperation::AllocatePhysical,
-            _ => ProcessOperation::Unknown,
+/// Declares a system call operation enum.
+///
+/// This is the single table that both user-space (to encode) and the kernel
+/// (to decode) use for an operation, it generates:
+///
+/// - The enum (with an additional `Unknown` variant).
+/// - `From<u64>` to decode the operation from a syscall argument.
+/// - `From<&str>` to parse the operation from its name (or from the alias
+///   given with `as "alias"` instead).
+///
+/// If the table names the system call the operations belong to (`for
+/// SystemCall::X as XRequest`), every operation also names its (at most
+/// four) arguments and the table generates a request enum with a variant
+/// (that has these fields) per operation. User-space issues a request with
+/// `call1`, `call2` or `call3` and the kernel gets it back with `decode`, so
+/// neither the system call, the operation nor the arguments can be mixed
+/// up.
+macro_rules! operations {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident for SystemCall::$syscall:ident as $request:ident {
+            $(
+                $(#[$vmeta:meta])*
+                $variant:ident($($arg:ident),*) = $value:literal $(as $alias:literal)?,
+            )*
+        }
+    ) => {
+        operations! {
+            $(#[$meta])*
+            pub enum $name {
+                $(
+                    $(#[$vmeta])*
+                    $variant = $value $(as $alias)?,
+                )*
+            }
         }
-    }
-}
 
-impl From<&str> for ProcessOperation {
-    /// Construct a ProcessOperation enum based on a str.
-    fn from(op: &str) -> ProcessOperation {
-        match op {
-            "Exit" => ProcessOperation::Exit,
-            "Log" => ProcessOperation::Log,
-            "GetVCpuArea" => ProcessOperation::GetVCpuArea,
-            "AllocateVector" => ProcessOperation::AllocateVector,
-            "SubscribeEvent" => ProcessOperation::SubscribeEvent,
-            "GetProcessInfo" => ProcessOperation::GetProcessInfo,
-            "RequestCore" => ProcessOperation::RequestCore,
-            "AllocatePhysical" => ProcessOperation::AllocatePhysical,
-            _ => ProcessOperation::Unknown,
+        impl $name {
+            /// The system call that carries these operations.
+            pub const SYSCALL: SystemCall = SystemCall::$syscall;
         }
-    }
+
+        /// An operation together with its arguments (see `operations!`).
+        #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+        pub enum $request {
+            $(
+                $(#[$vmeta])*
+                $variant { $($arg: u64),* },
+            )*
+            /// An operation we don't know (the value it was decoded from).
+            Unknown(u64),
+        }
+
+        impl $request {
+            /// Decodes operation `op` with `args` (the system call arguments
+            /// that follow the operation).
+            pub fn decode(op: u64, args: [u64; 4]) -> $request {
+                let mut args = args.iter().copied();
+                match $name::from(op) {
+                    $(
+                        $name::$variant => $request::$variant {
+                            $($arg: args.next().unwrap_or(0)),*
+                        },
+                    )*
+                    $name::Unknown => $request::Unknown(op),
+                }
+            }
+
+            /// The operation of the request.
+            pub fn operation(&self) -> $name {
+                match self {
+                    $($request::$variant { .. } => $name::$variant,)*
+                    $request::Unknown(_op) => $name::Unknown,
+                }
+            }
+
+            /// The operation (as passed to the system call) and its
+            /// arguments, unused ones are 0.
+            pub fn encode(&self) -> (u64, [u64; 4]) {
+                match *self {
+                    $(
+                        $request::$variant { $($arg),* } => {
+                            ($name::$variant as u64, operation_arguments!($($arg),*))
+                        }
+                    )*
+                    $request::Unknown(op) => (op, [0; 4]),
+                }
+            }
+
+            /// A submission that executes the request asynchronously (see
+            /// `asyncio`).
+            pub fn submission(&self, user_data: u64) -> crate::asyncio::Submission {
+                let (op, [a, b, c, d]) = self.encode();
+                crate::asyncio::Submission {
+                    syscall: SystemCall::$syscall as u64,
+                    args: [op, a, b, c, d],
+                    user_data,
+                }
+            }
+        }
+
+        #[cfg(target_os = "bespin")]
+        impl $request {
+            /// Issue the request, returns the error code.
+            ///
+            /// # Safety
+            /// The kernel may access memory that the arguments point to.
+            pub unsafe fn call1(self) -> u64 {
+                let (op, a) = self.encode();
+                crate::syscalls::macros::syscall_6_1(
+                    SystemCall::$syscall as u64,
+                    op,
+                    a[0],
+                    a[1],
+                    a[2],
+                    a[3],
+                )
+            }
+
+            /// Issue the request, returns the error code and one return
+            /// value.
+            ///
+            /// # Safety
+            /// The kernel may access memory that the arguments point to.
+            pub unsafe fn call2(self) -> (u64, u64) {
+                let (op, a) = self.encode();
+                crate::syscalls::macros::syscall_6_2(
+                    SystemCall::$syscall as u64,
+                    op,
+                    a[0],
+                    a[1],
+                    a[2],
+                    a[3],
+                )
+            }
+
+            /// Issue the request, returns the error code and two return
+            /// values.
+            ///
+            /// # Safety
+            /// The kernel may access memory that the arguments point to.
+            pub unsafe fn call3(self) -> (u64, u64, u64) {
+                let (op, a) = self.encode();
+                crate::syscalls::macros::syscall_6_3(
+                    SystemCall::$syscall as u64,
+                    op,
+                    a[0],
+                    a[1],
+                    a[2],
+                    a[3],
+                )
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$vmeta:meta])*
+                $variant:ident = $value:literal $(as $alias:literal)?,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+        #[repr(u64)]
+        pub enum $name {
+            $(
+                $(#[$vmeta])*
+                $variant = $value,
+            )*
+            Unknown,
+        }
+
+        impl From<u64> for $name {
+            /// Construct the operation based on a 64-bit value.
+            fn from(op: u64) -> $name {
+                match op {
+                    $($value => $name::$variant,)*
+                    _ => $name::Unknown,
+                }
+            }
+        }
+
+        impl From<&str> for $name {
+            /// Construct the operation based on a str.
+            fn from(op: &str) -> $name {
+                match op {
+                    $(operation_name!($variant $($alias)?) => $name::$variant,)*
+                    _ => $name::Unknown,
+                }
+            }
+        }
+    };
 }
 
-/// Flags for the map system call
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
-#[repr(u64)]
-pub enum VSpaceOperation {
-    /// Map some anonymous memory
-    Map = 1,
-    /// Unmap a mapped region
-    Unmap = 2,
-    /// Identity map some device memory
-    MapDevice = 3,
-    /// Map a previously allocated physical frame,
-    MapFrame = 4,
-    /// Resolve a virtual to a physical address
-    Identify = 5,
-    Unknown,
+/// The name an operation is parsed from (see `operations!`).
+macro_rules! operation_name {
+    ($variant:ident) => {
+        stringify!($variant)
+    };
+    ($variant:ident $alias:literal) => {
+        $alias
+    };
 }
 
-impl From<u64> for VSpaceOperation {
-    /// Construct a SystemCall enum based on a 64-bit value.
-    fn from(op: u64) -> VSpaceOperation {
-        match op {
-            1 => VSpaceOperation::Map,
-            2 => VSpaceOperation::Unmap,
-            3 => VSpaceOperation::MapDevice,
-            4 => VSpaceOperation::MapFrame,
-            5 => VSpaceOperation::Identify,
-            _ => VSpaceOperation::Unknown,
-        }
+/// The system call arguments of an operation (see `operations!`), unused
+/// ones are 0. An operation with more than four doesn't compile (they don't
+/// fit into the registers of a system call).
+macro_rules! operation_arguments {
+    () => {
+        [0, 0, 0, 0]
+    };
+    ($a:ident) => {
+        [$a, 0, 0, 0]
+    };
+    ($a:ident, $b:ident) => {
+        [$a, $b, 0, 0]
+    };
+    ($a:ident, $b:ident, $c:ident) => {
+        [$a, $b, $c, 0]
+    };
+    ($a:ident, $b:ident, $c:ident, $d:ident) => {
+        [$a, $b, $c, $d]
+    };
+}
+
+operations! {
+    /// Flags for the process system call
+    pub enum ProcessOperation for SystemCall::Process as ProcessRequest {
+        /// Exit the process.
+        Exit(code) = 1,
+        /// Log to console.
+        Log(buf, len) = 2,
+        /// Sets the process control and save area for trap/IRQ forwarding
+        /// to user-space for this process and CPU.
+        GetVCpuArea() = 3,
+        /// Allocate a device interrupt vector.
+        AllocateVector(vector, core) = 4,
+        /// Subscribe to periodic timer upcalls (`upcall::TIMER`) on this core
+        /// or to asynchronous events of the machine (`upcall::CORE_ONLINE`,
+        /// `upcall::LOW_MEMORY`).
+        SubscribeEvent(event, value) = 5,
+        /// Query info about the current process.
+        GetProcessInfo(buf, len) = 6,
+        /// Request a new core for the process.
+        RequestCore(gtid, entry_point) = 7,
+        /// Allocate a physical memory page as a mem object to the process.
+        AllocatePhysical(page_size) = 8,
+        /// Query the resources used by the process (see `UsageKind`).
+        GetUsage(kind) = 9,
+        /// Route a previously allocated device interrupt to a different core.
+        SteerVector(vector, core) = 10,
+        /// Read pending input from the console (doesn't block).
+        ReadConsole(buf, len) = 11,
+        /// Start a new process from a boot module (with arguments).
+        Spawn(binary, binary_len, args, args_len) = 12,
+        /// Query if a process exited (doesn't block).
+        Wait(pid) = 13,
+        /// List all processes in the system.
+        ListProcesses(buf, len) = 14,
+        /// Change the user and group the process runs as (only root can).
+        SetCredentials(uid, gid) = 15,
+        /// Duplicate the process (its memory is shared copy-on-write).
+        Fork() = 16,
+        /// Replace the process image with a boot module.
+        Exec(binary, binary_len) = 17,
+        /// Wait until a child process exited (blocks).
+        WaitPid(pid) = 18,
+        /// Give a physical memory page (allocated with `AllocatePhysical`)
+        /// back to the kernel.
+        ReleasePhysical(frame_id, page_size) = 19,
+        /// Create or join a process group.
+        SetGroup(pid, group) = 20,
+        /// Terminate all processes of a process group.
+        TerminateGroup(group) = 21,
+        /// Let the caller restart a child process after it exited.
+        Supervise(pid, cmdline, cmdline_len) = 22,
+        /// Wait until a supervised process exited and start it again.
+        Restart(pid) = 23,
+        /// Wait (give up the core) until the futex at an address gets woken
+        /// up, if it still holds the expected value.
+        FutexWait(addr, expected) = 24,
+        /// Wake up executors that wait on the futex at an address.
+        FutexWake(addr, count) = 25,
+        /// Allocate consecutive MSI(-X) vectors for a PCI function.
+        AllocateMsiVectors(function, core, count) = 26,
+        /// Allocate a memory protection key (see `VSpaceOperation::ProtectKey`).
+        AllocateKey(rights) = 27,
+        /// Give a memory protection key back.
+        FreeKey(key) = 28,
+        /// Run the calling executor with a (CET) shadow stack.
+        EnableShadowStack() = 29,
+        /// Allocate physically contiguous (DMA) memory and map it.
+        AllocateDmaRegion(size, alignment) = 30,
+        /// Change the share of its cores the process gets when they are
+        /// time-shared with other processes.
+        SetWeight(weight) = 31,
     }
 }
 
-impl From<&str> for VSpaceOperation {
-    /// Construct a VSpaceOperation enum based on a str.
-    fn from(op: &str) -> VSpaceOperation {
-        match op {
-            "Map" => VSpaceOperation::Map,
-            "Unmap" => VSpaceOperation::Unmap,
-            "MapDevice" => VSpaceOperation::MapDevice,
-            "MapFrame" => VSpaceOperation::MapFrame,
-            "Identify" => VSpaceOperation::Identify,
-            _ => VSpaceOperation::Unknown,
-        }
+operations! {
+    /// Flags for the map system call
+    pub enum VSpaceOperation for SystemCall::VSpace as VSpaceRequest {
+        /// Map some anonymous memory
+        Map(base, size) = 1,
+        /// Unmap a mapped region
+        Unmap(base, size) = 2,
+        /// Identity map some device memory
+        MapDevice(base, size) = 3,
+        /// Map a previously allocated physical frame,
+        MapFrame(base, frame_id) = 4,
+        /// Resolve a virtual to a physical address
+        Identify(base) = 5,
+        /// Change the access rights of a mapped region
+        Protect(base, size, rights) = 6,
+        /// Tag a mapped region with a memory protection key
+        ProtectKey(base, size, key) = 7,
     }
 }
 
-/// Flags for the fs related system call
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
-#[repr(u64)]
-pub enum FileOperation {
-    /// Create a file
-    Create = 1,
-    /// Open a file
-    Open = 2,
-    /// Read from a file
-    Read = 3,
-    /// Read from a file from the given offset
-    ReadAt = 4,
-    /// Write to a file
-    Write = 5,
-    /// Write to a file
-    WriteAt = 6,
-    /// Close an opened file.
-    Close = 7,
-    /// Get the information related to the file.
-    GetInfo = 8,
-    /// Delete the file
-    Delete = 9,
-    /// Write to a file without going into NR.
-    WriteDirect = 10,
-    /// Rename a file.
-    FileRename = 11,
-    /// Create a directory.
-    MkDir = 12,
-    Unknown,
+operations! {
+    /// Flags for the fs related system call
+    pub enum FileOperation for SystemCall::FileIO as FileRequest {
+        /// Create a file
+        Create(pathname, modes) = 1,
+        /// Open a file
+        Open(pathname, flags, modes) = 2,
+        /// Read from a file
+        Read(fd, buffer, len) = 3,
+        /// Read from a file from the given offset
+        ReadAt(fd, buffer, len, offset) = 4,
+        /// Write to a file
+        Write(fd, buffer, len) = 5,
+        /// Write to a file
+        WriteAt(fd, buffer, len, offset) = 6,
+        /// Close an opened file.
+        Close(fd) = 7,
+        /// Get the information related to the file.
+        GetInfo(name, info) = 8,
+        /// Delete the file
+        Delete(name) = 9,
+        /// Write to a file without going into NR.
+        WriteDirect(buffer, len, offset, has_offset) = 10,
+        /// Rename a file.
+        FileRename(old_name, new_name) = 11 as "Rename",
+        /// Create a directory.
+        MkDir(pathname, modes) = 12,
+        /// Map (a part of) a file read-only into the address space.
+        Mmap(fd, base, len, offset) = 13,
+        /// Remove a file mapping.
+        Munmap(base, len) = 14,
+        /// List the entries of a directory.
+        ReadDir(pathname, buf, len) = 15,
+        /// Change the offset of a file descriptor (lseek).
+        Seek(fd, offset, whence) = 16,
+        /// Create an anonymous pipe.
+        Pipe() = 17,
+        /// Reserve memory for a range of a file (fallocate).
+        Allocate(fd, offset, len) = 18,
+    }
 }
 
-impl From<u64> for FileOperation {
-    /// Construct a SystemCall enum based on a 64-bit value.
-    fn from(op: u64) -> FileOperation {
-        match op {
-            1 => FileOperation::Create,
-            2 => FileOperation::Open,
-            3 => FileOperation::Read,
-            4 => FileOperation::ReadAt,
-            5 => FileOperation::Write,
-            6 => FileOperation::WriteAt,
-            7 => FileOperation::Close,
-            8 => FileOperation::GetInfo,
-            9 => FileOperation::Delete,
-            10 => FileOperation::WriteDirect,
-            11 => FileOperation::FileRename,
-            12 => FileOperation::MkDir,
-            _ => FileOperation::Unknown,
-        }
+operations! {
+    /// Operations that query/set system-wide information.
+    pub enum SystemOperation for SystemCall::System as SystemRequest {
+        /// Query information about available hardware threads in the system
+        GetHardwareThreads(buf, len) = 1,
+        /// Print system/per-core info.
+        Stats(buf, len) = 2,
+        /// Get the core id for the current thread.
+        GetCoreID() = 3,
+        /// Suspend the machine to RAM (ACPI S3), returns after wake-up.
+        Suspend() = 4,
+        /// Soft-reboot into the kernel binary of a boot module.
+        KexecModule(name, len) = 5,
+        /// Soft-reboot into a kernel binary supplied by the process.
+        KexecImage(image, len) = 6,
+        /// Query free and total memory per NUMA node.
+        MemoryStats(buf, len) = 7,
+        /// Read the most recent records of the kernel log.
+        ReadKernelLog(buf, len) = 8,
+        /// Fill a buffer with random bytes.
+        GetRandom(buf, len) = 9,
+        /// Map (or with base 0, remove) the event ring of the process (see `eventring`).
+        OpenEventRing(base, topics) = 10,
+        /// Ask for an upcall once the event ring has new events.
+        ArmEventRing(seen) = 11,
+        /// Do nothing (to measure the system call entry/exit cost).
+        Null() = 12,
+        /// Get the id of the executor the calling thread runs on.
+        GetThreadId() = 13,
+        /// Look up the interrupt line (GSI) an interrupt pin of a PCI device
+        /// is connected to (as routed by the ACPI tables).
+        PciInterruptLine(location, pin) = 14,
+        /// Set the L3 way mask of a class of service (Intel CAT), returns the
+        /// number of classes and ways.
+        CacheAllocation(clos, mask) = 15,
+        /// Put a process into a class of service.
+        CacheClass(pid, clos) = 16,
+        /// Put a core into a class of service (for the processes that
+        /// aren't in one).
+        CoreCacheClass(gtid, clos) = 17,
     }
 }
 
-impl From<&str> for FileOperation {
-    /// Construct a FileOperation enum based on a str.
-    fn from(op: &str) -> FileOperation {
-        match op {
-            "Create" => FileOperation::Create,
-            "Open" => FileOperation::Open,
-            "Read" => FileOperation::Read,
-            "ReadAt" => FileOperation::ReadAt,
-            "Write" => FileOperation::Write,
-            "WriteAt" => FileOperation::WriteAt,
-            "Close" => FileOperation::Close,
-            "GetInfo" => FileOperation::GetInfo,
-            "Delete" => FileOperation::Delete,
-            "WriteDirect" => FileOperation::WriteDirect,
-            "Rename" => FileOperation::FileRename,
-            "MkDir" => FileOperation::MkDir,
-            _ => FileOperation::Unknown,
-        }
+operations! {
+    /// Which resource `ProcessOperation::GetUsage` reports.
+    pub enum UsageKind {
+        /// CPU time (cycles and dispatches).
+        Cpu = 1,
+        /// Bytes stored in files (and the quota of the process).
+        FileSystem = 2,
+        /// L3 cache occupancy and memory traffic (in the package of the core
+        /// that asks).
+        Cache = 3,
     }
 }
 
-/// Operations that query/set system-wide information.
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
-#[repr(u64)]
-pub enum SystemOperation {
-    /// Query information about available hardware threads in the system
-    GetHardwareThreads = 1,
-    /// Print system/per-core info.
-    Stats = 2,
-    /// Get the core id for the current thread.
-    GetCoreID = 3,
-    Unknown,
+operations! {
+    /// Operations on the asynchronous system call ring (see `asyncio`).
+    pub enum AsyncOperation for SystemCall::Async as AsyncRequest {
+        /// Register (or with base 0, remove) the ring of the process.
+        Setup(base, entries) = 1,
+        /// Execute the pending submissions of the ring right away.
+        Enter() = 2,
+    }
 }
 
-impl From<u64> for SystemOperation {
-    /// Construct a SystemCall enum based on a 64-bit value.
-    fn from(op: u64) -> SystemOperation {
-        match op {
-            1 => SystemOperation::GetHardwareThreads,
-            2 => SystemOperation::Stats,
-            3 => SystemOperation::GetCoreID,
-            _ => SystemOperation::Unknown,
-        }
+operations! {
+    /// Operations on PCI devices (see `syscalls::Device`).
+    ///
+    /// Devices are identified by the handle `Claim` returns.
+    pub enum DeviceOperation for SystemCall::Device as DeviceRequest {
+        /// Claim the n-th device with a vendor and device id.
+        Claim(ids, index) = 1,
+        /// Give a claimed device back.
+        Release(handle) = 2,
+        /// Query a base address register (BAR) of the device.
+        Bar(handle, index) = 3,
+        /// Map a memory BAR of the device into the address space.
+        MapBar(handle, index) = 4,
+        /// Read a register of the configuration space of the device.
+        ConfigRead(handle, offset) = 5,
+        /// Write a register of the configuration space of the device.
+        ConfigWrite(handle, offset, value) = 6,
+        /// Give a device to another process (only the initial process).
+        Assign(ids, index, pid) = 7,
     }
 }
 
-impl From<&str> for SystemOperation {
-    /// Construct a SystemOperation enum based on a str.
-    fn from(op: &str) -> SystemOperation {
-        match op {
-            "GetHardwareThreads" => SystemOperation::GetHardwareThreads,
-            "Stats" => SystemOperation::Stats,
-            "GetCoreID" => SystemOperation::GetCoreID,
-            _ => SystemOperation::Unknown,
-        }
+#[cfg(test)]
+#[test]
+fn operation_tables() {
+    assert_eq!(FileOperation::from(11), FileOperation::FileRename);
+    assert_eq!(FileOperation::from("Rename"), FileOperation::FileRename);
+    assert_eq!(FileOperation::from("FileRename"), FileOperation::Unknown);
+    assert_eq!(FileOperation::from(0), FileOperation::Unknown);
+    assert_eq!(FileOperation::from(13), FileOperation::Mmap);
+    assert_eq!(FileOperation::from(14), FileOperation::Munmap);
+    assert_eq!(FileOperation::from(15), FileOperation::ReadDir);
+    assert_eq!(FileOperation::from(16), FileOperation::Seek);
+    assert_eq!(FileOperation::from(17), FileOperation::Pipe);
+    assert_eq!(FileOperation::from(18), FileOperation::Allocate);
+    assert_eq!(FileOperation::from(19), FileOperation::Unknown);
+
+    for op in 1..=31 {
+        assert_eq!(ProcessOperation::from(op) as u64, op);
+    }
+    assert_eq!(ProcessOperation::from(32), ProcessOperation::Unknown);
+    assert_eq!(ProcessOperation::from("GetProcessInfo"), ProcessOperation::GetProcessInfo);
+    assert_eq!(SystemOperation::from("Stats"), SystemOperation::Stats);
+    assert_eq!(SystemOperation::from(8), SystemOperation::ReadKernelLog);
+    assert_eq!(SystemOperation::from(9), SystemOperation::GetRandom);
+    assert_eq!(SystemOperation::from(11), SystemOperation::ArmEventRing);
+    assert_eq!(SystemOperation::from(12), SystemOperation::Null);
+    assert_eq!(SystemOperation::from(13), SystemOperation::GetThreadId);
+    assert_eq!(SystemOperation::from(14), SystemOperation::PciInterruptLine);
+    assert_eq!(SystemOperation::from(15), SystemOperation::CacheAllocation);
+    assert_eq!(SystemOperation::from(17), SystemOperation::CoreCacheClass);
+    assert_eq!(SystemOperation::from(18), SystemOperation::Unknown);
+    assert_eq!(VSpaceOperation::from(7), VSpaceOperation::ProtectKey);
+    assert_eq!(VSpaceOperation::from(8), VSpaceOperation::Unknown);
+    assert_eq!(AsyncOperation::from(2), AsyncOperation::Enter);
+    assert_eq!(AsyncOperation::from(3), AsyncOperation::Unknown);
+    assert_eq!(DeviceOperation::from(4), DeviceOperation::MapBar);
+    assert_eq!(DeviceOperation::from(7), DeviceOperation::Assign);
+    assert_eq!(DeviceOperation::from(8), DeviceOperation::Unknown);
+    assert_eq!(UsageKind::from(2), UsageKind::FileSystem);
+    assert_eq!(UsageKind::from(3), UsageKind::Cache);
+    assert_eq!(UsageKind::from(0), UsageKind::Unknown);
+
+    assert_eq!(ProcessOperation::SYSCALL, SystemCall::Process);
+    assert_eq!(FileOperation::SYSCALL, SystemCall::FileIO);
+    assert_eq!(DeviceOperation::SYSCALL, SystemCall::Device);
+}
+
+#[cfg(test)]
+#[test]
+fn operation_requests() {
+    let request = FileRequest::ReadAt {
+        fd: 3,
+        buffer: 0x1000,
+        len: 16,
+        offset: 8,
+    };
+    let (op, args) = request.encode();
+    assert_eq!(op, FileOperation::ReadAt as u64);
+    assert_eq!(args, [3, 0x1000, 16, 8]);
+    assert_eq!(FileRequest::decode(op, args), request);
+    assert_eq!(request.operation(), FileOperation::ReadAt);
+
+    // Arguments an operation doesn't have are ignored
+    assert_eq!(
+        ProcessRequest::decode(ProcessOperation::Log as u64, [1, 2, 3, 4]),
+        ProcessRequest::Log { buf: 1, len: 2 }
+    );
+    assert_eq!(
+        ProcessRequest::decode(ProcessOperation::Fork as u64, [1, 2, 3, 4]).encode(),
+        (ProcessOperation::Fork as u64, [0; 4])
+    );
+    assert_eq!(
+        SystemRequest::decode(0, [1, 2, 3, 4]),
+        SystemRequest::Unknown(0)
+    );
+    assert_eq!(SystemRequest::Unknown(99).operation(), SystemOperation::Unknown);
+
+    let submission = VSpaceRequest::Map {
+        base: 0x2000,
+        size: 0x1000,
     }
+    .submission(7);
+    assert_eq!(submission.syscall, SystemCall::VSpace as u64);
+    assert_eq!(
+        submission.args,
+        [VSpaceOperation::Map as u64, 0x2000, 0x1000, 0, 0]
+    );
+    assert_eq!(submission.user_data, 7);
 }
 
 /// SystemCall is the type of call we are invoking.
@@ -295,6 +672,8 @@ pub enum SystemCall {
     Process = 2,
     VSpace = 3,
     FileIO = 4,
+    Async = 5,
+    Device = 6,
     Unknown,
 }
 
@@ -306,6 +685,8 @@ impl SystemCall {
             2 => SystemCall::Process,
             3 => SystemCall::VSpace,
             4 => SystemCall::FileIO,
+            5 => SystemCall::Async,
+            6 => SystemCall::Device,
             _ => SystemCall::Unknown,
         }
     }
@@ -319,6 +700,8 @@ impl From<&str> for SystemCall {
             "Process" => SystemCall::Process,
             "VSpace" => SystemCall::VSpace,
             "FileIO" => SystemCall::FileIO,
+            "Async" => SystemCall::Async,
+            "Device" => SystemCall::Device,
             _ => SystemCall::Unknown,
         }
     }
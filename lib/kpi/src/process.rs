@@ -1,8 +1,51 @@
-use core::convert::TryInto;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bitflags::*;
+use core::convert::{TryFrom, TryInto};
 use serde::{Deserialize, Serialize};
 
+use crate::SystemCallError;
+
 pub type FrameId = usize;
 
+bitflags! {
+    /// Access rights of mapped memory (see `VSpaceOperation::Protect`).
+    ///
+    /// An empty set makes the region inaccessible (e.g., for a guard page).
+    /// Pages are always readable if they are writable or executable.
+    pub struct MemoryRights: u64 {
+        const NONE = 0x0;
+        const READ = 0x1;
+        const WRITE = 0x2;
+        const EXECUTE = 0x4;
+    }
+}
+
+/// Scheduling weight of a process with default priority (see
+/// `ProcessOperation::SetWeight`).
+pub const DEFAULT_WEIGHT: u64 = 1024;
+
+/// Largest scheduling weight a process can have.
+pub const MAX_WEIGHT: u64 = 64 * DEFAULT_WEIGHT;
+
+/// Exit code of the processes that were terminated with
+/// `ProcessOperation::TerminateGroup`.
+pub const TERMINATED_EXIT_CODE: u64 = 0x89;
+
+/// Exit code of the processes that vibrio terminates because of a
+/// page-fault it can't handle (`upcall::PAGE_FAULT`).
+pub const PAGE_FAULT_EXIT_CODE: u64 = 0x8b;
+
+/// Exit code of the processes that the kernel terminates because their
+/// shadow stack didn't match a return (see
+/// `ProcessOperation::EnableShadowStack`).
+pub const CONTROL_PROTECTION_EXIT_CODE: u64 = 0x8c;
+
+/// `ProcessOperation::Spawn` passes the `SchedulingPolicy` of the new
+/// process in the bits of the argument length from this one on (all system
+/// call registers are taken by the other arguments).
+pub const SPAWN_POLICY_SHIFT: u64 = 56;
+
 #[derive(Debug)]
 pub struct CoreToken(usize);
 
@@ -13,6 +56,85 @@ impl CoreToken {
     }
 }
 
+/// CPU time used by a process (see `ProcessOperation::GetUsage`).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ProcessUsage {
+    /// Cycles the executors of the process ran in user-space.
+    pub cpu_cycles: u64,
+    /// How many times the kernel dispatched an executor of the process.
+    pub dispatches: u64,
+}
+
+/// Bytes stored in files by a process (see `UsageKind::FileSystem`).
+///
+/// Files are charged to the process that created them.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FsUsage {
+    /// Bytes in the files of the process.
+    pub bytes: u64,
+    /// How many bytes the process may store (None if it's unlimited).
+    pub quota: Option<u64>,
+}
+
+/// L3 cache and memory bandwidth usage of a process (see `UsageKind::Cache`).
+///
+/// Both are measured in the package of the core that asks.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CacheUsage {
+    /// Bytes of the L3 cache the process occupies.
+    pub llc_occupancy: u64,
+    /// Bytes the process read from and wrote to memory since it was first
+    /// queried (the difference of two samples gives the bandwidth).
+    pub memory_traffic: u64,
+}
+
+/// A process in the system (see `ProcessOperation::ListProcesses`).
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProcessEntry {
+    /// Process ID.
+    pub pid: u64,
+    /// Name of the boot module the process was started from.
+    pub binary: String,
+    /// Process group the process is in.
+    pub group: u64,
+    /// How the executors of the process are scheduled.
+    pub policy: SchedulingPolicy,
+    /// Cores that currently run an executor of the process.
+    pub cores: Vec<usize>,
+    /// Bytes the process printed.
+    pub printed_bytes: u64,
+    /// Bytes the process printed over its output limit (and didn't show up).
+    pub dropped_bytes: u64,
+}
+
+/// How the kernel schedules the executors of a process (selected at spawn).
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SchedulingPolicy {
+    /// Every core fairly time-slices between the executors assigned to it.
+    Fair = 0,
+    /// All executors of the process are dispatched together on their
+    /// cores (for tightly coupled parallel workloads).
+    Gang = 1,
+}
+
+impl TryFrom<u64> for SchedulingPolicy {
+    type Error = SystemCallError;
+
+    fn try_from(policy: u64) -> Result<SchedulingPolicy, SystemCallError> {
+        match policy {
+            0 => Ok(SchedulingPolicy::Fair),
+            1 => Ok(SchedulingPolicy::Gang),
+            _ => Err(SystemCallError::BadFlags),
+        }
+    }
+}
+
+impl Default for SchedulingPolicy {
+    fn default() -> SchedulingPolicy {
+        SchedulingPolicy::Fair
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct ProcessInfo {
     pub has_tls: bool,
@@ -28,6 +150,10 @@ pub struct ProcessInfo {
     pub cmdline: &'static str,
     /// App command line argument buffer
     pub app_cmdline: &'static str,
+    /// How the executors of the process are scheduled
+    pub policy: SchedulingPolicy,
+    /// Tuning knobs for the user-space allocator
+    pub malloc_conf: &'static str,
 }
 
 #[cfg(test)]
@@ -47,3 +173,17 @@ fn serialize() {
     log::info!("serialized.len = {}", serialized.len());
     log::info!("deserialized = {:?}", deserialized);
 }
+
+#[cfg(test)]
+#[test]
+fn spawn_policy() {
+    for policy in [SchedulingPolicy::Fair, SchedulingPolicy::Gang].iter() {
+        let args_len = 42 | ((*policy as u64) << SPAWN_POLICY_SHIFT);
+        assert_eq!(
+            SchedulingPolicy::try_from(args_len >> SPAWN_POLICY_SHIFT),
+            Ok(*policy)
+        );
+        assert_eq!(args_len & ((1 << SPAWN_POLICY_SHIFT) - 1), 42);
+    }
+    assert!(SchedulingPolicy::try_from(2).is_err());
+}
@@ -0,0 +1,35 @@
+//! System calls to manage the asynchronous system call ring of a process.
+
+use crate::*;
+
+/// System calls related to asynchronous system call rings.
+pub struct Async;
+
+impl Async {
+    /// Register the ring at `base` with `entries` entries (see
+    /// `asyncio::ring_size` for how much memory it needs).
+    ///
+    /// The header of the ring has to be zeroed. A process has one ring at
+    /// most, a `base` of 0 removes it.
+    pub unsafe fn setup(base: u64, entries: u64) -> Result<(), SystemCallError> {
+        let r = AsyncRequest::Setup { base, entries }.call1();
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Have the kernel execute the pending submissions, returns how many
+    /// completed.
+    pub fn enter() -> Result<u64, SystemCallError> {
+        let (r, completed) = unsafe { AsyncRequest::Enter {}.call2() };
+
+        if r == 0 {
+            Ok(completed)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
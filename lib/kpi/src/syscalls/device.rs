@@ -0,0 +1,133 @@
+//! System calls to access PCI devices from user-space drivers.
+
+use core::ops::Range;
+
+use crate::io::PciBar;
+use crate::*;
+
+/// System calls related to PCI devices.
+pub struct Device;
+
+impl Device {
+    /// Claim the `index`-th device with `vendor_id` and `device_id` (the
+    /// kernel enumerates the PCI bus at boot), returns a handle for it.
+    ///
+    /// A device can only be claimed by one process at a time.
+    pub fn claim(vendor_id: u16, device_id: u16, index: u64) -> Result<u64, SystemCallError> {
+        let (r, handle) = unsafe {
+            DeviceRequest::Claim {
+                ids: (vendor_id as u64) << 16 | device_id as u64,
+                index,
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(handle)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Give a claimed device back.
+    pub fn release(handle: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { DeviceRequest::Release { handle }.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Base address register `index` (0 to 5) of the device.
+    pub fn bar(handle: u64, index: u64) -> Result<PciBar, SystemCallError> {
+        let (r, base, size) = unsafe { DeviceRequest::Bar { handle, index }.call3() };
+
+        if r == 0 {
+            Ok(PciBar::from_encoded(base, size))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Map the memory region of BAR `index` of the device.
+    ///
+    /// The region is mapped at its physical address (like
+    /// `VSpace::map_device`), returns the (page-aligned) base and size of
+    /// the mapping.
+    pub unsafe fn map_bar(handle: u64, index: u64) -> Result<(u64, u64), SystemCallError> {
+        let (r, base, size) = DeviceRequest::MapBar { handle, index }.call3();
+
+        if r == 0 {
+            Ok((base, size))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Read the (aligned) dword at `offset` in the configuration space of
+    /// the device.
+    pub fn config_read(handle: u64, offset: u64) -> Result<u32, SystemCallError> {
+        let (r, value) = unsafe { DeviceRequest::ConfigRead { handle, offset }.call2() };
+
+        if r == 0 {
+            Ok(value as u32)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Write the (aligned) dword at `offset` in the configuration space of
+    /// the device. The BARs can't be changed.
+    pub fn config_write(handle: u64, offset: u64, value: u32) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            DeviceRequest::ConfigWrite {
+                handle,
+                offset,
+                value: value as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Assign the `index`-th device with `vendor_id` and `device_id` to
+    /// process `pid` (only the initial process can do this), returns the
+    /// handle of the device and its MSI-X vectors.
+    ///
+    /// The kernel claims the device for `pid`, maps its memory BARs into it
+    /// (at their physical address) and, if the device has MSI-X, routes one
+    /// vector to every core the process runs on. The process has to have
+    /// its cores before it gets the device.
+    ///
+    /// There is no IOMMU support: the device can still access all of the
+    /// physical memory with DMA.
+    pub fn assign(
+        pid: u64,
+        vendor_id: u16,
+        device_id: u16,
+        index: u64,
+    ) -> Result<(u64, Range<u64>), SystemCallError> {
+        let (r, handle, vectors) = unsafe {
+            DeviceRequest::Assign {
+                ids: (vendor_id as u64) << 16 | device_id as u64,
+                index,
+                pid,
+            }
+            .call3()
+        };
+
+        if r == 0 {
+            let (first, count) = (vectors & 0xffff_ffff, vectors >> 32);
+            Ok((handle, first..first + count))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -1,28 +1,89 @@
 //! Abstraction for system calls to access the global file-system and control interrupts.
 
+use alloc::vec::Vec;
+
 use crate::io::*;
 use crate::*;
 
-use crate::syscall;
-
 /// System calls related to interrupt routing.
 pub struct Irq;
 
 impl Irq {
-    /// Manipulate the CPU interrupt alloction table.
-    pub fn irqalloc(vec: u64, core: u64) -> Result<(), SystemCallError> {
-        let (r, retvec, retcore) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::AllocateVector as u64,
-                vec,
+    /// Routes interrupt line `gsi` (not the vector it arrives with, see
+    /// `io::IRQ_VECTOR_BASE`) to `core`.
+    pub fn irqalloc(gsi: u64, core: u64) -> Result<(), SystemCallError> {
+        let (r, retgsi, retcore) =
+            unsafe { ProcessRequest::AllocateVector { vector: gsi, core }.call3() };
+
+        assert_eq!(gsi, retgsi);
+        assert_eq!(core, retcore);
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// The interrupt line (GSI) that interrupt `pin` (0 for INTA# to 3 for
+    /// INTD#) of PCI `device` on `bus` is connected to.
+    ///
+    /// The line can be allocated with `irqalloc`.
+    pub fn pci_interrupt_line(bus: u8, device: u8, pin: u8) -> Result<u64, SystemCallError> {
+        let (r, gsi) = unsafe {
+            SystemRequest::PciInterruptLine {
+                location: (bus as u64) << 8 | device as u64,
+                pin: pin as u64,
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(gsi)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Allocates `count` consecutive MSI or MSI-X vectors for PCI `function`
+    /// of `device` on `bus` which are delivered to `core`.
+    ///
+    /// The device has to be claimed (`DeviceOperation::Claim`) by the process
+    /// first, a process can have at most 32 MSI vectors.
+    ///
+    /// Returns the first vector and the message address. The driver
+    /// programs the MSI capability (or the MSI-X table entries) of the
+    /// device with that address and the vector as message data, and
+    /// registers its handlers for the vectors in vibrio.
+    pub fn allocate_msi(
+        bus: u8,
+        device: u8,
+        function: u8,
+        core: u64,
+        count: u64,
+    ) -> Result<(u64, u64), SystemCallError> {
+        let pci_function = (bus as u64) << 8 | (device as u64) << 3 | function as u64;
+        let (r, vector, address) = unsafe {
+            ProcessRequest::AllocateMsiVectors {
+                function: pci_function,
                 core,
-                3
-            )
+                count,
+            }
+            .call3()
         };
 
-        assert_eq!(vec, retvec);
-        assert_eq!(core, retcore);
+        if r == 0 {
+            Ok((vector, address))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Route interrupt line `gsi` (previously allocated with `irqalloc`)
+    /// to a different core.
+    pub fn steer(gsi: u64, core: u64) -> Result<(), SystemCallError> {
+        let (r, _retgsi, _retcore) =
+            unsafe { ProcessRequest::SteerVector { vector: gsi, core }.call3() };
 
         if r == 0 {
             Ok(())
@@ -46,14 +107,12 @@ impl Fs {
     /// Open a file. Return `fd` if successful; error otherwise.
     pub fn open(pathname: u64, flags: u64, modes: u64) -> Result<u64, SystemCallError> {
         let (r, fd) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::Open as u64,
+            FileRequest::Open {
                 pathname,
                 flags,
                 modes,
-                2
-            )
+            }
+            .call2()
         };
 
         if r == 0 {
@@ -66,7 +125,7 @@ impl Fs {
     /// Close a file. This function will remove the file descriptor from the process.
     /// It doesn't do anything to the file.
     pub fn close(fd: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::Close, fd, 1) };
+        let r = unsafe { FileRequest::Close { fd }.call1() };
 
         if r == 0 {
             Ok(r)
@@ -79,8 +138,10 @@ impl Fs {
         Fs::fileio(FileOperation::Read, fd, buffer, len)
     }
 
+    /// Write `[buffer, buffer+len)` to `fd` (at its offset), returns how
+    /// much was written (less than `len` only if `fd` doesn't take more).
     pub fn write(fd: u64, buffer: u64, len: u64) -> Result<u64, SystemCallError> {
-        Fs::fileio(FileOperation::Write, fd, buffer, len)
+        Fs::write_all(fd, buffer, len, -1)
     }
 
     /// Read or write an opened file. `fd` is the file descriptor for the opened file.
@@ -89,8 +150,12 @@ impl Fs {
             return Err(SystemCallError::BadFileDescriptor);
         }
 
-        let (r, len) =
-            unsafe { syscall!(SystemCall::FileIO as u64, op as u64, fd, buffer, len, 2) };
+        let request = match op {
+            FileOperation::Read => FileRequest::Read { fd, buffer, len },
+            FileOperation::Write => FileRequest::Write { fd, buffer, len },
+            _ => unreachable!("fileio received non read/write op"),
+        };
+        let (r, len) = unsafe { request.call2() };
 
         if r == 0 {
             Ok(len)
@@ -104,7 +169,87 @@ impl Fs {
     }
 
     pub fn write_at(fd: u64, buffer: u64, len: u64, offset: i64) -> Result<u64, SystemCallError> {
-        Fs::fileio_at(FileOperation::WriteAt, fd, buffer, len, offset)
+        Fs::write_all(fd, buffer, len, offset)
+    }
+
+    /// The kernel writes large buffers in several steps: it returns early if
+    /// it has to give the core back and we continue with the rest.
+    fn write_all(fd: u64, buffer: u64, len: u64, offset: i64) -> Result<u64, SystemCallError> {
+        let mut written = 0;
+        loop {
+            let r = if offset == -1 {
+                Fs::fileio(FileOperation::Write, fd, buffer + written, len - written)
+            } else {
+                Fs::fileio_at(
+                    FileOperation::WriteAt,
+                    fd,
+                    buffer + written,
+                    len - written,
+                    offset + written as i64,
+                )
+            };
+            match r {
+                Ok(0) => return Ok(written),
+                Ok(len_written) => written += len_written,
+                // What we wrote so far stays written
+                Err(_e) if written > 0 => return Ok(written),
+                Err(e) => return Err(e),
+            }
+            if written >= len {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Change the offset of `fd` (like lseek), returns the new offset.
+    ///
+    /// The offset is used (and advanced) by `read` and `write`.
+    pub fn lseek(fd: u64, offset: i64, whence: SeekWhence) -> Result<u64, SystemCallError> {
+        let (r, new_offset) = unsafe {
+            FileRequest::Seek {
+                fd,
+                offset: offset as u64,
+                whence: whence as u64,
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(new_offset)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Make sure the file has memory for `[offset, offset+len)`, the file
+    /// grows (with zeros) if it is shorter (like posix_fallocate).
+    ///
+    /// Fails with `SystemCallError::OutOfMemory` if there isn't enough
+    /// memory, writes to the range can't run out of memory afterwards.
+    pub fn allocate(fd: u64, offset: u64, len: u64) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe { FileRequest::Allocate { fd, offset, len }.call2() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Create an anonymous pipe, returns the file descriptors of the
+    /// read and the write end.
+    ///
+    /// `read` and `write` on a pipe don't block, they return
+    /// `SystemCallError::WouldBlock` if the pipe is empty (or full).
+    /// `read` returns 0 once the pipe is empty and the write end is closed.
+    pub fn pipe() -> Result<(u64, u64), SystemCallError> {
+        let (r, read_fd, write_fd) = unsafe { FileRequest::Pipe {}.call3() };
+
+        if r == 0 {
+            Ok((read_fd, write_fd))
+        } else {
+            Err(SystemCallError::from(r))
+        }
     }
 
     /// Read or write an opened file starting at the offset.
@@ -128,17 +273,22 @@ impl Fs {
         }
 
         // If read or write is performed at the specific offset.
-        let (r, len) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                op as u64,
+        let offset = offset as u64;
+        let request = match op {
+            FileOperation::ReadAt => FileRequest::ReadAt {
+                fd,
+                buffer,
+                len,
+                offset,
+            },
+            _ => FileRequest::WriteAt {
                 fd,
                 buffer,
                 len,
-                offset as u64,
-                2
-            )
+                offset,
+            },
         };
+        let (r, len) = unsafe { request.call2() };
 
         if r == 0 {
             Ok(len)
@@ -151,13 +301,11 @@ impl Fs {
     pub fn getinfo(name: u64) -> Result<FileInfo, SystemCallError> {
         let fileinfo: FileInfo = Default::default();
         let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::GetInfo,
-                name as u64,
-                &fileinfo as *const FileInfo as u64,
-                1
-            )
+            FileRequest::GetInfo {
+                name: name as u64,
+                info: &fileinfo as *const FileInfo as u64,
+            }
+            .call1()
         };
 
         if r == 0 {
@@ -169,14 +317,7 @@ impl Fs {
 
     /// Delete a file given by `name`.
     pub fn delete(name: u64) -> Result<bool, SystemCallError> {
-        let (r, is_deleted) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::Delete as u64,
-                name,
-                2
-            )
-        };
+        let (r, is_deleted) = unsafe { FileRequest::Delete { name }.call2() };
 
         if r == 0 && is_deleted == 0 {
             Ok(true)
@@ -196,15 +337,13 @@ impl Fs {
 
         // If read or write is performed at the specific offset.
         let (r, len) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::WriteDirect as u64,
+            FileRequest::WriteDirect {
                 buffer,
                 len,
-                offset as u64,
-                is_offset,
-                2
-            )
+                offset: offset as u64,
+                has_offset: is_offset as u64,
+            }
+            .call2()
         };
 
         if r == 0 {
@@ -215,16 +354,51 @@ impl Fs {
     }
 
     pub fn rename(old_name: u64, new_name: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::FileRename,
-                old_name,
-                new_name,
-                1
-            )
+        let r = unsafe { FileRequest::FileRename { old_name, new_name }.call1() };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Map `len` bytes of the file `fd` (opened for reading) starting at
+    /// `offset` read-only at address `base`.
+    ///
+    /// `base` and `offset` have to be page-aligned and `[base, base+len)`
+    /// must not be mapped already. The mapping shares the pages of the file:
+    /// it shows every change made with `write` (the file can't be written
+    /// through the mapping). It can't go past the last page of the file
+    /// (`allocate` grows a file) and the file can't be truncated or deleted
+    /// while it is mapped. Returns the number of bytes that were mapped.
+    pub fn mmap(fd: u64, base: u64, len: u64, offset: i64) -> Result<u64, SystemCallError> {
+        if len == 0 || offset < 0 {
+            return Err(SystemCallError::OffsetError);
+        }
+
+        let (r, len) = unsafe {
+            FileRequest::Mmap {
+                fd,
+                base,
+                len,
+                offset: offset as u64,
+            }
+            .call2()
         };
 
+        if r == 0 {
+            Ok(len)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Unmap the mappings `mmap` established in `[base, base+len)`, the range
+    /// has to cover them completely.
+    pub fn munmap(base: u64, len: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe { FileRequest::Munmap { base, len }.call1() };
+
         if r == 0 {
             Ok(0)
         } else {
@@ -233,15 +407,7 @@ impl Fs {
     }
 
     pub fn mkdir_simple(pathname: u64, modes: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::MkDir,
-                pathname,
-                modes,
-                1
-            )
-        };
+        let r = unsafe { FileRequest::MkDir { pathname, modes }.call1() };
 
         if r == 0 {
             Ok(0)
@@ -249,4 +415,35 @@ impl Fs {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// List the entries of the directory given by `pathname`.
+    pub fn readdir(pathname: u64) -> Result<Vec<DirEntry>, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                FileRequest::ReadDir {
+                    pathname,
+                    buf: buf.as_mut_ptr() as u64,
+                    len: buf.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            // The kernel tells us how much space it needs if the buffer
+            // was too small, try again with a bigger one:
+            let len = len as usize;
+            if len > buf.len() {
+                buf.resize(len, 0);
+                continue;
+            }
+
+            buf.truncate(len);
+            let entries: Vec<DirEntry> = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(entries);
+        }
+    }
 }
@@ -2,19 +2,38 @@
 
 use core::convert::TryInto;
 
-use crate::process::FrameId;
+use crate::process::{FrameId, MemoryRights};
 use crate::*;
 
-use crate::syscall;
-
 use x86::bits64::paging::{PAddr, VAddr};
 
 /// System calls to manipulate the process' address-space.
 pub struct VSpace;
 
 impl VSpace {
+    /// Map (zeroed) memory at `[base, base+bound)`.
+    ///
+    /// The kernel maps large regions in several steps: it returns early if
+    /// it has to give the core back and we continue with the rest. If that
+    /// fails, the part that is already mapped stays mapped.
     pub unsafe fn map(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
-        VSpace::vspace(VSpaceOperation::Map, base, bound)
+        let mut paddr = None;
+        let mut mapped = 0;
+        loop {
+            let (err, chunk_paddr, len) = VSpaceRequest::Map {
+                base: base + mapped,
+                size: bound - mapped,
+            }
+            .call3();
+            if err != 0 {
+                return Err(SystemCallError::from(err));
+            }
+            paddr.get_or_insert(chunk_paddr);
+            mapped += len;
+            if mapped >= bound || len == 0 {
+                return Ok((VAddr::from(base), PAddr::from(paddr.unwrap_or(0))));
+            }
+        }
     }
 
     pub unsafe fn unmap(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
@@ -30,13 +49,7 @@ impl VSpace {
         base: u64,
     ) -> Result<(VAddr, PAddr), SystemCallError> {
         let frame_id: u64 = frame_id.try_into().unwrap();
-        let (err, paddr, _size) = syscall!(
-            SystemCall::VSpace as u64,
-            VSpaceOperation::MapFrame as u64,
-            base,
-            frame_id,
-            3
-        );
+        let (err, paddr, _size) = VSpaceRequest::MapFrame { base, frame_id }.call3();
 
         if err == 0 {
             Ok((VAddr::from(base), PAddr::from(paddr)))
@@ -49,13 +62,60 @@ impl VSpace {
         VSpace::vspace(VSpaceOperation::Identify, base, 0)
     }
 
+    /// Change the access rights of the mapped region `[base, base+bound)`.
+    ///
+    /// The region has to start at a mapping and cover whole mappings.
+    pub unsafe fn protect(
+        base: u64,
+        bound: u64,
+        rights: MemoryRights,
+    ) -> Result<(), SystemCallError> {
+        let err = VSpaceRequest::Protect {
+            base,
+            size: bound,
+            rights: rights.bits(),
+        }
+        .call1();
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Tag the mapped region `[base, base+bound)` with memory protection
+    /// `key` (allocated with `Process::allocate_key`, 0 removes the tag).
+    ///
+    /// The region has to start at a mapping and cover whole mappings.
+    pub unsafe fn protect_key(base: u64, bound: u64, key: u16) -> Result<(), SystemCallError> {
+        let err = VSpaceRequest::ProtectKey {
+            base,
+            size: bound,
+            key: key as u64,
+        }
+        .call1();
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     /// Manipulate the virtual address space.
     unsafe fn vspace(
         op: VSpaceOperation,
         base: u64,
         bound: u64,
     ) -> Result<(VAddr, PAddr), SystemCallError> {
-        let (err, paddr, size) = syscall!(SystemCall::VSpace as u64, op as u64, base, bound, 3);
+        let request = match op {
+            VSpaceOperation::Unmap => VSpaceRequest::Unmap { base, size: bound },
+            VSpaceOperation::MapDevice => VSpaceRequest::MapDevice { base, size: bound },
+            VSpaceOperation::Identify => VSpaceRequest::Identify { base },
+            _ => unreachable!("vspace received {:?}", op),
+        };
+        let (err, paddr, size) = request.call3();
 
         log::trace!(
             "OP={:?} {:#x} -- {:#x} --> {:#x} -- {:#x}",
@@ -83,32 +143,95 @@ pub struct PhysicalMemory;
 
 impl PhysicalMemory {
     pub fn allocate_base_page() -> Result<(FrameId, PAddr), SystemCallError> {
+        PhysicalMemory::allocate_page(x86::current::paging::BASE_PAGE_SIZE)
+    }
+
+    pub fn allocate_large_page() -> Result<(FrameId, PAddr), SystemCallError> {
+        PhysicalMemory::allocate_page(x86::current::paging::LARGE_PAGE_SIZE)
+    }
+
+    /// Allocate a 1 GiB page (map it with `VSpace::map_frame` at a 1 GiB
+    /// aligned address to use a single TLB entry for it).
+    pub fn allocate_huge_page() -> Result<(FrameId, PAddr), SystemCallError> {
+        PhysicalMemory::allocate_page(x86::current::paging::HUGE_PAGE_SIZE)
+    }
+
+    /// Allocate `size` bytes of physically contiguous memory, aligned to
+    /// `alignment` (a power of two), for devices to access with DMA.
+    ///
+    /// The memory is zeroed and mapped read-write, returns its virtual and
+    /// physical address. The kernel rounds the region up to a page (so at
+    /// most 1 GiB can be allocated) and it stays with the process until it
+    /// exits.
+    pub fn allocate_dma_region(
+        size: usize,
+        alignment: usize,
+    ) -> Result<(VAddr, PAddr), SystemCallError> {
         unsafe {
-            let (err, frame_id, paddr) = syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::AllocatePhysical as u64,
-                x86::current::paging::BASE_PAGE_SIZE,
-                3
-            );
+            let (err, vaddr, paddr) = ProcessRequest::AllocateDmaRegion {
+                size: size as u64,
+                alignment: alignment as u64,
+            }
+            .call3();
 
             if err == 0 {
-                debug_assert!(paddr > 0, "Valid PAddr");
-                Ok((frame_id.try_into().unwrap(), PAddr::from(paddr)))
+                Ok((VAddr::from(vaddr), PAddr::from(paddr)))
             } else {
                 Err(SystemCallError::from(err))
             }
         }
     }
 
-    pub fn allocate_large_page() -> Result<(FrameId, PAddr), SystemCallError> {
-        unimplemented!()
+    /// Give a base page back, it can't be mapped anymore (fails with
+    /// `SystemCallError::StillMapped` if it is).
+    pub fn release_base_page(id: FrameId) -> Result<(), SystemCallError> {
+        PhysicalMemory::release_page(id, x86::current::paging::BASE_PAGE_SIZE)
     }
 
-    pub fn release_base_page(_id: FrameId) -> Result<(), SystemCallError> {
-        unimplemented!()
+    /// Give a large page back, it can't be mapped anymore (fails with
+    /// `SystemCallError::StillMapped` if it is).
+    pub fn release_large_page(id: FrameId) -> Result<(), SystemCallError> {
+        PhysicalMemory::release_page(id, x86::current::paging::LARGE_PAGE_SIZE)
     }
 
-    pub fn release_large_page(_id: FrameId) -> Result<(), SystemCallError> {
-        unimplemented!()
+    /// Give a 1 GiB page back, it can't be mapped anymore (fails with
+    /// `SystemCallError::StillMapped` if it is).
+    pub fn release_huge_page(id: FrameId) -> Result<(), SystemCallError> {
+        PhysicalMemory::release_page(id, x86::current::paging::HUGE_PAGE_SIZE)
+    }
+
+    /// Allocate a physical page of `page_size` bytes for the process.
+    fn allocate_page(page_size: usize) -> Result<(FrameId, PAddr), SystemCallError> {
+        unsafe {
+            let (err, frame_id, paddr) = ProcessRequest::AllocatePhysical {
+                page_size: page_size as u64,
+            }
+            .call3();
+
+            if err == 0 {
+                debug_assert!(paddr > 0, "Valid PAddr");
+                Ok((frame_id.try_into().unwrap(), PAddr::from(paddr)))
+            } else {
+                Err(SystemCallError::from(err))
+            }
+        }
+    }
+
+    /// Release the physical page `id` (which has to be `page_size` bytes).
+    fn release_page(id: FrameId, page_size: usize) -> Result<(), SystemCallError> {
+        let frame_id: u64 = id.try_into().unwrap();
+        let r = unsafe {
+            ProcessRequest::ReleasePhysical {
+                frame_id,
+                page_size: page_size as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
     }
 }
@@ -2,12 +2,16 @@
 //!
 //! Code in this module is not linked into the kernel.
 
+mod asyncio;
+mod device;
 mod io;
-mod macros;
+pub(crate) mod macros;
 mod memory;
 mod process;
 mod system;
 
+pub use asyncio::Async;
+pub use device::Device;
 pub use io::{Fs, Irq};
 pub use memory::{PhysicalMemory, VSpace};
 pub use process::Process;
@@ -2,8 +2,13 @@
 
 use crate::*;
 
-use crate::process::{CoreToken, ProcessInfo};
-use crate::syscall;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU64;
+
+use crate::process::{
+    CacheUsage, CoreToken, FsUsage, MemoryRights, ProcessEntry, ProcessInfo, ProcessUsage,
+    SchedulingPolicy, SPAWN_POLICY_SHIFT,
+};
 use crate::x86_64::VirtualCpu;
 
 use x86::bits64::paging::VAddr;
@@ -14,13 +19,11 @@ impl Process {
     /// Request to run on `core_id` starting at `entry_point`.
     pub fn request_core(core_id: usize, entry_point: VAddr) -> Result<CoreToken, SystemCallError> {
         let (r, gtid, _eid) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::RequestCore as u64,
-                core_id as u64,
-                entry_point.as_u64(),
-                3
-            )
+            ProcessRequest::RequestCore {
+                gtid: core_id as u64,
+                entry_point: entry_point.as_u64(),
+            }
+            .call3()
         };
 
         if r == 0 {
@@ -31,16 +34,96 @@ impl Process {
         }
     }
 
+    /// Get a `upcall::TIMER` upcall every `period` rdtsc cycles on this
+    /// core (a `period` of 0 stops them). Like all upcalls these are
+    /// best-effort: we don't get one while upcalls are disabled.
+    pub fn subscribe_timer(period: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::SubscribeEvent {
+                event: crate::upcall::TIMER,
+                value: period,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Subscribe to (or, if `subscribe` is false, unsubscribe from) an
+    /// asynchronous kernel event of the machine (`upcall::CORE_ONLINE`,
+    /// `upcall::LOW_MEMORY`, `upcall::DEVICE_REMOVED` or
+    /// `upcall::CORE_FAILED`). The kernel sends
+    /// the event as an upcall to one of the cores of the process.
+    pub fn subscribe_event(event: u64, subscribe: bool) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::SubscribeEvent {
+                event,
+                value: subscribe as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Gives up the core until someone wakes up the futex `word` (see
+    /// `futex_wake`), unless `word` doesn't hold `expected` (anymore).
+    ///
+    /// Can return without being woken up, callers have to check `word`
+    /// again.
+    pub fn futex_wait(word: &AtomicU64, expected: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::FutexWait {
+                addr: word as *const AtomicU64 as u64,
+                expected,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            return Ok(());
+        }
+        match SystemCallError::from(r) {
+            // We gave up the core and got woken up
+            SystemCallError::WouldBlock => Ok(()),
+            e => Err(e),
+        }
+    }
+
+    /// Wakes up at most `count` executors of the process that wait on the
+    /// futex `word` (see `futex_wait`), returns how many were woken up.
+    pub fn futex_wake(word: &AtomicU64, count: u64) -> Result<u64, SystemCallError> {
+        let (r, woken) = unsafe {
+            ProcessRequest::FutexWake {
+                addr: word as *const AtomicU64 as u64,
+                count,
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(woken)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Print `buffer` on the console.
     pub fn print(buffer: &str) -> Result<(), SystemCallError> {
         let r = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::Log as u64,
-                buffer.as_ptr() as u64,
-                buffer.len(),
-                1
-            )
+            ProcessRequest::Log {
+                buf: buffer.as_ptr() as u64,
+                len: buffer.len() as u64,
+            }
+            .call1()
         };
 
         if r == 0 {
@@ -55,13 +138,7 @@ impl Process {
     /// This is allocated and controlled by the kernel, it doesn't move and
     /// will be valid as long as the current CPU is allocated to the process.
     pub fn vcpu_control_area() -> Result<&'static mut VirtualCpu, SystemCallError> {
-        let (r, control) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::GetVCpuArea as u64,
-                2
-            )
-        };
+        let (r, control) = unsafe { ProcessRequest::GetVCpuArea {}.call2() };
 
         if r == 0 {
             let vaddr = VAddr::from(control);
@@ -73,17 +150,82 @@ impl Process {
         }
     }
 
+    /// Query the CPU time (in cycles) the process has used so far.
+    ///
+    /// This only accounts the time executors ran in user-space, not the
+    /// time spent in the kernel or waiting for cores.
+    pub fn usage() -> Result<ProcessUsage, SystemCallError> {
+        let (r, cpu_cycles, dispatches) = unsafe {
+            ProcessRequest::GetUsage {
+                kind: UsageKind::Cpu as u64,
+            }
+            .call3()
+        };
+
+        if r == 0 {
+            Ok(ProcessUsage {
+                cpu_cycles,
+                dispatches,
+            })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Query the L3 cache occupancy and the memory traffic of the process
+    /// (in the package of the core we run on).
+    ///
+    /// Fails with `NotSupported` on machines without Intel CMT/MBM.
+    pub fn cache_usage() -> Result<CacheUsage, SystemCallError> {
+        let (r, llc_occupancy, memory_traffic) = unsafe {
+            ProcessRequest::GetUsage {
+                kind: UsageKind::Cache as u64,
+            }
+            .call3()
+        };
+
+        if r == 0 {
+            Ok(CacheUsage {
+                llc_occupancy,
+                memory_traffic,
+            })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Query how many bytes the process stored in files and its quota.
+    pub fn fs_usage() -> Result<FsUsage, SystemCallError> {
+        let (r, bytes, quota) = unsafe {
+            ProcessRequest::GetUsage {
+                kind: UsageKind::FileSystem as u64,
+            }
+            .call3()
+        };
+
+        if r == 0 {
+            Ok(FsUsage {
+                bytes,
+                quota: if quota == u64::max_value() {
+                    None
+                } else {
+                    Some(quota)
+                },
+            })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Query process specific information.
     pub fn process_info() -> Result<ProcessInfo, SystemCallError> {
         let mut buf = alloc::vec![0; 256];
         let (r, len) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::GetProcessInfo as u64,
-                buf.as_mut_ptr() as u64,
-                buf.len() as u64,
-                2
-            )
+            ProcessRequest::GetProcessInfo {
+                buf: buf.as_mut_ptr() as u64,
+                len: buf.len() as u64,
+            }
+            .call2()
         };
 
         if r == 0 {
@@ -98,15 +240,329 @@ impl Process {
         }
     }
 
+    /// Read input from the console into `buf`.
+    ///
+    /// Returns how many bytes were read (0 if there is no input pending).
+    pub fn read_console(buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        let (r, len) = unsafe {
+            ProcessRequest::ReadConsole {
+                buf: buf.as_mut_ptr() as u64,
+                len: buf.len() as u64,
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Start a new process from the boot module `binary`.
+    ///
+    /// `args` are the (space separated) arguments of the process, it finds
+    /// them in `ProcessInfo::app_cmdline`. Without arguments it gets the ones
+    /// of the kernel command-line. Arguments can't contain `'`.
+    ///
+    /// The process starts on the core of the caller (and time-shares it
+    /// with the caller), its executors are scheduled with `policy`. Returns
+    /// the pid of the new process.
+    pub fn spawn(
+        binary: &str,
+        args: &str,
+        policy: SchedulingPolicy,
+    ) -> Result<u64, SystemCallError> {
+        let (r, pid) = unsafe {
+            ProcessRequest::Spawn {
+                binary: binary.as_ptr() as u64,
+                binary_len: binary.len() as u64,
+                args: args.as_ptr() as u64,
+                args_len: args.len() as u64 | ((policy as u64) << SPAWN_POLICY_SHIFT),
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Check if the child process `pid` exited.
+    ///
+    /// Returns the exit code of the process or None if it's still running.
+    /// Like `wait_pid`, this only works for the parent of `pid`.
+    pub fn wait(pid: u64) -> Result<Option<u64>, SystemCallError> {
+        let (r, exited, code) = unsafe { ProcessRequest::Wait { pid }.call3() };
+
+        if r == 0 {
+            Ok(if exited != 0 { Some(code) } else { None })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Wait until the child process `pid` exited and return its exit code.
+    ///
+    /// Only the parent (the process that spawned or forked `pid`) can wait
+    /// for a process, and only once: The exit code is gone afterwards. The
+    /// caller gives up its core in the meantime.
+    pub fn wait_pid(pid: u64) -> Result<u64, SystemCallError> {
+        loop {
+            let (r, code) = unsafe { ProcessRequest::WaitPid { pid }.call2() };
+
+            if r == 0 {
+                return Ok(code);
+            }
+            match SystemCallError::from(r) {
+                // We got woken up because the child exited, ask again
+                SystemCallError::WouldBlock => continue,
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// List all processes that are currently running.
+    pub fn list() -> Result<Vec<ProcessEntry>, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                ProcessRequest::ListProcesses {
+                    buf: buf.as_mut_ptr() as u64,
+                    len: buf.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            let len = len as usize;
+            if len > buf.len() {
+                // More processes than fit in our buffer, try again
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.resize(len, 0);
+            let deserialized: Vec<ProcessEntry> = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
+    /// Run as user `uid` and group `gid` from now on.
+    ///
+    /// Only processes running as root (uid 0) can do this, so it can't be
+    /// undone unless `uid` is 0.
+    pub fn set_credentials(uid: u32, gid: u32) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::SetCredentials {
+                uid: uid as u64,
+                gid: gid as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Change the scheduling weight of the process (between 1 and
+    /// `MAX_WEIGHT`, `DEFAULT_WEIGHT` is what processes start with).
+    ///
+    /// On cores that are time-shared with other processes, the process gets
+    /// a share of the core proportional to its weight. Children created with
+    /// `fork` inherit the weight.
+    pub fn set_weight(weight: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { ProcessRequest::SetWeight { weight }.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Duplicate the process.
+    ///
+    /// The child gets a copy of the memory (copied lazily, when either
+    /// process writes to it), the file descriptors and the credentials of the
+    /// caller. Unlike POSIX, the file offsets aren't shared: reads, writes
+    /// and seeks of one process don't move the offsets of the other. Only
+    /// the calling core is duplicated, the child continues on
+    /// the same core (time-shared with the caller) as if it returned from
+    /// `fork` too. Returns the pid of the child to the caller and 0 to the
+    /// child.
+    pub fn fork() -> Result<u64, SystemCallError> {
+        let (r, pid) = unsafe { ProcessRequest::Fork {}.call2() };
+
+        if r == 0 {
+            Ok(pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Replace the image of the process with the boot module `binary`.
+    ///
+    /// Keeps the pid, file descriptors and credentials, everything else
+    /// (memory, threads, cores) is gone. The process has to run on a single
+    /// core. Only returns in case of an error.
+    pub fn exec(binary: &str) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::Exec {
+                binary: binary.as_ptr() as u64,
+                binary_len: binary.len() as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Move process `pid` (0 for the caller) into process group `group`.
+    ///
+    /// A `group` of 0 creates a new group (with the pid of the process as
+    /// its id), otherwise the group has to exist already. Processes start
+    /// in the group of their parent and can only change their own group or
+    /// the one of their children. Returns the group id.
+    pub fn set_group(pid: u64, group: u64) -> Result<u64, SystemCallError> {
+        let (r, group) = unsafe { ProcessRequest::SetGroup { pid, group }.call2() };
+
+        if r == 0 {
+            Ok(group)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Terminate all processes in process group `group` at once (they exit
+    /// with `TERMINATED_EXIT_CODE`), returns how many there were.
+    ///
+    /// Fails with `PermissionError` if the group has processes that run as
+    /// another user (unless the caller runs as root) or the init process.
+    /// If the caller is in the group too, this doesn't return.
+    pub fn terminate_group(group: u64) -> Result<u64, SystemCallError> {
+        let (r, terminated) = unsafe { ProcessRequest::TerminateGroup { group }.call2() };
+
+        if r == 0 {
+            Ok(terminated)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Supervise the child `pid`: once it exited (or was terminated) the
+    /// caller can start `cmdline` (a boot module followed by its arguments,
+    /// separated by a space) in its place with `restart`.
+    ///
+    /// The kernel keeps the file descriptors of the child until then.
+    pub fn supervise(pid: u64, cmdline: &str) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            ProcessRequest::Supervise {
+                pid,
+                cmdline: cmdline.as_ptr() as u64,
+                cmdline_len: cmdline.len() as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Wait until the supervised process `pid` exited and start it again (see
+    /// `supervise`). The caller gives up its core in the meantime.
+    ///
+    /// The new process gets the file descriptors, credentials, process group
+    /// and core of `pid` and is supervised by the caller too. Returns its pid
+    /// and the exit code of `pid`.
+    pub fn restart(pid: u64) -> Result<(u64, u64), SystemCallError> {
+        loop {
+            let (r, new_pid, code) = unsafe { ProcessRequest::Restart { pid }.call3() };
+
+            if r == 0 {
+                return Ok((new_pid, code));
+            }
+            match SystemCallError::from(r) {
+                // We got woken up because it exited, ask again
+                SystemCallError::WouldBlock => continue,
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// Allocate a memory protection key, returns the key.
+    ///
+    /// The calling executor gets `rights` to pages tagged with the key (see
+    /// `VSpace::protect_key`), other executors of the process have no access
+    /// until they change their PKRU (see `x86_64::pkru_with_rights`).
+    pub fn allocate_key(rights: MemoryRights) -> Result<u16, SystemCallError> {
+        let (r, key) = unsafe {
+            ProcessRequest::AllocateKey {
+                rights: rights.bits(),
+            }
+            .call2()
+        };
+
+        if r == 0 {
+            Ok(key as u16)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Give a memory protection key back.
+    ///
+    /// Pages that are still tagged with the key keep it, so they should be
+    /// tagged with key 0 (or unmapped) first.
+    pub fn free_key(key: u16) -> Result<(), SystemCallError> {
+        let r = unsafe { ProcessRequest::FreeKey { key: key as u64 }.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Let the executor of this core run with a (CET) shadow stack from now
+    /// on, returns its base and size.
+    ///
+    /// Once enabled, a return to an address that doesn't match the shadow
+    /// stack terminates the process (with `CONTROL_PROTECTION_EXIT_CODE`).
+    /// Every executor has to enable it on its own (e.g., on the
+    /// `upcall::NEW_CORE` upcall).
+    ///
+    /// There is only one shadow stack per executor, so code that switches
+    /// between stacks in user-space can't use this: lineup threads (and so
+    /// vibrio) get terminated on their first switch.
+    pub fn enable_shadow_stack() -> Result<(u64, u64), SystemCallError> {
+        let (r, base, size) = unsafe { ProcessRequest::EnableShadowStack {}.call3() };
+
+        if r == 0 {
+            Ok((base, size))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Exit the process (pass an error `code` to exit).
     pub fn exit(code: u64) -> ! {
         unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::Exit as u64,
-                code,
-                1
-            );
+            ProcessRequest::Exit { code }.call1();
 
             // This stops the process and never returns:
             unreachable!()
@@ -3,10 +3,10 @@
 
 use alloc::vec::Vec;
 
-use crate::syscall;
 use crate::*;
 
-use crate::system::{CoreId, CpuThread};
+use crate::eventring::EventTopics;
+use crate::system::{CoreId, CoreStats, CpuThread, KernelLogRecord, NodeMemoryStats};
 
 pub struct System;
 
@@ -15,13 +15,11 @@ impl System {
     pub fn threads() -> Result<Vec<CpuThread>, SystemCallError> {
         let mut buf = alloc::vec![0; 5*4096];
         let (r, len) = unsafe {
-            syscall!(
-                SystemCall::System as u64,
-                SystemOperation::GetHardwareThreads as u64,
-                buf.as_mut_ptr() as u64,
-                buf.len() as u64,
-                2
-            )
+            SystemRequest::GetHardwareThreads {
+                buf: buf.as_mut_ptr() as u64,
+                len: buf.len() as u64,
+            }
+            .call2()
         };
 
         if r == 0 {
@@ -37,7 +35,7 @@ impl System {
 
     /// Prints some stats for the core.
     pub fn stats() -> Result<(), SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::System as u64, SystemOperation::Stats as u64, 1) };
+        let r = unsafe { SystemRequest::Stats { buf: 0, len: 0 }.call1() };
 
         if r == 0 {
             Ok(())
@@ -46,15 +44,96 @@ impl System {
         }
     }
 
+    /// Retrieve the statistics (e.g., IPI latencies) the kernel collected
+    /// for the core we're currently running on.
+    pub fn core_stats() -> Result<CoreStats, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                SystemRequest::Stats {
+                    buf: buf.as_mut_ptr() as u64,
+                    len: buf.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            let len = len as usize;
+            if len > buf.len() {
+                // The kernel didn't copy anything, our buffer is too small
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.resize(len, 0);
+            let deserialized: CoreStats = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
+    /// Query total and free physical memory of every NUMA node.
+    pub fn memory_stats() -> Result<Vec<NodeMemoryStats>, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                SystemRequest::MemoryStats {
+                    buf: buf.as_mut_ptr() as u64,
+                    len: buf.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            let len = len as usize;
+            if len > buf.len() {
+                // Too many NUMA nodes for our buffer, try again
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.resize(len, 0);
+            let deserialized: Vec<NodeMemoryStats> = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
+    /// Read the most recent records of the kernel log (oldest first).
+    ///
+    /// Needs to be called by the initial process.
+    pub fn kernel_log() -> Result<Vec<KernelLogRecord>, SystemCallError> {
+        let mut buf = alloc::vec![0; 64 * 1024];
+        loop {
+            let (r, len) = unsafe {
+                SystemRequest::ReadKernelLog {
+                    buf: buf.as_mut_ptr() as u64,
+                    len: buf.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            let len = len as usize;
+            if len > buf.len() {
+                // More got logged since we sized the buffer, try again
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.resize(len, 0);
+            let deserialized: Vec<KernelLogRecord> = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
     /// Get the core id for the current running thread.
     pub fn core_id() -> Result<CoreId, SystemCallError> {
-        let (r, id) = unsafe {
-            syscall!(
-                SystemCall::System as u64,
-                SystemOperation::GetCoreID as u64,
-                2
-            )
-        };
+        let (r, id) = unsafe { SystemRequest::GetCoreID {}.call2() };
 
         if r == 0 {
             Ok(id as usize)
@@ -62,4 +141,183 @@ impl System {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Suspend the machine to RAM (ACPI S3).
+    ///
+    /// Needs to be called by the initial process from core 0, returns
+    /// once the machine woke up again.
+    pub fn suspend() -> Result<(), SystemCallError> {
+        let r = unsafe { SystemRequest::Suspend {}.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Soft-reboot (without going through the firmware) into the kernel
+    /// binary of the boot module `name`.
+    ///
+    /// Needs to be called by the initial process from core 0, only returns
+    /// in case of an error.
+    pub fn kexec_module(name: &str) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            SystemRequest::KexecModule {
+                name: name.as_ptr() as u64,
+                len: name.len() as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Soft-reboot (without going through the firmware) into the kernel
+    /// ELF binary `image` (e.g., read from a file).
+    ///
+    /// Needs to be called by the initial process from core 0, only returns
+    /// in case of an error.
+    pub fn kexec_image(image: &[u8]) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            SystemRequest::KexecImage {
+                image: image.as_ptr() as u64,
+                len: image.len() as u64,
+            }
+            .call1()
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Fill `buf` with random bytes.
+    ///
+    /// The kernel uses RDSEED/RDRAND (or a DRBG on machines that don't have
+    /// them), this is safe to call from multiple threads and cores.
+    pub fn get_random(buf: &mut [u8]) -> Result<(), SystemCallError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let rest = &mut buf[filled..];
+            let (r, len) = unsafe {
+                SystemRequest::GetRandom {
+                    buf: rest.as_mut_ptr() as u64,
+                    len: rest.len() as u64,
+                }
+                .call2()
+            };
+
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            filled += len as usize;
+        }
+
+        Ok(())
+    }
+
+    /// Map an event ring that receives the events of `topics` at `base` (see
+    /// `eventring`), a `base` of 0 removes the ring.
+    ///
+    /// `base` has to be aligned to `eventring::EVENT_RING_SIZE` and must not
+    /// be mapped yet. Needs to be called by the initial process.
+    pub unsafe fn open_event_ring(base: u64, topics: EventTopics) -> Result<(), SystemCallError> {
+        let r = SystemRequest::OpenEventRing {
+            base,
+            topics: topics.bits(),
+        }
+        .call1();
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Ask for `upcall::EVENT_RING` upcalls while the event ring has events
+    /// starting at index `seen` (`u64::MAX` disarms the doorbell).
+    ///
+    /// Returns the current tail of the ring, read up to it before waiting
+    /// for the doorbell.
+    pub fn arm_event_ring(seen: u64) -> Result<u64, SystemCallError> {
+        let (r, tail) = unsafe { SystemRequest::ArmEventRing { seen }.call2() };
+
+        if r == 0 {
+            Ok(tail)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// A system call that does nothing, it only measures the cost of
+    /// entering and leaving the kernel.
+    pub fn null() -> Result<(), SystemCallError> {
+        let r = unsafe { SystemRequest::Null {}.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Get the id of the executor the calling thread runs on (the kernel
+    /// only knows about executors, not user-space threads).
+    pub fn thread_id() -> Result<u64, SystemCallError> {
+        let (r, eid) = unsafe { SystemRequest::GetThreadId {}.call2() };
+
+        if r == 0 {
+            Ok(eid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sets the L3 way mask of class of service `clos` (Intel CAT), a mask
+    /// of 0 leaves it as it is. Returns the number of classes and ways the
+    /// machine has.
+    ///
+    /// The mask needs at least one way and the ways have to be contiguous.
+    /// Only the initial process can do this.
+    pub fn set_cache_mask(clos: u64, mask: u64) -> Result<(u64, u64), SystemCallError> {
+        let (r, classes, ways) = unsafe { SystemRequest::CacheAllocation { clos, mask }.call3() };
+
+        if r == 0 {
+            Ok((classes, ways))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Puts process `pid` into class of service `clos` (see
+    /// `set_cache_mask`).
+    pub fn set_cache_class(pid: u64, clos: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { SystemRequest::CacheClass { pid, clos }.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Puts core `gtid` into class of service `clos` (see `set_cache_mask`),
+    /// for processes that aren't in a class of their own.
+    pub fn set_core_cache_class(gtid: u64, clos: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { SystemRequest::CoreCacheClass { gtid, clos }.call1() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
@@ -1,5 +1,8 @@
 //! Data structures to exchange system-wide information between kernel and user-space.
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use serde::{Deserialize, Serialize};
 
 /// A system global ID for a CPU hardware thread.
@@ -30,3 +33,165 @@ pub struct CpuThread {
     /// ID of the thread (relative to the core (usually either 0 or 1)).
     pub thread_id: ThreadId,
 }
+
+/// A histogram with power-of-two buckets (used for kernel statistics).
+///
+/// `buckets[i]` counts the values `v` with `2^(i-1) <= v < 2^i`
+/// (`buckets[0]` counts the value 0).
+#[derive(Serialize, Deserialize, Default, Clone, Eq, PartialEq, Debug)]
+pub struct Histogram {
+    /// Number of recorded values.
+    pub count: u64,
+    /// Sum of all recorded values.
+    pub sum: u64,
+    /// Smallest recorded value.
+    pub min: u64,
+    /// Largest recorded value.
+    pub max: u64,
+    /// Median (upper bound of the bucket it falls in).
+    pub p50: u64,
+    /// 90th percentile (upper bound of the bucket it falls in).
+    pub p90: u64,
+    /// 99th percentile (upper bound of the bucket it falls in).
+    pub p99: u64,
+    /// 99.9th percentile (upper bound of the bucket it falls in).
+    pub p999: u64,
+    /// Bucket counts (trailing empty buckets are omitted).
+    pub buckets: Vec<u64>,
+}
+
+/// Latency between an interrupt being sent and its handler starting.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct IrqLatency {
+    /// The interrupt vector.
+    pub vector: u64,
+    /// Latency in cycles.
+    pub cycles: Histogram,
+}
+
+/// Time spent in the kernel for a class of system calls.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct SyscallLatency {
+    /// The system call class (see `SystemCall`).
+    pub syscall: u64,
+    /// Cycles spent handling the system call.
+    pub cycles: Histogram,
+}
+
+/// Statistics collected by the kernel for a single core.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct CoreStats {
+    /// ID of the hardware thread.
+    pub id: GlobalThreadId,
+    /// Cycles spent in the TLB shootdown handler.
+    pub tlb_time: u64,
+    /// Cycles spent in the TLB shootdown handler (per invocation).
+    pub tlb_handler: Histogram,
+    /// Latencies of inter-processor interrupts received by the core.
+    pub irq_latency: Vec<IrqLatency>,
+    /// Time spent in system calls on the core.
+    pub syscall_latency: Vec<SyscallLatency>,
+    /// Energy and thermal readings of the core and its package.
+    pub power: PowerStats,
+}
+
+/// Energy (RAPL) and thermal readings of a core and its package, the
+/// readings the hardware doesn't have (e.g., in a VM) are None.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PowerStats {
+    /// The energy counters count in units of `1 / 2^energy_unit_shift`
+    /// joules.
+    pub energy_unit_shift: u8,
+    /// Energy counter of the package (32 bits, wraps around).
+    pub package_energy: Option<u32>,
+    /// Energy counter of all cores of the package (32 bits, wraps around).
+    pub cores_energy: Option<u32>,
+    /// Temperature of the core (in degrees Celsius).
+    pub core_temperature: Option<u8>,
+    /// Temperature of the package (in degrees Celsius).
+    pub package_temperature: Option<u8>,
+    /// The core is too hot and runs slower right now.
+    pub thermal_throttling: bool,
+}
+
+impl PowerStats {
+    /// Energy (in microjoules) the package consumed since `earlier`.
+    ///
+    /// The counter wraps around every few minutes under load, the readings
+    /// have to be taken more often than that.
+    pub fn package_energy_since(&self, earlier: &PowerStats) -> Option<u64> {
+        self.microjoules(earlier.package_energy?, self.package_energy?)
+    }
+
+    /// Energy (in microjoules) the cores of the package consumed since
+    /// `earlier` (see `package_energy_since`).
+    pub fn cores_energy_since(&self, earlier: &PowerStats) -> Option<u64> {
+        self.microjoules(earlier.cores_energy?, self.cores_energy?)
+    }
+
+    fn microjoules(&self, earlier: u32, now: u32) -> Option<u64> {
+        let units = now.wrapping_sub(earlier) as u64;
+        Some((units * 1_000_000) >> self.energy_unit_shift)
+    }
+}
+
+/// Physical memory of a NUMA node (see `SystemOperation::MemoryStats`).
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct NodeMemoryStats {
+    /// ID of the NUMA node.
+    pub node: NodeId,
+    /// Memory (in bytes) that is still available in the node allocator.
+    pub free: u64,
+    /// How many of the free pages are base pages.
+    pub free_base_pages: u64,
+    /// How many of the free pages are large pages.
+    pub free_large_pages: u64,
+    /// Part of the free memory (in per-mille) that can only be handed out
+    /// as base pages.
+    pub external_fragmentation: u64,
+    /// Pages of evictable files (see `FileFlags::O_EVICTABLE`) that were
+    /// dropped to free memory on the node.
+    pub evicted_pages: u64,
+}
+
+/// A record of the kernel log (see `SystemOperation::ReadKernelLog`).
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct KernelLogRecord {
+    /// Sequence number (counts all records the kernel logged since boot).
+    pub seq: u64,
+    /// Log level (1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace).
+    pub level: u64,
+    /// Nanoseconds since boot.
+    pub timestamp: u64,
+    /// The module that logged the record (may be truncated).
+    pub module: String,
+    /// The message (may be truncated).
+    pub message: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn energy_since() {
+        let earlier = PowerStats {
+            energy_unit_shift: 14,
+            package_energy: Some(u32::MAX - 16383),
+            cores_energy: Some(100),
+            ..Default::default()
+        };
+        let now = PowerStats {
+            energy_unit_shift: 14,
+            // Wrapped around
+            package_energy: Some(16384),
+            cores_energy: Some(100 + 3 * 16384),
+            ..Default::default()
+        };
+        assert_eq!(now.package_energy_since(&earlier), Some(2_000_000));
+        assert_eq!(now.cores_energy_since(&earlier), Some(3_000_000));
+
+        // Not on this machine
+        assert_eq!(now.package_energy_since(&PowerStats::default()), None);
+    }
+}
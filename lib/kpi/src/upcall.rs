@@ -1,3 +1,43 @@
 //! Upcall command passed as the 2nd argument to the upcall.
 
 pub const NEW_CORE: u64 = 0x99;
+
+/// The kernel took away another core from the process (scheduler
+/// activation), the argument is the id of the preempted core.
+pub const CORE_PREEMPTED: u64 = 0x9a;
+
+/// The event ring of the process has new events (see `eventring`), the
+/// argument is the index of the next event the kernel will write.
+pub const EVENT_RING: u64 = 0x9b;
+
+/// A page-fault in user-space the kernel couldn't resolve (e.g., on a guard
+/// page), the argument is the faulting address. The state of the faulting
+/// thread is in the `enabled_state` of the vCPU.
+pub const PAGE_FAULT: u64 = 0x9c;
+
+/// The timer the executor subscribed to with `Process::subscribe_timer`
+/// expired, the argument is the rdtsc value at that time.
+pub const TIMER: u64 = 0x9d;
+
+/// A core of the machine came online again (e.g., after the system resumed
+/// from suspend-to-RAM), the argument is the id of the core. Processes get
+/// it after they subscribed with `Process::subscribe_event`.
+pub const CORE_ONLINE: u64 = 0x9e;
+
+/// A system call ran out of memory, the argument is the id of the process
+/// that made it. Processes get it after they subscribed with
+/// `Process::subscribe_event`.
+pub const LOW_MEMORY: u64 = 0x9f;
+
+/// A PCI device the process claimed was removed from the bus, the argument
+/// is the handle of the device (which is invalid from now on). The kernel
+/// already unmapped the BARs of the device and freed its MSI vectors.
+/// Processes get it after they subscribed with `Process::subscribe_event`.
+pub const DEVICE_REMOVED: u64 = 0xa0;
+
+/// The kernel on a core the process ran on panicked and the core was taken
+/// out of service (only if the kernel runs with `panic=isolate`), the
+/// argument is the id of the core. The executor of the process on that core
+/// is gone. Processes get it after they subscribed with
+/// `Process::subscribe_event`.
+pub const CORE_FAILED: u64 = 0xa1;
@@ -7,6 +7,8 @@ use core::fmt;
 use x86::bits64::paging::VAddr;
 use x86::bits64::rflags::RFlags;
 
+use crate::process::MemoryRights;
+
 /// The virtual CPU is a shared data-structure between the kernel and user-space
 /// that facilitates IRQ/trap delivery and emulation of critical sections
 /// for a user-space scheduler.
@@ -28,6 +30,15 @@ pub struct VirtualCpu {
     pub is_disabled: bool,
     /// An upcall needs to be executed.
     pub has_pending_upcall: bool,
+    /// rdtsc value at which the kernel will preempt us (in case the core is
+    /// time-shared with other processes, `u64::MAX` otherwise).
+    pub quantum_end: u64,
+    /// Set by the kernel when it gave our core to another process, can be
+    /// cleared by user-space.
+    pub preempted: bool,
+    /// Device interrupts (one bit per vector) that came in while upcalls
+    /// were disabled (see `take_pending_irq`).
+    pub pending_irqs: [u64; 4],
 }
 
 impl VirtualCpu {
@@ -43,6 +54,35 @@ impl VirtualCpu {
     pub fn disable_upcalls(&mut self) {
         self.is_disabled = true;
     }
+
+    /// Remembers device interrupt `vector` for later (upcalls are
+    /// disabled).
+    pub fn set_pending_irq(&mut self, vector: u64) {
+        let mut pending = self.pending_irqs;
+        pending[(vector / 64) as usize % 4] |= 1 << (vector % 64);
+        self.pending_irqs = pending;
+        self.has_pending_upcall = true;
+    }
+
+    /// Returns (and clears) the lowest device interrupt vector that came in
+    /// while upcalls were disabled.
+    pub fn take_pending_irq(&mut self) -> Option<u64> {
+        let mut pending = self.pending_irqs;
+        let idx = pending.iter().position(|bits| *bits != 0)?;
+        let bit = pending[idx].trailing_zeros() as u64;
+        pending[idx] &= !(1 << bit);
+        self.pending_irqs = pending;
+        Some(idx as u64 * 64 + bit)
+    }
+
+    /// How many cycles are left in the current time slice.
+    ///
+    /// Can be used to yield cooperatively (e.g., instead of starting a
+    /// new task that would get preempted).
+    pub fn remaining_quantum(&self) -> u64 {
+        let quantum_end = self.quantum_end;
+        quantum_end.saturating_sub(unsafe { x86::time::rdtsc() })
+    }
 }
 
 /// Memory area that is used by a CPU/scheduler to capture and save
@@ -146,6 +186,12 @@ impl SaveArea {
         self.rax = err as u64;
     }
 
+    /// Sets the error return code on a system call along with an auxiliary
+    /// detail value (see `SystemCallError::detail`).
+    pub fn set_syscall_error_detail(&mut self, err: crate::SystemCallError, detail: u64) {
+        self.rax = err.with_detail(detail);
+    }
+
     /// Sets the 1st return argument for system calls
     ///
     /// 1st argument is passed back in the rdi register.
@@ -194,3 +240,88 @@ rip = {:>#18x} rflags = {:?}",
         }
     }
 }
+
+/// Number of memory protection keys (see `VSpaceOperation::ProtectKey`).
+///
+/// Key 0 is the default key of all mappings and can't be allocated.
+pub const PROTECTION_KEYS: u16 = 16;
+
+/// PKRU bit (of a key) that disables all data accesses.
+const PKRU_ACCESS_DISABLE: u32 = 0x1;
+/// PKRU bit (of a key) that disables writes.
+const PKRU_WRITE_DISABLE: u32 = 0x2;
+
+/// The PKRU executors start with: only pages with key 0 are accessible.
+pub const PKRU_DEFAULT: u32 = 0x5555_5554;
+
+/// Returns `pkru` with the access rights of `key` changed to `rights`.
+///
+/// Keys only restrict data accesses, so `MemoryRights::EXECUTE` is ignored
+/// and an empty set disables reads and writes.
+pub fn pkru_with_rights(pkru: u32, key: u16, rights: MemoryRights) -> u32 {
+    let shift = 2 * (key as u32 % PROTECTION_KEYS as u32);
+    let bits = if rights.contains(MemoryRights::WRITE) {
+        0
+    } else if rights.contains(MemoryRights::READ) {
+        PKRU_WRITE_DISABLE
+    } else {
+        PKRU_ACCESS_DISABLE | PKRU_WRITE_DISABLE
+    };
+    (pkru & !(0x3 << shift)) | bits << shift
+}
+
+/// Reads the protection key rights register of the core.
+///
+/// # Safety
+/// Raises #UD if the kernel didn't enable protection keys.
+pub unsafe fn read_pkru() -> u32 {
+    let pkru: u32;
+    llvm_asm!("rdpkru" : "={eax}" (pkru) : "{ecx}" (0) : "edx" : "volatile");
+    pkru
+}
+
+/// Writes the protection key rights register of the core.
+///
+/// The kernel saves and restores it for every executor.
+///
+/// # Safety
+/// Raises #UD if the kernel didn't enable protection keys.
+pub unsafe fn write_pkru(pkru: u32) {
+    llvm_asm!("wrpkru" :: "{eax}" (pkru), "{ecx}" (0), "{edx}" (0) : "memory" : "volatile");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pending_irqs() {
+        let mut vcpu: VirtualCpu = unsafe { core::mem::zeroed() };
+        assert_eq!(vcpu.take_pending_irq(), None);
+
+        vcpu.set_pending_irq(0xe0);
+        vcpu.set_pending_irq(0x2a);
+        vcpu.set_pending_irq(0x2a);
+        assert!(vcpu.has_pending_upcall);
+
+        assert_eq!(vcpu.take_pending_irq(), Some(0x2a));
+        assert_eq!(vcpu.take_pending_irq(), Some(0xe0));
+        assert_eq!(vcpu.take_pending_irq(), None);
+    }
+
+    #[test]
+    fn pkru_rights() {
+        let all = MemoryRights::READ | MemoryRights::WRITE;
+        assert_eq!(
+            pkru_with_rights(PKRU_DEFAULT, 0, MemoryRights::NONE),
+            0x5555_5557
+        );
+        assert_eq!(pkru_with_rights(PKRU_DEFAULT, 1, all), 0x5555_5550);
+        assert_eq!(
+            pkru_with_rights(PKRU_DEFAULT, 1, MemoryRights::READ),
+            0x5555_5558
+        );
+        assert_eq!(pkru_with_rights(0, 15, MemoryRights::EXECUTE), 0xc000_0000);
+        assert_eq!(pkru_with_rights(0xc000_0000, 15, all), 0);
+    }
+}
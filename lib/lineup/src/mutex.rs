@@ -148,9 +148,8 @@ impl MutexInner {
             (yielder.upcalls.deschedule)(&mut rid, None);
             // What if another core makes this runnable/runs it before we're made unrunnable?
             // (i.e., counter+1, waitlist.push, exit, make unrunnable)
-            // A problem is if we would steal the thread and execute it on another core (we don't currently)
-            // It would likely error because we wouldn't find the generator in the hashmap (it's taken out of the
-            // map whenever we run a thread)
+            // A problem is if we would steal the thread and execute it on another core (we don't, stealing
+            // skips threads whose generator is taken out of the hashmap, which happens whenever we run a thread)
             // A better idea is probably to provide a callback to the yielder which then pushes
             // us in the waitlist after we've restored the generator (this would ensure we only update waitlist
             // after the generator has switched back to the scheduler context and is in a consistent state)
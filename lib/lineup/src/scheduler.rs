@@ -4,20 +4,28 @@
 //! * Cooperative scheduling (threads can yield voluntarily)
 //! * Round robin scheduling (per-core)
 //! * Per core run and wait lists
-//! * Thread affinity can be defined upon thread creation (currently no migration)
+//! * Thread affinity can be defined upon thread creation (threads only migrate
+//!   if the kernel takes a core away from us, see `SmpScheduler::migrate_core`,
+//!   or if work stealing is on, see `SmpScheduler::set_work_stealing`)
+//! * Optional work stealing: idle cores take runnable threads from other
+//!   cores (unless the threads are pinned)
 //! * Waitlist is sorted according to thread wake-up times.
+//! * Cores without anything to run can give up their hardware thread until
+//!   a thread becomes runnable on them (see `SmpScheduler::idle`).
+//! * Optional stack usage tracking per thread (see
+//!   `SmpScheduler::stack_stats`).
 
 use alloc::collections::VecDeque;
 use alloc::vec::Vec;
 use core::ptr;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use arr_macro::arr;
 use fringe::generator::Generator;
 use log::{error, trace};
 use rawtime::Instant;
 
-use crate::stack::LineupStack;
+use crate::stack::{LineupStack, StackOptions};
 use crate::threads::{Runnable, Thread, ThreadId, YieldRequest, YieldResume};
 use crate::tls2::{self, SchedulerControlBlock, ThreadControlBlock};
 use crate::upcalls::Upcalls;
@@ -26,7 +34,7 @@ use crate::{CoreId, IrqVector};
 /// Scheduler per-core state.
 ///
 /// # Lock order
-/// `waiting` before `runnable`.
+/// `threads` before `waiting` before `runnable` before `generators`.
 /// In case we need to lock across multiple `SchedulerCoreState`
 /// lower `core_id` should be locked first.
 struct SchedulerCoreState {
@@ -39,6 +47,13 @@ struct SchedulerCoreState {
     ///
     /// Protected by a mutex because anyone could put threads here.
     waiting: spin::Mutex<Vec<(Instant, ThreadId)>>,
+
+    /// Counts how often threads became runnable on the core, an idle core
+    /// waits on it (see `SmpScheduler::idle`).
+    wakeups: AtomicU64,
+
+    /// Is the core idle (or about to be)?
+    idle: AtomicBool,
 }
 
 impl SchedulerCoreState {
@@ -46,10 +61,23 @@ impl SchedulerCoreState {
         SchedulerCoreState {
             runnable: spin::Mutex::new(VecDeque::with_capacity(SmpScheduler::MAX_THREADS)),
             waiting: spin::Mutex::new(Vec::with_capacity(SmpScheduler::MAX_THREADS)),
+            wakeups: AtomicU64::new(0),
+            idle: AtomicBool::new(false),
         }
     }
 }
 
+/// How much stack a thread used (see `SmpScheduler::stack_stats`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackStats {
+    /// The thread.
+    pub tid: ThreadId,
+    /// Most bytes of its stack the thread had in use.
+    pub high_water_mark: usize,
+    /// Did the thread exit already?
+    pub exited: bool,
+}
+
 pub struct SmpScheduler<'a> {
     /// All thread generators need to dispatch threads.
     ///
@@ -68,6 +96,16 @@ pub struct SmpScheduler<'a> {
     tid_counter: AtomicUsize,
     /// Maps interrupt vectors to ThreadId
     irqvec_to_tid: spin::Mutex<hashbrown::HashMap<IrqVector, ThreadId>>,
+    /// How long (in rdtsc cycles) a thread can run before it gets preempted,
+    /// 0 if threads run until they yield.
+    time_slice: AtomicU64,
+    /// Do idle cores steal runnable threads from other cores?
+    work_stealing: AtomicBool,
+    /// The stack of threads spawned without one (see `set_spawn_stack`).
+    spawn_stack: spin::Mutex<StackOptions>,
+    /// The thread that used the most stack among the ones that exited (and
+    /// kept track of it).
+    deepest_exited: spin::Mutex<Option<StackStats>>,
 }
 
 unsafe impl Send for SmpScheduler<'static> {}
@@ -92,9 +130,97 @@ impl<'a> SmpScheduler<'a> {
             tid_counter: AtomicUsize::new(0),
             per_core: arr![SchedulerCoreState::new(); 96], // MAX_THREADS
             irqvec_to_tid: spin::Mutex::new(hashbrown::HashMap::with_capacity(8)),
+            time_slice: AtomicU64::new(0),
+            work_stealing: AtomicBool::new(false),
+            spawn_stack: spin::Mutex::new(StackOptions {
+                size: 64 * 4096,
+                ..Default::default()
+            }),
+            deepest_exited: spin::Mutex::new(None),
+        }
+    }
+
+    /// Preempt threads that run for longer than `cycles` (rdtsc), 0 turns
+    /// preemption off (threads run until they yield).
+    ///
+    /// The scheduler only decides (see `should_preempt`), someone has to
+    /// check regularly and switch away from the thread (e.g., vibrio on
+    /// timer upcalls).
+    pub fn set_time_slice(&self, cycles: u64) {
+        self.time_slice.store(cycles, Ordering::Relaxed);
+    }
+
+    /// The time slice of threads (0 if preemption is off).
+    pub fn time_slice(&self) -> u64 {
+        self.time_slice.load(Ordering::Relaxed)
+    }
+
+    /// Did the thread that runs on the core of `scb` use up its time slice
+    /// at `now` (rdtsc)?
+    ///
+    /// This is only ever true while the thread itself runs (and not the
+    /// scheduler on its behalf), so it's safe to make it yield.
+    pub fn should_preempt(&self, scb: &SchedulerControlBlock, now: u64) -> bool {
+        let time_slice = self.time_slice();
+        match scb.running_since() {
+            Some(since) if time_slice > 0 => now.saturating_sub(since) >= time_slice,
+            _ => false,
+        }
+    }
+
+    /// Lets cores that have nothing to run take runnable threads from other
+    /// cores (threads are spawned with an affinity but it's only a hint
+    /// then). Off by default.
+    ///
+    /// Threads that have to stay on their core (e.g., because they use
+    /// per-core state) can be excluded with `set_pinned`, interrupt threads
+    /// are never stolen.
+    pub fn set_work_stealing(&self, enabled: bool) {
+        self.work_stealing.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Pins thread `tid` to its core (or unpins it): pinned threads never
+    /// get stolen by other cores.
+    ///
+    /// Returns `false` if the thread doesn't exist (anymore).
+    pub fn set_pinned(&self, tid: ThreadId, pinned: bool) -> bool {
+        match self.threads.lock().get_mut(&tid) {
+            Some(thread) => {
+                thread.pinned = pinned;
+                true
+            }
+            None => false,
         }
     }
 
+    /// Sets the stack of threads that get spawned without one (by threads,
+    /// e.g., `Environment::spawn`), 64 pages without a guard by default.
+    pub fn set_spawn_stack(&self, options: StackOptions) {
+        *self.spawn_stack.lock() = options;
+    }
+
+    /// How much stack the threads that keep track of it used (see
+    /// `LineupStack::track_usage`): all the ones that still exist and the
+    /// one that used the most among the ones that exited.
+    pub fn stack_stats(&self) -> Vec<StackStats> {
+        let mut stats: Vec<StackStats> = self
+            .threads
+            .lock()
+            .values()
+            .filter_map(|thread| {
+                // Safe: the stack lives as long as the thread
+                thread.stack_usage.as_ref().map(|usage| StackStats {
+                    tid: thread.id,
+                    high_water_mark: unsafe { usage.high_water_mark() },
+                    exited: false,
+                })
+            })
+            .collect();
+        stats.extend(*self.deepest_exited.lock());
+        stats.sort_unstable_by_key(|s| s.tid);
+        stats
+    }
+
     /// Returns true as long as we have 'active', unfinished thread.
     ///
     /// A thread that is currently blocked/waiting still counts as active.
@@ -185,6 +311,57 @@ impl<'a> SmpScheduler<'a> {
     /// `runnable`.
     fn mark_runnable(&self, tid: ThreadId, affinity: CoreId) {
         self.per_core[affinity].runnable.lock().push_back(tid);
+        self.wake_core(affinity);
+    }
+
+    /// Lets core `core_id` know that it has work, wakes it up if it's idle
+    /// (see `idle`).
+    fn wake_core(&self, core_id: CoreId) {
+        let state = &self.per_core[core_id];
+        state.wakeups.fetch_add(1, Ordering::SeqCst);
+        if state.idle.load(Ordering::SeqCst) {
+            wake_up(&state.wakeups);
+        }
+    }
+
+    /// Can core `core_id` give up its hardware thread?
+    ///
+    /// Not if it has something to run or has to keep looking: for threads
+    /// to steal or for timeouts and interrupts of its threads (nobody wakes
+    /// it up for these).
+    fn can_idle(&self, scb: &SchedulerControlBlock) -> bool {
+        let core_id = scb.core_id;
+        if self.work_stealing.load(Ordering::Relaxed) || !scb.pending_irqs.is_empty() {
+            return false;
+        }
+
+        // Takes one lock at a time
+        let has_irq_threads = self
+            .threads
+            .lock()
+            .values()
+            .any(|thread| thread.affinity == core_id && thread.interrupt_vector.is_some());
+        let has_timeouts = !self.per_core[core_id].waiting.lock().is_empty();
+        let has_runnable = !self.per_core[core_id].runnable.lock().is_empty();
+        !has_irq_threads && !has_timeouts && !has_runnable
+    }
+
+    /// Gives up the hardware thread of the core of `scb` until a thread
+    /// becomes runnable on it, if there is nothing to run at the moment.
+    ///
+    /// Meant for the idle loop of a core (between calls to `run`). Can return
+    /// early (e.g., if the OS doesn't support waiting).
+    pub fn idle(&self, scb: &SchedulerControlBlock) {
+        let state = &self.per_core[scb.core_id];
+        // Read before we look for work: if a thread becomes runnable after
+        // we looked we don't wait
+        let wakeups = state.wakeups.load(Ordering::SeqCst);
+        state.idle.store(true, Ordering::SeqCst);
+        if self.can_idle(scb) {
+            trace!("Core {} is idle", scb.core_id);
+            wait_for_wakeup(&state.wakeups, wakeups);
+        }
+        state.idle.store(false, Ordering::SeqCst);
     }
 
     /// Make a thread no longer runnable.
@@ -222,6 +399,157 @@ impl<'a> SmpScheduler<'a> {
         trace!("Waitlist is {:?}", waiting);
     }
 
+    /// Moves all threads with affinity for core `from` to core `to`.
+    ///
+    /// Used when the kernel preempts core `from` (see
+    /// `kpi::upcall::CORE_PREEMPTED`): the threads that are queued there can
+    /// continue on `to` instead. The thread that was running on `from` when
+    /// it got preempted will also move to `to` once it yields.
+    pub fn migrate_core(&self, from: CoreId, to: CoreId) {
+        if from == to {
+            return;
+        }
+
+        for thread in self.threads.lock().values_mut() {
+            if thread.affinity == from {
+                thread.affinity = to;
+            }
+            for (_tid, affinity) in thread.joinlist.iter_mut() {
+                if *affinity == from {
+                    *affinity = to;
+                }
+            }
+        }
+
+        let waiting: Vec<(Instant, ThreadId)> =
+            self.per_core[from].waiting.lock().drain(..).collect();
+        for (until, tid) in waiting {
+            self.waitlist_insert(tid, to, until);
+        }
+
+        let runnable: Vec<ThreadId> = self.per_core[from].runnable.lock().drain(..).collect();
+        self.per_core[to].runnable.lock().extend(runnable);
+        self.wake_core(to);
+    }
+
+    /// Takes a runnable thread from another core and moves it to
+    /// `core_id` (if work stealing is on).
+    ///
+    /// Looks at the other cores round-robin, starting with the one after
+    /// `core_id`, and takes the most recently queued thread that can be
+    /// stolen (the other core would run it last anyways).
+    fn steal(&self, core_id: CoreId) -> Option<ThreadId> {
+        if !self.work_stealing.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let cores = self.per_core.len();
+        for victim in (1..cores).map(|i| (core_id + i) % cores) {
+            // Cheap check first, we don't want to lock `threads` on every
+            // idle round of the scheduler
+            let has_work = self.per_core[victim]
+                .runnable
+                .try_lock()
+                .map_or(false, |runnable| !runnable.is_empty());
+            if !has_work {
+                continue;
+            }
+
+            let mut threads = self.threads.lock();
+            let mut runnable = self.per_core[victim].runnable.lock();
+            // A thread can already be runnable again before it switched back
+            // to its scheduler (its generator is missing then), leave these
+            // to the core it runs on
+            let generators = self.generators.lock();
+            let stealable = runnable.iter().rposition(|tid| {
+                generators.contains_key(tid)
+                    && threads.get(tid).map_or(false, |thread| {
+                        !thread.pinned && thread.interrupt_vector.is_none()
+                    })
+            });
+            drop(generators);
+
+            if let Some(idx) = stealable {
+                let tid = runnable.remove(idx).expect("Index is valid");
+                let thread = threads.get_mut(&tid).expect("Checked above");
+                trace!("Core {} steals {} from core {}", core_id, tid, victim);
+                thread.affinity = core_id;
+                unsafe {
+                    (*thread.state).current_core = core_id;
+                }
+                return Some(tid);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the thread that overflowed its stack if `addr` (e.g., the
+    /// address of a page-fault) is in the guard page of one (see
+    /// `LineupStack::with_guard`).
+    ///
+    /// Gives up (returns `None`) if the thread list is locked, since we
+    /// might have faulted while holding the lock.
+    pub fn overflowed_stack(&self, addr: usize) -> Option<ThreadId> {
+        let threads = self.threads.try_lock()?;
+        threads
+            .values()
+            .find(|thread| {
+                thread.stack_region.as_ref().map_or(false, |region| {
+                    let (start, end) = region.guard();
+                    start <= addr && addr < end
+                })
+            })
+            .map(|thread| thread.id)
+    }
+
+    /// Maps more of the stack of a thread if `addr` (e.g., the address of a
+    /// page-fault) is in the part of its stack that isn't mapped yet (see
+    /// `LineupStack::growable`).
+    ///
+    /// Returns `false` if `addr` isn't in a stack that can grow.
+    #[cfg(target_os = "bespin")]
+    pub fn grow_stack(&self, addr: usize) -> bool {
+        if !crate::stack::in_stack_area(addr) {
+            return false;
+        }
+
+        // A thread can't fault on its stack while we hold the lock on this
+        // core (the scheduler runs on its own stack)
+        let threads = self.threads.lock();
+        let region = threads
+            .values()
+            .filter_map(|thread| thread.stack_region.as_ref())
+            .find(|region| region.can_grow(addr));
+        match region {
+            Some(region) => {
+                region.grow(addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` if `addr` is mapped as far as the stacks of our threads
+    /// are concerned, i.e., it's not in a guard page or the part of a stack
+    /// that hasn't grown yet.
+    ///
+    /// Gives up (returns `false`) if the thread list is locked.
+    pub fn stack_mapped(&self, addr: usize) -> bool {
+        if !crate::stack::in_stack_area(addr) {
+            return true;
+        }
+
+        self.threads.try_lock().map_or(false, |threads| {
+            threads.values().all(|thread| {
+                thread.stack_region.as_ref().map_or(true, |region| {
+                    let (start, end) = region.guard();
+                    !(start <= addr && addr < end) && !region.can_grow(addr)
+                })
+            })
+        })
+    }
+
     /// Handles a yield request of the thread given by `tid`.
     ///
     /// Updates run and waitlists accordingly.
@@ -237,6 +565,19 @@ impl<'a> SmpScheduler<'a> {
                     .remove(&tid)
                     .expect("Can't remove thread?");
 
+                // The stack is still around (the generator owns it)
+                if let Some(usage) = thread.stack_usage.as_ref() {
+                    let high_water_mark = unsafe { usage.high_water_mark() };
+                    let mut deepest = self.deepest_exited.lock();
+                    if deepest.map_or(true, |d| high_water_mark > d.high_water_mark) {
+                        *deepest = Some(StackStats {
+                            tid,
+                            high_water_mark,
+                            exited: true,
+                        });
+                    }
+                }
+
                 // Wake up all the waiters
                 for (sleeping_tid, sleeping_affinity) in thread.joinlist {
                     log::debug!(
@@ -343,15 +684,18 @@ impl<'a> SmpScheduler<'a> {
             }
             Some(YieldRequest::Spawn(function, arg, affinity, irq_vector)) => {
                 trace!("self.spawn {:?} {:p}", function, arg);
+                let stack = self.spawn_stack.lock().allocate();
+                let tls = unsafe { tls2::ThreadControlBlock::new_tls_area() };
                 let tid = self
-                    .spawn(
-                        64 * 4096,
+                    .spawn_with_args(
+                        stack,
                         move |arg| unsafe {
                             (function.unwrap())(arg);
                         },
                         arg,
                         affinity,
                         irq_vector,
+                        tls,
                     )
                     .expect("Can't spawn the thread");
                 YieldResume::Spawned(tid)
@@ -438,6 +782,7 @@ impl<'a> SmpScheduler<'a> {
 
             // The next thread ID we want to run
             let next_tid = self.per_core[core_id].runnable.lock().pop_front();
+            let next_tid = next_tid.or_else(|| self.steal(core_id));
             match next_tid {
                 Some(tid) => {
                     let mut generator = self
@@ -516,6 +861,30 @@ impl<'a> SmpScheduler<'a> {
     }
 }
 
+/// Gives up the hardware thread until `wakeups` no longer holds `expected`
+/// (or spuriously).
+#[cfg(target_os = "bespin")]
+fn wait_for_wakeup(wakeups: &AtomicU64, expected: u64) {
+    if let Err(e) = kpi::syscalls::Process::futex_wait(wakeups, expected) {
+        error!("Can't wait for a thread to become runnable: {:?}", e);
+    }
+}
+
+/// Wakes up the hardware thread waiting on `wakeups` (see `wait_for_wakeup`).
+#[cfg(target_os = "bespin")]
+fn wake_up(wakeups: &AtomicU64) {
+    if let Err(e) = kpi::syscalls::Process::futex_wake(wakeups, u64::max_value()) {
+        error!("Can't wake up idle core: {:?}", e);
+    }
+}
+
+/// Idle cores keep polling (we have no pthread to block here).
+#[cfg(not(target_os = "bespin"))]
+fn wait_for_wakeup(_wakeups: &AtomicU64, _expected: u64) {}
+
+#[cfg(not(target_os = "bespin"))]
+fn wake_up(_wakeups: &AtomicU64) {}
+
 #[cfg(test)]
 mod tests {
     use alloc::sync::Arc;
@@ -797,6 +1166,211 @@ mod tests {
         assert!(exp_duration <= ref_duration + bound, "Lineup was too slow?");
     }
 
+    /// Test that threads of a preempted core continue on another core.
+    #[test]
+    fn migrate_core() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Default::default();
+
+        let ran_on: Arc<ArrayQueue<usize>> = Arc::new(ArrayQueue::new(4));
+        for _i in 0..2 {
+            let ran_on = ran_on.clone();
+            s.spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let _r = ran_on.push(Environment::core_id());
+                    Environment::thread().relinquish();
+                    let _r = ran_on.push(Environment::core_id());
+                },
+                ptr::null_mut(),
+                1,
+            );
+        }
+
+        s.migrate_core(1, 0);
+        assert!(s.per_core[1].runnable.lock().is_empty());
+        assert_eq!(s.per_core[0].runnable.lock().len(), 2);
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+        assert_eq!(ran_on.len(), 4);
+        while let Ok(core) = ran_on.pop() {
+            assert_eq!(core, 0);
+        }
+    }
+
+    /// Test that an idle core steals (only) threads that aren't pinned from
+    /// another core, and only if work stealing is on.
+    #[test]
+    fn work_stealing() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Default::default();
+
+        let ran_on: Arc<ArrayQueue<(usize, usize)>> = Arc::new(ArrayQueue::new(4));
+        let mut tids = Vec::new();
+        for i in 0..4 {
+            let ran_on = ran_on.clone();
+            let tid = s.spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let _r = ran_on.push((i, Environment::core_id()));
+                },
+                ptr::null_mut(),
+                1,
+            );
+            tids.push(tid.expect("Can't spawn thread"));
+        }
+        assert!(s.set_pinned(tids[0], true));
+
+        let scb0: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb0);
+        assert!(ran_on.is_empty());
+
+        s.set_work_stealing(true);
+        s.run(&scb0);
+        assert_eq!(ran_on.len(), 3);
+        // Newest threads get stolen first
+        assert_eq!(ran_on.pop(), Ok((3, 0)));
+        assert_eq!(ran_on.pop(), Ok((2, 0)));
+        assert_eq!(ran_on.pop(), Ok((1, 0)));
+        assert_eq!(s.per_core[1].runnable.lock().len(), 1);
+
+        let scb1: SchedulerControlBlock = SchedulerControlBlock::new(1);
+        s.run(&scb1);
+        assert_eq!(ran_on.pop(), Ok((0, 1)));
+        assert!(!s.has_active_threads());
+        assert!(!s.set_pinned(tids[0], false));
+    }
+
+    /// Test that a core only goes idle if nothing could become runnable on it
+    /// without waking it up, and that threads it gets wake it up.
+    #[test]
+    fn idle() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Default::default();
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(1);
+        assert!(s.can_idle(&scb));
+
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().sleep(Duration::from_millis(1));
+            },
+            ptr::null_mut(),
+            1,
+        );
+        assert_eq!(s.per_core[1].wakeups.load(Ordering::SeqCst), 1);
+        assert!(!s.can_idle(&scb));
+
+        // The thread waits for its timeout
+        s.run(&scb);
+        assert!(s.has_active_threads());
+        assert!(!s.can_idle(&scb));
+
+        while s.has_active_threads() {
+            s.run(&scb);
+            s.idle(&scb);
+        }
+        assert!(s.can_idle(&scb));
+        assert!(!s.per_core[1].idle.load(Ordering::SeqCst));
+
+        s.set_work_stealing(true);
+        assert!(!s.can_idle(&scb));
+    }
+
+    /// Test that only threads that run and used up their time slice should
+    /// get preempted.
+    #[test]
+    fn should_preempt() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Default::default();
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        assert!(!s.should_preempt(&scb, u64::max_value()));
+
+        s.set_time_slice(1000);
+        let s1 = s.clone();
+        let preempt: Arc<ArrayQueue<bool>> = Arc::new(ArrayQueue::new(2));
+        let preempt1 = preempt.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let scb = Environment::scheduler();
+                let since = scb.running_since().expect("The thread is running");
+                let _r = preempt1.push(s1.should_preempt(scb, since + 999));
+                let _r = preempt1.push(s1.should_preempt(scb, since + 1000));
+            },
+            ptr::null_mut(),
+            0,
+        );
+        s.run(&scb);
+
+        assert_eq!(preempt.pop(), Ok(false));
+        assert_eq!(preempt.pop(), Ok(true));
+        // Nothing runs anymore
+        assert_eq!(scb.running_since(), None);
+        assert!(!s.should_preempt(&scb, u64::max_value()));
+    }
+
+    /// Test that we find out how much stack threads used if they keep track
+    /// of it.
+    #[test]
+    fn stack_stats() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Default::default();
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+
+        let tracked = StackOptions {
+            track_usage: true,
+            ..Default::default()
+        };
+        let stack = tracked.allocate();
+        assert_eq!(
+            stack.usage().map(|u| unsafe { u.high_water_mark() }),
+            Some(0)
+        );
+
+        let tid = s
+            .spawn_with_args(
+                stack,
+                move |_| {
+                    let mut buf = [0u8; 4096];
+                    for byte in buf.iter_mut() {
+                        unsafe { core::ptr::write_volatile(byte, 1) };
+                    }
+                },
+                ptr::null_mut(),
+                0,
+                None,
+                unsafe { tls2::ThreadControlBlock::new_tls_area() },
+            )
+            .expect("Can't spawn thread");
+        // Threads that don't keep track don't show up
+        let _untracked = s.spawn_with_args(
+            LineupStack::from_size(DEFAULT_STACK_SIZE_BYTES),
+            move |_| {},
+            ptr::null_mut(),
+            0,
+            None,
+            unsafe { tls2::ThreadControlBlock::new_tls_area() },
+        );
+
+        let stats = s.stack_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].tid, tid);
+        assert!(!stats[0].exited);
+        assert!(stats[0].high_water_mark < 4096);
+
+        while s.has_active_threads() {
+            s.run(&scb);
+        }
+        let stats = s.stack_stats();
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].exited);
+        assert!(stats[0].high_water_mark >= 4096);
+        assert!(stats[0].high_water_mark <= DEFAULT_STACK_SIZE_BYTES);
+    }
+
     /// Test that waitlist inserts are inserted with correct order.
     #[test]
     fn waitlist_inserts_are_sorted() {
@@ -1,18 +1,193 @@
 use alloc::alloc::alloc;
 use alloc::alloc::dealloc;
+use alloc::sync::Arc;
+#[cfg(target_os = "bespin")]
+use alloc::vec::Vec;
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use fringe::Stack;
 
 /// Default stack size in bytes.
 pub const DEFAULT_STACK_SIZE_BYTES: usize = 32 * 4096;
 
-/// LineupStack holds a non-guarded, heap-allocated stack.
+/// Size of the guard page below a guarded stack (see `LineupStack::with_guard`).
+pub const GUARD_PAGE_SIZE: usize = 4096;
+
+/// How much a growable stack grows (at least) once a thread runs out of it
+/// (see `LineupStack::growable`).
+pub const STACK_GROWTH_BYTES: usize = 4 * 4096;
+
+/// What we fill stacks with to find out how much of them threads used (see
+/// `LineupStack::track_usage`).
+const USAGE_PATTERN: u64 = 0x57ac_57ac_57ac_57ac;
+
+/// Address space we reserve for guarded stacks (nothing else maps memory
+/// there, so the page below every stack stays unmapped).
+#[cfg(target_os = "bespin")]
+const GUARDED_STACKS_BASE: usize = 0x700_0000_0000;
+#[cfg(target_os = "bespin")]
+const GUARDED_STACKS_LIMIT: usize = 0x800_0000_0000;
+
+/// Next free address for a guarded stack (including its guard page).
+#[cfg(target_os = "bespin")]
+static GUARDED_STACKS_NEXT: AtomicUsize = AtomicUsize::new(GUARDED_STACKS_BASE);
+
+/// Guarded stacks that were dropped, they stay mapped (as far as they grew)
+/// and get reused for the next guarded stack with the same maximum size.
+#[cfg(target_os = "bespin")]
+static FREE_GUARDED_STACKS: spin::Mutex<Vec<StackRegion>> = spin::Mutex::new(Vec::new());
+
+/// Is `addr` in the address space we reserve for guarded stacks?
+#[cfg(target_os = "bespin")]
+pub fn in_stack_area(addr: usize) -> bool {
+    GUARDED_STACKS_BASE <= addr && addr < GUARDED_STACKS_LIMIT
+}
+
+/// The address space of a guarded stack (see `LineupStack::growable`).
+#[derive(Debug, Clone)]
+pub struct StackRegion {
+    /// Lowest address of the stack, the guard page is below it.
+    limit: usize,
+    /// End of the stack.
+    base: usize,
+    /// The stack is mapped in `[mapped, base)`, it grows down to `limit` on
+    /// demand.
+    mapped: Arc<AtomicUsize>,
+}
+
+impl PartialEq for StackRegion {
+    fn eq(&self, other: &StackRegion) -> bool {
+        self.limit == other.limit && self.base == other.base
+    }
+}
+
+impl StackRegion {
+    /// The address range `[start, end)` of the guard page below the stack.
+    pub fn guard(&self) -> (usize, usize) {
+        (self.limit - GUARD_PAGE_SIZE, self.limit)
+    }
+
+    /// Is `addr` in the part of the stack that isn't mapped yet?
+    pub fn can_grow(&self, addr: usize) -> bool {
+        self.limit <= addr && addr < self.mapped.load(Ordering::Acquire)
+    }
+
+    /// Maps the stack down to the page `addr` is in (and at least
+    /// `STACK_GROWTH_BYTES` more than before).
+    #[cfg(target_os = "bespin")]
+    pub fn grow(&self, addr: usize) {
+        let mapped = self.mapped.load(Ordering::Acquire);
+        let start = core::cmp::min(
+            addr & !(GUARD_PAGE_SIZE - 1),
+            mapped.saturating_sub(STACK_GROWTH_BYTES),
+        );
+        self.map_from(core::cmp::max(start, self.limit));
+    }
+
+    /// Makes sure the stack is mapped in `[start, base)`.
+    #[cfg(target_os = "bespin")]
+    fn map_from(&self, start: usize) {
+        let mapped = self.mapped.load(Ordering::Acquire);
+        if start < mapped {
+            unsafe {
+                kpi::syscalls::VSpace::map(start as u64, (mapped - start) as u64)
+                    .expect("Can't map stack");
+            }
+            self.mapped.store(start, Ordering::Release);
+        }
+    }
+}
+
+/// How to allocate the stack of a thread.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StackOptions {
+    /// How many bytes of stack the thread starts with.
+    pub size: usize,
+    /// Put a guard page below the stack (see `LineupStack::with_guard`).
+    pub guard: bool,
+    /// How far the stack can grow (only for guarded stacks, see
+    /// `LineupStack::growable`), `None` if it stays at `size`.
+    pub max_size: Option<usize>,
+    /// Keep track of how much of the stack the thread used (see
+    /// `LineupStack::track_usage`).
+    pub track_usage: bool,
+}
+
+impl Default for StackOptions {
+    fn default() -> Self {
+        StackOptions {
+            size: DEFAULT_STACK_SIZE_BYTES,
+            guard: false,
+            max_size: None,
+            track_usage: false,
+        }
+    }
+}
+
+impl StackOptions {
+    /// Allocates a stack with these options.
+    pub fn allocate(&self) -> LineupStack {
+        let stack = match (self.guard, self.max_size) {
+            (true, Some(max_size)) => LineupStack::growable(self.size, max_size),
+            (true, None) => LineupStack::with_guard(self.size),
+            (false, _) => LineupStack::from_size(self.size),
+        };
+        if self.track_usage {
+            stack.track_usage()
+        } else {
+            stack
+        }
+    }
+}
+
+/// Finds out how much of a stack a thread used (see
+/// `LineupStack::track_usage`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackUsage {
+    /// From where on we filled the stack with `USAGE_PATTERN`.
+    painted: usize,
+    /// End of the stack.
+    base: usize,
+    /// Where the stack is if it can grow.
+    region: Option<StackRegion>,
+}
+
+impl StackUsage {
+    /// How many bytes of the stack were in use at most (so far).
+    ///
+    /// Once a growable stack grew, this is as precise as the growth (it
+    /// counts what's mapped).
+    ///
+    /// # Safety
+    /// The stack must still exist.
+    pub unsafe fn high_water_mark(&self) -> usize {
+        if let Some(region) = self.region.as_ref() {
+            let mapped = region.mapped.load(Ordering::Acquire);
+            if mapped < self.painted {
+                return self.base - mapped;
+            }
+        }
+
+        let mut addr = self.painted;
+        while addr < self.base && core::ptr::read_volatile(addr as *const u64) == USAGE_PATTERN {
+            addr += core::mem::size_of::<u64>();
+        }
+        self.base - addr
+    }
+}
+
+/// LineupStack holds a heap-allocated stack, or a stack with a guard page
+/// below it (see `LineupStack::growable`).
 #[derive(Debug, PartialEq)]
 pub struct LineupStack {
     base_ptr: *mut u8,
     layout: Layout,
     dealloc: bool,
+    /// Where the stack is if it has a guard page below `base_ptr`.
+    region: Option<StackRegion>,
+    /// Set if we keep track of how much of the stack is used.
+    usage: Option<StackUsage>,
 }
 
 impl Default for LineupStack {
@@ -36,10 +211,117 @@ impl LineupStack {
                 base_ptr,
                 layout,
                 dealloc: true,
+                region: None,
+                usage: None,
             }
         }
     }
 
+    /// Allocates a new stack with `size` accessible bytes (rounded up to
+    /// pages) and an unmapped guard page below it: overflowing the stack
+    /// faults (see `SmpScheduler::overflowed_stack`) instead of silently
+    /// corrupting adjacent memory.
+    pub fn with_guard(size: usize) -> LineupStack {
+        LineupStack::growable(size, size)
+    }
+
+    /// Allocates a new guarded stack (see `with_guard`) that can hold up to
+    /// `max` bytes, only the top `initial` bytes are mapped. The rest is
+    /// mapped once the thread runs out of stack (see
+    /// `SmpScheduler::grow_stack`).
+    #[cfg(target_os = "bespin")]
+    pub fn growable(initial: usize, max: usize) -> LineupStack {
+        let max = (max + GUARD_PAGE_SIZE - 1) & !(GUARD_PAGE_SIZE - 1);
+        let initial = core::cmp::min(
+            (initial + GUARD_PAGE_SIZE - 1) & !(GUARD_PAGE_SIZE - 1),
+            max,
+        );
+
+        let reused = {
+            let mut free = FREE_GUARDED_STACKS.lock();
+            free.iter()
+                .position(|region| region.base - region.limit == max)
+                .map(|idx| free.swap_remove(idx))
+        };
+        let region = reused.unwrap_or_else(|| {
+            let guard = GUARDED_STACKS_NEXT.fetch_add(GUARD_PAGE_SIZE + max, Ordering::Relaxed);
+            assert!(
+                guard + GUARD_PAGE_SIZE + max <= GUARDED_STACKS_LIMIT,
+                "Out of address space for guarded stacks"
+            );
+
+            let limit = guard + GUARD_PAGE_SIZE;
+            StackRegion {
+                limit,
+                base: limit + max,
+                mapped: Arc::new(AtomicUsize::new(limit + max)),
+            }
+        });
+        region.map_from(region.base - initial);
+
+        unsafe {
+            LineupStack {
+                base_ptr: region.limit as *mut u8,
+                layout: Layout::from_size_align_unchecked(max, fringe::STACK_ALIGNMENT),
+                dealloc: true,
+                region: Some(region),
+                usage: None,
+            }
+        }
+    }
+
+    /// Guard pages need support from the OS, we don't have it here so this
+    /// is the same as `from_size(max)`.
+    #[cfg(target_family = "unix")]
+    pub fn growable(_initial: usize, max: usize) -> LineupStack {
+        LineupStack::from_size(max)
+    }
+
+    /// The address range `[start, end)` of the guard page below the stack
+    /// (if it has one).
+    pub fn guard(&self) -> Option<(usize, usize)> {
+        self.region.as_ref().map(|region| region.guard())
+    }
+
+    /// Where the stack is (if it has a guard page).
+    pub fn region(&self) -> Option<StackRegion> {
+        self.region.clone()
+    }
+
+    /// Fills the stack with a pattern, so we can tell how much of it a
+    /// thread used later on (see `usage`, `SmpScheduler::stack_stats`).
+    ///
+    /// Only the part of a growable stack that is mapped already gets
+    /// filled.
+    pub fn track_usage(mut self) -> LineupStack {
+        let base = self.base() as usize;
+        let painted = self
+            .region
+            .as_ref()
+            .map_or(self.limit() as usize, |region| {
+                region.mapped.load(Ordering::Acquire)
+            });
+
+        let words = (base - painted) / core::mem::size_of::<u64>();
+        let stack = unsafe { core::slice::from_raw_parts_mut(painted as *mut u64, words) };
+        for word in stack.iter_mut() {
+            *word = USAGE_PATTERN;
+        }
+
+        self.usage = Some(StackUsage {
+            painted,
+            base,
+            region: self.region.clone(),
+        });
+        self
+    }
+
+    /// How to find out how much of the stack is used (if we keep track of
+    /// it, see `track_usage`).
+    pub fn usage(&self) -> Option<StackUsage> {
+        self.usage.clone()
+    }
+
     pub fn from_ptr(base_ptr: *mut u8, size: usize, dealloc: bool) -> LineupStack {
         unsafe {
             let aligned_size = size & !(fringe::STACK_ALIGNMENT - 1);
@@ -50,6 +332,8 @@ impl LineupStack {
                 base_ptr,
                 layout,
                 dealloc,
+                region: None,
+                usage: None,
             }
         }
     }
@@ -57,7 +341,10 @@ impl LineupStack {
 
 impl Drop for LineupStack {
     fn drop(&mut self) {
-        if self.dealloc {
+        if let Some(_region) = self.region.take() {
+            #[cfg(target_os = "bespin")]
+            FREE_GUARDED_STACKS.lock().push(_region);
+        } else if self.dealloc {
             unsafe { dealloc(self.base_ptr, self.layout) }
         }
     }
@@ -7,7 +7,7 @@ use core::ptr;
 use fringe::generator::{Generator, Yielder};
 use rawtime::Instant;
 
-use crate::stack::LineupStack;
+use crate::stack::{LineupStack, StackRegion, StackUsage};
 use crate::tls2::{self, ThreadControlBlock};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
@@ -48,6 +48,18 @@ pub(crate) struct Thread {
     /// Threads currently waiting (join, blocked) on us to exit.
     pub(crate) joinlist: Vec<(ThreadId, CoreId)>,
 
+    /// Pinned threads never get stolen by other cores (see
+    /// `SmpScheduler::set_work_stealing`).
+    pub(crate) pinned: bool,
+
+    /// Where the stack of the thread is if it's guarded (see
+    /// `LineupStack::growable`).
+    pub(crate) stack_region: Option<StackRegion>,
+
+    /// Set if we keep track of how much stack the thread uses (see
+    /// `LineupStack::track_usage`).
+    pub(crate) stack_usage: Option<StackUsage>,
+
     /// Storage to remember the pointer to the TCB
     ///
     /// TODO(correctness): It's not really static (it's on the thread's stack),
@@ -103,6 +115,9 @@ impl Thread {
             return_with: None,
             interrupt_vector,
             joinlist: Vec::with_capacity(crate::scheduler::SmpScheduler::MAX_THREADS),
+            pinned: false,
+            stack_region: stack.region(),
+            stack_usage: stack.usage(),
             state: tcb,
         };
 
@@ -111,11 +126,13 @@ impl Thread {
                 &Yielder<YieldResume, YieldRequest>,
                 &'static Yielder<YieldResume, YieldRequest>,
             >(yielder));
+            tls2::Environment::scheduler().thread_running();
 
             // rump lwp switchproc stuff here
             let r = f(arg);
 
             // Reset TCB/TLS once thread completes
+            tls2::Environment::scheduler().thread_stopped();
             tls2::arch::set_tcb(ptr::null_mut());
 
             // deallocate TLS? this shouldnt be done if the tls pointer comes from _rtld_tls_alloc
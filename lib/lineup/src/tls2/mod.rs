@@ -17,14 +17,14 @@ use alloc::vec::Vec;
 use core::mem;
 use core::ops::Add;
 use core::ptr;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 
 use fringe::generator::Yielder;
 
 use crossbeam_queue::ArrayQueue;
 use rawtime::{Duration, Instant};
 
-use crate::stack::LineupStack;
+use crate::stack::{LineupStack, StackOptions};
 use crate::threads::{ThreadId, YieldRequest, YieldResume};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
@@ -152,12 +152,24 @@ impl<'a> ThreadControlBlock<'a> {
         tcb: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId> {
         let request = YieldRequest::SpawnWithArgs(s, f, arg, core_id, irq_vector, tcb);
-        match self.yielder().suspend(request) {
+        match self.yield_request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
     }
 
+    /// Spawns a thread on `core_id` with a stack allocated as `options` say.
+    pub fn spawn_with_stack(
+        &self,
+        options: StackOptions,
+        f: Option<unsafe extern "C" fn(arg1: *mut u8) -> *mut u8>,
+        arg: *mut u8,
+        core_id: CoreId,
+    ) -> Option<ThreadId> {
+        let tcb = unsafe { ThreadControlBlock::new_tls_area() };
+        self.spawn_with_args(options.allocate(), f, arg, core_id, None, tcb)
+    }
+
     pub fn spawn_on_core(
         &self,
         f: Option<unsafe extern "C" fn(arg1: *mut u8) -> *mut u8>,
@@ -165,7 +177,7 @@ impl<'a> ThreadControlBlock<'a> {
         core_id: CoreId,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, None);
-        match self.yielder().suspend(request) {
+        match self.yield_request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -179,7 +191,7 @@ impl<'a> ThreadControlBlock<'a> {
         irq_vector: IrqVector,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, Some(irq_vector));
-        match self.yielder().suspend(request) {
+        match self.yield_request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -191,7 +203,7 @@ impl<'a> ThreadControlBlock<'a> {
         arg: *mut u8,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, self.current_core, None);
-        match self.yielder().suspend(request) {
+        match self.yield_request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -199,36 +211,46 @@ impl<'a> ThreadControlBlock<'a> {
 
     pub fn sleep(&self, d: Duration) {
         let request = YieldRequest::Timeout(Instant::now().add(d));
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub fn block(&self) {
         let request = YieldRequest::Unrunnable(Environment::tid());
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub fn make_runnable(&self, tid: ThreadId) {
         let request = YieldRequest::Runnable(tid);
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub fn make_all_runnable(&self, tids: Vec<ThreadId>) {
         let request = YieldRequest::RunnableList(tids);
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub fn make_unrunnable(&self, tid: ThreadId) {
         let request = YieldRequest::Unrunnable(tid);
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub fn join(&self, tid: ThreadId) {
         let request = YieldRequest::JoinOn(tid);
-        self.yielder().suspend(request);
+        self.yield_request(request);
     }
 
     pub(crate) fn suspend(&self, request: YieldRequest) {
-        self.yielder().suspend(request);
+        self.yield_request(request);
+    }
+
+    /// Hands `request` to the scheduler, returns its answer once the thread
+    /// runs again (a thread can only be preempted while it runs, see
+    /// `SmpScheduler::should_preempt`).
+    fn yield_request(&self, request: YieldRequest) -> YieldResume {
+        Environment::scheduler().thread_stopped();
+        let resume = self.yielder().suspend(request);
+        Environment::scheduler().thread_running();
+        resume
     }
 
     pub fn relinquish(&self) {
@@ -256,6 +278,10 @@ pub struct SchedulerControlBlock {
 
     /// Core identifier of this scheduler state
     pub core_id: usize,
+
+    /// rdtsc value when the thread that runs on this core got (re-)started,
+    /// 0 if no thread runs right now.
+    running_since: AtomicU64,
 }
 
 impl SchedulerControlBlock {
@@ -266,6 +292,27 @@ impl SchedulerControlBlock {
             pending_irqs: ArrayQueue::new(4),
             rump_upcalls: AtomicPtr::new(ptr::null_mut()),
             core_id,
+            running_since: AtomicU64::new(0),
+        }
+    }
+
+    /// A thread started running on this core.
+    pub(crate) fn thread_running(&self) {
+        let now = unsafe { x86::time::rdtsc() };
+        self.running_since.store(now, Ordering::Relaxed);
+    }
+
+    /// The thread that ran on this core stopped (it yielded or completed).
+    pub(crate) fn thread_stopped(&self) {
+        self.running_since.store(0, Ordering::Relaxed);
+    }
+
+    /// Since when (rdtsc) the thread that runs on this core has been
+    /// running, `None` if the core runs the scheduler (or nothing).
+    pub fn running_since(&self) -> Option<u64> {
+        match self.running_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(since),
         }
     }
 }
@@ -338,6 +385,18 @@ impl Environment {
         }
     }
 
+    /// Like `scheduler` but `None` if this core has no scheduler state (yet).
+    pub fn try_scheduler<'a>() -> Option<&'a SchedulerControlBlock> {
+        unsafe {
+            let scb = arch::get_scb();
+            if !scb.is_null() && (scb as u64) < KERNEL_BASE {
+                Some(&*scb)
+            } else {
+                None
+            }
+        }
+    }
+
     // This method returns the core-id for the current thread. It is needed because
     // SchedulerControlBlock allocates an ArrayQueue and that leads to recursive fault.
     pub fn core_id() -> CoreId {
@@ -0,0 +1,190 @@
+//! Asynchronous system calls.
+//!
+//! An `AsyncRing` batches file and vspace operations: they're queued with
+//! `read`, `write`, `map` etc. and the kernel executes them the next time it
+//! schedules the process or when `enter` is called. The results come back
+//! (in the same order) through `completion`.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::sync::atomic::Ordering;
+
+use kpi::asyncio::{
+    completion_offset, ring_size, submission_offset, Completion, RingHeader, Submission,
+};
+use kpi::syscalls::Async;
+use kpi::{FileRequest, SystemCallError, VSpaceRequest};
+
+/// The asynchronous system call ring of the process.
+///
+/// A process can only have one ring at a time, `new` fails while another
+/// one exists.
+pub struct AsyncRing {
+    base: *mut u8,
+    layout: Layout,
+    entries: u64,
+}
+
+impl AsyncRing {
+    /// Allocates a ring with space for `entries` submissions (and
+    /// completions) and registers it with the kernel.
+    pub fn new(entries: u64) -> Result<AsyncRing, SystemCallError> {
+        let layout = Layout::from_size_align(ring_size(entries), 4096)
+            .map_err(|_e| SystemCallError::NotSupported)?;
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(SystemCallError::OutOfMemory);
+        }
+
+        match unsafe { Async::setup(base as u64, entries) } {
+            Ok(()) => Ok(AsyncRing {
+                base,
+                layout,
+                entries,
+            }),
+            Err(e) => {
+                unsafe { dealloc(base, layout) };
+                Err(e)
+            }
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.base as *const RingHeader) }
+    }
+
+    /// Queues a system call, fails with `WouldBlock` if the submission queue
+    /// is full.
+    ///
+    /// # Safety
+    /// Memory the system call refers to has to stay valid until it
+    /// completed.
+    pub unsafe fn submit(&mut self, submission: Submission) -> Result<(), SystemCallError> {
+        let header = self.header();
+        let tail = header.sq_tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(header.sq_head.load(Ordering::Acquire)) >= self.entries {
+            return Err(SystemCallError::WouldBlock);
+        }
+
+        let entry = self.base.add(submission_offset(self.entries, tail)) as *mut Submission;
+        entry.write_volatile(submission);
+        header
+            .sq_tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Queues a read from file `fd` into `buf` (at `offset` if given).
+    ///
+    /// # Safety
+    /// `buf` has to stay valid until the read completed.
+    pub unsafe fn read(
+        &mut self,
+        fd: u64,
+        buf: &mut [u8],
+        offset: Option<i64>,
+        user_data: u64,
+    ) -> Result<(), SystemCallError> {
+        let buffer = buf.as_mut_ptr() as u64;
+        let len = buf.len() as u64;
+        let request = match offset {
+            Some(offset) => FileRequest::ReadAt {
+                fd,
+                buffer,
+                len,
+                offset: offset as u64,
+            },
+            None => FileRequest::Read { fd, buffer, len },
+        };
+        self.submit(request.submission(user_data))
+    }
+
+    /// Queues a write of `buf` to file `fd` (at `offset` if given).
+    ///
+    /// # Safety
+    /// `buf` has to stay valid until the write completed.
+    pub unsafe fn write(
+        &mut self,
+        fd: u64,
+        buf: &[u8],
+        offset: Option<i64>,
+        user_data: u64,
+    ) -> Result<(), SystemCallError> {
+        let buffer = buf.as_ptr() as u64;
+        let len = buf.len() as u64;
+        let request = match offset {
+            Some(offset) => FileRequest::WriteAt {
+                fd,
+                buffer,
+                len,
+                offset: offset as u64,
+            },
+            None => FileRequest::Write { fd, buffer, len },
+        };
+        self.submit(request.submission(user_data))
+    }
+
+    /// Queues mapping `size` bytes of anonymous memory at `base`.
+    ///
+    /// The completion has how much was mapped: large regions might only be
+    /// mapped in part (if the kernel had to give the core back), the rest
+    /// has to be queued again.
+    pub fn map(&mut self, base: u64, size: u64, user_data: u64) -> Result<(), SystemCallError> {
+        unsafe { self.submit(VSpaceRequest::Map { base, size }.submission(user_data)) }
+    }
+
+    /// Queues unmapping the region at `base`.
+    ///
+    /// # Safety
+    /// Nothing may use the region anymore.
+    pub unsafe fn unmap(&mut self, base: u64, user_data: u64) -> Result<(), SystemCallError> {
+        self.submit(VSpaceRequest::Unmap { base, size: 0 }.submission(user_data))
+    }
+
+    /// Has the kernel execute the queued system calls now, returns how many
+    /// completed.
+    pub fn enter(&self) -> Result<u64, SystemCallError> {
+        Async::enter()
+    }
+
+    /// Returns the next completion (if there is one).
+    pub fn completion(&mut self) -> Option<Completion> {
+        let header = self.header();
+        let head = header.cq_head.load(Ordering::Relaxed);
+        if head == header.cq_tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let completion = unsafe {
+            let entry = self.base.add(completion_offset(self.entries, head)) as *const Completion;
+            entry.read_volatile()
+        };
+        header
+            .cq_head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+
+    /// Returns the next completion, enters the kernel if there is none yet.
+    ///
+    /// Returns None if nothing was queued.
+    pub fn wait(&mut self) -> Result<Option<Completion>, SystemCallError> {
+        match self.completion() {
+            Some(completion) => Ok(Some(completion)),
+            None => {
+                self.enter()?;
+                Ok(self.completion())
+            }
+        }
+    }
+}
+
+impl Drop for AsyncRing {
+    fn drop(&mut self) {
+        unsafe {
+            // Only free the memory once the kernel stopped using it
+            if Async::setup(0, 0).is_ok() {
+                dealloc(self.base, self.layout);
+            }
+        }
+    }
+}
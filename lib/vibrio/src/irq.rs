@@ -0,0 +1,63 @@
+//! Delivery of device interrupts to user-space drivers.
+//!
+//! An interrupt line that was allocated with `Irq::irqalloc` arrives as an
+//! upcall on the core it is routed to. A driver either registers a handler
+//! for its vector (`register_handler`) or, with lineup, spawns a thread that
+//! is woken up for it (`spawn_irq_thread`). Interrupts that come in while
+//! upcalls are disabled are recorded in the vCPU area by the kernel and
+//! handled on the next upcall.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kpi::arch::VirtualCpu;
+use log::error;
+
+/// Registered handlers, indexed by vector (0 if there is none).
+static HANDLERS: [AtomicUsize; 256] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; 256]
+};
+
+/// Calls `handler` (with the vector) for every interrupt with `vector`
+/// (`gsi + kpi::io::IRQ_VECTOR_BASE`), replaces a handler registered earlier.
+///
+/// The handler runs on the upcall stack with upcalls disabled: it must not
+/// block or touch memory that isn't mapped yet. It's usually enough to
+/// acknowledge the device and wake up a thread.
+pub fn register_handler(vector: u64, handler: fn(u64)) {
+    HANDLERS[vector as usize % HANDLERS.len()].store(handler as usize, Ordering::Release);
+}
+
+/// Stops calling the handler registered for `vector`.
+pub fn unregister_handler(vector: u64) {
+    HANDLERS[vector as usize % HANDLERS.len()].store(0, Ordering::Release);
+}
+
+/// Handles the interrupts that came in while upcalls were disabled.
+pub(crate) fn dispatch_pending(control: &mut VirtualCpu) {
+    while let Some(vector) = control.take_pending_irq() {
+        dispatch(vector);
+    }
+}
+
+/// Handles interrupt `vector` (with its handler or the lineup thread for
+/// it).
+pub(crate) fn dispatch(vector: u64) {
+    let handler = HANDLERS[vector as usize % HANDLERS.len()].load(Ordering::Acquire);
+    if handler != 0 {
+        // Safe: only `register_handler` stores (valid) handlers here
+        let handler: fn(u64) = unsafe { core::mem::transmute(handler) };
+        handler(vector);
+        return;
+    }
+
+    // Wake up the thread lineup has for it
+    match lineup::tls2::Environment::try_scheduler() {
+        Some(scheduler) => {
+            if scheduler.pending_irqs.push(vector).is_err() {
+                error!("Overflowed pending_irqs, missed vector {:#x}", vector);
+            }
+        }
+        None => error!("No handler for interrupt vector {:#x}", vector),
+    }
+}
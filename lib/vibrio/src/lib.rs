@@ -11,19 +11,28 @@
     ptr_internals,
     ptr_offset_from,
     llvm_asm,
+    global_asm,
     lang_items,
     thread_local
 )]
 extern crate alloc;
 extern crate kpi;
 
+pub use kpi::eventring;
 pub use kpi::io;
+pub use kpi::process;
 pub use kpi::syscalls;
+pub use kpi::upcall;
+pub use kpi::SystemCallError;
 
 extern crate arrayvec;
 extern crate lazy_static;
 
+pub mod asyncring;
+pub mod irq;
 pub mod mem;
+pub mod pipe;
+pub mod pkeys;
 pub mod upcalls;
 pub mod vconsole;
 pub mod writer;
@@ -8,8 +8,8 @@ use core::ptr::{self, NonNull};
 
 use arrayvec::ArrayVec;
 use lazy_static::lazy_static;
-use log::{error, warn};
-use spin::Mutex;
+use log::{error, info, warn};
+use spin::{Mutex, Once};
 use x86::current::paging::{PAddr, VAddr};
 
 use kpi::SystemCallError;
@@ -27,10 +27,118 @@ macro_rules! round_up {
 }
 
 // Max number of cores supported by the allocator.
-const MAX_CORES: usize = 96;
+pub(crate) const MAX_CORES: usize = 96;
 
 static MEM_PROVIDER: crate::mem::SafeZoneAllocator = crate::mem::SafeZoneAllocator::new();
 
+/// Tuning knobs for the allocator.
+///
+/// The size-classes themselves are fixed by slabmalloc, but we can decide
+/// which of them are served by the ZoneAllocator and how much memory every
+/// (per-core) ZoneAllocator caches.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AllocatorConfig {
+    /// Allocations bigger than this go directly to the pager (and use one
+    /// or more large pages) instead of a size-class in the ZoneAllocator.
+    ///
+    /// Can be at most `ZoneAllocator::MAX_ALLOC_SIZE`.
+    pub slab_limit: usize,
+    /// Number of base pages we add to a size-class when it runs out of memory.
+    pub refill_pages: usize,
+    /// Number of large pages we add to a size-class when it runs out of memory.
+    pub refill_large_pages: usize,
+}
+
+impl AllocatorConfig {
+    pub const fn new() -> AllocatorConfig {
+        AllocatorConfig {
+            slab_limit: ZoneAllocator::MAX_ALLOC_SIZE,
+            refill_pages: 1,
+            refill_large_pages: 1,
+        }
+    }
+
+    /// Parses a configuration string of the form `key:value,key:value`.
+    ///
+    /// Valid keys are `slab_limit`, `refill` and `refill_large`; keys that
+    /// are not present keep their default value.
+    ///
+    /// # Example
+    /// `slab_limit:4096,refill:8`
+    pub fn parse(conf: &str) -> Result<AllocatorConfig, &'static str> {
+        let mut config = AllocatorConfig::new();
+
+        for knob in conf.split(',').filter(|knob| !knob.is_empty()) {
+            let mut kv = knob.splitn(2, ':');
+            let key = kv.next().unwrap_or("");
+            let value: usize = kv
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or("Allocator knob needs a numeric value")?;
+
+            match key {
+                "slab_limit" => config.slab_limit = value,
+                "refill" => config.refill_pages = value,
+                "refill_large" => config.refill_large_pages = value,
+                _ => return Err("Unknown allocator knob"),
+            }
+        }
+
+        if config.slab_limit > ZoneAllocator::MAX_ALLOC_SIZE {
+            return Err("slab_limit is bigger than the biggest size-class");
+        }
+        if config.refill_pages == 0 || config.refill_large_pages == 0 {
+            return Err("Need to refill at least one page");
+        }
+
+        Ok(config)
+    }
+}
+
+static DEFAULT_CONFIG: AllocatorConfig = AllocatorConfig::new();
+
+static CONFIG: Once<AllocatorConfig> = Once::new();
+
+/// Sets the allocator configuration for the process.
+///
+/// This can only be done once and should happen as early as possible:
+/// objects that were allocated before with a size above the new `slab_limit`
+/// are leaked when they're freed. Returns false if the allocator was
+/// already configured.
+pub fn configure(config: AllocatorConfig) -> bool {
+    let mut configured = false;
+    CONFIG.call_once(|| {
+        configured = true;
+        config
+    });
+    configured
+}
+
+/// Configures the allocator with the knobs passed in `malloc=` on the kernel
+/// command line (see [`AllocatorConfig::parse`]).
+pub fn configure_from(malloc_conf: &str) {
+    if malloc_conf.is_empty() {
+        return;
+    }
+
+    match AllocatorConfig::parse(malloc_conf) {
+        Ok(config) => {
+            if configure(config) {
+                info!("Allocator configured with {:?}", config);
+            } else {
+                warn!("Allocator was already configured, ignore {:?}", config);
+            }
+        }
+        Err(e) => error!("Can't parse allocator config '{}': {}", malloc_conf, e),
+    }
+}
+
+/// Returns the current allocator configuration.
+#[inline(always)]
+pub fn config() -> &'static AllocatorConfig {
+    CONFIG.r#try().unwrap_or(&DEFAULT_CONFIG)
+}
+
 #[cfg(target_os = "bespin")]
 #[global_allocator]
 static PER_CORE_MEM_PROVIDER: crate::mem::PerCoreAllocator = crate::mem::PerCoreAllocator::new();
@@ -49,6 +157,11 @@ impl Pager {
     const BASE_PAGE_SIZE: usize = 4096;
     const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
 
+    /// A pager that maps memory in `[sbrk, limit)`.
+    pub(crate) const fn new(sbrk: u64, limit: u64) -> Pager {
+        Pager { sbrk, limit }
+    }
+
     /// Allocates a given `page_size`.
     fn alloc_page(&mut self, page_size: usize) -> Option<*mut u8> {
         let (vaddr, _paddr) =
@@ -178,39 +291,42 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
             None
         }
 
+        let config = config();
         match layout.size() {
-            0..=ZoneAllocator::MAX_ALLOC_SIZE => {
+            size if size <= config.slab_limit => {
                 let mut zone_allocator = self.0.lock();
                 match zone_allocator.allocate(layout) {
                     Ok(nptr) => nptr.as_ptr(),
                     Err(AllocationError::OutOfMemory) => {
                         if layout.size() <= ZoneAllocator::MAX_BASE_ALLOC_SIZE {
-                            try_alloc_page().map_or(ptr::null_mut(), |page| {
-                                zone_allocator
-                                    .refill(layout, page)
-                                    .expect("Could not refill?");
-                                zone_allocator
-                                    .allocate(layout)
-                                    .expect("Should succeed after refill")
-                                    .as_ptr()
-                            })
+                            for _i in 0..config.refill_pages {
+                                match try_alloc_page() {
+                                    Some(page) => zone_allocator
+                                        .refill(layout, page)
+                                        .expect("Could not refill?"),
+                                    None => break,
+                                }
+                            }
                         } else {
                             // layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE
-                            try_alloc_largepage().map_or(ptr::null_mut(), |large_page| {
-                                zone_allocator
-                                    .refill_large(layout, large_page)
-                                    .expect("Could not refill?");
-                                zone_allocator
-                                    .allocate(layout)
-                                    .expect("Should succeed after refill")
-                                    .as_ptr()
-                            })
+                            for _i in 0..config.refill_large_pages {
+                                match try_alloc_largepage() {
+                                    Some(large_page) => zone_allocator
+                                        .refill_large(layout, large_page)
+                                        .expect("Could not refill?"),
+                                    None => break,
+                                }
+                            }
                         }
+
+                        zone_allocator
+                            .allocate(layout)
+                            .map_or(ptr::null_mut(), |nptr| nptr.as_ptr())
                     }
                     Err(AllocationError::InvalidLayout) => panic!("Can't allocate this size"),
                 }
             }
-            ZoneAllocator::MAX_ALLOC_SIZE..=Pager::LARGE_PAGE_SIZE => {
+            0..=Pager::LARGE_PAGE_SIZE => {
                 // Best to use the underlying backend directly to allocate large
                 // to avoid fragmentation
                 try_alloc_largepage().expect("Can't allocate page?") as *mut _ as *mut u8
@@ -235,7 +351,8 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        if layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE
+        if layout.size() <= config().slab_limit
+            && new_size <= config().slab_limit
             //&& layout.size() != x86::current::paging::BASE_PAGE_SIZE
             && new_size <= ZoneAllocator::get_max_size(layout.size()).unwrap_or(0x0)
         {
@@ -256,7 +373,7 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         match layout.size() {
-            0..=ZoneAllocator::MAX_ALLOC_SIZE => {
+            size if size <= config().slab_limit => {
                 if let Some(nptr) = NonNull::new(ptr) {
                     self.0
                         .lock()
@@ -269,7 +386,7 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
                 // An proper reclamation strategy could be implemented here
                 // to release empty pages back from the ZoneAllocator to the PAGER
             }
-            ZoneAllocator::MAX_ALLOC_SIZE..=Pager::LARGE_PAGE_SIZE => PAGER[Environment::core_id()]
+            0..=Pager::LARGE_PAGE_SIZE => PAGER[Environment::core_id()]
                 .lock()
                 .dealloc_page(ptr, Pager::LARGE_PAGE_SIZE),
             _ => error!("TODO: Currently can't dealloc of {:?}.", layout),
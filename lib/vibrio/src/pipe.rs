@@ -0,0 +1,44 @@
+//! Blocking I/O on pipes.
+//!
+//! The kernel never blocks on a pipe: `Fs::read` and `Fs::write` fail with
+//! `SystemCallError::WouldBlock` if the pipe is empty (or full). The functions
+//! here let the other (lineup) threads of the process run until the pipe has
+//! data (or space), so they have to be called from a lineup thread.
+
+use kpi::syscalls::Fs;
+use kpi::SystemCallError;
+use lineup::tls2::Environment;
+
+/// Creates a pipe, returns the file descriptors of the read and the write
+/// end.
+pub fn pipe() -> Result<(u64, u64), SystemCallError> {
+    Fs::pipe()
+}
+
+/// Reads from the pipe `fd` into `buf`, waits until there is something to
+/// read. Returns 0 once the pipe is empty and the write end is closed.
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+    loop {
+        match Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64) {
+            Ok(len) => return Ok(len as usize),
+            Err(SystemCallError::WouldBlock) => Environment::thread().relinquish(),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes all of `buf` to the pipe `fd`, waits for the reader whenever the
+/// pipe is full.
+pub fn write(fd: u64, buf: &[u8]) -> Result<(), SystemCallError> {
+    let mut written = 0;
+    while written < buf.len() {
+        let rest = &buf[written..];
+        match Fs::write(fd, rest.as_ptr() as u64, rest.len() as u64) {
+            Ok(len) => written += len as usize,
+            Err(SystemCallError::WouldBlock) => Environment::thread().relinquish(),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
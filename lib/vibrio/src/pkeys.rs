@@ -0,0 +1,156 @@
+//! Heap protection domains with memory protection keys.
+//!
+//! A [`ProtectionDomain`] is a heap whose pages are tagged with their own
+//! protection key. Code outside of [`ProtectionDomain::with`] (or after
+//! [`ProtectionDomain::deny`]) can't touch the objects allocated in it, even
+//! though they are in the same address-space.
+//!
+//! # Notes
+//! The access rights live in the PKRU register of the core, so they are per
+//! executor and not per lineup thread: a thread that gets preempted in
+//! `with` leaves the domain accessible to the threads that run next on the
+//! core. Processes created with `fork` don't inherit the keys.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::transmute;
+use core::ptr::{self, NonNull};
+
+use log::error;
+use slabmalloc::{AllocationError, Allocator, ZoneAllocator};
+use spin::Mutex;
+
+use kpi::process::MemoryRights;
+use kpi::x86_64::{pkru_with_rights, read_pkru, write_pkru};
+use kpi::SystemCallError;
+
+use crate::mem::Pager;
+use crate::syscalls::{Process, VSpace};
+
+/// Virtual address where the heaps of the domains start (each key gets
+/// `DOMAIN_SIZE` bytes).
+const DOMAIN_BASE: u64 = 0x600_0000_0000;
+
+/// How much address-space a domain can use.
+const DOMAIN_SIZE: u64 = 0x10_0000_0000;
+
+const BASE_PAGE_SIZE: usize = 4096;
+const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+struct Heap {
+    pager: Pager,
+    zone: ZoneAllocator<'static>,
+}
+
+impl Heap {
+    /// Maps a page of `size` bytes for the domain with `key`.
+    fn page(&mut self, size: usize, key: u16) -> Option<*mut u8> {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        let (vaddr, _paddr) = self.pager.allocate(layout).ok()?;
+        unsafe { VSpace::protect_key(vaddr.as_u64(), size as u64, key).ok()? };
+        Some(vaddr.as_mut_ptr())
+    }
+
+    fn refill(&mut self, layout: Layout, key: u16) -> Result<(), AllocationError> {
+        if layout.size() <= ZoneAllocator::MAX_BASE_ALLOC_SIZE {
+            let page = self
+                .page(BASE_PAGE_SIZE, key)
+                .ok_or(AllocationError::OutOfMemory)?;
+            unsafe { self.zone.refill(layout, transmute(page)) }
+        } else {
+            let page = self
+                .page(LARGE_PAGE_SIZE, key)
+                .ok_or(AllocationError::OutOfMemory)?;
+            unsafe { self.zone.refill_large(layout, transmute(page)) }
+        }
+    }
+}
+
+/// A heap that only code with access to its protection key can use.
+pub struct ProtectionDomain {
+    key: u16,
+    heap: Mutex<Heap>,
+}
+
+impl ProtectionDomain {
+    /// Creates a domain with a new protection key. The executor that creates
+    /// it gets read and write access, all others start without access.
+    ///
+    /// The key (and the memory of the domain) is never given back: its pages
+    /// stay mapped with the key.
+    pub fn new() -> Result<ProtectionDomain, SystemCallError> {
+        let key = Process::allocate_key(MemoryRights::READ | MemoryRights::WRITE)?;
+        let base = DOMAIN_BASE + key as u64 * DOMAIN_SIZE;
+
+        Ok(ProtectionDomain {
+            key,
+            heap: Mutex::new(Heap {
+                pager: Pager::new(base, base + DOMAIN_SIZE),
+                zone: ZoneAllocator::new(),
+            }),
+        })
+    }
+
+    /// The protection key of the domain.
+    pub fn key(&self) -> u16 {
+        self.key
+    }
+
+    /// Sets the rights of the executor to the objects in the domain.
+    pub fn allow(&self, rights: MemoryRights) {
+        unsafe { write_pkru(pkru_with_rights(read_pkru(), self.key, rights)) };
+    }
+
+    /// Takes all rights to the objects in the domain away from the executor.
+    pub fn deny(&self) {
+        self.allow(MemoryRights::NONE);
+    }
+
+    /// Runs `f` with read and write access to the domain, restores the
+    /// previous rights afterwards.
+    pub fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let pkru = unsafe { read_pkru() };
+        self.allow(MemoryRights::READ | MemoryRights::WRITE);
+        let r = f();
+        unsafe { write_pkru(pkru) };
+        r
+    }
+}
+
+/// Objects in a domain can be at most `ZoneAllocator::MAX_ALLOC_SIZE` bytes.
+unsafe impl GlobalAlloc for ProtectionDomain {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > ZoneAllocator::MAX_ALLOC_SIZE {
+            error!("Object of {:?} is too big for a protection domain.", layout);
+            return ptr::null_mut();
+        }
+
+        // The allocator keeps its meta-data in the pages of the domain
+        self.with(|| {
+            let mut heap = self.heap.lock();
+            match heap.zone.allocate(layout) {
+                Ok(nptr) => nptr.as_ptr(),
+                Err(AllocationError::OutOfMemory) => {
+                    if heap.refill(layout, self.key).is_err() {
+                        return ptr::null_mut();
+                    }
+                    heap.zone
+                        .allocate(layout)
+                        .map_or(ptr::null_mut(), |nptr| nptr.as_ptr())
+                }
+                Err(AllocationError::InvalidLayout) => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(nptr) = NonNull::new(ptr) {
+            self.with(|| {
+                self.heap
+                    .lock()
+                    .zone
+                    .deallocate(nptr, layout)
+                    .expect("Couldn't deallocate");
+            });
+        }
+    }
+}
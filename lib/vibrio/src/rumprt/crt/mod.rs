@@ -254,6 +254,7 @@ pub extern "C" fn main() {
     let mut maximum = 1; // We already have core 0
 
     let pinfo = crate::syscalls::Process::process_info().expect("Can't read process info");
+    crate::mem::configure_from(pinfo.malloc_conf);
 
     let ncores: Option<usize> = pinfo.cmdline.parse().ok();
     for hwthread in hwthreads.iter().take(ncores.unwrap_or(hwthreads.len())) {
@@ -1,11 +1,9 @@
 use super::{c_int, c_uint, c_ulong, c_void};
 
-use core::alloc::Layout;
 use core::fmt;
 use core::ptr;
 
 use hashbrown::HashMap;
-use lineup::tls2::Environment;
 use log::{error, info, trace, warn};
 use spin::Mutex;
 use x86::current::paging::{PAddr, VAddr};
@@ -128,7 +126,9 @@ pub unsafe extern "C" fn rumpcomp_pci_irq_map(
         )
         .expect("Can't create IRQ thread?");
 
-    crate::syscalls::Irq::irqalloc(vector as u64, 0).ok();
+    // The (legacy) interrupt line of the device is its GSI
+    let gsi = vector as u64;
+    crate::syscalls::Irq::irqalloc(gsi, 0).ok();
 
     0
 }
@@ -237,19 +237,8 @@ pub unsafe extern "C" fn rumpcomp_pci_dmalloc(
     pptr: *mut c_ulong,
     vptr: *mut c_ulong,
 ) -> c_int {
-    assert!(
-        size <= 2 * 1024 * 1024,
-        "Can't handle anything above 2 MiB (needs to be consecutive physically)"
-    );
-    let size = if size > 4096 { 2 * 1024 * 1024 } else { 4096 };
-    trace!("rumpcomp_pci_dmalloc adjusted size {} to", size);
-
-    let layout = Layout::from_size_align_unchecked(size, size);
-
-    let r = {
-        let mut p = crate::mem::PAGER[Environment::core_id()].lock();
-        (*p).allocate(layout)
-    };
+    // The kernel hands out physically contiguous memory (a whole page)
+    let r = kpi::syscalls::PhysicalMemory::allocate_dma_region(size, alignment);
 
     match r {
         Ok((vaddr, paddr)) => {
@@ -271,7 +260,13 @@ pub unsafe extern "C" fn rumpcomp_pci_dmalloc(
 
             0
         }
-        Err(_e) => 1,
+        Err(e) => {
+            error!(
+                "rumpcomp_pci_dmalloc {:#x} {:#x} failed: {:?}",
+                size, alignment, e
+            );
+            1
+        }
     }
 }
 
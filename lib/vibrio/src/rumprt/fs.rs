@@ -4,7 +4,7 @@ use super::{c_int, c_size_t, c_void, rump_biodone_fn};
 use cstr_core::CStr;
 
 use kpi::io::*;
-use kpi::FileOperation;
+use kpi::{FileOperation, SystemCallError};
 
 use bitflags::*;
 use log::*;
@@ -28,6 +28,33 @@ bitflags! {
     }
 }
 
+/// Translates the error of a file operation to an errno, `default` is used
+/// for errors that don't have a more specific one (e.g., invalid arguments).
+///
+/// The match is exhaustive on purpose: a new `SystemCallError` needs an
+/// errno here too.
+fn to_errno(err: SystemCallError, default: c_int) -> c_int {
+    use super::errno;
+    match err {
+        SystemCallError::Ok => default,
+        SystemCallError::NotLogged => errno::EIO,
+        SystemCallError::NotSupported => default,
+        SystemCallError::VSpaceAlreadyMapped => errno::EEXIST,
+        SystemCallError::OutOfMemory => errno::ENOMEM,
+        SystemCallError::InternalError => errno::EIO,
+        SystemCallError::BadAddress => errno::EFAULT,
+        SystemCallError::BadFileDescriptor => errno::EBADF,
+        SystemCallError::BadFlags => errno::EINVAL,
+        SystemCallError::PermissionError => errno::EACCES,
+        SystemCallError::OffsetError => errno::EINVAL,
+        SystemCallError::WouldBlock => errno::EAGAIN,
+        SystemCallError::BrokenPipe => errno::EPIPE,
+        SystemCallError::QuotaExceeded => errno::EDQUOT,
+        SystemCallError::StillMapped => errno::EBUSY,
+        SystemCallError::Unknown => default,
+    }
+}
+
 /// int rumpuser_open(const char *name, int mode, int *fdp)
 #[no_mangle]
 pub unsafe extern "C" fn rumpuser_open(name: *const i8, mode: c_int, fdp: *mut c_int) -> c_int {
@@ -61,7 +88,7 @@ pub unsafe extern "C" fn rumpuser_open(name: *const i8, mode: c_int, fdp: *mut c
             *fdp = fd as c_int;
             0
         }
-        Err(_) => super::errno::EINVAL as c_int,
+        Err(e) => to_errno(e, super::errno::EINVAL as c_int),
     }
 }
 
@@ -124,7 +151,7 @@ pub unsafe extern "C" fn rumpuser_iovread(
             *retv = len.try_into().unwrap();
             0
         }
-        Err(_) => super::errno::EINVAL as i32,
+        Err(e) => to_errno(e, super::errno::EINVAL as i32),
     }
 }
 
@@ -147,7 +174,7 @@ pub unsafe extern "C" fn rumpuser_iovwrite(
             *retv = len.try_into().unwrap();
             0
         }
-        Err(_) => super::errno::EINVAL as i32,
+        Err(e) => to_errno(e, super::errno::EINVAL as i32),
     }
 }
 
@@ -10,8 +10,16 @@
 //! [2]: www.barrelfish.org/publications/TN-010-Spec.pdf
 //! [3]: http://www.barrelfish.org/publications/ma-fuchs-tm-mp.pdf
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
+use kpi::system::GlobalThreadId;
+use kpi::SystemCallError;
 use lazy_static::lazy_static;
 use log::trace;
+use spin::Mutex;
+
+use crate::mem::MAX_CORES;
 
 lazy_static! {
     pub static ref PROCESS_SCHEDULER: lineup::scheduler::SmpScheduler<'static> = {
@@ -26,6 +34,51 @@ lazy_static! {
             lineup::scheduler::SmpScheduler::default()
         }
     };
+
+    /// Kernel ids of the cores we got with `kpi::upcall::NEW_CORE`, the core
+    /// at position `i` is core `i + 1` for lineup (see `core_index`).
+    static ref CORES: Mutex<ArrayVec<GlobalThreadId, MAX_CORES>> = Mutex::new(ArrayVec::new());
+}
+
+/// Maps the kernel id of a core (`GlobalThreadId`) to the dense core id
+/// lineup uses (and our per-core state is indexed with).
+///
+/// The core the process started on is 0, cores are numbered in the order
+/// we got them after that. `register` adds a new core.
+fn core_index(gtid: GlobalThreadId, register: bool) -> usize {
+    let mut cores = CORES.lock();
+    if let Some(idx) = cores.iter().position(|c| *c == gtid) {
+        return idx + 1;
+    }
+    if !register {
+        // Every other core went through `NEW_CORE` first
+        return 0;
+    }
+    // Core 0 isn't in `CORES`
+    assert!(cores.len() + 1 < MAX_CORES, "More cores than lineup supports");
+    cores.push(gtid);
+    cores.len()
+}
+
+/// Handlers for the events of the machine we subscribed to (see
+/// `subscribe`), indexed by `event - kpi::upcall::CORE_ONLINE`.
+static EVENT_HANDLERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn event_handler(event: u64) -> Option<&'static AtomicUsize> {
+    let idx = event.checked_sub(kpi::upcall::CORE_ONLINE)?;
+    EVENT_HANDLERS.get(idx as usize)
+}
+
+/// Calls `handler` (with the argument of the upcall) whenever the kernel
+/// sends us `event` (`kpi::upcall::CORE_ONLINE` or `kpi::upcall::LOW_MEMORY`).
+///
+/// The handler runs on the upcall stack with upcalls disabled: it must not
+/// block or touch memory that isn't mapped yet.
+pub fn subscribe(event: u64, handler: fn(u64)) -> Result<(), SystemCallError> {
+    if let Some(slot) = event_handler(event) {
+        slot.store(handler as usize, Ordering::Release);
+    }
+    crate::syscalls::Process::subscribe_event(event, true)
 }
 
 /// This is invoked through the kernel whenever we get an
@@ -48,27 +101,87 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
     );
 
     let sched = &PROCESS_SCHEDULER;
+    crate::irq::dispatch_pending(control);
 
     if cmd == kpi::upcall::NEW_CORE {
         use lineup::tls2::SchedulerControlBlock;
-        let core_id = arg;
-        log::info!("Got a new core ({}) assigned to us.", core_id);
+        let core_id = core_index(arg as GlobalThreadId, true);
+        log::info!("Got a new core ({}) assigned to us as {}.", arg, core_id);
 
-        let scb: SchedulerControlBlock = SchedulerControlBlock::new(core_id as usize);
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(core_id);
+        if sched.time_slice() > 0 {
+            if let Err(e) = crate::syscalls::Process::subscribe_timer(sched.time_slice()) {
+                log::error!("Can't preempt threads on core {}: {:?}", core_id, e);
+            }
+        }
         loop {
             sched.run(&scb);
+            sched.idle(&scb);
+        }
+    }
+
+    if cmd == kpi::upcall::TIMER {
+        // A thread that ran for too long continues once it's its turn again
+        let now = arg;
+        let scb = lineup::tls2::Environment::try_scheduler();
+        if scb.map_or(false, |scb| sched.should_preempt(scb, now)) {
+            unsafe { redirect_to_preemption(control) };
+        }
+
+        unsafe { resume(control) }
+    }
+
+    if cmd == kpi::upcall::CORE_PREEMPTED {
+        // Continue the threads of the preempted core on this one
+        let preempted_core = core_index(arg as GlobalThreadId, false);
+        let core_id = lineup::tls2::Environment::core_id();
+        log::debug!("Core {} was preempted, moving threads to {}", arg, core_id);
+        sched.migrate_core(preempted_core, core_id);
+
+        trace!("upcall_while_enabled: renable and resume...");
+        unsafe { resume(control) }
+    }
+
+    if let Some(slot) = event_handler(cmd) {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            // Safe: only `subscribe` stores (valid) handlers here
+            let handler: fn(u64) = unsafe { core::mem::transmute(handler) };
+            handler(arg);
+        }
+
+        unsafe { resume(control) }
+    }
+
+    if cmd == kpi::upcall::PAGE_FAULT {
+        let faulting_address = arg;
+        if sched.grow_stack(faulting_address as usize) {
+            // The thread needed more stack, it can continue
+            unsafe { resume(control) }
         }
+
+        // We can't recover the faulting thread, make sure we find out why
+        let rip = control.enabled_state.rip;
+        match sched.overflowed_stack(faulting_address as usize) {
+            Some(tid) => log::error!(
+                "stack overflow on thread {} (page-fault on {:#x}, rip {:#x})",
+                tid.0,
+                faulting_address,
+                rip
+            ),
+            None => log::error!(
+                "unhandled page-fault on {:#x} (rip {:#x})",
+                faulting_address,
+                rip
+            ),
+        }
+        crate::syscalls::Process::exit(kpi::process::PAGE_FAULT_EXIT_CODE);
     }
 
-    if cmd == 0x2a || cmd == 0x24 {
-        // TODO(correctness): this will use `gs` to access the SchedulerControlBlock
-        // that assumes that we have already called scheduler.run() and we preserve
-        // the SchedulerControlBlock register even if we return from run()
-        let scheduler = lineup::tls2::Environment::scheduler();
-        log::info!("got interrupt cmd={} arg={}", cmd, arg);
-        scheduler.pending_irqs.push(cmd).map_err(|_e| {
-            log::error!("Overflowed pending_irqs, missed cmd={} arg={}", cmd, arg);
-        });
+    if cmd >= kpi::io::IRQ_VECTOR_BASE {
+        // A device interrupt (see `irq`)
+        trace!("got interrupt cmd={:#x} arg={}", cmd, arg);
+        crate::irq::dispatch(cmd);
     } else {
         log::error!("got unknown interrupt... {}", cmd);
     }
@@ -77,6 +190,92 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
     unsafe { resume(control) }
 }
 
+/// Preempts threads of `PROCESS_SCHEDULER` that run for longer than
+/// `time_slice` (rdtsc cycles) on this core and on the cores we get later.
+///
+/// Don't use this with the rump runtime, rump kernels expect that their
+/// threads run until they yield.
+pub fn enable_preemption(time_slice: u64) -> Result<(), SystemCallError> {
+    PROCESS_SCHEDULER.set_time_slice(time_slice);
+    crate::syscalls::Process::subscribe_timer(time_slice)
+}
+
+/// Size of the area below the stack pointer that functions can use without
+/// moving the stack pointer (see the System V ABI).
+const RED_ZONE_SIZE: u64 = 128;
+
+/// Makes the interrupted thread (in `enabled_state`) call
+/// `vibrio_preempt_trampoline` when we resume it, as if it had called it.
+unsafe fn redirect_to_preemption(control: &mut kpi::arch::VirtualCpu) {
+    let state = &mut control.enabled_state;
+    let rsp = state.rsp - RED_ZONE_SIZE - 8;
+
+    // We can't take a page-fault here (upcalls are disabled), try again on
+    // the next tick
+    if !PROCESS_SCHEDULER.stack_mapped(rsp as usize) {
+        return;
+    }
+
+    *(rsp as *mut u64) = state.rip;
+    state.rsp = rsp;
+    state.rip = vibrio_preempt_trampoline as u64;
+}
+
+extern "C" {
+    fn vibrio_preempt_trampoline();
+}
+
+// Saves everything a preempted thread might still need (it can be
+// interrupted anywhere), yields and returns to where it was interrupted
+// (skipping the red-zone again with `ret $128`).
+global_asm!(
+    "
+    .global vibrio_preempt_trampoline
+vibrio_preempt_trampoline:
+    pushfq
+    pushq %rax
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %rbx
+    pushq %rbp
+
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    subq $512, %rsp
+    fxsave (%rsp)
+    callq vibrio_preempt_current
+    fxrstor (%rsp)
+    movq %rbx, %rsp
+
+    popq %rbp
+    popq %rbx
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rax
+    popfq
+    retq $128
+"
+);
+
+/// Gives up the core, called by `vibrio_preempt_trampoline` on the stack of
+/// the preempted thread.
+#[no_mangle]
+extern "C" fn vibrio_preempt_current() {
+    lineup::tls2::Environment::thread().relinquish();
+}
+
 /// A trap (exception or fault) happened while disabled, this is bad and
 /// shouldn't happen (i.e., it means there is a bug) in the user-space
 /// scheduler logic or upcall handling.
@@ -5,7 +5,8 @@
 //use crossbeam_queue::{ArrayQueue, PushError};
 use lazy_static::lazy_static;
 
-static COM1_IRQ: u64 = 4 + 32;
+/// The interrupt line (GSI) of the first serial port.
+static COM1_GSI: u64 = 4;
 
 /*lazy_static! {
     pub static ref VBUFFER: ArrayQueue<char> = ArrayQueue::new(12);
@@ -13,7 +14,7 @@ static COM1_IRQ: u64 = 4 + 32;
 
 pub fn init() {
     //lazy_static::initialize(&VBUFFER);
-    crate::syscalls::Irq::irqalloc(COM1_IRQ, 0).ok();
+    crate::syscalls::Irq::irqalloc(COM1_GSI, 0).ok();
 }
 
 fn getchar() -> Option<char> {
@@ -0,0 +1,233 @@
+//! Allocator micro-benchmarks.
+//!
+//! Modeled after the simpler tests in mimalloc-bench: every core runs the
+//! same allocation pattern for a while and reports how many alloc/free
+//! pairs it managed. Use `malloc=` on the kernel command line to try
+//! different allocator configurations (see `vibrio::mem::AllocatorConfig`).
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{error, info};
+use x86::bits64::paging::VAddr;
+
+use lineup::tls2::{Environment, SchedulerControlBlock};
+
+static BARRIER_ARRIVED: AtomicUsize = AtomicUsize::new(0);
+static BARRIER_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Object sizes for the `fixed` benchmark.
+const FIXED_SIZES: [usize; 6] = [16, 64, 256, 1024, 4096, 16384];
+
+/// How many objects the `random` benchmark keeps alive at a time.
+const RANDOM_WINDOW: usize = 1024;
+
+/// Largest object the `random` benchmark allocates.
+const RANDOM_MAX_SIZE: usize = 8192;
+
+/// A benchmark is a function that does one operation (an alloc/free pair)
+/// per call.
+trait Workload {
+    fn name(&self) -> &'static str;
+    fn size(&self) -> usize;
+    fn step(&mut self);
+}
+
+/// Allocates and immediately frees an object of the same size.
+struct Fixed {
+    layout: Layout,
+}
+
+impl Workload for Fixed {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn step(&mut self) {
+        unsafe {
+            let ptr = alloc(self.layout);
+            assert!(!ptr.is_null());
+            // Make sure the allocation isn't optimized away:
+            ptr.write_volatile(0xa);
+            dealloc(ptr, self.layout);
+        }
+    }
+}
+
+/// Replaces a random object in a window of live objects with a new one of
+/// random size.
+struct Random {
+    state: u64,
+    window: Vec<(*mut u8, Layout)>,
+}
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random {
+            state: seed | 1,
+            window: Vec::with_capacity(RANDOM_WINDOW),
+        }
+    }
+
+    /// xorshift64
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl Workload for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn size(&self) -> usize {
+        RANDOM_MAX_SIZE
+    }
+
+    fn step(&mut self) {
+        let size = 8 + (self.next() as usize % (RANDOM_MAX_SIZE - 8));
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr.write_volatile(0xb) };
+
+        if self.window.len() < RANDOM_WINDOW {
+            self.window.push((ptr, layout));
+        } else {
+            let idx = self.next() as usize % RANDOM_WINDOW;
+            let (old_ptr, old_layout) = core::mem::replace(&mut self.window[idx], (ptr, layout));
+            unsafe { dealloc(old_ptr, old_layout) };
+        }
+    }
+}
+
+impl Drop for Random {
+    fn drop(&mut self) {
+        for (ptr, layout) in self.window.drain(..) {
+            unsafe { dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Waits until all `cores` arrived (can be used repeatedly).
+fn barrier(cores: usize) {
+    let generation = BARRIER_GENERATION.load(Ordering::SeqCst);
+    if BARRIER_ARRIVED.fetch_add(1, Ordering::SeqCst) + 1 == cores {
+        BARRIER_ARRIVED.store(0, Ordering::SeqCst);
+        BARRIER_GENERATION.fetch_add(1, Ordering::SeqCst);
+    } else {
+        while BARRIER_GENERATION.load(Ordering::SeqCst) == generation {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+fn run<W: Workload>(workload: &mut W, cores: usize) {
+    barrier(cores);
+
+    let bench_duration_secs = if cfg!(feature = "smoke") { 1 } else { 10 };
+    let mut iteration = 0;
+    while iteration < bench_duration_secs {
+        let mut ops = 0;
+        let start = rawtime::Instant::now();
+        while start.elapsed().as_secs() < 1 {
+            for _i in 0..64 {
+                workload.step();
+            }
+            ops += 64;
+        }
+
+        info!(
+            "{},{},{},{},{},{},{}",
+            Environment::scheduler().core_id,
+            workload.name(),
+            cores,
+            workload.size(),
+            bench_duration_secs * 1000,
+            iteration * 1000,
+            ops
+        );
+        iteration += 1;
+    }
+}
+
+unsafe extern "C" fn bencher_trampoline(arg1: *mut u8) -> *mut u8 {
+    let cores = arg1 as usize;
+
+    for size in FIXED_SIZES.iter() {
+        let mut fixed = Fixed {
+            layout: Layout::from_size_align(*size, 8).unwrap(),
+        };
+        run(&mut fixed, cores);
+    }
+
+    let mut random = Random::new(Environment::scheduler().core_id as u64 + 1);
+    run(&mut random, cores);
+
+    ptr::null_mut()
+}
+
+pub fn bench(ncores: Option<usize>) {
+    info!("thread_id,benchmark,ncores,objsize,duration_total,duration,operations");
+
+    let hwthreads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    let cores = ncores.unwrap_or(hwthreads.len());
+
+    let mut maximum = 1; // We already have core 0
+    for hwthread in hwthreads.iter().take(cores) {
+        if hwthread.id != 0 {
+            match vibrio::syscalls::Process::request_core(
+                hwthread.id,
+                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+            ) {
+                Ok(_) => {
+                    maximum += 1;
+                    continue;
+                }
+                Err(e) => {
+                    error!("Can't spawn on {:?}: {:?}", hwthread.id, e);
+                    break;
+                }
+            }
+        }
+    }
+    info!("Spawned {} cores", maximum);
+
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let mut thandles = Vec::with_capacity(maximum);
+
+            for core_id in 0..maximum {
+                thandles.push(
+                    Environment::thread()
+                        .spawn_on_core(Some(bencher_trampoline), maximum as *mut u8, core_id)
+                        .expect("Can't spawn bench thread?"),
+                );
+            }
+
+            for thandle in thandles {
+                Environment::thread().join(thandle);
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    info!("allocbench OK");
+}
@@ -20,7 +20,7 @@ extern crate lineup;
 use core::alloc::{GlobalAlloc, Layout};
 use core::panic::PanicInfo;
 use core::ptr;
-use core::slice::from_raw_parts_mut;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
 use core::str::FromStr;
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -37,10 +37,15 @@ use log::{Level, Metadata, Record, SetLoggerError};
 #[cfg(any(feature = "bench-vmops", feature = "bench-vmops-unmaplat"))]
 mod vmops;
 
+#[cfg(feature = "bench-alloc")]
+mod allocbench;
+
 mod f64;
 #[cfg(feature = "fxmark")]
 mod fxmark;
 mod histogram;
+#[cfg(feature = "shell")]
+mod shell;
 
 #[thread_local]
 pub static mut TLS_TEST: [&str; 2] = ["abcd", "efgh"];
@@ -66,6 +71,417 @@ fn map_test() {
     info!("map_test OK");
 }
 
+fn long_syscall_test() {
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, VSpace};
+
+    // The kernel maps this in several steps (and might give the core back
+    // in between), it still ends up mapped completely
+    let base: u64 = 0x40_0020_0000;
+    let size: usize = 256 * 1024 * 1024 + 0x1000;
+    unsafe {
+        VSpace::map(base, size as u64).expect("Map syscall failed");
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, size);
+        for offset in (0..size).step_by(0x1000) {
+            assert_eq!(slice[offset], 0);
+            slice[offset] = 0xc;
+        }
+
+        // Same for large writes
+        let len = 8 * 1024 * 1024;
+        slice[0] = 0xd;
+        let fd = Fs::open(
+            "long.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        assert_eq!(Fs::write(fd, base, len), Ok(len));
+        assert_eq!(Fs::write_at(fd, base, len, len as i64), Ok(len));
+
+        // The second copy ends up after the first one
+        assert_eq!(Fs::read_at(fd, base + len, len, len as i64), Ok(len));
+        assert_eq!(slice[len as usize], 0xd);
+        assert_eq!(slice[len as usize + 0x1000], 0xc);
+        Fs::close(fd).expect("FileClose syscall failed");
+        Fs::delete("long.txt\0".as_ptr() as u64).expect("FileDelete syscall failed");
+    }
+
+    info!("long_syscall_test OK");
+}
+
+fn protect_test() {
+    use vibrio::io::*;
+    use vibrio::process::MemoryRights;
+    use vibrio::syscalls::{Fs, VSpace};
+    use vibrio::SystemCallError;
+
+    let base: u64 = 0x50_0000_0000;
+    let size: u64 = 2 * 0x1000;
+    let guard = base + size;
+    unsafe {
+        VSpace::map(base, size).expect("Map syscall failed");
+        VSpace::map(guard, 0x1000).expect("Map syscall failed");
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, size as usize);
+        slice[0] = 0xa;
+
+        // Flip the region to read-only (or executable) and back, the content
+        // stays the same
+        VSpace::protect(base, size, MemoryRights::READ).expect("Protect syscall failed");
+        assert_eq!(slice[0], 0xa);
+        VSpace::protect(base, size, MemoryRights::READ | MemoryRights::EXECUTE)
+            .expect("Protect syscall failed");
+        assert_eq!(slice[0], 0xa);
+        VSpace::protect(base, size, MemoryRights::READ | MemoryRights::WRITE)
+            .expect("Protect syscall failed");
+        slice[size as usize - 1] = 0xb;
+
+        // The kernel doesn't touch a guard page for us either
+        VSpace::protect(guard, 0x1000, MemoryRights::NONE).expect("Protect syscall failed");
+        let fd = Fs::open(
+            "protect.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        assert_eq!(Fs::write(fd, base, size), Ok(size));
+        assert_eq!(
+            Fs::read_at(fd, guard, 0x1000, 0),
+            Err(SystemCallError::BadAddress)
+        );
+        assert_eq!(
+            Fs::write_at(fd, base, size + 1, 0),
+            Err(SystemCallError::BadAddress)
+        );
+        Fs::close(fd).expect("FileClose syscall failed");
+        Fs::delete("protect.txt\0".as_ptr() as u64).expect("FileDelete syscall failed");
+
+        // Regions have to cover whole mappings
+        assert_eq!(
+            VSpace::protect(base + 1, size, MemoryRights::READ),
+            Err(SystemCallError::BadAddress)
+        );
+        assert_eq!(
+            VSpace::protect(guard + 0x1000, 0x1000, MemoryRights::READ),
+            Err(SystemCallError::BadAddress)
+        );
+        assert_eq!(
+            VSpace::protect(base, size, MemoryRights::from_bits_unchecked(0x8)),
+            Err(SystemCallError::BadFlags)
+        );
+        assert_eq!(
+            VSpace::protect(base, size + 0x2000, MemoryRights::READ),
+            Err(SystemCallError::BadAddress)
+        );
+
+        // Make the guard page accessible again
+        VSpace::protect(guard, 0x1000, MemoryRights::READ | MemoryRights::WRITE)
+            .expect("Protect syscall failed");
+        let guard_slice: &mut [u8] = from_raw_parts_mut(guard as *mut u8, 0x1000);
+        guard_slice[0] = 0xc;
+        assert_eq!(guard_slice[0], 0xc);
+    }
+
+    info!("protect_test OK");
+}
+
+/// Uses (at least) `depth` KiB of stack.
+#[inline(never)]
+fn use_stack(depth: usize) -> usize {
+    let buf = [depth; 128];
+    if depth == 0 {
+        return 0;
+    }
+    unsafe { core::ptr::read_volatile(&buf[depth % 128]) + use_stack(depth - 1) }
+}
+
+fn stack_guard_test() {
+    use lineup::stack::LineupStack;
+    use lineup::tls2::ThreadControlBlock;
+    use vibrio::process::PAGE_FAULT_EXIT_CODE;
+    use vibrio::syscalls::Process;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    let stack_size = 8 * 4096;
+
+    // Guarded stacks work like any other stack
+    let stack = LineupStack::with_guard(stack_size);
+    assert!(stack.guard().is_some());
+    unsafe {
+        s.spawn_with_args(
+            stack,
+            |_| assert!(use_stack(16) > 0),
+            ptr::null_mut(),
+            0,
+            None,
+            ThreadControlBlock::new_tls_area(),
+        );
+    }
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    // Until a thread runs out of stack
+    match Process::fork().expect("Fork syscall failed") {
+        0 => {
+            install_vcpu_area();
+            unsafe {
+                s.spawn_with_args(
+                    LineupStack::with_guard(stack_size),
+                    |_| {
+                        use_stack(64);
+                    },
+                    ptr::null_mut(),
+                    0,
+                    None,
+                    ThreadControlBlock::new_tls_area(),
+                );
+            }
+            loop {
+                s.run(&scb);
+            }
+        }
+        child => assert_eq!(Process::wait_pid(child), Ok(PAGE_FAULT_EXIT_CODE)),
+    }
+
+    info!("stack_guard_test OK");
+}
+
+fn stack_growth_test() {
+    use lineup::stack::LineupStack;
+    use lineup::tls2::ThreadControlBlock;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    const THREADS: usize = 256;
+
+    // Stacks start with a page, only the threads that need more get it
+    for i in 0..THREADS {
+        let depth = if i % 64 == 0 { 96 } else { 1 };
+        unsafe {
+            s.spawn_with_args(
+                LineupStack::growable(4096, 256 * 1024),
+                move |_| assert!(use_stack(depth) > 0),
+                ptr::null_mut(),
+                0,
+                None,
+                ThreadControlBlock::new_tls_area(),
+            );
+        }
+    }
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    info!("stack_growth_test OK");
+}
+
+fn futex_test() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU64;
+    use vibrio::syscalls::Process;
+
+    let word = AtomicU64::new(1);
+    // Doesn't wait if the word changed already
+    assert_eq!(Process::futex_wait(&word, 0), Ok(()));
+    assert_eq!(Process::futex_wake(&word, 1), Ok(0));
+
+    // Core 1 goes idle (gives up its hardware thread) when it has nothing to
+    // do, so every thread we give it has to wake it up again
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    vibrio::syscalls::Process::request_core(
+        1,
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+    )
+    .expect("Can't get core 1");
+
+    const ROUNDS: usize = 16;
+    let done = Arc::new(AtomicU64::new(0));
+    for round in 0..ROUNDS {
+        let done1 = done.clone();
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                assert_eq!(lineup::tls2::Environment::core_id(), 1);
+                done1.fetch_add(1, Ordering::SeqCst);
+            },
+            ptr::null_mut(),
+            1,
+            None,
+        );
+        while done.load(Ordering::SeqCst) as usize != round + 1 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        // Give core 1 time to go idle
+        let start = rawtime::Instant::now();
+        while start.elapsed() < core::time::Duration::from_millis(5) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    info!("futex_test OK");
+}
+
+fn preemption_test() {
+    use alloc::sync::Arc;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    // ~10ms on a 2 GHz machine
+    vibrio::upcalls::enable_preemption(20_000_000).expect("Can't enable preemption");
+
+    // The spinning thread never yields, the other thread only gets to set
+    // the flag if the spinning thread gets preempted
+    let done = Arc::new(AtomicBool::new(false));
+    let done1 = done.clone();
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            while !done1.load(Ordering::Relaxed) {
+                core::sync::atomic::spin_loop_hint();
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+    s.spawn(
+        32 * 4096,
+        move |_| done.store(true, Ordering::Relaxed),
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+
+    info!("preemption_test OK");
+}
+
+/// Runs with 1 GiB of memory (so we can't get a 1 GiB page).
+fn event_test() {
+    use core::sync::atomic::AtomicU64;
+    use vibrio::syscalls::{PhysicalMemory, Process};
+    use vibrio::SystemCallError;
+
+    static LOW_MEMORY: AtomicU64 = AtomicU64::new(0);
+    static LOW_MEMORY_PID: AtomicU64 = AtomicU64::new(0);
+    fn low_memory(pid: u64) {
+        LOW_MEMORY_PID.store(pid, Ordering::SeqCst);
+        LOW_MEMORY.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // There is no such event
+    assert!(Process::subscribe_event(0x42, true).is_err());
+
+    vibrio::upcalls::subscribe(vibrio::upcall::LOW_MEMORY, low_memory)
+        .expect("Can't subscribe to low memory events");
+    assert_eq!(
+        PhysicalMemory::allocate_huge_page().map(|_| ()),
+        Err(SystemCallError::OutOfMemory)
+    );
+
+    // The upcall comes with an IPI to our core
+    let start = rawtime::Instant::now();
+    while LOW_MEMORY.load(Ordering::SeqCst) == 0 {
+        assert!(start.elapsed() < core::time::Duration::from_secs(1));
+        core::sync::atomic::spin_loop_hint();
+    }
+    // We're the first process
+    assert_eq!(LOW_MEMORY_PID.load(Ordering::SeqCst), 1);
+
+    // No more upcalls once we unsubscribed
+    Process::subscribe_event(vibrio::upcall::LOW_MEMORY, false).expect("Can't unsubscribe");
+    let seen = LOW_MEMORY.load(Ordering::SeqCst);
+    assert!(PhysicalMemory::allocate_huge_page().is_err());
+    let start = rawtime::Instant::now();
+    while start.elapsed() < core::time::Duration::from_millis(10) {
+        core::sync::atomic::spin_loop_hint();
+    }
+    assert_eq!(LOW_MEMORY.load(Ordering::SeqCst), seen);
+
+    info!("event_test OK");
+}
+
+fn huge_page_test() {
+    use vibrio::syscalls::{PhysicalMemory, VSpace};
+    use x86::bits64::paging::HUGE_PAGE_SIZE;
+
+    // Map a 1 GiB frame we allocated explicitly
+    let base: u64 = 0x80_0000_0000;
+    let (frame_id, paddr) = PhysicalMemory::allocate_huge_page().expect("Can't allocate 1 GiB");
+    assert_eq!(paddr % HUGE_PAGE_SIZE, 0);
+    unsafe {
+        let (_, mapped_paddr) = VSpace::map_frame(frame_id, base).expect("Can't map frame");
+        assert_eq!(mapped_paddr, paddr);
+
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, HUGE_PAGE_SIZE);
+        slice[0] = 0xa;
+        slice[HUGE_PAGE_SIZE - 1] = 0xb;
+        assert_eq!(slice[0], 0xa);
+        assert_eq!(slice[HUGE_PAGE_SIZE - 1], 0xb);
+
+        // A huge-page is physically contiguous
+        let last = base + HUGE_PAGE_SIZE as u64 - 1;
+        let (_, last_paddr) = VSpace::identify(last).expect("Can't identify");
+        assert_eq!(last_paddr, paddr + (HUGE_PAGE_SIZE - 1));
+    }
+
+    // Anonymous memory at a 1 GiB aligned address
+    let base: u64 = 0xc0_0000_0000;
+    unsafe {
+        VSpace::map(base, HUGE_PAGE_SIZE as u64).expect("Map syscall failed");
+
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, HUGE_PAGE_SIZE);
+        assert_eq!(slice[HUGE_PAGE_SIZE - 1], 0x0);
+        slice[HUGE_PAGE_SIZE - 1] = 0xc;
+        assert_eq!(slice[HUGE_PAGE_SIZE - 1], 0xc);
+    }
+
+    info!("huge_page_test OK");
+}
+
+fn release_physical_test() {
+    use vibrio::syscalls::{PhysicalMemory, VSpace};
+    use x86::bits64::paging::BASE_PAGE_SIZE;
+
+    // A mapped frame can't be released
+    let base: u64 = 0x80_0000_0000;
+    let (frame_id, _paddr) = PhysicalMemory::allocate_base_page().expect("Can't allocate");
+    unsafe {
+        VSpace::map_frame(frame_id, base).expect("Can't map frame");
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, BASE_PAGE_SIZE);
+        slice[0] = 0xa;
+        assert!(PhysicalMemory::release_base_page(frame_id).is_err());
+
+        VSpace::unmap(base, BASE_PAGE_SIZE as u64).expect("Can't unmap frame");
+    }
+    PhysicalMemory::release_base_page(frame_id).expect("Can't release unmapped frame");
+
+    // The FrameId is gone now
+    assert!(PhysicalMemory::release_base_page(frame_id).is_err());
+    unsafe {
+        assert!(VSpace::map_frame(frame_id, base).is_err());
+    }
+
+    // The size has to match
+    let (frame_id, _paddr) = PhysicalMemory::allocate_large_page().expect("Can't allocate");
+    assert!(PhysicalMemory::release_base_page(frame_id).is_err());
+    PhysicalMemory::release_large_page(frame_id).expect("Can't release frame");
+
+    // Released memory can be allocated again (2 GiB in total, more than
+    // the machine has)
+    for _i in 0..1024 {
+        let (frame_id, _paddr) = PhysicalMemory::allocate_large_page().expect("Can't allocate");
+        PhysicalMemory::release_large_page(frame_id).expect("Can't release frame");
+    }
+
+    info!("release_physical_test OK");
+}
+
 fn alloc_test() {
     use alloc::vec::Vec;
     let mut v: Vec<u16> = Vec::with_capacity(256);
@@ -79,6 +495,316 @@ fn alloc_test() {
     info!("alloc_test OK");
 }
 
+fn fork_test() {
+    use alloc::boxed::Box;
+
+    let mut value = Box::new(1u64);
+    match vibrio::syscalls::Process::fork().expect("Fork syscall failed") {
+        0 => {
+            // The child writes to its own copy of the heap
+            *value = 2;
+            vibrio::syscalls::Process::exit(*value);
+        }
+        child => loop {
+            match vibrio::syscalls::Process::wait(child).expect("Wait syscall failed") {
+                Some(code) => {
+                    assert_eq!(code, 2);
+                    break;
+                }
+                None => core::hint::spin_loop(),
+            }
+        },
+    }
+    assert_eq!(*value, 1);
+
+    info!("fork_test OK");
+}
+
+fn waitpid_test() {
+    use vibrio::syscalls::Process;
+
+    // We can only wait for our own children
+    assert!(Process::wait_pid(u64::max_value()).is_err());
+
+    match Process::fork().expect("Fork syscall failed") {
+        0 => Process::exit(3),
+        child => {
+            // Gives our core to the child until it exited
+            assert_eq!(Process::wait_pid(child), Ok(3));
+            // The exit code can only be collected once
+            assert!(Process::wait_pid(child).is_err());
+        }
+    }
+
+    info!("waitpid_test OK");
+}
+
+fn process_group_test() {
+    use vibrio::process::TERMINATED_EXIT_CODE;
+    use vibrio::syscalls::Process;
+
+    let me = Process::list()
+        .expect("Can't list processes")
+        .into_iter()
+        .find(|p| p.binary == "init")
+        .expect("Can't find ourselves");
+
+    // Groups have to exist and we can only move our children
+    assert!(Process::set_group(0, u64::max_value()).is_err());
+    assert!(Process::set_group(u64::max_value(), 0).is_err());
+    // The init process can't be terminated
+    assert!(Process::terminate_group(me.group).is_err());
+
+    // A child in a new group that starts another child (and both never exit)
+    let child = match Process::fork().expect("Fork syscall failed") {
+        0 => {
+            let group = Process::set_group(0, 0).expect("Can't create group");
+            if Process::fork().expect("Fork syscall failed") == 0 {
+                assert_eq!(
+                    Process::set_group(0, group).expect("Can't join group"),
+                    group
+                );
+            }
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+        child => child,
+    };
+
+    // Wait until both are in the group of the child
+    loop {
+        let members = Process::list()
+            .expect("Can't list processes")
+            .into_iter()
+            .filter(|p| p.group == child)
+            .count();
+        if members == 2 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+
+    assert_eq!(Process::terminate_group(child), Ok(2));
+    assert_eq!(Process::wait_pid(child), Ok(TERMINATED_EXIT_CODE));
+    assert!(Process::list()
+        .expect("Can't list processes")
+        .iter()
+        .all(|p| p.group != child));
+    assert!(Process::terminate_group(child).is_err());
+
+    info!("process_group_test OK");
+}
+
+/// Asks the RPC server of `supervisor_test` to exit with `SUPERVISOR_CRASH`
+/// plus its request number.
+const SUPERVISOR_CRASH: u64 = 0x1000;
+
+/// Reads an u64 from the pipe `fd`, spins until it arrives.
+fn pipe_recv(fd: u64) -> u64 {
+    use vibrio::syscalls::Fs;
+    use vibrio::SystemCallError;
+
+    let mut buf = [0u8; 8];
+    let mut received = 0;
+    while received < buf.len() {
+        let remaining = &mut buf[received..];
+        match Fs::read(fd, remaining.as_mut_ptr() as u64, remaining.len() as u64) {
+            Ok(0) => panic!("Pipe {} closed", fd),
+            Ok(len) => received += len as usize,
+            Err(SystemCallError::WouldBlock) => core::hint::spin_loop(),
+            Err(e) => panic!("Pipe read failed: {:?}", e),
+        }
+    }
+    u64::from_le_bytes(buf)
+}
+
+/// Writes `value` to the pipe `fd`.
+fn pipe_send(fd: u64, value: u64) {
+    let buf = value.to_le_bytes();
+    let len = vibrio::syscalls::Fs::write(fd, buf.as_ptr() as u64, buf.len() as u64)
+        .expect("Pipe write failed");
+    assert_eq!(len, buf.len() as u64);
+}
+
+/// The RPC server of `supervisor_test`: answers a request n with n + 1 on
+/// the pipes `args` names (the read end for requests, the write end for
+/// replies).
+fn rpc_server(args: &str) -> ! {
+    let mut fds = args.split_whitespace().skip(1).map(|fd| fd.parse::<u64>());
+    let (requests, replies) = match (fds.next(), fds.next()) {
+        (Some(Ok(requests)), Some(Ok(replies))) => (requests, replies),
+        _ => panic!("Bad rpc-server arguments {}", args),
+    };
+
+    loop {
+        let request = pipe_recv(requests);
+        if request >= SUPERVISOR_CRASH {
+            vibrio::syscalls::Process::exit(request);
+        }
+        pipe_send(replies, request + 1);
+    }
+}
+
+fn supervisor_test() {
+    use vibrio::process::TERMINATED_EXIT_CODE;
+    use vibrio::syscalls::{Fs, Process};
+
+    const ROUNDS: u64 = 10;
+
+    let (requests, request_fd) = Fs::pipe().expect("Pipe syscall failed");
+    let (reply_fd, replies) = Fs::pipe().expect("Pipe syscall failed");
+    let args = alloc::format!("rpc-server {} {}", requests, replies);
+
+    // We can only supervise our children
+    assert!(Process::supervise(u64::max_value(), "init").is_err());
+    assert!(Process::restart(u64::max_value()).is_err());
+
+    let mut server = match Process::fork().expect("Fork syscall failed") {
+        0 => {
+            Process::set_group(0, 0).expect("Can't create group");
+            rpc_server(&args)
+        }
+        child => child,
+    };
+    // Restarted servers stay in its group
+    let group = server;
+    // Restarted servers find their pipes in the arguments
+    let cmdline = alloc::format!("init {}", args);
+    assert!(Process::supervise(server, "no-such-module").is_err());
+    Process::supervise(server, &cmdline).expect("Can't supervise server");
+
+    for round in 0..ROUNDS {
+        pipe_send(request_fd, round);
+        assert_eq!(pipe_recv(reply_fd), round + 1);
+
+        // Kill it or let it crash, it comes back with the same pipes
+        let code = if round % 2 == 0 {
+            assert_eq!(Process::terminate_group(group), Ok(1));
+            TERMINATED_EXIT_CODE
+        } else {
+            pipe_send(request_fd, SUPERVISOR_CRASH + round);
+            SUPERVISOR_CRASH + round
+        };
+        let (restarted, exit_code) = Process::restart(server).expect("Can't restart server");
+        assert_eq!(exit_code, code, "Round {}", round);
+        assert_ne!(restarted, server);
+        // It's gone for good
+        assert!(Process::restart(server).is_err());
+        server = restarted;
+    }
+
+    pipe_send(request_fd, ROUNDS);
+    assert_eq!(pipe_recv(reply_fd), ROUNDS + 1);
+
+    info!("supervisor_test OK");
+}
+
+fn exit_reclaim_test() {
+    use vibrio::syscalls::{Process, VSpace};
+
+    // Together, the children use a lot more memory than the machine has, so
+    // this only works if it's given back when they exit
+    const CHILDREN: usize = 32;
+    const CHILD_MEMORY: usize = 64 * 1024 * 1024;
+
+    for i in 0..CHILDREN {
+        match Process::fork().expect("Fork syscall failed") {
+            0 => unsafe {
+                let base: u64 = 0x80_0000_0000;
+                VSpace::map(base, CHILD_MEMORY as u64).expect("Map syscall failed");
+
+                let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, CHILD_MEMORY);
+                slice[0] = 0xa;
+                slice[CHILD_MEMORY - 1] = 0xb;
+                Process::exit((slice[0] + slice[CHILD_MEMORY - 1]) as u64)
+            },
+            child => assert_eq!(Process::wait_pid(child), Ok(0xa + 0xb), "Child {}", i),
+        }
+    }
+
+    info!("exit_reclaim_test OK");
+}
+
+fn exec_test() {
+    use vibrio::syscalls::Process;
+
+    // Only boot modules can be executed, we stay the same process otherwise
+    assert!(Process::exec("no-such-module").is_err());
+    assert!(Process::exec("").is_err());
+
+    info!("exec_test OK");
+}
+
+fn eventring_test() {
+    use vibrio::eventring::{oldest, read_event, EventRingHeader, EventTopics};
+
+    let base: u64 = 0x6000_0000;
+    unsafe {
+        vibrio::syscalls::System::open_event_ring(base, EventTopics::LOG | EventTopics::SCHEDULER)
+            .expect("Can't open event ring");
+        // A process only gets one ring
+        assert!(vibrio::syscalls::System::open_event_ring(base, EventTopics::LOG).is_err());
+
+        let header = &*(base as *const EventRingHeader);
+        let tail = vibrio::syscalls::System::arm_event_ring(0).expect("Can't arm doorbell");
+        assert!(header.tail.load(Ordering::Acquire) >= tail);
+        for idx in oldest(tail)..tail {
+            if let Some(event) = read_event(base as *const u8, idx) {
+                assert_eq!(event.seq, idx);
+                assert!(!event.topic().is_empty());
+            }
+        }
+
+        vibrio::syscalls::System::arm_event_ring(u64::max_value()).expect("Can't disarm doorbell");
+        vibrio::syscalls::System::open_event_ring(0, EventTopics::empty())
+            .expect("Can't remove event ring");
+    }
+
+    info!("eventring_test OK");
+}
+
+fn syscall_cost_test() {
+    use vibrio::syscalls::System;
+
+    const BATCHES: usize = 100;
+    const BATCH_SIZE: u64 = 1000;
+
+    // Average cycles per system call of the fastest batch (the others might
+    // have been interrupted):
+    let measure = |syscall: &dyn Fn()| -> u64 {
+        (0..BATCHES)
+            .map(|_b| {
+                let start = unsafe { x86::time::rdtsc() };
+                for _i in 0..BATCH_SIZE {
+                    syscall();
+                }
+                (unsafe { x86::time::rdtsc() } - start) / BATCH_SIZE
+            })
+            .min()
+            .unwrap_or(0)
+    };
+
+    let eid = System::thread_id().expect("GetThreadId syscall failed");
+    let null = measure(&|| System::null().expect("Null syscall failed"));
+    let gettid = measure(&|| assert_eq!(System::thread_id(), Ok(eid)));
+
+    // The vector registers have to survive a system call too
+    let mut with_syscalls = eid as f64 + 1.5;
+    let mut without_syscalls = with_syscalls;
+    for i in 0..100 {
+        with_syscalls = with_syscalls * 1.5 + i as f64;
+        System::null().expect("Null syscall failed");
+    }
+    for i in 0..100 {
+        without_syscalls = without_syscalls * 1.5 + i as f64;
+    }
+    assert_eq!(with_syscalls, without_syscalls);
+
+    info!("syscall_cost_test null={} gettid={} cycles", null, gettid);
+    info!("syscall_cost_test OK");
+}
+
 fn scheduler_smp_test() {
     use lineup::threads::ThreadId;
     use lineup::tls2::Environment;
@@ -520,6 +1246,8 @@ fn fs_test() {
             .expect("FileOpen syscall failed");
         assert_eq!(fileinfo.fsize, 256);
         assert_eq!(fileinfo.ftype, rumprt::Rump_FileType::File as u64);
+        assert_eq!(fileinfo.base_pages, 1);
+        assert_eq!(fileinfo.large_pages, 0);
 
         // Reset the slice content. And read the file content from the file and
         // check if it's same as the date which was written to the file.
@@ -536,6 +1264,44 @@ fn fs_test() {
         let ret = vibrio::syscalls::Fs::write_at(fd, slice.as_ptr() as u64, 256, 4096 * 255)
             .expect("FileWriteAt syscall failed");
 
+        // Map the start of the file, change the file and check that the
+        // mapping sees the change.
+        let mmap_base: u64 = 0x5000_0000;
+        let ret = vibrio::syscalls::Fs::mmap(fd, mmap_base, 256, 0).expect("Mmap syscall failed");
+        assert_eq!(ret, 256);
+        let mapping: &[u8] = from_raw_parts(mmap_base as *const u8, 256);
+        assert_eq!(mapping[255], 0xb);
+        slice[0] = 0xc;
+        let ret = vibrio::syscalls::Fs::write_at(fd, slice.as_ptr() as u64, 1, 0)
+            .expect("FileWriteAt syscall failed");
+        assert_eq!(ret, 1);
+        assert_eq!(core::ptr::read_volatile(&mapping[0]), 0xc);
+        assert!(vibrio::syscalls::Fs::delete("file.txt\0".as_ptr() as u64).is_err());
+        vibrio::syscalls::Fs::munmap(mmap_base, 256).expect("Munmap syscall failed");
+
+        // Move the offset around and read the last byte of the file.
+        let ret = vibrio::syscalls::Fs::lseek(fd, 0, SeekWhence::Set).expect("Seek syscall failed");
+        assert_eq!(ret, 0);
+        let ret = vibrio::syscalls::Fs::lseek(fd, 0, SeekWhence::End).expect("Seek syscall failed");
+        assert_eq!(ret, 4096 * 255 + 256);
+        let ret =
+            vibrio::syscalls::Fs::lseek(fd, -1, SeekWhence::Cur).expect("Seek syscall failed");
+        assert_eq!(ret, 4096 * 255 + 255);
+        let ret = vibrio::syscalls::Fs::read(fd, slice.as_ptr() as u64, 256)
+            .expect("FileRead syscall failed");
+        assert_eq!(ret, 1);
+        assert_eq!(slice[0], 0xb);
+        assert!(vibrio::syscalls::Fs::lseek(fd, -1, SeekWhence::Set).is_err());
+
+        // Reserve memory past the end, the file grows with zeros.
+        vibrio::syscalls::Fs::allocate(fd, 4096 * 256, 4096).expect("Allocate syscall failed");
+        let ret = vibrio::syscalls::Fs::lseek(fd, 0, SeekWhence::End).expect("Seek syscall failed");
+        assert_eq!(ret, 4096 * 257);
+        let ret = vibrio::syscalls::Fs::read_at(fd, slice.as_ptr() as u64, 1, 4096 * 256)
+            .expect("FileReadAt syscall failed");
+        assert_eq!(ret, 1);
+        assert_eq!(slice[0], 0);
+
         // Close the file.
         let ret = vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
         assert_eq!(ret, 0);
@@ -548,18 +1314,193 @@ fn fs_test() {
         .expect("FileRename syscall failed");
         assert_eq!(ret, 0);
 
+        // List the root directory.
+        let ret = vibrio::syscalls::Fs::mkdir_simple(
+            "dir\0".as_ptr() as u64,
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("MkDir syscall failed");
+        assert_eq!(ret, 0);
+        let entries =
+            vibrio::syscalls::Fs::readdir("/\0".as_ptr() as u64).expect("ReadDir syscall failed");
+        assert!(entries
+            .iter()
+            .any(|e| e.name == "filenew.txt" && e.ftype == rumprt::Rump_FileType::File as u64));
+        assert!(entries
+            .iter()
+            .any(|e| e.name == "dir" && e.ftype == rumprt::Rump_FileType::Directory as u64));
+        let ret = vibrio::syscalls::Fs::delete("dir\0".as_ptr() as u64)
+            .expect("FileDelete syscall failed");
+        assert_eq!(ret, true);
+
         // Delete the file.
         let ret = vibrio::syscalls::Fs::delete("filenew.txt\0".as_ptr() as u64)
             .expect("FileDelete syscall failed");
         assert_eq!(ret, true);
 
+        // Send some bytes through a pipe.
+        let (read_fd, write_fd) = vibrio::syscalls::Fs::pipe().expect("Pipe syscall failed");
+        let mut buf = [0u8; 8];
+        assert!(vibrio::syscalls::Fs::read(read_fd, buf.as_mut_ptr() as u64, 8).is_err());
+        let ret = vibrio::syscalls::Fs::write(write_fd, "hello".as_ptr() as u64, 5)
+            .expect("Pipe write failed");
+        assert_eq!(ret, 5);
+        assert!(vibrio::syscalls::Fs::read(write_fd, buf.as_mut_ptr() as u64, 8).is_err());
+        assert!(vibrio::syscalls::Fs::lseek(read_fd, 0, SeekWhence::Set).is_err());
+        assert!(vibrio::syscalls::Fs::allocate(write_fd, 0, 8).is_err());
+        let ret = vibrio::syscalls::Fs::close(write_fd).expect("FileClose syscall failed");
+        assert_eq!(ret, 0);
+        let ret = vibrio::syscalls::Fs::read(read_fd, buf.as_mut_ptr() as u64, 8)
+            .expect("Pipe read failed");
+        assert_eq!(&buf[..ret as usize], b"hello");
+        // Empty, and nobody writes anymore
+        let ret = vibrio::syscalls::Fs::read(read_fd, buf.as_mut_ptr() as u64, 8)
+            .expect("Pipe read failed");
+        assert_eq!(ret, 0);
+        let ret = vibrio::syscalls::Fs::close(read_fd).expect("FileClose syscall failed");
+        assert_eq!(ret, 0);
+
+        // Batch a write and a read through the async ring.
+        let (read_fd, write_fd) = vibrio::syscalls::Fs::pipe().expect("Pipe syscall failed");
+        let mut ring = vibrio::asyncring::AsyncRing::new(4).expect("Can't set up async ring");
+        assert!(vibrio::asyncring::AsyncRing::new(4).is_err());
+        let mut buf = [0u8; 8];
+        unsafe {
+            ring.write(write_fd, b"ring", None, 1)
+                .expect("Can't submit write");
+            ring.read(read_fd, &mut buf, None, 2)
+                .expect("Can't submit read");
+        }
+        let c = ring.wait().expect("Enter failed").expect("No completion");
+        assert_eq!((c.user_data, c.result()), (1, Ok((4, 0))));
+        let c = ring.wait().expect("Enter failed").expect("No completion");
+        assert_eq!((c.user_data, c.result()), (2, Ok((4, 0))));
+        assert_eq!(&buf[..4], b"ring");
+        assert_eq!(ring.wait(), Ok(None));
+        drop(ring);
+        vibrio::syscalls::Fs::close(write_fd).expect("FileClose syscall failed");
+        vibrio::syscalls::Fs::close(read_fd).expect("FileClose syscall failed");
+
         // Test fs with invalid userspace pointers
         test_fs_invalid_addresses();
+
+        // Drop root and check that file modes are enforced (this has to be
+        // the last test, we can't become root again).
+        let ret = vibrio::syscalls::Fs::mkdir_simple(
+            "private\0".as_ptr() as u64,
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("MkDir syscall failed");
+        assert_eq!(ret, 0);
+        let fd = vibrio::syscalls::Fs::open(
+            "shared.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU | FileModes::S_IROTH),
+        )
+        .expect("FileOpen syscall failed");
+        let ret = vibrio::syscalls::Fs::write_at(fd, "root".as_ptr() as u64, 4, 0)
+            .expect("FileWrite syscall failed");
+        assert_eq!(ret, 4);
+
+        vibrio::syscalls::Process::set_credentials(1000, 100).expect("Can't drop root");
+        assert!(vibrio::syscalls::Process::set_credentials(0, 0).is_err());
+        assert!(vibrio::syscalls::Fs::open(
+            "private/file.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .is_err());
+        let mut buf = [0u8; 4];
+        let ret = vibrio::syscalls::Fs::read_at(fd, buf.as_mut_ptr() as u64, 4, 0)
+            .expect("FileRead syscall failed");
+        assert_eq!(&buf[..ret as usize], b"root");
+        assert!(vibrio::syscalls::Fs::write_at(fd, "user".as_ptr() as u64, 4, 0).is_err());
+        vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
     }
 
     info!("fs_test OK");
 }
 
+/// Runs with a quota of 64 KiB (`fsquota=65536`).
+fn fs_quota_test() {
+    use vibrio::io::*;
+    let base: u64 = 0xff000;
+    let size: u64 = 0x1000 * 8;
+    unsafe {
+        let usage = vibrio::syscalls::Process::fs_usage().expect("Can't get fs usage");
+        assert_eq!(usage.bytes, 0);
+        assert_eq!(usage.quota, Some(0x1000 * 16));
+
+        let fd = vibrio::syscalls::Fs::open(
+            "quota.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        vibrio::syscalls::VSpace::map(base, size).expect("Map syscall failed");
+
+        // Fill up the quota, overwriting data doesn't count twice
+        for offset in &[0, size, 0] {
+            let ret = vibrio::syscalls::Fs::write_at(fd, base, size, *offset as i64)
+                .expect("FileWriteAt syscall failed");
+            assert_eq!(ret, size);
+        }
+        let usage = vibrio::syscalls::Process::fs_usage().expect("Can't get fs usage");
+        assert_eq!(usage.bytes, 2 * size);
+
+        // One more byte is too much
+        assert!(vibrio::syscalls::Fs::write_at(fd, base, 1, 2 * size as i64).is_err());
+        assert!(vibrio::syscalls::Fs::allocate(fd, 2 * size, 1).is_err());
+
+        // Deleting the file gives the bytes back
+        vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
+        vibrio::syscalls::Fs::delete("quota.txt\0".as_ptr() as u64)
+            .expect("FileDelete syscall failed");
+        let usage = vibrio::syscalls::Process::fs_usage().expect("Can't get fs usage");
+        assert_eq!(usage.bytes, 0);
+    }
+
+    info!("fs_quota_test OK");
+}
+
+fn fs_evict_test() {
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, System, VSpace};
+    use x86::bits64::paging::LARGE_PAGE_SIZE;
+
+    let stats = System::memory_stats().expect("Can't get memory stats");
+    let free: u64 = stats.iter().map(|node| node.free).sum();
+    let evicted = stats[0].evicted_pages;
+
+    // A cache that takes up half of the free memory
+    let cache_size = (free / 2) & !(LARGE_PAGE_SIZE as u64 - 1);
+    let fd = Fs::open(
+        "cache.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT | FileFlags::O_EVICTABLE),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::allocate(fd, 0, cache_size).expect("FileAllocate syscall failed");
+    // The pages stay as long as the file is open for writing
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    // Mapping more than what's left only works if the cache gets smaller
+    let base: u64 = 0x80_0000_0000;
+    let chunk: u64 = 16 * 1024 * 1024;
+    let mut mapped = 0;
+    while mapped < free / 4 * 3 {
+        unsafe { VSpace::map(base + mapped, chunk).expect("Map syscall failed") };
+        mapped += chunk;
+    }
+
+    let stats = System::memory_stats().expect("Can't get memory stats");
+    assert!(stats[0].evicted_pages > evicted);
+    let info = Fs::getinfo("cache.txt\0".as_ptr() as u64).expect("FileInfo syscall failed");
+    assert!(info.fsize < cache_size);
+
+    info!("fs_evict_test OK");
+}
+
 fn fs_write_test() {
     use vibrio::syscalls::Fs;
 
@@ -616,6 +1557,16 @@ pub extern "C" fn _start() -> ! {
     install_vcpu_area();
 
     let pinfo = vibrio::syscalls::Process::process_info().expect("Can't read process info");
+    vibrio::mem::configure_from(pinfo.malloc_conf);
+
+    // A server restarted by `supervisor_test`
+    #[cfg(feature = "test-supervisor")]
+    {
+        let args = pinfo.app_cmdline.trim_matches('\'');
+        if args.starts_with("rpc-server ") {
+            rpc_server(args);
+        }
+    }
     #[cfg(not(feature = "fxmark"))]
     let ncores: Option<usize> = pinfo.cmdline.parse().ok();
 
@@ -637,6 +1588,9 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "bench-vmops-unmaplat")]
     vmops::unmaplat::bench(ncores);
 
+    #[cfg(feature = "bench-alloc")]
+    allocbench::bench(ncores);
+
     #[cfg(feature = "test-print")]
     print_test();
 
@@ -646,9 +1600,60 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "test-map")]
     map_test();
 
+    #[cfg(feature = "test-huge-page")]
+    huge_page_test();
+
+    #[cfg(feature = "test-long-syscall")]
+    long_syscall_test();
+
+    #[cfg(feature = "test-protect")]
+    protect_test();
+
+    #[cfg(feature = "test-stack-guard")]
+    stack_guard_test();
+
+    #[cfg(feature = "test-stack-growth")]
+    stack_growth_test();
+
+    #[cfg(feature = "test-preemption")]
+    preemption_test();
+
+    #[cfg(feature = "test-futex")]
+    futex_test();
+
+    #[cfg(feature = "test-event")]
+    event_test();
+
+    #[cfg(feature = "test-release-physical")]
+    release_physical_test();
+
     #[cfg(feature = "test-alloc")]
     alloc_test();
 
+    #[cfg(feature = "test-fork")]
+    fork_test();
+
+    #[cfg(feature = "test-waitpid")]
+    waitpid_test();
+
+    #[cfg(feature = "test-exit-reclaim")]
+    exit_reclaim_test();
+
+    #[cfg(feature = "test-process-group")]
+    process_group_test();
+
+    #[cfg(feature = "test-supervisor")]
+    supervisor_test();
+
+    #[cfg(feature = "test-exec")]
+    exec_test();
+
+    #[cfg(feature = "test-eventring")]
+    eventring_test();
+
+    #[cfg(feature = "test-syscall-cost")]
+    syscall_cost_test();
+
     #[cfg(feature = "test-scheduler")]
     scheduler_test();
 
@@ -669,12 +1674,21 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "test-fs")]
     fs_test();
 
+    #[cfg(feature = "test-fs-quota")]
+    fs_quota_test();
+
+    #[cfg(feature = "test-fs-evict")]
+    fs_evict_test();
+
     #[cfg(feature = "fs-write")]
     fs_write_test();
 
     #[cfg(feature = "fxmark")]
     fxmark::bench(ncores, open_files, benchmark, write_ratio);
 
+    #[cfg(feature = "shell")]
+    vibrio::syscalls::Process::exit(shell::run());
+
     vibrio::vconsole::init();
 
     debug!("Done with init tests, if we came here probably everything is good.");
@@ -0,0 +1,297 @@
+//! A small interactive shell that reads commands from the console.
+//!
+//! It can start boot modules as new processes (in the foreground or as
+//! background jobs, every job gets its own process group), and shows some
+//! information about the system. Type `help` for a list of commands.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use log::error;
+use vibrio::process::SchedulingPolicy;
+use vibrio::syscalls::{Process, System};
+use vibrio::{sys_print, sys_println};
+
+const PROMPT: &str = "bespin> ";
+
+/// A process that we started in the background.
+struct Job {
+    id: usize,
+    pid: u64,
+    binary: String,
+}
+
+struct Shell {
+    jobs: Vec<Job>,
+    next_job_id: usize,
+}
+
+/// Reads a line from the console (and echoes it back).
+fn read_line() -> String {
+    let mut line = String::new();
+    let mut buf = [0u8; 64];
+
+    loop {
+        let len = match Process::read_console(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Can't read from the console: {:?}", e);
+                0
+            }
+        };
+        if len == 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+
+        for b in &buf[..len] {
+            match *b {
+                b'\r' | b'\n' => {
+                    sys_println!("");
+                    return line;
+                }
+                // Backspace / delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        sys_print!("\x08 \x08");
+                    }
+                }
+                b if b.is_ascii() && !b.is_ascii_control() => {
+                    line.push(b as char);
+                    sys_print!("{}", b as char);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Blocks until process `pid` exited and returns its exit code.
+fn wait_for(pid: u64) -> Option<u64> {
+    match Process::wait_pid(pid) {
+        Ok(code) => Some(code),
+        Err(e) => {
+            sys_println!("wait: process {} isn't our child ({:?})", pid, e);
+            None
+        }
+    }
+}
+
+impl Shell {
+    fn new() -> Shell {
+        Shell {
+            jobs: Vec::new(),
+            next_job_id: 1,
+        }
+    }
+
+    fn help(&self) {
+        sys_println!("run <module> [args]  run a boot module and wait for it to exit");
+        sys_println!("<module> [args] &    run a boot module in the background");
+        sys_println!("jobs                 list background jobs");
+        sys_println!("fg <job>             wait for a background job");
+        sys_println!("kill <job>           terminate a background job (and its children)");
+        sys_println!("ps                   list processes");
+        sys_println!("mem                  show free memory");
+        sys_println!("dmesg                show the kernel log");
+        sys_println!("exit [code]          exit the shell");
+    }
+
+    fn run(&mut self, binary: &str, args: &[&str], background: bool) {
+        let pid = match Process::spawn(binary, &args.join(" "), SchedulingPolicy::default()) {
+            Ok(pid) => pid,
+            Err(e) => {
+                sys_println!("run: can't start {} ({:?})", binary, e);
+                return;
+            }
+        };
+
+        if background {
+            // So we can get rid of the job and everything it started at once
+            if let Err(e) = Process::set_group(pid, 0) {
+                sys_println!("run: can't create a process group for {} ({:?})", pid, e);
+            }
+            let id = self.next_job_id;
+            self.next_job_id += 1;
+            sys_println!("[{}] {}", id, pid);
+            self.jobs.push(Job {
+                id,
+                pid,
+                binary: String::from(binary),
+            });
+        } else if let Some(code) = wait_for(pid) {
+            if code != 0 {
+                sys_println!("{} exited with {}", binary, code);
+            }
+        }
+    }
+
+    /// Prints the state of all jobs and forgets about the ones that are done.
+    fn jobs(&mut self) {
+        self.jobs.retain(|job| match Process::wait(job.pid) {
+            Ok(None) => {
+                sys_println!("[{}] {} running    {}", job.id, job.pid, job.binary);
+                true
+            }
+            Ok(Some(code)) => {
+                sys_println!("[{}] {} exit({})    {}", job.id, job.pid, code, job.binary);
+                // Doesn't block, we just collect the exit code
+                let _r = wait_for(job.pid);
+                false
+            }
+            Err(_e) => false,
+        });
+    }
+
+    fn fg(&mut self, id: Option<usize>) {
+        let idx = match id {
+            Some(id) => self.jobs.iter().position(|job| job.id == id),
+            None if !self.jobs.is_empty() => Some(self.jobs.len() - 1),
+            None => None,
+        };
+
+        match idx {
+            Some(idx) => {
+                let job = self.jobs.remove(idx);
+                sys_println!("{}", job.binary);
+                if let Some(code) = wait_for(job.pid) {
+                    if code != 0 {
+                        sys_println!("{} exited with {}", job.binary, code);
+                    }
+                }
+            }
+            None => sys_println!("fg: no such job"),
+        }
+    }
+
+    fn kill(&mut self, id: Option<usize>) {
+        match self.jobs.iter().position(|job| Some(job.id) == id) {
+            Some(idx) => {
+                let job = self.jobs.remove(idx);
+                match Process::terminate_group(job.pid) {
+                    Ok(n) => sys_println!("[{}] terminated {} process(es)", job.id, n),
+                    Err(e) => sys_println!("kill: can't terminate {} ({:?})", job.pid, e),
+                }
+                // Collect the exit code
+                let _r = wait_for(job.pid);
+            }
+            None => sys_println!("kill: no such job"),
+        }
+    }
+
+    fn ps(&self) {
+        match Process::list() {
+            Ok(processes) => {
+                sys_println!(
+                    "{:>5} {:>5} {:>6} {:<16} {:>10} {:>10} CORES",
+                    "PID",
+                    "GROUP",
+                    "POLICY",
+                    "BINARY",
+                    "PRINTED",
+                    "DROPPED"
+                );
+                for p in processes {
+                    sys_println!(
+                        "{:>5} {:>5} {:>6} {:<16} {:>10} {:>10} {:?}",
+                        p.pid,
+                        p.group,
+                        alloc::format!("{:?}", p.policy),
+                        p.binary,
+                        p.printed_bytes,
+                        p.dropped_bytes,
+                        p.cores
+                    );
+                }
+            }
+            Err(e) => sys_println!("ps: {:?}", e),
+        }
+    }
+
+    fn mem(&self) {
+        match System::memory_stats() {
+            Ok(nodes) => {
+                sys_println!(
+                    "{:>4} {:>12} {:>10} {:>10} {:>10}",
+                    "NODE",
+                    "FREE",
+                    "4K",
+                    "2M",
+                    "EVICTED"
+                );
+                for n in nodes {
+                    sys_println!(
+                        "{:>4} {:>10}Mi {:>10} {:>10} {:>10}",
+                        n.node,
+                        n.free / (1024 * 1024),
+                        n.free_base_pages,
+                        n.free_large_pages,
+                        n.evicted_pages
+                    );
+                }
+            }
+            Err(e) => sys_println!("mem: {:?}", e),
+        }
+    }
+
+    fn dmesg(&self) {
+        const LEVELS: [&str; 6] = ["", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+        match System::kernel_log() {
+            Ok(records) => {
+                for r in records {
+                    sys_println!(
+                        "[{:>5}.{:06}] {:<5} {}: {}",
+                        r.timestamp / 1_000_000_000,
+                        (r.timestamp % 1_000_000_000) / 1_000,
+                        LEVELS.get(r.level as usize).unwrap_or(&"?"),
+                        r.module,
+                        r.message
+                    );
+                }
+            }
+            Err(e) => sys_println!("dmesg: {:?}", e),
+        }
+    }
+
+    /// Executes a command, returns the exit code if the shell should exit.
+    fn execute(&mut self, line: &str) -> Option<u64> {
+        let mut args: Vec<&str> = line.split_whitespace().collect();
+        let background = args.last() == Some(&"&");
+        if background {
+            args.pop();
+        }
+
+        match args.as_slice() {
+            [] => {}
+            ["help"] => self.help(),
+            ["exit"] => return Some(0),
+            ["exit", code] => return Some(code.parse().unwrap_or(1)),
+            ["run", binary, args @ ..] => self.run(binary, args, background),
+            ["jobs"] => self.jobs(),
+            ["fg"] => self.fg(None),
+            ["fg", id] => self.fg(id.parse().ok()),
+            ["kill", id] => self.kill(id.parse().ok()),
+            ["ps"] => self.ps(),
+            ["mem"] => self.mem(),
+            ["dmesg"] => self.dmesg(),
+            [binary, args @ ..] if background => self.run(binary, args, true),
+            [cmd, ..] => sys_println!("{}: unknown command (try help)", cmd),
+        }
+
+        None
+    }
+}
+
+/// Runs the shell until the user types `exit`, returns the exit code.
+pub fn run() -> u64 {
+    let mut shell = Shell::new();
+    sys_println!("bespin shell, type 'help' for a list of commands.");
+
+    loop {
+        sys_print!("{}", PROMPT);
+        let line = read_line();
+        if let Some(code) = shell.execute(line.trim()) {
+            return code;
+        }
+    }
+}
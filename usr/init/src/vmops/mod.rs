@@ -169,10 +169,33 @@ pub fn bench(ncores: Option<usize>) {
         None,
     );
 
+    #[cfg(feature = "power")]
+    let power_before = vibrio::syscalls::System::core_stats()
+        .expect("Can't get core stats")
+        .power;
+
     let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
     while s.has_active_threads() {
         s.run(&scb);
     }
+
+    #[cfg(feature = "power")]
+    {
+        let power = vibrio::syscalls::System::core_stats()
+            .expect("Can't get core stats")
+            .power;
+        info!("benchmark,ncores,package_uj,cores_uj,core_celsius,package_celsius");
+        info!(
+            "Energy: {},{},{:?},{:?},{:?},{:?}",
+            "maponly",
+            cores,
+            power.package_energy_since(&power_before),
+            power.cores_energy_since(&power_before),
+            power.core_temperature,
+            power.package_temperature,
+        );
+    }
+
     #[cfg(feature = "latency")]
     {
         let hlock = LATENCY_HISTOGRAM.lock();
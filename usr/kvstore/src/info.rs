@@ -0,0 +1,145 @@
+//! The `INFO` command: telemetry of the machine kvstore runs on.
+//!
+//! Long benchmark runs on remote machines can be watched with
+//! `redis-cli -h <host> INFO [section]` instead of the serial console. Like
+//! in Redis, the report is a bulk string of `key:value` lines grouped in
+//! sections:
+//!
+//! - `server`: the machine (cores and NUMA nodes)
+//! - `memory`: free memory of every NUMA node
+//! - `cpu`: statistics the kernel collected for the core that serves the
+//!   connection (TLB shootdowns, system calls, energy and temperature)
+//! - `keyspace`: how many keys the store has
+//! - `log`: the most recent records of the kernel log (only if kvstore runs
+//!   as the initial process), not part of the default report
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use vibrio::syscalls::System;
+
+/// Sections we report if `INFO` doesn't name any (or names `all`).
+const DEFAULT_SECTIONS: &[&str] = &["server", "memory", "cpu", "keyspace"];
+
+/// The report for `sections` (the arguments of `INFO`), `keys` is the size
+/// of the store.
+pub fn report(sections: &[&[u8]], keys: usize) -> String {
+    let mut out = String::new();
+    if sections.is_empty() {
+        for name in DEFAULT_SECTIONS {
+            section(&mut out, name, keys);
+        }
+    }
+    for name in sections {
+        let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+        match name.as_str() {
+            "all" | "default" => {
+                for name in DEFAULT_SECTIONS {
+                    section(&mut out, name, keys);
+                }
+            }
+            name => section(&mut out, name, keys),
+        }
+    }
+    out
+}
+
+/// Appends section `name` to `out` (nothing for sections we don't know,
+/// like Redis).
+fn section(out: &mut String, name: &str, keys: usize) {
+    // Writing to a String doesn't fail
+    let _r = match name {
+        "server" => server(out),
+        "memory" => memory(out),
+        "cpu" => cpu(out),
+        "keyspace" => writeln!(out, "# Keyspace\r\ndb0:keys={}\r", keys),
+        "log" => log(out),
+        _ => return,
+    };
+    out.push_str("\r\n");
+}
+
+fn server(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "# Server\r")?;
+    if let Ok(threads) = System::threads() {
+        let mut nodes: alloc::vec::Vec<_> = threads.iter().map(|t| t.node_id).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        writeln!(out, "cores:{}\r", threads.len())?;
+        writeln!(out, "numa_nodes:{}\r", nodes.len())?;
+    }
+    Ok(())
+}
+
+fn memory(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "# Memory\r")?;
+    if let Ok(nodes) = System::memory_stats() {
+        for node in nodes {
+            writeln!(
+                out,
+                "node{}:free={},free_base_pages={},free_large_pages={},external_fragmentation={},evicted_pages={}\r",
+                node.node,
+                node.free,
+                node.free_base_pages,
+                node.free_large_pages,
+                node.external_fragmentation,
+                node.evicted_pages
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn cpu(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "# CPU\r")?;
+    if let Ok(stats) = System::core_stats() {
+        writeln!(out, "core:{}\r", stats.id)?;
+        writeln!(out, "tlb_shootdown_cycles:{}\r", stats.tlb_time)?;
+        writeln!(
+            out,
+            "tlb_handler:count={},p50={},p99={},max={}\r",
+            stats.tlb_handler.count,
+            stats.tlb_handler.p50,
+            stats.tlb_handler.p99,
+            stats.tlb_handler.max
+        )?;
+        for s in stats.syscall_latency.iter().filter(|s| s.cycles.count > 0) {
+            writeln!(
+                out,
+                "syscall{}:count={},p50={},p99={},max={}\r",
+                s.syscall, s.cycles.count, s.cycles.p50, s.cycles.p99, s.cycles.max
+            )?;
+        }
+        if let Some(temperature) = stats.power.core_temperature {
+            writeln!(out, "core_temperature:{}\r", temperature)?;
+        }
+        if let Some(temperature) = stats.power.package_temperature {
+            writeln!(out, "package_temperature:{}\r", temperature)?;
+        }
+        writeln!(
+            out,
+            "thermal_throttling:{}\r",
+            stats.power.thermal_throttling as u8
+        )?;
+    }
+    Ok(())
+}
+
+fn log(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "# Log\r")?;
+    // Fails unless we're the initial process
+    if let Ok(records) = System::kernel_log() {
+        for record in records {
+            // Keep the `key:value` format intact
+            let message = record
+                .message
+                .replace(|c: char| c == '\r' || c == '\n', " ");
+            writeln!(
+                out,
+                "log{}:{} {} {}: {}\r",
+                record.seq, record.timestamp, record.level, record.module, message
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! kvstore: A persistent key--value store that speaks (a subset of) the Redis
+//! protocol over TCP.
+//!
+//! It's our standard macro-benchmark for vibrio, lineup and the rump network
+//! stack: every connection is served by its own lineup thread, all updates are
+//! persisted in a log file in the kernel file-system.
+//!
+//! The process command line (`testcmd=`) is the port to listen on (6379 by
+//! default). Besides the store, `INFO` reports telemetry of the machine (see
+//! `info`).
+#![no_std]
+#![no_main]
+#![feature(thread_local)]
+#![feature(llvm_asm)]
+#![feature(alloc_error_handler)]
+#![feature(const_fn)]
+#![feature(panic_info_message)]
+#![feature(lang_items)]
+#![feature(core_intrinsics)]
+extern crate alloc;
+extern crate kpi;
+extern crate spin;
+extern crate vibrio;
+extern crate x86;
+#[macro_use]
+extern crate lazy_static;
+
+extern crate lineup;
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cstr_core::CStr;
+use lineup::tls2::{Environment, SchedulerControlBlock};
+use log::{debug, error, info, Level};
+use spin::Mutex;
+use vibrio::rumprt;
+use x86::bits64::paging::VAddr;
+
+mod info;
+mod resp;
+mod store;
+
+use store::Store;
+
+/// Port we listen on if none is given on the command line.
+const DEFAULT_PORT: u16 = 6379;
+
+/// File (in the kernel file-system) that holds the log of the store.
+const LOG_FILE: &str = "kvstore.log\0";
+
+/// How many bytes we try to receive at once from a connection.
+const RECV_SIZE: usize = 4096;
+
+/// Stack size of the server thread.
+const STACK_SIZE: usize = 32 * 4096;
+
+const AF_INET: i64 = 2;
+const SOCK_STREAM: i64 = 1;
+const IPPROTO_TCP: i64 = 6;
+const MSG_NOSIGNAL: i64 = 0x0400;
+
+/// Maximum number of pending connections.
+const BACKLOG: i64 = 64;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct sockaddr_in {
+    sin_len: u8,
+    sin_family: u8,
+    sin_port: u16,
+    sin_addr: u32,
+    zero: [u8; 8],
+}
+
+extern "C" {
+    fn rump_boot_setsigmodel(sig: usize);
+    fn rump_init(fnptr: extern "C" fn()) -> u64;
+    fn rump_pub_netconfig_dhcp_ipv4_oneshot(iface: *const i8) -> i64;
+
+    fn socket(domain: i64, typ: i64, protocol: i64) -> i64;
+    fn bind(fd: i64, addr: *const sockaddr_in, len: usize) -> i64;
+    fn listen(fd: i64, backlog: i64) -> i64;
+    fn accept(fd: i64, addr: *mut sockaddr_in, len: *mut u32) -> i64;
+    fn recv(fd: i64, buf: *mut u8, len: usize, flags: i64) -> i64;
+    fn send(fd: i64, buf: *const u8, len: usize, flags: i64) -> i64;
+    fn close(sock: i64) -> i64;
+}
+
+lazy_static! {
+    /// The store, shared by all connections.
+    static ref STORE: Mutex<Option<Store>> = Mutex::new(None);
+}
+
+static READY_FLAG: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn ready() {
+    READY_FLAG.store(true, Ordering::Relaxed);
+}
+
+/// Executes the command in `args` and appends the reply to `out`.
+fn execute(args: &[&[u8]], out: &mut Vec<u8>) {
+    if args.is_empty() {
+        return;
+    }
+    if args[0].eq_ignore_ascii_case(b"INFO") {
+        // Don't hold on to the store while we ask the kernel
+        let keys = STORE.lock().as_ref().map_or(0, |store| store.len());
+        let report = info::report(&args[1..], keys);
+        resp::bulk(out, Some(report.as_bytes()));
+        return;
+    }
+
+    let mut guard = STORE.lock();
+    let store = guard.as_mut().expect("Store not initialized");
+
+    let mut cmd = args[0].to_vec();
+    cmd.make_ascii_uppercase();
+    match (cmd.as_slice(), args.len()) {
+        (b"PING", 1) => resp::simple(out, "PONG"),
+        (b"PING", 2) => resp::bulk(out, Some(args[1])),
+        (b"GET", 2) => resp::bulk(out, store.get(args[1])),
+        (b"SET", 3) => match store.put(args[1], args[2]) {
+            Ok(()) => resp::simple(out, "OK"),
+            Err(e) => {
+                error!("Can't persist SET: {:?}", e);
+                resp::error(out, "can't write log")
+            }
+        },
+        (b"DEL", n) if n > 1 => {
+            let mut deleted = 0;
+            for key in &args[1..] {
+                match store.delete(key) {
+                    Ok(true) => deleted += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Can't persist DEL: {:?}", e);
+                        resp::error(out, "can't write log");
+                        return;
+                    }
+                }
+            }
+            resp::integer(out, deleted);
+        }
+        (b"DBSIZE", 1) => resp::integer(out, store.len() as i64),
+        (b"PING", _) | (b"GET", _) | (b"SET", _) | (b"DEL", _) | (b"DBSIZE", _) => {
+            resp::error(out, "wrong number of arguments")
+        }
+        _ => resp::error(out, "unknown command"),
+    }
+}
+
+/// Serves one client connection until it's closed by the client.
+///
+/// `arg` is the socket of the connection.
+unsafe extern "C" fn connection(arg: *mut u8) -> *mut u8 {
+    let fd = arg as i64;
+    let mut buf: Vec<u8> = Vec::with_capacity(RECV_SIZE);
+    let mut out: Vec<u8> = Vec::with_capacity(RECV_SIZE);
+
+    loop {
+        let len = buf.len();
+        buf.resize(len + RECV_SIZE, 0);
+        let r = recv(fd, buf.as_mut_ptr().add(len), RECV_SIZE, 0);
+        if r <= 0 {
+            break;
+        }
+        buf.truncate(len + r as usize);
+
+        // Clients may pipeline requests, handle all complete ones we got:
+        let mut consumed = 0;
+        loop {
+            match resp::parse(&buf[consumed..]) {
+                Ok(Some((args, size))) => {
+                    execute(&args, &mut out);
+                    consumed += size;
+                }
+                Ok(None) => break,
+                Err(()) => {
+                    resp::error(&mut out, "protocol error");
+                    consumed = buf.len();
+                    break;
+                }
+            }
+        }
+        buf.drain(..consumed);
+
+        let mut sent = 0;
+        while sent < out.len() {
+            let r = send(fd, out[sent..].as_ptr(), out.len() - sent, MSG_NOSIGNAL);
+            if r <= 0 {
+                debug!("Connection {} closed while sending", fd);
+                close(fd);
+                return ptr::null_mut();
+            }
+            sent += r as usize;
+        }
+        out.clear();
+    }
+
+    close(fd);
+    ptr::null_mut()
+}
+
+/// Brings up the network, opens the store and accepts connections.
+unsafe fn serve(port: u16) {
+    let start = rawtime::Instant::now();
+    rump_boot_setsigmodel(1);
+    let ri = rump_init(ready);
+    assert_eq!(ri, 0);
+    while !READY_FLAG.load(Ordering::Relaxed) {
+        Environment::thread().relinquish();
+    }
+
+    #[cfg(feature = "virtio")]
+    let iface = b"vioif0\0";
+    #[cfg(not(feature = "virtio"))]
+    let iface = b"wm0\0";
+
+    let iface = CStr::from_bytes_with_nul(iface).unwrap();
+    let r = rump_pub_netconfig_dhcp_ipv4_oneshot(iface.as_ptr());
+    assert_eq!(r, 0, "rump_pub_netconfig_dhcp_ipv4_oneshot");
+    info!("Network configured in {:?}", start.elapsed());
+
+    *STORE.lock() = Some(Store::open(LOG_FILE).expect("Can't open the store"));
+
+    let sockfd = socket(AF_INET, SOCK_STREAM, IPPROTO_TCP);
+    assert!(sockfd > 0, "socket");
+
+    let addr = sockaddr_in {
+        sin_len: core::mem::size_of::<sockaddr_in>() as u8,
+        sin_family: AF_INET as u8,
+        sin_port: port.to_be(),
+        sin_addr: 0, // INADDR_ANY
+        zero: [0; 8],
+    };
+    let r = bind(
+        sockfd,
+        &addr as *const sockaddr_in,
+        core::mem::size_of::<sockaddr_in>(),
+    );
+    assert_eq!(r, 0, "bind");
+    let r = listen(sockfd, BACKLOG);
+    assert_eq!(r, 0, "listen");
+    info!("kvstore: listening on port {}", port);
+
+    loop {
+        let fd = accept(sockfd, ptr::null_mut(), ptr::null_mut());
+        if fd < 0 {
+            error!("accept failed with {}", fd);
+            continue;
+        }
+
+        Environment::thread()
+            .spawn(Some(connection), fd as *mut u8)
+            .expect("Can't spawn connection thread");
+    }
+}
+
+pub fn install_vcpu_area() {
+    let ctl =
+        vibrio::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
+    ctl.resume_with_upcall =
+        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        log::set_logger(&vibrio::writer::LOGGER)
+            .map(|()| log::set_max_level(Level::Info.to_level_filter()))
+            .expect("Can't set-up logging");
+    }
+    install_vcpu_area();
+
+    let pinfo = vibrio::syscalls::Process::process_info().expect("Can't read process info");
+    let port: u16 = pinfo.cmdline.trim().parse().unwrap_or(DEFAULT_PORT);
+
+    let up = lineup::upcalls::Upcalls {
+        curlwp: rumprt::rumpkern_curlwp,
+        deschedule: rumprt::rumpkern_unsched,
+        schedule: rumprt::rumpkern_sched,
+        context_switch: rumprt::prt::context_switch,
+    };
+
+    let mut scheduler = lineup::scheduler::SmpScheduler::with_upcalls(up);
+    scheduler.spawn(
+        STACK_SIZE,
+        move |_arg| unsafe { serve(port) },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    loop {
+        scheduler.run(&scb);
+    }
+}
@@ -0,0 +1,106 @@
+//! A subset of the Redis protocol (RESP), just enough so we can use
+//! `redis-cli`, `nc` and `redis-benchmark` as clients.
+//!
+//! Requests are either arrays of bulk strings (`*2\r\n$3\r\nGET\r\n$1\r\nk\r\n`)
+//! or inline commands (`GET k\r\n`).
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::str;
+
+/// Parses a decimal number from `buf`.
+fn number(buf: &[u8]) -> Option<i64> {
+    str::from_utf8(buf).ok()?.parse().ok()
+}
+
+/// Returns the line at the start of `buf` (without the `\r\n` or `\n`)
+/// and the size of the line including the terminator.
+fn line(buf: &[u8]) -> Option<(&[u8], usize)> {
+    let end = buf.iter().position(|b| *b == b'\n')?;
+    let line = match &buf[..end] {
+        [line @ .., b'\r'] => line,
+        line => line,
+    };
+    Some((line, end + 1))
+}
+
+/// Parses one request at the start of `buf`.
+///
+/// Returns the arguments of the request and how many bytes of `buf` it
+/// used, `Ok(None)` if `buf` doesn't contain a complete request yet or an
+/// error if the request is malformed.
+pub fn parse(buf: &[u8]) -> Result<Option<(Vec<&[u8]>, usize)>, ()> {
+    let (header, mut consumed) = match line(buf) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if header.first() != Some(&b'*') {
+        let args = header
+            .split(|b| *b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        return Ok(Some((args, consumed)));
+    }
+
+    let count = number(&header[1..]).ok_or(())?;
+    let mut args = Vec::with_capacity(count.max(0) as usize);
+    for _i in 0..count {
+        let (len_line, len_size) = match line(&buf[consumed..]) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if len_line.first() != Some(&b'$') {
+            return Err(());
+        }
+        let len = number(&len_line[1..]).ok_or(())?;
+        if len < 0 {
+            return Err(());
+        }
+
+        let start = consumed + len_size;
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        args.push(&buf[start..end]);
+        consumed = end + 2;
+    }
+
+    Ok(Some((args, consumed)))
+}
+
+/// Appends a status reply.
+pub fn simple(out: &mut Vec<u8>, msg: &str) {
+    out.push(b'+');
+    out.extend_from_slice(msg.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Appends an error reply.
+pub fn error(out: &mut Vec<u8>, msg: &str) {
+    out.extend_from_slice(b"-ERR ");
+    out.extend_from_slice(msg.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Appends an integer reply.
+pub fn integer(out: &mut Vec<u8>, value: i64) {
+    out.push(b':');
+    out.extend_from_slice(value.to_string().as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Appends a bulk string reply (or the nil reply for `None`).
+pub fn bulk(out: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            out.push(b'$');
+            out.extend_from_slice(value.len().to_string().as_bytes());
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        None => out.extend_from_slice(b"$-1\r\n"),
+    }
+}
@@ -0,0 +1,179 @@
+//! A key--value store that keeps an in-memory index and persists all
+//! updates in an append-only log file (in the kernel file-system).
+//!
+//! Every update is a record in the log:
+//! `[op: u8][key length: u32][value length: u32][key][value]`
+//! (lengths are little-endian). On start-up we replay the log to rebuild
+//! the index.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use kpi::SystemCallError;
+use log::{info, warn};
+use vibrio::io::{FileFlags, FileModes};
+use vibrio::syscalls::Fs;
+
+/// Record that inserts or replaces a key.
+const OP_PUT: u8 = 1;
+/// Record that removes a key.
+const OP_DELETE: u8 = 2;
+
+/// Size of the record header (op + key length + value length).
+const HEADER_SIZE: usize = 1 + 4 + 4;
+
+/// An update to the store, as it's written to the log.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Record<'a> {
+    Put(&'a [u8], &'a [u8]),
+    Delete(&'a [u8]),
+}
+
+impl<'a> Record<'a> {
+    /// Serializes the record and appends it to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let (op, key, value): (u8, &[u8], &[u8]) = match self {
+            Record::Put(key, value) => (OP_PUT, key, value),
+            Record::Delete(key) => (OP_DELETE, key, &[]),
+        };
+
+        buf.push(op);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+    }
+
+    /// Deserializes the record at the start of `buf`.
+    ///
+    /// Returns the record and its size, or None if `buf` doesn't start with
+    /// a complete record (e.g., a torn write at the end of the log).
+    pub fn decode(buf: &'a [u8]) -> Option<(Record<'a>, usize)> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(buf[1..5].try_into().ok()?) as usize;
+        let value_len = u32::from_le_bytes(buf[5..9].try_into().ok()?) as usize;
+        let size = HEADER_SIZE + key_len + value_len;
+        if buf.len() < size {
+            return None;
+        }
+
+        let key = &buf[HEADER_SIZE..HEADER_SIZE + key_len];
+        let value = &buf[HEADER_SIZE + key_len..size];
+        match buf[0] {
+            OP_PUT => Some((Record::Put(key, value), size)),
+            OP_DELETE => Some((Record::Delete(key), size)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Store {
+    /// The current value of all keys.
+    index: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// File descriptor of the log.
+    fd: u64,
+    /// Where we append the next record in the log.
+    offset: i64,
+    /// Scratch space to serialize records.
+    buf: Vec<u8>,
+}
+
+impl Store {
+    /// Opens (or creates) the store with its log at `path` (a
+    /// null-terminated file name).
+    pub fn open(path: &str) -> Result<Store, SystemCallError> {
+        let fd = Fs::open(
+            path.as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )?;
+        let size = Fs::getinfo(path.as_ptr() as u64)?.fsize as usize;
+
+        let mut store = Store {
+            index: BTreeMap::new(),
+            fd,
+            offset: 0,
+            buf: Vec::with_capacity(4096),
+        };
+
+        if size > 0 {
+            let log = vec![0u8; size];
+            Fs::read_at(fd, log.as_ptr() as u64, size as u64, 0)?;
+            store.offset = store.replay(&log) as i64;
+            if store.offset as usize != size {
+                warn!(
+                    "Ignoring {} bytes of incomplete records at the end of the log.",
+                    size - store.offset as usize
+                );
+            }
+        }
+        info!(
+            "Opened store with {} keys ({} bytes of log)",
+            store.index.len(),
+            store.offset
+        );
+
+        Ok(store)
+    }
+
+    /// Applies the records in `log` to the index.
+    ///
+    /// Returns how many bytes of `log` we consumed.
+    fn replay(&mut self, log: &[u8]) -> usize {
+        let mut consumed = 0;
+        while let Some((record, size)) = Record::decode(&log[consumed..]) {
+            match record {
+                Record::Put(key, value) => {
+                    self.index.insert(key.to_vec(), value.to_vec());
+                }
+                Record::Delete(key) => {
+                    self.index.remove(key);
+                }
+            }
+            consumed += size;
+        }
+        consumed
+    }
+
+    /// Appends `record` to the log.
+    fn append(&mut self, record: Record) -> Result<(), SystemCallError> {
+        self.buf.clear();
+        record.encode(&mut self.buf);
+        let written = Fs::write_at(
+            self.fd,
+            self.buf.as_ptr() as u64,
+            self.buf.len() as u64,
+            self.offset,
+        )?;
+        self.offset += written as i64;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.index.get(key).map(|v| v.as_slice())
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), SystemCallError> {
+        self.append(Record::Put(key, value))?;
+        self.index.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Removes `key`, returns true if it existed.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, SystemCallError> {
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(Record::Delete(key))?;
+        self.index.remove(key);
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+}